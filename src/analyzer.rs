@@ -0,0 +1,279 @@
+//! Page-level space accounting for the b-trees of a database, in the spirit of SQLite's
+//! `dbstat` virtual table and `sqlite3_analyzer`.
+
+use std::collections::HashSet;
+
+use anyhow::{Context, bail, ensure};
+
+use crate::pager::{self, Pager};
+
+pub const PAGE_INTERIOR_INDEX_ID: u8 = 0x02;
+pub const PAGE_INTERIOR_TABLE_ID: u8 = 0x05;
+pub const PAGE_LEAF_INDEX_ID: u8 = 0x0a;
+pub const PAGE_LEAF_TABLE_ID: u8 = 0x0d;
+
+#[derive(Debug, Default, Clone)]
+pub struct BtreeStats {
+    pub name: String,
+    pub leaf_pages: usize,
+    pub interior_pages: usize,
+    pub overflow_pages: usize,
+    pub cells: usize,
+    pub payload_bytes: usize,
+    pub unused_bytes: usize,
+    /// Pages that don't immediately follow the previous page of the b-tree on disk.
+    pub out_of_order_pages: usize,
+}
+
+impl BtreeStats {
+    pub fn pages(&self) -> usize {
+        self.leaf_pages + self.interior_pages + self.overflow_pages
+    }
+
+    /// Percentage of pages that aren't stored sequentially.
+    pub fn fragmentation(&self) -> f64 {
+        match self.pages() {
+            0 | 1 => 0.0,
+            n => 100.0 * self.out_of_order_pages as f64 / (n - 1) as f64,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct PageStats {
+    leaf: bool,
+    cells: usize,
+    payload_bytes: usize,
+    unused_bytes: usize,
+    children: Vec<usize>,
+    /// First overflow page and remaining payload size of each spilled cell.
+    overflows: Vec<(usize, usize)>,
+}
+
+pub fn analyze_btree(pager: &Pager, name: &str, root: usize) -> anyhow::Result<BtreeStats> {
+    let usable_size = pager.header().usable_page_size();
+    let mut stats = BtreeStats {
+        name: name.to_string(),
+        ..Default::default()
+    };
+
+    let mut visited = HashSet::new();
+    let mut previous = None;
+    let mut visit = |page: usize, stats: &mut BtreeStats| -> anyhow::Result<()> {
+        ensure!(visited.insert(page), "page {page} is referenced twice");
+        if previous.is_some_and(|p| p + 1 != page) {
+            stats.out_of_order_pages += 1;
+        }
+        previous = Some(page);
+        Ok(())
+    };
+
+    let mut stack = vec![root];
+    while let Some(page_num) = stack.pop() {
+        visit(page_num, &mut stats)?;
+
+        let buffer = pager.read_raw(page_num)?;
+        let page = analyze_page(&buffer[..usable_size], page_num)
+            .with_context(|| format!("analyze page {page_num} of {name}"))?;
+
+        if page.leaf {
+            stats.leaf_pages += 1;
+        } else {
+            stats.interior_pages += 1;
+        }
+        stats.cells += page.cells;
+        stats.payload_bytes += page.payload_bytes;
+        stats.unused_bytes += page.unused_bytes;
+
+        for (first, mut remaining) in page.overflows {
+            let mut next = first;
+            while next != 0 && remaining > 0 {
+                visit(next, &mut stats)?;
+                let overflow = pager.read_raw(next)?;
+                let used = remaining.min(usable_size - 4);
+
+                stats.overflow_pages += 1;
+                stats.payload_bytes += used;
+                stats.unused_bytes += usable_size - 4 - used;
+
+                remaining -= used;
+                next = pager::read_be_double_at(&overflow, 0) as usize;
+            }
+        }
+
+        stack.extend(page.children.into_iter().rev());
+    }
+
+    Ok(stats)
+}
+
+fn analyze_page(buffer: &[u8], page_num: usize) -> anyhow::Result<PageStats> {
+    let offset = if page_num == 1 { pager::HEADER_SIZE } else { 0 };
+    let usable_size = buffer.len();
+    let page_type = buffer[offset];
+
+    let (leaf, table) = match page_type {
+        PAGE_LEAF_TABLE_ID => (true, true),
+        PAGE_INTERIOR_TABLE_ID => (false, true),
+        PAGE_LEAF_INDEX_ID => (true, false),
+        PAGE_INTERIOR_INDEX_ID => (false, false),
+        t => bail!("unknown page type: {t}"),
+    };
+
+    let header_size = if leaf { 8 } else { 12 };
+    let first_freeblock = read_u16(buffer, offset + 1);
+    let cell_count = read_u16(buffer, offset + 3);
+    let content_start = match read_u16(buffer, offset + 5) {
+        0 => 65536,
+        n => n,
+    };
+    let fragmented = buffer[offset + 7] as usize;
+
+    let pointers_end = offset + header_size + 2 * cell_count;
+    ensure!(
+        pointers_end <= content_start && content_start <= usable_size,
+        "invalid page layout"
+    );
+
+    let mut stats = PageStats {
+        leaf,
+        cells: cell_count,
+        unused_bytes: content_start - pointers_end + fragmented,
+        ..Default::default()
+    };
+
+    let mut freeblock = first_freeblock;
+    while freeblock != 0 {
+        ensure!(freeblock + 4 <= usable_size, "invalid freeblock offset");
+        stats.unused_bytes += read_u16(buffer, freeblock + 2);
+        let next = read_u16(buffer, freeblock);
+        ensure!(
+            next == 0 || next > freeblock,
+            "freeblock list is not sorted"
+        );
+        freeblock = next;
+    }
+
+    for i in 0..cell_count {
+        let mut cell = read_u16(buffer, offset + header_size + 2 * i);
+        ensure!(cell < usable_size, "invalid cell pointer");
+
+        if !leaf {
+            stats
+                .children
+                .push(pager::read_be_double_at(buffer, cell) as usize);
+            cell += 4;
+            if table {
+                continue;
+            }
+        }
+
+        let (n, payload_size) = pager::read_payload_size_at(buffer, cell)?;
+        cell += n as usize;
+        if table {
+            let (n, _rowid) = pager::read_varint_at(buffer, cell)?;
+            cell += n as usize;
+        }
+
+        let local_size = local_payload_size(usable_size, table, payload_size);
+        stats.payload_bytes += local_size;
+
+        if local_size < payload_size {
+            ensure!(
+                cell + local_size + 4 <= usable_size,
+                "invalid overflow pointer"
+            );
+            let first = pager::read_be_double_at(buffer, cell + local_size) as usize;
+            stats.overflows.push((first, payload_size - local_size));
+        }
+    }
+
+    if !leaf {
+        stats
+            .children
+            .push(pager::read_be_double_at(buffer, offset + 8) as usize);
+    }
+
+    Ok(stats)
+}
+
+/// Bytes of a payload stored on the page of its cell, the rest going to overflow pages.
+pub fn local_payload_size(usable_size: usize, table: bool, payload_size: usize) -> usize {
+    let max_local = if table {
+        usable_size - 35
+    } else {
+        (usable_size - 12) * 64 / 255 - 23
+    };
+    if payload_size <= max_local {
+        return payload_size;
+    }
+
+    let min_local = (usable_size - 12) * 32 / 255 - 23;
+    let k = min_local + (payload_size - min_local) % (usable_size - 4);
+    if k <= max_local { k } else { min_local }
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> usize {
+    u16::from_be_bytes([buffer[offset], buffer[offset + 1]]) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_leaf_page() {
+        let mut page = vec![0; 512];
+        // Leaf header: two cells, content starting at 500, one fragmented byte.
+        page[..8].copy_from_slice(&[PAGE_LEAF_TABLE_ID, 0, 0, 0, 2, 0x01, 0xf4, 1]);
+        page[8..12].copy_from_slice(&[0x01, 0xfa, 0x01, 0xf4]);
+        // Cells: payload size, rowid, payload.
+        page[500..506].copy_from_slice(&[4, 2, 1, 2, 3, 4]);
+        page[506..511].copy_from_slice(&[3, 1, 7, 8, 9]);
+
+        let stats = analyze_page(&page, 2).unwrap();
+        assert_eq!(
+            stats,
+            PageStats {
+                leaf: true,
+                cells: 2,
+                payload_bytes: 7,
+                unused_bytes: 500 - 12 + 1,
+                children: vec![],
+                overflows: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn interior_page_children() {
+        let mut page = vec![0; 512];
+        page[..12].copy_from_slice(&[
+            PAGE_INTERIOR_TABLE_ID,
+            0,
+            0,
+            0,
+            1,
+            0x01,
+            0xfb,
+            0,
+            0,
+            0,
+            0,
+            9,
+        ]);
+        page[12..14].copy_from_slice(&[0x01, 0xfb]);
+        page[507..512].copy_from_slice(&[0, 0, 0, 4, 10]);
+
+        let stats = analyze_page(&page, 3).unwrap();
+        assert_eq!(stats.children, vec![4, 9]);
+        assert_eq!(stats.payload_bytes, 0);
+    }
+
+    #[test]
+    fn overflowing_payload() {
+        assert_eq!(local_payload_size(4096, true, 100), 100);
+        assert!(local_payload_size(4096, true, 10_000) < 4096);
+        assert!(local_payload_size(4096, false, 2000) < 2000);
+    }
+}
@@ -0,0 +1,1203 @@
+//! Modification of the b-trees of a database being written. Pages are rewritten whole,
+//! with their cells packed at the end, rather than updated in place. A page that
+//! overflows is split, and one left underfull by a deletion is merged with a sibling
+//! or rebalanced against it, so that like in SQLite, all the leaves stay at the same
+//! depth and only the root page can be left without cells.
+
+use std::{cmp::Ordering, ops::Range};
+
+use anyhow::{Context, bail, ensure};
+
+use crate::{
+    cursor,
+    engine::KeyOrder,
+    page::{self, DbHeader, PageType, TextEncoding},
+    pager::{self, HEADER_SIZE, read_be_double_at, read_payload_size_at, read_varint_at, varint},
+    ptrmap::{PtrmapEntry, PtrmapKind},
+    value::OwnedValue,
+    writer::PageWriter,
+};
+
+const TABLE_LEAF: u8 = 0x0d;
+const TABLE_INTERIOR: u8 = 0x05;
+const INDEX_LEAF: u8 = 0x0a;
+const INDEX_INTERIOR: u8 = 0x02;
+
+/// The fraction of its page a node must fill after a deletion not to be rebalanced
+/// against a sibling.
+const MIN_FILL_DIVISOR: usize = 3;
+
+/// A b-tree of the database being written, by its root page.
+#[derive(Debug, Clone)]
+pub struct Btree {
+    root: usize,
+    /// How the columns of the keys of an index b-tree are ordered. The columns past
+    /// them, like the rowid ending the keys, are in ascending binary order.
+    orders: Option<Vec<KeyOrder>>,
+}
+
+/// The cells of a page, as stored.
+#[derive(Debug, Clone)]
+struct Node {
+    page_type: u8,
+    cells: Vec<Vec<u8>>,
+    right_child: Option<u32>,
+}
+
+/// The way from the root to a node: the pages above it, with the position of the
+/// child taken in each of them, the rightmost child being past the last cell.
+type Path = Vec<(usize, usize)>;
+
+/// Where a search ended: the path to the node, its page, the node, and the position
+/// of the first cell not before the target, with whether it matches the target.
+struct Position {
+    path: Path,
+    page: usize,
+    node: Node,
+    cell: usize,
+    found: bool,
+    /// Whether the node is the last one of its level.
+    rightmost: bool,
+}
+
+/// How the cells of a node being stored changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Added,
+    /// A cell was added after all the others of the b-tree, as when rows are inserted
+    /// in rowid order. A node overflowing then splits into full pages rather than
+    /// balanced ones, the next cells going to the last page.
+    Appended,
+    /// Cells were removed or replaced, which may have left the node underfull.
+    Removed,
+}
+
+impl Btree {
+    pub fn table(root: usize) -> Self {
+        Self { root, orders: None }
+    }
+
+    pub fn index(root: usize, orders: Vec<KeyOrder>) -> Self {
+        Self {
+            root,
+            orders: Some(orders),
+        }
+    }
+
+    /// The record of the row with `rowid`.
+    pub fn row(&self, w: &mut PageWriter, rowid: i64) -> anyhow::Result<Option<Vec<u8>>> {
+        let position = self.seek_rowid(w, rowid)?;
+        if !position.found {
+            return Ok(None);
+        }
+        let cell = &position.node.cells[position.cell];
+        self.payload(w, TABLE_LEAF, cell).map(Some)
+    }
+
+    /// The first row with a rowid not before `rowid`, with its record.
+    pub fn next_row(
+        &self,
+        w: &mut PageWriter,
+        mut rowid: i64,
+    ) -> anyhow::Result<Option<(i64, Vec<u8>)>> {
+        loop {
+            let position = self.seek_rowid(w, rowid)?;
+            if let Some(cell) = position.node.cells.get(position.cell) {
+                let record = self.payload(w, TABLE_LEAF, cell)?;
+                return Ok(Some((cell_rowid(TABLE_LEAF, cell)?, record)));
+            }
+            // The leaf ends before `rowid`: the next row is past the largest rowid of
+            // the deepest subtree followed by another one.
+            let mut next = None;
+            for &(page, position) in position.path.iter().rev() {
+                let node = self.read_node(w, page)?;
+                if let Some(cell) = node.cells.get(position) {
+                    next = Some(cell_rowid(TABLE_INTERIOR, cell)?);
+                    break;
+                }
+            }
+            match next.and_then(|key| key.checked_add(1)) {
+                Some(next) => rowid = next,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Frees all the pages of the b-tree, its root and overflow pages included.
+    pub fn destroy(&self, w: &mut PageWriter) -> anyhow::Result<()> {
+        let mut pages = vec![self.root];
+        while let Some(page) = pages.pop() {
+            let node = self.read_node(w, page)?;
+            for cell in &node.cells {
+                if is_interior(node.page_type) {
+                    pages.push(child_pointer(cell));
+                }
+                self.free_overflow(w, node.page_type, cell)?;
+            }
+            pages.extend(node.right_child.map(|child| child as usize));
+            w.free(page)?;
+        }
+        Ok(())
+    }
+
+    /// The largest rowid of the table, if it has rows.
+    pub fn last_rowid(&self, w: &mut PageWriter) -> anyhow::Result<Option<i64>> {
+        let mut page = self.root;
+        for _ in 0..cursor::MAX_BTREE_DEPTH {
+            let node = self.read_node(w, page)?;
+            match node.right_child {
+                Some(child) => page = child as usize,
+                None => {
+                    return node
+                        .cells
+                        .last()
+                        .map(|cell| cell_rowid(TABLE_LEAF, cell))
+                        .transpose();
+                }
+            }
+        }
+        bail!("b-tree {} is too deep, it must be corrupted", self.root)
+    }
+
+    /// Stores the row with `rowid`, replacing the one with the same rowid if any.
+    pub fn insert_row(&self, w: &mut PageWriter, rowid: i64, record: &[u8]) -> anyhow::Result<()> {
+        let Position {
+            path,
+            page,
+            mut node,
+            cell,
+            found,
+            rightmost,
+        } = self.seek_rowid(w, rowid)?;
+        let new_cell = self.new_cell(w, TABLE_LEAF, &varint(rowid), record)?;
+        let change = if found {
+            let old = std::mem::replace(&mut node.cells[cell], new_cell);
+            self.free_overflow(w, TABLE_LEAF, &old)?;
+            Change::Removed
+        } else {
+            node.cells.insert(cell, new_cell);
+            appended(rightmost, cell, &node)
+        };
+        self.store(w, &path, page, node, change)
+    }
+
+    /// Deletes the row with `rowid`, returning whether there was one.
+    pub fn delete_row(&self, w: &mut PageWriter, rowid: i64) -> anyhow::Result<bool> {
+        let Position {
+            path,
+            page,
+            mut node,
+            cell,
+            found,
+            ..
+        } = self.seek_rowid(w, rowid)?;
+        if !found {
+            return Ok(false);
+        }
+        let old = node.cells.remove(cell);
+        self.free_overflow(w, TABLE_LEAF, &old)?;
+        self.store(w, &path, page, node, Change::Removed)?;
+        Ok(true)
+    }
+
+    /// The first key of an index starting with the values of `prefix`.
+    pub fn find_key(
+        &self,
+        w: &mut PageWriter,
+        prefix: &[OwnedValue],
+    ) -> anyhow::Result<Option<Vec<OwnedValue>>> {
+        let position = self.seek_key(w, prefix)?;
+        if !position.found {
+            return Ok(None);
+        }
+        let cell = &position.node.cells[position.cell];
+        self.key(w, position.node.page_type, cell).map(Some)
+    }
+
+    /// Adds `key` to an index. Keys end with the rowid, so they are unique.
+    pub fn insert_key(&self, w: &mut PageWriter, key: &[OwnedValue]) -> anyhow::Result<()> {
+        let Position {
+            path,
+            page,
+            mut node,
+            cell,
+            found,
+            rightmost,
+        } = self.seek_key(w, key)?;
+        ensure!(!found, "the key is already in index b-tree {}", self.root);
+        let record = encode_record(key, &w.header());
+        let new_cell = self.new_cell(w, INDEX_LEAF, &[], &record)?;
+        node.cells.insert(cell, new_cell);
+        let change = appended(rightmost, cell, &node);
+        self.store(w, &path, page, node, change)
+    }
+
+    /// Removes `key` from an index, returning whether it was there.
+    pub fn delete_key(&self, w: &mut PageWriter, key: &[OwnedValue]) -> anyhow::Result<bool> {
+        let Position {
+            mut path,
+            page,
+            mut node,
+            cell,
+            found,
+            ..
+        } = self.seek_key(w, key)?;
+        if !found {
+            return Ok(false);
+        }
+        if node.page_type == INDEX_LEAF {
+            let old = node.cells.remove(cell);
+            self.free_overflow(w, INDEX_LEAF, &old)?;
+            self.store(w, &path, page, node, Change::Removed)?;
+            return Ok(true);
+        }
+
+        // A key of an interior page is replaced by the one preceding it, the last key
+        // of the rightmost leaf of its left child.
+        let old = node.cells[cell].clone();
+        let mut leaf = child_pointer(&old);
+        for _ in 0..cursor::MAX_BTREE_DEPTH {
+            let below = self.read_node(w, leaf)?;
+            match below.right_child {
+                Some(child) => leaf = child as usize,
+                None => break,
+            }
+        }
+        let mut leaf_node = self.read_node(w, leaf)?;
+        let predecessor = leaf_node
+            .cells
+            .pop()
+            .with_context(|| format!("empty leaf page {leaf}"))?;
+        self.write_node(w, leaf, &leaf_node)?;
+        self.free_overflow(w, INDEX_INTERIOR, &old)?;
+        node.cells[cell] = with_child(child_pointer(&old), &predecessor);
+        self.store(w, &path, page, node, Change::Added)?;
+
+        // Storing the interior page may have split it, so the leaf, which may now be
+        // underfull, is found again from the key that replaced the deleted one.
+        let predecessor_key = self.key(w, INDEX_LEAF, &predecessor)?;
+        let position = self.seek_key(w, &predecessor_key)?;
+        ensure!(position.found, "moved key missing from index {}", self.root);
+        path = position.path;
+        path.push((position.page, position.cell));
+        let mut page = child_pointer(&position.node.cells[position.cell]);
+        loop {
+            let node = self.read_node(w, page)?;
+            match node.right_child {
+                Some(child) => {
+                    path.push((page, node.cells.len()));
+                    page = child as usize;
+                }
+                None => {
+                    return self
+                        .store(w, &path, page, node, Change::Removed)
+                        .map(|()| true);
+                }
+            }
+            ensure!(
+                path.len() < cursor::MAX_BTREE_DEPTH,
+                "b-tree {} is too deep, it must be corrupted",
+                self.root
+            );
+        }
+    }
+
+    /// Descends to the leaf of a table b-tree where `rowid` is or belongs.
+    fn seek_rowid(&self, w: &mut PageWriter, rowid: i64) -> anyhow::Result<Position> {
+        let mut path = Path::new();
+        let mut page = self.root;
+        let mut rightmost = true;
+        loop {
+            ensure!(
+                path.len() < cursor::MAX_BTREE_DEPTH,
+                "b-tree {} is too deep, it must be corrupted",
+                self.root
+            );
+            let node = self.read_node(w, page)?;
+            ensure!(
+                matches!(node.page_type, TABLE_LEAF | TABLE_INTERIOR),
+                "page {page} is not a table b-tree page"
+            );
+            let rowids = (node.cells.iter())
+                .map(|cell| cell_rowid(node.page_type, cell))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let cell = rowids.partition_point(|&key| key < rowid);
+            if node.page_type == TABLE_LEAF {
+                let found = rowids.get(cell) == Some(&rowid);
+                return Ok(Position {
+                    path,
+                    page,
+                    node,
+                    cell,
+                    found,
+                    rightmost,
+                });
+            }
+            rightmost &= cell == node.cells.len();
+            path.push((page, cell));
+            page = node.child(cell)?;
+        }
+    }
+
+    /// Descends an index b-tree to the first key not before `target`, stopping at an
+    /// interior page holding a key starting with the values of `target`.
+    fn seek_key(&self, w: &mut PageWriter, target: &[OwnedValue]) -> anyhow::Result<Position> {
+        let mut path = Path::new();
+        let mut page = self.root;
+        let mut rightmost = true;
+        loop {
+            ensure!(
+                path.len() < cursor::MAX_BTREE_DEPTH,
+                "b-tree {} is too deep, it must be corrupted",
+                self.root
+            );
+            let node = self.read_node(w, page)?;
+            ensure!(
+                matches!(node.page_type, INDEX_LEAF | INDEX_INTERIOR),
+                "page {page} is not an index b-tree page"
+            );
+            let (mut low, mut high) = (0, node.cells.len());
+            let mut found = false;
+            while low < high {
+                let middle = (low + high) / 2;
+                let key = self.key(w, node.page_type, &node.cells[middle])?;
+                match self.compare(&key, target) {
+                    Ordering::Less => low = middle + 1,
+                    ordering => {
+                        found = ordering == Ordering::Equal;
+                        high = middle;
+                    }
+                }
+            }
+            if found || node.page_type == INDEX_LEAF {
+                return Ok(Position {
+                    path,
+                    page,
+                    node,
+                    cell: low,
+                    found,
+                    rightmost,
+                });
+            }
+            rightmost &= low == node.cells.len();
+            path.push((page, low));
+            page = node.child(low)?;
+        }
+    }
+
+    /// Compares keys on the columns they both have.
+    fn compare(&self, a: &[OwnedValue], b: &[OwnedValue]) -> Ordering {
+        let orders = self.orders.as_deref().unwrap_or_default();
+        for (i, (a, b)) in a.iter().zip(b).enumerate() {
+            let order = orders.get(i).copied().unwrap_or_default();
+            let ordering = order.collation.compare(a, b);
+            if ordering.is_ne() {
+                return if order.descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                };
+            }
+        }
+        Ordering::Equal
+    }
+
+    /// Writes `node` to `page`, at the end of `path`. A node overflowing its page is
+    /// split, and one left underfull by a deletion is merged with a sibling or
+    /// rebalanced against it. The parent then gets stored in turn.
+    fn store(
+        &self,
+        w: &mut PageWriter,
+        path: &[(usize, usize)],
+        page: usize,
+        node: Node,
+        change: Change,
+    ) -> anyhow::Result<()> {
+        let Some((&(parent_page, position), ancestors)) = path.split_last() else {
+            return self.store_root(w, page, node, change);
+        };
+        let capacity = capacity(w, page, node.page_type);
+        let size = node.size();
+        let underfull = change == Change::Removed && size < capacity / MIN_FILL_DIVISOR;
+        if size <= capacity && !underfull {
+            return self.write_node(w, page, &node);
+        }
+
+        let parent = self.read_node(w, parent_page)?;
+        let mut first = position;
+        let mut children = vec![(page, node)];
+        if size <= capacity {
+            if position > 0 {
+                first = position - 1;
+                let sibling = parent.child(first)?;
+                children.insert(0, (sibling, self.read_node(w, sibling)?));
+            } else if position < parent.cells.len() {
+                let sibling = parent.child(position + 1)?;
+                children.push((sibling, self.read_node(w, sibling)?));
+            } else {
+                // The only child of a root without cells, which it may replace.
+                self.write_node(w, page, &children[0].1)?;
+                return self.store(w, ancestors, parent_page, parent, Change::Removed);
+            }
+        }
+        let (parent, change) = self.rebalance(w, parent, first, children, change)?;
+        self.store(w, ancestors, parent_page, parent, change)
+    }
+
+    /// Stores the root, which keeps its page. Its content moves down to a new child
+    /// when it overflows, and the content of its only child moves up into it when it
+    /// has no cells left.
+    fn store_root(
+        &self,
+        w: &mut PageWriter,
+        page: usize,
+        node: Node,
+        change: Change,
+    ) -> anyhow::Result<()> {
+        if node.size() > capacity(w, page, node.page_type) {
+            let child = w.allocate()?;
+            let root = Node {
+                page_type: interior_type(node.page_type),
+                cells: Vec::new(),
+                right_child: Some(child as u32),
+            };
+            self.write_node(w, page, &root)?;
+            let (root, change) = self.rebalance(w, root, 0, vec![(child, node)], change)?;
+            return self.store(w, &[], page, root, change);
+        }
+
+        if node.cells.is_empty()
+            && let Some(child) = node.right_child
+        {
+            let child = child as usize;
+            let child_node = self.read_node(w, child)?;
+            // The first page may be too small, the database header taking its start.
+            if child_node.size() <= capacity(w, page, child_node.page_type) {
+                self.write_node(w, page, &child_node)?;
+                return w.free(child);
+            }
+        }
+        self.write_node(w, page, &node)
+    }
+
+    /// Redistributes the cells of `children`, consecutive children of `parent`
+    /// starting at position `first`, and of the dividers between them, between as
+    /// few pages as they fit in. Returns the parent, with the dividers of the pages, and
+    /// how it changed.
+    fn rebalance(
+        &self,
+        w: &mut PageWriter,
+        mut parent: Node,
+        first: usize,
+        children: Vec<(usize, Node)>,
+        change: Change,
+    ) -> anyhow::Result<(Node, Change)> {
+        let page_type = children[0].1.page_type;
+        let count = children.len();
+        let mut pages = Vec::with_capacity(count);
+        let mut combined = Node {
+            page_type,
+            cells: Vec::new(),
+            right_child: None,
+        };
+        for (i, (page, child)) in children.into_iter().enumerate() {
+            pages.push(page);
+            combined.cells.extend(child.cells);
+            combined.right_child = child.right_child;
+            let Some(divider) = parent.cells.get(first + i).filter(|_| i + 1 < count) else {
+                continue;
+            };
+            // The rowids dividing table leaves are copies, while the other dividers
+            // are cells that moved up.
+            match page_type {
+                TABLE_LEAF => {}
+                INDEX_LEAF => combined.cells.push(divider[4..].to_vec()),
+                _ => {
+                    let child = child.right_child.context("missing rightmost pointer")?;
+                    combined
+                        .cells
+                        .push(with_child(child as usize, &divider[4..]));
+                }
+            }
+        }
+
+        let sizes: Vec<usize> = combined.cells.iter().map(|cell| cell.len() + 2).collect();
+        let capacity = w.usable_size() - header_size(page_type);
+        let separated = page_type != TABLE_LEAF;
+        let groups = match change {
+            Change::Appended => fill(&sizes, capacity, separated, None),
+            _ => partition(&sizes, capacity, separated),
+        };
+
+        // The last group keeps the last page, which the pointer following the
+        // dividers points to.
+        let last_page = pages.pop().expect("at least one child");
+        let mut nodes = Vec::with_capacity(groups.len());
+        let mut dividers = Vec::with_capacity(groups.len() - 1);
+        for (i, range) in groups.iter().enumerate() {
+            let mut node = Node {
+                page_type,
+                cells: combined.cells[range.clone()].to_vec(),
+                right_child: combined.right_child,
+            };
+            let page = if i + 1 == groups.len() {
+                nodes.push((last_page, node));
+                break;
+            } else if i < pages.len() {
+                pages[i]
+            } else {
+                w.allocate()?
+            };
+            let divider = match page_type {
+                TABLE_LEAF => {
+                    let last = node.cells.last().context("empty page")?;
+                    varint(cell_rowid(TABLE_LEAF, last)?)
+                }
+                INDEX_LEAF => combined.cells[range.end].clone(),
+                _ => {
+                    let separator = &combined.cells[range.end];
+                    node.right_child = Some(child_pointer(separator) as u32);
+                    separator[4..].to_vec()
+                }
+            };
+            dividers.push(with_child(page, &divider));
+            nodes.push((page, node));
+        }
+        for &page in pages.iter().skip(groups.len() - 1) {
+            w.free(page)?;
+        }
+
+        let change = match groups.len() < count {
+            true => Change::Removed,
+            false if change == Change::Appended => Change::Appended,
+            false => Change::Added,
+        };
+        parent.cells.splice(first..first + count - 1, dividers);
+        for (page, node) in &nodes {
+            self.write_node(w, *page, node)?;
+        }
+        Ok((parent, change))
+    }
+
+    fn read_node(&self, w: &mut PageWriter, page: usize) -> anyhow::Result<Node> {
+        let usable = w.usable_size();
+        let offset = header_offset(page);
+        let buffer = w.page(page)?;
+        let page_type = buffer[offset];
+        ensure!(
+            matches!(
+                page_type,
+                TABLE_LEAF | TABLE_INTERIOR | INDEX_LEAF | INDEX_INTERIOR
+            ),
+            "page {page} is not a b-tree page"
+        );
+        let count = u16::from_be_bytes([buffer[offset + 3], buffer[offset + 4]]) as usize;
+        let right_child = is_interior(page_type).then(|| read_be_double_at(buffer, offset + 8));
+        let pointers = offset + header_size(page_type);
+        let mut cells = Vec::with_capacity(count);
+        for i in 0..count {
+            let pointer = pointers + 2 * i;
+            let start = buffer
+                .get(pointer..pointer + 2)
+                .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+                .with_context(|| format!("cell pointers past the end of page {page}"))?;
+            let cell = buffer
+                .get(start..usable)
+                .with_context(|| format!("cell {i} past the end of page {page}"))?;
+            let size = cell_size(page_type, cell, usable)
+                .with_context(|| format!("read cell {i} of page {page}"))?;
+            ensure!(size <= cell.len(), "cell {i} past the end of page {page}");
+            cells.push(cell[..size].to_vec());
+        }
+        Ok(Node {
+            page_type,
+            cells,
+            right_child,
+        })
+    }
+
+    fn write_node(&self, w: &mut PageWriter, page: usize, node: &Node) -> anyhow::Result<()> {
+        let usable = w.usable_size();
+        let offset = header_offset(page);
+        ensure!(
+            offset + header_size(node.page_type) + node.size() <= usable,
+            "the cells don't fit in page {page}"
+        );
+        let buffer = w.page_mut(page)?;
+        buffer[offset..].fill(0);
+        buffer[offset] = node.page_type;
+        buffer[offset + 3..offset + 5].copy_from_slice(&(node.cells.len() as u16).to_be_bytes());
+        if let Some(child) = node.right_child {
+            buffer[offset + 8..offset + 12].copy_from_slice(&child.to_be_bytes());
+        }
+        let pointers = offset + header_size(node.page_type);
+        let mut content = usable;
+        for (i, cell) in node.cells.iter().enumerate() {
+            content -= cell.len();
+            buffer[content..content + cell.len()].copy_from_slice(cell);
+            let pointer = pointers + 2 * i;
+            buffer[pointer..pointer + 2].copy_from_slice(&(content as u16).to_be_bytes());
+        }
+        // A content area starting at 65536 is stored as 0.
+        buffer[offset + 5..offset + 7].copy_from_slice(&(content as u16).to_be_bytes());
+
+        // The pointer-map of auto_vacuum databases records the parents of the children
+        // and overflow chains of the node, which may have moved from another page.
+        if w.header().largest_root_page == 0 {
+            return Ok(());
+        }
+        let btree = |parent| PtrmapEntry {
+            kind: PtrmapKind::Btree,
+            parent: parent as u32,
+        };
+        for cell in &node.cells {
+            if is_interior(node.page_type) {
+                w.set_ptrmap_entry(child_pointer(cell), btree(page))?;
+            }
+            if let Some(first) = overflow_page(node.page_type, cell, usable)? {
+                let entry = PtrmapEntry {
+                    kind: PtrmapKind::FirstOverflow,
+                    parent: page as u32,
+                };
+                w.set_ptrmap_entry(first, entry)?;
+            }
+        }
+        if let Some(child) = node.right_child {
+            w.set_ptrmap_entry(child as usize, btree(page))?;
+        }
+        Ok(())
+    }
+
+    /// A leaf cell of `payload`, following the `prefix` of table leaf cells, the
+    /// rowid. What doesn't fit in the cell goes to overflow pages.
+    fn new_cell(
+        &self,
+        w: &mut PageWriter,
+        page_type: u8,
+        prefix: &[u8],
+        payload: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let local = local_size(page_type, w.usable_size(), payload.len());
+        let mut cell = varint(payload.len() as i64);
+        cell.extend(prefix);
+        cell.extend(&payload[..local]);
+        if local < payload.len() {
+            let first = self.write_overflow(w, &payload[local..])?;
+            cell.extend((first as u32).to_be_bytes());
+        }
+        Ok(cell)
+    }
+
+    /// Writes `data` to a chain of overflow pages, returning the first one.
+    fn write_overflow(&self, w: &mut PageWriter, data: &[u8]) -> anyhow::Result<usize> {
+        let chunk_size = w.usable_size() - 4;
+        let pages = (data.chunks(chunk_size))
+            .map(|_| w.allocate())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        for (i, chunk) in data.chunks(chunk_size).enumerate() {
+            let next = pages.get(i + 1).copied().unwrap_or(0) as u32;
+            let page = w.page_mut(pages[i])?;
+            page[..4].copy_from_slice(&next.to_be_bytes());
+            page[4..4 + chunk.len()].copy_from_slice(chunk);
+        }
+        // The first page is recorded with the page of its cell, once it is written.
+        for pair in pages.windows(2) {
+            let entry = PtrmapEntry {
+                kind: PtrmapKind::Overflow,
+                parent: pair[0] as u32,
+            };
+            w.set_ptrmap_entry(pair[1], entry)?;
+        }
+        Ok(pages[0])
+    }
+
+    /// The whole payload of a cell, read from its overflow pages past the local part.
+    fn payload(&self, w: &mut PageWriter, page_type: u8, cell: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let usable = w.usable_size();
+        let (start, size) = payload_bounds(page_type, cell)?;
+        let local = local_size(page_type, usable, size);
+        let mut payload = cell
+            .get(start..start + local)
+            .context("payload past the end of its cell")?
+            .to_vec();
+        let mut next = overflow_page(page_type, cell, usable)?;
+        while payload.len() < size {
+            let page = next.context("overflow chain shorter than the payload")?;
+            let buffer = w.page(page)?;
+            let chunk = (size - payload.len()).min(usable - 4);
+            payload.extend(&buffer[4..4 + chunk]);
+            next = Some(read_be_double_at(buffer, 0) as usize).filter(|&next| next != 0);
+        }
+        Ok(payload)
+    }
+
+    /// The values of the key of an index cell.
+    fn key(
+        &self,
+        w: &mut PageWriter,
+        page_type: u8,
+        cell: &[u8],
+    ) -> anyhow::Result<Vec<OwnedValue>> {
+        let payload = self.payload(w, page_type, cell)?;
+        cursor::decode_record(&payload, w.header().text_encoding)
+    }
+
+    /// Frees the overflow pages of a cell being deleted.
+    fn free_overflow(&self, w: &mut PageWriter, page_type: u8, cell: &[u8]) -> anyhow::Result<()> {
+        let mut next = overflow_page(page_type, cell, w.usable_size())?;
+        while let Some(page) = next {
+            next = Some(read_be_double_at(w.page(page)?, 0) as usize).filter(|&next| next != 0);
+            w.free(page)?;
+        }
+        Ok(())
+    }
+}
+
+impl Node {
+    /// The bytes the cells take, with their pointers.
+    fn size(&self) -> usize {
+        self.cells.iter().map(|cell| cell.len() + 2).sum()
+    }
+
+    /// The child of an interior node at `position`, the rightmost one past the cells.
+    fn child(&self, position: usize) -> anyhow::Result<usize> {
+        match self.cells.get(position) {
+            Some(cell) => Ok(child_pointer(cell)),
+            None => Ok(self.right_child.context("missing rightmost pointer")? as usize),
+        }
+    }
+}
+
+/// How a node changed once a cell was inserted at position `cell`: appended when it is
+/// the last one of the `rightmost` node.
+fn appended(rightmost: bool, cell: usize, node: &Node) -> Change {
+    match rightmost && cell + 1 == node.cells.len() {
+        true => Change::Appended,
+        false => Change::Added,
+    }
+}
+
+/// Splits cells of the given sizes, pointers included, between the fewest pages of
+/// `capacity` bytes, as evenly as possible. With `separated`, the cell between two
+/// pages moves up to their parent rather than going to either of them.
+fn partition(sizes: &[usize], capacity: usize, separated: bool) -> Vec<Range<usize>> {
+    let filled = fill(sizes, capacity, separated, None);
+    if filled.len() == 1 {
+        return filled;
+    }
+    let target = sizes.iter().sum::<usize>().div_ceil(filled.len());
+    let balanced = fill(sizes, capacity, separated, Some(target));
+    if balanced.len() == filled.len() {
+        balanced
+    } else {
+        filled
+    }
+}
+
+/// Fills pages in turn with the cells, up to their capacity, or up to about `target`
+/// bytes.
+fn fill(
+    sizes: &[usize],
+    capacity: usize,
+    separated: bool,
+    target: Option<usize>,
+) -> Vec<Range<usize>> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut end = start;
+        let mut used = 0;
+        while let Some(&size) = sizes.get(end)
+            && (end == start
+                || used + size <= capacity && target.is_none_or(|t| used + size / 2 <= t))
+        {
+            used += size;
+            end += 1;
+        }
+        groups.push(start..end);
+        start = end + separated as usize;
+        if start >= sizes.len() {
+            break;
+        }
+    }
+    // The last cell can't move up, as the last page would be left empty: the one
+    // before it does instead.
+    if separated
+        && let Some(last) = groups.last_mut()
+        && last.end + 1 == sizes.len()
+    {
+        if last.len() > 1 {
+            last.end -= 1;
+            groups.push(sizes.len() - 1..sizes.len());
+        } else {
+            last.end += 1;
+        }
+    }
+    groups
+}
+
+/// The bytes of a page of `page_type` left for cells.
+fn capacity(w: &PageWriter, page: usize, page_type: u8) -> usize {
+    w.usable_size() - header_offset(page) - header_size(page_type)
+}
+
+/// Where the page header starts: the first page starts with the database header.
+fn header_offset(page: usize) -> usize {
+    if page == 1 { HEADER_SIZE } else { 0 }
+}
+
+fn is_interior(page_type: u8) -> bool {
+    matches!(page_type, TABLE_INTERIOR | INDEX_INTERIOR)
+}
+
+fn interior_type(page_type: u8) -> u8 {
+    match page_type {
+        TABLE_LEAF | TABLE_INTERIOR => TABLE_INTERIOR,
+        _ => INDEX_INTERIOR,
+    }
+}
+
+fn header_size(page_type: u8) -> usize {
+    if is_interior(page_type) { 12 } else { 8 }
+}
+
+fn child_pointer(cell: &[u8]) -> usize {
+    read_be_double_at(cell, 0) as usize
+}
+
+fn with_child(child: usize, cell: &[u8]) -> Vec<u8> {
+    let mut with_child = (child as u32).to_be_bytes().to_vec();
+    with_child.extend(cell);
+    with_child
+}
+
+/// The rowid of a cell of a table b-tree: the key of an interior cell, or the rowid
+/// following the payload size of a leaf cell.
+fn cell_rowid(page_type: u8, cell: &[u8]) -> anyhow::Result<i64> {
+    let offset = match page_type {
+        TABLE_INTERIOR => 4,
+        _ => read_varint_at(cell, 0)?.0 as usize,
+    };
+    Ok(read_varint_at(cell, offset)?.1)
+}
+
+/// Where the payload of a cell starts, and its whole size.
+fn payload_bounds(page_type: u8, cell: &[u8]) -> anyhow::Result<(usize, usize)> {
+    let start = if page_type == INDEX_INTERIOR { 4 } else { 0 };
+    let (n, size) = read_payload_size_at(cell, start)?;
+    let mut start = start + n as usize;
+    if page_type == TABLE_LEAF {
+        start += read_varint_at(cell, start)?.0 as usize;
+    }
+    Ok((start, size))
+}
+
+/// The first overflow page of a cell, following its local payload.
+fn overflow_page(page_type: u8, cell: &[u8], usable: usize) -> anyhow::Result<Option<usize>> {
+    if page_type == TABLE_INTERIOR {
+        return Ok(None);
+    }
+    let (start, size) = payload_bounds(page_type, cell)?;
+    let local = local_size(page_type, usable, size);
+    if local == size {
+        return Ok(None);
+    }
+    let pointer = pager::read_array_at::<4>(cell, start + local)?;
+    Ok(Some(u32::from_be_bytes(pointer) as usize))
+}
+
+fn cell_size(page_type: u8, cell: &[u8], usable: usize) -> anyhow::Result<usize> {
+    if page_type == TABLE_INTERIOR {
+        return Ok(4 + read_varint_at(cell, 4)?.0 as usize);
+    }
+    let (start, size) = payload_bounds(page_type, cell)?;
+    let local = local_size(page_type, usable, size);
+    Ok(start + local + if local < size { 4 } else { 0 })
+}
+
+fn local_size(page_type: u8, usable: usize, payload_size: usize) -> usize {
+    let page_type = match page_type {
+        TABLE_LEAF => PageType::TableLeaf,
+        _ => PageType::IndexLeaf,
+    };
+    page::local_payload_size(page_type, usable, payload_size)
+}
+
+/// Encodes the values as a record, with the smallest serial type for each integer.
+/// The serial types of 0 and 1 only exist from the fourth schema format on.
+pub fn encode_record(values: &[OwnedValue], header: &DbHeader) -> Vec<u8> {
+    let small_ints = header.schema_format >= 4;
+    let mut types = Vec::new();
+    let mut body = Vec::new();
+    for value in values {
+        let serial_type = match value {
+            OwnedValue::Null => 0,
+            OwnedValue::Int(0) if small_ints => 8,
+            OwnedValue::Int(1) if small_ints => 9,
+            OwnedValue::Int(i) => {
+                let (serial_type, size) = match *i {
+                    -0x80..0x80 => (1, 1),
+                    -0x8000..0x8000 => (2, 2),
+                    -0x80_0000..0x80_0000 => (3, 3),
+                    -0x8000_0000..0x8000_0000 => (4, 4),
+                    -0x8000_0000_0000..0x8000_0000_0000 => (5, 6),
+                    _ => (6, 8),
+                };
+                body.extend(&i.to_be_bytes()[8 - size..]);
+                serial_type
+            }
+            OwnedValue::Float(f) => {
+                body.extend(f.to_be_bytes());
+                7
+            }
+            OwnedValue::Blob(b) => {
+                body.extend(b.iter());
+                12 + 2 * b.len() as i64
+            }
+            OwnedValue::String(s) => {
+                let start = body.len();
+                match header.text_encoding {
+                    TextEncoding::Utf8 => body.extend(s.as_bytes()),
+                    TextEncoding::Utf16Le => {
+                        body.extend(s.encode_utf16().flat_map(u16::to_le_bytes))
+                    }
+                    TextEncoding::Utf16Be => {
+                        body.extend(s.encode_utf16().flat_map(u16::to_be_bytes))
+                    }
+                }
+                13 + 2 * (body.len() - start) as i64
+            }
+        };
+        types.extend(varint(serial_type));
+    }
+
+    // The header size counts its own varint, which may take one more byte.
+    let mut header_size = types.len() + 1;
+    if varint(header_size as i64).len() > 1 {
+        header_size += 1;
+    }
+    [varint(header_size as i64), types, body].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{
+        db::Db,
+        testing::{database_file, open_database, schema_cell, schema_database, text, write_leaf},
+        value::SendValue,
+    };
+
+    fn rows(db: &Db, sql: &str) -> Vec<Vec<SendValue>> {
+        let mut query = db.query(sql).unwrap();
+        let mut rows = Vec::new();
+        while let Some(row) = query.next_row().unwrap() {
+            rows.push(row.iter().map(SendValue::from).collect());
+        }
+        rows
+    }
+
+    fn string(s: String) -> OwnedValue {
+        OwnedValue::String(Rc::new(s))
+    }
+
+    /// A value of `i`, long enough for some of the rows to overflow.
+    fn value(i: i64) -> String {
+        format!("{i:04}").repeat(if i % 7 == 0 { 200 } else { 3 })
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let db = schema_database(
+            "btree-split-merge",
+            &[
+                ("table", "t", "t", "CREATE TABLE t(a)"),
+                ("index", "i", "t", "CREATE INDEX i ON t(a DESC)"),
+            ],
+        );
+        let table = Btree::table(2);
+        let index = Btree::index(
+            3,
+            vec![KeyOrder {
+                descending: true,
+                ..KeyOrder::default()
+            }],
+        );
+        let ids: Vec<i64> = (0..300).map(|i| i * 37 % 300).collect();
+        db.write(|w| {
+            for &i in &ids {
+                let values = [string(value(i))];
+                table.insert_row(w, i, &encode_record(&values, &w.header()))?;
+                index.insert_key(w, &[string(value(i)), OwnedValue::Int(i)])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(rows(&db, "PRAGMA integrity_check"), [[text("ok")]]);
+        assert_eq!(
+            rows(&db, "SELECT rowid, a FROM t"),
+            (0..300)
+                .map(|i| vec![SendValue::Int(i), text(&value(i))])
+                .collect::<Vec<_>>()
+        );
+        assert!(db.pager().page_count().unwrap() > 20);
+        db.write(|w| {
+            assert_eq!(
+                table.next_row(w, i64::MIN)?.map(|(rowid, _)| rowid),
+                Some(0)
+            );
+            assert_eq!(table.next_row(w, 150)?.map(|(rowid, _)| rowid), Some(150));
+            assert_eq!(table.next_row(w, 300)?, None);
+            let mut rowids = Vec::new();
+            let mut next = 0;
+            while let Some((rowid, record)) = table.next_row(w, next)? {
+                assert_eq!(record, encode_record(&[string(value(rowid))], &w.header()));
+                rowids.push(rowid);
+                next = rowid + 1;
+            }
+            assert_eq!(rowids, (0..300).collect::<Vec<_>>());
+            Ok(())
+        })
+        .unwrap();
+
+        // Deleting all but a few rows merges the pages back.
+        db.write(|w| {
+            for &i in ids.iter().filter(|&&i| i % 50 != 0) {
+                assert!(table.delete_row(w, i)?);
+                assert!(index.delete_key(w, &[string(value(i)), OwnedValue::Int(i)])?);
+            }
+            assert!(!table.delete_row(w, 1)?);
+            assert_eq!(table.last_rowid(w)?, Some(250));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(rows(&db, "PRAGMA integrity_check"), [[text("ok")]]);
+        let remaining = |order: &str| -> Vec<Vec<SendValue>> {
+            let mut ids: Vec<i64> = (0..6).map(|i| i * 50).collect();
+            if order == "DESC" {
+                ids.reverse();
+            }
+            ids.into_iter().map(|i| vec![text(&value(i))]).collect()
+        };
+        assert_eq!(rows(&db, "SELECT a FROM t"), remaining("ASC"));
+        let mut scanner = db.scanner(3);
+        let mut keys = Vec::new();
+        while let Some(mut cursor) = scanner.next_record().unwrap() {
+            keys.push(vec![SendValue::from(
+                &cursor.owned_field(0).unwrap().unwrap(),
+            )]);
+        }
+        assert_eq!(keys, remaining("DESC"));
+        assert!(db.free_page_count().unwrap() > 20);
+    }
+
+    #[test]
+    fn auto_vacuum() {
+        // The second page is the first pointer-map page, recording the root pages of
+        // the table and its index, which follow it.
+        const PAGE_SIZE: usize = 512;
+        let mut file = database_file(PAGE_SIZE, 4);
+        file[52..56].copy_from_slice(&4u32.to_be_bytes());
+        let cells = [
+            schema_cell(1, "table", "t", "t", 3, "CREATE TABLE t(a)"),
+            schema_cell(2, "index", "i", "t", 4, "CREATE INDEX i ON t(a)"),
+        ];
+        write_leaf(&mut file[..PAGE_SIZE], 100, TABLE_LEAF, &cells);
+        file[PAGE_SIZE] = PtrmapKind::RootPage.id();
+        file[PAGE_SIZE + 5] = PtrmapKind::RootPage.id();
+        write_leaf(&mut file[2 * PAGE_SIZE..], 0, TABLE_LEAF, &[]);
+        write_leaf(&mut file[3 * PAGE_SIZE..], 0, INDEX_LEAF, &[]);
+        let db = open_database("btree-auto-vacuum", &file);
+
+        let table = Btree::table(3);
+        let index = Btree::index(4, vec![KeyOrder::default()]);
+        let ids: Vec<i64> = (0..300).map(|i| i * 37 % 300).collect();
+        db.write(|w| {
+            for &i in &ids {
+                let values = [string(value(i))];
+                table.insert_row(w, i, &encode_record(&values, &w.header()))?;
+                index.insert_key(w, &[string(value(i)), OwnedValue::Int(i)])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        // The pages span several pointer-map pages.
+        assert!(db.pager().page_count().unwrap() > 2 * PAGE_SIZE / 5);
+        assert_eq!(rows(&db, "PRAGMA integrity_check"), [[text("ok")]]);
+
+        db.write(|w| {
+            for &i in ids.iter().filter(|&&i| i % 50 != 0) {
+                assert!(table.delete_row(w, i)?);
+                assert!(index.delete_key(w, &[string(value(i)), OwnedValue::Int(i)])?);
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(rows(&db, "PRAGMA integrity_check"), [[text("ok")]]);
+        assert_eq!(rows(&db, "SELECT count(*) FROM t"), [[SendValue::Int(6)]]);
+    }
+
+    #[test]
+    fn appending_fills_pages() {
+        // The same rows, inserted in rowid order or in reverse, with their index keys.
+        let page_count = |name, ascending| {
+            let db = schema_database(
+                name,
+                &[
+                    ("table", "t", "t", "CREATE TABLE t(a)"),
+                    ("index", "i", "t", "CREATE INDEX i ON t(a)"),
+                ],
+            );
+            let table = Btree::table(2);
+            let index = Btree::index(3, vec![KeyOrder::default()]);
+            db.write(|w| {
+                for i in 0..500 {
+                    let i = if ascending { i } else { 499 - i };
+                    let values = [OwnedValue::Int(i * 1000)];
+                    table.insert_row(w, i, &encode_record(&values, &w.header()))?;
+                    index.insert_key(w, &[OwnedValue::Int(i * 1000), OwnedValue::Int(i)])?;
+                }
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(rows(&db, "PRAGMA integrity_check"), [[text("ok")]]);
+            db.pager().page_count().unwrap()
+        };
+        let appended = page_count("btree-appended", true);
+        let prepended = page_count("btree-prepended", false);
+        assert!(
+            appended * 3 < prepended * 2,
+            "{appended} and {prepended} pages"
+        );
+    }
+
+    #[test]
+    fn records() {
+        let header = DbHeader {
+            schema_format: 4,
+            ..DbHeader::default()
+        };
+        let values = [
+            OwnedValue::Null,
+            OwnedValue::Int(0),
+            OwnedValue::Int(1),
+            OwnedValue::Int(-200),
+            OwnedValue::Int(1 << 40),
+            OwnedValue::Float(0.5),
+            string("é".to_string()),
+            OwnedValue::Blob(Rc::new(vec![1, 2])),
+        ];
+        let record = encode_record(&values, &header);
+        assert_eq!(record[..9], [9, 0, 8, 9, 2, 5, 7, 17, 16]);
+        assert_eq!(
+            cursor::decode_record(&record, header.text_encoding).unwrap(),
+            values
+        );
+
+        // Before the fourth format, 0 and 1 are stored like the other integers.
+        let record = encode_record(&values[1..3], &DbHeader::default());
+        assert_eq!(record, [3, 1, 1, 0, 1]);
+    }
+}
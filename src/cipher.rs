@@ -0,0 +1,273 @@
+use aes::Aes256;
+use anyhow::{Context, bail, ensure};
+use cbc::cipher::{BlockDecryptMut, KeyIvInit, block_padding::NoPadding};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::Sha512;
+
+use crate::pager::HEADER_PREFIX;
+
+pub const SALT_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+const IV_SIZE: usize = 16;
+const BLOCK_SIZE: usize = 16;
+const HMAC_SALT_MASK: u8 = 0x3a;
+const HMAC_KDF_ITERATIONS: u32 = 2;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HmacAlgorithm {
+    Sha1,
+    Sha512,
+}
+
+impl HmacAlgorithm {
+    fn output_size(&self) -> usize {
+        match self {
+            HmacAlgorithm::Sha1 => 20,
+            HmacAlgorithm::Sha512 => 64,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CipherSettings {
+    pub page_size: u32,
+    pub kdf_iterations: u32,
+    pub algorithm: HmacAlgorithm,
+}
+
+impl CipherSettings {
+    pub fn sqlcipher_v4() -> Self {
+        Self {
+            page_size: 4096,
+            kdf_iterations: 256_000,
+            algorithm: HmacAlgorithm::Sha512,
+        }
+    }
+
+    pub fn sqlcipher_v3() -> Self {
+        Self {
+            page_size: 1024,
+            kdf_iterations: 64_000,
+            algorithm: HmacAlgorithm::Sha1,
+        }
+    }
+
+    pub fn compat(version: u8) -> anyhow::Result<Self> {
+        match version {
+            3 => Ok(Self::sqlcipher_v3()),
+            4 => Ok(Self::sqlcipher_v4()),
+            v => bail!("unsupported sqlcipher compatibility version: {v}"),
+        }
+    }
+
+    /// Bytes reserved at the end of every page for the IV and the HMAC.
+    pub fn reserved_size(&self) -> usize {
+        (IV_SIZE + self.algorithm.output_size()).next_multiple_of(BLOCK_SIZE)
+    }
+}
+
+impl Default for CipherSettings {
+    fn default() -> Self {
+        Self::sqlcipher_v4()
+    }
+}
+
+pub struct Cipher {
+    key: [u8; KEY_SIZE],
+    hmac_key: [u8; KEY_SIZE],
+    settings: CipherSettings,
+}
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cipher")
+            .field("settings", &self.settings)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Cipher {
+    /// Derives the page key from a passphrase, or uses it directly when given
+    /// as a raw `x'<64 hex digits>'` key like SQLCipher does.
+    pub fn new(passphrase: &str, salt: &[u8; SALT_SIZE], settings: CipherSettings) -> Self {
+        let key = match parse_raw_key(passphrase) {
+            Some(key) => key,
+            None => derive_key(
+                settings.algorithm,
+                passphrase.as_bytes(),
+                salt,
+                settings.kdf_iterations,
+            ),
+        };
+
+        let hmac_salt = salt.map(|b| b ^ HMAC_SALT_MASK);
+        let hmac_key = derive_key(settings.algorithm, &key, &hmac_salt, HMAC_KDF_ITERATIONS);
+
+        Self {
+            key,
+            hmac_key,
+            settings,
+        }
+    }
+
+    /// Authenticates and decrypts a full page in place. The first page starts with the
+    /// salt instead of the header prefix, which is restored after decryption.
+    pub fn decrypt_page(&self, page_num: usize, page: &mut [u8]) -> anyhow::Result<()> {
+        ensure!(
+            page.len() == self.settings.page_size as usize,
+            "invalid encrypted page size: {}",
+            page.len()
+        );
+
+        let offset = if page_num == 1 { SALT_SIZE } else { 0 };
+        let data_end = page.len() - self.settings.reserved_size();
+        let iv_end = data_end + IV_SIZE;
+        let hmac_end = iv_end + self.settings.algorithm.output_size();
+
+        self.verify_hmac(page_num, &page[offset..iv_end], &page[iv_end..hmac_end])
+            .with_context(|| format!("hmac check failed for page {page_num}"))?;
+
+        let iv: [u8; IV_SIZE] = page[data_end..iv_end].try_into().unwrap();
+        cbc::Decryptor::<Aes256>::new(&self.key.into(), &iv.into())
+            .decrypt_padded_mut::<NoPadding>(&mut page[offset..data_end])
+            .map_err(|_| anyhow::anyhow!("decrypt page {page_num}"))?;
+
+        if page_num == 1 {
+            page[..SALT_SIZE].copy_from_slice(HEADER_PREFIX);
+        }
+
+        Ok(())
+    }
+
+    fn verify_hmac(&self, page_num: usize, data: &[u8], expected: &[u8]) -> anyhow::Result<()> {
+        let page_num = (page_num as u32).to_le_bytes();
+        match self.settings.algorithm {
+            HmacAlgorithm::Sha1 => {
+                let mut mac = Hmac::<Sha1>::new_from_slice(&self.hmac_key)?;
+                mac.update(data);
+                mac.update(&page_num);
+                mac.verify_slice(expected)?;
+            }
+            HmacAlgorithm::Sha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(&self.hmac_key)?;
+                mac.update(data);
+                mac.update(&page_num);
+                mac.verify_slice(expected)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn derive_key(
+    algorithm: HmacAlgorithm,
+    password: &[u8],
+    salt: &[u8],
+    iterations: u32,
+) -> [u8; KEY_SIZE] {
+    let mut key = [0; KEY_SIZE];
+    match algorithm {
+        HmacAlgorithm::Sha1 => pbkdf2::pbkdf2_hmac::<Sha1>(password, salt, iterations, &mut key),
+        HmacAlgorithm::Sha512 => {
+            pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, iterations, &mut key)
+        }
+    }
+    key
+}
+
+fn parse_raw_key(passphrase: &str) -> Option<[u8; KEY_SIZE]> {
+    let hex = passphrase.strip_prefix("x'")?.strip_suffix('\'')?;
+    if hex.len() != KEY_SIZE * 2 {
+        return None;
+    }
+
+    let mut key = [0; KEY_SIZE];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use cbc::cipher::BlockEncryptMut;
+
+    use super::*;
+
+    const SALT: [u8; SALT_SIZE] = *b"0123456789abcdef";
+
+    fn settings() -> CipherSettings {
+        CipherSettings {
+            page_size: 512,
+            kdf_iterations: 10,
+            algorithm: HmacAlgorithm::Sha512,
+        }
+    }
+
+    fn encrypt_page(cipher: &Cipher, page_num: usize, plain: &[u8]) -> Vec<u8> {
+        let mut page = plain.to_vec();
+        let offset = if page_num == 1 { SALT_SIZE } else { 0 };
+        let data_end = page.len() - cipher.settings.reserved_size();
+        let iv = [7; IV_SIZE];
+
+        cbc::Encryptor::<Aes256>::new(&cipher.key.into(), &iv.into())
+            .encrypt_padded_mut::<NoPadding>(&mut page[offset..data_end], data_end - offset)
+            .unwrap();
+        page[data_end..data_end + IV_SIZE].copy_from_slice(&iv);
+        if page_num == 1 {
+            page[..SALT_SIZE].copy_from_slice(&SALT);
+        }
+
+        let mut mac = Hmac::<Sha512>::new_from_slice(&cipher.hmac_key).unwrap();
+        mac.update(&page[offset..data_end + IV_SIZE]);
+        mac.update(&(page_num as u32).to_le_bytes());
+        let tag = mac.finalize().into_bytes();
+        page[data_end + IV_SIZE..].copy_from_slice(&tag);
+
+        page
+    }
+
+    fn plain_page(page_num: usize) -> Vec<u8> {
+        let mut page: Vec<u8> = (0..512).map(|i| (i % 251) as u8).collect();
+        if page_num == 1 {
+            page[..SALT_SIZE].copy_from_slice(HEADER_PREFIX);
+        }
+        page
+    }
+
+    #[test]
+    fn decrypt_round_trip() {
+        let cipher = Cipher::new("secret", &SALT, settings());
+
+        for page_num in [1, 2] {
+            let plain = plain_page(page_num);
+            let mut page = encrypt_page(&cipher, page_num, &plain);
+            cipher.decrypt_page(page_num, &mut page).unwrap();
+            let data_end = plain.len() - cipher.settings.reserved_size();
+            assert_eq!(&page[..data_end], &plain[..data_end]);
+        }
+    }
+
+    #[test]
+    fn wrong_key_fails_hmac() {
+        let cipher = Cipher::new("secret", &SALT, settings());
+        let mut page = encrypt_page(&cipher, 2, &plain_page(2));
+
+        let wrong = Cipher::new("not the secret", &SALT, settings());
+        assert!(wrong.decrypt_page(2, &mut page).is_err());
+    }
+
+    #[test]
+    fn raw_key() {
+        let raw = format!("x'{}'", "ab".repeat(KEY_SIZE));
+        let cipher = Cipher::new(&raw, &SALT, settings());
+        assert_eq!(cipher.key, [0xab; KEY_SIZE]);
+    }
+
+    #[test]
+    fn reserved_size() {
+        assert_eq!(CipherSettings::sqlcipher_v4().reserved_size(), 80);
+        assert_eq!(CipherSettings::sqlcipher_v3().reserved_size(), 48);
+    }
+}
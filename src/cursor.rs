@@ -1,13 +1,22 @@
-use std::{borrow::Cow, sync::Arc};
+use std::{
+    borrow::Cow,
+    ops::RangeInclusive,
+    sync::{Arc, OnceLock},
+};
 
-use anyhow::Context;
+use anyhow::{Context, bail, ensure};
 
 use crate::{
-    page::{Cell, Page, PageType},
-    pager::Pager,
+    db::InterruptCheck,
+    page::{Cell, IndexCell, Page, PageType, TableLeafCell, TextEncoding},
+    pager::{Pager, read_array_at},
     value::{OwnedValue, Value},
 };
 
+/// The depth of the deepest b-tree SQLite reads. Only a corrupted b-tree, whose pages
+/// point back to their ancestors, can be deeper.
+pub const MAX_BTREE_DEPTH: usize = 20;
+
 #[derive(Debug, Copy, Clone)]
 pub enum RecordFieldType {
     Null,
@@ -20,106 +29,167 @@ pub enum RecordFieldType {
     Float,
     Zero,
     One,
-    String(usize),
-    Blob(usize),
+    String(u32),
+    Blob(u32),
 }
 
+/// Offsets and sizes are stored as `u32` (records are at most 1 GiB) to keep the
+/// headers cached on pages compact.
 #[derive(Debug, Clone)]
 pub struct RecordField {
-    pub offset: usize,
+    pub offset: u32,
     pub field_type: RecordFieldType,
 }
 
 impl RecordField {
     pub fn end_offset(&self) -> usize {
+        let offset = self.offset as usize;
         let size = match self.field_type {
             RecordFieldType::Null => 0,
             RecordFieldType::I8 => 1,
             RecordFieldType::I16 => 2,
             RecordFieldType::I24 => 3,
             RecordFieldType::I32 => 4,
-            RecordFieldType::I48 => 5,
+            RecordFieldType::I48 => 6,
             RecordFieldType::I64 => 8,
             RecordFieldType::Float => 8,
             RecordFieldType::Zero => 0,
             RecordFieldType::One => 0,
-            RecordFieldType::String(size) | RecordFieldType::Blob(size) => size,
+            RecordFieldType::String(size) | RecordFieldType::Blob(size) => size as usize,
         };
 
-        self.offset + size
+        offset + size
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct RecordHeader {
-    pub fields: Vec<RecordField>,
-}
-
-fn parse_record_header(mut buffer: &[u8]) -> anyhow::Result<RecordHeader> {
-    let (varint_size, header_length) = crate::pager::read_varint_at(buffer, 0);
-    buffer = &buffer[varint_size as usize..header_length as usize];
-
-    let mut fields = Vec::new();
-    let mut current_offset = header_length as usize;
-
-    while !buffer.is_empty() {
-        let (discriminant_size, discriminant) = crate::pager::read_varint_at(buffer, 0);
-        buffer = &buffer[discriminant_size as usize..];
-
-        let (field_type, field_size) = match discriminant {
-            0 => (RecordFieldType::Null, 0),
-            1 => (RecordFieldType::I8, 1),
-            2 => (RecordFieldType::I16, 2),
-            3 => (RecordFieldType::I24, 3),
-            4 => (RecordFieldType::I32, 4),
-            5 => (RecordFieldType::I48, 6),
-            6 => (RecordFieldType::I64, 8),
-            7 => (RecordFieldType::Float, 8),
-            8 => (RecordFieldType::Zero, 0),
-            9 => (RecordFieldType::One, 0),
-            n if n >= 12 && n % 2 == 0 => {
-                let size = ((n - 12) / 2) as usize;
-                (RecordFieldType::Blob(size), size)
-            }
-            n if n >= 13 && n % 2 == 1 => {
-                let size = ((n - 13) / 2) as usize;
-                (RecordFieldType::String(size), size)
-            }
-            n => anyhow::bail!("unsupported field type: {}", n),
-        };
+    pub fields: Box<[RecordField]>,
+}
 
+pub fn parse_record_header(buffer: &[u8]) -> anyhow::Result<RecordHeader> {
+    let (varint_size, header_length) =
+        crate::pager::read_varint_at(buffer, 0).context("read record header length")?;
+    let header_length = usize::try_from(header_length)
+        .with_context(|| format!("invalid record header length: {header_length}"))?;
+    let header = buffer.get(..header_length).with_context(|| {
+        format!(
+            "record header of {header_length} bytes exceeds the {} bytes of the payload",
+            buffer.len()
+        )
+    })?;
+
+    // Serial types almost always fit in a single byte, which bounds the field count.
+    let mut fields = Vec::with_capacity(header_length.saturating_sub(varint_size as usize));
+    let mut position = varint_size as usize;
+    let mut current_offset = header_length as u32;
+
+    while position < header_length {
+        let (size, discriminant) = crate::pager::read_varint_at(header, position)
+            .with_context(|| format!("read type of field {}", fields.len()))?;
+        position += size as usize;
+
+        let (field_type, field_size) = serial_type(discriminant)?;
         fields.push(RecordField {
             offset: current_offset,
             field_type,
         });
+        current_offset = current_offset
+            .checked_add(field_size)
+            .context("record too large")?;
+    }
 
-        current_offset += field_size;
+    Ok(RecordHeader {
+        fields: fields.into_boxed_slice(),
+    })
+}
+
+#[inline]
+fn serial_type(discriminant: i64) -> anyhow::Result<(RecordFieldType, u32)> {
+    let field = match discriminant {
+        0 => (RecordFieldType::Null, 0),
+        1 => (RecordFieldType::I8, 1),
+        2 => (RecordFieldType::I16, 2),
+        3 => (RecordFieldType::I24, 3),
+        4 => (RecordFieldType::I32, 4),
+        5 => (RecordFieldType::I48, 6),
+        6 => (RecordFieldType::I64, 8),
+        7 => (RecordFieldType::Float, 8),
+        8 => (RecordFieldType::Zero, 0),
+        9 => (RecordFieldType::One, 0),
+        n if n >= 12 => {
+            let size = u32::try_from((n - 12) / 2).context("field too large")?;
+            if n % 2 == 0 {
+                (RecordFieldType::Blob(size), size)
+            } else {
+                (RecordFieldType::String(size), size)
+            }
+        }
+        n => anyhow::bail!("unsupported field type: {}", n),
+    };
+    Ok(field)
+}
+
+/// Record payload of a cursor, borrowed from the cached page until overflow pages have
+/// to be appended to it.
+#[derive(Debug)]
+enum Payload {
+    Local(Arc<[u8]>),
+    Extended(Vec<u8>),
+}
+
+impl Payload {
+    fn extend_from_slice(&mut self, data: &[u8]) {
+        match self {
+            Payload::Local(local) => {
+                let mut extended = Vec::with_capacity(local.len() + data.len());
+                extended.extend_from_slice(local);
+                extended.extend_from_slice(data);
+                *self = Payload::Extended(extended);
+            }
+            Payload::Extended(extended) => extended.extend_from_slice(data),
+        }
     }
+}
 
-    Ok(RecordHeader { fields })
+impl std::ops::Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Payload::Local(local) => local,
+            Payload::Extended(extended) => extended,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Cursor {
-    header: RecordHeader,
-    payload: Vec<u8>,
+    /// 0 for the keys of index b-trees, which have no rowid.
+    rowid: i64,
+    header: Arc<RecordHeader>,
+    payload: Payload,
     pager: Pager,
     next_overflow_page: Option<usize>,
 }
 
 impl Cursor {
+    pub fn rowid(&self) -> i64 {
+        self.rowid
+    }
+
     pub fn owned_field(&mut self, n: usize) -> anyhow::Result<Option<OwnedValue>> {
         Ok(self.field(n)?.map(Into::into))
     }
 
-    pub fn field(&mut self, n: usize) -> anyhow::Result<Option<Value>> {
+    pub fn field(&mut self, n: usize) -> anyhow::Result<Option<Value<'_>>> {
         let Some(record_field) = self.header.fields.get(n) else {
             return Ok(None);
         };
 
         let end_offset = record_field.end_offset();
 
-        if end_offset > (self.payload.len() - 1)
+        if end_offset >= self.payload.len()
             && let Some(overflow_page) = self.next_overflow_page
         {
             let overflow_size = end_offset.saturating_sub(self.payload.len());
@@ -130,79 +200,96 @@ impl Cursor {
             self.payload.extend_from_slice(&overflow_data);
         }
 
-        let value = match record_field.field_type {
-            RecordFieldType::Null => Some(Value::Null),
-            RecordFieldType::I8 => Some(Value::Int(read_i8_at(&self.payload, record_field.offset))),
-            RecordFieldType::I16 => {
-                Some(Value::Int(read_i16_at(&self.payload, record_field.offset)))
-            }
-            RecordFieldType::I24 => {
-                Some(Value::Int(read_i24_at(&self.payload, record_field.offset)))
-            }
-            RecordFieldType::I32 => {
-                Some(Value::Int(read_i32_at(&self.payload, record_field.offset)))
-            }
-            RecordFieldType::I48 => {
-                Some(Value::Int(read_i48_at(&self.payload, record_field.offset)))
-            }
-            RecordFieldType::I64 => {
-                Some(Value::Int(read_i64_at(&self.payload, record_field.offset)))
-            }
-            RecordFieldType::Float => Some(Value::Float(read_f64_at(
-                &self.payload,
-                record_field.offset,
-            ))),
-            RecordFieldType::String(length) => {
-                let value = std::str::from_utf8(
-                    &self.payload[record_field.offset..record_field.offset + length],
-                )
-                .expect("invalid utf8");
-                Some(Value::String(Cow::Borrowed(value)))
-            }
-            RecordFieldType::Blob(length) => {
-                let value = &self.payload[record_field.offset..record_field.offset + length];
-                Some(Value::Blob(Cow::Borrowed(value)))
-            }
-            RecordFieldType::One => Some(Value::Int(1)),
-            RecordFieldType::Zero => Some(Value::Int(0)),
-        };
-
-        Ok(value)
+        let encoding = self.pager.header().text_encoding;
+        field_value(&self.payload, record_field, encoding).map(Some)
     }
 }
 
-fn read_i8_at(input: &[u8], offset: usize) -> i64 {
-    input[offset] as i64
+/// The values of a record whose payload was read whole, overflow included.
+pub fn decode_record(payload: &[u8], encoding: TextEncoding) -> anyhow::Result<Vec<OwnedValue>> {
+    let header = parse_record_header(payload)?;
+    (header.fields.iter())
+        .map(|field| field_value(payload, field, encoding).map(OwnedValue::from))
+        .collect()
+}
+
+fn field_value<'p>(
+    payload: &'p [u8],
+    record_field: &RecordField,
+    encoding: TextEncoding,
+) -> anyhow::Result<Value<'p>> {
+    let offset = record_field.offset as usize;
+    Ok(match record_field.field_type {
+        RecordFieldType::Null => Value::Null,
+        RecordFieldType::I8 => Value::Int(read_i8_at(payload, offset)?),
+        RecordFieldType::I16 => Value::Int(read_i16_at(payload, offset)?),
+        RecordFieldType::I24 => Value::Int(read_i24_at(payload, offset)?),
+        RecordFieldType::I32 => Value::Int(read_i32_at(payload, offset)?),
+        RecordFieldType::I48 => Value::Int(read_i48_at(payload, offset)?),
+        RecordFieldType::I64 => Value::Int(read_i64_at(payload, offset)?),
+        RecordFieldType::Float => Value::Float(read_f64_at(payload, offset)?),
+        RecordFieldType::String(length) => {
+            Value::String(encoding.decode(field_bytes(payload, offset, length)?))
+        }
+        RecordFieldType::Blob(length) => {
+            Value::Blob(Cow::Borrowed(field_bytes(payload, offset, length)?))
+        }
+        RecordFieldType::One => Value::Int(1),
+        RecordFieldType::Zero => Value::Int(0),
+    })
+}
+
+fn read_i8_at(input: &[u8], offset: usize) -> anyhow::Result<i64> {
+    Ok(i8::from_be_bytes(read_array_at(input, offset)?).into())
+}
+
+fn read_i16_at(input: &[u8], offset: usize) -> anyhow::Result<i64> {
+    Ok(i16::from_be_bytes(read_array_at(input, offset)?).into())
 }
 
-fn read_i16_at(input: &[u8], offset: usize) -> i64 {
-    i16::from_be_bytes(input[offset..offset + 2].try_into().unwrap()) as i64
+fn read_i24_at(input: &[u8], offset: usize) -> anyhow::Result<i64> {
+    let [a, b, c] = read_array_at(input, offset)?;
+    // Shift the value into the top bytes so that the sign is extended on the way back.
+    Ok((i32::from_be_bytes([a, b, c, 0]) >> 8).into())
 }
 
-fn read_i24_at(input: &[u8], offset: usize) -> i64 {
-    (i32::from_be_bytes(input[offset..offset + 3].try_into().unwrap()) & 0x00FFFFFF) as i64
+fn read_i32_at(input: &[u8], offset: usize) -> anyhow::Result<i64> {
+    Ok(i32::from_be_bytes(read_array_at(input, offset)?).into())
 }
 
-fn read_i32_at(input: &[u8], offset: usize) -> i64 {
-    i32::from_be_bytes(input[offset..offset + 4].try_into().unwrap()) as i64
+fn read_i48_at(input: &[u8], offset: usize) -> anyhow::Result<i64> {
+    let mut bytes = [0; 8];
+    bytes[..6].copy_from_slice(&read_array_at::<6>(input, offset)?);
+    Ok(i64::from_be_bytes(bytes) >> 16)
 }
 
-fn read_i48_at(input: &[u8], offset: usize) -> i64 {
-    i64::from_be_bytes(input[offset..offset + 6].try_into().unwrap()) & 0x0000FFFFFFFFFFFF
+fn read_i64_at(input: &[u8], offset: usize) -> anyhow::Result<i64> {
+    read_array_at(input, offset).map(i64::from_be_bytes)
 }
 
-fn read_i64_at(input: &[u8], offset: usize) -> i64 {
-    i64::from_be_bytes(input[offset..offset + 8].try_into().unwrap())
+fn read_f64_at(input: &[u8], offset: usize) -> anyhow::Result<f64> {
+    read_array_at(input, offset).map(f64::from_be_bytes)
 }
 
-fn read_f64_at(input: &[u8], offset: usize) -> f64 {
-    f64::from_be_bytes(input[offset..offset + 8].try_into().unwrap())
+/// The `length` bytes of a string or blob field at `offset` into the record.
+fn field_bytes(payload: &[u8], offset: usize, length: u32) -> anyhow::Result<&[u8]> {
+    payload
+        .get(offset..offset + length as usize)
+        .with_context(|| {
+            format!(
+                "field of {length} bytes at offset {offset} exceeds the {} bytes of the record",
+                payload.len()
+            )
+        })
 }
 
 #[derive(Debug)]
 pub struct PositionedPage {
     pub page: Arc<Page>,
     pub cell: usize,
+    /// Whether the left child of the current cell, on an interior index page, was
+    /// scanned already.
+    pub left_scanned: bool,
 }
 
 impl PositionedPage {
@@ -212,9 +299,26 @@ impl PositionedPage {
         cell
     }
 
+    /// The left child of the current cell of an interior index page, unless it was
+    /// scanned already: the key of the cell comes after the keys of the child.
+    pub fn unscanned_left_child(&mut self) -> Option<u32> {
+        let Some(Cell::Index(IndexCell {
+            left_child_page: Some(child),
+            ..
+        })) = self.page.get(self.cell)
+        else {
+            return None;
+        };
+
+        self.left_scanned = !self.left_scanned;
+        self.left_scanned.then_some(*child)
+    }
+
     pub fn next_page(&mut self) -> Option<u32> {
-        if self.page.header.page_type == PageType::TableInterior
-            && self.cell == self.page.cells.len()
+        if matches!(
+            self.page.header.page_type,
+            PageType::TableInterior | PageType::IndexInterior
+        ) && self.cell == self.page.cells.len()
         {
             self.cell += 1;
             self.page.header.rightmost_pointer
@@ -229,6 +333,9 @@ pub struct Scanner {
     initial_page: usize,
     page_stack: Vec<PositionedPage>,
     pager: Pager,
+    /// The rowids the scan of a table b-tree is restricted to.
+    rowids: Option<RangeInclusive<i64>>,
+    interrupt: Option<InterruptCheck>,
 }
 
 impl Scanner {
@@ -237,18 +344,59 @@ impl Scanner {
             initial_page: page,
             page_stack: Vec::new(),
             pager,
+            rowids: None,
+            interrupt: None,
         }
     }
 
+    /// Fails the scan once the queries `interrupt` checks are interrupted.
+    pub fn with_interrupt(mut self, interrupt: InterruptCheck) -> Self {
+        self.interrupt = Some(interrupt);
+        self
+    }
+
+    /// Only reads the records of a table b-tree with rowids in `rowids`, starting from
+    /// the first of them rather than the first leaf.
+    pub fn with_rowids(mut self, rowids: RangeInclusive<i64>) -> Self {
+        self.rowids = Some(rowids);
+        self
+    }
+
+    pub fn rowids(&self) -> Option<&RangeInclusive<i64>> {
+        self.rowids.as_ref()
+    }
+
+    /// Restarts the scan from the first record.
+    pub fn rewind(&mut self) {
+        self.page_stack.clear();
+    }
+
     pub fn next_record(&mut self) -> anyhow::Result<Option<Cursor>> {
+        if let Some(interrupt) = &mut self.interrupt {
+            interrupt.check()?;
+        }
+
+        if let Some(rowids) = &self.rowids
+            && self.page_stack.is_empty()
+        {
+            self.seek_rowid(*rowids.start())?;
+        }
+
         loop {
             match self.next_elem() {
-                Ok(Some(ScannerElem::Cursor(cursor))) => return Ok(Some(cursor)),
+                Ok(Some(ScannerElem::Cursor(cursor))) => {
+                    return match &self.rowids {
+                        Some(rowids) if cursor.rowid() > *rowids.end() => Ok(None),
+                        _ => Ok(Some(cursor)),
+                    };
+                }
                 Ok(Some(ScannerElem::Page(page_num))) => {
+                    check_depth(self.page_stack.len(), self.initial_page)?;
                     let new_page = self.pager.read_page(page_num as usize)?.clone();
                     self.page_stack.push(PositionedPage {
                         page: new_page,
                         cell: 0,
+                        left_scanned: false,
                     });
                 }
                 Ok(None) if self.page_stack.len() > 1 => {
@@ -270,22 +418,186 @@ impl Scanner {
         if let Some(page) = page.next_page() {
             return Ok(Some(ScannerElem::Page(page)));
         }
+        if let Some(child) = page.unscanned_left_child() {
+            return Ok(Some(ScannerElem::Page(child)));
+        }
 
         let Some(cell) = page.next_cell() else {
             return Ok(None);
         };
 
         match cell {
-            Cell::TableLeaf(cell) => {
-                let header = parse_record_header(&cell.payload)?;
-                Ok(Some(ScannerElem::Cursor(Cursor {
-                    header,
-                    payload: cell.payload.clone(),
-                    pager,
-                    next_overflow_page: cell.first_overflow,
-                })))
-            }
+            Cell::TableLeaf(cell) => Ok(Some(ScannerElem::Cursor(leaf_cursor(cell, pager)?))),
             Cell::TableInterior(cell) => Ok(Some(ScannerElem::Page(cell.left_child_page))),
+            Cell::Index(cell) => Ok(Some(ScannerElem::Cursor(index_cursor(cell, pager)?))),
+        }
+    }
+
+    /// Reads the record of a table b-tree with `rowid`, descending from the root along
+    /// the keys of the interior pages. The position of the scan is left unchanged.
+    pub fn find_rowid(&self, rowid: i64) -> anyhow::Result<Option<Cursor>> {
+        let mut page = self.pager.read_page(self.initial_page)?;
+        let mut depth = 0;
+        loop {
+            check_depth(depth, self.initial_page)?;
+            depth += 1;
+            let child = match page.header.page_type {
+                PageType::TableInterior => {
+                    let next = page.cells.partition_point(
+                        |cell| matches!(cell, Cell::TableInterior(cell) if cell.key < rowid),
+                    );
+                    match page.cells.get(next) {
+                        Some(Cell::TableInterior(cell)) => cell.left_child_page,
+                        _ => page
+                            .header
+                            .rightmost_pointer
+                            .context("missing rightmost pointer")?,
+                    }
+                }
+                PageType::TableLeaf => {
+                    let found = page.cells.binary_search_by_key(&rowid, |cell| match cell {
+                        Cell::TableLeaf(cell) => cell.rowid,
+                        _ => i64::MIN,
+                    });
+                    return match found.map(|i| &page.cells[i]) {
+                        Ok(Cell::TableLeaf(cell)) => {
+                            Ok(Some(leaf_cursor(cell, self.pager.clone())?))
+                        }
+                        _ => Ok(None),
+                    };
+                }
+                PageType::IndexLeaf | PageType::IndexInterior => {
+                    bail!("page {} is not a table b-tree page", self.initial_page)
+                }
+            };
+            page = self.pager.read_page(child as usize)?;
+        }
+    }
+
+    /// Splits the rowids of a table b-tree into at most `n` ranges, each holding about
+    /// as many subtrees of the b-tree. The subtrees are those of the root page, or of
+    /// the levels below it while they're fewer than `n`.
+    pub fn rowid_partitions(&self, n: usize) -> anyhow::Result<Vec<RangeInclusive<i64>>> {
+        // The largest rowid of each subtree of the level but the last one.
+        let mut keys: Vec<i64> = Vec::new();
+        let mut pages = vec![self.initial_page];
+        while keys.len() + 1 < n {
+            let mut level_keys = Vec::new();
+            let mut children = Vec::new();
+            for (i, &number) in pages.iter().enumerate() {
+                let page = self.pager.read_page(number)?;
+                if page.header.page_type != PageType::TableInterior {
+                    return Ok(partition(&keys, n));
+                }
+                for cell in &page.cells {
+                    if let Cell::TableInterior(cell) = cell {
+                        level_keys.push(cell.key);
+                        children.push(cell.left_child_page as usize);
+                    }
+                }
+                children.push(
+                    page.header
+                        .rightmost_pointer
+                        .context("missing rightmost pointer")? as usize,
+                );
+                // The subtree of the page ends with the key of its parent.
+                level_keys.extend(keys.get(i));
+            }
+            keys = level_keys;
+            pages = children;
+        }
+        Ok(partition(&keys, n))
+    }
+
+    /// Positions the scan of a table b-tree on its first record with a rowid of at
+    /// least `rowid`.
+    pub fn seek_rowid(&mut self, rowid: i64) -> anyhow::Result<()> {
+        self.page_stack.clear();
+        let mut number = self.initial_page;
+        loop {
+            check_depth(self.page_stack.len(), self.initial_page)?;
+            let page = self.pager.read_page(number)?;
+            let position = page.cells.partition_point(|cell| match cell {
+                Cell::TableInterior(cell) => cell.key < rowid,
+                Cell::TableLeaf(cell) => cell.rowid < rowid,
+                Cell::Index(_) => false,
+            });
+            let child = match page.header.page_type {
+                PageType::TableInterior => match page.cells.get(position) {
+                    Some(Cell::TableInterior(cell)) => cell.left_child_page,
+                    _ => page
+                        .header
+                        .rightmost_pointer
+                        .context("missing rightmost pointer")?,
+                },
+                PageType::TableLeaf => {
+                    self.page_stack.push(PositionedPage {
+                        page,
+                        cell: position,
+                        left_scanned: false,
+                    });
+                    return Ok(());
+                }
+                PageType::IndexLeaf | PageType::IndexInterior => {
+                    bail!("page {number} is not a table b-tree page")
+                }
+            };
+
+            // Once done with the child, the scan moves on to the next cell, or to the
+            // rightmost child after the last one, unless it was the one descended.
+            self.page_stack.push(PositionedPage {
+                page,
+                cell: position + 1,
+                left_scanned: false,
+            });
+            number = child as usize;
+        }
+    }
+
+    /// Positions the scan of an index b-tree on its first key for which `before` is
+    /// false, `before` holding for a prefix of the keys in order.
+    pub fn seek(
+        &mut self,
+        mut before: impl FnMut(&mut Cursor) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<()> {
+        self.page_stack.clear();
+        let mut number = self.initial_page;
+        loop {
+            check_depth(self.page_stack.len(), self.initial_page)?;
+            let page = self.pager.read_page(number)?;
+            let (mut low, mut high) = (0, page.cells.len());
+            while low < high {
+                let middle = (low + high) / 2;
+                let Some(Cell::Index(cell)) = page.cells.get(middle) else {
+                    bail!("page {number} is not an index b-tree page");
+                };
+                if before(&mut index_cursor(cell, self.pager.clone())?)? {
+                    low = middle + 1;
+                } else {
+                    high = middle;
+                }
+            }
+
+            // The keys of the child come before the key of the cell, which the scan
+            // reads once done with them. Past the last cell, the child is the rightmost
+            // one, which the scan mustn't read again.
+            let child = match page.cells.get(low) {
+                Some(Cell::Index(cell)) => cell.left_child_page,
+                _ => page.header.rightmost_pointer,
+            };
+            let cell = match child {
+                Some(_) if low == page.cells.len() => low + 1,
+                _ => low,
+            };
+            self.page_stack.push(PositionedPage {
+                page,
+                cell,
+                left_scanned: child.is_some(),
+            });
+            match child {
+                Some(child) => number = child as usize,
+                None => return Ok(()),
+            }
         }
     }
 
@@ -296,13 +608,76 @@ impl Scanner {
                 Err(e) => return Err(e),
             };
 
-            self.page_stack.push(PositionedPage { page, cell: 0 });
+            self.page_stack.push(PositionedPage {
+                page,
+                cell: 0,
+                left_scanned: false,
+            });
         }
 
         Ok(self.page_stack.last_mut())
     }
 }
 
+/// Fails once a scan of the b-tree rooted at `root` already went `depth` levels down,
+/// rather than looping forever on a corrupted b-tree.
+fn check_depth(depth: usize, root: usize) -> anyhow::Result<()> {
+    ensure!(
+        depth < MAX_BTREE_DEPTH,
+        "b-tree {root} is deeper than {MAX_BTREE_DEPTH} levels, it must be corrupted"
+    );
+    Ok(())
+}
+
+/// Groups the subtrees ending with `keys`, and the last one, into `n` ranges of rowids.
+fn partition(keys: &[i64], n: usize) -> Vec<RangeInclusive<i64>> {
+    let subtrees = keys.len() + 1;
+    let groups = n.clamp(1, subtrees);
+    (0..groups)
+        .map(|g| {
+            let (first, end) = (g * subtrees / groups, (g + 1) * subtrees / groups);
+            let start = match first {
+                0 => i64::MIN,
+                first => keys[first - 1].saturating_add(1),
+            };
+            let last = keys.get(end - 1).copied().unwrap_or(i64::MAX);
+            start..=last
+        })
+        .collect()
+}
+
+fn leaf_cursor(cell: &TableLeafCell, pager: Pager) -> anyhow::Result<Cursor> {
+    Ok(Cursor {
+        rowid: cell.rowid,
+        header: shared_header(&cell.header, &cell.payload)?,
+        payload: Payload::Local(cell.payload.clone()),
+        pager,
+        next_overflow_page: cell.first_overflow,
+    })
+}
+
+fn index_cursor(cell: &IndexCell, pager: Pager) -> anyhow::Result<Cursor> {
+    Ok(Cursor {
+        rowid: 0,
+        header: shared_header(&cell.header, &cell.payload)?,
+        payload: Payload::Local(cell.payload.clone()),
+        pager,
+        next_overflow_page: cell.first_overflow,
+    })
+}
+
+/// The header of the record of a cell, parsed by the first scan reading the cell.
+fn shared_header(
+    header: &OnceLock<Arc<RecordHeader>>,
+    payload: &[u8],
+) -> anyhow::Result<Arc<RecordHeader>> {
+    if let Some(header) = header.get() {
+        return Ok(header.clone());
+    }
+    let parsed = Arc::new(parse_record_header(payload)?);
+    Ok(header.get_or_init(|| parsed).clone())
+}
+
 #[derive(Debug)]
 enum ScannerElem {
     Page(u32),
@@ -334,3 +709,133 @@ impl OverflowScanner {
         Ok((next_page, buffer))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::multilevel_btrees;
+
+    /// The first fields of the records left to scan, with their rowid.
+    fn remaining(scanner: &mut Scanner) -> Vec<(i64, i64)> {
+        let mut records = Vec::new();
+        while let Some(mut record) = scanner.next_record().unwrap() {
+            let value = match record.owned_field(0).unwrap() {
+                Some(OwnedValue::Int(value)) => value,
+                value => panic!("expected an integer: {value:?}"),
+            };
+            records.push((value, record.rowid()));
+        }
+        records
+    }
+
+    #[test]
+    fn record_header() {
+        // Header length 6: NULL, I8, a 100-byte string (serial type 213 = 0x81 0x55), a
+        // blob of 1 byte.
+        let header = parse_record_header(&[6, 0, 1, 0x81, 0x55, 14]).unwrap();
+        let fields: Vec<(usize, usize)> = header
+            .fields
+            .iter()
+            .map(|f| (f.offset as usize, f.end_offset()))
+            .collect();
+
+        assert_eq!(fields, vec![(6, 6), (6, 7), (7, 107), (107, 108)]);
+        assert!(matches!(
+            header.fields[2].field_type,
+            RecordFieldType::String(100)
+        ));
+    }
+
+    #[test]
+    fn rowid_partitions() {
+        let keys = [10, 20, 30, 40, 50];
+        assert_eq!(
+            partition(&keys, 3),
+            vec![i64::MIN..=20, 21..=40, 41..=i64::MAX]
+        );
+        assert_eq!(partition(&keys, 1), vec![i64::MIN..=i64::MAX]);
+        assert_eq!(partition(&[7], 4), vec![i64::MIN..=7, 8..=i64::MAX]);
+    }
+
+    #[test]
+    fn payload_extension() {
+        let local: Arc<[u8]> = Arc::from(&[1, 2][..]);
+        let mut payload = Payload::Local(local.clone());
+        assert_eq!(&*payload, &[1, 2]);
+
+        payload.extend_from_slice(&[3]);
+        payload.extend_from_slice(&[4, 5]);
+        assert_eq!(&*payload, &[1, 2, 3, 4, 5]);
+        assert_eq!(&*local, &[1, 2]);
+    }
+
+    #[test]
+    fn odd_sized_integers() {
+        assert_eq!(read_i24_at(&[0x01, 0x00, 0x00], 0).unwrap(), 65536);
+        assert_eq!(read_i24_at(&[0xff, 0xff, 0xfe], 0).unwrap(), -2);
+        assert_eq!(read_i48_at(&[0, 0x01, 0, 0, 0, 0], 0).unwrap(), 1 << 32);
+        assert_eq!(read_i48_at(&[0xff; 6], 0).unwrap(), -1);
+    }
+
+    #[test]
+    fn truncated_fields() {
+        assert_eq!(read_i8_at(&[0xfb], 0).unwrap(), -5);
+        assert!(read_i16_at(&[0x01], 0).is_err());
+        assert!(read_i64_at(&[0; 8], 1).is_err());
+        assert!(read_f64_at(&[], usize::MAX).is_err());
+        assert!(field_bytes(&[1, 2, 3], 2, 2).is_err());
+        // The header claims more bytes than the payload holds.
+        assert!(parse_record_header(&[6, 1, 1]).is_err());
+    }
+
+    #[test]
+    fn rowid_lookups() {
+        let (db, table, _) = multilevel_btrees("rowid-lookups");
+        let scanner = db.scanner(table);
+        for rowid in (10..=1000).step_by(10) {
+            let mut record = scanner.find_rowid(rowid).unwrap().unwrap();
+            assert_eq!(record.rowid(), rowid);
+            assert_eq!(
+                record.owned_field(0).unwrap(),
+                Some(OwnedValue::Int(rowid / 5 - 2))
+            );
+        }
+        for rowid in [i64::MIN, 0, 9, 15, 995, 1001, i64::MAX] {
+            assert!(scanner.find_rowid(rowid).unwrap().is_none(), "{rowid}");
+        }
+    }
+
+    #[test]
+    fn rowid_seeks() {
+        let (db, table, _) = multilevel_btrees("rowid-seeks");
+        let mut scanner = db.scanner(table);
+        for start in [i64::MIN, 0, 10, 11, 500, 995, 1000, 1001, i64::MAX] {
+            scanner.seek_rowid(start).unwrap();
+            let rowids: Vec<i64> = remaining(&mut scanner).iter().map(|&(_, r)| r).collect();
+            let expected: Vec<i64> = (10..=1000).step_by(10).filter(|&r| r >= start).collect();
+            assert_eq!(rowids, expected, "{start}");
+        }
+    }
+
+    #[test]
+    fn index_seeks() {
+        let (db, _, index) = multilevel_btrees("index-seeks");
+        let mut scanner = db.scanner(index);
+        let first = |key: &mut Cursor| match key.owned_field(0)? {
+            Some(OwnedValue::Int(value)) => Ok(value),
+            value => bail!("expected an integer: {value:?}"),
+        };
+        for target in -1..=200 {
+            // Every key from the target on, then every key past it.
+            scanner.seek(|key| Ok(first(key)? < target)).unwrap();
+            let values: Vec<i64> = remaining(&mut scanner).iter().map(|&(v, _)| v).collect();
+            let expected: Vec<i64> = (0..200).step_by(2).filter(|&v| v >= target).collect();
+            assert_eq!(values, expected, ">= {target}");
+
+            scanner.seek(|key| Ok(first(key)? <= target)).unwrap();
+            let values: Vec<i64> = remaining(&mut scanner).iter().map(|&(v, _)| v).collect();
+            let expected: Vec<i64> = (0..200).step_by(2).filter(|&v| v > target).collect();
+            assert_eq!(values, expected, "> {target}");
+        }
+    }
+}
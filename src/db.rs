@@ -1,31 +1,246 @@
-use std::{io::Read, path::Path};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, OnceLock, RwLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
 
-use anyhow::Context;
+use anyhow::{Context, anyhow, ensure};
 
 use crate::{
+    analyzer::{self, BtreeStats},
+    cipher::{self, Cipher, CipherSettings},
     cursor::{Cursor, Scanner},
-    pager::{self, Pager},
+    engine::{
+        CaseFolding, MemoryLimit, MemoryTracker, Params, Query, ResultCache, StatementCache, plan,
+    },
+    page::Freelist,
+    pager::{self, FileVersion, Pager},
     sql::{self, ast},
+    vacuum,
+    value::Collation,
+    vfs::{self, Mmap, SharedLock},
+    vtab,
+    wal::Wal,
+    writer::PageWriter,
 };
 
+/// A table of the schema. Its definition is only parsed when first needed, so that
+/// opening a database doesn't parse every table of its schema.
 #[derive(Debug, Clone)]
 pub struct TableMetadata {
     pub name: String,
+    pub sql: String,
+    pub first_page: usize,
+    definition: OnceLock<TableDef>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TableDef {
     pub columns: Vec<ast::ColumnDef>,
+    pub constraints: Vec<ast::TableConstraint>,
+    pub without_rowid: bool,
+    pub module: Option<VirtualTableModule>,
+}
+
+#[derive(Debug, Clone)]
+pub struct VirtualTableModule {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexMetadata {
+    pub name: String,
+    pub table_name: String,
+    /// Missing for the indexes SQLite creates for UNIQUE and PRIMARY KEY constraints.
+    pub sql: Option<String>,
     pub first_page: usize,
+    columns: OnceLock<Vec<IndexColumn>>,
+}
+
+/// A column of the keys of an index, which end with the rowid.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexColumn {
+    /// The lowercase name of the column, `None` for an expression.
+    pub name: Option<String>,
+    /// The collation set by the index rather than the column.
+    pub collation: Option<Collation>,
+    pub descending: bool,
+}
+
+/// The estimates ANALYZE stores in the `sqlite_stat1` table.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableStatistics {
+    pub rows: u64,
+    /// The average number of rows sharing a value of a column, for the columns that
+    /// come first in an index, by lowercase name.
+    pub rows_per_value: HashMap<String, u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TriggerMetadata {
+    pub name: String,
+    pub table_name: String,
+    pub sql: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ViewMetadata {
+    pub name: String,
+    pub sql: String,
+}
+
+impl ViewMetadata {
+    fn from_cursor(mut cursor: Cursor) -> anyhow::Result<Self> {
+        let name = cursor
+            .field(1)?
+            .and_then(|v| v.as_str().map(str::to_owned))
+            .context("view name should be a string")?;
+
+        let sql = cursor
+            .field(4)?
+            .and_then(|v| v.as_str().map(str::to_owned))
+            .context("view sql should be a string")?;
+
+        Ok(ViewMetadata { name, sql })
+    }
+}
+
+impl TriggerMetadata {
+    fn from_cursor(mut cursor: Cursor) -> anyhow::Result<Self> {
+        let name = cursor
+            .field(1)?
+            .and_then(|v| v.as_str().map(str::to_owned))
+            .context("trigger name should be a string")?;
+
+        let table_name = cursor
+            .field(2)?
+            .and_then(|v| v.as_str().map(str::to_owned))
+            .context("trigger table name should be a string")?;
+
+        let sql = cursor
+            .field(4)?
+            .and_then(|v| v.as_str().map(str::to_owned))
+            .context("trigger sql should be a string")?;
+
+        Ok(TriggerMetadata {
+            name,
+            table_name,
+            sql,
+        })
+    }
+}
+
+impl IndexMetadata {
+    /// Whether the index only holds the rows selected by its WHERE clause.
+    pub fn is_partial(&self) -> bool {
+        matches!(
+            self.sql.as_deref().map(sql::parse_create_statement),
+            Some(Ok(ast::Statement::CreateIndex(create))) if create.partial
+        )
+    }
+
+    /// The columns of the keys, parsed from the definition of the index. Empty for
+    /// partial indexes and the indexes SQLite creates for constraints, which hold
+    /// keys queries can't use without knowing their origin.
+    pub fn columns(&self) -> &[IndexColumn] {
+        self.columns.get_or_init(|| {
+            let Some(Ok(ast::Statement::CreateIndex(create))) =
+                self.sql.as_deref().map(sql::parse_create_statement)
+            else {
+                return Vec::new();
+            };
+            if create.partial {
+                return Vec::new();
+            }
+
+            let column = |term: &ast::OrderingTerm| {
+                let (expr, collation) = match &term.expr {
+                    ast::Expr::Collate(collate) => match Collation::from_name(&collate.collation) {
+                        Some(collation) => (collate.expr.as_ref(), Some(collation)),
+                        None => (&term.expr, None),
+                    },
+                    expr => (expr, None),
+                };
+                IndexColumn {
+                    name: match expr {
+                        ast::Expr::Column(column) => Some(column.name.to_lowercase()),
+                        _ => None,
+                    },
+                    collation,
+                    descending: term.descending,
+                }
+            };
+            create.columns.iter().map(column).collect()
+        })
+    }
+
+    /// The lowercase name of the first indexed column, unless the index starts with an
+    /// expression or was created by SQLite for a constraint.
+    fn first_column(&self) -> Option<String> {
+        self.columns().first()?.name.clone()
+    }
+
+    fn from_cursor(mut cursor: Cursor) -> anyhow::Result<Self> {
+        let name = cursor
+            .field(1)?
+            .and_then(|v| v.as_str().map(str::to_owned))
+            .context("index name should be a string")?;
+
+        let table_name = cursor
+            .field(2)?
+            .and_then(|v| v.as_str().map(str::to_owned))
+            .context("index table name should be a string")?;
+
+        let first_page = cursor
+            .field(3)?
+            .and_then(|v| v.as_int())
+            .context("index first page should be an integer")? as usize;
+
+        let sql = cursor.field(4)?.and_then(|v| v.as_str().map(str::to_owned));
+
+        Ok(IndexMetadata {
+            name,
+            table_name,
+            sql,
+            first_page,
+            columns: OnceLock::new(),
+        })
+    }
 }
 
 impl TableMetadata {
-    fn from_cursor(mut cursor: Cursor) -> anyhow::Result<Option<Self>> {
-        let type_value = cursor
-            .field(0)?
-            .context("missing type field")
-            .context("invalid type field")?;
+    pub fn new(name: String, sql: String, first_page: usize) -> Self {
+        Self {
+            name,
+            sql,
+            first_page,
+            definition: OnceLock::new(),
+        }
+    }
 
-        if type_value.as_str() != Some("table") {
-            return Ok(None);
+    pub fn definition(&self) -> anyhow::Result<&TableDef> {
+        if let Some(definition) = self.definition.get() {
+            return Ok(definition);
         }
 
+        let definition = TableDef::parse(&self.sql)
+            .with_context(|| format!("parse definition of table {}", self.name))?;
+        Ok(self.definition.get_or_init(|| definition))
+    }
+
+    fn from_cursor(mut cursor: Cursor) -> anyhow::Result<Self> {
+        let name = cursor
+            .field(1)?
+            .and_then(|v| v.as_str().map(str::to_owned))
+            .context("table name should be a string")?;
+
         let create_stmt = cursor
             .field(4)?
             .context("missing create statement")
@@ -34,30 +249,296 @@ impl TableMetadata {
             .context("table create statement should be a string")?
             .to_owned();
 
-        let create = sql::parse_create_statement(&create_stmt)?;
-
         let first_page = cursor
             .field(3)?
             .context("missing table first page")?
             .as_int()
             .context("table first page should be an integer")? as usize;
 
-        Ok(Some(TableMetadata {
-            name: create.name,
-            columns: create.columns,
-            first_page,
-        }))
+        Ok(TableMetadata::new(name, create_stmt, first_page))
+    }
+}
+
+impl TableDef {
+    /// The `INTEGER PRIMARY KEY` column, whose value is the rowid rather than being
+    /// stored in the record.
+    pub fn rowid_alias(&self) -> Option<usize> {
+        if !self.has_rowid() {
+            return None;
+        }
+
+        match self.primary_key()[..] {
+            [i] if (self.columns[i].col_type.as_deref())
+                .is_some_and(|t| t.eq_ignore_ascii_case("integer")) =>
+            {
+                Some(i)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the rows of the table are keyed by a rowid.
+    pub fn has_rowid(&self) -> bool {
+        !self.without_rowid && self.module.is_none()
+    }
+
+    /// The positions of the columns stored in the records, in the order of the record
+    /// fields: all but the virtual generated ones. The records of WITHOUT ROWID tables
+    /// start with the primary key.
+    pub fn stored_columns(&self) -> Vec<usize> {
+        let mut stored: Vec<usize> = if self.without_rowid {
+            self.primary_key()
+        } else {
+            Vec::new()
+        };
+        for i in 0..self.columns.len() {
+            if !stored.contains(&i) && !matches!(self.columns[i].generated(), Some((_, false))) {
+                stored.push(i);
+            }
+        }
+        stored
+    }
+
+    /// The positions of the columns of the primary key, in key order.
+    pub fn primary_key(&self) -> Vec<usize> {
+        let position = |name: &str| {
+            self.columns
+                .iter()
+                .position(|c| c.name.eq_ignore_ascii_case(name))
+        };
+
+        let mut key = Vec::new();
+        for constraint in &self.constraints {
+            if let ast::TableConstraint::PrimaryKey { columns, .. } = constraint {
+                for i in columns.iter().filter_map(|c| position(c)) {
+                    if !key.contains(&i) {
+                        key.push(i);
+                    }
+                }
+            }
+        }
+        if key.is_empty() {
+            key.extend(self.columns.iter().position(ast::ColumnDef::is_primary_key));
+        }
+        key
+    }
+
+    /// The columns of the UNIQUE and PRIMARY KEY constraints, with their `ON CONFLICT`
+    /// clause, in the order SQLite numbers the indexes it creates for them,
+    /// `sqlite_autoindex_<table>_<n>`. The INTEGER PRIMARY KEY is the rowid, and the
+    /// primary key of a WITHOUT ROWID table the table itself, rather than indexes. A
+    /// constraint on the columns of an earlier one shares its index.
+    pub fn unique_keys(&self) -> Vec<(Vec<usize>, Option<ast::ConflictResolution>)> {
+        let position = |name: &str| {
+            self.columns
+                .iter()
+                .position(|c| c.name.eq_ignore_ascii_case(name))
+        };
+        let positions = |columns: &[String]| columns.iter().filter_map(|c| position(c)).collect();
+        let is_table_key = |columns: &[usize]| {
+            (self.without_rowid || self.rowid_alias().is_some()) && columns == self.primary_key()
+        };
+
+        let mut keys: Vec<(Vec<usize>, _)> = Vec::new();
+        let constraints = (self.columns.iter().enumerate())
+            .flat_map(|(i, column)| {
+                column.constraints.iter().filter_map(move |c| match c {
+                    ast::ColumnConstraint::PrimaryKey(on_conflict)
+                    | ast::ColumnConstraint::Unique(on_conflict) => Some((vec![i], *on_conflict)),
+                    _ => None,
+                })
+            })
+            .chain(self.constraints.iter().filter_map(|c| match c {
+                ast::TableConstraint::PrimaryKey {
+                    columns,
+                    on_conflict,
+                }
+                | ast::TableConstraint::Unique {
+                    columns,
+                    on_conflict,
+                } => Some((positions(columns), *on_conflict)),
+                ast::TableConstraint::Check(_) => None,
+            }));
+        for (columns, on_conflict) in constraints {
+            if !is_table_key(&columns) && !keys.iter().any(|(key, _)| *key == columns) {
+                keys.push((columns, on_conflict));
+            }
+        }
+        keys
+    }
+
+    fn parse(sql: &str) -> anyhow::Result<Self> {
+        match sql::parse_create_statement(sql)? {
+            ast::Statement::CreateTable(create) => Ok(TableDef {
+                columns: create.columns,
+                constraints: create.constraints,
+                without_rowid: create.without_rowid,
+                module: None,
+            }),
+            ast::Statement::CreateVirtualTable(create) => Ok(TableDef {
+                columns: vtab::module_columns(&create.module, &create.args),
+                constraints: Vec::new(),
+                without_rowid: false,
+                module: Some(VirtualTableModule {
+                    name: create.module,
+                    args: create.args,
+                }),
+            }),
+            _ => anyhow::bail!("expected a create statement"),
+        }
+    }
+}
+
+/// The entries of the schema table.
+#[derive(Debug, Default)]
+pub struct SchemaMetadata {
+    tables: Vec<TableMetadata>,
+    /// Positions in `tables`, by lowercase name.
+    table_positions: HashMap<String, usize>,
+    pub indexes: Vec<IndexMetadata>,
+    pub triggers: Vec<TriggerMetadata>,
+    pub views: Vec<ViewMetadata>,
+    /// Statistics of the analyzed tables, by lowercase name.
+    statistics: HashMap<String, TableStatistics>,
+}
+
+impl SchemaMetadata {
+    pub fn tables(&self) -> &[TableMetadata] {
+        &self.tables
+    }
+
+    pub fn table(&self, name: &str) -> Option<&TableMetadata> {
+        let position = self.table_positions.get(&name.to_lowercase())?;
+        Some(&self.tables[*position])
+    }
+
+    pub fn statistics(&self, table: &str) -> Option<&TableStatistics> {
+        self.statistics.get(&table.to_lowercase())
+    }
+
+    fn add_table(&mut self, table: TableMetadata) {
+        self.table_positions
+            .insert(table.name.to_lowercase(), self.tables.len());
+        self.tables.push(table);
+    }
+}
+
+/// Number of parsed statements kept for reuse.
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+
+/// Interrupts the queries running on a database, e.g. from another thread or a signal
+/// handler, making their scans fail. Like `sqlite3_interrupt()`, it doesn't affect the
+/// queries started once none is running anymore.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle {
+    state: Arc<InterruptState>,
+}
+
+#[derive(Debug, Default)]
+struct InterruptState {
+    interrupted: AtomicBool,
+    running: AtomicUsize,
+}
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.state.interrupted.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.state.interrupted.load(Ordering::Relaxed)
+    }
+
+    /// Checks the interrupt for a loop, once every few iterations.
+    pub fn checker(&self) -> InterruptCheck {
+        InterruptCheck {
+            handle: self.clone(),
+            unchecked: 0,
+        }
+    }
+
+    /// Counts a query as running until the returned guard is dropped.
+    pub fn start_query(&self) -> RunningQuery {
+        if self.state.running.fetch_add(1, Ordering::SeqCst) == 0 {
+            self.state.interrupted.store(false, Ordering::Relaxed);
+        }
+        RunningQuery(self.clone())
+    }
+}
+
+/// Iterations of a loop between checks of the interrupt.
+const INTERRUPT_CHECK_INTERVAL: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct InterruptCheck {
+    handle: InterruptHandle,
+    /// Iterations since the interrupt was last checked.
+    unchecked: usize,
+}
+
+impl InterruptCheck {
+    /// Counts an iteration, failing if the queries were interrupted.
+    pub fn check(&mut self) -> anyhow::Result<()> {
+        self.unchecked += 1;
+        if self.unchecked == INTERRUPT_CHECK_INTERVAL {
+            self.unchecked = 0;
+            ensure!(!self.handle.is_interrupted(), "interrupted");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct RunningQuery(InterruptHandle);
+
+impl Drop for RunningQuery {
+    fn drop(&mut self) {
+        self.0.state.running.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
 pub struct Db {
-    pub tables_metadata: Vec<TableMetadata>,
+    metadata: RwLock<Arc<SchemaMetadata>>,
+    /// Version of the file the page cache and metadata were read from.
+    version: Mutex<FileVersion>,
     pager: Pager,
+    memory_budget: usize,
+    memory_limit: Arc<MemoryLimit>,
+    slow_query_threshold: Option<Duration>,
+    result_cache: Option<ResultCache>,
+    statements: StatementCache,
+    case_folding: CaseFolding,
+    interrupt: InterruptHandle,
+    /// Held by the transaction being written. The reserved lock doesn't keep out the
+    /// other threads of the process, as POSIX locks belong to the process.
+    writer: Mutex<()>,
+    /// The path of the file when it is compressed, for writes to replace it.
+    compressed: Option<PathBuf>,
+}
+
+/// How to open a database file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpenOptions {
+    /// Memory-maps the file, so that pages are parsed without being copied.
+    pub mmap: bool,
+}
+
+/// How to copy a database with `Db::backup`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BackupOptions {
+    /// Leaves out the free pages, renumbering the other ones to follow each other.
+    pub vacuum: bool,
+    /// Copies encrypted databases, although their copy isn't encrypted.
+    pub plaintext: bool,
 }
 
 impl Db {
     pub fn from_file(filename: impl AsRef<Path>) -> anyhow::Result<Db> {
-        let mut file = std::fs::File::open(filename.as_ref()).context("open db file")?;
+        Self::open(filename, OpenOptions::default())
+    }
+
+    pub fn open(filename: impl AsRef<Path>, options: OpenOptions) -> anyhow::Result<Db> {
+        let (mut file, lock) = vfs::open(&filename)?;
 
         let mut header_buffer = [0; pager::HEADER_SIZE];
         file.read_exact(&mut header_buffer)
@@ -65,30 +546,726 @@ impl Db {
 
         let header = pager::parse_header(&header_buffer).context("parse db header")?;
 
-        let pager = Pager::new(header, file);
+        let wal = Wal::open(&filename, header.page_size)?;
+        let mmap = options.mmap.then(|| Mmap::open(&filename)).transpose()?;
+        // Only compressed files are opened without a lock, SQLite not writing them.
+        let compressed = lock.is_none().then(|| filename.as_ref().to_path_buf());
+        let pager = Pager::new(header, file)
+            .with_cache_size(pager::default_cache_size(header.page_size))
+            .with_lock(lock)
+            .with_wal(wal)
+            .with_mmap(mmap);
 
-        let tables_metadata = Self::collect_tables_metadata(pager.clone())?;
+        Ok(Db {
+            compressed,
+            ..Self::new(pager)?
+        })
+    }
+
+    pub fn from_encrypted_file(
+        filename: impl AsRef<Path>,
+        key: &str,
+        settings: CipherSettings,
+    ) -> anyhow::Result<Db> {
+        let (mut file, lock) = vfs::open(&filename)?;
+
+        let mut salt = [0; cipher::SALT_SIZE];
+        file.read_exact(&mut salt).context("read cipher salt")?;
+
+        let cipher = Cipher::new(key, &salt, settings);
+
+        let mut first_page = vec![0; settings.page_size as usize];
+        file.seek(SeekFrom::Start(0))
+            .context("seek to first page")?;
+        file.read_exact(&mut first_page)
+            .context("read first page")?;
+        cipher
+            .decrypt_page(1, &mut first_page)
+            .context("decrypt first page (wrong key or cipher settings?)")?;
+
+        let header = pager::parse_header(&first_page).context("parse db header")?;
+        ensure!(
+            header.page_size == settings.page_size,
+            "cipher page size {} does not match database page size {}",
+            settings.page_size,
+            header.page_size
+        );
+
+        let wal = Wal::open(&filename, header.page_size)?;
+        let pager = Pager::new(header, file)
+            .with_cache_size(pager::default_cache_size(header.page_size))
+            .with_cipher(cipher)
+            .with_lock(lock)
+            .with_wal(wal);
+
+        Self::new(pager)
+    }
+
+    fn new(pager: Pager) -> anyhow::Result<Db> {
+        let _lock = pager.lock_shared()?;
+        let version = pager.file_version()?;
+        let metadata = Self::collect_metadata(pager.clone())?;
 
         Ok(Db {
+            metadata: RwLock::new(Arc::new(metadata)),
+            version: Mutex::new(version),
             pager,
-            tables_metadata,
+            memory_budget: plan::DEFAULT_MEMORY_BUDGET,
+            memory_limit: Arc::new(MemoryLimit::new(None)),
+            slow_query_threshold: None,
+            result_cache: None,
+            statements: StatementCache::new(STATEMENT_CACHE_CAPACITY),
+            case_folding: CaseFolding::default(),
+            interrupt: InterruptHandle::default(),
+            writer: Mutex::new(()),
+            compressed: None,
         })
     }
 
+    /// Parses `sql`, reusing the statement when it was recently parsed.
+    pub fn prepare(&self, sql: &str) -> anyhow::Result<Arc<ast::Statement>> {
+        self.statements
+            .get_or_parse(sql, |sql| sql::parse_statement(sql, false))
+    }
+
+    /// Parses and plans `sql`, returning a query producing its rows.
+    pub fn query(&self, sql: &str) -> anyhow::Result<Query<'_>> {
+        Query::new(self, sql, &Params::default())
+    }
+
+    /// Like `query`, with `params` bound to the parameters of `sql`.
+    pub fn query_with_params(&self, sql: &str, params: &Params) -> anyhow::Result<Query<'_>> {
+        Query::new(self, sql, params)
+    }
+
+    pub fn memory_budget(&self) -> usize {
+        self.memory_budget
+    }
+
+    /// Bytes of rows the operators of a query may buffer before spilling them to disk.
+    pub fn set_memory_budget(&mut self, bytes: usize) {
+        self.memory_budget = bytes;
+    }
+
+    pub fn page_cache_size(&self) -> anyhow::Result<Option<usize>> {
+        self.pager.cache_size()
+    }
+
+    /// Pages the pager keeps in memory, or `None` to keep every page read.
+    pub fn set_page_cache_size(&self, pages: Option<usize>) -> anyhow::Result<()> {
+        self.pager.set_cache_size(pages)
+    }
+
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit.bytes()
+    }
+
+    /// Bytes of rows all the running queries may buffer at once, past which they fail.
+    pub fn set_memory_limit(&mut self, bytes: Option<usize>) {
+        self.memory_limit.set_bytes(bytes);
+    }
+
+    /// Tracks the memory of a query, against the budget and the limit.
+    pub fn memory_tracker(&self) -> MemoryTracker {
+        MemoryTracker::new(self.memory_budget).with_limit(self.memory_limit.clone())
+    }
+
+    pub fn slow_query_threshold(&self) -> Option<Duration> {
+        self.slow_query_threshold
+    }
+
+    /// Queries running longer than `threshold` are logged to stderr when they finish.
+    pub fn set_slow_query_threshold(&mut self, threshold: Option<Duration>) {
+        self.slow_query_threshold = threshold;
+    }
+
+    pub fn result_cache(&self) -> Option<&ResultCache> {
+        self.result_cache.as_ref()
+    }
+
+    /// Serves repeated queries from up to `bytes` of cached results while the file is
+    /// unchanged, or disables the cache.
+    pub fn set_result_cache_size(&mut self, bytes: Option<usize>) {
+        self.result_cache = bytes.map(ResultCache::new);
+    }
+
+    pub fn case_folding(&self) -> CaseFolding {
+        self.case_folding
+    }
+
+    /// Selects how `upper()`, `lower()`, LIKE and the NOCASE collation fold the case of
+    /// letters.
+    pub fn set_case_folding(&mut self, case_folding: CaseFolding) {
+        self.case_folding = case_folding;
+        if let Some(cache) = &self.result_cache {
+            cache.clear();
+        }
+    }
+
+    /// Keeps other connections from modifying the file until the lock is dropped.
+    pub fn lock_shared(&self) -> anyhow::Result<Option<SharedLock>> {
+        self.pager.lock_shared()
+    }
+
+    /// The schema as of the last refresh.
+    pub fn metadata(&self) -> Arc<SchemaMetadata> {
+        self.metadata
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Checks whether another connection modified the file since it was last read,
+    /// dropping the stale cached pages and reloading the schema if it changed.
+    pub fn refresh_metadata(&self) -> anyhow::Result<Arc<SchemaMetadata>> {
+        Ok(self.pin()?.0)
+    }
+
+    /// Like `refresh_metadata`, also returning the version of the file and a pager
+    /// reading its pages as of that version, even once other connections committed
+    /// newer transactions to the WAL.
+    pub fn pin(&self) -> anyhow::Result<(Arc<SchemaMetadata>, FileVersion, Pager)> {
+        let mut known = self
+            .version
+            .lock()
+            .map_err(|_| anyhow!("poisoned file version lock"))?;
+        let pager = self.pager.pinned()?;
+        let version = pager.file_version()?;
+
+        if *known != version {
+            self.pager.clear_cache()?;
+            if let Some(cache) = &self.result_cache {
+                cache.clear();
+            }
+            if known.schema_cookie != version.schema_cookie {
+                let metadata = Self::collect_metadata(pager.clone())?;
+                *self
+                    .metadata
+                    .write()
+                    .map_err(|_| anyhow!("poisoned metadata lock"))? = Arc::new(metadata);
+            }
+            *known = version;
+        }
+
+        Ok((self.metadata(), version, pager))
+    }
+
+    /// Runs `f` in a write transaction, committed once `f` succeeds and discarded
+    /// otherwise. Like SQLite, it holds the reserved lock while `f` runs, then the
+    /// exclusive lock while the pages are written, after being journaled.
+    pub fn write<T>(
+        &self,
+        f: impl FnOnce(&mut PageWriter<'_>) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let _writer = self
+            .writer
+            .lock()
+            .map_err(|_| anyhow!("poisoned writer lock"))?;
+        if let Some(path) = &self.compressed {
+            return self.write_compressed(path, f);
+        }
+        let lock = self.pager.write_lock()?;
+        let (_shared, mut reserved) = lock.reserved()?;
+        ensure!(
+            lock.hot_journal().is_none(),
+            "cannot write a database with a hot journal, which SQLite has to roll back first"
+        );
+        self.refresh_metadata()?;
+
+        let mut writer = PageWriter::new(lock.file(), self.pager.read_header()?)?
+            .with_journal(lock.journal_path())?;
+        let result = f(&mut writer)?;
+        reserved.exclusive()?;
+        writer.commit()?;
+        drop(reserved);
+
+        self.refresh_metadata()?;
+        Ok(result)
+    }
+
+    /// Like `write`, for a compressed database: its pages are written with the
+    /// unmodified ones to a new file, which replaces it once synced, rather than being
+    /// journaled. The connections of other processes keep reading the file they opened.
+    fn write_compressed<T>(
+        &self,
+        path: &Path,
+        f: impl FnOnce(&mut PageWriter<'_>) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        self.refresh_metadata()?;
+        let header = self.pager.read_header()?;
+        ensure!(
+            header.write_version != 2,
+            "cannot write a database in WAL mode"
+        );
+
+        let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+        let mut new_path = path.as_os_str().to_owned();
+        new_path.push("-new");
+        let new_path = PathBuf::from(new_path);
+        let output =
+            File::create(&new_path).with_context(|| format!("create {}", new_path.display()))?;
+        let mut writer = PageWriter::compressed(&file, &output, header)?;
+        let result = f(&mut writer).and_then(|result| {
+            writer.commit()?;
+            std::fs::rename(&new_path, path)
+                .with_context(|| format!("replace {}", path.display()))?;
+            Ok(result)
+        });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&new_path);
+        }
+        let result = result?;
+
+        let (file, _) = vfs::open(path)?;
+        self.pager.replace_input(file)?;
+        self.refresh_metadata()?;
+        Ok(result)
+    }
+
+    /// Copies the database to a new file at `path`, page by page. The file stays locked
+    /// for reading during the copy, and the pages of the WAL are read as of its start,
+    /// so the copy is a consistent snapshot. Compressed databases, and encrypted ones
+    /// with `options.plaintext`, are copied as plain SQLite files, and the header of
+    /// the copy counts the pages read from the WAL or the hot journal. The free pages of
+    /// the copy are zeroed rather than holding what was deleted from the database, and
+    /// left out when it is vacuumed. Returns the number of pages of the copy.
+    pub fn backup(&self, path: impl AsRef<Path>, options: BackupOptions) -> anyhow::Result<usize> {
+        let path = path.as_ref();
+        ensure!(
+            options.plaintext || !self.pager.is_encrypted(),
+            "the copy of an encrypted database isn't encrypted: use --plaintext to copy it anyway"
+        );
+        let _lock = self.lock_shared()?;
+        let (_, _, snapshot) = self.pin()?;
+
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .with_context(|| format!("create {}", path.display()))?;
+        let mut writer = PageWriter::new(&file, snapshot.header())?;
+        if options.vacuum {
+            let page_count = vacuum::copy(&snapshot, &mut writer)
+                .with_context(|| format!("copy the pages in use to {}", path.display()))?;
+            writer
+                .commit()
+                .with_context(|| format!("write {}", path.display()))?;
+            return Ok(page_count);
+        }
+
+        let page_count = snapshot.page_count()?;
+        let freelist = snapshot.freelist()?;
+        let mut free_pages: Vec<usize> =
+            freelist.trunks.into_iter().chain(freelist.leaves).collect();
+        free_pages.sort_unstable();
+        // Like SQLite, leaves the lock-byte page of large databases unwritten.
+        let lock_byte_page = pager::lock_byte_page(snapshot.header().page_size);
+        for n in 1..=page_count {
+            if n != lock_byte_page && free_pages.binary_search(&n).is_err() {
+                writer
+                    .write_page(n, snapshot.read_raw(n)?)
+                    .with_context(|| format!("write page {n} to {}", path.display()))?;
+            }
+        }
+        writer
+            .replace_freelist(free_pages)
+            .with_context(|| format!("write the freelist of {}", path.display()))?;
+        writer
+            .commit()
+            .with_context(|| format!("write {}", path.display()))?;
+
+        Ok(page_count)
+    }
+
+    /// Space usage of every b-tree in the database, starting with the schema table.
+    pub fn space_usage(&self) -> anyhow::Result<Vec<BtreeStats>> {
+        let mut btrees = vec![("sqlite_schema".to_string(), 1)];
+
+        let mut scanner = Scanner::new(1, self.pager.clone());
+        while let Some(mut record) = scanner.next_record()? {
+            let name = record
+                .field(1)?
+                .and_then(|v| v.as_str().map(str::to_owned))
+                .context("schema entry name should be a string")?;
+            let root = record.field(3)?.and_then(|v| v.as_int()).unwrap_or(0);
+            if root > 0 {
+                btrees.push((name, root as usize));
+            }
+        }
+
+        btrees
+            .into_iter()
+            .map(|(name, root)| analyzer::analyze_btree(&self.pager, &name, root))
+            .collect()
+    }
+
+    /// The free pages of the database.
+    pub fn freelist(&self) -> anyhow::Result<Freelist> {
+        let _lock = self.lock_shared()?;
+        self.pager.freelist()
+    }
+
+    pub fn free_page_count(&self) -> anyhow::Result<usize> {
+        Ok(self.freelist()?.page_count())
+    }
+
+    /// The pager of the database, for raw page access below the SQL layer.
+    pub fn pager(&self) -> &Pager {
+        &self.pager
+    }
+
+    /// A scanner of the b-tree rooted at `page`, failing once the queries of the
+    /// database are interrupted.
     pub fn scanner(&self, page: usize) -> Scanner {
-        Scanner::new(page, self.pager.clone())
+        Scanner::new(page, self.pager.clone()).with_interrupt(self.interrupt.checker())
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
     }
 
-    fn collect_tables_metadata(pager: Pager) -> anyhow::Result<Vec<TableMetadata>> {
-        let mut metadata = Vec::new();
-        let mut scanner = Scanner::new(1, pager);
+    fn collect_metadata(pager: Pager) -> anyhow::Result<SchemaMetadata> {
+        let mut metadata = SchemaMetadata::default();
+        let mut scanner = Scanner::new(1, pager.clone());
+
+        while let Some(mut record) = scanner.next_record()? {
+            let entry_type = record
+                .field(0)?
+                .and_then(|v| v.as_str().map(str::to_owned))
+                .context("invalid type field")?;
 
-        while let Some(record) = scanner.next_record()? {
-            if let Some(m) = TableMetadata::from_cursor(record)? {
-                metadata.push(m);
+            match entry_type.as_str() {
+                "table" => metadata.add_table(TableMetadata::from_cursor(record)?),
+                "index" => metadata.indexes.push(IndexMetadata::from_cursor(record)?),
+                "trigger" => metadata
+                    .triggers
+                    .push(TriggerMetadata::from_cursor(record)?),
+                "view" => metadata.views.push(ViewMetadata::from_cursor(record)?),
+                _ => {}
             }
         }
 
+        if let Some(stat1) = metadata.table("sqlite_stat1") {
+            let scanner = Scanner::new(stat1.first_page, pager);
+            metadata.statistics = Self::collect_statistics(scanner, &metadata.indexes)?;
+        }
         Ok(metadata)
     }
+
+    /// Reads the `tbl`, `idx` and `stat` columns of `sqlite_stat1`. The stat of an
+    /// index starts with the number of rows, followed by the average number of rows
+    /// sharing a value of its first column, its first two columns, and so on.
+    fn collect_statistics(
+        mut scanner: Scanner,
+        indexes: &[IndexMetadata],
+    ) -> anyhow::Result<HashMap<String, TableStatistics>> {
+        let mut statistics: HashMap<String, TableStatistics> = HashMap::new();
+        while let Some(mut record) = scanner.next_record()? {
+            let mut text = |n| -> anyhow::Result<Option<String>> {
+                Ok(record.field(n)?.and_then(|v| v.as_str().map(str::to_owned)))
+            };
+            let (Some(table), index, Some(stat)) = (text(0)?, text(1)?, text(2)?) else {
+                continue;
+            };
+
+            let counts: Vec<u64> = stat.split(' ').map_while(|n| n.parse().ok()).collect();
+            let Some(&rows) = counts.first() else {
+                continue;
+            };
+            let table = statistics.entry(table.to_lowercase()).or_default();
+            table.rows = rows;
+
+            let first_column = indexes
+                .iter()
+                .find(|i| {
+                    index
+                        .as_deref()
+                        .is_some_and(|name| i.name.eq_ignore_ascii_case(name))
+                })
+                .and_then(IndexMetadata::first_column);
+            if let (Some(column), Some(&per_value)) = (first_column, counts.get(1)) {
+                let known = table.rows_per_value.entry(column).or_insert(per_value);
+                *known = (*known).min(per_value);
+            }
+        }
+        Ok(statistics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        testing::{
+            database_file, index_btree, index_cell, open_database, record, schema_cell,
+            seekable_zstd, table_btree, table_cell, text, write_leaf,
+        },
+        value::SendValue,
+        vfs::ZstdPageFile,
+    };
+
+    use super::*;
+
+    #[test]
+    fn interrupted_queries() {
+        let handle = InterruptHandle::default();
+        let running = handle.start_query();
+        let mut check = handle.checker();
+        handle.interrupt();
+        let checks = (0..INTERRUPT_CHECK_INTERVAL).map(|_| check.check());
+        assert_eq!(checks.filter(Result::is_err).count(), 1);
+
+        // Stays interrupted while a query is running, and is reset by the next one.
+        let other = handle.start_query();
+        assert!(handle.is_interrupted());
+        drop((running, other));
+        handle.interrupt();
+        let _running = handle.start_query();
+        assert!(!handle.is_interrupted());
+    }
+
+    fn rows(db: &Db, sql: &str) -> Vec<Vec<SendValue>> {
+        let mut query = db.query(sql).unwrap();
+        let mut rows = Vec::new();
+        while let Some(row) = query.next_row().unwrap() {
+            rows.push(row.iter().map(SendValue::from).collect());
+        }
+        rows
+    }
+
+    #[test]
+    fn backup_copy() {
+        const PAGE_SIZE: usize = 512;
+        // A table on page 2 and a freelist of the trunk page 3 and the leaf page 4.
+        let mut file = database_file(PAGE_SIZE, 4);
+        file[32..36].copy_from_slice(&3u32.to_be_bytes());
+        file[36..40].copy_from_slice(&2u32.to_be_bytes());
+        let schema = schema_cell(1, "table", "t", "t", 2, "CREATE TABLE t(a)");
+        write_leaf(&mut file[..PAGE_SIZE], 100, 0x0d, &[schema]);
+        let rows_cells: Vec<_> = ["x", "y"]
+            .iter()
+            .zip(1..)
+            .map(|(a, rowid)| table_cell(rowid, &record(&[text(a)])))
+            .collect();
+        write_leaf(&mut file[PAGE_SIZE..2 * PAGE_SIZE], 0, 0x0d, &rows_cells);
+        file[2 * PAGE_SIZE + 4..2 * PAGE_SIZE + 12].copy_from_slice(&[0, 0, 0, 1, 0, 0, 0, 4]);
+        file[3 * PAGE_SIZE..].fill(0xaa);
+
+        let db = open_database("backup", &file);
+        let path = std::env::temp_dir().join(format!("rqlite-backup-{}", std::process::id()));
+        assert_eq!(db.backup(&path, BackupOptions::default()).unwrap(), 4);
+        assert!(db.backup(&path, BackupOptions::default()).is_err());
+
+        let copy = Db::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rows(&copy, "select a from t"), [[text("x")], [text("y")]]);
+        assert_eq!(copy.freelist().unwrap(), db.freelist().unwrap());
+        assert!(copy.pager().read_raw(4).unwrap().iter().all(|&b| b == 0));
+        assert_eq!(
+            copy.pager().header().change_counter,
+            db.pager().header().change_counter + 1
+        );
+    }
+
+    #[test]
+    fn write_compressed() {
+        const PAGE_SIZE: usize = 512;
+        let mut file = database_file(PAGE_SIZE, 2);
+        let schema = schema_cell(1, "table", "t", "t", 2, "CREATE TABLE t(a)");
+        write_leaf(&mut file[..PAGE_SIZE], 100, 0x0d, &[schema]);
+        write_leaf(&mut file[PAGE_SIZE..], 0, 0x0d, &[]);
+        let path = std::env::temp_dir().join(format!("rqlite-compressed-{}", std::process::id()));
+        std::fs::write(&path, seekable_zstd(&file, PAGE_SIZE)).unwrap();
+
+        let db = Db::from_file(&path).unwrap();
+        for i in 0..50 {
+            let sql = format!("INSERT INTO t VALUES ('{}')", "x".repeat(i * 10));
+            db.query(&sql).unwrap();
+        }
+        let expected = [[SendValue::Int(50), SendValue::Int(12250)]];
+        let sql = "SELECT count(*), sum(length(a)) FROM t";
+        assert_eq!(rows(&db, sql), expected);
+
+        // The file was replaced by a compressed one, of a frame per page.
+        let copy = Db::from_file(&path).unwrap();
+        let compressed = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rows(&copy, sql), expected);
+        assert_eq!(rows(&copy, "PRAGMA integrity_check"), [[text("ok")]]);
+        let page_count = copy.pager().page_count().unwrap();
+        assert!(page_count > 10);
+        assert!(compressed.len() < page_count * PAGE_SIZE);
+        let mut file = ZstdPageFile::new(std::io::Cursor::new(compressed)).unwrap();
+        assert_eq!(
+            file.seek(SeekFrom::End(0)).unwrap(),
+            (page_count * PAGE_SIZE) as u64
+        );
+    }
+
+    #[test]
+    fn unicode_case_folding() {
+        const PAGE_SIZE: usize = 512;
+        let mut file = database_file(PAGE_SIZE, 3);
+        let schema = [
+            schema_cell(
+                1,
+                "table",
+                "t",
+                "t",
+                2,
+                "CREATE TABLE t(a TEXT COLLATE NOCASE)",
+            ),
+            schema_cell(2, "index", "i", "t", 3, "CREATE INDEX i ON t(a)"),
+        ];
+        write_leaf(&mut file[..PAGE_SIZE], 100, 0x0d, &schema);
+        let values = ["éa", "Éb", "ea", "x"];
+        let rows_cells: Vec<_> = (values.iter().zip(1..))
+            .map(|(a, rowid)| table_cell(rowid, &record(&[text(a)])))
+            .collect();
+        write_leaf(&mut file[PAGE_SIZE..2 * PAGE_SIZE], 0, 0x0d, &rows_cells);
+        // The keys, in the order of SQLite's NOCASE, which only folds ASCII letters.
+        let keys: Vec<_> = [(3, "ea"), (4, "x"), (2, "Éb"), (1, "éa")]
+            .iter()
+            .map(|&(rowid, a)| index_cell(&record(&[text(a), SendValue::Int(rowid)])))
+            .collect();
+        write_leaf(&mut file[2 * PAGE_SIZE..], 0, 0x0a, &keys);
+
+        let mut db = open_database("case-folding", &file);
+        let texts = |values: &[&str]| -> Vec<Vec<SendValue>> {
+            values.iter().map(|&a| vec![text(a)]).collect()
+        };
+        assert_eq!(rows(&db, "select a from t where a = 'ÉA'"), texts(&[]));
+        assert_eq!(
+            rows(&db, "select a from t where a like 'É%'"),
+            texts(&["Éb"])
+        );
+        assert_eq!(
+            rows(&db, "select a from t order by a"),
+            texts(&["ea", "x", "Éb", "éa"])
+        );
+
+        db.set_case_folding(CaseFolding::Unicode);
+        assert_eq!(rows(&db, "select a from t where a = 'ÉA'"), texts(&["éa"]));
+        assert_eq!(rows(&db, "select a from t where a > 'ÉA'"), texts(&["Éb"]));
+        assert_eq!(
+            rows(&db, "select a from t where a like 'É%'"),
+            texts(&["éa", "Éb"])
+        );
+        assert_eq!(
+            rows(&db, "select a from t order by a"),
+            texts(&["ea", "x", "éa", "Éb"])
+        );
+    }
+
+    /// A database of the table `t(id INTEGER PRIMARY KEY, a INTEGER, c REAL, b TEXT)`
+    /// of the rows `(a, c, b)`, with ids from 1, and of its index `i` on `a`.
+    fn indexed_table(name: &str, rows: &[(i64, f64, &str)]) -> Db {
+        const PAGE_SIZE: usize = 512;
+        let mut file = database_file(PAGE_SIZE, 1);
+        let records: Vec<_> = (rows.iter().zip(1..))
+            .map(|(&(a, c, b), rowid)| {
+                let values = [
+                    SendValue::Null,
+                    SendValue::Int(a),
+                    SendValue::Float(c),
+                    text(b),
+                ];
+                (rowid, record(&values))
+            })
+            .collect();
+        let table = table_btree(&mut file, PAGE_SIZE, &records, 8);
+        let mut keys: Vec<_> = (rows.iter().zip(1..))
+            .map(|(&(a, ..), rowid)| (a, rowid))
+            .collect();
+        keys.sort();
+        let keys: Vec<_> = (keys.into_iter())
+            .map(|(a, rowid)| record(&[SendValue::Int(a), SendValue::Int(rowid)]))
+            .collect();
+        let index = index_btree(&mut file, PAGE_SIZE, &keys, 8);
+        let schema = [
+            schema_cell(
+                1,
+                "table",
+                "t",
+                "t",
+                table.into(),
+                "CREATE TABLE t(id INTEGER PRIMARY KEY, a INTEGER, c REAL, b TEXT)",
+            ),
+            schema_cell(2, "index", "i", "t", index.into(), "CREATE INDEX i ON t(a)"),
+        ];
+        write_leaf(&mut file[..PAGE_SIZE], 100, 0x0d, &schema);
+        open_database(name, &file)
+    }
+
+    #[test]
+    fn comparison_affinity() {
+        let values = [
+            (7, 0.5, "7"),
+            (12, 50.5, "12"),
+            (100, 60.0, "100"),
+            (491, 12.25, "491"),
+        ];
+        let db = indexed_table("affinity", &values);
+        let ids = |sql| -> Vec<i64> {
+            rows(&db, sql)
+                .into_iter()
+                .map(|row| match row[..] {
+                    [SendValue::Int(id)] => id,
+                    _ => panic!("expected an id: {row:?}"),
+                })
+                .collect()
+        };
+        // Text compared with an INTEGER or REAL column is converted to a number, in
+        // rowid lookups, index searches, scan filters and other expressions alike.
+        assert_eq!(ids("select id from t where id = '2'"), [2]);
+        assert_eq!(ids("select id from t where a = '7'"), [1]);
+        assert_eq!(ids("select id from t where a > '490'"), [4]);
+        assert_eq!(
+            ids("select id from t where a between '10' and '100'"),
+            [2, 3]
+        );
+        assert_eq!(ids("select id from t where '50.5' < c"), [3]);
+        assert_eq!(ids("select id from t where c = '60' or a = '12'"), [2, 3]);
+        assert_eq!(ids("select id from t where a = '7x'"), Vec::<i64>::new());
+        // Numbers compared with a TEXT column are converted to text.
+        assert_eq!(ids("select id from t where b = 12"), [2]);
+        assert_eq!(ids("select id from t where b > 100"), [1, 2, 4]);
+        assert_eq!(ids("select id from t where a = b"), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn explain_query_plan() {
+        let values: Vec<_> = (0..200).map(|i| (i % 50, i as f64, "")).collect();
+        let db = indexed_table("query-plan", &values);
+        let plan = |sql: &str| -> Vec<SendValue> {
+            let rows = rows(&db, &format!("explain query plan {sql}"));
+            rows.into_iter().map(|row| row[3].clone()).collect()
+        };
+        assert_eq!(plan("select * from t"), [text("SCAN t")]);
+        assert_eq!(
+            plan("select * from t where a = 12"),
+            [text("SEARCH t USING INDEX i (a=?)")]
+        );
+        assert_eq!(
+            plan("select * from t where id = 2"),
+            [text("SEARCH t USING INTEGER PRIMARY KEY (rowid=?)")]
+        );
+        assert_eq!(
+            plan("select a from t where a > 12"),
+            [text("SEARCH t USING COVERING INDEX i (a>?)")]
+        );
+        // The terms of a join's WHERE clause filtering one table are pushed down.
+        assert_eq!(
+            plan("select t1.b from t t1 join t t2 on t2.id = t1.c where t1.a = 12 and t2.id < 5"),
+            [
+                text("SEARCH t1 USING INDEX i (a=?)"),
+                text("SEARCH t2 USING INTEGER PRIMARY KEY (rowid<?)"),
+            ]
+        );
+        // Unless the missing rows of the table are padded with NULLs.
+        assert_eq!(
+            plan("select t1.b from t t1 left join t t2 on t2.id = t1.c where t2.a = 12"),
+            [text("SCAN t1"), text("SCAN t2")]
+        );
+    }
 }
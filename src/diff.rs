@@ -0,0 +1,409 @@
+//! Row-level comparison of two databases, emitting the SQL statements that turn the
+//! first one into the second, like `sqldiff`.
+
+use std::{cmp::Ordering, collections::HashSet, io::Write, iter::Peekable};
+
+use anyhow::Context;
+
+use crate::{
+    db::{Db, TableDef, TableMetadata},
+    sql::quote_identifier,
+    value::{Collation, OwnedValue},
+};
+
+/// The values of a row, with the key identifying it: its rowid, or the values of the
+/// primary key of a WITHOUT ROWID table.
+type Row = (Vec<OwnedValue>, Vec<OwnedValue>);
+
+pub fn diff(source: &Db, target: &Db, out: &mut impl Write) -> anyhow::Result<()> {
+    let (source_metadata, target_metadata) = (source.metadata(), target.metadata());
+    let target_names: HashSet<&str> = target_metadata
+        .tables()
+        .iter()
+        .map(|t| t.name.as_str())
+        .collect();
+
+    for table in source_metadata.tables() {
+        if !target_names.contains(table.name.as_str()) {
+            writeln!(out, "DROP TABLE {};", quote_identifier(&table.name))?;
+        }
+    }
+
+    for table in target_metadata.tables() {
+        let definition = table.definition()?;
+        if definition.module.is_some() {
+            continue;
+        }
+
+        let Some(source_table) = source_metadata.table(&table.name) else {
+            writeln!(out, "{};", table.sql)?;
+            write_rows(table, rows(target, table)?, &mut std::iter::empty(), out)?;
+            continue;
+        };
+
+        let source_columns = &source_table.definition()?.columns;
+        let same_columns = source_columns.len() == definition.columns.len()
+            && source_columns
+                .iter()
+                .zip(&definition.columns)
+                .all(|(a, b)| a.name == b.name);
+
+        if !same_columns {
+            writeln!(out, "DROP TABLE {};", quote_identifier(&table.name))?;
+            writeln!(out, "{};", table.sql)?;
+            write_rows(table, rows(target, table)?, &mut std::iter::empty(), out)?;
+            continue;
+        }
+
+        write_rows(
+            table,
+            rows(target, table)?,
+            &mut rows(source, source_table)?,
+            out,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Reads the rows of `table` in key order, with the rowid alias column filled in.
+/// The rows of WITHOUT ROWID tables are read at once and sorted, so that both
+/// databases list them in the order `compare_keys` merges them in.
+fn rows<'d>(
+    db: &'d Db,
+    table: &'d TableMetadata,
+) -> anyhow::Result<Box<dyn Iterator<Item = anyhow::Result<Row>> + 'd>> {
+    let mut scanner = db.scanner(table.first_page);
+    let definition = table.definition()?;
+    let alias = definition.rowid_alias();
+    let stored = definition.stored_columns();
+    let primary_key = definition.primary_key();
+
+    let rows = std::iter::from_fn(move || {
+        let mut record = match scanner.next_record() {
+            Ok(Some(record)) => record,
+            Ok(None) => return None,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let rowid = record.rowid();
+        let mut values = vec![OwnedValue::Null; definition.columns.len()];
+        for (field, &column) in stored.iter().enumerate() {
+            values[column] = match alias {
+                Some(a) if a == column => OwnedValue::Int(rowid),
+                _ => match record.owned_field(field) {
+                    Ok(value) => value.unwrap_or(OwnedValue::Null),
+                    Err(e) => return Some(Err(e)),
+                },
+            };
+        }
+
+        let key = match definition.without_rowid {
+            false => vec![OwnedValue::Int(rowid)],
+            true => primary_key.iter().map(|&i| values[i].clone()).collect(),
+        };
+        Some(Ok((key, values)))
+    });
+    if !definition.without_rowid {
+        return Ok(Box::new(rows));
+    }
+
+    let mut rows = rows.collect::<anyhow::Result<Vec<_>>>()?;
+    rows.sort_by(|(a, _), (b, _)| compare_keys(definition, a, b));
+    Ok(Box::new(rows.into_iter().map(Ok)))
+}
+
+/// Orders the keys of the rows of a table: by rowid, or by the primary key columns
+/// with their collations.
+fn compare_keys(definition: &TableDef, a: &[OwnedValue], b: &[OwnedValue]) -> Ordering {
+    let collations = match definition.without_rowid {
+        false => vec![Collation::Binary],
+        true => (definition.primary_key().iter())
+            .map(|&i| definition.columns[i].collation())
+            .collect(),
+    };
+    a.iter()
+        .zip(b)
+        .zip(collations)
+        .map(|((a, b), collation)| collation.compare(a, b))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Merges the key-ordered `target` and `source` rows, writing an INSERT, UPDATE or
+/// DELETE for every row that differs.
+fn write_rows(
+    table: &TableMetadata,
+    target: impl Iterator<Item = anyhow::Result<Row>>,
+    source: &mut dyn Iterator<Item = anyhow::Result<Row>>,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let definition = table.definition()?;
+    let mut target = target.peekable();
+    let mut source = source.peekable();
+
+    loop {
+        let target_key = peek_key(&mut target)?;
+        let source_key = peek_key(&mut source)?;
+        let ordering = match (&source_key, &target_key) {
+            (None, None) => return Ok(()),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(s), Some(t)) => compare_keys(definition, s, t),
+        };
+
+        match ordering {
+            Ordering::Less => {
+                let (key, _) = source.next().context("missing source row")??;
+                writeln!(
+                    out,
+                    "DELETE FROM {} WHERE {};",
+                    quote_identifier(&table.name),
+                    key_condition(table, &key)?
+                )?;
+            }
+            Ordering::Greater => {
+                let (key, values) = target.next().context("missing target row")??;
+                write_insert(table, &key, &values, out)?;
+            }
+            Ordering::Equal => {
+                let (key, old) = source.next().context("missing source row")??;
+                let (_, new) = target.next().context("missing target row")??;
+                write_update(table, &key, &old, &new, out)?;
+            }
+        }
+    }
+}
+
+fn peek_key<I: Iterator<Item = anyhow::Result<Row>>>(
+    rows: &mut Peekable<I>,
+) -> anyhow::Result<Option<Vec<OwnedValue>>> {
+    match rows.peek() {
+        Some(Ok((key, _))) => Ok(Some(key.clone())),
+        Some(Err(_)) => Err(rows.next().unwrap().unwrap_err()),
+        None => Ok(None),
+    }
+}
+
+fn write_insert(
+    table: &TableMetadata,
+    key: &[OwnedValue],
+    values: &[OwnedValue],
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let definition = table.definition()?;
+    // Generated columns are computed by the database the statement is applied to.
+    let (mut columns, mut literals): (Vec<String>, Vec<String>) = (definition.columns.iter())
+        .zip(values)
+        .filter(|(column, _)| column.generated().is_none())
+        .map(|(column, value)| (quote_identifier(&column.name), sql_literal(value)))
+        .unzip();
+
+    if definition.has_rowid() && definition.rowid_alias().is_none() {
+        columns.insert(0, "rowid".to_string());
+        literals.insert(0, sql_literal(&key[0]));
+    }
+
+    writeln!(
+        out,
+        "INSERT INTO {}({}) VALUES({});",
+        quote_identifier(&table.name),
+        columns.join(","),
+        literals.join(",")
+    )?;
+    Ok(())
+}
+
+fn write_update(
+    table: &TableMetadata,
+    key: &[OwnedValue],
+    old: &[OwnedValue],
+    new: &[OwnedValue],
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let assignments: Vec<String> = table
+        .definition()?
+        .columns
+        .iter()
+        .zip(old.iter().zip(new))
+        .filter(|(column, (old, new))| column.generated().is_none() && old != new)
+        .map(|(column, (_, new))| {
+            format!("{}={}", quote_identifier(&column.name), sql_literal(new))
+        })
+        .collect();
+
+    if assignments.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(
+        out,
+        "UPDATE {} SET {} WHERE {};",
+        quote_identifier(&table.name),
+        assignments.join(", "),
+        key_condition(table, key)?
+    )?;
+    Ok(())
+}
+
+/// The WHERE condition selecting the row with the given key.
+fn key_condition(table: &TableMetadata, key: &[OwnedValue]) -> anyhow::Result<String> {
+    let definition = table.definition()?;
+    let columns: Vec<String> = match (definition.without_rowid, definition.rowid_alias()) {
+        (true, _) => (definition.primary_key().iter())
+            .map(|&i| quote_identifier(&definition.columns[i].name))
+            .collect(),
+        (false, Some(i)) => vec![quote_identifier(&definition.columns[i].name)],
+        (false, None) => vec!["rowid".to_string()],
+    };
+    let terms: Vec<String> = columns
+        .iter()
+        .zip(key)
+        .map(|(column, value)| format!("{column}={}", sql_literal(value)))
+        .collect();
+    Ok(terms.join(" AND "))
+}
+
+fn sql_literal(value: &OwnedValue) -> String {
+    match value {
+        OwnedValue::Null => "NULL".to_string(),
+        OwnedValue::Int(i) => i.to_string(),
+        // sqldiff writes infinities as literals that overflow to them.
+        OwnedValue::Float(f) if f.is_infinite() => match f.is_sign_positive() {
+            true => "1e999".to_string(),
+            false => "-1e999".to_string(),
+        },
+        OwnedValue::Float(f) => format!("{f:?}"),
+        OwnedValue::String(s) => format!("'{}'", s.replace('\'', "''")),
+        OwnedValue::Blob(b) => {
+            let hex: String = b.iter().map(|byte| format!("{byte:02X}")).collect();
+            format!("X'{hex}'")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    fn table() -> TableMetadata {
+        TableMetadata::new(
+            "users".to_string(),
+            "create table users(id integer primary key, name text)".to_string(),
+            2,
+        )
+    }
+
+    fn row(id: i64, name: &str) -> anyhow::Result<Row> {
+        Ok((
+            vec![OwnedValue::Int(id)],
+            vec![
+                OwnedValue::Int(id),
+                OwnedValue::String(Rc::new(name.to_string())),
+            ],
+        ))
+    }
+
+    #[test]
+    fn row_changes() {
+        let source = vec![row(1, "a"), row(2, "b"), row(3, "c")];
+        let target = vec![row(1, "a"), row(3, "it's"), row(4, "d")];
+
+        let mut out = Vec::new();
+        write_rows(
+            &table(),
+            target.into_iter(),
+            &mut source.into_iter(),
+            &mut out,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "DELETE FROM users WHERE id=2;\n\
+             UPDATE users SET name='it''s' WHERE id=3;\n\
+             INSERT INTO users(id,name) VALUES(4,'d');\n"
+        );
+    }
+
+    #[test]
+    fn without_rowid_changes() {
+        let table = TableMetadata::new(
+            "pairs".to_string(),
+            "create table pairs(a text collate nocase, b, c, primary key(a, b)) without rowid"
+                .to_string(),
+            2,
+        );
+        let row = |a: &str, b: i64, c: i64| -> anyhow::Result<Row> {
+            let (a, b) = (
+                OwnedValue::String(Rc::new(a.to_string())),
+                OwnedValue::Int(b),
+            );
+            Ok((vec![a.clone(), b.clone()], vec![a, b, OwnedValue::Int(c)]))
+        };
+        let source = vec![row("a", 1, 1), row("B", 1, 2), row("c", 1, 3)];
+        let target = vec![row("a", 1, 1), row("a", 2, 0), row("b", 1, 5)];
+
+        let mut out = Vec::new();
+        write_rows(
+            &table,
+            target.into_iter(),
+            &mut source.into_iter(),
+            &mut out,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "INSERT INTO pairs(a,b,c) VALUES('a',2,0);\n\
+             UPDATE pairs SET a='b', c=5 WHERE a='B' AND b=1;\n\
+             DELETE FROM pairs WHERE a='c' AND b=1;\n"
+        );
+    }
+
+    #[test]
+    fn generated_columns() {
+        let table = TableMetadata::new(
+            "t".to_string(),
+            "create table t(id integer primary key, a, b as (abs(a)), c as (abs(a)) stored)"
+                .to_string(),
+            2,
+        );
+        let row = |id: i64, a: i64| -> anyhow::Result<Row> {
+            let (id, a) = (OwnedValue::Int(id), OwnedValue::Int(a));
+            // Virtual columns are read as NULL, stored ones as computed.
+            Ok((vec![id.clone()], vec![id, a.clone(), OwnedValue::Null, a]))
+        };
+        let source = vec![row(1, 1), row(2, 2)];
+        let target = vec![row(1, 5), row(2, 2), row(3, 3)];
+
+        let mut out = Vec::new();
+        write_rows(
+            &table,
+            target.into_iter(),
+            &mut source.into_iter(),
+            &mut out,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "UPDATE t SET a=5 WHERE id=1;\n\
+             INSERT INTO t(id,a) VALUES(3,3);\n"
+        );
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(sql_literal(&OwnedValue::Float(1.0)), "1.0");
+        assert_eq!(sql_literal(&OwnedValue::Float(f64::INFINITY)), "1e999");
+        assert_eq!(sql_literal(&OwnedValue::Float(f64::NEG_INFINITY)), "-1e999");
+        assert_eq!(
+            sql_literal(&OwnedValue::Blob(Rc::new(vec![0xab, 1]))),
+            "X'AB01'"
+        );
+        assert_eq!(quote_identifier("my table"), "\"my table\"");
+    }
+}
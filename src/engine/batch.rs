@@ -0,0 +1,111 @@
+//! Rows passed between operators a batch at a time, stored by column so that
+//! expressions are evaluated over a whole column per step rather than row by row.
+
+use crate::value::OwnedValue;
+
+/// The most rows operators put in a batch.
+pub const BATCH_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Batch {
+    columns: Vec<Vec<OwnedValue>>,
+    /// Kept apart from the columns, as rows may have none.
+    len: usize,
+}
+
+impl Batch {
+    pub fn from_columns(columns: Vec<Vec<OwnedValue>>, len: usize) -> Self {
+        debug_assert!(columns.iter().all(|column| column.len() == len));
+        Self { columns, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn column(&self, i: usize) -> &[OwnedValue] {
+        &self.columns[i]
+    }
+
+    pub fn push_row(&mut self, row: &[OwnedValue]) {
+        if self.len == 0 {
+            self.columns = vec![Vec::with_capacity(BATCH_SIZE); row.len()];
+        }
+        for (column, value) in self.columns.iter_mut().zip(row) {
+            column.push(value.clone());
+        }
+        self.len += 1;
+    }
+
+    /// Replaces the content of `row` with the values of the `i`th row.
+    pub fn read_row(&self, i: usize, row: &mut Vec<OwnedValue>) {
+        row.clear();
+        row.extend(self.columns.iter().map(|column| column[i].clone()));
+    }
+
+    /// Keeps the rows for which `keep` is true.
+    pub fn retain(&mut self, keep: &[bool]) {
+        for column in &mut self.columns {
+            let mut kept = keep.iter();
+            column.retain(|_| kept.next() == Some(&true));
+        }
+        self.len = keep.iter().filter(|&&kept| kept).count();
+    }
+
+    /// Removes the rows from the `at`th on, returning them.
+    fn split_off(&mut self, at: usize) -> Batch {
+        let columns = self.columns.iter_mut().map(|c| c.split_off(at)).collect();
+        let rest = Batch::from_columns(columns, self.len - at);
+        self.len = at;
+        rest
+    }
+}
+
+/// Hands out the rows of the batches of an operator one at a time, which is how
+/// operators producing batches implement `next_row`.
+#[derive(Debug, Default)]
+pub struct BatchRows {
+    batch: Batch,
+    position: usize,
+    row: Vec<OwnedValue>,
+}
+
+impl BatchRows {
+    /// Whether all the rows of the batch were handed out.
+    pub fn is_done(&self) -> bool {
+        self.position == self.batch.len()
+    }
+
+    pub fn refill(&mut self, batch: Batch) {
+        self.batch = batch;
+        self.position = 0;
+    }
+
+    pub fn next_row(&mut self) -> Option<&[OwnedValue]> {
+        if self.is_done() {
+            return None;
+        }
+        self.batch.read_row(self.position, &mut self.row);
+        self.position += 1;
+        Some(&self.row)
+    }
+
+    /// The rows not handed out yet, for an operator switching from `next_row` to
+    /// `next_batch`.
+    pub fn take_rest(&mut self) -> Option<Batch> {
+        if self.is_done() {
+            return None;
+        }
+        let rest = self.batch.split_off(self.position);
+        self.clear();
+        Some(rest)
+    }
+
+    pub fn clear(&mut self) {
+        self.refill(Batch::default());
+    }
+}
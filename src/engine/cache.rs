@@ -0,0 +1,244 @@
+//! Caches kept by a database across queries: parsed statements, and results of recent
+//! queries served again while the file is unchanged.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{pager::FileVersion, sql::ast::Statement, value::SendValue};
+
+pub type CachedRows = Arc<Vec<Vec<SendValue>>>;
+
+/// The `capacity` most recently used statements, keyed by SQL text, so that running
+/// the same query again skips parsing it.
+#[derive(Debug)]
+pub struct StatementCache {
+    capacity: usize,
+    state: Mutex<StatementCacheState>,
+}
+
+#[derive(Debug, Default)]
+struct StatementCacheState {
+    statements: HashMap<String, (Arc<Statement>, u64)>,
+    clock: u64,
+}
+
+impl StatementCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn get_or_parse(
+        &self,
+        sql: &str,
+        parse: impl FnOnce(&str) -> anyhow::Result<Statement>,
+    ) -> anyhow::Result<Arc<Statement>> {
+        {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.clock += 1;
+            let clock = state.clock;
+            if let Some((statement, last_used)) = state.statements.get_mut(sql) {
+                *last_used = clock;
+                return Ok(statement.clone());
+            }
+        }
+
+        let statement = Arc::new(parse(sql)?);
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.statements.len() >= self.capacity {
+            let oldest = state
+                .statements
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                state.statements.remove(&oldest);
+            }
+        }
+        let clock = state.clock;
+        state
+            .statements
+            .insert(sql.to_string(), (statement.clone(), clock));
+
+        Ok(statement)
+    }
+}
+
+/// What a cached result is the result of: the SQL text of the query, and the values
+/// bound to its parameters as encoded by `Params::cache_key`.
+pub type ResultKey = (String, Vec<u8>);
+
+/// Query results keyed by SQL text and parameter values, holding at most `capacity`
+/// bytes of rows. Entries read from an older version of the file are never served, and
+/// the least recently used entries are evicted first.
+#[derive(Debug)]
+pub struct ResultCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<ResultKey, CacheEntry>,
+    size: usize,
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    version: FileVersion,
+    rows: CachedRows,
+    size: usize,
+    last_used: u64,
+}
+
+impl ResultCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn get(&self, key: &ResultKey, version: FileVersion) -> Option<CachedRows> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.clock += 1;
+        let clock = state.clock;
+
+        let entry = state.entries.get_mut(key)?;
+        if entry.version != version {
+            let size = entry.size;
+            state.entries.remove(key);
+            state.size -= size;
+            return None;
+        }
+
+        entry.last_used = clock;
+        Some(entry.rows.clone())
+    }
+
+    /// Stores the `size` bytes of `rows` produced by the query `key`, evicting older
+    /// entries to make room for them.
+    pub fn insert(
+        &self,
+        key: ResultKey,
+        version: FileVersion,
+        rows: Vec<Vec<SendValue>>,
+        size: usize,
+    ) {
+        if size > self.capacity {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(previous) = state.entries.remove(&key) {
+            state.size -= previous.size;
+        }
+
+        while state.size + size > self.capacity {
+            let Some(oldest) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(sql, _)| sql.clone())
+            else {
+                break;
+            };
+            let evicted = state.entries.remove(&oldest).unwrap();
+            state.size -= evicted.size;
+        }
+
+        state.clock += 1;
+        let entry = CacheEntry {
+            version,
+            rows: Arc::new(rows),
+            size,
+            last_used: state.clock,
+        };
+        state.size += size;
+        state.entries.insert(key, entry);
+    }
+
+    /// Drops every entry, e.g. once the file changed.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.entries.clear();
+        state.size = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V1: FileVersion = FileVersion {
+        change_counter: 1,
+        schema_cookie: 1,
+        wal: None,
+    };
+    const V2: FileVersion = FileVersion {
+        change_counter: 2,
+        schema_cookie: 1,
+        wal: None,
+    };
+
+    #[test]
+    fn reuses_recent_statements() {
+        let cache = StatementCache::new(2);
+        let parses = std::cell::Cell::new(0);
+        let parse = |sql: &str| {
+            parses.set(parses.get() + 1);
+            crate::sql::parse_statement(sql, false)
+        };
+
+        let a = cache.get_or_parse("select a from t", parse).unwrap();
+        assert!(Arc::ptr_eq(
+            &a,
+            &cache.get_or_parse("select a from t", parse).unwrap()
+        ));
+        cache.get_or_parse("select b from t", parse).unwrap();
+        cache.get_or_parse("select a from t", parse).unwrap();
+        cache.get_or_parse("select c from t", parse).unwrap();
+        assert_eq!(parses.get(), 3);
+
+        cache.get_or_parse("select a from t", parse).unwrap();
+        cache.get_or_parse("select b from t", parse).unwrap();
+        assert_eq!(parses.get(), 4);
+        assert!(cache.get_or_parse("select from", parse).is_err());
+    }
+
+    fn key(sql: &str) -> ResultKey {
+        (sql.to_string(), Vec::new())
+    }
+
+    fn rows(n: i64) -> Vec<Vec<SendValue>> {
+        vec![vec![SendValue::Int(n)]]
+    }
+
+    #[test]
+    fn evicts_stale_and_least_recently_used() {
+        let cache = ResultCache::new(100);
+        cache.insert(key("a"), V1, rows(1), 40);
+        cache.insert(key("b"), V1, rows(2), 40);
+
+        assert_eq!(*cache.get(&key("a"), V1).unwrap(), rows(1));
+        cache.insert(key("c"), V1, rows(3), 40);
+        assert!(cache.get(&key("b"), V1).is_none());
+        assert!(cache.get(&key("a"), V1).is_some());
+
+        assert!(cache.get(&key("c"), V2).is_none());
+        assert!(cache.get(&key("c"), V1).is_none());
+
+        cache.insert(key("d"), V1, rows(4), 200);
+        assert!(cache.get(&key("d"), V1).is_none());
+    }
+}
@@ -0,0 +1,261 @@
+//! Estimates of the work done by the ways of reading a table, which the planner
+//! compares to choose one.
+
+use crate::{db::TableStatistics, page::Cell, pager::Pager, sql::ast};
+
+/// Reading a page, the unit the other costs are relative to.
+const PAGE_COST: f64 = 1.0;
+/// Checking a record against the filters pushed down into the scan.
+const FILTER_COST: f64 = 0.01;
+/// Decoding the columns of a row and passing it on.
+const ROW_COST: f64 = 0.05;
+/// Starting the thread of an `Exchange` and handing its batches over.
+const EXCHANGE_COST: f64 = 50.0;
+/// Looking a row up by rowid, descending pages that are mostly cached.
+const LOOKUP_COST: f64 = 0.2;
+
+/// The size of a table, estimated from the leftmost path of its b-tree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TableEstimate {
+    pub pages: f64,
+    pub rows: f64,
+}
+
+impl TableEstimate {
+    /// Descends from `root` to the first leaf, assuming the pages of each level have
+    /// as many cells as the first one. Reads one page per level of the b-tree.
+    pub fn of_btree(pager: &Pager, root: usize) -> anyhow::Result<Self> {
+        let mut pages = 0.0;
+        let mut level_pages = 1.0;
+        let mut number = root;
+        loop {
+            let page = pager.read_page(number)?;
+            pages += level_pages;
+            let cells = page.cells.len() as f64;
+            let Some(rightmost) = page.header.rightmost_pointer else {
+                return Ok(TableEstimate {
+                    pages,
+                    rows: level_pages * cells,
+                });
+            };
+
+            level_pages *= cells + 1.0;
+            number = match page.cells.first() {
+                Some(Cell::TableInterior(cell)) => cell.left_child_page,
+                Some(Cell::Index(cell)) => cell.left_child_page.unwrap_or(rightmost),
+                _ => rightmost,
+            } as usize;
+        }
+    }
+
+    /// Replaces the estimated number of rows with the one counted by ANALYZE.
+    pub fn with_statistics(mut self, statistics: Option<&TableStatistics>) -> Self {
+        if let Some(statistics) = statistics {
+            self.rows = statistics.rows as f64;
+        }
+        self
+    }
+}
+
+/// How the rows of a table are read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessPath {
+    /// Every record, on the thread of the query.
+    Scan,
+    /// Every record, on threads of their own running concurrently with the operators
+    /// consuming the rows, each scanning a part of the table.
+    ParallelScan { workers: usize },
+    /// The keys of an index within the bounds the query sets on their first column,
+    /// or all of them if it sets none.
+    IndexSearch {
+        /// The position of the index in the schema.
+        index: usize,
+        /// The estimated pages of the index.
+        pages: f64,
+        /// The fraction of the keys within the bounds.
+        range: f64,
+        /// Whether the keys hold the columns of the query, sparing the lookups of the
+        /// rows.
+        covering: bool,
+    },
+    /// The row with the rowid the query compares it to.
+    RowidSeek,
+    /// The records within the bounds the query sets on the rowid.
+    RowidRange {
+        /// The fraction of the records within the bounds.
+        range: f64,
+    },
+}
+
+impl AccessPath {
+    /// The cost of reading `table` for a query keeping `selectivity` of its rows.
+    pub fn cost(self, table: &TableEstimate, selectivity: f64) -> f64 {
+        let kept = table.rows * selectivity * ROW_COST;
+        let scanned = table.pages * PAGE_COST + table.rows * FILTER_COST;
+        match self {
+            AccessPath::Scan => scanned + kept,
+            // The pages are read and the records filtered while the rows already
+            // produced are consumed.
+            AccessPath::ParallelScan { workers } => {
+                let workers = workers.max(1) as f64;
+                EXCHANGE_COST * workers + (scanned / workers).max(kept)
+            }
+            AccessPath::IndexSearch {
+                pages,
+                range,
+                covering,
+                ..
+            } => {
+                let keys = table.rows * range;
+                let lookups = if covering { 0.0 } else { keys * LOOKUP_COST };
+                pages * range * PAGE_COST + keys * FILTER_COST + lookups + kept
+            }
+            AccessPath::RowidSeek => LOOKUP_COST + ROW_COST,
+            AccessPath::RowidRange { range } => range * scanned + kept,
+        }
+    }
+
+    /// The cheapest of `paths` for reading `table`.
+    pub fn choose(
+        paths: impl IntoIterator<Item = AccessPath>,
+        table: &TableEstimate,
+        selectivity: f64,
+    ) -> Self {
+        paths
+            .into_iter()
+            .min_by(|a, b| {
+                let cost = |path: &AccessPath| path.cost(table, selectivity);
+                cost(a).total_cmp(&cost(b))
+            })
+            .unwrap_or(AccessPath::Scan)
+    }
+}
+
+/// The fraction of the keys of an index within `bounds` bounds on its first column,
+/// named `column`, which are the same value if `equal`. Without statistics, SQLite
+/// assumes each value of an indexed column is held by ten rows.
+pub fn range_selectivity(
+    column: &str,
+    equal: bool,
+    bounds: usize,
+    table: &TableEstimate,
+    statistics: Option<&TableStatistics>,
+) -> f64 {
+    let analyzed = statistics.and_then(|statistics| {
+        let per_value = statistics.rows_per_value.get(column)?;
+        Some(*per_value as f64 / statistics.rows.max(1) as f64)
+    });
+    match (equal, bounds) {
+        (true, _) => analyzed.unwrap_or(10.0 / table.rows.max(10.0)),
+        (false, 0) => 1.0,
+        (false, bounds) => 0.25f64.powi(bounds as i32),
+    }
+}
+
+/// The fraction of the rows `expr` holds for. Equalities on the first column of an
+/// index use the statistics of the table, if any; the other terms are guessed from
+/// their shape like SQLite does without statistics.
+pub fn selectivity(expr: &ast::Expr, statistics: Option<&TableStatistics>) -> f64 {
+    let selectivity = |expr| selectivity(expr, statistics);
+    match expr {
+        ast::Expr::Binary(binary) => match binary.op {
+            ast::BinaryOperator::And => selectivity(&binary.lhs) * selectivity(&binary.rhs),
+            ast::BinaryOperator::Or => {
+                let (lhs, rhs) = (selectivity(&binary.lhs), selectivity(&binary.rhs));
+                (lhs + rhs - lhs * rhs).min(1.0)
+            }
+            ast::BinaryOperator::Eq | ast::BinaryOperator::Is => {
+                let analyzed = |expr: &ast::Expr| {
+                    let (ast::Expr::Column(column), Some(statistics)) = (expr, statistics) else {
+                        return None;
+                    };
+                    let per_value = statistics.rows_per_value.get(&column.name.to_lowercase())?;
+                    Some(*per_value as f64 / statistics.rows.max(1) as f64)
+                };
+                analyzed(&binary.lhs)
+                    .or_else(|| analyzed(&binary.rhs))
+                    .unwrap_or(0.1)
+            }
+            ast::BinaryOperator::Lt
+            | ast::BinaryOperator::LtEq
+            | ast::BinaryOperator::Gt
+            | ast::BinaryOperator::GtEq => 0.25,
+            _ => 0.5,
+        },
+        ast::Expr::Between(between) if !between.negated => 0.25 * 0.25,
+        ast::Expr::Unary(ast::UnaryExpr {
+            op: ast::UnaryOperator::Not,
+            expr,
+        }) => 1.0 - selectivity(expr),
+        _ => 0.5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql;
+
+    fn where_selectivity(sql: &str, statistics: Option<&TableStatistics>) -> f64 {
+        let ast::Statement::Select(select) = sql::parse_statement(sql, false).unwrap() else {
+            panic!("expected a select statement");
+        };
+        selectivity(&select.core.where_clause.unwrap(), statistics)
+    }
+
+    #[test]
+    fn access_path_costs() {
+        assert_eq!(where_selectivity("select * from t where a = 1", None), 0.1);
+        let selectivity =
+            where_selectivity("select * from t where a > 1 and not (b = 2 or c < 3)", None);
+        assert!((selectivity - 0.25 * 0.675).abs() < 1e-9);
+
+        let statistics = TableStatistics {
+            rows: 3000,
+            rows_per_value: [("a".to_string(), 30)].into(),
+        };
+        assert_eq!(
+            where_selectivity("select * from t where 1 = t.A and b = 2", Some(&statistics)),
+            0.01 * 0.1
+        );
+        assert_eq!(
+            where_selectivity("select * from t where a = 1", Some(&statistics)),
+            0.01
+        );
+
+        let small = TableEstimate {
+            pages: 2.0,
+            rows: 100.0,
+        };
+        let large = TableEstimate {
+            pages: 1000.0,
+            rows: 100_000.0,
+        };
+        let scans = [AccessPath::Scan, AccessPath::ParallelScan { workers: 1 }];
+        assert_eq!(AccessPath::choose(scans, &small, 1.0), AccessPath::Scan);
+        assert_eq!(AccessPath::choose(scans, &large, 0.1), scans[1]);
+        let parallel = [scans[1], AccessPath::ParallelScan { workers: 4 }];
+        assert_eq!(AccessPath::choose(parallel, &large, 0.1), parallel[1]);
+        assert_eq!(AccessPath::choose(parallel, &small, 1.0), parallel[0]);
+
+        let search = |range, covering| AccessPath::IndexSearch {
+            index: 0,
+            pages: 300.0,
+            range,
+            covering,
+        };
+        let paths = [scans[0], scans[1], search(0.001, false)];
+        assert_eq!(AccessPath::choose(paths, &large, 0.001), paths[2]);
+        let paths = [scans[0], scans[1], search(0.25, false), search(1.0, true)];
+        assert_eq!(AccessPath::choose(paths, &large, 0.01), paths[3]);
+        let paths = [scans[0], search(0.001, false), AccessPath::RowidSeek];
+        assert_eq!(AccessPath::choose(paths, &large, 0.00001), paths[2]);
+        let paths = [scans[0], scans[1], AccessPath::RowidRange { range: 0.25 }];
+        assert_eq!(AccessPath::choose(paths, &large, 0.25), paths[2]);
+        assert_eq!(
+            range_selectivity("a", true, 2, &large, Some(&statistics)),
+            0.01
+        );
+        assert_eq!(range_selectivity("a", true, 2, &large, None), 0.0001);
+    }
+}
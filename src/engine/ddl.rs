@@ -0,0 +1,574 @@
+//! Statements modifying the schema. Like those modifying rows, they are applied as they
+//! are planned, in a write transaction of their own, and produce no rows.
+
+use std::{rc::Rc, sync::Arc};
+
+use anyhow::{Context, bail, ensure};
+
+use crate::{
+    btree::{self, Btree},
+    cursor,
+    db::{TableDef, TableMetadata},
+    sql::{self, ast},
+    value::OwnedValue,
+    writer::PageWriter,
+};
+
+use super::{
+    operator::{Operator, TableFunctionScan},
+    plan::Planner,
+};
+
+/// An entry of the `sqlite_schema` table.
+struct SchemaEntry {
+    rowid: i64,
+    kind: String,
+    name: String,
+    table_name: String,
+    root: usize,
+    sql: Option<String>,
+}
+
+impl SchemaEntry {
+    fn is(&self, kind: ast::SchemaObjectKind) -> bool {
+        self.kind.eq_ignore_ascii_case(kind.as_sql())
+    }
+}
+
+impl Planner<'_> {
+    /// Applies a DROP statement. Dropping a table also drops its indexes and triggers,
+    /// and deletes its statistics and AUTOINCREMENT sequence, like in SQLite.
+    pub(super) fn compile_drop(self, drop: &ast::DropStatement) -> anyhow::Result<Operator> {
+        let name = &drop.name;
+        if drop.kind == ast::SchemaObjectKind::Table
+            && ["sqlite_schema", "sqlite_master"]
+                .iter()
+                .any(|n| n.eq_ignore_ascii_case(name))
+        {
+            bail!("table sqlite_master may not be dropped");
+        }
+
+        self.db.write(|w| {
+            let entries = schema_entries(w)?;
+            let Some(entry) = find_entry(&entries, drop.kind, name)? else {
+                ensure!(
+                    drop.if_exists,
+                    "no such {}: {name}",
+                    drop.kind.as_sql().to_lowercase()
+                );
+                return Ok(());
+            };
+            // The pages of auto_vacuum databases are moved for the root pages to come
+            // first, which isn't supported.
+            ensure!(
+                w.header().largest_root_page == 0,
+                "cannot drop {} {} from an auto_vacuum database",
+                drop.kind.as_sql().to_lowercase(),
+                entry.name
+            );
+
+            let mut dropped = vec![entry];
+            match drop.kind {
+                ast::SchemaObjectKind::Table => {
+                    let lowercase = entry.name.to_lowercase();
+                    if lowercase.starts_with("sqlite_") && !lowercase.starts_with("sqlite_stat") {
+                        bail!("table {} may not be dropped", entry.name);
+                    }
+                    // Virtual tables have no pages, their modules storing their rows.
+                    ensure!(entry.root > 0, "cannot drop virtual table {}", entry.name);
+                    dropped.extend(entries.iter().filter(|e| {
+                        (e.is(ast::SchemaObjectKind::Index) || e.is(ast::SchemaObjectKind::Trigger))
+                            && e.table_name.eq_ignore_ascii_case(&entry.name)
+                    }));
+                    delete_rows(w, &entries, "sqlite_sequence", 0, &entry.name)?;
+                    delete_statistics(w, &entries, 0, &entry.name)?;
+                }
+                ast::SchemaObjectKind::Index => {
+                    ensure!(
+                        entry.sql.is_some(),
+                        "index associated with UNIQUE or PRIMARY KEY constraint cannot be dropped"
+                    );
+                    delete_statistics(w, &entries, 1, &entry.name)?;
+                }
+                ast::SchemaObjectKind::View | ast::SchemaObjectKind::Trigger => {}
+            }
+
+            let schema = Btree::table(1);
+            for entry in dropped {
+                if entry.root > 0 {
+                    Btree::table(entry.root).destroy(w)?;
+                }
+                schema.delete_row(w, entry.rowid)?;
+            }
+            w.change_schema()
+        })?;
+        Ok(Operator::TableFunctionScan(TableFunctionScan::new(
+            Vec::new(),
+        )))
+    }
+
+    /// Applies an ALTER TABLE statement dropping a column, like SQLite: the column is
+    /// removed from the definition of the table and from its records, unless other
+    /// columns, constraints, indexes, triggers or views use it.
+    pub(super) fn compile_alter_table(
+        self,
+        alter: &ast::AlterTableStatement,
+    ) -> anyhow::Result<Operator> {
+        let ast::AlterTableAction::DropColumn(name) = &alter.action;
+        self.db.write(|w| {
+            // The statement was planned before the file was locked for writing.
+            ensure!(
+                Arc::ptr_eq(&self.db.metadata(), &self.metadata),
+                "database schema has changed"
+            );
+            let entries = schema_entries(w)?;
+            if let Some(view) = (entries.iter()).find(|e| {
+                e.is(ast::SchemaObjectKind::View) && e.name.eq_ignore_ascii_case(&alter.table)
+            }) {
+                bail!("cannot drop column from view \"{}\"", view.name);
+            }
+            let table = self.writable_table(&alter.table)?;
+            if table.name.to_lowercase().starts_with("sqlite_") {
+                bail!("table {} may not be altered", table.name);
+            }
+            let definition = table.definition()?;
+            let column = dropped_column(definition, name)?;
+
+            let sql = sql::drop_column_definition(&table.sql, column, definition.columns.len())?;
+            let altered = TableMetadata::new(table.name.clone(), sql, table.first_page);
+            if let Some(reference) = column_reference(altered.definition()?, &table.name, name) {
+                bail!(
+                    "error in table {} after drop column: no such column: {reference}",
+                    table.name
+                );
+            }
+            for entry in &entries {
+                let Some(sql) = &entry.sql else {
+                    continue;
+                };
+                let uses_table = entry.table_name.eq_ignore_ascii_case(&table.name)
+                    || sql::find_name(sql, &table.name)?.is_some();
+                if entry.is(ast::SchemaObjectKind::Table) || !uses_table {
+                    continue;
+                }
+                if let Some(reference) = sql::find_name(sql, name)? {
+                    bail!(
+                        "error in {} {} after drop column: no such column: {reference}",
+                        entry.kind,
+                        entry.name
+                    );
+                }
+            }
+
+            // Virtual generated columns aren't stored, and the records written before
+            // the column was added end before it.
+            if let Some(field) = (definition.stored_columns().iter()).position(|&i| i == column) {
+                let btree = Btree::table(table.first_page);
+                let mut next = Some(i64::MIN);
+                while let Some(rowid) = next
+                    && let Some((rowid, record)) = btree.next_row(w, rowid)?
+                {
+                    let mut fields = cursor::decode_record(&record, w.header().text_encoding)?;
+                    if field < fields.len() {
+                        fields.remove(field);
+                        let record = btree::encode_record(&fields, &w.header());
+                        btree.insert_row(w, rowid, &record)?;
+                    }
+                    next = rowid.checked_add(1);
+                }
+            }
+
+            let entry = (entries.iter())
+                .find(|e| e.is(ast::SchemaObjectKind::Table) && e.name == table.name)
+                .context("missing schema entry of the table")?;
+            let schema = Btree::table(1);
+            let record = schema
+                .row(w, entry.rowid)?
+                .context("missing schema entry of the table")?;
+            let mut fields = cursor::decode_record(&record, w.header().text_encoding)?;
+            fields[4] = OwnedValue::String(Rc::new(altered.sql.clone()));
+            let record = btree::encode_record(&fields, &w.header());
+            schema.insert_row(w, entry.rowid, &record)?;
+            w.change_schema()
+        })?;
+        Ok(Operator::TableFunctionScan(TableFunctionScan::new(
+            Vec::new(),
+        )))
+    }
+}
+
+/// The position of the column `name`, failing when it is the only column of the table,
+/// or one of its PRIMARY KEY or UNIQUE columns.
+fn dropped_column(definition: &TableDef, name: &str) -> anyhow::Result<usize> {
+    let column = (definition.columns.iter())
+        .position(|c| c.name.eq_ignore_ascii_case(name))
+        .with_context(|| format!("no such column: \"{name}\""))?;
+
+    for constraint in &definition.columns[column].constraints {
+        match constraint {
+            ast::ColumnConstraint::PrimaryKey(_) => {
+                bail!("cannot drop PRIMARY KEY column: \"{name}\"")
+            }
+            ast::ColumnConstraint::Unique(_) => {
+                bail!("cannot drop UNIQUE column: \"{name}\"")
+            }
+            ast::ColumnConstraint::NotNull(_)
+            | ast::ColumnConstraint::Check(_)
+            | ast::ColumnConstraint::Default(_)
+            | ast::ColumnConstraint::Collate(_)
+            | ast::ColumnConstraint::Generated { .. } => {}
+        }
+    }
+    for constraint in &definition.constraints {
+        match constraint {
+            ast::TableConstraint::PrimaryKey { columns, .. }
+                if columns.iter().any(|c| c.eq_ignore_ascii_case(name)) =>
+            {
+                bail!("cannot drop PRIMARY KEY column: \"{name}\"")
+            }
+            ast::TableConstraint::Unique { columns, .. }
+                if columns.iter().any(|c| c.eq_ignore_ascii_case(name)) =>
+            {
+                bail!("cannot drop UNIQUE column: \"{name}\"")
+            }
+            _ => {}
+        }
+    }
+    if definition.columns.len() == 1 {
+        bail!("cannot drop column \"{name}\": no other columns exist");
+    }
+    Ok(column)
+}
+
+/// A reference to the column `name` of `table` in the CHECK constraints and generated
+/// columns of its definition, as written.
+fn column_reference(definition: &TableDef, table: &str, name: &str) -> Option<String> {
+    let column_exprs = definition.columns.iter().flat_map(|column| {
+        column
+            .constraints
+            .iter()
+            .filter_map(|constraint| match constraint {
+                ast::ColumnConstraint::Check(check) => Some(&check.expr),
+                ast::ColumnConstraint::Generated { expr, .. } => Some(expr),
+                _ => None,
+            })
+    });
+    let table_exprs = definition
+        .constraints
+        .iter()
+        .filter_map(|constraint| match constraint {
+            ast::TableConstraint::Check(check) => Some(&check.expr),
+            _ => None,
+        });
+    let mut exprs: Vec<&ast::Expr> = column_exprs.chain(table_exprs).collect();
+    while let Some(expr) = exprs.pop() {
+        if let ast::Expr::Column(column) = expr
+            && column.name.eq_ignore_ascii_case(name)
+            && column
+                .table
+                .as_ref()
+                .is_none_or(|t| t.eq_ignore_ascii_case(table))
+        {
+            return Some(match &column.table {
+                Some(table) => format!("{table}.{}", column.name),
+                None => column.name.clone(),
+            });
+        }
+        exprs.extend(expr.children());
+    }
+    None
+}
+
+/// The entries of the schema, read in the write transaction.
+fn schema_entries(w: &mut PageWriter) -> anyhow::Result<Vec<SchemaEntry>> {
+    rows(w, 1)?
+        .into_iter()
+        .map(|(rowid, fields)| {
+            let text = |n: usize| match fields.get(n) {
+                Some(OwnedValue::String(s)) => Some(s.to_string()),
+                _ => None,
+            };
+            let root = match fields.get(3) {
+                Some(OwnedValue::Int(root)) => *root as usize,
+                _ => 0,
+            };
+            Ok(SchemaEntry {
+                rowid,
+                kind: text(0).context("invalid type field")?,
+                name: text(1).context("schema entry name should be a string")?,
+                table_name: text(2).unwrap_or_default(),
+                root,
+                sql: text(4),
+            })
+        })
+        .collect()
+}
+
+/// The entry a DROP of `kind` applies to. Tables and views share their names, and
+/// dropping one with the statement of the other fails, even with IF EXISTS.
+fn find_entry<'e>(
+    entries: &'e [SchemaEntry],
+    kind: ast::SchemaObjectKind,
+    name: &str,
+) -> anyhow::Result<Option<&'e SchemaEntry>> {
+    let named = |kind| {
+        entries
+            .iter()
+            .find(|e| e.is(kind) && e.name.eq_ignore_ascii_case(name))
+    };
+    let other = match kind {
+        ast::SchemaObjectKind::Table => Some(ast::SchemaObjectKind::View),
+        ast::SchemaObjectKind::View => Some(ast::SchemaObjectKind::Table),
+        ast::SchemaObjectKind::Index | ast::SchemaObjectKind::Trigger => None,
+    };
+    if let Some(other) = other
+        && let Some(entry) = named(other)
+    {
+        bail!(
+            "use DROP {} to delete {} {}",
+            other.as_sql(),
+            other.as_sql().to_lowercase(),
+            entry.name
+        );
+    }
+    Ok(named(kind))
+}
+
+/// Deletes the rows of the `sqlite_stat` tables about `name`, a table when `column` is
+/// their first one, or an index.
+fn delete_statistics(
+    w: &mut PageWriter,
+    entries: &[SchemaEntry],
+    column: usize,
+    name: &str,
+) -> anyhow::Result<()> {
+    for table in [
+        "sqlite_stat1",
+        "sqlite_stat2",
+        "sqlite_stat3",
+        "sqlite_stat4",
+    ] {
+        delete_rows(w, entries, table, column, name)?;
+    }
+    Ok(())
+}
+
+/// Deletes the rows of the table `table`, if it exists, whose field at `column` is
+/// `value`.
+fn delete_rows(
+    w: &mut PageWriter,
+    entries: &[SchemaEntry],
+    table: &str,
+    column: usize,
+    value: &str,
+) -> anyhow::Result<()> {
+    let Some(entry) = entries
+        .iter()
+        .find(|e| e.is(ast::SchemaObjectKind::Table) && e.name.eq_ignore_ascii_case(table))
+    else {
+        return Ok(());
+    };
+    let btree = Btree::table(entry.root);
+    for (rowid, fields) in rows(w, entry.root)? {
+        if matches!(fields.get(column), Some(OwnedValue::String(s)) if s.as_str() == value) {
+            btree.delete_row(w, rowid)?;
+        }
+    }
+    Ok(())
+}
+
+/// The rows of the table b-tree rooted at `root`, with their fields.
+fn rows(w: &mut PageWriter, root: usize) -> anyhow::Result<Vec<(i64, Vec<OwnedValue>)>> {
+    let btree = Btree::table(root);
+    let mut rows = Vec::new();
+    let mut next = Some(i64::MIN);
+    while let Some(rowid) = next
+        && let Some((rowid, record)) = btree.next_row(w, rowid)?
+    {
+        rows.push((
+            rowid,
+            cursor::decode_record(&record, w.header().text_encoding)?,
+        ));
+        next = rowid.checked_add(1);
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        db::Db,
+        testing::{schema_database, text},
+        value::SendValue,
+    };
+
+    fn rows(db: &Db, sql: &str) -> Vec<Vec<SendValue>> {
+        let mut query = db.query(sql).unwrap();
+        let mut rows = Vec::new();
+        while let Some(row) = query.next_row().unwrap() {
+            rows.push(row.iter().map(SendValue::from).collect());
+        }
+        rows
+    }
+
+    fn run(db: &Db, sql: &str) -> anyhow::Result<()> {
+        db.query(sql).map(drop)
+    }
+
+    #[test]
+    fn drop_schema_objects() {
+        let db = schema_database(
+            "drop-schema-objects",
+            &[
+                (
+                    "table",
+                    "t",
+                    "t",
+                    "CREATE TABLE t(id INTEGER PRIMARY KEY, a, b)",
+                ),
+                ("index", "i", "t", "CREATE INDEX i ON t(a)"),
+                ("table", "u", "u", "CREATE TABLE u(x)"),
+                (
+                    "table",
+                    "sqlite_stat1",
+                    "sqlite_stat1",
+                    "CREATE TABLE sqlite_stat1(tbl,idx,stat)",
+                ),
+                ("view", "v", "v", "CREATE VIEW v AS SELECT a FROM t"),
+                (
+                    "trigger",
+                    "tr",
+                    "t",
+                    "CREATE TRIGGER tr AFTER INSERT ON t BEGIN INSERT INTO u VALUES (new.a); END",
+                ),
+            ],
+        );
+        let long = "x".repeat(2000);
+        for i in 0..50 {
+            run(&db, &format!("INSERT INTO t(a, b) VALUES ({i}, '{long}')")).unwrap();
+        }
+        run(
+            &db,
+            "INSERT INTO sqlite_stat1 VALUES ('t', 'i', '50 1'), ('u', NULL, '50')",
+        )
+        .unwrap();
+
+        let error = |sql| run(&db, sql).unwrap_err().to_string();
+        assert_eq!(error("DROP TABLE v"), "use DROP VIEW to delete view v");
+        assert_eq!(
+            error("DROP TABLE IF EXISTS v"),
+            "use DROP VIEW to delete view v"
+        );
+        assert_eq!(error("DROP VIEW t"), "use DROP TABLE to delete table t");
+        assert_eq!(error("DROP INDEX t"), "no such index: t");
+        assert_eq!(error("DROP TRIGGER nope"), "no such trigger: nope");
+        assert_eq!(
+            error("DROP TABLE sqlite_schema"),
+            "table sqlite_master may not be dropped"
+        );
+        run(&db, "DROP TABLE IF EXISTS nope").unwrap();
+        run(&db, "DROP INDEX IF EXISTS nope").unwrap();
+
+        let free_pages = db.free_page_count().unwrap();
+        run(&db, "DROP INDEX i").unwrap();
+        assert!(db.free_page_count().unwrap() > free_pages);
+        assert_eq!(
+            rows(&db, "SELECT id FROM t WHERE a = 7"),
+            [[SendValue::Int(8)]]
+        );
+        assert_eq!(error("DROP INDEX i"), "no such index: i");
+
+        run(&db, "DROP TRIGGER tr").unwrap();
+        run(&db, "DROP VIEW v").unwrap();
+        run(&db, "INSERT INTO t(a) VALUES ('no trigger')").unwrap();
+        assert_eq!(rows(&db, "SELECT count(*) FROM u"), [[SendValue::Int(50)]]);
+
+        run(&db, "DROP TABLE t").unwrap();
+        assert_eq!(rows(&db, "SELECT tbl FROM sqlite_stat1"), [[text("u")]]);
+        assert_eq!(error("INSERT INTO t(a) VALUES (1)"), "no such table: t");
+        assert_eq!(rows(&db, "PRAGMA integrity_check"), [[text("ok")]]);
+
+        // Only the schema is left once the other tables are dropped.
+        run(&db, "DROP TABLE u").unwrap();
+        run(&db, "DROP TABLE sqlite_stat1").unwrap();
+        assert_eq!(
+            db.free_page_count().unwrap(),
+            db.pager().page_count().unwrap() - 1
+        );
+        assert_eq!(rows(&db, "PRAGMA integrity_check"), [[text("ok")]]);
+    }
+
+    #[test]
+    fn drop_columns() {
+        let db = schema_database(
+            "drop-columns",
+            &[
+                (
+                    "table",
+                    "t",
+                    "t",
+                    "CREATE TABLE t(id INTEGER PRIMARY KEY, a, b, c, d AS (c), e CHECK (e <> 'z'))",
+                ),
+                ("index", "i", "t", "CREATE INDEX i ON t(a)"),
+                ("table", "u", "u", "CREATE TABLE u(x)"),
+                (
+                    "trigger",
+                    "tr",
+                    "t",
+                    "CREATE TRIGGER tr AFTER INSERT ON t BEGIN INSERT INTO u VALUES (new.b); END",
+                ),
+                ("view", "v", "v", "CREATE VIEW v AS SELECT t.c FROM t"),
+            ],
+        );
+        let long = "x".repeat(1000);
+        for i in 0..20 {
+            let sql = format!("INSERT INTO t(a, b, c, e) VALUES ({i}, 'b', '{long}', 'e')");
+            run(&db, &sql).unwrap();
+        }
+
+        let error = |sql| run(&db, sql).unwrap_err().to_string();
+        assert_eq!(
+            error("ALTER TABLE t DROP COLUMN f"),
+            "no such column: \"f\""
+        );
+        assert_eq!(
+            error("ALTER TABLE t DROP COLUMN id"),
+            "cannot drop PRIMARY KEY column: \"id\""
+        );
+        assert_eq!(
+            error("ALTER TABLE v DROP COLUMN c"),
+            "cannot drop column from view \"v\""
+        );
+        assert_eq!(
+            error("ALTER TABLE t DROP COLUMN a"),
+            "error in index i after drop column: no such column: a"
+        );
+        assert_eq!(
+            error("ALTER TABLE t DROP COLUMN b"),
+            "error in trigger tr after drop column: no such column: new.b"
+        );
+        assert_eq!(
+            error("ALTER TABLE t DROP COLUMN c"),
+            "error in table t after drop column: no such column: c"
+        );
+
+        // The last column, stored in the records, and a generated column, which isn't.
+        run(&db, "ALTER TABLE t DROP COLUMN e").unwrap();
+        run(&db, "ALTER TABLE t DROP COLUMN d").unwrap();
+        assert_eq!(
+            db.metadata().table("t").unwrap().sql,
+            "CREATE TABLE t(id INTEGER PRIMARY KEY, a, b, c)"
+        );
+        assert_eq!(
+            error("ALTER TABLE t DROP COLUMN c"),
+            "error in view v after drop column: no such column: t.c"
+        );
+        assert_eq!(
+            rows(&db, "SELECT * FROM t WHERE a = 3"),
+            [[SendValue::Int(4), SendValue::Int(3), text("b"), text(&long)]]
+        );
+        run(&db, "INSERT INTO t VALUES (30, 'a', 'b', 'c')").unwrap();
+        assert_eq!(rows(&db, "SELECT count(*) FROM u"), [[SendValue::Int(21)]]);
+        assert_eq!(rows(&db, "PRAGMA integrity_check"), [[text("ok")]]);
+    }
+}
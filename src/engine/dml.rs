@@ -0,0 +1,1754 @@
+//! Statements modifying the rows of tables, and the triggers they fire. They are
+//! applied as they are planned, in a write transaction of their own, and produce no
+//! rows.
+
+use std::{cell::Cell, collections::HashMap, rc::Rc, sync::Arc};
+
+use anyhow::{Context, anyhow, bail, ensure};
+
+use crate::{
+    btree::{self, Btree},
+    cursor,
+    db::{TableDef, TableMetadata},
+    page::DbHeader,
+    sql::{self, ast},
+    value::{Affinity, Collation, OwnedValue},
+    writer::PageWriter,
+};
+
+use super::{
+    expr::Expr,
+    operator::{KeyOrder, Operator, TableFunctionScan},
+    plan::{Planner, ROWID_COLUMN, ROWID_NAMES, conjuncts},
+};
+
+/// A rowid table being written, with its indexes.
+struct TableWriter<'t> {
+    table: &'t TableMetadata,
+    definition: &'t TableDef,
+    btree: Btree,
+    /// The values of the columns missing from records written before they were added.
+    defaults: Vec<OwnedValue>,
+    /// The generated columns, with the expressions computing them from the row.
+    generated: Vec<(usize, Expr)>,
+    /// The NOT NULL columns, with the `ON CONFLICT` clause of their constraint.
+    not_null: Vec<(usize, Option<ast::ConflictResolution>)>,
+    /// The CHECK constraints, with what their errors name them.
+    checks: Vec<(String, Expr)>,
+    /// The `ON CONFLICT` clause of the INTEGER PRIMARY KEY.
+    rowid_conflict: Option<ast::ConflictResolution>,
+    indexes: Vec<IndexWriter>,
+}
+
+/// An index of the table being written, with the expressions computing its keys from
+/// the rows, the rowid ending them.
+struct IndexWriter {
+    btree: Btree,
+    keys: Vec<Expr>,
+    unique: Option<Unique>,
+}
+
+/// The UNIQUE constraint enforced by an index: no two rows can have the same key
+/// before the rowid, unless one of its values is NULL.
+struct Unique {
+    on_conflict: Option<ast::ConflictResolution>,
+    /// The constrained columns, or the name of the index when its keys hold
+    /// expressions, for the errors.
+    description: String,
+}
+
+/// How writing a row ended. It is skipped by IGNORE or `RAISE(IGNORE)`, and FAIL stops
+/// the statement, keeping the rows it wrote before.
+enum Outcome {
+    Done,
+    Skipped,
+    Failed(anyhow::Error),
+}
+
+/// A statement writing a table, run for the statement being applied or by a trigger.
+/// Its expressions are compiled against the columns of the table, when it reads its
+/// rows, followed by those of the rows of the trigger.
+enum Write<'t> {
+    Insert {
+        table: &'t TableMetadata,
+        on_conflict: Option<ast::ConflictResolution>,
+        /// The positions of the columns the values are for, the rowid being past them.
+        targets: Vec<usize>,
+        rows: Vec<Vec<Expr>>,
+    },
+    Update {
+        table: &'t TableMetadata,
+        on_conflict: Option<ast::ConflictResolution>,
+        assignments: Vec<(usize, Expr)>,
+        filter: Filter,
+    },
+    Delete {
+        table: &'t TableMetadata,
+        filter: Filter,
+    },
+    /// A SELECT of a trigger, only run for the `RAISE()` among its result columns.
+    Select {
+        condition: Option<Expr>,
+        columns: Vec<SelectColumn>,
+    },
+}
+
+/// The rows an UPDATE or DELETE applies to: those its condition is true for, only
+/// looking up the row with `rowid` when the condition requires its rowid to be equal
+/// to a value not depending on the row.
+struct Filter {
+    condition: Option<Expr>,
+    rowid: Option<Expr>,
+}
+
+enum SelectColumn {
+    Expr(Expr),
+    /// `RAISE(IGNORE)`, skipping the row the trigger fired on.
+    Ignore,
+    /// `RAISE(ABORT, message)`, or FAIL or ROLLBACK, failing the statement.
+    Raise(ast::ConflictResolution, Expr),
+}
+
+/// A trigger of a table, with its statements compiled.
+struct Trigger<'t> {
+    name: &'t str,
+    timing: ast::TriggerTiming,
+    event: Event,
+    when: Option<Expr>,
+    statements: Vec<Write<'t>>,
+}
+
+/// What a row is written by, or what a trigger fires on.
+#[derive(Debug, Clone, PartialEq)]
+enum Event {
+    Insert,
+    /// The columns set by an UPDATE, or those it has to set for a trigger to fire,
+    /// any of them when empty.
+    Update(Vec<usize>),
+    Delete,
+}
+
+/// The NEW and OLD rows of a trigger, which its statements refer to, taking the
+/// columns of the table after the ones of their own.
+struct TriggerRows<'t> {
+    definition: &'t TableDef,
+    rows: &'static [&'static str],
+}
+
+/// Runs statements in a write transaction, firing the triggers of the rows they write.
+struct Writer<'p, 'd> {
+    planner: &'p Planner<'d>,
+    /// The tables written so far, and their triggers, by lowercase name.
+    tables: HashMap<String, Rc<TableWriter<'p>>>,
+    triggers: HashMap<String, Rc<Vec<Trigger<'p>>>>,
+    /// The triggers being run, which like in SQLite without `PRAGMA
+    /// recursive_triggers`, don't fire again until they are done.
+    running: Vec<&'p str>,
+    /// Whether to insert the rows of an INSERT by rowid, when they can be, and whether
+    /// they were.
+    sort: bool,
+    sorted: bool,
+}
+
+impl Planner<'_> {
+    /// Applies an INSERT, UPDATE or DELETE statement.
+    pub(super) fn compile_write(self, statement: &ast::Statement) -> anyhow::Result<Operator> {
+        let metadata = self.metadata.clone();
+        let write = self.compile_statement(statement, None)?;
+        // Rows inserted in rowid order fill the pages of the table one after the other,
+        // so an INSERT is first applied with its rows sorted. This can't change its
+        // outcome unless one of them fails, and it is then applied again in order.
+        let unsorted = Cell::new(false);
+        let apply = |sort| {
+            self.db.write(|w| {
+                // The statement was planned before the file was locked for writing.
+                ensure!(
+                    Arc::ptr_eq(&self.db.metadata(), &metadata),
+                    "database schema has changed"
+                );
+                let mut writer = Writer {
+                    planner: &self,
+                    tables: HashMap::new(),
+                    triggers: HashMap::new(),
+                    running: Vec::new(),
+                    sort,
+                    sorted: false,
+                };
+                match writer.run(w, &write, &[], None) {
+                    Ok(Outcome::Done | Outcome::Skipped) => Ok(None),
+                    _ if writer.sorted => {
+                        unsorted.set(true);
+                        bail!("sorted rows failed")
+                    }
+                    Ok(Outcome::Failed(e)) => Ok(Some(e)),
+                    Err(e) => Err(e),
+                }
+            })
+        };
+        let failure = match apply(true) {
+            Err(_) if unsorted.get() => apply(false)?,
+            result => result?,
+        };
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(Operator::TableFunctionScan(TableFunctionScan::new(
+                Vec::new(),
+            ))),
+        }
+    }
+
+    /// Compiles a statement being applied, or of a trigger with `trigger` rows.
+    fn compile_statement(
+        &self,
+        statement: &ast::Statement,
+        trigger: Option<&TriggerRows>,
+    ) -> anyhow::Result<Write<'_>> {
+        let trigger_names = trigger.map(TriggerRows::names).unwrap_or_default();
+        let bind = |expr: &ast::Expr| match trigger {
+            Some(trigger) => trigger.bind(expr),
+            None => Ok(expr.clone()),
+        };
+        match statement {
+            ast::Statement::Insert(insert) => {
+                let table = self.writable_table(&insert.table)?;
+                let definition = table.definition()?;
+                let targets = match insert.columns.is_empty() {
+                    true => (0..definition.columns.len())
+                        .filter(|&i| definition.columns[i].generated().is_none())
+                        .collect(),
+                    false => (insert.columns.iter())
+                        .map(|name| {
+                            let i = column_position(definition, name).with_context(|| {
+                                format!("table {} has no column named {name}", table.name)
+                            })?;
+                            ensure_not_generated(definition, i, "INSERT into")?;
+                            Ok(i)
+                        })
+                        .collect::<anyhow::Result<Vec<_>>>()?,
+                };
+                let width = insert.rows.first().map_or(0, Vec::len);
+                if insert.columns.is_empty() {
+                    ensure!(
+                        width == targets.len(),
+                        "table {} has {} columns but {width} values were supplied",
+                        table.name,
+                        targets.len()
+                    );
+                } else {
+                    ensure!(
+                        width == targets.len(),
+                        "{width} values for {} columns",
+                        targets.len()
+                    );
+                }
+
+                let names: Vec<&str> = trigger_names.iter().map(String::as_str).collect();
+                let rows = (insert.rows.iter())
+                    .map(|values| {
+                        (values.iter())
+                            .map(|expr| self.compile_expr(&bind(expr)?, &names))
+                            .collect()
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(Write::Insert {
+                    table,
+                    on_conflict: insert.on_conflict,
+                    targets,
+                    rows,
+                })
+            }
+            ast::Statement::Update(update) => {
+                let table = self.writable_table(&update.table)?;
+                let definition = table.definition()?;
+                let mut names = self.declare_table(table, definition);
+                names.extend(trigger_names);
+                let names: Vec<&str> = names.iter().map(String::as_str).collect();
+                let assignments = (update.assignments.iter())
+                    .map(|(name, expr)| {
+                        let i = column_position(definition, name)
+                            .with_context(|| format!("no such column: {name}"))?;
+                        ensure_not_generated(definition, i, "UPDATE")?;
+                        Ok((i, self.compile_expr(&bind(expr)?, &names)?))
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                let condition = update.where_clause.as_ref().map(bind).transpose()?;
+                Ok(Write::Update {
+                    table,
+                    on_conflict: update.on_conflict,
+                    assignments,
+                    filter: self.compile_filter(condition.as_ref(), definition, &names)?,
+                })
+            }
+            ast::Statement::Delete(delete) => {
+                let table = self.writable_table(&delete.table)?;
+                let definition = table.definition()?;
+                let mut names = self.declare_table(table, definition);
+                names.extend(trigger_names);
+                let names: Vec<&str> = names.iter().map(String::as_str).collect();
+                let condition = delete.where_clause.as_ref().map(bind).transpose()?;
+                Ok(Write::Delete {
+                    table,
+                    filter: self.compile_filter(condition.as_ref(), definition, &names)?,
+                })
+            }
+            ast::Statement::Select(select) if trigger.is_some() => {
+                let core = &select.core;
+                ensure!(
+                    matches!(&core.from, ast::SelectFrom::Values { rows, .. }
+                        if rows.len() == 1 && rows[0].is_empty()),
+                    "cannot read tables in a trigger"
+                );
+                let names: Vec<&str> = trigger_names.iter().map(String::as_str).collect();
+                let condition = (core.where_clause.as_ref())
+                    .map(|expr| self.compile_expr(&bind(expr)?, &names))
+                    .transpose()?;
+                let columns = (core.result_columns.iter())
+                    .map(|column| {
+                        let ast::ResultColumn::Expr(column) = column else {
+                            bail!("cannot select all the columns in a trigger");
+                        };
+                        self.compile_select_column(&bind(&column.expr)?, &names)
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(Write::Select { condition, columns })
+            }
+            _ => bail!("expected a statement modifying a table"),
+        }
+    }
+
+    /// A result column of a SELECT of a trigger, where `RAISE()` is a call of a function
+    /// named so, whose first argument is a column named after its kind.
+    fn compile_select_column(
+        &self,
+        expr: &ast::Expr,
+        names: &[&str],
+    ) -> anyhow::Result<SelectColumn> {
+        let ast::Expr::Function(call) = expr else {
+            return Ok(SelectColumn::Expr(self.compile_expr(expr, names)?));
+        };
+        if !call.name.eq_ignore_ascii_case("raise") {
+            return Ok(SelectColumn::Expr(self.compile_expr(expr, names)?));
+        }
+        let kind = match call.args.first() {
+            Some(ast::Expr::Column(ast::Column { table: None, name })) => name.to_lowercase(),
+            _ => bail!("invalid RAISE()"),
+        };
+        let resolution = match (kind.as_str(), &call.args[1..]) {
+            ("ignore", []) => return Ok(SelectColumn::Ignore),
+            ("abort", [_]) => ast::ConflictResolution::Abort,
+            ("fail", [_]) => ast::ConflictResolution::Fail,
+            ("rollback", [_]) => ast::ConflictResolution::Rollback,
+            _ => bail!("invalid RAISE()"),
+        };
+        let message = self.compile_expr(&call.args[1], names)?;
+        Ok(SelectColumn::Raise(resolution, message))
+    }
+
+    /// The rows of an UPDATE or DELETE with the `condition`, compiled against `names`,
+    /// the columns of the table followed by those of the rows of its trigger.
+    fn compile_filter(
+        &self,
+        condition: Option<&ast::Expr>,
+        definition: &TableDef,
+        names: &[&str],
+    ) -> anyhow::Result<Filter> {
+        let Some(condition) = condition else {
+            return Ok(Filter {
+                condition: None,
+                rowid: None,
+            });
+        };
+        let rowid_slot = definition.columns.len();
+        // The values not depending on the row compile without the columns of the
+        // table, which are still counted.
+        let unnamed: Vec<&str> = (names.iter().enumerate())
+            .map(|(i, name)| if i <= rowid_slot { "" } else { name })
+            .collect();
+        let rowid = conjuncts(condition).into_iter().find_map(|term| {
+            let ast::Expr::Binary(binary) = term else {
+                return None;
+            };
+            if binary.op != ast::BinaryOperator::Eq {
+                return None;
+            }
+            [(&binary.lhs, &binary.rhs), (&binary.rhs, &binary.lhs)]
+                .into_iter()
+                .find_map(|(column, value)| {
+                    let Ok(Expr::Column(i)) = self.compile_expr(column, names) else {
+                        return None;
+                    };
+                    if i != rowid_slot && Some(i) != definition.rowid_alias() {
+                        return None;
+                    }
+                    self.compile_expr(value, &unnamed).ok()
+                })
+        });
+        Ok(Filter {
+            condition: Some(self.compile_expr(condition, names)?),
+            rowid,
+        })
+    }
+
+    /// The table `name`, which must be one the statements can write.
+    pub(super) fn writable_table(&self, name: &str) -> anyhow::Result<&TableMetadata> {
+        let table =
+            (self.metadata.table(name)).with_context(|| format!("no such table: {name}"))?;
+        let definition = table.definition()?;
+        ensure!(
+            definition.module.is_none(),
+            "cannot modify {}: virtual tables are read-only",
+            table.name
+        );
+        ensure!(
+            !definition.without_rowid,
+            "cannot modify {}: WITHOUT ROWID tables are read-only",
+            table.name
+        );
+        Ok(table)
+    }
+
+    /// Declares the columns of `table`, returning their names, followed by the one of
+    /// the rowid.
+    fn declare_table(&self, table: &TableMetadata, definition: &TableDef) -> Vec<String> {
+        let mut names: Vec<String> = (definition.columns.iter())
+            .map(|column| format!("{}.{}", table.name, column.name))
+            .collect();
+        names.push(format!("{}.{ROWID_COLUMN}", table.name));
+        self.declare_columns(&names, &definition.columns);
+        names
+    }
+
+    /// Compiles the triggers of `table`, the most recently created first as they fire
+    /// in this order.
+    fn compile_triggers<'t>(&'t self, table: &TableMetadata) -> anyhow::Result<Vec<Trigger<'t>>> {
+        let definition = table.definition()?;
+        let mut triggers = Vec::new();
+        for trigger in self.metadata.triggers.iter().rev() {
+            if !trigger.table_name.eq_ignore_ascii_case(&table.name) {
+                continue;
+            }
+            let ast::Statement::CreateTrigger(create) = sql::parse_create_statement(&trigger.sql)?
+            else {
+                bail!("invalid definition of trigger {}", trigger.name);
+            };
+            let program = sql::parse_trigger_program(&trigger.sql)
+                .with_context(|| format!("parse trigger {}", trigger.name))?;
+            ensure!(
+                create.timing != ast::TriggerTiming::InsteadOf,
+                "cannot modify {}: INSTEAD OF trigger {} isn't supported",
+                table.name,
+                trigger.name
+            );
+            let (event, rows): (_, &'static [&'static str]) = match &create.event {
+                ast::TriggerEvent::Insert => (Event::Insert, &["new"]),
+                ast::TriggerEvent::Delete => (Event::Delete, &["old"]),
+                ast::TriggerEvent::Update(columns) => {
+                    let columns = (columns.iter())
+                        .filter_map(|name| column_position(definition, name))
+                        .collect();
+                    (Event::Update(columns), &["new", "old"])
+                }
+            };
+            let rows = TriggerRows { definition, rows };
+            let names = rows.names();
+            for row in names.chunks(definition.columns.len() + 1) {
+                self.declare_columns(row, &definition.columns);
+            }
+            let names: Vec<&str> = names.iter().map(String::as_str).collect();
+            let when = (program.when.as_ref())
+                .map(|expr| self.compile_expr(&rows.bind(expr)?, &names))
+                .transpose()?;
+            let statements = (program.statements.iter())
+                .map(|statement| self.compile_statement(statement, Some(&rows)))
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| format!("compile trigger {}", trigger.name))?;
+            triggers.push(Trigger {
+                name: &trigger.name,
+                timing: create.timing,
+                event,
+                when,
+                statements,
+            });
+        }
+        Ok(triggers)
+    }
+
+    fn table_writer<'t>(&self, table: &'t TableMetadata) -> anyhow::Result<TableWriter<'t>> {
+        let definition = table.definition()?;
+        let names = self.declare_table(table, definition);
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+
+        let mut defaults = Vec::with_capacity(definition.columns.len());
+        let mut generated = Vec::new();
+        for (i, column) in definition.columns.iter().enumerate() {
+            let default = column.constraints.iter().find_map(|c| match c {
+                ast::ColumnConstraint::Default(expr) => Some(expr),
+                _ => None,
+            });
+            defaults.push(match default {
+                Some(expr) => self
+                    .compile_expr(expr, &[])?
+                    .eval(&[])?
+                    .apply_affinity(column.affinity()),
+                None => OwnedValue::Null,
+            });
+            if let Some((expr, _)) = column.generated() {
+                generated.push((i, self.compile_expr(expr, &names)?));
+            }
+        }
+
+        let unique_keys = definition.unique_keys();
+        let autoindex_prefix = format!("sqlite_autoindex_{}_", table.name);
+        let mut indexes = Vec::new();
+        for index in &self.metadata.indexes {
+            if !index.table_name.eq_ignore_ascii_case(&table.name) {
+                continue;
+            }
+            let (terms, unique) = match index.sql.as_deref().map(sql::parse_create_statement) {
+                Some(Ok(ast::Statement::CreateIndex(create))) => {
+                    ensure!(
+                        !create.partial,
+                        "cannot modify {}: partial index {} isn't supported",
+                        table.name,
+                        index.name
+                    );
+                    let unique = create.unique.then_some(None);
+                    (create.columns, unique)
+                }
+                Some(result) => {
+                    result.with_context(|| format!("parse definition of index {}", index.name))?;
+                    bail!("invalid definition of index {}", index.name)
+                }
+                // The indexes SQLite creates for constraints are numbered from 1.
+                None => {
+                    let (columns, on_conflict) = (index.name.strip_prefix(&autoindex_prefix))
+                        .and_then(|n| n.parse::<usize>().ok())
+                        .and_then(|n| unique_keys.get(n.checked_sub(1)?))
+                        .with_context(|| format!("no constraint for index {}", index.name))?;
+                    let terms = (columns.iter())
+                        .map(|&i| ast::OrderingTerm {
+                            expr: ast::Expr::Column(ast::Column {
+                                table: None,
+                                name: definition.columns[i].name.clone(),
+                            }),
+                            descending: false,
+                        })
+                        .collect();
+                    (terms, Some(*on_conflict))
+                }
+            };
+
+            let mut orders = Vec::with_capacity(terms.len());
+            let mut keys = Vec::with_capacity(terms.len() + 1);
+            let mut columns = Vec::with_capacity(terms.len());
+            for term in &terms {
+                let column = match &term.expr {
+                    ast::Expr::Column(column) => (definition.columns.iter())
+                        .find(|c| c.name.eq_ignore_ascii_case(&column.name)),
+                    _ => None,
+                };
+                let collation = match &term.expr {
+                    ast::Expr::Collate(collate) => Collation::from_name(&collate.collation),
+                    _ => column.map(|c| c.collation()),
+                };
+                columns.push(column.map(|c| format!("{}.{}", table.name, c.name)));
+                orders.push(KeyOrder {
+                    descending: term.descending,
+                    collation: collation.unwrap_or_default(),
+                });
+                keys.push(self.compile_expr(&term.expr, &names)?);
+            }
+            keys.push(Expr::Column(definition.columns.len()));
+            let unique = unique.map(|on_conflict| Unique {
+                on_conflict,
+                description: match columns.into_iter().collect::<Option<Vec<_>>>() {
+                    Some(columns) => columns.join(", "),
+                    None => format!("index '{}'", index.name),
+                },
+            });
+            indexes.push(IndexWriter {
+                btree: Btree::index(index.first_page, orders),
+                keys,
+                unique,
+            });
+        }
+
+        // In the order SQLite checks them in: the indexes created last first, and the
+        // constraints replacing conflicting rows by default after the others.
+        indexes.reverse();
+        indexes.sort_by_key(|index| {
+            (index.unique.as_ref())
+                .is_some_and(|u| u.on_conflict == Some(ast::ConflictResolution::Replace))
+        });
+
+        let not_null = (definition.columns.iter().enumerate())
+            .flat_map(|(i, column)| {
+                column.constraints.iter().filter_map(move |c| match c {
+                    ast::ColumnConstraint::NotNull(on_conflict) => Some((i, *on_conflict)),
+                    _ => None,
+                })
+            })
+            .collect();
+        // Unnamed constraints are named by their expression.
+        let column_checks = (definition.columns.iter())
+            .flat_map(|column| &column.constraints)
+            .filter_map(|c| match c {
+                ast::ColumnConstraint::Check(check) => Some(check),
+                _ => None,
+            });
+        let table_checks = definition.constraints.iter().filter_map(|c| match c {
+            ast::TableConstraint::Check(check) => Some(check),
+            _ => None,
+        });
+        let checks = column_checks
+            .chain(table_checks)
+            .map(|check| {
+                let name = (check.name.clone()).unwrap_or_else(|| check.expr.to_string());
+                Ok((name, self.compile_expr(&check.expr, &names)?))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let rowid_conflict = definition.rowid_alias().and_then(|i| {
+            let constraints = &definition.columns[i].constraints;
+            let column_clause = constraints.iter().find_map(|c| match c {
+                ast::ColumnConstraint::PrimaryKey(on_conflict) => Some(*on_conflict),
+                _ => None,
+            });
+            let table_clause = definition.constraints.iter().find_map(|c| match c {
+                ast::TableConstraint::PrimaryKey { on_conflict, .. } => Some(*on_conflict),
+                _ => None,
+            });
+            column_clause.or(table_clause).flatten()
+        });
+
+        Ok(TableWriter {
+            table,
+            definition,
+            btree: Btree::table(table.first_page),
+            defaults,
+            generated,
+            not_null,
+            checks,
+            rowid_conflict,
+            indexes,
+        })
+    }
+}
+
+impl<'p> Writer<'p, '_> {
+    /// Runs `write`, whose expressions take the `trigger` values after those of the
+    /// rows of its table. `on_conflict` is the `OR` clause of the statement firing the
+    /// trigger running it, which overrides its own.
+    fn run(
+        &mut self,
+        w: &mut PageWriter,
+        write: &Write<'p>,
+        trigger: &[OwnedValue],
+        on_conflict: Option<ast::ConflictResolution>,
+    ) -> anyhow::Result<Outcome> {
+        match write {
+            Write::Insert {
+                table,
+                on_conflict: own,
+                targets,
+                rows,
+            } => {
+                let on_conflict = on_conflict.or(*own);
+                let writer = self.table(table)?;
+                let mut rows = (rows.iter())
+                    .map(|values| {
+                        let mut row = writer.defaults.clone();
+                        row.push(OwnedValue::Null);
+                        for (&i, expr) in targets.iter().zip(values) {
+                            row[i] = expr.eval(trigger)?;
+                        }
+                        Ok(row)
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                if self.sort && rows.len() > 1 {
+                    self.sort = false;
+                    self.sorted = self.sort_rows(&writer, &mut rows)?;
+                }
+                for row in rows {
+                    let outcome = self.insert(w, &writer, row, on_conflict)?;
+                    if let Outcome::Failed(e) = outcome {
+                        return Ok(Outcome::Failed(e));
+                    }
+                }
+                Ok(Outcome::Done)
+            }
+            Write::Update {
+                table,
+                on_conflict: own,
+                assignments,
+                filter,
+            } => {
+                let on_conflict = on_conflict.or(*own);
+                let writer = self.table(table)?;
+                for old in self.rows(w, &writer, filter, trigger)? {
+                    let values = [&old[..], trigger].concat();
+                    let mut new = old.clone();
+                    for (i, expr) in assignments {
+                        new[*i] = expr.eval(&values)?;
+                    }
+                    let columns: Vec<usize> = assignments.iter().map(|(i, _)| *i).collect();
+                    let outcome = self.update(w, &writer, old, new, columns, on_conflict)?;
+                    if let Outcome::Failed(e) = outcome {
+                        return Ok(Outcome::Failed(e));
+                    }
+                }
+                Ok(Outcome::Done)
+            }
+            Write::Delete { table, filter } => {
+                let writer = self.table(table)?;
+                for old in self.rows(w, &writer, filter, trigger)? {
+                    if let Outcome::Failed(e) = self.delete(w, &writer, old, on_conflict)? {
+                        return Ok(Outcome::Failed(e));
+                    }
+                }
+                Ok(Outcome::Done)
+            }
+            Write::Select { condition, columns } => {
+                if let Some(condition) = condition
+                    && condition.eval(trigger)?.as_bool() != Some(true)
+                {
+                    return Ok(Outcome::Done);
+                }
+                for column in columns {
+                    match column {
+                        SelectColumn::Expr(expr) => {
+                            expr.eval(trigger)?;
+                        }
+                        SelectColumn::Ignore => return Ok(Outcome::Skipped),
+                        SelectColumn::Raise(resolution, message) => {
+                            let error = anyhow!("{}", message.eval(trigger)?);
+                            return match resolution {
+                                ast::ConflictResolution::Fail => Ok(Outcome::Failed(error)),
+                                _ => Err(error),
+                            };
+                        }
+                    }
+                }
+                Ok(Outcome::Done)
+            }
+        }
+    }
+
+    /// Inserts `row`, the values of the columns followed by the rowid, firing the
+    /// triggers of the table.
+    fn insert(
+        &mut self,
+        w: &mut PageWriter,
+        writer: &TableWriter<'p>,
+        mut row: Vec<OwnedValue>,
+        on_conflict: Option<ast::ConflictResolution>,
+    ) -> anyhow::Result<Outcome> {
+        writer.prepare(&mut row)?;
+        // Like in SQLite, the rowid the row is yet to be given is -1 for the BEFORE
+        // triggers.
+        let mut new = row.clone();
+        let rowid_slot = writer.definition.columns.len();
+        if new[rowid_slot] == OwnedValue::Null {
+            new[rowid_slot] = OwnedValue::Int(-1);
+            if let Some(i) = writer.definition.rowid_alias() {
+                new[i] = OwnedValue::Int(-1);
+            }
+        }
+        let before = ast::TriggerTiming::Before;
+        match self.fire(
+            w,
+            writer.table,
+            before,
+            &Event::Insert,
+            &[&new],
+            on_conflict,
+        )? {
+            Outcome::Done => {}
+            outcome => return Ok(outcome),
+        }
+        match writer.insert(w, &mut row, on_conflict)? {
+            Outcome::Done => {}
+            outcome => return Ok(outcome),
+        }
+        let after = ast::TriggerTiming::After;
+        self.fire(w, writer.table, after, &Event::Insert, &[&row], on_conflict)
+    }
+
+    /// Replaces the row `old` by `new`, whose `columns` were set, firing the triggers
+    /// of the table.
+    fn update(
+        &mut self,
+        w: &mut PageWriter,
+        writer: &TableWriter<'p>,
+        old: Vec<OwnedValue>,
+        mut new: Vec<OwnedValue>,
+        columns: Vec<usize>,
+        on_conflict: Option<ast::ConflictResolution>,
+    ) -> anyhow::Result<Outcome> {
+        let rowid_slot = writer.definition.columns.len();
+        let alias = writer.definition.rowid_alias();
+        for &i in &columns {
+            if let Some(alias) = alias {
+                match i == rowid_slot {
+                    true => new[alias] = new[i].clone(),
+                    false if i == alias => new[rowid_slot] = new[i].clone(),
+                    false => {}
+                }
+            }
+        }
+        ensure!(new[rowid_slot] != OwnedValue::Null, "datatype mismatch");
+        writer.prepare(&mut new)?;
+
+        let event = Event::Update(columns);
+        let before = ast::TriggerTiming::Before;
+        match self.fire(w, writer.table, before, &event, &[&new, &old], on_conflict)? {
+            Outcome::Done => {}
+            outcome => return Ok(outcome),
+        }
+        // The BEFORE triggers may have changed the row, or deleted it.
+        let OwnedValue::Int(rowid) = old[rowid_slot] else {
+            bail!("invalid rowid");
+        };
+        let Some(old) = writer.row(w, rowid)? else {
+            return Ok(Outcome::Skipped);
+        };
+        let Event::Update(columns) = &event else {
+            unreachable!()
+        };
+        for i in 0..=rowid_slot {
+            if !columns.contains(&i) && Some(i) != alias {
+                new[i] = old[i].clone();
+            }
+        }
+        writer.prepare(&mut new)?;
+
+        writer.delete(w, &old)?;
+        match writer.insert(w, &mut new, on_conflict)? {
+            Outcome::Done => {}
+            outcome => {
+                writer.store(w, &old)?;
+                return Ok(outcome);
+            }
+        }
+        let after = ast::TriggerTiming::After;
+        self.fire(w, writer.table, after, &event, &[&new, &old], on_conflict)
+    }
+
+    /// Deletes the row `old`, firing the triggers of the table.
+    fn delete(
+        &mut self,
+        w: &mut PageWriter,
+        writer: &TableWriter<'p>,
+        old: Vec<OwnedValue>,
+        on_conflict: Option<ast::ConflictResolution>,
+    ) -> anyhow::Result<Outcome> {
+        let before = ast::TriggerTiming::Before;
+        match self.fire(
+            w,
+            writer.table,
+            before,
+            &Event::Delete,
+            &[&old],
+            on_conflict,
+        )? {
+            Outcome::Done => {}
+            outcome => return Ok(outcome),
+        }
+        // The BEFORE triggers may have changed the row, or deleted it.
+        let OwnedValue::Int(rowid) = old[writer.definition.columns.len()] else {
+            bail!("invalid rowid");
+        };
+        let Some(old) = writer.row(w, rowid)? else {
+            return Ok(Outcome::Skipped);
+        };
+        writer.delete(w, &old)?;
+        let after = ast::TriggerTiming::After;
+        self.fire(w, writer.table, after, &Event::Delete, &[&old], on_conflict)
+    }
+
+    /// Runs the triggers of `table` firing at `timing` on `event`, for the `rows` they
+    /// refer to as NEW and OLD. A trigger stopping with `RAISE(IGNORE)` skips the row.
+    fn fire(
+        &mut self,
+        w: &mut PageWriter,
+        table: &'p TableMetadata,
+        timing: ast::TriggerTiming,
+        event: &Event,
+        rows: &[&[OwnedValue]],
+        on_conflict: Option<ast::ConflictResolution>,
+    ) -> anyhow::Result<Outcome> {
+        let triggers = self.triggers(table)?;
+        if triggers.is_empty() {
+            return Ok(Outcome::Done);
+        }
+        let values = rows.concat();
+        for trigger in triggers.iter() {
+            if !trigger.fires(timing, event) || self.running.contains(&trigger.name) {
+                continue;
+            }
+            if let Some(when) = &trigger.when
+                && when.eval(&values)?.as_bool() != Some(true)
+            {
+                continue;
+            }
+            self.running.push(trigger.name);
+            let outcome =
+                (trigger.statements.iter()).try_fold(Outcome::Done, |outcome, statement| {
+                    match outcome {
+                        Outcome::Done => self.run(w, statement, &values, on_conflict),
+                        outcome => Ok(outcome),
+                    }
+                });
+            self.running.pop();
+            match outcome? {
+                Outcome::Done => {}
+                outcome => return Ok(outcome),
+            }
+        }
+        Ok(Outcome::Done)
+    }
+
+    /// Sorts the `rows` to insert into the table of `writer` by rowid, unless the order
+    /// they are inserted in matters when none fails: when triggers fire, when rows may
+    /// conflict with one another on a UNIQUE constraint, or when they are given the
+    /// next rowids. Returns whether they were sorted.
+    fn sort_rows(
+        &mut self,
+        writer: &TableWriter<'p>,
+        rows: &mut Vec<Vec<OwnedValue>>,
+    ) -> anyhow::Result<bool> {
+        let unique = writer.indexes.iter().any(|index| index.unique.is_some());
+        if unique || !self.triggers(writer.table)?.is_empty() {
+            return Ok(false);
+        }
+        let rowid_slot = writer.definition.columns.len();
+        let mut rowids = Vec::with_capacity(rows.len());
+        for row in rows.iter() {
+            let mut row = row.clone();
+            writer.prepare(&mut row)?;
+            match row[rowid_slot] {
+                OwnedValue::Int(rowid) => rowids.push(rowid),
+                _ => return Ok(false),
+            }
+        }
+        let mut sorted = rowids.clone();
+        sorted.sort_unstable();
+        if sorted.windows(2).any(|pair| pair[0] == pair[1]) {
+            return Ok(false);
+        }
+        let mut keyed: Vec<_> = rowids.into_iter().zip(std::mem::take(rows)).collect();
+        keyed.sort_unstable_by_key(|(rowid, _)| *rowid);
+        *rows = keyed.into_iter().map(|(_, row)| row).collect();
+        Ok(true)
+    }
+
+    /// The rows `filter` selects, read before any of them is written.
+    fn rows(
+        &self,
+        w: &mut PageWriter,
+        writer: &TableWriter,
+        filter: &Filter,
+        trigger: &[OwnedValue],
+    ) -> anyhow::Result<Vec<Vec<OwnedValue>>> {
+        let mut rows = Vec::new();
+        let mut matching = |row: Vec<OwnedValue>| -> anyhow::Result<()> {
+            let selected = match &filter.condition {
+                Some(condition) => {
+                    let values = [&row[..], trigger].concat();
+                    condition.eval(&values)?.as_bool() == Some(true)
+                }
+                None => true,
+            };
+            if selected {
+                rows.push(row);
+            }
+            Ok(())
+        };
+        if let Some(rowid) = &filter.rowid {
+            let mut values = vec![OwnedValue::Null; writer.definition.columns.len() + 1];
+            values.extend_from_slice(trigger);
+            if let OwnedValue::Int(rowid) = rowid.eval(&values)?.apply_affinity(Affinity::Integer)
+                && let Some(row) = writer.row(w, rowid)?
+            {
+                matching(row)?;
+            }
+            return Ok(rows);
+        }
+        let mut next = Some(i64::MIN);
+        while let Some(rowid) = next
+            && let Some((rowid, record)) = writer.btree.next_row(w, rowid)?
+        {
+            matching(writer.decode(w, rowid, &record)?)?;
+            next = rowid.checked_add(1);
+        }
+        Ok(rows)
+    }
+
+    fn table(&mut self, table: &'p TableMetadata) -> anyhow::Result<Rc<TableWriter<'p>>> {
+        let key = table.name.to_lowercase();
+        if let Some(writer) = self.tables.get(&key) {
+            return Ok(writer.clone());
+        }
+        let writer = Rc::new(self.planner.table_writer(table)?);
+        self.tables.insert(key, writer.clone());
+        Ok(writer)
+    }
+
+    fn triggers(&mut self, table: &'p TableMetadata) -> anyhow::Result<Rc<Vec<Trigger<'p>>>> {
+        let key = table.name.to_lowercase();
+        if let Some(triggers) = self.triggers.get(&key) {
+            return Ok(triggers.clone());
+        }
+        let triggers = Rc::new(self.planner.compile_triggers(table)?);
+        self.triggers.insert(key, triggers.clone());
+        Ok(triggers)
+    }
+}
+
+impl Trigger<'_> {
+    fn fires(&self, timing: ast::TriggerTiming, event: &Event) -> bool {
+        self.timing == timing
+            && match (&self.event, event) {
+                (Event::Insert, Event::Insert) | (Event::Delete, Event::Delete) => true,
+                (Event::Update(columns), Event::Update(set)) => {
+                    columns.is_empty() || columns.iter().any(|i| set.contains(i))
+                }
+                _ => false,
+            }
+    }
+}
+
+impl TriggerRows<'_> {
+    /// The names of the columns of the rows, each followed by its rowid, e.g. `new#a`.
+    fn names(&self) -> Vec<String> {
+        let columns = (self.definition.columns.iter())
+            .map(|column| column.name.as_str())
+            .chain([ROWID_COLUMN]);
+        (self.rows.iter())
+            .flat_map(|row| columns.clone().map(move |column| format!("{row}#{column}")))
+            .collect()
+    }
+
+    /// Rewrites the references to the rows in `expr`, `new.a` becoming the column
+    /// `new#a` of `names`.
+    fn bind(&self, expr: &ast::Expr) -> anyhow::Result<ast::Expr> {
+        if let ast::Expr::Column(ast::Column {
+            table: Some(row),
+            name,
+        }) = expr
+            && let Some(row) = self.rows.iter().find(|r| r.eq_ignore_ascii_case(row))
+        {
+            let column = match column_position(self.definition, name) {
+                Some(i) if i < self.definition.columns.len() => &self.definition.columns[i].name,
+                Some(_) => ROWID_COLUMN,
+                None => bail!("no such column: {row}.{name}"),
+            };
+            return Ok(ast::Expr::Column(ast::Column {
+                table: None,
+                name: format!("{row}#{column}"),
+            }));
+        }
+        expr.try_map_children(|child| self.bind(child))
+    }
+}
+
+impl TableWriter<'_> {
+    /// Prepares `row`, the values of the columns followed by the rowid, to be written:
+    /// the values take the affinity of their column, the rowid is taken from its alias
+    /// if set, and the generated columns are computed. The rowid stays NULL when the
+    /// next one is to be given to the row.
+    fn prepare(&self, row: &mut [OwnedValue]) -> anyhow::Result<()> {
+        let columns = &self.definition.columns;
+        for (value, column) in row.iter_mut().zip(columns) {
+            *value = value.apply_affinity(column.affinity());
+        }
+        let alias = self.definition.rowid_alias();
+        // The rowid can be given as the alias, or by one of the names of the rowid.
+        let value = (alias.map(|i| &row[i]))
+            .filter(|value| **value != OwnedValue::Null)
+            .unwrap_or(&row[columns.len()]);
+        let rowid = match value {
+            OwnedValue::Null => OwnedValue::Null,
+            value => match value.apply_affinity(Affinity::Integer) {
+                OwnedValue::Int(rowid) => OwnedValue::Int(rowid),
+                _ => bail!("datatype mismatch"),
+            },
+        };
+        if let Some(i) = alias {
+            row[i] = rowid.clone();
+        }
+        row[columns.len()] = rowid;
+        self.compute_generated(row)
+    }
+
+    /// Inserts `row`, as prepared by `prepare`, giving it the next rowid if it has
+    /// none. The constraints it fails are resolved by `on_conflict`, or by their own
+    /// `ON CONFLICT` clause.
+    fn insert(
+        &self,
+        w: &mut PageWriter,
+        row: &mut [OwnedValue],
+        on_conflict: Option<ast::ConflictResolution>,
+    ) -> anyhow::Result<Outcome> {
+        let resolution = |clause: Option<_>| on_conflict.or(clause).unwrap_or_default();
+        let columns = &self.definition.columns;
+        let alias = self.definition.rowid_alias();
+        let rowid = match row[columns.len()] {
+            OwnedValue::Int(rowid) => rowid,
+            _ => {
+                let rowid = match self.btree.last_rowid(w)? {
+                    Some(i64::MAX) => bail!("database or disk is full"),
+                    Some(last) => last.max(0) + 1,
+                    None => 1,
+                };
+                row[columns.len()] = OwnedValue::Int(rowid);
+                if let Some(i) = alias {
+                    row[i] = OwnedValue::Int(rowid);
+                }
+                self.compute_generated(row)?;
+                rowid
+            }
+        };
+
+        for &(i, clause) in &self.not_null {
+            if row[i] != OwnedValue::Null {
+                continue;
+            }
+            let error = anyhow!(
+                "NOT NULL constraint failed: {}.{}",
+                self.table.name,
+                columns[i].name
+            );
+            // REPLACE falls back to the default of the column, or aborts without one.
+            match resolution(clause) {
+                ast::ConflictResolution::Replace if self.defaults[i] != OwnedValue::Null => {
+                    row[i] = self.defaults[i].clone();
+                    self.compute_generated(row)?;
+                }
+                ast::ConflictResolution::Replace => return Err(error),
+                resolution => return reject(resolution, error),
+            }
+        }
+
+        // A check passes unless it's false, REPLACE aborting when it is.
+        for (name, expr) in &self.checks {
+            if expr.eval(row)?.as_bool() == Some(false) {
+                let error = anyhow!("CHECK constraint failed: {name}");
+                match resolution(None) {
+                    ast::ConflictResolution::Replace => return Err(error),
+                    resolution => return reject(resolution, error),
+                }
+            }
+        }
+
+        // The rows the constraints resolved by REPLACE conflict with are only deleted
+        // once none of the others rejected the row.
+        if self.btree.row(w, rowid)?.is_some() {
+            let column = alias.map_or("rowid", |i| &columns[i].name);
+            let error = anyhow!("UNIQUE constraint failed: {}.{column}", self.table.name);
+            match resolution(self.rowid_conflict) {
+                ast::ConflictResolution::Replace => {}
+                resolution => return reject(resolution, error),
+            }
+        }
+        for index in &self.indexes {
+            if let Some(unique) = &index.unique
+                && index.conflict(w, row)?.is_some()
+            {
+                let error = anyhow!("UNIQUE constraint failed: {}", unique.description);
+                match resolution(unique.on_conflict) {
+                    ast::ConflictResolution::Replace => {}
+                    resolution => return reject(resolution, error),
+                }
+            }
+        }
+        if let Some(old) = self.row(w, rowid)? {
+            self.delete(w, &old)?;
+        }
+        for index in &self.indexes {
+            if let Some(existing) = index.conflict(w, row)? {
+                let old = (self.row(w, existing)?)
+                    .with_context(|| format!("index of {} points to no row", self.table.name))?;
+                self.delete(w, &old)?;
+            }
+        }
+
+        self.store(w, row)?;
+        Ok(Outcome::Done)
+    }
+
+    /// Stores `row`, with its index keys, without checking its constraints.
+    fn store(&self, w: &mut PageWriter, row: &[OwnedValue]) -> anyhow::Result<()> {
+        let OwnedValue::Int(rowid) = row[self.definition.columns.len()] else {
+            bail!("invalid rowid");
+        };
+        let record = self.record(row, &w.header());
+        self.btree.insert_row(w, rowid, &record)?;
+        for index in &self.indexes {
+            index.btree.insert_key(w, &index.key(row)?)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes `row`, as read by `row`, and its index keys.
+    fn delete(&self, w: &mut PageWriter, row: &[OwnedValue]) -> anyhow::Result<()> {
+        for index in &self.indexes {
+            let key = index.key(row)?;
+            ensure!(
+                index.btree.delete_key(w, &key)?,
+                "index of {} missing a key, it must be corrupted",
+                self.table.name
+            );
+        }
+        let OwnedValue::Int(rowid) = row[self.definition.columns.len()] else {
+            bail!("invalid rowid");
+        };
+        self.btree.delete_row(w, rowid)?;
+        Ok(())
+    }
+
+    /// The row with `rowid`, with its generated columns.
+    fn row(&self, w: &mut PageWriter, rowid: i64) -> anyhow::Result<Option<Vec<OwnedValue>>> {
+        let Some(record) = self.btree.row(w, rowid)? else {
+            return Ok(None);
+        };
+        self.decode(w, rowid, &record).map(Some)
+    }
+
+    /// The row with `rowid` stored as `record`, with its generated columns.
+    fn decode(
+        &self,
+        w: &mut PageWriter,
+        rowid: i64,
+        record: &[u8],
+    ) -> anyhow::Result<Vec<OwnedValue>> {
+        let fields = cursor::decode_record(record, w.header().text_encoding)?;
+        let mut row = self.defaults.clone();
+        row.push(OwnedValue::Int(rowid));
+        for (i, field) in self.definition.stored_columns().into_iter().zip(fields) {
+            row[i] = field;
+        }
+        if let Some(i) = self.definition.rowid_alias() {
+            row[i] = OwnedValue::Int(rowid);
+        }
+        self.compute_generated(&mut row)?;
+        Ok(row)
+    }
+
+    /// Computes the generated columns of `row`. As they may depend on one another, all
+    /// are computed again until none changes.
+    fn compute_generated(&self, row: &mut [OwnedValue]) -> anyhow::Result<()> {
+        for _ in 0..=self.generated.len() {
+            let mut changed = false;
+            for (i, expr) in &self.generated {
+                let affinity = self.definition.columns[*i].affinity();
+                let value = expr.eval(row)?.apply_affinity(affinity);
+                changed |= row[*i] != value;
+                row[*i] = value;
+            }
+            if !changed {
+                return Ok(());
+            }
+        }
+        bail!("generated column loop in table {}", self.table.name)
+    }
+
+    /// The record of `row`: its stored columns, with NULL for the rowid alias.
+    fn record(&self, row: &[OwnedValue], header: &DbHeader) -> Vec<u8> {
+        let alias = self.definition.rowid_alias();
+        let values: Vec<OwnedValue> = (self.definition.stored_columns().into_iter())
+            .map(|i| match Some(i) == alias {
+                true => OwnedValue::Null,
+                false => row[i].clone(),
+            })
+            .collect();
+        btree::encode_record(&values, header)
+    }
+}
+
+/// Resolves a conflict other than by REPLACE: ABORT and ROLLBACK fail the statement,
+/// undoing it, FAIL keeps the rows it inserted so far, IGNORE skips the row.
+fn reject(resolution: ast::ConflictResolution, error: anyhow::Error) -> anyhow::Result<Outcome> {
+    match resolution {
+        ast::ConflictResolution::Ignore => Ok(Outcome::Skipped),
+        ast::ConflictResolution::Fail => Ok(Outcome::Failed(error)),
+        _ => Err(error),
+    }
+}
+
+impl IndexWriter {
+    fn key(&self, row: &[OwnedValue]) -> anyhow::Result<Vec<OwnedValue>> {
+        self.keys.iter().map(|key| key.eval(row)).collect()
+    }
+
+    /// The rowid of another row with the same key as `row` in a unique index.
+    fn conflict(&self, w: &mut PageWriter, row: &[OwnedValue]) -> anyhow::Result<Option<i64>> {
+        if self.unique.is_none() {
+            return Ok(None);
+        }
+        let mut key = self.key(row)?;
+        let rowid = key.pop();
+        if key.contains(&OwnedValue::Null) {
+            return Ok(None);
+        }
+        match self.btree.find_key(w, &key)?.as_deref() {
+            Some([.., OwnedValue::Int(existing)]) if Some(OwnedValue::Int(*existing)) != rowid => {
+                Ok(Some(*existing))
+            }
+            Some([.., OwnedValue::Int(_)]) | None => Ok(None),
+            Some(_) => bail!("invalid rowid in index"),
+        }
+    }
+}
+
+/// The position of the column `name` of a table, the rowid being past the columns.
+fn column_position(definition: &TableDef, name: &str) -> Option<usize> {
+    match (definition.columns.iter()).position(|c| c.name.eq_ignore_ascii_case(name)) {
+        Some(i) => Some(i),
+        None if ROWID_NAMES.iter().any(|n| n.eq_ignore_ascii_case(name)) => {
+            Some(definition.columns.len())
+        }
+        None => None,
+    }
+}
+
+/// Fails when the column at position `i` is generated, as a `statement` can't set it.
+fn ensure_not_generated(definition: &TableDef, i: usize, statement: &str) -> anyhow::Result<()> {
+    match definition.columns.get(i) {
+        Some(column) if column.generated().is_some() => {
+            bail!("cannot {statement} generated column \"{}\"", column.name)
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        db::Db,
+        testing::{schema_database, text},
+        value::SendValue,
+    };
+
+    fn rows(db: &Db, sql: &str) -> Vec<Vec<SendValue>> {
+        let mut query = db.query(sql).unwrap();
+        let mut rows = Vec::new();
+        while let Some(row) = query.next_row().unwrap() {
+            rows.push(row.iter().map(SendValue::from).collect());
+        }
+        rows
+    }
+
+    fn run(db: &Db, sql: &str) -> anyhow::Result<()> {
+        db.query(sql).map(drop)
+    }
+
+    fn indexed_table(name: &str) -> Db {
+        schema_database(
+            name,
+            &[
+                (
+                    "table",
+                    "t",
+                    "t",
+                    "CREATE TABLE t(id INTEGER PRIMARY KEY, a TEXT, b DEFAULT 'none')",
+                ),
+                ("index", "i", "t", "CREATE INDEX i ON t(a)"),
+            ],
+        )
+    }
+
+    fn row(id: i64, a: &str, b: &str) -> Vec<SendValue> {
+        vec![SendValue::Int(id), text(a), text(b)]
+    }
+
+    #[test]
+    fn insert_rows() {
+        let db = indexed_table("insert-rows");
+        run(&db, "INSERT INTO t(a) VALUES ('x'), ('y')").unwrap();
+        run(&db, "INSERT INTO t VALUES (10, 'z', 'set')").unwrap();
+        run(&db, "INSERT INTO t(rowid, a) VALUES ('5', 'w')").unwrap();
+        run(&db, "INSERT INTO t(a) VALUES ('v')").unwrap();
+        assert_eq!(
+            rows(&db, "SELECT * FROM t"),
+            [
+                row(1, "x", "none"),
+                row(2, "y", "none"),
+                row(5, "w", "none"),
+                row(10, "z", "set"),
+                row(11, "v", "none"),
+            ]
+        );
+        assert_eq!(
+            rows(&db, "SELECT id FROM t WHERE a = 'w'"),
+            [[SendValue::Int(5)]]
+        );
+        assert_eq!(rows(&db, "PRAGMA integrity_check"), [[text("ok")]]);
+
+        let error = |sql| run(&db, sql).unwrap_err().to_string();
+        assert_eq!(
+            error("INSERT INTO t VALUES (1)"),
+            "table t has 3 columns but 1 values were supplied"
+        );
+        assert_eq!(
+            error("INSERT INTO t(a, b) VALUES (1)"),
+            "1 values for 2 columns"
+        );
+        assert_eq!(
+            error("INSERT INTO t(c) VALUES (1)"),
+            "table t has no column named c"
+        );
+        assert_eq!(error("INSERT INTO t(id) VALUES ('x')"), "datatype mismatch");
+        assert_eq!(
+            error("EXPLAIN INSERT INTO t(a) VALUES (1)"),
+            "cannot explain a statement modifying the database"
+        );
+    }
+
+    #[test]
+    fn insert_sorted_rows() {
+        let db = indexed_table("insert-sorted-rows");
+        run(
+            &db,
+            "INSERT INTO t VALUES (3, 'c', 'x'), (1, 'a', 'x'), (2, 'b', 'x')",
+        )
+        .unwrap();
+        assert_eq!(
+            rows(&db, "SELECT * FROM t"),
+            [row(1, "a", "x"), row(2, "b", "x"), row(3, "c", "x")]
+        );
+
+        // Once a row fails, the rows are inserted again in their order, FAIL keeping
+        // the ones before it.
+        let error = run(
+            &db,
+            "INSERT OR FAIL INTO t VALUES (9, 'i', 'x'), (2, 'conflict', 'x'), (5, 'e', 'x')",
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "UNIQUE constraint failed: t.id");
+        assert_eq!(
+            rows(&db, "SELECT id FROM t"),
+            [1, 2, 3, 9].map(|id| [SendValue::Int(id)])
+        );
+        assert_eq!(rows(&db, "PRAGMA integrity_check"), [[text("ok")]]);
+    }
+
+    #[test]
+    fn conflict_resolution() {
+        let db = indexed_table("conflict-resolution");
+        run(&db, "INSERT INTO t VALUES (1, 'x', 'first')").unwrap();
+        let conflicting = |resolution| {
+            format!("INSERT OR {resolution} INTO t VALUES (2, 'y', 'new'), (1, 'z', 'new')")
+        };
+
+        // ABORT and ROLLBACK undo the whole statement, FAIL keeps the rows before the
+        // conflict.
+        for resolution in ["ABORT", "ROLLBACK"] {
+            let error = run(&db, &conflicting(resolution)).unwrap_err();
+            assert_eq!(error.to_string(), "UNIQUE constraint failed: t.id");
+            assert_eq!(rows(&db, "SELECT * FROM t"), [row(1, "x", "first")]);
+        }
+        assert!(run(&db, &conflicting("FAIL")).is_err());
+        assert_eq!(
+            rows(&db, "SELECT * FROM t"),
+            [row(1, "x", "first"), row(2, "y", "new")]
+        );
+
+        run(
+            &db,
+            "INSERT OR IGNORE INTO t VALUES (1, 'z', 'new'), (3, 'w', 'new')",
+        )
+        .unwrap();
+        assert_eq!(
+            rows(&db, "SELECT * FROM t"),
+            [row(1, "x", "first"), row(2, "y", "new"), row(3, "w", "new")]
+        );
+
+        // REPLACE deletes the conflicting row, along with its index keys.
+        run(&db, "REPLACE INTO t VALUES (1, 'z', 'replaced')").unwrap();
+        assert_eq!(
+            rows(&db, "SELECT * FROM t WHERE id = 1"),
+            [row(1, "z", "replaced")]
+        );
+        assert_eq!(
+            rows(&db, "SELECT id FROM t WHERE a = 'x'"),
+            Vec::<Vec<_>>::new()
+        );
+        assert_eq!(
+            rows(&db, "SELECT a FROM t ORDER BY a"),
+            [[text("w")], [text("y")], [text("z")]]
+        );
+        assert_eq!(rows(&db, "PRAGMA integrity_check"), [[text("ok")]]);
+    }
+
+    #[test]
+    fn not_null_constraints() {
+        let db = schema_database(
+            "not-null-constraints",
+            &[(
+                "table",
+                "t",
+                "t",
+                "CREATE TABLE t(a NOT NULL, b NOT NULL DEFAULT 'b', \
+                 c NOT NULL ON CONFLICT IGNORE)",
+            )],
+        );
+        let error = |sql| run(&db, sql).unwrap_err().to_string();
+        assert_eq!(
+            error("INSERT INTO t VALUES (NULL, 'x', 'x')"),
+            "NOT NULL constraint failed: t.a"
+        );
+        assert_eq!(
+            error("INSERT OR FAIL INTO t VALUES ('x', NULL, 'x')"),
+            "NOT NULL constraint failed: t.b"
+        );
+        // REPLACE stores the default instead, or aborts without one.
+        assert_eq!(
+            error("INSERT OR REPLACE INTO t VALUES (NULL, 'x', 'x')"),
+            "NOT NULL constraint failed: t.a"
+        );
+        run(&db, "INSERT OR REPLACE INTO t VALUES ('a', NULL, 'c')").unwrap();
+        // The clause of the constraint applies unless the statement has its own.
+        run(&db, "INSERT INTO t VALUES ('x', 'x', NULL)").unwrap();
+        assert_eq!(
+            error("INSERT OR ABORT INTO t VALUES ('x', 'x', NULL)"),
+            "NOT NULL constraint failed: t.c"
+        );
+        assert_eq!(
+            rows(&db, "SELECT * FROM t"),
+            [[text("a"), text("b"), text("c")]]
+        );
+    }
+
+    #[test]
+    fn unique_constraints() {
+        let db = schema_database(
+            "unique-constraints",
+            &[
+                (
+                    "table",
+                    "t",
+                    "t",
+                    "CREATE TABLE t(id INTEGER PRIMARY KEY, a UNIQUE, b, c, \
+                     UNIQUE (b, c) ON CONFLICT REPLACE)",
+                ),
+                ("index", "sqlite_autoindex_t_1", "t", ""),
+                ("index", "sqlite_autoindex_t_2", "t", ""),
+                ("index", "u", "t", "CREATE UNIQUE INDEX u ON t(lower(a), c)"),
+            ],
+        );
+        let error = |sql| run(&db, sql).unwrap_err().to_string();
+        let ids = |sql| -> Vec<i64> {
+            rows(&db, sql)
+                .into_iter()
+                .map(|row| match row[..] {
+                    [SendValue::Int(id)] => id,
+                    _ => panic!("expected an id"),
+                })
+                .collect()
+        };
+        run(
+            &db,
+            "INSERT INTO t VALUES (1, 'x', 'b', 'c'), (2, NULL, 'b', NULL)",
+        )
+        .unwrap();
+        // NULLs are distinct from one another.
+        run(
+            &db,
+            "INSERT INTO t VALUES (3, NULL, NULL, 'd'), (4, NULL, 'b', NULL)",
+        )
+        .unwrap();
+        assert_eq!(
+            error("INSERT INTO t VALUES (5, 'x', 'e', 'e')"),
+            "UNIQUE constraint failed: t.a"
+        );
+        assert_eq!(
+            error("INSERT INTO t VALUES (5, 'X', 'e', 'c')"),
+            "UNIQUE constraint failed: index 'u'"
+        );
+        run(&db, "INSERT OR IGNORE INTO t VALUES (5, 'x', 'e', 'e')").unwrap();
+        assert_eq!(ids("SELECT id FROM t"), [1, 2, 3, 4]);
+
+        // The clause of the constraint replaces the row with the same (b, c), unless
+        // the statement resolves the conflict otherwise.
+        run(&db, "INSERT INTO t VALUES (6, 'y', 'b', 'c')").unwrap();
+        assert_eq!(ids("SELECT id FROM t"), [2, 3, 4, 6]);
+        assert_eq!(
+            error("INSERT OR ABORT INTO t VALUES (7, 'z', 'b', 'c')"),
+            "UNIQUE constraint failed: t.b, t.c"
+        );
+        // No row is replaced when another constraint rejects the new one.
+        assert_eq!(
+            error("INSERT INTO t VALUES (7, 'y', 'b', 'c')"),
+            "UNIQUE constraint failed: index 'u'"
+        );
+        assert_eq!(ids("SELECT id FROM t"), [2, 3, 4, 6]);
+
+        // REPLACE deletes each of the rows conflicting with the new one.
+        run(&db, "REPLACE INTO t VALUES (3, 'y', 'f', 'D')").unwrap();
+        assert_eq!(ids("SELECT id FROM t"), [2, 3, 4]);
+        assert_eq!(ids("SELECT id FROM t WHERE a = 'y'"), [3]);
+        assert_eq!(rows(&db, "PRAGMA integrity_check"), [[text("ok")]]);
+    }
+
+    #[test]
+    fn check_constraints() {
+        let db = schema_database(
+            "check-constraints",
+            &[(
+                "table",
+                "t",
+                "t",
+                "CREATE TABLE t(a INTEGER CHECK (a >= 0), \
+                 b TEXT CONSTRAINT b_len CHECK (length(b) < 4), \
+                 c AS (upper(b)), CHECK (c != 'NO'))",
+            )],
+        );
+        let error = |sql| run(&db, sql).unwrap_err().to_string();
+        assert_eq!(
+            error("INSERT INTO t VALUES (-1, 'x')"),
+            "CHECK constraint failed: a >= 0"
+        );
+        assert_eq!(
+            error("INSERT INTO t VALUES (1, 'long')"),
+            "CHECK constraint failed: b_len"
+        );
+        // Checks see the generated columns, and REPLACE can't resolve them.
+        assert_eq!(
+            error("INSERT OR REPLACE INTO t VALUES (1, 'no')"),
+            "CHECK constraint failed: c != 'NO'"
+        );
+        // NULL passes the checks.
+        run(&db, "INSERT INTO t VALUES (NULL, NULL), ('1', 'yes')").unwrap();
+        run(&db, "INSERT OR IGNORE INTO t VALUES (-1, 'x'), (2, 'ok')").unwrap();
+        assert!(run(&db, "INSERT OR FAIL INTO t VALUES (3, 'ok'), (-1, 'x')").is_err());
+        assert_eq!(
+            rows(&db, "SELECT a, c FROM t"),
+            [
+                [SendValue::Null, SendValue::Null],
+                [SendValue::Int(1), text("YES")],
+                [SendValue::Int(2), text("OK")],
+                [SendValue::Int(3), text("OK")],
+            ]
+        );
+    }
+
+    #[test]
+    fn update_and_delete_rows() {
+        let db = indexed_table("update-and-delete-rows");
+        run(&db, "INSERT INTO t(a) VALUES ('x'), ('y'), ('z')").unwrap();
+        run(&db, "UPDATE t SET b = a, a = 'w' WHERE id = 2").unwrap();
+        run(&db, "UPDATE t SET id = 10 WHERE a = 'z'").unwrap();
+        run(&db, "DELETE FROM t WHERE rowid = '1'").unwrap();
+        assert_eq!(
+            rows(&db, "SELECT * FROM t"),
+            [row(2, "w", "y"), row(10, "z", "none")]
+        );
+        assert_eq!(
+            rows(&db, "SELECT id FROM t WHERE a = 'w'"),
+            [[SendValue::Int(2)]]
+        );
+        assert_eq!(rows(&db, "PRAGMA integrity_check"), [[text("ok")]]);
+
+        let error = |sql| run(&db, sql).unwrap_err().to_string();
+        assert_eq!(
+            error("UPDATE t SET id = 10 WHERE id = 2"),
+            "UNIQUE constraint failed: t.id"
+        );
+        assert_eq!(error("UPDATE t SET id = NULL"), "datatype mismatch");
+        assert_eq!(error("UPDATE t SET c = 1"), "no such column: c");
+        run(&db, "UPDATE OR IGNORE t SET id = 10").unwrap();
+        assert_eq!(
+            rows(&db, "SELECT id FROM t"),
+            [[SendValue::Int(2)], [SendValue::Int(10)]]
+        );
+
+        run(&db, "DELETE FROM t").unwrap();
+        assert_eq!(rows(&db, "SELECT * FROM t"), Vec::<Vec<_>>::new());
+        assert_eq!(rows(&db, "PRAGMA integrity_check"), [[text("ok")]]);
+    }
+
+    #[test]
+    fn triggers() {
+        let trigger = |name, sql| ("trigger", name, "t", sql);
+        let db = schema_database(
+            "triggers",
+            &[
+                (
+                    "table",
+                    "t",
+                    "t",
+                    "CREATE TABLE t(id INTEGER PRIMARY KEY, a)",
+                ),
+                ("table", "log", "log", "CREATE TABLE log(event, x, y)"),
+                trigger(
+                    "before_insert",
+                    "CREATE TRIGGER before_insert BEFORE INSERT ON t \
+                     BEGIN INSERT INTO log VALUES ('before insert', new.id, new.a); END",
+                ),
+                trigger(
+                    "after_insert",
+                    "CREATE TRIGGER after_insert AFTER INSERT ON t WHEN new.a != 'quiet' \
+                     BEGIN INSERT INTO log VALUES ('after insert', new.id, new.a); END",
+                ),
+                trigger(
+                    "update_a",
+                    "CREATE TRIGGER update_a AFTER UPDATE OF a ON t \
+                     BEGIN INSERT INTO log VALUES ('update', old.a, new.a); END",
+                ),
+                trigger(
+                    "keep",
+                    "CREATE TRIGGER keep BEFORE DELETE ON t WHEN old.a = 'kept' \
+                     BEGIN SELECT raise(ignore); END",
+                ),
+                trigger(
+                    "forbid",
+                    "CREATE TRIGGER forbid BEFORE DELETE ON t WHEN old.a = 'forbidden' \
+                     BEGIN SELECT raise(abort, 'cannot delete ' || old.id); END",
+                ),
+                // Like in SQLite by default, a trigger doesn't fire itself.
+                trigger(
+                    "again",
+                    "CREATE TRIGGER again AFTER DELETE ON t \
+                     BEGIN INSERT INTO t VALUES (old.id, 'again'); DELETE FROM t; END",
+                ),
+            ],
+        );
+        run(&db, "INSERT INTO t(a) VALUES ('x'), ('quiet')").unwrap();
+        run(&db, "UPDATE t SET a = 'kept' WHERE id = 1").unwrap();
+        run(&db, "UPDATE t SET id = 3 WHERE id = 2").unwrap();
+        assert_eq!(
+            rows(&db, "SELECT * FROM log"),
+            [
+                vec![text("before insert"), SendValue::Int(-1), text("x")],
+                vec![text("after insert"), SendValue::Int(1), text("x")],
+                vec![text("before insert"), SendValue::Int(-1), text("quiet")],
+                vec![text("update"), text("x"), text("kept")],
+            ]
+        );
+
+        // The row being deleted is inserted again, then deleted along with the others
+        // but the one the trigger keeps.
+        run(&db, "DELETE FROM log").unwrap();
+        run(&db, "DELETE FROM t WHERE id = 3").unwrap();
+        assert_eq!(
+            rows(&db, "SELECT * FROM t"),
+            [vec![SendValue::Int(1), text("kept")]]
+        );
+        assert_eq!(
+            rows(&db, "SELECT event, x FROM log"),
+            [
+                vec![text("before insert"), SendValue::Int(3)],
+                vec![text("after insert"), SendValue::Int(3)],
+            ]
+        );
+
+        run(&db, "UPDATE t SET a = 'forbidden'").unwrap();
+        let error = run(&db, "DELETE FROM t").unwrap_err();
+        assert_eq!(error.to_string(), "cannot delete 1");
+        assert_eq!(
+            rows(&db, "SELECT * FROM t"),
+            [vec![SendValue::Int(1), text("forbidden")]]
+        );
+        assert_eq!(rows(&db, "PRAGMA integrity_check"), [[text("ok")]]);
+    }
+}
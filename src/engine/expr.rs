@@ -0,0 +1,512 @@
+use std::{cmp::Ordering, rc::Rc};
+
+use crate::{
+    engine::{
+        batch::Batch,
+        function::{Args, ScalarFunction},
+    },
+    value::{Affinity, Collation, OwnedValue, format_real},
+};
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Column(usize),
+    Literal(OwnedValue),
+    Function(FunctionExpr),
+    Unary(Box<UnaryExpr>),
+    Binary(Box<BinaryExpr>),
+    Cast(Box<CastExpr>),
+    /// Applies the affinity of a column to the value, like storing it would.
+    Affinity(Box<CastExpr>),
+    /// The first of the expressions that isn't NULL, evaluating none after it.
+    Coalesce(Vec<Expr>),
+    Iif(Box<IifExpr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Not,
+}
+
+#[derive(Debug, Clone)]
+pub struct UnaryExpr {
+    pub op: UnaryOp,
+    pub expr: Expr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Eq,
+    NotEq,
+    Is,
+    IsNot,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone)]
+pub struct BinaryExpr {
+    pub op: BinaryOp,
+    pub lhs: Expr,
+    pub rhs: Expr,
+    /// How text operands are compared.
+    pub collation: Collation,
+}
+
+#[derive(Debug, Clone)]
+pub struct CastExpr {
+    pub expr: Expr,
+    pub affinity: Affinity,
+}
+
+/// `iif(condition, then, otherwise)`, evaluating only the branch it returns.
+#[derive(Debug, Clone)]
+pub struct IifExpr {
+    pub condition: Expr,
+    pub then: Expr,
+    pub otherwise: Expr,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionExpr {
+    pub function: &'static ScalarFunction,
+    pub args: Vec<Expr>,
+    json_args: Vec<bool>,
+}
+
+impl FunctionExpr {
+    pub fn new(function: &'static ScalarFunction, args: Vec<Expr>) -> Self {
+        let json_args = args.iter().map(Expr::is_json).collect();
+        Self {
+            function,
+            args,
+            json_args,
+        }
+    }
+}
+
+impl FunctionExpr {
+    /// Whether the function implements a binary operator, such as `||`.
+    fn is_operator(&self) -> bool {
+        !self
+            .function
+            .name
+            .starts_with(|c: char| c.is_ascii_alphabetic())
+            && self.args.len() == 2
+    }
+}
+
+impl Expr {
+    pub fn string(s: &str) -> Self {
+        Expr::Literal(OwnedValue::String(Rc::new(s.to_string())))
+    }
+
+    pub fn eval(&self, row: &[OwnedValue]) -> anyhow::Result<OwnedValue> {
+        match self {
+            Expr::Column(i) => Ok(row[*i].clone()),
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Function(f) => {
+                let values = f
+                    .args
+                    .iter()
+                    .map(|arg| arg.eval(row))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                (f.function.call)(&Args::new(&values, &f.json_args))
+            }
+            Expr::Unary(u) => u.eval(row),
+            Expr::Binary(b) => b.eval(row),
+            Expr::Cast(c) => Ok(c.expr.eval(row)?.cast(c.affinity)),
+            Expr::Affinity(c) => Ok(c.expr.eval(row)?.apply_affinity(c.affinity)),
+            Expr::Coalesce(exprs) => {
+                for expr in exprs {
+                    let value = expr.eval(row)?;
+                    if value != OwnedValue::Null {
+                        return Ok(value);
+                    }
+                }
+                Ok(OwnedValue::Null)
+            }
+            Expr::Iif(iif) => match iif.condition.eval(row)?.as_bool() {
+                Some(true) => iif.then.eval(row),
+                _ => iif.otherwise.eval(row),
+            },
+        }
+    }
+
+    /// Evaluates the expression on each row of `batch`, a step over a column at a time
+    /// where the result is the same as evaluating it row by row.
+    pub fn eval_batch(&self, batch: &Batch) -> anyhow::Result<Vec<OwnedValue>> {
+        let map = |expr: &Expr, f: &dyn Fn(OwnedValue) -> OwnedValue| {
+            Ok(expr.eval_batch(batch)?.into_iter().map(f).collect())
+        };
+        match self {
+            Expr::Column(i) => Ok(batch.column(*i).to_vec()),
+            Expr::Literal(value) => Ok(vec![value.clone(); batch.len()]),
+            Expr::Function(f) => {
+                let args = f
+                    .args
+                    .iter()
+                    .map(|arg| arg.eval_batch(batch))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let mut values = Vec::with_capacity(args.len());
+                (0..batch.len())
+                    .map(|row| {
+                        values.clear();
+                        values.extend(args.iter().map(|arg| arg[row].clone()));
+                        (f.function.call)(&Args::new(&values, &f.json_args))
+                    })
+                    .collect()
+            }
+            Expr::Unary(u) => map(&u.expr, &|value| u.apply(value)),
+            // The right side of a conjunction or disjunction isn't evaluated on the
+            // rows the left side decides, where it could fail.
+            Expr::Binary(b) if matches!(b.op, BinaryOp::And | BinaryOp::Or) && b.rhs.can_fail() => {
+                self.eval_rows(batch)
+            }
+            Expr::Binary(b) => {
+                let lhs = b.lhs.eval_batch(batch)?;
+                let rhs = b.rhs.eval_batch(batch)?;
+                Ok(lhs
+                    .into_iter()
+                    .zip(rhs)
+                    .map(|(lhs, rhs)| b.apply(lhs, rhs))
+                    .collect())
+            }
+            Expr::Cast(c) => map(&c.expr, &|value| value.cast(c.affinity)),
+            Expr::Affinity(c) => map(&c.expr, &|value| value.apply_affinity(c.affinity)),
+            Expr::Coalesce(_) | Expr::Iif(_) => self.eval_rows(batch),
+        }
+    }
+
+    fn eval_rows(&self, batch: &Batch) -> anyhow::Result<Vec<OwnedValue>> {
+        let mut row = Vec::new();
+        (0..batch.len())
+            .map(|i| {
+                batch.read_row(i, &mut row);
+                self.eval(&row)
+            })
+            .collect()
+    }
+
+    /// Whether evaluating the expression may fail, which only functions do.
+    fn can_fail(&self) -> bool {
+        match self {
+            Expr::Column(_) | Expr::Literal(_) => false,
+            Expr::Function(_) => true,
+            Expr::Unary(u) => u.expr.can_fail(),
+            Expr::Binary(b) => b.lhs.can_fail() || b.rhs.can_fail(),
+            Expr::Cast(c) | Expr::Affinity(c) => c.expr.can_fail(),
+            Expr::Coalesce(exprs) => exprs.iter().any(Expr::can_fail),
+            Expr::Iif(iif) => [&iif.condition, &iif.then, &iif.otherwise]
+                .into_iter()
+                .any(Expr::can_fail),
+        }
+    }
+
+    /// Writes the expression as SQL, naming its columns after `columns`, or by their
+    /// position when their name is unknown.
+    pub fn to_sql(&self, columns: &[String]) -> String {
+        let operand = |expr: &Expr| match expr {
+            Expr::Binary(_) => format!("({})", expr.to_sql(columns)),
+            Expr::Function(f) if f.is_operator() => format!("({})", expr.to_sql(columns)),
+            _ => expr.to_sql(columns),
+        };
+        let list = |exprs: &[Expr]| {
+            exprs
+                .iter()
+                .map(|expr| expr.to_sql(columns))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        match self {
+            Expr::Column(i) => columns.get(*i).cloned().unwrap_or_else(|| format!("#{i}")),
+            Expr::Literal(OwnedValue::Null) => "NULL".to_string(),
+            Expr::Literal(OwnedValue::String(s)) => format!("'{}'", s.replace('\'', "''")),
+            Expr::Literal(OwnedValue::Blob(b)) => {
+                let hex: String = b.iter().map(|byte| format!("{byte:02X}")).collect();
+                format!("X'{hex}'")
+            }
+            Expr::Literal(OwnedValue::Int(i)) => i.to_string(),
+            Expr::Literal(OwnedValue::Float(x)) => format_real(*x),
+            Expr::Function(f) if f.is_operator() => format!(
+                "{} {} {}",
+                operand(&f.args[0]),
+                f.function.name,
+                operand(&f.args[1])
+            ),
+            Expr::Function(f) => format!("{}({})", f.function.name, list(&f.args)),
+            Expr::Unary(u) => match u.op {
+                UnaryOp::Not => format!("NOT {}", operand(&u.expr)),
+            },
+            Expr::Binary(b) => {
+                let sql = format!("{} {} {}", operand(&b.lhs), b.op.as_sql(), operand(&b.rhs));
+                match b.collation {
+                    Collation::Binary => sql,
+                    collation => format!("{sql} COLLATE {}", collation.name()),
+                }
+            }
+            Expr::Cast(c) => format!(
+                "CAST({} AS {})",
+                c.expr.to_sql(columns),
+                format!("{:?}", c.affinity).to_uppercase()
+            ),
+            Expr::Affinity(c) => c.expr.to_sql(columns),
+            Expr::Coalesce(exprs) => format!("coalesce({})", list(exprs)),
+            Expr::Iif(iif) => format!(
+                "iif({}, {}, {})",
+                iif.condition.to_sql(columns),
+                iif.then.to_sql(columns),
+                iif.otherwise.to_sql(columns)
+            ),
+        }
+    }
+
+    /// Whether the expression produces text carrying SQLite's JSON subtype, which
+    /// JSON functions embed as-is instead of quoting it as a string.
+    fn is_json(&self) -> bool {
+        matches!(self, Expr::Function(f) if f.function.json_result)
+    }
+}
+
+impl BinaryOp {
+    /// Whether the comparison holds for operands ordered by `ordering`.
+    pub fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            BinaryOp::Eq => ordering == Ordering::Equal,
+            BinaryOp::NotEq => ordering != Ordering::Equal,
+            BinaryOp::Lt => ordering == Ordering::Less,
+            BinaryOp::LtEq => ordering != Ordering::Greater,
+            BinaryOp::Gt => ordering == Ordering::Greater,
+            BinaryOp::GtEq => ordering != Ordering::Less,
+            BinaryOp::And | BinaryOp::Or | BinaryOp::Is | BinaryOp::IsNot => {
+                unreachable!("not a comparison: {self:?}")
+            }
+        }
+    }
+
+    /// The same comparison with its operands swapped, e.g. `1 < x` becomes `x > 1`.
+    pub fn flip(self) -> Self {
+        match self {
+            BinaryOp::Lt => BinaryOp::Gt,
+            BinaryOp::LtEq => BinaryOp::GtEq,
+            BinaryOp::Gt => BinaryOp::Lt,
+            BinaryOp::GtEq => BinaryOp::LtEq,
+            op => op,
+        }
+    }
+
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            BinaryOp::Eq => "=",
+            BinaryOp::NotEq => "!=",
+            BinaryOp::Is => "IS",
+            BinaryOp::IsNot => "IS NOT",
+            BinaryOp::Lt => "<",
+            BinaryOp::LtEq => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::GtEq => ">=",
+            BinaryOp::And => "AND",
+            BinaryOp::Or => "OR",
+        }
+    }
+}
+
+impl UnaryExpr {
+    fn eval(&self, row: &[OwnedValue]) -> anyhow::Result<OwnedValue> {
+        Ok(self.apply(self.expr.eval(row)?))
+    }
+
+    fn apply(&self, value: OwnedValue) -> OwnedValue {
+        match self.op {
+            UnaryOp::Not => match value.as_bool() {
+                Some(b) => OwnedValue::Int(!b as i64),
+                None => OwnedValue::Null,
+            },
+        }
+    }
+}
+
+impl BinaryExpr {
+    fn eval(&self, row: &[OwnedValue]) -> anyhow::Result<OwnedValue> {
+        let lhs = self.lhs.eval(row)?;
+        if matches!(self.op, BinaryOp::And | BinaryOp::Or) {
+            // FALSE wins over NULL in a conjunction and TRUE in a disjunction, so the
+            // right side is only skipped when the left side decides the result.
+            let decisive = self.op == BinaryOp::Or;
+            if lhs.as_bool() == Some(decisive) {
+                return Ok(OwnedValue::Int(decisive as i64));
+            }
+        }
+        let rhs = self.rhs.eval(row)?;
+        Ok(self.apply(lhs, rhs))
+    }
+
+    fn apply(&self, lhs: OwnedValue, rhs: OwnedValue) -> OwnedValue {
+        match self.op {
+            BinaryOp::And | BinaryOp::Or => {
+                let decisive = self.op == BinaryOp::Or;
+                match (lhs.as_bool(), rhs.as_bool()) {
+                    (Some(a), _) | (_, Some(a)) if a == decisive => {
+                        OwnedValue::Int(decisive as i64)
+                    }
+                    (Some(_), Some(_)) => OwnedValue::Int(!decisive as i64),
+                    _ => OwnedValue::Null,
+                }
+            }
+            // NULL is equal to itself and distinct from anything else.
+            BinaryOp::Is | BinaryOp::IsNot => {
+                let equal = self.collation.compare(&lhs, &rhs) == Ordering::Equal;
+                OwnedValue::Int((equal == (self.op == BinaryOp::Is)) as i64)
+            }
+            _ if lhs == OwnedValue::Null || rhs == OwnedValue::Null => OwnedValue::Null,
+            op => OwnedValue::Int(op.holds(self.collation.compare(&lhs, &rhs)) as i64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn three_valued_logic() {
+        let values = [OwnedValue::Int(0), OwnedValue::Int(1), OwnedValue::Null];
+        let binary = |op, lhs: &OwnedValue, rhs: &OwnedValue| {
+            Expr::Binary(Box::new(BinaryExpr {
+                op,
+                lhs: Expr::Literal(lhs.clone()),
+                rhs: Expr::Literal(rhs.clone()),
+                collation: Collation::Binary,
+            }))
+            .eval(&[])
+            .unwrap()
+            .as_bool()
+        };
+
+        for lhs in &values {
+            for rhs in &values {
+                let (a, b) = (lhs.as_bool(), rhs.as_bool());
+                let and = match (a, b) {
+                    (Some(false), _) | (_, Some(false)) => Some(false),
+                    (Some(true), Some(true)) => Some(true),
+                    _ => None,
+                };
+                let or = match (a, b) {
+                    (Some(true), _) | (_, Some(true)) => Some(true),
+                    (Some(false), Some(false)) => Some(false),
+                    _ => None,
+                };
+                assert_eq!(binary(BinaryOp::And, lhs, rhs), and, "{lhs:?} AND {rhs:?}");
+                assert_eq!(binary(BinaryOp::Or, lhs, rhs), or, "{lhs:?} OR {rhs:?}");
+            }
+
+            let not = Expr::Unary(Box::new(UnaryExpr {
+                op: UnaryOp::Not,
+                expr: Expr::Literal(lhs.clone()),
+            }));
+            assert_eq!(not.eval(&[]).unwrap().as_bool(), lhs.as_bool().map(|b| !b));
+        }
+    }
+
+    #[test]
+    fn null_safe_equality() {
+        let values = [OwnedValue::Int(0), OwnedValue::Int(1), OwnedValue::Null];
+        for lhs in &values {
+            for rhs in &values {
+                let is = |op| {
+                    Expr::Binary(Box::new(BinaryExpr {
+                        op,
+                        lhs: Expr::Literal(lhs.clone()),
+                        rhs: Expr::Literal(rhs.clone()),
+                        collation: Collation::Binary,
+                    }))
+                    .eval(&[])
+                    .unwrap()
+                };
+                let equal = (lhs == rhs) as i64;
+                assert_eq!(is(BinaryOp::Is), OwnedValue::Int(equal));
+                assert_eq!(is(BinaryOp::IsNot), OwnedValue::Int(1 - equal));
+            }
+        }
+    }
+
+    #[test]
+    fn batch_evaluation() {
+        let values = [OwnedValue::Int(0), OwnedValue::Int(1), OwnedValue::Null];
+        let mut batch = Batch::default();
+        for a in &values {
+            for b in &values {
+                batch.push_row(&[a.clone(), b.clone()]);
+            }
+        }
+        let binary = |op, lhs, rhs| {
+            Expr::Binary(Box::new(BinaryExpr {
+                op,
+                lhs,
+                rhs,
+                collation: Collation::Binary,
+            }))
+        };
+        let exprs = [
+            binary(BinaryOp::And, Expr::Column(0), Expr::Column(1)),
+            binary(BinaryOp::Or, Expr::Column(1), Expr::Column(0)),
+            binary(BinaryOp::IsNot, Expr::Column(0), Expr::Column(1)),
+            Expr::Unary(Box::new(UnaryExpr {
+                op: UnaryOp::Not,
+                expr: binary(BinaryOp::Lt, Expr::Column(0), Expr::Column(1)),
+            })),
+            Expr::Iif(Box::new(IifExpr {
+                condition: Expr::Column(0),
+                then: Expr::Column(1),
+                otherwise: Expr::Literal(OwnedValue::Int(2)),
+            })),
+        ];
+
+        let mut row = Vec::new();
+        for expr in &exprs {
+            let rows: Vec<_> = (0..batch.len())
+                .map(|i| {
+                    batch.read_row(i, &mut row);
+                    expr.eval(&row).unwrap()
+                })
+                .collect();
+            assert_eq!(expr.eval_batch(&batch).unwrap(), rows);
+        }
+    }
+
+    #[test]
+    fn conditional_evaluation() {
+        // Evaluating the column would panic on the empty row.
+        let unevaluated = Expr::Column(9);
+        let coalesce = Expr::Coalesce(vec![
+            Expr::Literal(OwnedValue::Null),
+            Expr::Literal(OwnedValue::Int(1)),
+            unevaluated.clone(),
+        ]);
+        assert_eq!(coalesce.eval(&[]).unwrap(), OwnedValue::Int(1));
+
+        let iif = |condition| {
+            Expr::Iif(Box::new(IifExpr {
+                condition: Expr::Literal(condition),
+                then: Expr::Literal(OwnedValue::Int(1)),
+                otherwise: Expr::Literal(OwnedValue::Int(2)),
+            }))
+            .eval(&[])
+            .unwrap()
+        };
+        assert_eq!(iif(OwnedValue::Int(3)), OwnedValue::Int(1));
+        assert_eq!(iif(OwnedValue::Null), OwnedValue::Int(2));
+
+        let lazy = Expr::Iif(Box::new(IifExpr {
+            condition: Expr::Literal(OwnedValue::Int(0)),
+            then: unevaluated,
+            otherwise: Expr::Literal(OwnedValue::Int(2)),
+        }));
+        assert_eq!(lazy.eval(&[]).unwrap(), OwnedValue::Int(2));
+    }
+}
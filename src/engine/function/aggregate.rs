@@ -0,0 +1,247 @@
+use std::{cmp::Ordering, rc::Rc};
+
+use anyhow::bail;
+
+use crate::value::OwnedValue;
+
+#[derive(Debug)]
+pub struct AggregateFunction {
+    pub name: &'static str,
+    pub min_args: usize,
+    pub max_args: Option<usize>,
+    pub init: fn() -> Accumulator,
+}
+
+pub static AGGREGATE_FUNCTIONS: &[AggregateFunction] = &[
+    AggregateFunction {
+        name: "count",
+        min_args: 0,
+        max_args: Some(1),
+        init: || Accumulator::Count(0),
+    },
+    AggregateFunction {
+        name: "sum",
+        min_args: 1,
+        max_args: Some(1),
+        init: || Accumulator::Sum(Sum::default()),
+    },
+    AggregateFunction {
+        name: "total",
+        min_args: 1,
+        max_args: Some(1),
+        init: || Accumulator::Total(Sum::default()),
+    },
+    AggregateFunction {
+        name: "avg",
+        min_args: 1,
+        max_args: Some(1),
+        init: || Accumulator::Avg(Sum::default()),
+    },
+    AggregateFunction {
+        name: "min",
+        min_args: 1,
+        max_args: Some(1),
+        init: || Accumulator::Min(OwnedValue::Null),
+    },
+    AggregateFunction {
+        name: "max",
+        min_args: 1,
+        max_args: Some(1),
+        init: || Accumulator::Max(OwnedValue::Null),
+    },
+    AggregateFunction {
+        name: "group_concat",
+        min_args: 1,
+        max_args: Some(2),
+        init: || Accumulator::GroupConcat(None),
+    },
+];
+
+/// Produces the value of a column that is neither grouped on nor aggregated, which
+/// SQLite takes from the last row of the group.
+pub static BARE_COLUMN: AggregateFunction = AggregateFunction {
+    name: "bare column",
+    min_args: 1,
+    max_args: Some(1),
+    init: || Accumulator::Last(OwnedValue::Null),
+};
+
+/// The running state of an aggregate function over the rows of a group.
+#[derive(Debug, Clone)]
+pub enum Accumulator {
+    Count(i64),
+    Sum(Sum),
+    Total(Sum),
+    Avg(Sum),
+    Min(OwnedValue),
+    Max(OwnedValue),
+    GroupConcat(Option<String>),
+    Last(OwnedValue),
+}
+
+/// Sums integers exactly for as long as no real number is added, like SQLite.
+#[derive(Debug, Clone, Default)]
+pub struct Sum {
+    int: i64,
+    real: f64,
+    count: usize,
+    approximate: bool,
+    overflow: bool,
+}
+
+impl Sum {
+    fn add(&mut self, value: &OwnedValue) {
+        let int = match value {
+            OwnedValue::Null => return,
+            OwnedValue::Int(i) => Some(*i),
+            OwnedValue::String(s) => s.trim().parse().ok(),
+            _ => None,
+        };
+
+        self.count += 1;
+        match int {
+            Some(i) => {
+                self.real += i as f64;
+                match self.int.checked_add(i) {
+                    Some(sum) => self.int = sum,
+                    None => self.overflow = true,
+                }
+            }
+            None => {
+                self.real += value.as_f64();
+                self.approximate = true;
+            }
+        }
+    }
+}
+
+impl Accumulator {
+    pub fn step(&mut self, args: &[OwnedValue]) {
+        let value = args.first().unwrap_or(&OwnedValue::Null);
+        match self {
+            // count(*) has no argument and counts every row.
+            Accumulator::Count(n) => {
+                if args.is_empty() || *value != OwnedValue::Null {
+                    *n += 1;
+                }
+            }
+            Accumulator::Sum(sum) | Accumulator::Total(sum) | Accumulator::Avg(sum) => {
+                sum.add(value)
+            }
+            Accumulator::Min(min) => {
+                if *value != OwnedValue::Null
+                    && (*min == OwnedValue::Null || value.sql_cmp(min) == Ordering::Less)
+                {
+                    *min = value.clone();
+                }
+            }
+            Accumulator::Max(max) => {
+                if *value != OwnedValue::Null && value.sql_cmp(max) == Ordering::Greater {
+                    *max = value.clone();
+                }
+            }
+            Accumulator::GroupConcat(text) => {
+                if *value == OwnedValue::Null {
+                    return;
+                }
+                match text {
+                    Some(text) => {
+                        match args.get(1) {
+                            Some(OwnedValue::Null) => {}
+                            Some(separator) => push_text(text, separator),
+                            None => text.push(','),
+                        }
+                        push_text(text, value);
+                    }
+                    None => {
+                        let mut first = String::new();
+                        push_text(&mut first, value);
+                        *text = Some(first);
+                    }
+                }
+            }
+            Accumulator::Last(last) => *last = value.clone(),
+        }
+    }
+
+    pub fn finish(&self) -> anyhow::Result<OwnedValue> {
+        Ok(match self {
+            Accumulator::Count(n) => OwnedValue::Int(*n),
+            Accumulator::Sum(sum) if sum.count == 0 => OwnedValue::Null,
+            Accumulator::Sum(sum) if sum.approximate => OwnedValue::Float(sum.real),
+            Accumulator::Sum(sum) if sum.overflow => bail!("integer overflow"),
+            Accumulator::Sum(sum) => OwnedValue::Int(sum.int),
+            Accumulator::Total(sum) => OwnedValue::Float(sum.real),
+            Accumulator::Avg(sum) if sum.count == 0 => OwnedValue::Null,
+            Accumulator::Avg(sum) => OwnedValue::Float(sum.real / sum.count as f64),
+            Accumulator::Min(value) | Accumulator::Max(value) | Accumulator::Last(value) => {
+                value.clone()
+            }
+            Accumulator::GroupConcat(None) => OwnedValue::Null,
+            Accumulator::GroupConcat(Some(text)) => OwnedValue::String(Rc::new(text.clone())),
+        })
+    }
+}
+
+fn push_text(text: &mut String, value: &OwnedValue) {
+    match value {
+        OwnedValue::String(s) => text.push_str(s),
+        OwnedValue::Blob(b) => text.push_str(&String::from_utf8_lossy(b)),
+        value => text.push_str(&value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aggregate(name: &str, rows: &[&[OwnedValue]]) -> anyhow::Result<OwnedValue> {
+        let function = AGGREGATE_FUNCTIONS.iter().find(|f| f.name == name).unwrap();
+        let mut accumulator = (function.init)();
+        for row in rows {
+            accumulator.step(row);
+        }
+        accumulator.finish()
+    }
+
+    #[test]
+    fn sqlite_aggregates() {
+        let text = |s: &str| OwnedValue::String(Rc::new(s.to_string()));
+        let values: &[&[OwnedValue]] = &[
+            &[OwnedValue::Int(3)],
+            &[OwnedValue::Null],
+            &[text("4")],
+            &[OwnedValue::Int(-1)],
+        ];
+
+        assert_eq!(aggregate("count", values).unwrap(), OwnedValue::Int(3));
+        assert_eq!(aggregate("count", &[&[], &[]]).unwrap(), OwnedValue::Int(2));
+        assert_eq!(aggregate("sum", values).unwrap(), OwnedValue::Int(6));
+        assert_eq!(aggregate("avg", values).unwrap(), OwnedValue::Float(2.0));
+        assert_eq!(aggregate("min", values).unwrap(), OwnedValue::Int(-1));
+        assert_eq!(aggregate("max", values).unwrap(), text("4"));
+        assert_eq!(aggregate("group_concat", values).unwrap(), text("3,4,-1"));
+
+        assert_eq!(aggregate("sum", &[]).unwrap(), OwnedValue::Null);
+        assert_eq!(aggregate("total", &[]).unwrap(), OwnedValue::Float(0.0));
+        assert_eq!(
+            aggregate("sum", &[&[OwnedValue::Int(1)], &[OwnedValue::Float(0.5)]]).unwrap(),
+            OwnedValue::Float(1.5)
+        );
+        assert!(
+            aggregate(
+                "sum",
+                &[&[OwnedValue::Int(i64::MAX)], &[OwnedValue::Int(1)]]
+            )
+            .is_err()
+        );
+        assert_eq!(
+            aggregate(
+                "group_concat",
+                &[&[text("a"), text("; ")], &[text("b"), text("; ")]]
+            )
+            .unwrap(),
+            text("a; b")
+        );
+    }
+}
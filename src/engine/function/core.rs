@@ -0,0 +1,24 @@
+use std::cmp::Ordering;
+
+use crate::{
+    engine::function::{Args, ScalarFunction},
+    value::OwnedValue,
+};
+
+// coalesce(), ifnull() and iif() skip the evaluation of arguments, so they are
+// expressions rather than functions.
+pub static SCALAR_FUNCTIONS: &[ScalarFunction] = &[ScalarFunction {
+    name: "nullif",
+    min_args: 2,
+    max_args: Some(2),
+    json_result: false,
+    call: nullif,
+}];
+
+/// The first argument, or NULL when it is equal to the second.
+fn nullif(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(match args[0].sql_cmp(&args[1]) {
+        Ordering::Equal => OwnedValue::Null,
+        _ => args[0].clone(),
+    })
+}
@@ -0,0 +1,891 @@
+use std::{fmt::Write, rc::Rc};
+
+use anyhow::{Context, bail, ensure};
+
+use crate::{
+    engine::function::{Args, ScalarFunction, TableFunction},
+    value::OwnedValue,
+};
+
+pub static SCALAR_FUNCTIONS: &[ScalarFunction] = &[
+    ScalarFunction {
+        name: "json",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: true,
+        call: json,
+    },
+    ScalarFunction {
+        name: "json_valid",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: json_valid,
+    },
+    ScalarFunction {
+        name: "json_type",
+        min_args: 1,
+        max_args: Some(2),
+        json_result: false,
+        call: json_type,
+    },
+    ScalarFunction {
+        name: "json_extract",
+        min_args: 1,
+        max_args: None,
+        json_result: false,
+        call: json_extract,
+    },
+    ScalarFunction {
+        name: "json_array",
+        min_args: 0,
+        max_args: None,
+        json_result: true,
+        call: json_array,
+    },
+    ScalarFunction {
+        name: "json_object",
+        min_args: 0,
+        max_args: None,
+        json_result: true,
+        call: json_object,
+    },
+    ScalarFunction {
+        name: "json_array_length",
+        min_args: 1,
+        max_args: Some(2),
+        json_result: false,
+        call: json_array_length,
+    },
+    ScalarFunction {
+        name: "->",
+        min_args: 2,
+        max_args: Some(2),
+        json_result: true,
+        call: arrow,
+    },
+    ScalarFunction {
+        name: "->>",
+        min_args: 2,
+        max_args: Some(2),
+        json_result: false,
+        call: long_arrow,
+    },
+];
+
+const EACH_COLUMNS: &[&str] = &[
+    "key", "value", "type", "atom", "id", "parent", "fullkey", "path",
+];
+
+pub static TABLE_FUNCTIONS: &[TableFunction] = &[
+    TableFunction {
+        name: "json_each",
+        columns: EACH_COLUMNS,
+        min_args: 1,
+        max_args: Some(2),
+        call: json_each,
+    },
+    TableFunction {
+        name: "json_tree",
+        columns: EACH_COLUMNS,
+        min_args: 1,
+        max_args: Some(2),
+        call: json_tree,
+    },
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    True,
+    False,
+    Integer(i64),
+    Real(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Json::Null => "null",
+            Json::True => "true",
+            Json::False => "false",
+            Json::Integer(_) => "integer",
+            Json::Real(_) => "real",
+            Json::String(_) => "text",
+            Json::Array(_) => "array",
+            Json::Object(_) => "object",
+        }
+    }
+
+    fn is_container(&self) -> bool {
+        matches!(self, Json::Array(_) | Json::Object(_))
+    }
+
+    fn to_value(&self) -> OwnedValue {
+        match self {
+            Json::Null => OwnedValue::Null,
+            Json::True => OwnedValue::Int(1),
+            Json::False => OwnedValue::Int(0),
+            Json::Integer(i) => OwnedValue::Int(*i),
+            Json::Real(f) => OwnedValue::Float(*f),
+            Json::String(s) => text(s.clone()),
+            Json::Array(_) | Json::Object(_) => text(self.to_string()),
+        }
+    }
+
+    fn from_value(value: &OwnedValue, is_json: bool) -> anyhow::Result<Json> {
+        match value {
+            OwnedValue::Null => Ok(Json::Null),
+            OwnedValue::Int(i) => Ok(Json::Integer(*i)),
+            OwnedValue::Float(f) => Ok(Json::Real(*f)),
+            OwnedValue::String(s) if is_json => parse(s),
+            OwnedValue::String(s) => Ok(Json::String(s.to_string())),
+            OwnedValue::Blob(_) => bail!("JSON cannot hold BLOB values"),
+        }
+    }
+}
+
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::True => f.write_str("true"),
+            Json::False => f.write_str("false"),
+            Json::Integer(i) => write!(f, "{i}"),
+            Json::Real(x) if x.is_nan() => f.write_str("null"),
+            Json::Real(x) if x.is_infinite() => {
+                f.write_str(if *x > 0.0 { "9.0e+999" } else { "-9.0e+999" })
+            }
+            Json::Real(x) => {
+                let s = x.to_string();
+                f.write_str(&s)?;
+                if !s.contains(['.', 'e']) {
+                    f.write_str(".0")?;
+                }
+                Ok(())
+            }
+            Json::String(s) => write_quoted(f, s),
+            Json::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_char(']')
+            }
+            Json::Object(entries) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_quoted(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+/// Encodes a SQL value as JSON text, as `json_quote()` does.
+pub fn to_json(value: &OwnedValue) -> anyhow::Result<String> {
+    Json::from_value(value, false).map(|json| json.to_string())
+}
+
+fn write_quoted(f: &mut impl Write, s: &str) -> std::fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\u{8}' => f.write_str("\\b")?,
+            '\u{c}' => f.write_str("\\f")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+fn parse(input: &str) -> anyhow::Result<Json> {
+    let mut parser = Parser {
+        input: input.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.parse_value().context("malformed JSON")?;
+    parser.skip_whitespace();
+    ensure!(parser.pos == parser.input.len(), "malformed JSON");
+    Ok(value)
+}
+
+struct Parser<'i> {
+    input: &'i [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn parse_value(&mut self) -> anyhow::Result<Json> {
+        self.skip_whitespace();
+        match self.peek().context("unexpected end of JSON")? {
+            b'{' => self.parse_object(),
+            b'[' => self.parse_array(),
+            b'"' => self.parse_string().map(Json::String),
+            b'-' | b'0'..=b'9' => self.parse_number(),
+            _ if self.eat_keyword("null") => Ok(Json::Null),
+            _ if self.eat_keyword("true") => Ok(Json::True),
+            _ if self.eat_keyword("false") => Ok(Json::False),
+            c => bail!("unexpected character in JSON: {}", c as char),
+        }
+    }
+
+    fn parse_object(&mut self) -> anyhow::Result<Json> {
+        self.expect(b'{')?;
+        let mut entries = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(entries));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            entries.push((key, self.parse_value()?));
+            self.skip_whitespace();
+            match self.next().context("unterminated JSON object")? {
+                b',' => continue,
+                b'}' => return Ok(Json::Object(entries)),
+                c => bail!("unexpected character in JSON object: {}", c as char),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> anyhow::Result<Json> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.next().context("unterminated JSON array")? {
+                b',' => continue,
+                b']' => return Ok(Json::Array(items)),
+                c => bail!("unexpected character in JSON array: {}", c as char),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> anyhow::Result<String> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            match self.next().context("unterminated JSON string")? {
+                b'"' => break,
+                b'\\' => match self.next().context("unterminated JSON string")? {
+                    b'"' => bytes.push(b'"'),
+                    b'\\' => bytes.push(b'\\'),
+                    b'/' => bytes.push(b'/'),
+                    b'b' => bytes.push(0x08),
+                    b'f' => bytes.push(0x0c),
+                    b'n' => bytes.push(b'\n'),
+                    b'r' => bytes.push(b'\r'),
+                    b't' => bytes.push(b'\t'),
+                    b'u' => {
+                        let c = self.parse_unicode_escape()?;
+                        bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                    }
+                    c => bail!("invalid escape in JSON string: \\{}", c as char),
+                },
+                c if c < 0x20 => bail!("control character in JSON string"),
+                c => bytes.push(c),
+            }
+        }
+        String::from_utf8(bytes).context("invalid utf8 in JSON string")
+    }
+
+    fn parse_unicode_escape(&mut self) -> anyhow::Result<char> {
+        let high = self.parse_hex4()?;
+        if !(0xD800..0xDC00).contains(&high) {
+            return char::from_u32(high).context("invalid unicode escape");
+        }
+
+        ensure!(
+            self.next() == Some(b'\\') && self.next() == Some(b'u'),
+            "unpaired surrogate in JSON string"
+        );
+        let low = self.parse_hex4()?;
+        ensure!(
+            (0xDC00..0xE000).contains(&low),
+            "invalid surrogate pair in JSON string"
+        );
+        char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00))
+            .context("invalid unicode escape")
+    }
+
+    fn parse_hex4(&mut self) -> anyhow::Result<u32> {
+        let digits = self
+            .input
+            .get(self.pos..self.pos + 4)
+            .context("truncated unicode escape")?;
+        self.pos += 4;
+        let digits = std::str::from_utf8(digits).context("invalid unicode escape")?;
+        u32::from_str_radix(digits, 16).context("invalid unicode escape")
+    }
+
+    fn parse_number(&mut self) -> anyhow::Result<Json> {
+        let start = self.pos;
+        let mut is_real = false;
+
+        self.eat(b'-');
+        match self.next() {
+            Some(b'0') => {}
+            Some(b'1'..=b'9') => {
+                self.skip_digits();
+            }
+            _ => bail!("invalid JSON number"),
+        }
+        if self.eat(b'.') {
+            is_real = true;
+            ensure!(self.skip_digits() > 0, "invalid JSON number");
+        }
+        if self.eat(b'e') || self.eat(b'E') {
+            is_real = true;
+            if !self.eat(b'+') {
+                self.eat(b'-');
+            }
+            ensure!(self.skip_digits() > 0, "invalid JSON number");
+        }
+
+        let text = std::str::from_utf8(&self.input[start..self.pos]).unwrap();
+        if !is_real && let Ok(i) = text.parse() {
+            return Ok(Json::Integer(i));
+        }
+        Ok(Json::Real(text.parse().context("invalid JSON number")?))
+    }
+
+    fn skip_digits(&mut self) -> usize {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .peek()
+            .is_some_and(|c| matches!(c, b' ' | b'\t' | b'\n' | b'\r'))
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.input[self.pos..].starts_with(keyword.as_bytes()) {
+            self.pos += keyword.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> anyhow::Result<()> {
+        ensure!(self.eat(c), "expected '{}' in JSON", c as char);
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek();
+        if c.is_some() {
+            self.pos += 1;
+        }
+        c
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+    FromEnd(usize),
+}
+
+fn parse_path(path: &str) -> anyhow::Result<Vec<PathSegment>> {
+    let bad_path = || anyhow::anyhow!("bad JSON path: '{path}'");
+    let mut rest = path.strip_prefix('$').ok_or_else(bad_path)?;
+    let mut segments = Vec::new();
+
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix('.') {
+            let (key, r) = if let Some(quoted) = r.strip_prefix('"') {
+                let end = quoted.find('"').ok_or_else(bad_path)?;
+                (&quoted[..end], &quoted[end + 1..])
+            } else {
+                let end = r.find(['.', '[']).unwrap_or(r.len());
+                (&r[..end], &r[end..])
+            };
+            if key.is_empty() {
+                return Err(bad_path());
+            }
+            segments.push(PathSegment::Key(key.to_string()));
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('[') {
+            let end = r.find(']').ok_or_else(bad_path)?;
+            let index = &r[..end];
+            let segment = if let Some(from_end) = index.strip_prefix("#-") {
+                PathSegment::FromEnd(from_end.trim().parse().map_err(|_| bad_path())?)
+            } else {
+                PathSegment::Index(index.trim().parse().map_err(|_| bad_path())?)
+            };
+            segments.push(segment);
+            rest = &r[end + 1..];
+        } else {
+            return Err(bad_path());
+        }
+    }
+
+    Ok(segments)
+}
+
+fn lookup<'j>(json: &'j Json, path: &[PathSegment]) -> Option<&'j Json> {
+    path.iter()
+        .try_fold(json, |current, segment| match (current, segment) {
+            (Json::Object(entries), PathSegment::Key(key)) => {
+                entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+            }
+            (Json::Array(items), PathSegment::Index(i)) => items.get(*i),
+            (Json::Array(items), PathSegment::FromEnd(i)) => {
+                items.len().checked_sub(*i).and_then(|i| items.get(i))
+            }
+            _ => None,
+        })
+}
+
+fn text(s: String) -> OwnedValue {
+    OwnedValue::String(Rc::new(s))
+}
+
+/// Parses a function argument as JSON, numbers being their own JSON representation.
+fn parse_arg(value: &OwnedValue) -> anyhow::Result<Option<Json>> {
+    match value {
+        OwnedValue::Null => Ok(None),
+        OwnedValue::String(s) => parse(s).map(Some),
+        OwnedValue::Int(i) => Ok(Some(Json::Integer(*i))),
+        OwnedValue::Float(f) => Ok(Some(Json::Real(*f))),
+        OwnedValue::Blob(_) => bail!("malformed JSON"),
+    }
+}
+
+fn path_arg(value: &OwnedValue) -> anyhow::Result<Option<Vec<PathSegment>>> {
+    match value {
+        OwnedValue::Null => Ok(None),
+        OwnedValue::String(s) => parse_path(s).map(Some),
+        v => bail!("bad JSON path: '{v}'"),
+    }
+}
+
+fn json(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(match parse_arg(&args[0])? {
+        Some(json) => text(json.to_string()),
+        None => OwnedValue::Null,
+    })
+}
+
+fn json_valid(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(match &args[0] {
+        OwnedValue::Null => OwnedValue::Null,
+        OwnedValue::String(s) => OwnedValue::Int(parse(s).is_ok() as i64),
+        OwnedValue::Int(_) | OwnedValue::Float(_) => OwnedValue::Int(1),
+        OwnedValue::Blob(_) => OwnedValue::Int(0),
+    })
+}
+
+fn json_type(args: &Args) -> anyhow::Result<OwnedValue> {
+    let Some(json) = parse_arg(&args[0])? else {
+        return Ok(OwnedValue::Null);
+    };
+
+    let path = match args.get(1) {
+        Some(path) => match path_arg(path)? {
+            Some(path) => path,
+            None => return Ok(OwnedValue::Null),
+        },
+        None => Vec::new(),
+    };
+
+    Ok(match lookup(&json, &path) {
+        Some(found) => text(found.type_name().to_string()),
+        None => OwnedValue::Null,
+    })
+}
+
+fn json_extract(args: &Args) -> anyhow::Result<OwnedValue> {
+    let Some(json) = parse_arg(&args[0])? else {
+        return Ok(OwnedValue::Null);
+    };
+
+    if args.len() == 2 {
+        let Some(path) = path_arg(&args[1])? else {
+            return Ok(OwnedValue::Null);
+        };
+        return Ok(lookup(&json, &path).map_or(OwnedValue::Null, Json::to_value));
+    }
+
+    let mut results = Vec::with_capacity(args.len() - 1);
+    for path in &args[1..] {
+        let Some(path) = path_arg(path)? else {
+            return Ok(OwnedValue::Null);
+        };
+        results.push(lookup(&json, &path).cloned().unwrap_or(Json::Null));
+    }
+    Ok(text(Json::Array(results).to_string()))
+}
+
+fn json_array(args: &Args) -> anyhow::Result<OwnedValue> {
+    let items = args
+        .iter()
+        .enumerate()
+        .map(|(i, v)| Json::from_value(v, args.is_json(i)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(text(Json::Array(items).to_string()))
+}
+
+fn json_object(args: &Args) -> anyhow::Result<OwnedValue> {
+    ensure!(
+        args.len().is_multiple_of(2),
+        "json_object() requires an even number of arguments"
+    );
+
+    let mut entries = Vec::with_capacity(args.len() / 2);
+    for i in (0..args.len()).step_by(2) {
+        let OwnedValue::String(key) = &args[i] else {
+            bail!("json_object() labels must be TEXT");
+        };
+        entries.push((
+            key.to_string(),
+            Json::from_value(&args[i + 1], args.is_json(i + 1))?,
+        ));
+    }
+    Ok(text(Json::Object(entries).to_string()))
+}
+
+fn json_array_length(args: &Args) -> anyhow::Result<OwnedValue> {
+    let Some(json) = parse_arg(&args[0])? else {
+        return Ok(OwnedValue::Null);
+    };
+
+    let path = match args.get(1) {
+        Some(path) => match path_arg(path)? {
+            Some(path) => path,
+            None => return Ok(OwnedValue::Null),
+        },
+        None => Vec::new(),
+    };
+
+    Ok(match lookup(&json, &path) {
+        Some(Json::Array(items)) => OwnedValue::Int(items.len() as i64),
+        Some(_) => OwnedValue::Int(0),
+        None => OwnedValue::Null,
+    })
+}
+
+/// Right-hand side of `->`/`->>`: a full path, an object label or an array index.
+fn operator_path(value: &OwnedValue) -> anyhow::Result<Option<Vec<PathSegment>>> {
+    match value {
+        OwnedValue::String(s) if s.starts_with('$') => parse_path(s).map(Some),
+        OwnedValue::String(s) => Ok(Some(vec![PathSegment::Key(s.to_string())])),
+        OwnedValue::Int(i) if *i >= 0 => Ok(Some(vec![PathSegment::Index(*i as usize)])),
+        OwnedValue::Int(i) => Ok(Some(vec![PathSegment::FromEnd(i.unsigned_abs() as usize)])),
+        _ => Ok(None),
+    }
+}
+
+fn arrow(args: &Args) -> anyhow::Result<OwnedValue> {
+    let (Some(json), Some(path)) = (parse_arg(&args[0])?, operator_path(&args[1])?) else {
+        return Ok(OwnedValue::Null);
+    };
+    Ok(lookup(&json, &path).map_or(OwnedValue::Null, |found| text(found.to_string())))
+}
+
+fn long_arrow(args: &Args) -> anyhow::Result<OwnedValue> {
+    let (Some(json), Some(path)) = (parse_arg(&args[0])?, operator_path(&args[1])?) else {
+        return Ok(OwnedValue::Null);
+    };
+    Ok(lookup(&json, &path).map_or(OwnedValue::Null, Json::to_value))
+}
+
+fn json_each(args: &Args) -> anyhow::Result<Vec<Vec<OwnedValue>>> {
+    let mut walker = TreeWalker::default();
+    walker.walk_target(args, false)?;
+    Ok(walker.rows)
+}
+
+fn json_tree(args: &Args) -> anyhow::Result<Vec<Vec<OwnedValue>>> {
+    let mut walker = TreeWalker::default();
+    walker.walk_target(args, true)?;
+    Ok(walker.rows)
+}
+
+#[derive(Default)]
+struct TreeWalker {
+    rows: Vec<Vec<OwnedValue>>,
+    next_id: i64,
+}
+
+impl TreeWalker {
+    fn walk_target(&mut self, args: &Args, recursive: bool) -> anyhow::Result<()> {
+        let Some(json) = parse_arg(&args[0])? else {
+            return Ok(());
+        };
+
+        let (path_text, path) = match args.get(1) {
+            Some(OwnedValue::String(p)) => (p.to_string(), parse_path(p)?),
+            Some(OwnedValue::Null) => return Ok(()),
+            Some(v) => bail!("bad JSON path: '{v}'"),
+            None => ("$".to_string(), Vec::new()),
+        };
+
+        let Some(target) = lookup(&json, &path) else {
+            return Ok(());
+        };
+
+        let key = match path.last() {
+            Some(PathSegment::Key(k)) => text(k.clone()),
+            Some(PathSegment::Index(i)) => OwnedValue::Int(*i as i64),
+            Some(PathSegment::FromEnd(_)) | None => OwnedValue::Null,
+        };
+        let parent_path = match path_text.rfind(['.', '[']) {
+            Some(end) if !path.is_empty() => path_text[..end].to_string(),
+            _ => "$".to_string(),
+        };
+
+        if recursive {
+            self.walk_tree(target, key, OwnedValue::Null, &path_text, &parent_path);
+        } else if target.is_container() {
+            self.walk_children(target, OwnedValue::Null, &path_text, false);
+        } else {
+            self.push_row(target, key, OwnedValue::Null, &path_text, &parent_path);
+        }
+
+        Ok(())
+    }
+
+    fn walk_tree(
+        &mut self,
+        json: &Json,
+        key: OwnedValue,
+        parent: OwnedValue,
+        full: &str,
+        path: &str,
+    ) {
+        let id = self.push_row(json, key, parent, full, path);
+        self.walk_children(json, OwnedValue::Int(id), full, true);
+    }
+
+    fn walk_children(&mut self, json: &Json, parent: OwnedValue, path: &str, recursive: bool) {
+        let children: Vec<(OwnedValue, String, &Json)> = match json {
+            Json::Array(items) => items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| (OwnedValue::Int(i as i64), format!("{path}[{i}]"), item))
+                .collect(),
+            Json::Object(entries) => entries
+                .iter()
+                .map(|(k, v)| (text(k.clone()), format!("{path}.{}", path_label(k)), v))
+                .collect(),
+            _ => return,
+        };
+
+        for (key, full, child) in children {
+            if recursive {
+                self.walk_tree(child, key, parent.clone(), &full, path);
+            } else {
+                self.push_row(child, key, parent.clone(), &full, path);
+            }
+        }
+    }
+
+    fn push_row(
+        &mut self,
+        json: &Json,
+        key: OwnedValue,
+        parent: OwnedValue,
+        full: &str,
+        path: &str,
+    ) -> i64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let atom = if json.is_container() {
+            OwnedValue::Null
+        } else {
+            json.to_value()
+        };
+
+        self.rows.push(vec![
+            key,
+            json.to_value(),
+            text(json.type_name().to_string()),
+            atom,
+            OwnedValue::Int(id),
+            parent,
+            text(full.to_string()),
+            text(path.to_string()),
+        ]);
+
+        id
+    }
+}
+
+fn path_label(key: &str) -> String {
+    if !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        key.to_string()
+    } else {
+        format!("\"{key}\"")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(f: fn(&Args) -> anyhow::Result<OwnedValue>, args: &[OwnedValue]) -> String {
+        let json = vec![false; args.len()];
+        f(&Args::new(args, &json)).unwrap().to_string()
+    }
+
+    fn s(s: &str) -> OwnedValue {
+        text(s.to_string())
+    }
+
+    #[test]
+    fn parse_and_minify() {
+        let input = r#" { "a" : [1, 2.5, -3e2, true, false, null], "b\n" : "é😀" } "#;
+        assert_eq!(
+            parse(input).unwrap().to_string(),
+            "{\"a\":[1,2.5,-300.0,true,false,null],\"b\\n\":\"é😀\"}"
+        );
+    }
+
+    #[test]
+    fn reject_malformed() {
+        for input in [
+            "",
+            "{",
+            "[1,]",
+            "{\"a\" 1}",
+            "01",
+            "1.",
+            "\"abc",
+            "nul",
+            "[1] 2",
+        ] {
+            assert!(parse(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn extract() {
+        let doc = s(r#"{"a":{"b":[10,"x",{"c":null}]},"d e":1.5}"#);
+        assert_eq!(call(json_extract, &[doc.clone(), s("$.a.b[0]")]), "10");
+        assert_eq!(call(json_extract, &[doc.clone(), s("$.a.b[1]")]), "x");
+        assert_eq!(
+            call(json_extract, &[doc.clone(), s("$.a.b[#-1]")]),
+            "{\"c\":null}"
+        );
+        assert_eq!(call(json_extract, &[doc.clone(), s("$.\"d e\"")]), "1.5");
+        assert_eq!(call(json_extract, &[doc.clone(), s("$.missing")]), "null");
+        assert_eq!(
+            call(json_extract, &[doc.clone(), s("$.a.b[0]"), s("$.zz")]),
+            "[10,null]"
+        );
+        assert!(json_extract(&Args::new(&[doc, s("a.b")], &[false, false])).is_err());
+    }
+
+    #[test]
+    fn arrows() {
+        let doc = s(r#"{"a":{"b":"x"},"c":[1,2]}"#);
+        assert_eq!(call(arrow, &[doc.clone(), s("a")]), "{\"b\":\"x\"}");
+        assert_eq!(call(arrow, &[doc.clone(), s("$.a.b")]), "\"x\"");
+        assert_eq!(call(long_arrow, &[doc.clone(), s("$.a.b")]), "x");
+        assert_eq!(call(long_arrow, &[doc, s("$.c[1]")]), "2");
+    }
+
+    #[test]
+    fn types_and_validity() {
+        let doc = s(r#"{"a":[1,2.0,"s",true,null,{}]}"#);
+        assert_eq!(call(json_type, std::slice::from_ref(&doc)), "object");
+        assert_eq!(call(json_type, &[doc.clone(), s("$.a[1]")]), "real");
+        assert_eq!(call(json_type, &[doc.clone(), s("$.a[3]")]), "true");
+        assert_eq!(call(json_type, &[doc.clone(), s("$.a[9]")]), "null");
+        assert_eq!(call(json_array_length, &[doc.clone(), s("$.a")]), "6");
+        assert_eq!(call(json_valid, &[doc]), "1");
+        assert_eq!(call(json_valid, &[s("{oops}")]), "0");
+    }
+
+    #[test]
+    fn build_arrays_and_objects() {
+        let args = [s("a"), OwnedValue::Int(1), s("[1]"), OwnedValue::Null];
+        assert_eq!(
+            json_array(&Args::new(&args, &[false, false, true, false]))
+                .unwrap()
+                .to_string(),
+            "[\"a\",1,[1],null]"
+        );
+        assert_eq!(
+            call(
+                json_object,
+                &[s("k"), s("v\"q"), s("n"), OwnedValue::Float(2.0)]
+            ),
+            "{\"k\":\"v\\\"q\",\"n\":2.0}"
+        );
+        assert!(json_object(&Args::new(&[s("k")], &[false])).is_err());
+    }
+
+    #[test]
+    fn each_and_tree() {
+        let doc = [s(r#"{"a":[1,{"b":2}],"c d":"x"}"#)];
+        let json = [false];
+
+        let each = json_each(&Args::new(&doc, &json)).unwrap();
+        let fullkeys: Vec<_> = each.iter().map(|r| r[6].to_string()).collect();
+        assert_eq!(fullkeys, vec!["$.a", "$.\"c d\""]);
+        assert_eq!(each[1][1].to_string(), "x");
+
+        let tree = json_tree(&Args::new(&doc, &json)).unwrap();
+        let fullkeys: Vec<_> = tree.iter().map(|r| r[6].to_string()).collect();
+        assert_eq!(
+            fullkeys,
+            vec!["$", "$.a", "$.a[0]", "$.a[1]", "$.a[1].b", "$.\"c d\""]
+        );
+        assert_eq!(tree[4][5].to_string(), tree[3][4].to_string());
+        assert_eq!(tree[4][7].to_string(), "$.a[1]");
+    }
+}
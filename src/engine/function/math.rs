@@ -0,0 +1,568 @@
+use std::{
+    cmp::Ordering,
+    f64::consts::{LN_2, LN_10, PI},
+};
+
+use anyhow::Context;
+
+use crate::{
+    engine::function::{Args, ScalarFunction},
+    value::{Affinity, OwnedValue},
+};
+
+pub static SCALAR_FUNCTIONS: &[ScalarFunction] = &[
+    ScalarFunction {
+        name: "abs",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: abs,
+    },
+    ScalarFunction {
+        name: "round",
+        min_args: 1,
+        max_args: Some(2),
+        json_result: false,
+        call: round,
+    },
+    ScalarFunction {
+        name: "sign",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: sign,
+    },
+    ScalarFunction {
+        name: "min",
+        min_args: 2,
+        max_args: None,
+        json_result: false,
+        call: min,
+    },
+    ScalarFunction {
+        name: "max",
+        min_args: 2,
+        max_args: None,
+        json_result: false,
+        call: max,
+    },
+    ScalarFunction {
+        name: "ceil",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: ceil,
+    },
+    ScalarFunction {
+        name: "ceiling",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: ceil,
+    },
+    ScalarFunction {
+        name: "floor",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: floor,
+    },
+    ScalarFunction {
+        name: "trunc",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: trunc,
+    },
+    ScalarFunction {
+        name: "ln",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: ln,
+    },
+    ScalarFunction {
+        name: "log",
+        min_args: 1,
+        max_args: Some(2),
+        json_result: false,
+        call: log,
+    },
+    ScalarFunction {
+        name: "log10",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: log10,
+    },
+    ScalarFunction {
+        name: "log2",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: log2,
+    },
+    ScalarFunction {
+        name: "exp",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: exp,
+    },
+    ScalarFunction {
+        name: "pow",
+        min_args: 2,
+        max_args: Some(2),
+        json_result: false,
+        call: pow,
+    },
+    ScalarFunction {
+        name: "power",
+        min_args: 2,
+        max_args: Some(2),
+        json_result: false,
+        call: pow,
+    },
+    ScalarFunction {
+        name: "sqrt",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: sqrt,
+    },
+    ScalarFunction {
+        name: "mod",
+        min_args: 2,
+        max_args: Some(2),
+        json_result: false,
+        call: modulo,
+    },
+    ScalarFunction {
+        name: "pi",
+        min_args: 0,
+        max_args: Some(0),
+        json_result: false,
+        call: pi,
+    },
+    ScalarFunction {
+        name: "degrees",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: degrees,
+    },
+    ScalarFunction {
+        name: "radians",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: radians,
+    },
+    ScalarFunction {
+        name: "sin",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: sin,
+    },
+    ScalarFunction {
+        name: "cos",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: cos,
+    },
+    ScalarFunction {
+        name: "tan",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: tan,
+    },
+    ScalarFunction {
+        name: "asin",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: asin,
+    },
+    ScalarFunction {
+        name: "acos",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: acos,
+    },
+    ScalarFunction {
+        name: "atan",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: atan,
+    },
+    ScalarFunction {
+        name: "atan2",
+        min_args: 2,
+        max_args: Some(2),
+        json_result: false,
+        call: atan2,
+    },
+    ScalarFunction {
+        name: "sinh",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: sinh,
+    },
+    ScalarFunction {
+        name: "cosh",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: cosh,
+    },
+    ScalarFunction {
+        name: "tanh",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: tanh,
+    },
+    ScalarFunction {
+        name: "asinh",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: asinh,
+    },
+    ScalarFunction {
+        name: "acosh",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: acosh,
+    },
+    ScalarFunction {
+        name: "atanh",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: atanh,
+    },
+];
+
+fn abs(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(match &args[0] {
+        OwnedValue::Null => OwnedValue::Null,
+        OwnedValue::Int(i) => OwnedValue::Int(i.checked_abs().context("integer overflow")?),
+        value => OwnedValue::Float(value.as_f64().abs()),
+    })
+}
+
+fn round(args: &Args) -> anyhow::Result<OwnedValue> {
+    let digits = match args.get(1) {
+        None => 0,
+        Some(OwnedValue::Null) => return Ok(OwnedValue::Null),
+        Some(digits) => match digits.cast(Affinity::Integer) {
+            OwnedValue::Int(digits) => digits.clamp(0, 30),
+            _ => 0,
+        },
+    };
+    Ok(match &args[0] {
+        OwnedValue::Null => OwnedValue::Null,
+        value => OwnedValue::Float(round_real(value.as_f64(), digits as i32)),
+    })
+}
+
+/// Rounds like SQLite, which prints `x` with `digits` decimals and parses it back.
+/// Its printf adds half a unit of the last decimal, nudged up by a few ulps when the
+/// decimals are within the precision of the number, then keeps at most 16
+/// significant digits without rounding them.
+fn round_real(x: f64, digits: i32) -> f64 {
+    // Larger reals have no fractional part.
+    const EXACT_LIMIT: f64 = (1u64 << 52) as f64;
+    if !(-EXACT_LIMIT..=EXACT_LIMIT).contains(&x) {
+        return x;
+    }
+    if digits == 0 {
+        return (x + 0.5f64.copysign(x)) as i64 as f64;
+    }
+
+    let mut rounder = 0.5 * 10f64.powi(-digits);
+    let binary_exponent = ((x.abs().to_bits() >> 52) & 0x7ff) as i32 - 1023;
+    if digits + binary_exponent / 3 < 15 {
+        rounder += x.abs() * 3e-16;
+    }
+
+    let shifted = format!("{:.40e}", x.abs() + rounder);
+    let (mantissa, exponent) = shifted.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let kept = (exponent + 1 + digits).clamp(0, 16) as usize;
+    let mantissa = mantissa.replace('.', "");
+    let rounded: f64 = match kept {
+        0 => 0.0,
+        _ => format!("{}e{}", &mantissa[..kept], exponent + 1 - kept as i32)
+            .parse()
+            .unwrap(),
+    };
+    rounded.copysign(x)
+}
+
+/// -1, 0 or 1 depending on the sign of a number.
+fn sign(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(match args[0].as_number() {
+        Some(value) => OwnedValue::Int(match value.as_f64() {
+            x if x < 0.0 => -1,
+            x if x > 0.0 => 1,
+            _ => 0,
+        }),
+        None => OwnedValue::Null,
+    })
+}
+
+/// The smallest argument, or NULL if any is. Called with a single argument, `min` is
+/// the aggregate function.
+fn min(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(extremum(args, Ordering::Less))
+}
+
+fn max(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(extremum(args, Ordering::Greater))
+}
+
+fn extremum(args: &Args, wanted: Ordering) -> OwnedValue {
+    if args.contains(&OwnedValue::Null) {
+        return OwnedValue::Null;
+    }
+    // Like SQLite, the last of equal smallest values, but the first of the largest.
+    let mut best = &args[0];
+    for arg in &args[1..] {
+        let ordering = arg.sql_cmp(best);
+        if ordering == wanted || (ordering == Ordering::Equal && wanted == Ordering::Less) {
+            best = arg;
+        }
+    }
+    best.clone()
+}
+
+fn ceil(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(integral_function(args, f64::ceil))
+}
+
+fn floor(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(integral_function(args, f64::floor))
+}
+
+fn trunc(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(integral_function(args, f64::trunc))
+}
+
+/// Applies `f` to a real argument, returning integers unchanged.
+fn integral_function(args: &Args, f: fn(f64) -> f64) -> OwnedValue {
+    match args[0].as_number() {
+        Some(OwnedValue::Float(x)) => OwnedValue::Float(f(x)),
+        Some(value) => value,
+        None => OwnedValue::Null,
+    }
+}
+
+/// `log(x)` is the base 10 logarithm of `x`, and `log(b, x)` its base `b` logarithm.
+fn log(args: &Args) -> anyhow::Result<OwnedValue> {
+    let Some(x) = args[0].as_number().map(|x| x.as_f64()) else {
+        return Ok(OwnedValue::Null);
+    };
+    if args.len() == 1 {
+        return log10(args);
+    }
+
+    // SQLite doesn't check that the number is numeric, only the base.
+    let (base, x) = (x.ln(), args[1].as_f64());
+    if base <= 0.0 || x <= 0.0 || base.is_nan() {
+        return Ok(OwnedValue::Null);
+    }
+    Ok(real(x.ln() / base))
+}
+
+fn ln(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(
+        args,
+        |x| if x > 0.0 { x.ln() } else { f64::NAN },
+    ))
+}
+
+// Like SQLite, through the natural logarithm, whose results can differ from the
+// dedicated functions' in the last digit.
+fn log10(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, |x| {
+        if x > 0.0 { x.ln() / LN_10 } else { f64::NAN }
+    }))
+}
+
+fn log2(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, |x| {
+        if x > 0.0 { x.ln() / LN_2 } else { f64::NAN }
+    }))
+}
+
+fn exp(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::exp))
+}
+
+fn sqrt(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::sqrt))
+}
+
+fn degrees(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::to_degrees))
+}
+
+fn radians(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::to_radians))
+}
+
+fn sin(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::sin))
+}
+
+fn cos(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::cos))
+}
+
+fn tan(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::tan))
+}
+
+fn asin(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::asin))
+}
+
+fn acos(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::acos))
+}
+
+fn atan(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::atan))
+}
+
+fn sinh(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::sinh))
+}
+
+fn cosh(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::cosh))
+}
+
+fn tanh(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::tanh))
+}
+
+fn asinh(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::asinh))
+}
+
+fn acosh(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::acosh))
+}
+
+fn atanh(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function(args, f64::atanh))
+}
+
+fn pow(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function2(args, f64::powf))
+}
+
+fn modulo(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function2(args, |x, y| x % y))
+}
+
+fn atan2(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(real_function2(args, f64::atan2))
+}
+
+fn pi(_args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(OwnedValue::Float(PI))
+}
+
+/// Applies `f` to the argument converted to a real number, as functions of SQLite's
+/// math extension do. The result is NULL when the argument isn't a number, or the
+/// function isn't defined for it.
+fn real_function(args: &Args, f: impl Fn(f64) -> f64) -> OwnedValue {
+    match args[0].as_number() {
+        Some(x) => real(f(x.as_f64())),
+        None => OwnedValue::Null,
+    }
+}
+
+fn real_function2(args: &Args, f: fn(f64, f64) -> f64) -> OwnedValue {
+    match (args[0].as_number(), args[1].as_number()) {
+        (Some(x), Some(y)) => real(f(x.as_f64(), y.as_f64())),
+        _ => OwnedValue::Null,
+    }
+}
+
+/// NaN, SQLite's result for arguments outside the domain of a function, is NULL.
+fn real(x: f64) -> OwnedValue {
+    match x.is_nan() {
+        true => OwnedValue::Null,
+        false => OwnedValue::Float(x),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    fn call(f: fn(&Args) -> anyhow::Result<OwnedValue>, args: &[OwnedValue]) -> OwnedValue {
+        f(&Args::new(args, &[])).unwrap()
+    }
+
+    #[test]
+    fn rounding() {
+        assert_eq!(round_real(2.5, 0), 3.0);
+        assert_eq!(round_real(-2.5, 0), -3.0);
+        assert_eq!(round_real(2.675, 2), 2.68);
+        assert_eq!(round_real(1.005, 2), 1.01);
+        assert_eq!(round_real(1.23456789, 30), 1.234567889999999);
+        assert_eq!(round_real(-0.001, 2).to_bits(), (-0.0f64).to_bits());
+        assert_eq!(
+            call(round, &[OwnedValue::Int(5), OwnedValue::Null]),
+            OwnedValue::Null
+        );
+    }
+
+    #[test]
+    fn numeric_arguments() {
+        let text = |s: &str| OwnedValue::String(Rc::new(s.to_string()));
+
+        assert_eq!(call(ceil, &[OwnedValue::Int(3)]), OwnedValue::Int(3));
+        assert_eq!(call(floor, &[text(" 3.5")]), OwnedValue::Float(3.0));
+        assert_eq!(call(ceil, &[text("3.5x")]), OwnedValue::Null);
+        assert_eq!(call(sqrt, &[OwnedValue::Int(-1)]), OwnedValue::Null);
+        assert_eq!(call(ln, &[OwnedValue::Int(0)]), OwnedValue::Null);
+        assert_eq!(
+            call(log, &[OwnedValue::Int(2), OwnedValue::Int(8)]),
+            OwnedValue::Float(3.0)
+        );
+        assert_eq!(call(abs, &[text("-3")]), OwnedValue::Float(3.0));
+        assert!(abs(&Args::new(&[OwnedValue::Int(i64::MIN)], &[])).is_err());
+    }
+
+    #[test]
+    fn scalar_min_max() {
+        let args = [
+            OwnedValue::Int(1),
+            OwnedValue::Float(1.0),
+            OwnedValue::Int(0),
+        ];
+        assert_eq!(call(min, &args), OwnedValue::Int(0));
+        assert_eq!(call(max, &args), OwnedValue::Int(1));
+        assert_eq!(call(min, &args[..2]), OwnedValue::Float(1.0));
+        assert_eq!(
+            call(max, &[OwnedValue::Int(1), OwnedValue::Null]),
+            OwnedValue::Null
+        );
+    }
+}
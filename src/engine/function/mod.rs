@@ -0,0 +1,126 @@
+use std::ops::Deref;
+
+use anyhow::ensure;
+
+use crate::value::OwnedValue;
+
+mod aggregate;
+mod core;
+mod json;
+mod math;
+mod text;
+mod window;
+
+pub use aggregate::{Accumulator, AggregateFunction, BARE_COLUMN};
+pub use json::to_json;
+pub use text::CaseFolding;
+pub use window::{WindowFunction, window_function};
+
+#[derive(Debug)]
+pub struct ScalarFunction {
+    pub name: &'static str,
+    pub min_args: usize,
+    pub max_args: Option<usize>,
+    pub json_result: bool,
+    pub call: fn(&Args) -> anyhow::Result<OwnedValue>,
+}
+
+#[derive(Debug)]
+pub struct TableFunction {
+    pub name: &'static str,
+    pub columns: &'static [&'static str],
+    pub min_args: usize,
+    pub max_args: Option<usize>,
+    pub call: fn(&Args) -> anyhow::Result<Vec<Vec<OwnedValue>>>,
+}
+
+#[derive(Debug)]
+pub struct Args<'a> {
+    values: &'a [OwnedValue],
+    json: &'a [bool],
+}
+
+impl<'a> Args<'a> {
+    pub fn new(values: &'a [OwnedValue], json: &'a [bool]) -> Self {
+        Self { values, json }
+    }
+
+    pub fn is_json(&self, n: usize) -> bool {
+        self.json.get(n).copied().unwrap_or(false)
+    }
+}
+
+impl Deref for Args<'_> {
+    type Target = [OwnedValue];
+
+    fn deref(&self) -> &Self::Target {
+        self.values
+    }
+}
+
+pub fn scalar_function(
+    name: &str,
+    arg_count: usize,
+    case_folding: CaseFolding,
+) -> anyhow::Result<&'static ScalarFunction> {
+    // The first function found wins, letting the Unicode ones replace the others.
+    let case_folded = match case_folding {
+        CaseFolding::Ascii => &[],
+        CaseFolding::Unicode => text::UNICODE_SCALAR_FUNCTIONS,
+    };
+    let function = [
+        core::SCALAR_FUNCTIONS,
+        json::SCALAR_FUNCTIONS,
+        case_folded,
+        text::SCALAR_FUNCTIONS,
+        math::SCALAR_FUNCTIONS,
+    ]
+    .into_iter()
+    .flatten()
+    .find(|f| f.name == name)
+    .ok_or_else(|| anyhow::anyhow!("no such function: {name}"))?;
+    check_arg_count(name, function.min_args, function.max_args, arg_count)?;
+    Ok(function)
+}
+
+/// Looks up `name` among aggregate functions, returning `None` for other functions.
+pub fn aggregate_function(
+    name: &str,
+    arg_count: usize,
+) -> anyhow::Result<Option<&'static AggregateFunction>> {
+    // With several arguments, min() and max() are scalar functions.
+    if matches!(name, "min" | "max") && arg_count > 1 {
+        return Ok(None);
+    }
+    let Some(function) = aggregate::AGGREGATE_FUNCTIONS
+        .iter()
+        .find(|f| f.name == name)
+    else {
+        return Ok(None);
+    };
+    check_arg_count(name, function.min_args, function.max_args, arg_count)?;
+    Ok(Some(function))
+}
+
+pub fn table_function(name: &str, arg_count: usize) -> anyhow::Result<&'static TableFunction> {
+    let function = [json::TABLE_FUNCTIONS]
+        .into_iter()
+        .flatten()
+        .find(|f| f.name == name)
+        .ok_or_else(|| anyhow::anyhow!("no such table-valued function: {name}"))?;
+    check_arg_count(name, function.min_args, function.max_args, arg_count)?;
+    Ok(function)
+}
+
+fn check_arg_count(
+    name: &str,
+    min_args: usize,
+    max_args: Option<usize>,
+    arg_count: usize,
+) -> anyhow::Result<()> {
+    ensure!(
+        arg_count >= min_args && max_args.is_none_or(|max| arg_count <= max),
+        "wrong number of arguments to function {name}()"
+    );
+    Ok(())
+}
@@ -0,0 +1,518 @@
+use std::{borrow::Cow, ops::Range, rc::Rc};
+
+use anyhow::{bail, ensure};
+
+pub use crate::value::CaseFolding;
+use crate::{
+    engine::function::{Args, ScalarFunction},
+    value::{Affinity, OwnedValue, format_real},
+};
+
+pub static SCALAR_FUNCTIONS: &[ScalarFunction] = &[
+    ScalarFunction {
+        name: "upper",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: upper,
+    },
+    ScalarFunction {
+        name: "lower",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: lower,
+    },
+    ScalarFunction {
+        name: "||",
+        min_args: 2,
+        max_args: Some(2),
+        json_result: false,
+        call: concat,
+    },
+    ScalarFunction {
+        name: "length",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: length,
+    },
+    ScalarFunction {
+        name: "substr",
+        min_args: 2,
+        max_args: Some(3),
+        json_result: false,
+        call: substr,
+    },
+    ScalarFunction {
+        name: "trim",
+        min_args: 1,
+        max_args: Some(2),
+        json_result: false,
+        call: trim,
+    },
+    ScalarFunction {
+        name: "ltrim",
+        min_args: 1,
+        max_args: Some(2),
+        json_result: false,
+        call: ltrim,
+    },
+    ScalarFunction {
+        name: "rtrim",
+        min_args: 1,
+        max_args: Some(2),
+        json_result: false,
+        call: rtrim,
+    },
+    ScalarFunction {
+        name: "replace",
+        min_args: 3,
+        max_args: Some(3),
+        json_result: false,
+        call: replace,
+    },
+    ScalarFunction {
+        name: "instr",
+        min_args: 2,
+        max_args: Some(2),
+        json_result: false,
+        call: instr,
+    },
+    ScalarFunction {
+        name: "like",
+        min_args: 2,
+        max_args: Some(3),
+        json_result: false,
+        call: like,
+    },
+];
+
+/// Functions replacing the ones of [`SCALAR_FUNCTIONS`] with Unicode case folding.
+pub static UNICODE_SCALAR_FUNCTIONS: &[ScalarFunction] = &[
+    ScalarFunction {
+        name: "upper",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: unicode_upper,
+    },
+    ScalarFunction {
+        name: "lower",
+        min_args: 1,
+        max_args: Some(1),
+        json_result: false,
+        call: unicode_lower,
+    },
+    ScalarFunction {
+        name: "like",
+        min_args: 2,
+        max_args: Some(3),
+        json_result: false,
+        call: unicode_like,
+    },
+];
+
+fn upper(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(map_text(&args[0], |s| s.to_ascii_uppercase()))
+}
+
+fn lower(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(map_text(&args[0], |s| s.to_ascii_lowercase()))
+}
+
+fn unicode_upper(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(map_text(&args[0], str::to_uppercase))
+}
+
+fn unicode_lower(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(map_text(&args[0], str::to_lowercase))
+}
+
+fn concat(args: &Args) -> anyhow::Result<OwnedValue> {
+    let (Some(a), Some(b)) = (to_text(&args[0]), to_text(&args[1])) else {
+        return Ok(OwnedValue::Null);
+    };
+    Ok(OwnedValue::String(Rc::new(a.into_owned() + &b)))
+}
+
+/// The number of characters of text, or of bytes of blobs.
+fn length(args: &Args) -> anyhow::Result<OwnedValue> {
+    Ok(match &args[0] {
+        OwnedValue::Null => OwnedValue::Null,
+        OwnedValue::Blob(b) => OwnedValue::Int(b.len() as i64),
+        value => OwnedValue::Int(to_text(value).unwrap_or_default().chars().count() as i64),
+    })
+}
+
+fn substr(args: &Args) -> anyhow::Result<OwnedValue> {
+    if args.contains(&OwnedValue::Null) {
+        return Ok(OwnedValue::Null);
+    }
+    let start = to_integer(&args[1]);
+    let length = args.get(2).map(to_integer);
+
+    Ok(match &args[0] {
+        OwnedValue::Blob(b) => {
+            let range = substr_range(b.len(), start, length);
+            OwnedValue::Blob(Rc::new(b[range].to_vec()))
+        }
+        value => {
+            let text = to_text(value).unwrap_or_default();
+            let range = substr_range(text.chars().count(), start, length);
+            let substring = text.chars().skip(range.start).take(range.len()).collect();
+            OwnedValue::String(Rc::new(substring))
+        }
+    })
+}
+
+/// The characters, or bytes, of a value of `len` of them selected by `substr`. Like
+/// SQLite, positions count from 1, or from the end when negative, a negative length
+/// selects the characters before the start, and position 0 is just before the first
+/// character.
+fn substr_range(len: usize, start: i64, length: Option<i64>) -> Range<usize> {
+    let len = len as i64;
+    let mut p1 = start;
+    let mut p2 = length.map_or(i64::MAX, i64::saturating_abs);
+
+    if p1 < 0 {
+        p1 = p1.saturating_add(len);
+        if p1 < 0 {
+            p2 = p2.saturating_add(p1);
+            p1 = 0;
+        }
+    } else if p1 > 0 {
+        p1 -= 1;
+    } else if p2 > 0 {
+        p2 -= 1;
+    }
+    if length.is_some_and(|l| l < 0) {
+        p1 = p1.saturating_sub(p2);
+        if p1 < 0 {
+            p2 = p2.saturating_add(p1);
+            p1 = 0;
+        }
+    }
+
+    let start = p1.min(len);
+    let end = p1.saturating_add(p2.max(0)).min(len);
+    start as usize..end as usize
+}
+
+fn trim(args: &Args) -> anyhow::Result<OwnedValue> {
+    trim_sides(args, true, true)
+}
+
+fn ltrim(args: &Args) -> anyhow::Result<OwnedValue> {
+    trim_sides(args, true, false)
+}
+
+fn rtrim(args: &Args) -> anyhow::Result<OwnedValue> {
+    trim_sides(args, false, true)
+}
+
+/// Removes the characters of the second argument, spaces by default, from the start
+/// and/or end of the first.
+fn trim_sides(args: &Args, start: bool, end: bool) -> anyhow::Result<OwnedValue> {
+    let Some(text) = to_text(&args[0]) else {
+        return Ok(OwnedValue::Null);
+    };
+    let characters: Vec<char> = match args.get(1).map(to_text) {
+        None => vec![' '],
+        Some(None) => return Ok(OwnedValue::Null),
+        Some(Some(characters)) => characters.chars().collect(),
+    };
+
+    let mut trimmed: &str = &text;
+    if start {
+        trimmed = trimmed.trim_start_matches(characters.as_slice());
+    }
+    if end {
+        trimmed = trimmed.trim_end_matches(characters.as_slice());
+    }
+    Ok(OwnedValue::String(Rc::new(trimmed.to_string())))
+}
+
+fn replace(args: &Args) -> anyhow::Result<OwnedValue> {
+    let (Some(text), Some(pattern), Some(replacement)) =
+        (to_text(&args[0]), to_text(&args[1]), to_text(&args[2]))
+    else {
+        return Ok(OwnedValue::Null);
+    };
+    if pattern.is_empty() {
+        return Ok(args[0].clone());
+    }
+    Ok(OwnedValue::String(Rc::new(
+        text.replace(&*pattern, &replacement),
+    )))
+}
+
+/// The position of the first occurrence of the second argument in the first, counted
+/// in characters from 1, or in bytes when both are blobs, or 0 when it doesn't occur.
+fn instr(args: &Args) -> anyhow::Result<OwnedValue> {
+    if let (OwnedValue::Blob(haystack), OwnedValue::Blob(needle)) = (&args[0], &args[1]) {
+        let position = match needle.is_empty() {
+            true => Some(0),
+            false => haystack
+                .windows(needle.len())
+                .position(|w| w == &needle[..]),
+        };
+        return Ok(OwnedValue::Int(position.map_or(0, |p| p as i64 + 1)));
+    }
+
+    let (Some(haystack), Some(needle)) = (to_text(&args[0]), to_text(&args[1])) else {
+        return Ok(OwnedValue::Null);
+    };
+    let position = haystack
+        .find(&*needle)
+        .map_or(0, |i| haystack[..i].chars().count() as i64 + 1);
+    Ok(OwnedValue::Int(position))
+}
+
+/// SQLite's limit on the length of LIKE patterns, in bytes.
+const MAX_LIKE_PATTERN_LENGTH: usize = 50000;
+
+/// `like(pattern, text, escape)`, what `text LIKE pattern ESCAPE escape` computes:
+/// whether the text matches the pattern, where `%` matches any sequence of
+/// characters, `_` any character, and the escape character makes the next one match
+/// itself. ASCII letters match regardless of their case.
+fn like(args: &Args) -> anyhow::Result<OwnedValue> {
+    like_folding(args, CaseFolding::Ascii)
+}
+
+/// `like()` where all letters match regardless of their case.
+fn unicode_like(args: &Args) -> anyhow::Result<OwnedValue> {
+    like_folding(args, CaseFolding::Unicode)
+}
+
+fn like_folding(args: &Args, case_folding: CaseFolding) -> anyhow::Result<OwnedValue> {
+    let (Some(pattern), Some(text)) = (to_text(&args[0]), to_text(&args[1])) else {
+        return Ok(OwnedValue::Null);
+    };
+    let escape = match args.get(2).map(to_text) {
+        None => None,
+        Some(None) => return Ok(OwnedValue::Null),
+        Some(Some(escape)) => {
+            let mut chars = escape.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Some(c),
+                _ => bail!("ESCAPE expression must be a single character"),
+            }
+        }
+    };
+    ensure!(
+        pattern.len() <= MAX_LIKE_PATTERN_LENGTH,
+        "LIKE or GLOB pattern too complex"
+    );
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let matches = like_matches(&pattern, &text, escape, case_folding);
+    Ok(OwnedValue::Int(matches as i64))
+}
+
+fn like_matches(
+    pattern: &[char],
+    text: &[char],
+    escape: Option<char>,
+    case_folding: CaseFolding,
+) -> bool {
+    // The escape character loses its meaning as a wildcard.
+    let wildcard = |c: char, wildcard: char| c == wildcard && Some(c) != escape;
+
+    let (mut p, mut t) = (0, 0);
+    while p < pattern.len() {
+        let c = pattern[p];
+        if wildcard(c, '%') {
+            // Runs of wildcards match as many characters as they hold `_`, or more.
+            while p < pattern.len() && (wildcard(pattern[p], '%') || wildcard(pattern[p], '_')) {
+                if pattern[p] == '_' {
+                    if t == text.len() {
+                        return false;
+                    }
+                    t += 1;
+                }
+                p += 1;
+            }
+            return p == pattern.len()
+                || (t..=text.len()).any(|start| {
+                    like_matches(&pattern[p..], &text[start..], escape, case_folding)
+                });
+        }
+        if wildcard(c, '_') {
+            if t == text.len() {
+                return false;
+            }
+        } else {
+            let literal = match Some(c) == escape {
+                true => {
+                    p += 1;
+                    // A trailing escape character matches nothing.
+                    match pattern.get(p) {
+                        Some(&literal) => literal,
+                        None => return false,
+                    }
+                }
+                false => c,
+            };
+            let same_letter = |c: &char| match case_folding {
+                CaseFolding::Ascii => c.eq_ignore_ascii_case(&literal),
+                CaseFolding::Unicode => c.to_lowercase().eq(literal.to_lowercase()),
+            };
+            if !text.get(t).is_some_and(same_letter) {
+                return false;
+            }
+        }
+        p += 1;
+        t += 1;
+    }
+    t == text.len()
+}
+
+/// Applies `f` to the value converted to text, keeping NULLs.
+fn map_text(value: &OwnedValue, f: impl Fn(&str) -> String) -> OwnedValue {
+    match to_text(value) {
+        Some(text) => OwnedValue::String(Rc::new(f(&text))),
+        None => OwnedValue::Null,
+    }
+}
+
+/// The value converted to text as SQLite does, or `None` for NULL.
+fn to_text(value: &OwnedValue) -> Option<Cow<'_, str>> {
+    match value {
+        OwnedValue::Null => None,
+        OwnedValue::String(s) => Some(Cow::Borrowed(s)),
+        OwnedValue::Blob(b) => Some(String::from_utf8_lossy(b)),
+        OwnedValue::Int(i) => Some(Cow::Owned(i.to_string())),
+        OwnedValue::Float(x) => Some(Cow::Owned(format_real(*x))),
+    }
+}
+
+fn to_integer(value: &OwnedValue) -> i64 {
+    match value.cast(Affinity::Integer) {
+        OwnedValue::Int(i) => i,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(f: fn(&Args) -> anyhow::Result<OwnedValue>, s: &str) -> OwnedValue {
+        f(&Args::new(
+            &[OwnedValue::String(Rc::new(s.to_string()))],
+            &[],
+        ))
+        .unwrap()
+    }
+
+    fn text(s: &str) -> OwnedValue {
+        OwnedValue::String(Rc::new(s.to_string()))
+    }
+
+    #[test]
+    fn case_folding() {
+        assert_eq!(call(upper, "straße é"), text("STRAßE é"));
+        assert_eq!(call(unicode_upper, "straße é"), text("STRASSE É"));
+        assert_eq!(call(lower, "ÀB"), text("Àb"));
+        assert_eq!(call(unicode_lower, "ÀB"), text("àb"));
+        assert_eq!(
+            upper(&Args::new(&[OwnedValue::Null], &[])).unwrap(),
+            OwnedValue::Null
+        );
+        assert_eq!(
+            upper(&Args::new(&[OwnedValue::Int(12)], &[])).unwrap(),
+            text("12")
+        );
+    }
+
+    #[test]
+    fn substrings() {
+        let substr = |s: &str, start: i64, length: Option<i64>| {
+            let mut args = vec![text(s), OwnedValue::Int(start)];
+            args.extend(length.map(OwnedValue::Int));
+            super::substr(&Args::new(&args, &[])).unwrap()
+        };
+
+        assert_eq!(substr("héllo", 2, None), text("éllo"));
+        assert_eq!(substr("héllo", 2, Some(2)), text("él"));
+        assert_eq!(substr("héllo", 0, Some(2)), text("h"));
+        assert_eq!(substr("héllo", -2, None), text("lo"));
+        assert_eq!(substr("héllo", -7, Some(3)), text("h"));
+        assert_eq!(substr("héllo", 4, Some(-2)), text("él"));
+        assert_eq!(substr("héllo", 0, None), text("héllo"));
+        assert_eq!(substr("héllo", 9, None), text(""));
+    }
+
+    #[test]
+    fn string_functions() {
+        let call = |f: fn(&Args) -> anyhow::Result<OwnedValue>, args: &[OwnedValue]| {
+            f(&Args::new(args, &[])).unwrap()
+        };
+
+        assert_eq!(
+            call(concat, &[text("a"), OwnedValue::Float(1.0)]),
+            text("a1.0")
+        );
+        assert_eq!(
+            call(concat, &[text("a"), OwnedValue::Null]),
+            OwnedValue::Null
+        );
+        assert_eq!(call(length, &[text("héllo")]), OwnedValue::Int(5));
+        assert_eq!(call(length, &[OwnedValue::Int(-12)]), OwnedValue::Int(3));
+        assert_eq!(call(trim, &[text("  a b  ")]), text("a b"));
+        assert_eq!(call(ltrim, &[text("xxaxx"), text("x")]), text("axx"));
+        assert_eq!(call(rtrim, &[text("xxaxy"), text("xy")]), text("xxa"));
+        assert_eq!(
+            call(replace, &[text("a-b-c"), text("-"), text("+")]),
+            text("a+b+c")
+        );
+        assert_eq!(call(instr, &[text("héllo"), text("l")]), OwnedValue::Int(3));
+        assert_eq!(call(instr, &[text("héllo"), text("z")]), OwnedValue::Int(0));
+    }
+
+    #[test]
+    fn like_patterns() {
+        let like = |pattern: &str, s: &str, escape: Option<&str>| {
+            let mut args = vec![text(pattern), text(s)];
+            args.extend(escape.map(text));
+            super::like(&Args::new(&args, &[]))
+        };
+        let matches = |pattern, s, escape| like(pattern, s, escape).unwrap() == OwnedValue::Int(1);
+
+        assert!(matches("a%", "ABC", None));
+        assert!(matches("%b_", "abc", None));
+        assert!(!matches("_é%", "xÉ", None));
+        assert!(matches("_é%", "xé", None));
+        assert!(matches("%%_c", "abc", None));
+        assert!(!matches("%_%_c", "bc", None));
+        assert!(matches("100!%", "100%", Some("!")));
+        assert!(!matches("100!%", "1000", Some("!")));
+        assert!(matches("a!_%", "a_b", Some("!")));
+        assert!(!matches("a!_%", "ab", Some("!")));
+        assert!(!matches("a!", "a", Some("!")));
+        // An escape character that is also a wildcard only escapes.
+        assert!(matches("a%%", "a%", Some("%")));
+        assert!(!matches("a%%", "a%b", Some("%")));
+
+        assert_eq!(
+            super::like(&Args::new(&[text("a"), OwnedValue::Null], &[])).unwrap(),
+            OwnedValue::Null
+        );
+        assert!(like("a", "a", Some("ab")).is_err());
+        assert!(like("a", "a", Some("")).is_err());
+    }
+
+    #[test]
+    fn unicode_like_patterns() {
+        let like = |pattern: &str, s: &str| {
+            unicode_like(&Args::new(&[text(pattern), text(s)], &[])).unwrap() == OwnedValue::Int(1)
+        };
+        assert!(like("_é%", "xÉ"));
+        assert!(like("%ΣΑ_", "σας"));
+        assert!(!like("straße", "STRASSE"));
+        assert!(like("a%", "ABC"));
+        assert!(!like("a_", "ABC"));
+    }
+}
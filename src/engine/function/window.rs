@@ -0,0 +1,38 @@
+use anyhow::bail;
+
+use super::{AggregateFunction, aggregate_function, check_arg_count};
+
+/// A function computed over the rows of a window partition. Aggregates are computed
+/// over the rows up to the last peer of the current row.
+#[derive(Debug, Clone, Copy)]
+pub enum WindowFunction {
+    RowNumber,
+    Rank,
+    DenseRank,
+    Aggregate(&'static AggregateFunction),
+}
+
+impl WindowFunction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WindowFunction::RowNumber => "row_number",
+            WindowFunction::Rank => "rank",
+            WindowFunction::DenseRank => "dense_rank",
+            WindowFunction::Aggregate(function) => function.name,
+        }
+    }
+}
+
+pub fn window_function(name: &str, arg_count: usize) -> anyhow::Result<WindowFunction> {
+    let function = match name {
+        "row_number" => WindowFunction::RowNumber,
+        "rank" => WindowFunction::Rank,
+        "dense_rank" => WindowFunction::DenseRank,
+        _ => match aggregate_function(name, arg_count)? {
+            Some(function) => return Ok(WindowFunction::Aggregate(function)),
+            None => bail!("no such window function: {name}"),
+        },
+    };
+    check_arg_count(name, 0, Some(0), arg_count)?;
+    Ok(function)
+}
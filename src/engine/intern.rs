@@ -0,0 +1,62 @@
+use std::{collections::HashSet, rc::Rc};
+
+use crate::value::OwnedValue;
+
+const MAX_INTERNED_LEN: usize = 64;
+const MAX_INTERNED_STRINGS: usize = 4096;
+
+/// Deduplicates the strings of rows kept in memory, so that enum-like columns share a
+/// single allocation per distinct value. Long strings aren't interned, and interning
+/// stops once too many distinct strings have been seen, since the column is then
+/// unlikely to have a low cardinality.
+#[derive(Debug, Default)]
+pub struct Interner {
+    strings: HashSet<Rc<String>>,
+}
+
+impl Interner {
+    pub fn intern(&mut self, value: &OwnedValue) -> OwnedValue {
+        let OwnedValue::String(s) = value else {
+            return value.clone();
+        };
+        if s.len() > MAX_INTERNED_LEN {
+            return value.clone();
+        }
+
+        if let Some(interned) = self.strings.get(s.as_ref()) {
+            return OwnedValue::String(interned.clone());
+        }
+        if self.strings.len() >= MAX_INTERNED_STRINGS {
+            return value.clone();
+        }
+
+        // Copy the string rather than sharing it, so that the scan that produced it can
+        // keep reusing its buffer.
+        let interned = Rc::new(s.as_ref().clone());
+        self.strings.insert(interned.clone());
+        OwnedValue::String(interned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_repeated_strings() {
+        let mut interner = Interner::default();
+        let value = |s: &str| OwnedValue::String(Rc::new(s.to_string()));
+
+        let (OwnedValue::String(a), OwnedValue::String(b)) = (
+            interner.intern(&value("red")),
+            interner.intern(&value("red")),
+        ) else {
+            panic!("expected strings");
+        };
+        assert!(Rc::ptr_eq(&a, &b));
+
+        assert_eq!(interner.intern(&OwnedValue::Int(1)), OwnedValue::Int(1));
+        assert_eq!(interner.intern(&value("blue")), value("blue"));
+        assert_eq!(interner.strings.len(), 2);
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    cell::Cell,
+    rc::Rc,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use anyhow::bail;
+
+/// Memory budget shared by the operators of a query. Operators buffering rows take
+/// reservations against it and spill to disk when they can't grow them.
+#[derive(Debug, Clone)]
+pub struct MemoryTracker {
+    budget: usize,
+    used: Rc<Cell<usize>>,
+    peak: Rc<Cell<usize>>,
+    limit: Option<Arc<MemoryLimit>>,
+}
+
+/// Caps the memory buffered by all the queries of a database at once. Unlike the budget
+/// of a query, which operators spill past, queries fail rather than exceed the limit.
+#[derive(Debug)]
+pub struct MemoryLimit {
+    bytes: AtomicUsize,
+    used: AtomicUsize,
+}
+
+impl MemoryLimit {
+    pub fn new(bytes: Option<usize>) -> Self {
+        Self {
+            bytes: AtomicUsize::new(bytes.unwrap_or(usize::MAX)),
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn bytes(&self) -> Option<usize> {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        (bytes != usize::MAX).then_some(bytes)
+    }
+
+    pub fn set_bytes(&self, bytes: Option<usize>) {
+        self.bytes
+            .store(bytes.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    fn try_reserve(&self, bytes: usize) -> bool {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed);
+        if used.saturating_add(bytes) > self.bytes.load(Ordering::Relaxed) {
+            self.release(bytes);
+            return false;
+        }
+        true
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+impl MemoryTracker {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            used: Rc::default(),
+            peak: Rc::default(),
+            limit: None,
+        }
+    }
+
+    pub fn with_limit(mut self, limit: Arc<MemoryLimit>) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Most bytes reserved at once.
+    pub fn peak(&self) -> usize {
+        self.peak.get()
+    }
+
+    pub fn reservation(&self) -> MemoryReservation {
+        MemoryReservation {
+            tracker: self.clone(),
+            bytes: 0,
+        }
+    }
+}
+
+/// Bytes held by one operator, released when the reservation is dropped.
+#[derive(Debug)]
+pub struct MemoryReservation {
+    tracker: MemoryTracker,
+    bytes: usize,
+}
+
+impl MemoryReservation {
+    /// Grows the reservation unless that would exceed the budget or the limit.
+    pub fn try_grow(&mut self, bytes: usize) -> bool {
+        let used = self.tracker.used.get();
+        if used + bytes > self.tracker.budget {
+            return false;
+        }
+        if let Some(limit) = &self.tracker.limit
+            && !limit.try_reserve(bytes)
+        {
+            return false;
+        }
+
+        self.account(bytes);
+        true
+    }
+
+    /// Grows the reservation even past the budget, for operators that must hold at
+    /// least one row to make progress. Fails past the limit.
+    pub fn grow(&mut self, bytes: usize) -> anyhow::Result<()> {
+        if let Some(limit) = &self.tracker.limit
+            && !limit.try_reserve(bytes)
+        {
+            let limit = limit.bytes().unwrap_or(usize::MAX);
+            bail!("out of memory: the queries would buffer more than the limit of {limit} bytes");
+        }
+
+        self.account(bytes);
+        Ok(())
+    }
+
+    fn account(&mut self, bytes: usize) {
+        let used = self.tracker.used.get() + bytes;
+        self.tracker.used.set(used);
+        self.tracker.peak.set(self.tracker.peak.get().max(used));
+        self.bytes += bytes;
+    }
+
+    pub fn free(&mut self) {
+        self.tracker.used.set(self.tracker.used.get() - self.bytes);
+        if let Some(limit) = &self.tracker.limit {
+            limit.release(self.bytes);
+        }
+        self.bytes = 0;
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.free();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_budget() {
+        let tracker = MemoryTracker::new(100);
+        let mut a = tracker.reservation();
+        let mut b = tracker.reservation();
+
+        assert!(a.try_grow(60));
+        assert!(!b.try_grow(50));
+        assert!(b.try_grow(40));
+
+        drop(a);
+        assert!(b.try_grow(50));
+        b.free();
+        assert_eq!(b.bytes, 0);
+        assert_eq!(tracker.used.get(), 0);
+        assert_eq!(tracker.peak(), 100);
+
+        let limit = Arc::new(MemoryLimit::new(Some(150)));
+        let first = MemoryTracker::new(100).with_limit(limit.clone());
+        let second = MemoryTracker::new(100).with_limit(limit.clone());
+        let mut a = first.reservation();
+        let mut b = second.reservation();
+        assert!(a.try_grow(100));
+        assert!(!b.try_grow(60));
+        assert!(b.grow(60).is_err());
+        b.grow(50).unwrap();
+        drop(a);
+        assert!(b.try_grow(50));
+        assert_eq!(limit.used.load(Ordering::Relaxed), 100);
+    }
+}
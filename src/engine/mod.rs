@@ -1,2 +1,23 @@
+mod batch;
+mod cache;
+mod cost;
+mod ddl;
+mod dml;
+mod expr;
+mod function;
+mod intern;
+mod memory;
 mod operator;
+mod params;
 pub mod plan;
+mod query;
+mod row;
+mod spill;
+
+pub use cache::{ResultCache, StatementCache};
+pub use function::{CaseFolding, to_json};
+pub use memory::{MemoryLimit, MemoryTracker};
+pub use operator::KeyOrder;
+pub use params::Params;
+pub use query::{ExecutionStats, Query};
+pub use row::Schema;
@@ -1,47 +1,3273 @@
-use anyhow::Context;
+use std::{
+    cell::Cell,
+    cmp::Ordering,
+    collections::{BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque, hash_map::DefaultHasher},
+    hash::{Hash, Hasher},
+    mem,
+    rc::Rc,
+    sync::{
+        Arc,
+        atomic::{self, AtomicUsize},
+        mpsc::{self, Receiver, SyncSender},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
-use crate::{cursor::Scanner, value::OwnedValue};
+use anyhow::{Context, bail};
+
+use crate::{
+    cursor::{Cursor, Scanner},
+    db::InterruptCheck,
+    engine::{
+        batch::{BATCH_SIZE, Batch, BatchRows},
+        cache::CachedRows,
+        expr::{BinaryExpr, BinaryOp, Expr},
+        function::{Accumulator, AggregateFunction, BARE_COLUMN, WindowFunction},
+        intern::Interner,
+        memory::MemoryReservation,
+        spill::{self, SpillReader, SpillWriter},
+    },
+    pager::IoStats,
+    value::{Affinity, Collation, OwnedValue, SendValue, Value},
+    vtab::rtree::RTreeCursor,
+};
 
 #[derive(Debug)]
 pub enum Operator {
     SeqScan(SeqScan),
+    IndexScan(IndexScan),
+    RowidSeek(RowidSeek),
+    TableFunctionScan(TableFunctionScan),
+    Fts5Scan(Fts5Scan),
+    RTreeScan(RTreeScan),
+    Project(Project),
+    Filter(Filter),
+    NestedLoopJoin(NestedLoopJoin),
+    Materialize(Materialize),
+    Limit(Limit),
+    Distinct(Distinct),
+    HashAggregate(HashAggregate),
+    Window(Window),
+    Sort(Sort),
+    TopN(TopN),
+    CountRows(CountRows),
+    Exchange(Exchange),
+    CachedScan(CachedScan),
+    Instrumented(Instrumented),
+}
+
+/// A step of a query plan, producing rows. `Operator` holds the engine's operators and
+/// dispatches to them through this trait, so that adding one only takes a variant.
+pub trait RowOperator: std::fmt::Debug {
+    /// The name EXPLAIN shows the operator by.
+    fn name(&self) -> &'static str;
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>>;
+
+    /// The next rows, at most `BATCH_SIZE` of them, or `None` once done. Operators
+    /// that don't produce batches of their own collect the rows of `next_row`.
+    fn next_batch(&mut self) -> anyhow::Result<Option<Batch>> {
+        let mut batch = Batch::default();
+        while batch.len() < BATCH_SIZE {
+            match self.next_row()? {
+                Some(row) => batch.push_row(row),
+                None => break,
+            }
+        }
+        Ok((!batch.is_empty()).then_some(batch))
+    }
+
+    /// Restarts the operator, so that it produces its rows again. Only scans and the
+    /// operators streaming their rows can restart.
+    fn rewind(&mut self) -> anyhow::Result<()> {
+        bail!("operator can't be restarted")
+    }
+
+    /// The operators it reads from, if any.
+    fn inputs_mut(&mut self) -> Vec<&mut Operator> {
+        Vec::new()
+    }
+
+    /// Describes the operator and the operators it reads from.
+    fn plan(&self) -> PlanNode;
 }
 
 impl Operator {
     pub fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("next_row", operator = self.name()).entered();
+
+        self.as_row_operator_mut().next_row()
+    }
+
+    pub fn next_batch(&mut self) -> anyhow::Result<Option<Batch>> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("next_batch", operator = self.name()).entered();
+
+        self.as_row_operator_mut().next_batch()
+    }
+
+    pub fn rewind(&mut self) -> anyhow::Result<()> {
+        self.as_row_operator_mut().rewind()
+    }
+
+    pub fn plan(&self) -> PlanNode {
+        self.as_row_operator().plan()
+    }
+
+    /// Wraps the operator and those it reads from in `Instrumented` operators, which
+    /// EXPLAIN ANALYZE reads the counts of the plan from. The stages of an `Exchange`
+    /// run on other threads and aren't instrumented.
+    pub fn instrument(mut self) -> Operator {
+        for input in self.as_row_operator_mut().inputs_mut() {
+            let placeholder = Operator::TableFunctionScan(TableFunctionScan::new(Vec::new()));
+            *input = mem::replace(input, placeholder).instrument();
+        }
+        match self {
+            // Doesn't show in plans.
+            Operator::CountRows(_) => self,
+            _ => Operator::Instrumented(Instrumented::new(self)),
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    fn name(&self) -> &'static str {
+        self.as_row_operator().name()
+    }
+
+    fn as_row_operator(&self) -> &dyn RowOperator {
+        match self {
+            Operator::SeqScan(o) => o,
+            Operator::IndexScan(o) => o,
+            Operator::RowidSeek(o) => o,
+            Operator::TableFunctionScan(o) => o,
+            Operator::Fts5Scan(o) => o,
+            Operator::RTreeScan(o) => o,
+            Operator::CachedScan(o) => o,
+            Operator::Project(o) => o,
+            Operator::Filter(o) => o,
+            Operator::NestedLoopJoin(o) => o,
+            Operator::Materialize(o) => o,
+            Operator::Limit(o) => o,
+            Operator::Distinct(o) => o,
+            Operator::HashAggregate(o) => o,
+            Operator::Window(o) => o,
+            Operator::Sort(o) => o,
+            Operator::TopN(o) => o,
+            Operator::CountRows(o) => o,
+            Operator::Exchange(o) => o,
+            Operator::Instrumented(o) => o,
+        }
+    }
+
+    fn as_row_operator_mut(&mut self) -> &mut dyn RowOperator {
         match self {
-            Operator::SeqScan(s) => s.next_row(),
+            Operator::SeqScan(o) => o,
+            Operator::IndexScan(o) => o,
+            Operator::RowidSeek(o) => o,
+            Operator::TableFunctionScan(o) => o,
+            Operator::Fts5Scan(o) => o,
+            Operator::RTreeScan(o) => o,
+            Operator::CachedScan(o) => o,
+            Operator::Project(o) => o,
+            Operator::Filter(o) => o,
+            Operator::NestedLoopJoin(o) => o,
+            Operator::Materialize(o) => o,
+            Operator::Limit(o) => o,
+            Operator::Distinct(o) => o,
+            Operator::HashAggregate(o) => o,
+            Operator::Window(o) => o,
+            Operator::Sort(o) => o,
+            Operator::TopN(o) => o,
+            Operator::CountRows(o) => o,
+            Operator::Exchange(o) => o,
+            Operator::Instrumented(o) => o,
+        }
+    }
+}
+
+/// An operator of a plan as shown by EXPLAIN, with the names of the columns of the rows
+/// it produces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanNode {
+    pub description: String,
+    pub columns: Vec<String>,
+    pub inputs: Vec<PlanNode>,
+    /// The line describing the operator in SQLite's EXPLAIN QUERY PLAN, for the
+    /// operators that have one.
+    pub query_plan: Option<String>,
+}
+
+impl PlanNode {
+    fn new(description: String, columns: Vec<String>, inputs: Vec<PlanNode>) -> Self {
+        Self {
+            description,
+            columns,
+            inputs,
+            query_plan: None,
+        }
+    }
+
+    fn with_query_plan(mut self, detail: String) -> Self {
+        self.query_plan = Some(detail);
+        self
+    }
+
+    /// The rows of EXPLAIN QUERY PLAN: the id of the step, the id of its parent and
+    /// its description. Like SQLite, a step follows the steps producing its input.
+    pub fn query_plan_steps(&self) -> Vec<(i64, i64, String)> {
+        let mut steps = Vec::new();
+        self.collect_steps(&mut steps);
+        steps
+    }
+
+    fn collect_steps(&self, steps: &mut Vec<(i64, i64, String)>) {
+        for input in &self.inputs {
+            input.collect_steps(steps);
+        }
+        if let Some(detail) = &self.query_plan {
+            steps.push((steps.len() as i64 + 1, 0, detail.clone()));
         }
     }
+
+    /// One line per operator, indented below the operator reading its rows.
+    pub fn render(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        self.render_into(0, &mut lines);
+        lines
+    }
+
+    fn render_into(&self, depth: usize, lines: &mut Vec<String>) {
+        lines.push(format!("{}{}", "  ".repeat(depth), self.description));
+        for input in &self.inputs {
+            input.render_into(depth + 1, lines);
+        }
+    }
+}
+
+/// Names for the columns of rows whose columns have no name.
+fn positional_columns(width: usize) -> Vec<String> {
+    (0..width).map(|i| format!("#{i}")).collect()
 }
 
 #[derive(Debug)]
 pub struct SeqScan {
+    /// The name the query refers to the table by, its alias if it has one.
+    table: String,
+    /// Names of all the columns of the table, of which `fields` are read.
+    columns: Vec<String>,
+    affinities: Vec<Affinity>,
     fields: Vec<usize>,
+    /// The columns holding the rowid rather than a field of the record, i.e. the
+    /// INTEGER PRIMARY KEY column, stored as NULL, and the hidden rowid column.
+    rowid_columns: Vec<usize>,
+    /// Columns the query doesn't refer to, left NULL rather than decoded.
+    skipped: Vec<usize>,
+    filters: Vec<ScanFilter>,
+    /// The values the filters compare with.
+    constants: Vec<OwnedValue>,
+    /// Counts the rows rejected by the filters, for the scan's `CountRows`.
+    rejected: Arc<AtomicUsize>,
+    /// The number of rows the planner expects the scan to produce, from the statistics
+    /// of the table.
+    estimated_rows: Option<u64>,
     scanner: Scanner,
     row_buffer: Vec<OwnedValue>,
 }
 
+/// A `column op constant` comparison pushed down from the WHERE clause into a scan,
+/// which checks it on each record before decoding the other columns, so that the rows
+/// it rejects cost a single field.
+#[derive(Debug, Clone)]
+pub struct ScanFilter {
+    /// The column of the scan's rows compared.
+    pub column: usize,
+    pub op: BinaryOp,
+    pub value: SendValue,
+    pub collation: Collation,
+}
+
+impl ScanFilter {
+    fn passes(&self, value: &OwnedValue, constant: &OwnedValue) -> bool {
+        *value != OwnedValue::Null
+            && *constant != OwnedValue::Null
+            && self.op.holds(self.collation.compare(value, constant))
+    }
+}
+
 impl SeqScan {
-    pub fn new(fields: Vec<usize>, scanner: Scanner) -> Self {
+    pub fn new(
+        table: String,
+        columns: Vec<String>,
+        affinities: Vec<Affinity>,
+        fields: Vec<usize>,
+        scanner: Scanner,
+    ) -> Self {
         let row_buffer = vec![OwnedValue::Null; fields.len()];
 
         Self {
+            table,
+            columns,
+            affinities,
             fields,
+            rowid_columns: Vec::new(),
+            skipped: Vec::new(),
+            filters: Vec::new(),
+            constants: Vec::new(),
+            rejected: Arc::default(),
+            estimated_rows: None,
             scanner,
             row_buffer,
         }
     }
 
+    pub fn with_rowid_columns(mut self, rowid_columns: Vec<usize>) -> Self {
+        self.rowid_columns = rowid_columns;
+        self
+    }
+
+    pub fn with_skipped(mut self, skipped: Vec<usize>) -> Self {
+        self.skipped = skipped;
+        self
+    }
+
+    /// Skips the rows failing `filters`, counting them in `rejected`.
+    pub fn with_filters(mut self, filters: Vec<ScanFilter>, rejected: Arc<AtomicUsize>) -> Self {
+        self.constants = filters.iter().map(|f| f.value.clone().into()).collect();
+        self.filters = filters;
+        self.rejected = rejected;
+        self
+    }
+
+    pub fn with_estimated_rows(mut self, estimated_rows: Option<u64>) -> Self {
+        self.estimated_rows = estimated_rows;
+        self
+    }
+
+    /// Decodes the `i`th column of the rows from `record` into the row buffer.
+    fn read_column(&mut self, record: &mut Cursor, i: usize) -> anyhow::Result<()> {
+        let n = self.fields[i];
+        if self.rowid_columns.contains(&n) {
+            self.row_buffer[i] = OwnedValue::Int(record.rowid());
+            return Ok(());
+        }
+
+        let value = record.field(n)?.context("missing record field")?;
+        // SQLite stores the reals of REAL columns that have no fractional part as
+        // integers, to save space.
+        match value {
+            Value::Int(int) if self.affinities[n] == Affinity::Real => {
+                self.row_buffer[i] = OwnedValue::Float(int as f64)
+            }
+            value => self.row_buffer[i].set(value),
+        }
+        Ok(())
+    }
+
+    /// Decodes the row of `record` into the row buffer, unless the filters reject it.
+    fn decode(&mut self, record: &mut Cursor) -> anyhow::Result<bool> {
+        for f in 0..self.filters.len() {
+            let column = self.filters[f].column;
+            self.read_column(record, column)?;
+            if !self.filters[f].passes(&self.row_buffer[column], &self.constants[f]) {
+                self.rejected.fetch_add(1, atomic::Ordering::Relaxed);
+                return Ok(false);
+            }
+        }
+
+        for i in 0..self.fields.len() {
+            if !self.skipped.contains(&i) && !self.filters.iter().any(|f| f.column == i) {
+                self.read_column(record, i)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Describes the scan as `access`, e.g. the operator and the table it reads.
+    fn describe(&self, access: &str) -> (String, Vec<String>) {
+        let columns: Vec<String> = self
+            .fields
+            .iter()
+            .map(|&i| self.columns[i].clone())
+            .collect();
+        let decoded: Vec<&str> = columns
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !self.skipped.contains(i))
+            .map(|(_, column)| column.as_str())
+            .collect();
+        let mut description = format!("{access} ({})", decoded.join(", "));
+        let filters: Vec<String> = self
+            .filters
+            .iter()
+            .zip(&self.constants)
+            .map(|(filter, constant)| {
+                let comparison = BinaryExpr {
+                    op: filter.op,
+                    lhs: Expr::Column(filter.column),
+                    rhs: Expr::Literal(constant.clone()),
+                    collation: filter.collation,
+                };
+                Expr::Binary(Box::new(comparison)).to_sql(&columns)
+            })
+            .collect();
+        if !filters.is_empty() {
+            description.push_str(&format!(" WHERE {}", filters.join(" AND ")));
+        }
+        if let Some(rows) = self.estimated_rows {
+            description.push_str(&format!(" (~{rows} rows)"));
+        }
+        (description, columns)
+    }
+}
+
+impl RowOperator for SeqScan {
+    fn name(&self) -> &'static str {
+        "SeqScan"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        while let Some(mut record) = self.scanner.next_record()? {
+            if self.decode(&mut record)? {
+                return Ok(Some(&self.row_buffer));
+            }
+        }
+        Ok(None)
+    }
+
+    fn rewind(&mut self) -> anyhow::Result<()> {
+        self.scanner.rewind();
+        Ok(())
+    }
+
+    fn plan(&self) -> PlanNode {
+        let access = format!("{} {}", self.name(), self.table);
+        let Some(rowids) = self.scanner.rowids() else {
+            let (description, columns) = self.describe(&access);
+            return PlanNode::new(description, columns, Vec::new())
+                .with_query_plan(format!("SCAN {}", self.table));
+        };
+
+        let (description, columns) = self.describe(&format!("{access} USING INTEGER PRIMARY KEY"));
+        let bounds: Vec<&str> = [
+            (*rowids.start() != i64::MIN).then_some("rowid>?"),
+            (*rowids.end() != i64::MAX).then_some("rowid<?"),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        PlanNode::new(description, columns, Vec::new()).with_query_plan(format!(
+            "SEARCH {} USING INTEGER PRIMARY KEY ({})",
+            self.table,
+            bounds.join(" AND ")
+        ))
+    }
+}
+
+/// Reads the rows of a table in the order of one of its indexes, from the first key
+/// within the bounds set on the first column of the keys to the last. The row of each
+/// key is looked up by the rowid ending it, unless the index covers the columns of the
+/// query and the rows are decoded from the keys themselves.
+#[derive(Debug)]
+pub struct IndexScan {
+    index: String,
+    /// Decodes the rows, and looks them up with its scanner of the table.
+    rows: Box<SeqScan>,
+    keys: Scanner,
+    /// The position of the rowid in the keys, unless the index covers the query.
+    lookup: Option<usize>,
+    /// The first column of the keys, for the plan.
+    column: String,
+    lower: Option<(OwnedValue, bool)>,
+    upper: Option<(OwnedValue, bool)>,
+    collation: Collation,
+    positioned: bool,
+}
+
+/// A bound on the first column of the keys of an index, and whether keys equal to it
+/// are within the bound.
+#[derive(Debug, Clone)]
+pub struct IndexBound {
+    pub value: SendValue,
+    pub inclusive: bool,
+}
+
+impl IndexScan {
+    pub fn new(rows: SeqScan, index: &str, keys: Scanner, lookup: Option<usize>) -> Self {
+        Self {
+            index: index.to_string(),
+            rows: Box::new(rows),
+            keys,
+            lookup,
+            column: String::new(),
+            lower: None,
+            upper: None,
+            collation: Collation::Binary,
+            positioned: false,
+        }
+    }
+
+    /// Only reads the keys whose first column, named `column`, is within the bounds.
+    pub fn with_bounds(
+        mut self,
+        column: String,
+        lower: Option<IndexBound>,
+        upper: Option<IndexBound>,
+        collation: Collation,
+    ) -> Self {
+        let owned = |bound: IndexBound| (bound.value.into(), bound.inclusive);
+        self.column = column;
+        self.lower = lower.map(owned);
+        self.upper = upper.map(owned);
+        self.collation = collation;
+        self
+    }
+
+    fn past_upper_bound(&self, key: &mut Cursor) -> anyhow::Result<bool> {
+        let Some((upper, inclusive)) = &self.upper else {
+            return Ok(false);
+        };
+        let first = key.owned_field(0)?.unwrap_or(OwnedValue::Null);
+        Ok(match self.collation.compare(&first, upper) {
+            Ordering::Greater => true,
+            Ordering::Equal => !inclusive,
+            Ordering::Less => false,
+        })
+    }
+}
+
+impl RowOperator for IndexScan {
+    fn name(&self) -> &'static str {
+        "IndexScan"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        if !self.positioned {
+            self.positioned = true;
+            if let Some((lower, inclusive)) = &self.lower {
+                self.keys.seek(|key| {
+                    let first = key.owned_field(0)?.unwrap_or(OwnedValue::Null);
+                    Ok(match self.collation.compare(&first, lower) {
+                        Ordering::Less => true,
+                        Ordering::Equal => !inclusive,
+                        Ordering::Greater => false,
+                    })
+                })?;
+            }
+        }
+
+        while let Some(mut key) = self.keys.next_record()? {
+            if self.past_upper_bound(&mut key)? {
+                return Ok(None);
+            }
+
+            let decoded = match self.lookup {
+                None => self.rows.decode(&mut key)?,
+                Some(n) => {
+                    let rowid = key
+                        .field(n)?
+                        .and_then(|v| v.as_int())
+                        .with_context(|| format!("missing rowid in key of index {}", self.index))?;
+                    let mut record =
+                        self.rows.scanner.find_rowid(rowid)?.with_context(|| {
+                            format!("missing row {rowid} of index {}", self.index)
+                        })?;
+                    self.rows.decode(&mut record)?
+                }
+            };
+            if decoded {
+                return Ok(Some(&self.rows.row_buffer));
+            }
+        }
+        Ok(None)
+    }
+
+    fn rewind(&mut self) -> anyhow::Result<()> {
+        self.keys.rewind();
+        self.positioned = false;
+        Ok(())
+    }
+
+    fn plan(&self) -> PlanNode {
+        let covering = if self.lookup.is_none() {
+            "COVERING "
+        } else {
+            ""
+        };
+        let access = format!(
+            "{} {} USING {covering}INDEX {}",
+            self.name(),
+            self.rows.table,
+            self.index
+        );
+        let (description, columns) = self.rows.describe(&access);
+
+        let bounds: Vec<String> = [(&self.lower, ">"), (&self.upper, "<")]
+            .into_iter()
+            .filter_map(|(bound, op)| {
+                let (_, inclusive) = bound.as_ref()?;
+                Some(format!(
+                    "{}{op}{}?",
+                    self.column,
+                    if *inclusive { "=" } else { "" }
+                ))
+            })
+            .collect();
+        let bounds = match &self.lower {
+            Some((lower, true)) if self.upper.as_ref() == Some(&(lower.clone(), true)) => {
+                format!(" ({}=?)", self.column)
+            }
+            _ if bounds.is_empty() => String::new(),
+            _ => format!(" ({})", bounds.join(" AND ")),
+        };
+        let verb = if bounds.is_empty() { "SCAN" } else { "SEARCH" };
+        PlanNode::new(description, columns, Vec::new()).with_query_plan(format!(
+            "{verb} {} USING {covering}INDEX {}{bounds}",
+            self.rows.table, self.index
+        ))
+    }
+}
+
+/// Reads the row of a table with a given rowid, descending its b-tree along the keys
+/// of the interior pages rather than scanning the leaves.
+#[derive(Debug)]
+pub struct RowidSeek {
+    rows: SeqScan,
+    rowid: i64,
+    done: bool,
+}
+
+impl RowidSeek {
+    pub fn new(rows: SeqScan, rowid: i64) -> Self {
+        Self {
+            rows,
+            rowid,
+            done: false,
+        }
+    }
+}
+
+impl RowOperator for RowidSeek {
+    fn name(&self) -> &'static str {
+        "RowidSeek"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        if std::mem::replace(&mut self.done, true) {
+            return Ok(None);
+        }
+        let Some(mut record) = self.rows.scanner.find_rowid(self.rowid)? else {
+            return Ok(None);
+        };
+        match self.rows.decode(&mut record)? {
+            true => Ok(Some(&self.rows.row_buffer)),
+            false => Ok(None),
+        }
+    }
+
+    fn rewind(&mut self) -> anyhow::Result<()> {
+        self.done = false;
+        Ok(())
+    }
+
+    fn plan(&self) -> PlanNode {
+        let access = format!("{} {}", self.name(), self.rows.table);
+        let (description, columns) = self.rows.describe(&access);
+        PlanNode::new(description, columns, Vec::new()).with_query_plan(format!(
+            "SEARCH {} USING INTEGER PRIMARY KEY (rowid=?)",
+            self.rows.table
+        ))
+    }
+}
+
+#[derive(Debug)]
+pub struct TableFunctionScan {
+    /// The table-valued function producing the rows, if they don't come from the query.
+    function: Option<String>,
+    rows: Vec<Vec<OwnedValue>>,
+    position: usize,
+}
+
+impl TableFunctionScan {
+    pub fn new(rows: Vec<Vec<OwnedValue>>) -> Self {
+        Self {
+            function: None,
+            rows,
+            position: 0,
+        }
+    }
+
+    pub fn function(name: String, rows: Vec<Vec<OwnedValue>>) -> Self {
+        Self {
+            function: Some(name),
+            rows,
+            position: 0,
+        }
+    }
+}
+
+impl RowOperator for TableFunctionScan {
+    fn name(&self) -> &'static str {
+        "TableFunctionScan"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        let Some(row) = self.rows.get(self.position) else {
+            return Ok(None);
+        };
+
+        self.position += 1;
+        Ok(Some(row))
+    }
+
+    fn rewind(&mut self) -> anyhow::Result<()> {
+        self.position = 0;
+        Ok(())
+    }
+
+    fn plan(&self) -> PlanNode {
+        let name = self.name();
+        let width = self.rows.first().map_or(0, Vec::len);
+        let columns = positional_columns(width);
+        match &self.function {
+            Some(function) => PlanNode::new(format!("{name} {function}"), columns, vec![])
+                .with_query_plan(format!("SCAN {function} VIRTUAL TABLE")),
+            None => PlanNode::new(name.to_string(), columns, Vec::new())
+                .with_query_plan(format!("SCAN {} CONSTANT ROWS", self.rows.len())),
+        }
+    }
+}
+
+/// Replays the rows of a query from the result cache.
+#[derive(Debug)]
+pub struct CachedScan {
+    rows: CachedRows,
+    position: usize,
+    row_buffer: Vec<OwnedValue>,
+}
+
+impl CachedScan {
+    pub fn new(rows: CachedRows) -> Self {
+        Self {
+            rows,
+            position: 0,
+            row_buffer: Vec::new(),
+        }
+    }
+}
+
+impl RowOperator for CachedScan {
+    fn name(&self) -> &'static str {
+        "CachedScan"
+    }
+
     fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
-        let Some(mut record) = self.scanner.next_record()? else {
+        let Some(row) = self.rows.get(self.position) else {
             return Ok(None);
         };
+        self.position += 1;
+
+        self.row_buffer.clear();
+        self.row_buffer
+            .extend(row.iter().cloned().map(OwnedValue::from));
+        Ok(Some(&self.row_buffer))
+    }
+
+    fn plan(&self) -> PlanNode {
+        let name = self.name();
+        PlanNode::new(name.to_string(), Vec::new(), Vec::new())
+    }
+}
+
+/// Scans the documents of an FTS5 table, keeping only the `rowids` matched by the
+/// full-text query when there is one. Contentless tables yield NULL columns. The
+/// rows end with the hidden column named after the table, which is NULL, and the
+/// rowid.
+#[derive(Debug)]
+pub struct Fts5Scan {
+    table: String,
+    scanner: Scanner,
+    rowids: Option<BTreeSet<i64>>,
+    columns: usize,
+    has_content: bool,
+    row_buffer: Vec<OwnedValue>,
+}
+
+impl Fts5Scan {
+    pub fn new(
+        table: String,
+        scanner: Scanner,
+        rowids: Option<BTreeSet<i64>>,
+        columns: usize,
+        has_content: bool,
+    ) -> Self {
+        Self {
+            table,
+            scanner,
+            rowids,
+            columns,
+            has_content,
+            row_buffer: vec![OwnedValue::Null; columns + 2],
+        }
+    }
+}
+
+impl RowOperator for Fts5Scan {
+    fn name(&self) -> &'static str {
+        "Fts5Scan"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        loop {
+            let Some(mut record) = self.scanner.next_record()? else {
+                return Ok(None);
+            };
+
+            if let Some(rowids) = &self.rowids
+                && !rowids.contains(&record.rowid())
+            {
+                continue;
+            }
+
+            if self.has_content {
+                for (i, value) in self.row_buffer[..self.columns].iter_mut().enumerate() {
+                    value.set(record.field(i + 1)?.unwrap_or(Value::Null));
+                }
+            }
+            self.row_buffer[self.columns + 1] = OwnedValue::Int(record.rowid());
+
+            return Ok(Some(&self.row_buffer));
+        }
+    }
+
+    fn plan(&self) -> PlanNode {
+        let name = self.name();
+        let columns = positional_columns(self.row_buffer.len());
+        PlanNode::new(format!("{name} {}", self.table), columns, Vec::new())
+            .with_query_plan(format!("SCAN {} VIRTUAL TABLE", self.table))
+    }
+}
+
+#[derive(Debug)]
+pub struct RTreeScan {
+    table: String,
+    cursor: RTreeCursor,
+    row_buffer: Vec<OwnedValue>,
+}
+
+impl RTreeScan {
+    pub fn new(table: String, cursor: RTreeCursor, columns: usize) -> Self {
+        Self {
+            table,
+            cursor,
+            row_buffer: vec![OwnedValue::Null; columns],
+        }
+    }
+}
+
+impl RowOperator for RTreeScan {
+    fn name(&self) -> &'static str {
+        "RTreeScan"
+    }
 
-        for (i, &n) in self.fields.iter().enumerate() {
-            self.row_buffer[i] = record.owned_field(n)?.context("missing record field")?;
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        if !self.cursor.next_row(&mut self.row_buffer)? {
+            return Ok(None);
         }
 
         Ok(Some(&self.row_buffer))
     }
+
+    fn plan(&self) -> PlanNode {
+        let name = self.name();
+        let columns = positional_columns(self.row_buffer.len());
+        PlanNode::new(format!("{name} {}", self.table), columns, Vec::new())
+            .with_query_plan(format!("SCAN {} VIRTUAL TABLE", self.table))
+    }
+}
+
+/// Computes its expressions on the batches of its input.
+#[derive(Debug)]
+pub struct Project {
+    input: Box<Operator>,
+    exprs: Vec<Expr>,
+    rows: BatchRows,
+}
+
+impl Project {
+    pub fn new(input: Operator, exprs: Vec<Expr>) -> Self {
+        Self {
+            input: Box::new(input),
+            exprs,
+            rows: BatchRows::default(),
+        }
+    }
+
+    fn project_batch(&mut self) -> anyhow::Result<Option<Batch>> {
+        let Some(input) = self.input.next_batch()? else {
+            return Ok(None);
+        };
+        let columns = self
+            .exprs
+            .iter()
+            .map(|expr| expr.eval_batch(&input))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Some(Batch::from_columns(columns, input.len())))
+    }
+}
+
+impl RowOperator for Project {
+    fn name(&self) -> &'static str {
+        "Project"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        if self.rows.is_done() {
+            match self.project_batch()? {
+                Some(batch) => self.rows.refill(batch),
+                None => return Ok(None),
+            }
+        }
+        Ok(self.rows.next_row())
+    }
+
+    fn next_batch(&mut self) -> anyhow::Result<Option<Batch>> {
+        match self.rows.take_rest() {
+            Some(rest) => Ok(Some(rest)),
+            None => self.project_batch(),
+        }
+    }
+
+    fn rewind(&mut self) -> anyhow::Result<()> {
+        self.rows.clear();
+        self.input.rewind()
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Operator> {
+        vec![&mut self.input]
+    }
+
+    fn plan(&self) -> PlanNode {
+        let name = self.name();
+        let input = self.input.plan();
+        let columns: Vec<String> = self
+            .exprs
+            .iter()
+            .map(|e| e.to_sql(&input.columns))
+            .collect();
+        let description = format!("{name} {}", columns.join(", "));
+        PlanNode::new(description, columns, vec![input])
+    }
+}
+
+/// Passes on the rows of its input for which the predicate is true.
+#[derive(Debug)]
+pub struct Filter {
+    input: Box<Operator>,
+    predicate: Expr,
+    rows: BatchRows,
+}
+
+impl Filter {
+    pub fn new(input: Operator, predicate: Expr) -> Self {
+        Self {
+            input: Box::new(input),
+            predicate,
+            rows: BatchRows::default(),
+        }
+    }
+
+    /// Filters the batches of the input until one keeps rows.
+    fn filter_batch(&mut self) -> anyhow::Result<Option<Batch>> {
+        while let Some(mut batch) = self.input.next_batch()? {
+            let keep: Vec<bool> = (self.predicate.eval_batch(&batch)?.iter())
+                .map(|value| value.as_bool() == Some(true))
+                .collect();
+            batch.retain(&keep);
+            if !batch.is_empty() {
+                return Ok(Some(batch));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl RowOperator for Filter {
+    fn name(&self) -> &'static str {
+        "Filter"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        if self.rows.is_done() {
+            match self.filter_batch()? {
+                Some(batch) => self.rows.refill(batch),
+                None => return Ok(None),
+            }
+        }
+        Ok(self.rows.next_row())
+    }
+
+    fn next_batch(&mut self) -> anyhow::Result<Option<Batch>> {
+        match self.rows.take_rest() {
+            Some(rest) => Ok(Some(rest)),
+            None => self.filter_batch(),
+        }
+    }
+
+    fn rewind(&mut self) -> anyhow::Result<()> {
+        self.rows.clear();
+        self.input.rewind()
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Operator> {
+        vec![&mut self.input]
+    }
+
+    fn plan(&self) -> PlanNode {
+        let name = self.name();
+        let input = self.input.plan();
+        let description = format!("{name} {}", self.predicate.to_sql(&input.columns));
+        PlanNode::new(description, input.columns.clone(), vec![input])
+    }
+}
+
+/// Joins each row of `left` with the rows of `right` for which the join constraint is
+/// true, restarting `right` for every row of `left`.
+#[derive(Debug)]
+pub struct NestedLoopJoin {
+    left: Box<Operator>,
+    right: Box<Operator>,
+    constraint: Option<Expr>,
+    /// For LEFT JOINs, the number of columns of `right`, filled with NULLs for the left
+    /// rows that matched no right row.
+    null_padding: Option<usize>,
+    /// The number of values of the current left row at the start of `row_buffer`, or
+    /// `None` before reading the next left row.
+    left_len: Option<usize>,
+    matched: bool,
+    /// Checked for each pair of rows, which the scans don't see when the right side
+    /// is materialized.
+    interrupt: Option<InterruptCheck>,
+    row_buffer: Vec<OwnedValue>,
+}
+
+impl NestedLoopJoin {
+    pub fn new(
+        left: Operator,
+        right: Operator,
+        constraint: Option<Expr>,
+        null_padding: Option<usize>,
+    ) -> Self {
+        Self {
+            left: Box::new(left),
+            right: Box::new(right),
+            constraint,
+            null_padding,
+            left_len: None,
+            matched: false,
+            interrupt: None,
+            row_buffer: Vec::new(),
+        }
+    }
+
+    pub fn with_interrupt(mut self, interrupt: InterruptCheck) -> Self {
+        self.interrupt = Some(interrupt);
+        self
+    }
+}
+
+impl RowOperator for NestedLoopJoin {
+    fn name(&self) -> &'static str {
+        "NestedLoopJoin"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        loop {
+            let left_len = match self.left_len {
+                Some(len) => len,
+                None => {
+                    self.row_buffer.clear();
+                    let Some(row) = self.left.next_row()? else {
+                        return Ok(None);
+                    };
+                    self.row_buffer.extend_from_slice(row);
+                    self.right.rewind()?;
+                    self.matched = false;
+                    *self.left_len.insert(self.row_buffer.len())
+                }
+            };
+
+            if let Some(interrupt) = &mut self.interrupt {
+                interrupt.check()?;
+            }
+            let Some(row) = self.right.next_row()? else {
+                self.left_len = None;
+                if let Some(width) = self.null_padding
+                    && !self.matched
+                {
+                    self.row_buffer.truncate(left_len);
+                    self.row_buffer.resize(left_len + width, OwnedValue::Null);
+                    return Ok(Some(&self.row_buffer));
+                }
+                continue;
+            };
+            self.row_buffer.truncate(left_len);
+            self.row_buffer.extend_from_slice(row);
+
+            let matches = match &self.constraint {
+                Some(constraint) => constraint.eval(&self.row_buffer)?.as_bool() == Some(true),
+                None => true,
+            };
+            if matches {
+                self.matched = true;
+                return Ok(Some(&self.row_buffer));
+            }
+        }
+    }
+
+    fn rewind(&mut self) -> anyhow::Result<()> {
+        self.left.rewind()?;
+        self.left_len = None;
+        Ok(())
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Operator> {
+        vec![&mut self.left, &mut self.right]
+    }
+
+    fn plan(&self) -> PlanNode {
+        let name = self.name();
+        let (left, mut right) = (self.left.plan(), self.right.plan());
+        let columns = [left.columns.as_slice(), right.columns.as_slice()].concat();
+        let mut description = name.to_string();
+        if self.null_padding.is_some() {
+            description.push_str(" LEFT");
+            if let Some(scan) = &mut right.query_plan {
+                scan.push_str(" LEFT-JOIN");
+            }
+        }
+        if let Some(constraint) = &self.constraint {
+            description.push_str(&format!(" ON {}", constraint.to_sql(&columns)));
+        }
+        PlanNode::new(description, columns, vec![left, right])
+    }
+}
+
+/// Keeps the rows of its input as they're first read, in memory until they outgrow
+/// its reservation and in a temporary file after that, so that rewinding replays them
+/// rather than running the input again, e.g. for each row of the outer side of a join.
+#[derive(Debug)]
+pub struct Materialize {
+    input: Box<Operator>,
+    /// Whether all the rows of the input are kept.
+    filled: bool,
+    /// The first rows, with the others in the spill file.
+    rows: Vec<Vec<OwnedValue>>,
+    spill: Option<SpillWriter>,
+    spilled: Option<SpillReader>,
+    /// The position in `rows` when replaying them.
+    position: usize,
+    memory: MemoryReservation,
+    row_buffer: Vec<OwnedValue>,
+}
+
+impl Materialize {
+    pub fn new(input: Operator, memory: MemoryReservation) -> Self {
+        Self {
+            input: Box::new(input),
+            filled: false,
+            rows: Vec::new(),
+            spill: None,
+            spilled: None,
+            position: 0,
+            memory,
+            row_buffer: Vec::new(),
+        }
+    }
+
+    fn keep(&mut self, row: &[OwnedValue]) -> anyhow::Result<()> {
+        if self.spill.is_none() && self.memory.try_grow(spill::row_size(row)) {
+            self.rows.push(row.to_vec());
+            return Ok(());
+        }
+        let spill = match &mut self.spill {
+            Some(spill) => spill,
+            None => self.spill.insert(SpillWriter::create()?),
+        };
+        spill.write_row(row)
+    }
+}
+
+impl RowOperator for Materialize {
+    fn name(&self) -> &'static str {
+        "Materialize"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        if !self.filled {
+            let Some(row) = self.input.next_row()? else {
+                self.filled = true;
+                self.position = self.rows.len();
+                return Ok(None);
+            };
+            self.row_buffer.clear();
+            self.row_buffer.extend_from_slice(row);
+            let row = mem::take(&mut self.row_buffer);
+            self.keep(&row)?;
+            self.row_buffer = row;
+            return Ok(Some(&self.row_buffer));
+        }
+
+        if let Some(row) = self.rows.get(self.position) {
+            self.position += 1;
+            return Ok(Some(row));
+        }
+        if let Some(spilled) = &mut self.spilled
+            && spilled.read_row(&mut self.row_buffer)?
+        {
+            return Ok(Some(&self.row_buffer));
+        }
+        Ok(None)
+    }
+
+    fn rewind(&mut self) -> anyhow::Result<()> {
+        // The rows not read yet are kept before replaying them all.
+        while !self.filled {
+            match self.input.next_row()? {
+                Some(row) => {
+                    let row = row.to_vec();
+                    self.keep(&row)?;
+                }
+                None => self.filled = true,
+            }
+        }
+        if let Some(spill) = self.spill.take() {
+            self.spilled = Some(spill.into_reader()?);
+        }
+        if let Some(spilled) = &mut self.spilled {
+            spilled.rewind()?;
+        }
+        self.position = 0;
+        Ok(())
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Operator> {
+        vec![&mut self.input]
+    }
+
+    fn plan(&self) -> PlanNode {
+        let input = self.input.plan();
+        PlanNode::new(self.name().to_string(), input.columns.clone(), vec![input])
+    }
+}
+
+/// Skips the first `offset` rows of its input, then passes on at most `limit` rows
+/// without reading further.
+#[derive(Debug)]
+pub struct Limit {
+    input: Box<Operator>,
+    limit: Option<usize>,
+    offset: usize,
+    skipped: usize,
+    returned: usize,
+}
+
+impl Limit {
+    pub fn new(input: Operator, limit: Option<usize>, offset: usize) -> Self {
+        Self {
+            input: Box::new(input),
+            limit,
+            offset,
+            skipped: 0,
+            returned: 0,
+        }
+    }
+}
+
+impl RowOperator for Limit {
+    fn name(&self) -> &'static str {
+        "Limit"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        while self.skipped < self.offset {
+            if self.input.next_row()?.is_none() {
+                return Ok(None);
+            }
+            self.skipped += 1;
+        }
+
+        if self.limit == Some(self.returned) {
+            return Ok(None);
+        }
+        self.returned += 1;
+        self.input.next_row()
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Operator> {
+        vec![&mut self.input]
+    }
+
+    fn plan(&self) -> PlanNode {
+        let name = self.name();
+        let input = self.input.plan();
+        let limit = self.limit.map_or(-1, |limit| limit as i64);
+        let mut description = format!("{name} {limit}");
+        if self.offset > 0 {
+            description.push_str(&format!(" OFFSET {}", self.offset));
+        }
+        PlanNode::new(description, input.columns.clone(), vec![input])
+    }
+}
+
+/// Counts the rows produced by its input, e.g. the rows read by a scan.
+#[derive(Debug)]
+pub struct CountRows {
+    input: Box<Operator>,
+    count: Rc<Cell<usize>>,
+    /// Rows read but rejected by the filters of the scan, which it counts on its own
+    /// thread.
+    rejected: Option<Arc<AtomicUsize>>,
+}
+
+impl CountRows {
+    pub fn new(input: Operator, count: Rc<Cell<usize>>) -> Self {
+        Self {
+            input: Box::new(input),
+            count,
+            rejected: None,
+        }
+    }
+
+    pub fn with_rejected(mut self, rejected: Arc<AtomicUsize>) -> Self {
+        self.rejected = Some(rejected);
+        self
+    }
+}
+
+impl RowOperator for CountRows {
+    fn name(&self) -> &'static str {
+        "CountRows"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        let row = self.input.next_row()?;
+        let mut count = self.count.get() + row.is_some() as usize;
+        if let Some(rejected) = &self.rejected {
+            count += rejected.swap(0, atomic::Ordering::Relaxed);
+        }
+        self.count.set(count);
+        Ok(row)
+    }
+
+    fn rewind(&mut self) -> anyhow::Result<()> {
+        self.input.rewind()
+    }
+
+    // Counting the rows of a scan is bookkeeping rather than part of the plan.
+    fn inputs_mut(&mut self) -> Vec<&mut Operator> {
+        vec![&mut self.input]
+    }
+
+    fn plan(&self) -> PlanNode {
+        self.input.plan()
+    }
+}
+
+/// Counts the rows produced by its input, the times it was read from the start and
+/// the time spent producing the rows, which includes the time spent in the operators
+/// it reads from. Plans show the counts next to the input.
+#[derive(Debug)]
+pub struct Instrumented {
+    input: Box<Operator>,
+    rows: usize,
+    loops: usize,
+    /// Whether the input was read since it was last restarted.
+    reading: bool,
+    elapsed: Duration,
+}
+
+impl Instrumented {
+    pub fn new(input: Operator) -> Self {
+        Self {
+            input: Box::new(input),
+            rows: 0,
+            loops: 0,
+            reading: false,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    fn start_reading(&mut self) {
+        if !self.reading {
+            self.reading = true;
+            self.loops += 1;
+        }
+    }
+}
+
+impl RowOperator for Instrumented {
+    fn name(&self) -> &'static str {
+        "Instrumented"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        self.start_reading();
+        let started = Instant::now();
+        let row = self.input.next_row()?;
+        self.elapsed += started.elapsed();
+        self.rows += row.is_some() as usize;
+        Ok(row)
+    }
+
+    fn next_batch(&mut self) -> anyhow::Result<Option<Batch>> {
+        self.start_reading();
+        let started = Instant::now();
+        let batch = self.input.next_batch()?;
+        self.elapsed += started.elapsed();
+        self.rows += batch.as_ref().map_or(0, Batch::len);
+        Ok(batch)
+    }
+
+    fn rewind(&mut self) -> anyhow::Result<()> {
+        // Operators like `Materialize` may read their input when rewound.
+        let started = Instant::now();
+        self.reading = false;
+        self.input.rewind()?;
+        self.elapsed += started.elapsed();
+        Ok(())
+    }
+
+    fn plan(&self) -> PlanNode {
+        let mut plan = self.input.plan();
+        let time = self.elapsed.as_secs_f64() * 1e3;
+        plan.description += &match self.loops {
+            0 | 1 => format!(" (rows={} time={time:.3}ms)", self.rows),
+            loops => format!(" (rows={} loops={loops} time={time:.3}ms)", self.rows),
+        };
+        plan
+    }
+}
+
+/// Rows sent at once by an exchange, and batches buffered in its channel.
+const EXCHANGE_BATCH_ROWS: usize = 256;
+const EXCHANGE_CHANNEL_BATCHES: usize = 4;
+
+/// Runs stages of the plan on other threads, receiving their rows in batches through
+/// bounded channels so that the stages run concurrently with the operators above them.
+/// Operators aren't `Send`, so each stage is built on its thread.
+#[derive(Debug)]
+pub struct Exchange {
+    /// The plan of the stages, which can't be inspected once on their threads.
+    stage: PlanNode,
+    /// The rows of a stage after the other's, or as they arrive if there's a single
+    /// channel for all of them.
+    receivers: VecDeque<Receiver<anyhow::Result<ExchangeBatch>>>,
+    workers: Vec<JoinHandle<()>>,
+    ordered: bool,
+    rows: std::vec::IntoIter<Vec<SendValue>>,
+    row_buffer: Vec<OwnedValue>,
+}
+
+#[derive(Debug)]
+struct ExchangeBatch {
+    rows: Vec<Vec<SendValue>>,
+    /// Pages accessed by the stage since the previous batch, credited to the query.
+    io: IoStats,
+}
+
+impl Exchange {
+    /// Runs each of `stages`, e.g. the scans of the parts of a table, on a thread of
+    /// its own. Their rows are passed on a stage after the other when `ordered`, and
+    /// as they arrive otherwise.
+    pub fn partitioned<S>(plan: PlanNode, stages: Vec<S>, ordered: bool) -> Self
+    where
+        S: FnOnce() -> Operator + Send + 'static,
+    {
+        let mut receivers = VecDeque::new();
+        let mut shared = None;
+        let mut workers = Vec::new();
+        for stage in stages {
+            let sender = match &shared {
+                Some(sender) => SyncSender::clone(sender),
+                None => {
+                    let (sender, receiver) = mpsc::sync_channel(EXCHANGE_CHANNEL_BATCHES);
+                    receivers.push_back(receiver);
+                    if !ordered {
+                        shared = Some(sender.clone());
+                    }
+                    sender
+                }
+            };
+            workers.push(thread::spawn(move || run_stage(stage, sender)));
+        }
+
+        Self {
+            stage: plan,
+            receivers,
+            workers,
+            ordered,
+            rows: Vec::new().into_iter(),
+            row_buffer: Vec::new(),
+        }
+    }
+}
+
+/// Sends the rows of `stage` in batches until it's done, fails, or the exchange stops
+/// receiving them.
+fn run_stage(stage: impl FnOnce() -> Operator, sender: SyncSender<anyhow::Result<ExchangeBatch>>) {
+    let mut input = stage();
+    let mut io = IoStats::snapshot();
+
+    loop {
+        let mut rows = Vec::with_capacity(EXCHANGE_BATCH_ROWS);
+        let result = loop {
+            match input.next_row() {
+                Ok(Some(row)) => {
+                    rows.push(row.iter().map(SendValue::from).collect());
+                    if rows.len() == EXCHANGE_BATCH_ROWS {
+                        break Ok(true);
+                    }
+                }
+                Ok(None) => break Ok(false),
+                Err(e) => break Err(e),
+            }
+        };
+
+        let now = IoStats::snapshot();
+        let batch = ExchangeBatch {
+            rows,
+            io: now.since(io),
+        };
+        io = now;
+
+        let more = matches!(result, Ok(true));
+        let message = result.map(|_| batch);
+        if sender.send(message).is_err() || !more {
+            return;
+        }
+    }
+}
+
+impl RowOperator for Exchange {
+    fn name(&self) -> &'static str {
+        "Exchange"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        loop {
+            if let Some(row) = self.rows.next() {
+                self.row_buffer.clear();
+                self.row_buffer
+                    .extend(row.into_iter().map(OwnedValue::from));
+                return Ok(Some(&self.row_buffer));
+            }
+
+            let Some(receiver) = self.receivers.front() else {
+                for worker in self.workers.drain(..) {
+                    worker
+                        .join()
+                        .map_err(|_| anyhow::anyhow!("exchange worker panicked"))?;
+                }
+                return Ok(None);
+            };
+
+            match receiver.recv() {
+                Ok(batch) => {
+                    let batch = batch?;
+                    batch.io.record();
+                    self.rows = batch.rows.into_iter();
+                }
+                Err(_) => {
+                    self.receivers.pop_front();
+                }
+            }
+        }
+    }
+
+    fn plan(&self) -> PlanNode {
+        let name = self.name();
+        let description = match (self.workers.len(), self.ordered) {
+            (0 | 1, _) => name.to_string(),
+            (workers, true) => format!("{name} ({workers} partitions)"),
+            (workers, false) => format!("{name} ({workers} partitions, unordered)"),
+        };
+        PlanNode::new(
+            description,
+            self.stage.columns.clone(),
+            vec![self.stage.clone()],
+        )
+    }
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+        // Closing the channels first stops the workers waiting to send a batch.
+        self.receivers.clear();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Drops the rows that were already produced. Seen rows are kept in memory, with their
+/// strings interned, until they exceed the memory budget. The rest of the input is then
+/// hash-partitioned to disk, each partition is deduplicated on its own (partitioning it
+/// again if needed), and the surviving rows are merged back in input order.
+#[derive(Debug)]
+pub struct Distinct {
+    input: Box<Operator>,
+    interner: Interner,
+    seen: HashSet<Vec<DistinctKey>>,
+    memory: MemoryReservation,
+    spilled: Option<MergedRuns>,
+    row_buffer: Vec<OwnedValue>,
+}
+
+const SPILL_PARTITIONS: usize = 16;
+
+impl Distinct {
+    pub fn new(input: Operator, memory: MemoryReservation) -> Self {
+        Self {
+            input: Box::new(input),
+            interner: Interner::default(),
+            seen: HashSet::new(),
+            memory,
+            spilled: None,
+            row_buffer: Vec::new(),
+        }
+    }
+
+    /// Partitions the unseen rows left in the input, starting with `first`, and
+    /// deduplicates the partitions.
+    fn spill(&mut self, first: Vec<DistinctKey>) -> anyhow::Result<MergedRuns> {
+        let mut partitions = Partitions::new(0);
+        let mut position = 0;
+        let mut write = |key: Vec<DistinctKey>, partitions: &mut Partitions| {
+            let mut row: Vec<OwnedValue> = key.into_iter().map(|k| k.0).collect();
+            row.push(OwnedValue::Int(position));
+            position += 1;
+            partitions.write(&row, row.len() - 1)
+        };
+
+        write(first, &mut partitions)?;
+        while let Some(row) = self.input.next_row()? {
+            let key = distinct_key(&mut self.interner, row);
+            if !self.seen.contains(&key) {
+                write(key, &mut partitions)?;
+            }
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(rows = position, "spilled distinct input");
+
+        self.seen = HashSet::new();
+        self.memory.free();
+
+        let mut pending = partitions.into_readers()?;
+        let mut runs = Vec::new();
+        while let Some((reader, level)) = pending.pop() {
+            runs.push(self.deduplicate_partition(reader, level, &mut pending)?);
+        }
+
+        Ok(MergedRuns {
+            runs,
+            orders: vec![KeyOrder::default()],
+        })
+    }
+
+    /// Writes the first occurrence of each row of the partition to a new run. Rows that
+    /// don't fit in memory are partitioned again and pushed to `pending`.
+    fn deduplicate_partition(
+        &mut self,
+        mut reader: SpillReader,
+        level: usize,
+        pending: &mut Vec<(SpillReader, usize)>,
+    ) -> anyhow::Result<SpillRun> {
+        let mut seen = HashSet::new();
+        let mut survivors = SpillWriter::create()?;
+        let mut overflow: Option<Partitions> = None;
+
+        let mut row = Vec::new();
+        while reader.read_row(&mut row)? {
+            let key = distinct_key(&mut self.interner, &row[..row.len() - 1]);
+            if seen.contains(&key) {
+                continue;
+            }
+
+            match &mut overflow {
+                None if self.memory.try_grow(spill::row_size(&row)) || seen.is_empty() => {
+                    survivors.write_row(&row)?;
+                    seen.insert(key);
+                }
+                None => {
+                    let mut partitions = Partitions::new(level + 1);
+                    partitions.write(&row, row.len() - 1)?;
+                    overflow = Some(partitions);
+                }
+                Some(partitions) => partitions.write(&row, row.len() - 1)?,
+            }
+        }
+
+        self.memory.free();
+        if let Some(partitions) = overflow {
+            pending.extend(partitions.into_readers()?);
+        }
+        SpillRun::new(survivors.into_reader()?)
+    }
+}
+
+impl RowOperator for Distinct {
+    fn name(&self) -> &'static str {
+        "Distinct"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        self.row_buffer.clear();
+
+        if self.spilled.is_none() {
+            loop {
+                let Some(row) = self.input.next_row()? else {
+                    return Ok(None);
+                };
+
+                let key = distinct_key(&mut self.interner, row);
+                if self.seen.contains(&key) {
+                    continue;
+                }
+
+                let size = spill::row_size(row);
+                if !self.memory.try_grow(size) && !self.seen.is_empty() {
+                    self.spilled = Some(self.spill(key)?);
+                    break;
+                }
+
+                self.row_buffer.extend(key.iter().map(|k| k.0.clone()));
+                self.seen.insert(key);
+                return Ok(Some(&self.row_buffer));
+            }
+        }
+
+        let runs = self.spilled.as_mut().expect("distinct rows were spilled");
+        let Some(mut row) = runs.next()? else {
+            return Ok(None);
+        };
+        // Drop the input position used to restore the order.
+        row.pop();
+        self.row_buffer = row;
+        Ok(Some(&self.row_buffer))
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Operator> {
+        vec![&mut self.input]
+    }
+
+    fn plan(&self) -> PlanNode {
+        let name = self.name();
+        let input = self.input.plan();
+        PlanNode::new(name.to_string(), input.columns.clone(), vec![input])
+            .with_query_plan("USE TEMP B-TREE FOR DISTINCT".to_string())
+    }
+}
+
+/// An aggregate function and the expressions computing its arguments.
+#[derive(Debug)]
+pub struct AggregateExpr {
+    pub function: &'static AggregateFunction,
+    pub args: Vec<Expr>,
+}
+
+impl AggregateExpr {
+    fn to_sql(&self, columns: &[String]) -> String {
+        let args: Vec<String> = self.args.iter().map(|e| e.to_sql(columns)).collect();
+        if std::ptr::eq(self.function, &BARE_COLUMN) {
+            return args.join(", ");
+        }
+        match args.is_empty() {
+            true if self.function.name == "count" => "count(*)".to_string(),
+            _ => format!("{}({})", self.function.name, args.join(", ")),
+        }
+    }
+}
+
+/// Groups the rows of its input on the values of `group_by`, producing one row per
+/// group: its group values followed by the results of the aggregates, in the order the
+/// groups were first seen. Without group expressions, the whole input is one group,
+/// even when empty. Groups are kept in memory until they exceed the memory budget; the
+/// rows of new groups are then hash-partitioned to disk, and each partition is
+/// aggregated on its own once the groups in memory were produced.
+#[derive(Debug)]
+pub struct HashAggregate {
+    input: Box<Operator>,
+    group_by: Vec<Expr>,
+    aggregates: Vec<AggregateExpr>,
+    interner: Interner,
+    memory: MemoryReservation,
+    output: Option<std::vec::IntoIter<Vec<OwnedValue>>>,
+    pending: Vec<(SpillReader, usize)>,
+    row_buffer: Vec<OwnedValue>,
+}
+
+impl HashAggregate {
+    pub fn new(
+        input: Operator,
+        group_by: Vec<Expr>,
+        aggregates: Vec<AggregateExpr>,
+        memory: MemoryReservation,
+    ) -> Self {
+        Self {
+            input: Box::new(input),
+            group_by,
+            aggregates,
+            interner: Interner::default(),
+            memory,
+            output: None,
+            pending: Vec::new(),
+            row_buffer: Vec::new(),
+        }
+    }
+
+    /// Aggregates the rows of the input, or of a spilled partition, returning the
+    /// groups that fit in memory. The rows of the others are partitioned again.
+    fn aggregate(
+        &mut self,
+        mut spilled: Option<SpillReader>,
+        level: usize,
+    ) -> anyhow::Result<Vec<Vec<OwnedValue>>> {
+        // The groups produced before were handed over to the output.
+        self.memory.free();
+
+        let key_len = self.group_by.len();
+        let mut index = HashMap::new();
+        let mut groups: Vec<(Vec<OwnedValue>, Vec<Accumulator>)> = Vec::new();
+        let mut overflow: Option<Partitions> = None;
+
+        let mut row = Vec::new();
+        while self.next_evaluated_row(&mut spilled, &mut row)? {
+            let key = distinct_key(&mut self.interner, &row[..key_len]);
+            let group = match index.get(&key) {
+                Some(&group) => group,
+                None => {
+                    let size = spill::row_size(&row[..key_len])
+                        + mem::size_of::<Accumulator>() * self.aggregates.len();
+                    if !self.memory.try_grow(size) {
+                        if !groups.is_empty() {
+                            overflow
+                                .get_or_insert_with(|| Partitions::new(level + 1))
+                                .write(&row, key_len)?;
+                            continue;
+                        }
+                        self.memory.grow(size)?;
+                    }
+
+                    let values = key.iter().map(|k| k.0.clone()).collect();
+                    let accumulators = self.aggregates.iter().map(|a| (a.function.init)());
+                    groups.push((values, accumulators.collect()));
+                    index.insert(key, groups.len() - 1);
+                    groups.len() - 1
+                }
+            };
+
+            let mut args = &row[key_len..];
+            for (accumulator, aggregate) in groups[group].1.iter_mut().zip(&self.aggregates) {
+                let (values, rest) = args.split_at(aggregate.args.len());
+                accumulator.step(values);
+                args = rest;
+            }
+        }
+
+        if key_len == 0 && groups.is_empty() {
+            let accumulators = self.aggregates.iter().map(|a| (a.function.init)());
+            groups.push((Vec::new(), accumulators.collect()));
+        }
+        if let Some(partitions) = overflow {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(level, "spilled aggregate input");
+            self.pending.extend(partitions.into_readers()?);
+        }
+
+        groups
+            .into_iter()
+            .map(|(mut values, accumulators)| {
+                for accumulator in &accumulators {
+                    values.push(accumulator.finish()?);
+                }
+                Ok(values)
+            })
+            .collect()
+    }
+
+    /// Reads the next row of the partition, or evaluates the group values and
+    /// aggregate arguments of the next input row, into `row`.
+    fn next_evaluated_row(
+        &mut self,
+        spilled: &mut Option<SpillReader>,
+        row: &mut Vec<OwnedValue>,
+    ) -> anyhow::Result<bool> {
+        if let Some(reader) = spilled {
+            return reader.read_row(row);
+        }
+
+        row.clear();
+        let Some(input) = self.input.next_row()? else {
+            return Ok(false);
+        };
+        for expr in &self.group_by {
+            row.push(expr.eval(input)?);
+        }
+        for aggregate in &self.aggregates {
+            for arg in &aggregate.args {
+                row.push(arg.eval(input)?);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl RowOperator for HashAggregate {
+    fn name(&self) -> &'static str {
+        "HashAggregate"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        loop {
+            if let Some(row) = self.output.as_mut().and_then(Iterator::next) {
+                self.row_buffer = row;
+                return Ok(Some(&self.row_buffer));
+            }
+
+            let groups = if self.output.is_none() {
+                self.aggregate(None, 0)?
+            } else if let Some((reader, level)) = self.pending.pop() {
+                self.aggregate(Some(reader), level)?
+            } else {
+                return Ok(None);
+            };
+            self.output = Some(groups.into_iter());
+        }
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Operator> {
+        vec![&mut self.input]
+    }
+
+    fn plan(&self) -> PlanNode {
+        let name = self.name();
+        let input = self.input.plan();
+        let group_by: Vec<String> = self
+            .group_by
+            .iter()
+            .map(|e| e.to_sql(&input.columns))
+            .collect();
+        let aggregates: Vec<String> = self
+            .aggregates
+            .iter()
+            .map(|aggregate| aggregate.to_sql(&input.columns))
+            .collect();
+
+        let mut description = format!("{name} {}", aggregates.join(", "));
+        if !group_by.is_empty() {
+            description.push_str(&format!(" GROUP BY {}", group_by.join(", ")));
+        }
+        let grouped = !group_by.is_empty();
+        let columns = [group_by, aggregates].concat();
+        let node = PlanNode::new(description.trim_end().to_string(), columns, vec![input]);
+        match grouped {
+            true => node.with_query_plan("USE TEMP B-TREE FOR GROUP BY".to_string()),
+            false => node,
+        }
+    }
+}
+
+fn distinct_key(interner: &mut Interner, row: &[OwnedValue]) -> Vec<DistinctKey> {
+    row.iter()
+        .map(|value| DistinctKey(interner.intern(value)))
+        .collect()
+}
+
+/// Spill files receiving rows according to the hash of their first `key_len` values.
+/// Each partitioning level uses a different hash.
+#[derive(Debug)]
+struct Partitions {
+    level: usize,
+    writers: Vec<Option<SpillWriter>>,
+}
+
+impl Partitions {
+    fn new(level: usize) -> Self {
+        Self {
+            level,
+            writers: (0..SPILL_PARTITIONS).map(|_| None).collect(),
+        }
+    }
+
+    fn write(&mut self, row: &[OwnedValue], key_len: usize) -> anyhow::Result<()> {
+        let mut hasher = DefaultHasher::new();
+        self.level.hash(&mut hasher);
+        for value in &row[..key_len] {
+            DistinctKey(value.clone()).hash(&mut hasher);
+        }
+        let partition = (hasher.finish() % SPILL_PARTITIONS as u64) as usize;
+
+        let writer = match &mut self.writers[partition] {
+            Some(writer) => writer,
+            slot => slot.insert(SpillWriter::create()?),
+        };
+        writer.write_row(row)
+    }
+
+    fn into_readers(self) -> anyhow::Result<Vec<(SpillReader, usize)>> {
+        self.writers
+            .into_iter()
+            .flatten()
+            .map(|writer| Ok((writer.into_reader()?, self.level)))
+            .collect()
+    }
+}
+
+/// Row value compared the way DISTINCT does: integral floats are equal to the
+/// corresponding integers.
+#[derive(Debug)]
+struct DistinctKey(OwnedValue);
+
+impl DistinctKey {
+    fn normalized(&self) -> Option<i64> {
+        match self.0 {
+            OwnedValue::Int(i) => Some(i),
+            OwnedValue::Float(f) if f.fract() == 0.0 && f.abs() < i64::MAX as f64 => Some(f as i64),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for DistinctKey {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.normalized(), other.normalized()) {
+            (Some(a), Some(b)) => a == b,
+            (None, None) => match (&self.0, &other.0) {
+                (OwnedValue::Float(a), OwnedValue::Float(b)) => a.to_bits() == b.to_bits(),
+                (a, b) => a == b,
+            },
+            _ => false,
+        }
+    }
+}
+
+impl Eq for DistinctKey {}
+
+impl Hash for DistinctKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if let Some(i) = self.normalized() {
+            return (0, i).hash(state);
+        }
+
+        match &self.0 {
+            OwnedValue::Null => 1.hash(state),
+            OwnedValue::Float(f) => (2, f.to_bits()).hash(state),
+            OwnedValue::String(s) => (3, s).hash(state),
+            OwnedValue::Blob(b) => (4, b).hash(state),
+            OwnedValue::Int(_) => unreachable!("integers are normalized"),
+        }
+    }
+}
+
+/// A window function and the expressions computing its arguments.
+#[derive(Debug)]
+pub struct WindowExpr {
+    pub function: WindowFunction,
+    pub args: Vec<Expr>,
+}
+
+/// Appends the values of window functions to the rows of its input, which is sorted on
+/// `partition_by` and then `order_by`. The rows are buffered one group of peers at a
+/// time: the rows of a partition with equal `order_by` values, which share their rank
+/// and the values of the aggregates, computed over the partition up to the last peer.
+#[derive(Debug)]
+pub struct Window {
+    input: Box<Operator>,
+    partition_by: Vec<Expr>,
+    order_by: Vec<Expr>,
+    functions: Vec<WindowExpr>,
+    memory: MemoryReservation,
+    /// The partition and order values of the current group of peers.
+    keys: Option<Vec<OwnedValue>>,
+    /// The first row of the next group of peers, with its keys.
+    next: Option<(Vec<OwnedValue>, Vec<OwnedValue>)>,
+    peers: std::vec::IntoIter<Vec<OwnedValue>>,
+    row_number: i64,
+    dense_rank: i64,
+    accumulators: Vec<Option<Accumulator>>,
+    row_buffer: Vec<OwnedValue>,
+}
+
+impl Window {
+    pub fn new(
+        input: Operator,
+        partition_by: Vec<Expr>,
+        order_by: Vec<Expr>,
+        functions: Vec<WindowExpr>,
+        memory: MemoryReservation,
+    ) -> Self {
+        Self {
+            input: Box::new(input),
+            partition_by,
+            order_by,
+            functions,
+            memory,
+            keys: None,
+            next: None,
+            peers: Vec::new().into_iter(),
+            row_number: 0,
+            dense_rank: 0,
+            accumulators: Vec::new(),
+            row_buffer: Vec::new(),
+        }
+    }
+
+    /// Reads the next group of peers, computing the window values of its rows.
+    fn next_peers(&mut self) -> anyhow::Result<bool> {
+        self.memory.free();
+
+        let first = match self.next.take() {
+            Some(first) => first,
+            None => match self.read_row()? {
+                Some(first) => first,
+                None => return Ok(false),
+            },
+        };
+        let (row, keys) = first;
+
+        let partition_len = self.partition_by.len();
+        let same_partition = self
+            .keys
+            .as_ref()
+            .is_some_and(|k| same_values(&k[..partition_len], &keys[..partition_len]));
+        if !same_partition {
+            self.row_number = 0;
+            self.dense_rank = 0;
+            self.accumulators = self
+                .functions
+                .iter()
+                .map(|f| match f.function {
+                    WindowFunction::Aggregate(function) => Some((function.init)()),
+                    _ => None,
+                })
+                .collect();
+        }
+
+        let mut peers = vec![row];
+        loop {
+            match self.read_row()? {
+                Some((row, row_keys)) if same_values(&row_keys, &keys) => peers.push(row),
+                next => {
+                    self.next = next;
+                    break;
+                }
+            }
+        }
+        self.keys = Some(keys);
+
+        let mut args = Vec::new();
+        for row in &peers {
+            for (function, accumulator) in self.functions.iter().zip(&mut self.accumulators) {
+                if let Some(accumulator) = accumulator {
+                    args.clear();
+                    for arg in &function.args {
+                        args.push(arg.eval(row)?);
+                    }
+                    accumulator.step(&args);
+                }
+            }
+        }
+        let aggregates = self
+            .accumulators
+            .iter()
+            .map(|a| a.as_ref().map(Accumulator::finish).transpose())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let rank = self.row_number + 1;
+        self.dense_rank += 1;
+        for row in &mut peers {
+            self.row_number += 1;
+            for (function, aggregate) in self.functions.iter().zip(&aggregates) {
+                row.push(match function.function {
+                    WindowFunction::RowNumber => OwnedValue::Int(self.row_number),
+                    WindowFunction::Rank => OwnedValue::Int(rank),
+                    WindowFunction::DenseRank => OwnedValue::Int(self.dense_rank),
+                    WindowFunction::Aggregate(_) => aggregate.clone().unwrap_or(OwnedValue::Null),
+                });
+            }
+        }
+        self.peers = peers.into_iter();
+        Ok(true)
+    }
+
+    /// Reads a row of the input, with its partition and order values.
+    fn read_row(&mut self) -> anyhow::Result<Option<(Vec<OwnedValue>, Vec<OwnedValue>)>> {
+        let Some(row) = self.input.next_row()? else {
+            return Ok(None);
+        };
+        let keys = self
+            .partition_by
+            .iter()
+            .chain(&self.order_by)
+            .map(|expr| expr.eval(row))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let row = row.to_vec();
+        self.memory.grow(spill::row_size(&row))?;
+        Ok(Some((row, keys)))
+    }
+}
+
+impl RowOperator for Window {
+    fn name(&self) -> &'static str {
+        "Window"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        loop {
+            if let Some(row) = self.peers.next() {
+                self.row_buffer = row;
+                return Ok(Some(&self.row_buffer));
+            }
+            if !self.next_peers()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Operator> {
+        vec![&mut self.input]
+    }
+
+    fn plan(&self) -> PlanNode {
+        let name = self.name();
+        let input = self.input.plan();
+        let functions: Vec<String> = self
+            .functions
+            .iter()
+            .map(|f| {
+                let args: Vec<String> = f.args.iter().map(|e| e.to_sql(&input.columns)).collect();
+                format!("{}({})", f.function.name(), args.join(", "))
+            })
+            .collect();
+
+        let mut description = format!("{name} {}", functions.join(", "));
+        let list = |exprs: &[Expr]| {
+            exprs
+                .iter()
+                .map(|e| e.to_sql(&input.columns))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        if !self.partition_by.is_empty() {
+            description.push_str(&format!(" PARTITION BY {}", list(&self.partition_by)));
+        }
+        if !self.order_by.is_empty() {
+            description.push_str(&format!(" ORDER BY {}", list(&self.order_by)));
+        }
+        let columns = [input.columns.clone(), functions].concat();
+        PlanNode::new(description, columns, vec![input])
+    }
+}
+
+fn same_values(a: &[OwnedValue], b: &[OwnedValue]) -> bool {
+    a.iter()
+        .zip(b)
+        .all(|(a, b)| a.sql_cmp(b) == Ordering::Equal)
+}
+
+/// Sorts its input on the `keys` expressions. Rows are buffered until they exceed the
+/// memory budget, at which point they are sorted and spilled to a temporary file as a
+/// run; the runs are then merged.
+#[derive(Debug)]
+pub struct Sort {
+    input: Box<Operator>,
+    state: SortState,
+    keys: Vec<SortKey>,
+    memory: MemoryReservation,
+    row_buffer: Vec<OwnedValue>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SortKey {
+    pub expr: Expr,
+    pub order: KeyOrder,
+}
+
+/// How the values of a sort key are ordered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeyOrder {
+    pub descending: bool,
+    pub collation: Collation,
+}
+
+#[derive(Debug)]
+enum SortState {
+    /// The input wasn't consumed yet.
+    Pending,
+    InMemory(std::vec::IntoIter<Vec<OwnedValue>>),
+    Merging(MergedRuns),
+}
+
+impl Sort {
+    pub fn new(input: Operator, keys: Vec<SortKey>, memory: MemoryReservation) -> Self {
+        Self {
+            input: Box::new(input),
+            state: SortState::Pending,
+            keys,
+            memory,
+            row_buffer: Vec::new(),
+        }
+    }
+}
+
+impl RowOperator for Sort {
+    fn name(&self) -> &'static str {
+        "Sort"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        if let SortState::Pending = self.state {
+            self.state = consume_sort_input(&mut self.input, &self.keys, &mut self.memory)?;
+        }
+
+        let row = match &mut self.state {
+            SortState::Pending => unreachable!("input was consumed"),
+            SortState::InMemory(rows) => rows.next(),
+            SortState::Merging(runs) => runs.next()?,
+        };
+
+        let Some(mut row) = row else {
+            return Ok(None);
+        };
+        row.truncate(row.len() - self.keys.len());
+        self.row_buffer = row;
+        Ok(Some(&self.row_buffer))
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Operator> {
+        vec![&mut self.input]
+    }
+
+    fn plan(&self) -> PlanNode {
+        let name = self.name();
+        let input = self.input.plan();
+        let columns = input.columns.clone();
+        let keys = sort_keys_sql(&self.keys, &columns);
+        PlanNode::new(format!("{name} {keys}"), columns, vec![input])
+            .with_query_plan("USE TEMP B-TREE FOR ORDER BY".to_string())
+    }
+}
+
+/// Keeps the first `limit` rows in the order of the keys, in a bounded heap, instead
+/// of buffering all the rows of its input like `Sort`.
+#[derive(Debug)]
+pub struct TopN {
+    input: Box<Operator>,
+    state: SortState,
+    keys: Vec<SortKey>,
+    limit: usize,
+    row_buffer: Vec<OwnedValue>,
+}
+
+impl TopN {
+    pub fn new(input: Operator, keys: Vec<SortKey>, limit: usize) -> Self {
+        Self {
+            input: Box::new(input),
+            state: SortState::Pending,
+            keys,
+            limit,
+            row_buffer: Vec::new(),
+        }
+    }
+}
+
+impl RowOperator for TopN {
+    fn name(&self) -> &'static str {
+        "TopN"
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<&[OwnedValue]>> {
+        if let SortState::Pending = self.state {
+            self.state = consume_top_n_input(&mut self.input, &self.keys, self.limit)?;
+        }
+
+        let SortState::InMemory(rows) = &mut self.state else {
+            unreachable!("input was consumed");
+        };
+        let Some(mut row) = rows.next() else {
+            return Ok(None);
+        };
+        row.truncate(row.len() - self.keys.len());
+        self.row_buffer = row;
+        Ok(Some(&self.row_buffer))
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Operator> {
+        vec![&mut self.input]
+    }
+
+    fn plan(&self) -> PlanNode {
+        let name = self.name();
+        let input = self.input.plan();
+        let columns = input.columns.clone();
+        let keys = sort_keys_sql(&self.keys, &columns);
+        PlanNode::new(
+            format!("{name} {} {keys}", self.limit),
+            columns,
+            vec![input],
+        )
+        .with_query_plan("USE TEMP B-TREE FOR ORDER BY".to_string())
+    }
+}
+
+/// A row kept by a `TopN`, followed by its sort keys. Rows with equal keys are ranked
+/// by their position in the input, so that they keep their order like with `Sort`.
+#[derive(Debug)]
+struct RankedRow {
+    row: Vec<OwnedValue>,
+    position: usize,
+    orders: Rc<[KeyOrder]>,
+}
+
+impl Ord for RankedRow {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_keys(&self.orders, &self.row, &other.row).then(self.position.cmp(&other.position))
+    }
+}
+
+impl PartialOrd for RankedRow {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RankedRow {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RankedRow {}
+
+fn consume_top_n_input(
+    input: &mut Operator,
+    keys: &[SortKey],
+    limit: usize,
+) -> anyhow::Result<SortState> {
+    let orders: Rc<[KeyOrder]> = keys.iter().map(|k| k.order).collect();
+    // A max-heap, whose top is the last of the rows kept so far.
+    let mut heap: BinaryHeap<RankedRow> = BinaryHeap::with_capacity(limit.min(1024));
+    let mut position = 0;
+
+    while limit > 0
+        && let Some(row) = input.next_row()?
+    {
+        let values = keys
+            .iter()
+            .map(|key| key.expr.eval(row))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        position += 1;
+
+        // Later rows only displace the last row kept when their keys come first.
+        if heap.len() == limit
+            && let Some(last) = heap.peek()
+            && compare_keys(&orders, &values, &last.row) != Ordering::Less
+        {
+            continue;
+        }
+
+        let mut ranked = row.to_vec();
+        ranked.extend(values);
+        heap.push(RankedRow {
+            row: ranked,
+            position,
+            orders: orders.clone(),
+        });
+        if heap.len() > limit {
+            heap.pop();
+        }
+    }
+
+    let rows: Vec<_> = heap.into_sorted_vec().into_iter().map(|r| r.row).collect();
+    Ok(SortState::InMemory(rows.into_iter()))
+}
+
+/// Formats sort keys the way an ORDER BY clause lists them.
+fn sort_keys_sql(keys: &[SortKey], columns: &[String]) -> String {
+    keys.iter()
+        .map(|key| {
+            let mut sql = key.expr.to_sql(columns);
+            if key.order.collation != Collation::Binary {
+                sql = format!("{sql} COLLATE {}", key.order.collation.name());
+            }
+            if key.order.descending {
+                sql.push_str(" DESC");
+            }
+            sql
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn consume_sort_input(
+    input: &mut Operator,
+    keys: &[SortKey],
+    memory: &mut MemoryReservation,
+) -> anyhow::Result<SortState> {
+    let orders: Vec<KeyOrder> = keys.iter().map(|k| k.order).collect();
+    let mut rows = Vec::new();
+    let mut runs = Vec::new();
+
+    while let Some(row) = input.next_row()? {
+        let mut sorted_row = row.to_vec();
+        for key in keys {
+            sorted_row.push(key.expr.eval(row)?);
+        }
+
+        let size = spill::row_size(&sorted_row);
+        if !memory.try_grow(size) && !rows.is_empty() {
+            runs.push(spill_sorted_run(mem::take(&mut rows), &orders)?);
+            memory.free();
+            memory.grow(size)?;
+        }
+        rows.push(sorted_row);
+    }
+
+    if runs.is_empty() {
+        rows.sort_by(|a, b| compare_keys(&orders, a, b));
+        return Ok(SortState::InMemory(rows.into_iter()));
+    }
+
+    if !rows.is_empty() {
+        runs.push(spill_sorted_run(rows, &orders)?);
+    }
+    memory.free();
+    Ok(SortState::Merging(MergedRuns { runs, orders }))
+}
+
+fn spill_sorted_run(
+    mut rows: Vec<Vec<OwnedValue>>,
+    orders: &[KeyOrder],
+) -> anyhow::Result<SpillRun> {
+    rows.sort_by(|a, b| compare_keys(orders, a, b));
+
+    #[cfg(feature = "tracing")]
+    tracing::debug!(rows = rows.len(), "spilled sort run");
+
+    let mut writer = SpillWriter::create()?;
+    for row in &rows {
+        writer.write_row(row)?;
+    }
+    SpillRun::new(writer.into_reader()?)
+}
+
+/// A spilled run and its next row.
+#[derive(Debug)]
+struct SpillRun {
+    reader: SpillReader,
+    head: Option<Vec<OwnedValue>>,
+}
+
+impl SpillRun {
+    fn new(reader: SpillReader) -> anyhow::Result<Self> {
+        let mut run = Self { reader, head: None };
+        run.advance()?;
+        Ok(run)
+    }
+
+    fn advance(&mut self) -> anyhow::Result<()> {
+        let mut row = self.head.take().unwrap_or_default();
+        if self.reader.read_row(&mut row)? {
+            self.head = Some(row);
+        }
+        Ok(())
+    }
+}
+
+/// Runs sorted on the keys stored at the end of their rows, merged into a single
+/// sorted stream.
+#[derive(Debug)]
+struct MergedRuns {
+    runs: Vec<SpillRun>,
+    orders: Vec<KeyOrder>,
+}
+
+impl MergedRuns {
+    fn next(&mut self) -> anyhow::Result<Option<Vec<OwnedValue>>> {
+        let mut min: Option<usize> = None;
+        for (i, run) in self.runs.iter().enumerate() {
+            let Some(head) = &run.head else {
+                continue;
+            };
+            // Earlier runs win ties, keeping the sort stable.
+            let smaller = min.is_none_or(|m| {
+                let current = self.runs[m].head.as_deref().unwrap_or_default();
+                compare_keys(&self.orders, head, current) == Ordering::Less
+            });
+            if smaller {
+                min = Some(i);
+            }
+        }
+
+        let Some(i) = min else {
+            return Ok(None);
+        };
+        let row = self.runs[i].head.clone();
+        self.runs[i].advance()?;
+        Ok(row)
+    }
+}
+
+/// Compares rows on the sort key values stored at their end.
+fn compare_keys(orders: &[KeyOrder], a: &[OwnedValue], b: &[OwnedValue]) -> Ordering {
+    let a = &a[a.len() - orders.len()..];
+    let b = &b[b.len() - orders.len()..];
+
+    for ((order, a), b) in orders.iter().zip(a).zip(b) {
+        let ordering = order.collation.compare(a, b);
+        if ordering != Ordering::Equal {
+            return if order.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+        }
+    }
+    Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{
+        db::Db,
+        engine::{
+            CaseFolding,
+            expr::{BinaryExpr, BinaryOp, FunctionExpr},
+            function,
+            memory::MemoryTracker,
+        },
+        testing::multilevel_btrees,
+    };
+
+    fn distinct(rows: Vec<Vec<OwnedValue>>, memory_budget: usize) -> Vec<Vec<OwnedValue>> {
+        let input = Operator::TableFunctionScan(TableFunctionScan::new(rows));
+        let memory = MemoryTracker::new(memory_budget);
+        let mut distinct = Distinct::new(input, memory.reservation());
+
+        let mut output = Vec::new();
+        while let Some(row) = distinct.next_row().unwrap() {
+            output.push(row.to_vec());
+        }
+        output
+    }
+
+    #[test]
+    fn distinct_rows() {
+        let text = |s: &str| OwnedValue::String(Rc::new(s.to_string()));
+        let rows = vec![
+            vec![text("a"), OwnedValue::Int(1)],
+            vec![text("a"), OwnedValue::Float(1.0)],
+            vec![text("b"), OwnedValue::Int(1)],
+            vec![text("a"), OwnedValue::Float(1.5)],
+            vec![OwnedValue::Null, OwnedValue::Null],
+            vec![OwnedValue::Null, OwnedValue::Null],
+        ];
+
+        assert_eq!(
+            distinct(rows, usize::MAX),
+            vec![
+                vec![text("a"), OwnedValue::Int(1)],
+                vec![text("b"), OwnedValue::Int(1)],
+                vec![text("a"), OwnedValue::Float(1.5)],
+                vec![OwnedValue::Null, OwnedValue::Null],
+            ]
+        );
+    }
+
+    #[test]
+    fn spilled_distinct_rows() {
+        let pairs: Vec<(i64, i64)> = (0..2000).map(|i| (i * 7 % 300, i % 3)).collect();
+        let to_rows = |pairs: &[(i64, i64)]| -> Vec<Vec<OwnedValue>> {
+            pairs
+                .iter()
+                .map(|&(a, b)| vec![OwnedValue::Int(a), OwnedValue::Int(b)])
+                .collect()
+        };
+
+        let mut seen = HashSet::new();
+        let unique: Vec<_> = pairs.iter().copied().filter(|&p| seen.insert(p)).collect();
+        let (rows, expected) = (to_rows(&pairs), to_rows(&unique));
+
+        // A tiny budget partitions the input, and partitions again within partitions.
+        assert_eq!(distinct(rows, 500), expected);
+    }
+
+    fn aggregated(rows: Vec<Vec<OwnedValue>>, memory_budget: usize) -> Vec<Vec<OwnedValue>> {
+        let count = function::aggregate_function("count", 0).unwrap().unwrap();
+        let sum = function::aggregate_function("sum", 1).unwrap().unwrap();
+        let aggregates = vec![
+            AggregateExpr {
+                function: count,
+                args: vec![],
+            },
+            AggregateExpr {
+                function: sum,
+                args: vec![Expr::Column(1)],
+            },
+        ];
+        let input = Operator::TableFunctionScan(TableFunctionScan::new(rows));
+        let memory = MemoryTracker::new(memory_budget);
+        let mut aggregate = HashAggregate::new(
+            input,
+            vec![Expr::Column(0)],
+            aggregates,
+            memory.reservation(),
+        );
+
+        let mut output = Vec::new();
+        while let Some(row) = aggregate.next_row().unwrap() {
+            output.push(row.to_vec());
+        }
+        output.sort_by(|a, b| a[0].sql_cmp(&b[0]));
+        output
+    }
+
+    #[test]
+    fn spilled_aggregate() {
+        let rows: Vec<Vec<OwnedValue>> = (0..3000)
+            .map(|i| vec![OwnedValue::Int(i % 500), OwnedValue::Int(i)])
+            .collect();
+        let expected: Vec<Vec<OwnedValue>> = (0..500)
+            .map(|k| {
+                vec![
+                    OwnedValue::Int(k),
+                    OwnedValue::Int(6),
+                    OwnedValue::Int((0..6).map(|j| k + 500 * j).sum()),
+                ]
+            })
+            .collect();
+
+        assert_eq!(aggregated(rows.clone(), usize::MAX), expected);
+        // A tiny budget spills the rows of most groups, and partitions them again.
+        assert_eq!(aggregated(rows, 1000), expected);
+
+        let input = Operator::TableFunctionScan(TableFunctionScan::new(Vec::new()));
+        let count = function::aggregate_function("count", 0).unwrap().unwrap();
+        let aggregates = vec![AggregateExpr {
+            function: count,
+            args: vec![],
+        }];
+        let memory = MemoryTracker::new(usize::MAX);
+        let mut total = HashAggregate::new(input, vec![], aggregates, memory.reservation());
+        assert_eq!(total.next_row().unwrap(), Some(&[OwnedValue::Int(0)][..]));
+        assert_eq!(total.next_row().unwrap(), None);
+    }
+
+    fn joined(null_padding: Option<usize>) -> Vec<String> {
+        let left = (1..=3).map(|i| vec![OwnedValue::Int(i)]).collect();
+        let right = vec![
+            vec![OwnedValue::Int(2), OwnedValue::Int(20)],
+            vec![OwnedValue::Null, OwnedValue::Int(0)],
+            vec![OwnedValue::Int(3), OwnedValue::Int(30)],
+            vec![OwnedValue::Int(2), OwnedValue::Int(21)],
+        ];
+        let constraint = Expr::Binary(Box::new(BinaryExpr {
+            op: BinaryOp::Eq,
+            lhs: Expr::Column(0),
+            rhs: Expr::Column(1),
+            collation: Collation::Binary,
+        }));
+
+        let mut join = NestedLoopJoin::new(
+            Operator::TableFunctionScan(TableFunctionScan::new(left)),
+            Operator::TableFunctionScan(TableFunctionScan::new(right)),
+            Some(constraint),
+            null_padding,
+        );
+        let mut output = Vec::new();
+        while let Some(row) = join.next_row().unwrap() {
+            let values: Vec<String> = row.iter().map(|v| v.to_string()).collect();
+            output.push(values.join("|"));
+        }
+        output
+    }
+
+    #[test]
+    fn nested_loop_join() {
+        assert_eq!(joined(None), vec!["2|2|20", "2|2|21", "3|3|30"]);
+        assert_eq!(
+            joined(Some(2)),
+            vec!["1|null|null", "2|2|20", "2|2|21", "3|3|30"]
+        );
+    }
+
+    #[test]
+    fn filter_rows() {
+        let rows = vec![
+            vec![OwnedValue::Int(1), OwnedValue::Int(10)],
+            vec![OwnedValue::Int(2), OwnedValue::Null],
+            vec![OwnedValue::Int(3), OwnedValue::Int(30)],
+            vec![OwnedValue::Int(4), OwnedValue::Float(0.0)],
+        ];
+        let compare = |op, column, value| {
+            Expr::Binary(Box::new(BinaryExpr {
+                op,
+                lhs: Expr::Column(column),
+                rhs: Expr::Literal(OwnedValue::Int(value)),
+                collation: Collation::Binary,
+            }))
+        };
+        // a >= 2 AND b > 0, where the NULL comparison rejects the second row.
+        let predicate = Expr::Binary(Box::new(BinaryExpr {
+            op: BinaryOp::And,
+            lhs: compare(BinaryOp::GtEq, 0, 2),
+            rhs: compare(BinaryOp::Gt, 1, 0),
+            collation: Collation::Binary,
+        }));
+
+        let input = Operator::TableFunctionScan(TableFunctionScan::new(rows.clone()));
+        let mut filter = Filter::new(input, predicate);
+        let mut output = Vec::new();
+        while let Some(row) = filter.next_row().unwrap() {
+            output.push(row.to_vec());
+        }
+        assert_eq!(output, vec![rows[2].clone()]);
+    }
+
+    #[test]
+    fn batches() {
+        let rows: Vec<_> = (0..3000).map(|i| vec![OwnedValue::Int(i)]).collect();
+        let input = Operator::TableFunctionScan(TableFunctionScan::new(rows));
+        let function = |name, args: Vec<Expr>| {
+            let function = function::scalar_function(name, args.len(), CaseFolding::Ascii);
+            Expr::Function(FunctionExpr::new(function.unwrap(), args))
+        };
+        // Keeps the even rows, and pairs them with their remainder by 7.
+        let even = Expr::Binary(Box::new(BinaryExpr {
+            op: BinaryOp::Eq,
+            lhs: function(
+                "mod",
+                vec![Expr::Column(0), Expr::Literal(OwnedValue::Int(2))],
+            ),
+            rhs: Expr::Literal(OwnedValue::Int(0)),
+            collation: Collation::Binary,
+        }));
+        let filter = Operator::Filter(Filter::new(input, even));
+        let mut project = Project::new(
+            filter,
+            vec![
+                Expr::Column(0),
+                function(
+                    "mod",
+                    vec![Expr::Column(0), Expr::Literal(OwnedValue::Int(7))],
+                ),
+            ],
+        );
+
+        let row = project.next_row().unwrap().unwrap().to_vec();
+        assert_eq!(row, vec![OwnedValue::Int(0), OwnedValue::Float(0.0)]);
+        // The rest of the first batch, then batches of the input's size.
+        let mut sizes = Vec::new();
+        let mut last = Vec::new();
+        while let Some(batch) = project.next_batch().unwrap() {
+            sizes.push(batch.len());
+            batch.read_row(batch.len() - 1, &mut last);
+        }
+        assert_eq!(sizes, vec![511, 512, 476]);
+        assert_eq!(last, vec![OwnedValue::Int(2998), OwnedValue::Float(2.0)]);
+
+        project.rewind().unwrap();
+        assert_eq!(project.next_batch().unwrap().unwrap().len(), 512);
+    }
+
+    #[test]
+    fn limit_rows() {
+        let limited = |limit, offset| {
+            let rows = (0..10).map(|i| vec![OwnedValue::Int(i)]).collect();
+            let input = Operator::TableFunctionScan(TableFunctionScan::new(rows));
+            let mut limit = Limit::new(input, limit, offset);
+            let mut output = Vec::new();
+            while let Some(row) = limit.next_row().unwrap() {
+                output.push(row[0].clone());
+            }
+            output
+        };
+        let ints = |range: std::ops::Range<i64>| range.map(OwnedValue::Int).collect::<Vec<_>>();
+
+        assert_eq!(limited(Some(3), 0), ints(0..3));
+        assert_eq!(limited(Some(3), 8), ints(8..10));
+        assert_eq!(limited(None, 4), ints(4..10));
+        assert_eq!(limited(Some(0), 0), ints(0..0));
+        assert_eq!(limited(None, 20), ints(0..0));
+    }
+
+    #[test]
+    fn plan_tree() {
+        let rows = vec![vec![OwnedValue::Int(1), OwnedValue::Null]];
+        let input = Operator::TableFunctionScan(TableFunctionScan::new(rows));
+        let predicate = Expr::Binary(Box::new(BinaryExpr {
+            op: BinaryOp::Eq,
+            lhs: Expr::Column(1),
+            rhs: Expr::string("it's"),
+            collation: Collation::Binary,
+        }));
+        let filter = Operator::Filter(Filter::new(input, predicate));
+        let keys = vec![SortKey {
+            expr: Expr::Column(0),
+            order: KeyOrder {
+                descending: true,
+                collation: Collation::Binary,
+            },
+        }];
+        let memory = MemoryTracker::new(1024);
+        let sort = Operator::Sort(Sort::new(filter, keys, memory.reservation()));
+        let limit = Operator::Limit(Limit::new(sort, Some(10), 5));
+
+        let plan = limit.plan();
+        assert_eq!(plan.columns, vec!["#0", "#1"]);
+        assert_eq!(
+            plan.render(),
+            vec![
+                "Limit 10 OFFSET 5",
+                "  Sort #0 DESC",
+                "    Filter #1 = 'it''s'",
+                "      TableFunctionScan",
+            ]
+        );
+        assert_eq!(
+            plan.query_plan_steps(),
+            vec![
+                (1, 0, "SCAN 1 CONSTANT ROWS".to_string()),
+                (2, 0, "USE TEMP B-TREE FOR ORDER BY".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn exchange_rows() {
+        let rows = || {
+            (0..1000)
+                .map(|i| {
+                    vec![
+                        OwnedValue::Int(i),
+                        OwnedValue::String(Rc::new(i.to_string())),
+                    ]
+                })
+                .collect::<Vec<_>>()
+        };
+        let plan = || Operator::TableFunctionScan(TableFunctionScan::new(Vec::new())).plan();
+
+        let scan = |range: std::ops::Range<usize>| {
+            move || Operator::TableFunctionScan(TableFunctionScan::new(rows()[range].to_vec()))
+        };
+        let read = |mut exchange: Exchange| {
+            let mut output = Vec::new();
+            while let Some(row) = exchange.next_row().unwrap() {
+                output.push(row.to_vec());
+            }
+            output
+        };
+        assert_eq!(
+            read(Exchange::partitioned(plan(), vec![scan(0..1000)], true)),
+            rows()
+        );
+
+        let partitions = || {
+            vec![
+                scan(0..100),
+                scan(100..250),
+                scan(250..900),
+                scan(900..1000),
+            ]
+        };
+        let ordered = Exchange::partitioned(plan(), partitions(), true);
+        assert_eq!(ordered.plan().description, "Exchange (4 partitions)");
+        assert_eq!(read(ordered), rows());
+        let mut unordered = read(Exchange::partitioned(plan(), partitions(), false));
+        unordered.sort_by_key(|row| row[0].as_f64() as i64);
+        assert_eq!(unordered, rows());
+
+        let mut abandoned = Exchange::partitioned(plan(), partitions(), false);
+        assert!(abandoned.next_row().unwrap().is_some());
+        drop(abandoned);
+    }
+
+    #[test]
+    fn instrumented_plan() {
+        let rows = (0..10).map(|i| vec![OwnedValue::Int(i)]).collect();
+        let input = Operator::TableFunctionScan(TableFunctionScan::new(rows));
+        let keys = vec![SortKey {
+            expr: Expr::Column(0),
+            order: KeyOrder::default(),
+        }];
+        let sort = Operator::Sort(Sort::new(
+            input,
+            keys,
+            MemoryTracker::new(usize::MAX).reservation(),
+        ));
+        let mut limit = Operator::Limit(Limit::new(sort, Some(3), 2)).instrument();
+        while limit.next_row().unwrap().is_some() {}
+
+        let counts: Vec<String> = limit
+            .plan()
+            .render()
+            .iter()
+            .map(|line| line.split(" time=").next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                "Limit 3 OFFSET 2 (rows=3",
+                "  Sort #0 (rows=5",
+                "    TableFunctionScan (rows=10"
+            ]
+        );
+    }
+
+    #[test]
+    fn materialized_rows() {
+        let rows: Vec<Vec<OwnedValue>> = (0..100)
+            .map(|i| {
+                vec![
+                    OwnedValue::Int(i),
+                    OwnedValue::String(Rc::new(i.to_string())),
+                ]
+            })
+            .collect();
+        let read = |materialize: &mut Materialize, limit: usize| {
+            let mut output = Vec::new();
+            while output.len() < limit
+                && let Some(row) = materialize.next_row().unwrap()
+            {
+                output.push(row.to_vec());
+            }
+            output
+        };
+
+        // Spilled past the first rows, and rewound before the input was read to the end.
+        for budget in [usize::MAX, 1000] {
+            let input = Operator::TableFunctionScan(TableFunctionScan::new(rows.clone()));
+            let memory = MemoryTracker::new(budget);
+            let mut materialize = Materialize::new(input, memory.reservation());
+            assert_eq!(read(&mut materialize, 10), rows[..10]);
+            for _ in 0..2 {
+                materialize.rewind().unwrap();
+                assert_eq!(read(&mut materialize, usize::MAX), rows);
+            }
+            assert_eq!(materialize.spilled.is_some(), budget == 1000);
+        }
+    }
+
+    fn sorted(rows: Vec<Vec<OwnedValue>>, memory_budget: usize) -> Vec<Vec<OwnedValue>> {
+        let keys = vec![
+            SortKey {
+                expr: Expr::Column(1),
+                order: KeyOrder {
+                    descending: true,
+                    collation: Collation::Binary,
+                },
+            },
+            SortKey {
+                expr: Expr::Column(0),
+                order: KeyOrder {
+                    descending: false,
+                    collation: Collation::Binary,
+                },
+            },
+        ];
+        let input = Operator::TableFunctionScan(TableFunctionScan::new(rows));
+        let memory = MemoryTracker::new(memory_budget);
+        let mut sort = Sort::new(input, keys, memory.reservation());
+
+        let mut output = Vec::new();
+        while let Some(row) = sort.next_row().unwrap() {
+            output.push(row.to_vec());
+        }
+        output
+    }
+
+    #[test]
+    fn external_sort() {
+        let rows: Vec<Vec<OwnedValue>> = (0..500)
+            .map(|i| vec![OwnedValue::Int(i), OwnedValue::Int(i % 7)])
+            .collect();
+
+        let mut expected = rows.clone();
+        expected.sort_by_key(|row| match (&row[0], &row[1]) {
+            (OwnedValue::Int(a), OwnedValue::Int(b)) => (-b, *a),
+            _ => unreachable!(),
+        });
+
+        assert_eq!(sorted(rows.clone(), usize::MAX), expected);
+        // A tiny budget spills a run every few rows.
+        assert_eq!(sorted(rows, 1000), expected);
+    }
+
+    #[test]
+    fn top_n_rows() {
+        let rows: Vec<Vec<OwnedValue>> = (0..500)
+            .map(|i| vec![OwnedValue::Int(i), OwnedValue::Int(i % 7)])
+            .collect();
+        let top_n = |limit| {
+            let keys = vec![SortKey {
+                expr: Expr::Column(1),
+                order: KeyOrder {
+                    descending: true,
+                    collation: Collation::Binary,
+                },
+            }];
+            let input = Operator::TableFunctionScan(TableFunctionScan::new(rows.clone()));
+            let mut top_n = TopN::new(input, keys, limit);
+
+            let mut output = Vec::new();
+            while let Some(row) = top_n.next_row().unwrap() {
+                output.push(row.to_vec());
+            }
+            output
+        };
+
+        // Rows with equal keys keep their order, like with a stable sort.
+        let mut expected = rows.clone();
+        expected.sort_by_key(|row| match &row[1] {
+            OwnedValue::Int(b) => -b,
+            _ => unreachable!(),
+        });
+        assert_eq!(top_n(100), expected[..100]);
+        assert_eq!(top_n(1000), expected);
+        assert!(top_n(0).is_empty());
+    }
+
+    #[test]
+    fn window_functions() {
+        let int = OwnedValue::Int;
+        // Sorted on the partition, then the order values.
+        let rows = vec![
+            vec![int(1), int(10)],
+            vec![int(1), int(20)],
+            vec![int(1), int(20)],
+            vec![int(1), int(30)],
+            vec![int(2), int(5)],
+            vec![int(2), OwnedValue::Null],
+        ];
+        let function = |name, args| WindowExpr {
+            function: function::window_function(name, usize::from(args)).unwrap(),
+            args: if args { vec![Expr::Column(1)] } else { vec![] },
+        };
+        let functions = vec![
+            function("row_number", false),
+            function("rank", false),
+            function("dense_rank", false),
+            function("sum", true),
+        ];
+
+        let input = Operator::TableFunctionScan(TableFunctionScan::new(rows));
+        let memory = MemoryTracker::new(usize::MAX);
+        let mut window = Window::new(
+            input,
+            vec![Expr::Column(0)],
+            vec![Expr::Column(1)],
+            functions,
+            memory.reservation(),
+        );
+
+        let mut output = Vec::new();
+        while let Some(row) = window.next_row().unwrap() {
+            output.push(row[2..].to_vec());
+        }
+        assert_eq!(
+            output,
+            vec![
+                vec![int(1), int(1), int(1), int(10)],
+                vec![int(2), int(2), int(2), int(50)],
+                vec![int(3), int(2), int(2), int(50)],
+                vec![int(4), int(4), int(3), int(80)],
+                vec![int(1), int(1), int(1), int(5)],
+                vec![int(2), int(2), int(2), int(5)],
+            ]
+        );
+    }
+
+    /// A scan of the table of `multilevel_btrees`, producing its values and rowids.
+    fn table_scan(db: &Db, table: usize) -> SeqScan {
+        let columns = vec!["t.a".to_string(), "t.#rowid".to_string()];
+        SeqScan::new(
+            "t".to_string(),
+            columns,
+            vec![Affinity::Integer; 2],
+            vec![0, 1],
+            db.scanner(table),
+        )
+        .with_rowid_columns(vec![1])
+    }
+
+    fn int_rows(operator: &mut impl RowOperator) -> Vec<(i64, i64)> {
+        let mut rows = Vec::new();
+        while let Some(row) = operator.next_row().unwrap() {
+            match row {
+                [OwnedValue::Int(value), OwnedValue::Int(rowid)] => rows.push((*value, *rowid)),
+                row => panic!("unexpected row: {row:?}"),
+            }
+        }
+        rows
+    }
+
+    #[test]
+    fn index_scan_bounds() {
+        let (db, table, index) = multilevel_btrees("index-scan");
+        let bound = |value, inclusive| {
+            Some(IndexBound {
+                value: SendValue::Int(value),
+                inclusive,
+            })
+        };
+        // The values are the even numbers up to 198, of the rowids `5 * (value + 2)`.
+        let expected = |values: std::ops::RangeInclusive<i64>| -> Vec<(i64, i64)> {
+            values
+                .filter(|v| v % 2 == 0)
+                .map(|v| (v, 5 * (v + 2)))
+                .collect()
+        };
+        let cases = [
+            (None, None, expected(0..=198)),
+            (bound(50, true), bound(60, false), expected(50..=58)),
+            (bound(49, false), bound(60, true), expected(50..=60)),
+            (bound(0, true), bound(0, true), expected(0..=0)),
+            (bound(198, true), bound(198, true), expected(198..=198)),
+            (bound(151, true), None, expected(151..=198)),
+            (None, bound(7, false), expected(0..=6)),
+            (bound(198, false), None, Vec::new()),
+            (None, bound(0, false), Vec::new()),
+            (bound(51, true), bound(51, true), Vec::new()),
+        ];
+        for (lower, upper, expected) in cases {
+            let description = format!("{lower:?} {upper:?}");
+            let mut scan = IndexScan::new(table_scan(&db, table), "i", db.scanner(index), Some(1))
+                .with_bounds("a".to_string(), lower, upper, Collation::Binary);
+            assert_eq!(int_rows(&mut scan), expected, "{description}");
+            scan.rewind().unwrap();
+            assert_eq!(int_rows(&mut scan), expected, "{description}");
+        }
+    }
+
+    #[test]
+    fn rowid_seeks() {
+        let (db, table, _) = multilevel_btrees("rowid-seek");
+        for rowid in [10, 20, 500, 990, 1000] {
+            let mut seek = RowidSeek::new(table_scan(&db, table), rowid);
+            assert_eq!(int_rows(&mut seek), [(rowid / 5 - 2, rowid)]);
+            seek.rewind().unwrap();
+            assert_eq!(int_rows(&mut seek), [(rowid / 5 - 2, rowid)]);
+        }
+        for rowid in [i64::MIN, 0, 15, 1001, i64::MAX] {
+            let mut seek = RowidSeek::new(table_scan(&db, table), rowid);
+            assert_eq!(int_rows(&mut seek), [], "{rowid}");
+        }
+    }
 }
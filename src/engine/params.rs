@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+use crate::{sql::ast, value::OwnedValue};
+
+/// Values bound to the parameters of a statement, by index or by name. Parameters left
+/// unbound are NULL, as in SQLite.
+#[derive(Debug, Clone, Default)]
+pub struct Params {
+    indexed: HashMap<usize, OwnedValue>,
+    named: HashMap<String, OwnedValue>,
+}
+
+impl Params {
+    /// Binds the parameter at `index`, starting at 1.
+    pub fn bind(&mut self, index: usize, value: OwnedValue) {
+        self.indexed.insert(index, value);
+    }
+
+    /// Binds the parameters written `name`, prefix included, e.g. `:id`.
+    pub fn bind_named(&mut self, name: &str, value: OwnedValue) {
+        self.named.insert(name.to_string(), value);
+    }
+
+    pub fn clear(&mut self) {
+        self.indexed.clear();
+        self.named.clear();
+    }
+
+    /// Encodes the bound values, so that statements run with the same values get the
+    /// same key, and any other values a different one.
+    pub fn cache_key(&self) -> Vec<u8> {
+        let mut indexed: Vec<_> = self.indexed.iter().collect();
+        indexed.sort_by_key(|(index, _)| **index);
+        let mut named: Vec<_> = self.named.iter().collect();
+        named.sort_by_key(|(name, _)| *name);
+
+        let mut key = Vec::new();
+        let bindings = (indexed.into_iter())
+            .map(|(index, value)| (index.to_string(), value))
+            .chain(named.into_iter().map(|(name, value)| (name.clone(), value)));
+        for (parameter, value) in bindings {
+            encode_bytes(&mut key, parameter.as_bytes());
+            match value {
+                OwnedValue::Null => key.push(0),
+                OwnedValue::Int(i) => {
+                    key.push(1);
+                    key.extend(i.to_be_bytes());
+                }
+                OwnedValue::Float(f) => {
+                    key.push(2);
+                    key.extend(f.to_bits().to_be_bytes());
+                }
+                OwnedValue::String(s) => {
+                    key.push(3);
+                    encode_bytes(&mut key, s.as_bytes());
+                }
+                OwnedValue::Blob(b) => {
+                    key.push(4);
+                    encode_bytes(&mut key, b);
+                }
+            }
+        }
+        key
+    }
+
+    /// The value of `parameter`, preferring the one bound to its name.
+    pub fn value(&self, parameter: &ast::Parameter) -> OwnedValue {
+        parameter
+            .name
+            .as_ref()
+            .and_then(|name| self.named.get(name))
+            .or_else(|| self.indexed.get(&parameter.index))
+            .cloned()
+            .unwrap_or(OwnedValue::Null)
+    }
+}
+
+/// Appends `bytes` to `key`, preceded by their length so that they can't run into
+/// what follows.
+fn encode_bytes(key: &mut Vec<u8>, bytes: &[u8]) {
+    key.extend(bytes.len().to_be_bytes());
+    key.extend(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bound_values() {
+        let parameter = |index, name: Option<&str>| ast::Parameter {
+            index,
+            name: name.map(str::to_string),
+        };
+        let mut params = Params::default();
+        assert!(params.cache_key().is_empty());
+
+        params.bind(1, OwnedValue::Int(7));
+        params.bind_named(":id", OwnedValue::Int(3));
+        assert_eq!(params.value(&parameter(1, None)), OwnedValue::Int(7));
+        assert_eq!(params.value(&parameter(1, Some(":id"))), OwnedValue::Int(3));
+        assert_eq!(
+            params.value(&parameter(1, Some(":other"))),
+            OwnedValue::Int(7)
+        );
+        assert_eq!(params.value(&parameter(2, None)), OwnedValue::Null);
+
+        let key = params.cache_key();
+        let mut same = Params::default();
+        same.bind_named(":id", OwnedValue::Int(3));
+        same.bind(1, OwnedValue::Int(7));
+        assert_eq!(same.cache_key(), key);
+        same.bind(1, OwnedValue::Float(7.0));
+        assert_ne!(same.cache_key(), key);
+
+        params.clear();
+        assert!(params.cache_key().is_empty());
+        assert_eq!(params.value(&parameter(1, None)), OwnedValue::Null);
+    }
+}
@@ -1,62 +1,2461 @@
-use anyhow::{bail, Context, Ok};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    ops::{Range, RangeInclusive},
+    rc::Rc,
+    sync::{Arc, atomic::AtomicUsize},
+    thread,
+    time::Instant,
+};
+
+use anyhow::{Context, Ok, bail, ensure};
 
 use crate::{
-    db::Db,
+    cursor::Scanner,
+    db::{
+        Db, IndexColumn, IndexMetadata, SchemaMetadata, TableDef, TableMetadata, VirtualTableModule,
+    },
+    integrity,
+    pager::Pager,
     sql::ast::{self, SelectFrom},
+    value::{Affinity, Collation, OwnedValue, SendValue},
+    vtab::{
+        fts5::Fts5Table,
+        rtree::{Constraint, ConstraintOp, RTreeTable},
+    },
+};
+
+use super::{
+    cost::{self, AccessPath, TableEstimate},
+    expr::{BinaryExpr, BinaryOp, CastExpr, Expr, FunctionExpr, IifExpr, UnaryExpr, UnaryOp},
+    function,
+    memory::MemoryTracker,
+    operator::{
+        AggregateExpr, CountRows, Distinct, Exchange, Filter, Fts5Scan, HashAggregate, IndexBound,
+        IndexScan, KeyOrder, Limit, Materialize, NestedLoopJoin, Operator, Project, RTreeScan,
+        RowidSeek, ScanFilter, SeqScan, Sort, SortKey, TableFunctionScan, TopN, Window, WindowExpr,
+    },
+    params::Params,
+    row::{ColumnMetadata, Schema},
 };
 
-use super::operator::{Operator, SeqScan};
+pub const DEFAULT_MEMORY_BUDGET: usize = 64 << 20;
+
+/// The largest number of rows an ORDER BY with a LIMIT keeps in a `TopN` rather than
+/// sorting all the rows.
+const TOP_N_MAX_ROWS: usize = 10_000;
+
+/// The most threads scanning the parts of a table at once.
+const MAX_SCAN_WORKERS: usize = 4;
 
 pub struct Planner<'d> {
-    db: &'d Db,
+    pub(super) db: &'d Db,
+    pub(super) metadata: Arc<SchemaMetadata>,
+    /// Reads the pages of the version of the file `metadata` describes.
+    pager: Pager,
+    memory: MemoryTracker,
+    rows_scanned: Rc<Cell<usize>>,
+    params: &'d Params,
+    /// The collating sequences declared by the columns of the scanned tables, by
+    /// qualified name.
+    collations: RefCell<HashMap<String, Collation>>,
+    /// The affinities of the columns of the scanned tables, by qualified name.
+    affinities: RefCell<HashMap<String, Affinity>>,
+    /// The number of rows the ORDER BY of the query has to produce when its LIMIT
+    /// makes it worth sorting with a `TopN`.
+    top_n: Cell<Option<usize>>,
 }
 
 impl<'d> Planner<'d> {
-    pub fn new(db: &'d Db) -> Self {
-        Self { db }
+    /// Creates a planner resolving tables in `metadata`, whose operators read the pages
+    /// with `pager`, buffer rows against `memory` and count the rows read by their
+    /// scans in `rows_scanned`. Parameters are replaced by their value in `params`.
+    pub fn new(
+        db: &'d Db,
+        metadata: Arc<SchemaMetadata>,
+        pager: Pager,
+        memory: MemoryTracker,
+        rows_scanned: Rc<Cell<usize>>,
+        params: &'d Params,
+    ) -> Self {
+        Self {
+            db,
+            metadata,
+            pager,
+            memory,
+            rows_scanned,
+            params,
+            collations: RefCell::new(HashMap::new()),
+            affinities: RefCell::new(HashMap::new()),
+            top_n: Cell::new(None),
+        }
     }
+
     pub fn compile(self, statement: &ast::Statement) -> anyhow::Result<Operator> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("compile").entered();
+
         match statement {
-            ast::Statement::Select(s) => self.compile_select(s),
+            ast::Statement::Select(s) => {
+                let limit = self.compile_limit(s)?;
+                let distinct = s.core.distinct.then(|| self.memory.reservation());
+                // DISTINCT drops rows after the sort, which then can't stop at the limit.
+                if let Some((Some(limit), offset)) = limit
+                    && distinct.is_none()
+                {
+                    let rows = limit.saturating_add(offset);
+                    self.top_n.set((rows <= TOP_N_MAX_ROWS).then_some(rows));
+                }
+
+                let mut operator = self.compile_select(s)?;
+                if let Some(memory) = distinct {
+                    operator = Operator::Distinct(Distinct::new(operator, memory));
+                }
+                if let Some((limit, offset)) = limit {
+                    operator = Operator::Limit(Limit::new(operator, limit, offset));
+                }
+                Ok(operator)
+            }
+            ast::Statement::Insert(_) | ast::Statement::Update(_) | ast::Statement::Delete(_) => {
+                self.compile_write(statement)
+            }
+            ast::Statement::AlterTable(alter) => self.compile_alter_table(alter),
+            ast::Statement::Pragma(pragma) => self.compile_pragma(pragma),
+            ast::Statement::ExplainQueryPlan(statement) => {
+                ensure!(
+                    !statement.is_write(),
+                    "cannot explain a statement modifying the database"
+                );
+                let rows = self
+                    .compile(statement)?
+                    .plan()
+                    .query_plan_steps()
+                    .into_iter()
+                    .map(|(id, parent, detail)| {
+                        vec![
+                            OwnedValue::Int(id),
+                            OwnedValue::Int(parent),
+                            OwnedValue::Int(0),
+                            OwnedValue::String(Rc::new(detail)),
+                        ]
+                    })
+                    .collect();
+                Ok(Operator::TableFunctionScan(TableFunctionScan::new(rows)))
+            }
+            ast::Statement::Explain(statement) => {
+                ensure!(
+                    !statement.is_write(),
+                    "cannot explain a statement modifying the database"
+                );
+                let rows = self
+                    .compile(statement)?
+                    .plan()
+                    .render()
+                    .into_iter()
+                    .map(|line| vec![OwnedValue::String(Rc::new(line))])
+                    .collect();
+                Ok(Operator::TableFunctionScan(TableFunctionScan::new(rows)))
+            }
+            ast::Statement::ExplainAnalyze(statement) => {
+                ensure!(
+                    !statement.is_write(),
+                    "cannot explain a statement modifying the database"
+                );
+                let started = Instant::now();
+                let mut operator = self.compile(statement)?.instrument();
+                while operator.next_row()?.is_some() {}
+                let elapsed = started.elapsed();
+
+                let mut lines = operator.plan().render();
+                lines.push(format!(
+                    "Execution time: {:.3}ms",
+                    elapsed.as_secs_f64() * 1e3
+                ));
+                let rows = lines
+                    .into_iter()
+                    .map(|line| vec![OwnedValue::String(Rc::new(line))])
+                    .collect();
+                Ok(Operator::TableFunctionScan(TableFunctionScan::new(rows)))
+            }
+            ast::Statement::Drop(drop) => self.compile_drop(drop),
             stmt => bail!("unsupported statement: {stmt:?}"),
         }
     }
 
+    /// Evaluates the LIMIT and OFFSET of the query. A negative limit means no limit.
+    fn compile_limit(
+        &self,
+        select: &ast::SelectStatement,
+    ) -> anyhow::Result<Option<(Option<usize>, usize)>> {
+        let Some(limit) = &select.limit else {
+            return Ok(None);
+        };
+
+        let limit = self.eval_integer(limit)?;
+        let offset = match &select.offset {
+            Some(offset) => self.eval_integer(offset)?.max(0) as usize,
+            None => 0,
+        };
+        Ok(Some((usize::try_from(limit).ok(), offset)))
+    }
+
+    /// Evaluates an expression that can't refer to columns, such as a LIMIT.
+    fn eval_integer(&self, expr: &ast::Expr) -> anyhow::Result<i64> {
+        match self.compile_expr(expr, &[])?.eval(&[])? {
+            OwnedValue::Int(i) => Ok(i),
+            OwnedValue::String(s) => s.trim().parse().ok().context("datatype mismatch"),
+            _ => bail!("datatype mismatch"),
+        }
+    }
+
+    fn compile_pragma(self, pragma: &ast::PragmaStatement) -> anyhow::Result<Operator> {
+        let quick = match pragma.name.as_str() {
+            "integrity_check" => false,
+            "quick_check" => true,
+            name => bail!("unsupported pragma: {name}"),
+        };
+        // Like in SQLite, the argument is either the most problems to report, or the
+        // table to check.
+        let (max_errors, table) = match &pragma.arg {
+            Some(ast::PragmaArg::Integer(n)) if *n > 0 => (*n as usize, None),
+            Some(ast::PragmaArg::Name(table)) => {
+                (integrity::DEFAULT_MAX_ERRORS, Some(table.as_str()))
+            }
+            _ => (integrity::DEFAULT_MAX_ERRORS, None),
+        };
+
+        let rows = integrity::check(&self.pager, &self.metadata, table, quick, max_errors)?
+            .into_iter()
+            .map(|row| vec![OwnedValue::String(Rc::new(row))])
+            .collect();
+        Ok(Operator::TableFunctionScan(TableFunctionScan::new(rows)))
+    }
+
     fn compile_select(self, select: &ast::SelectStatement) -> anyhow::Result<Operator> {
-        let SelectFrom::Table(table_name) = &select.core.from;
+        let SelectFrom::Table {
+            name: table_name,
+            alias,
+        } = &select.core.from
+        else {
+            let mut names = Vec::new();
+            let mut tables = Vec::new();
+            self.source_layout(&select.core.from, false, &mut names, &mut tables)?;
+            let names: Vec<&str> = names.iter().map(String::as_str).collect();
+            let (pushed, where_clause) =
+                push_down_filters(select.core.where_clause.as_ref(), &names, &tables);
+            let referenced = referenced_columns(select, &names);
+            let (input, columns) =
+                self.compile_source(&select.core.from, 0, &pushed, referenced.as_deref())?;
+            let columns: Vec<&str> = columns.iter().map(String::as_str).collect();
+            let input = self.filter(where_clause.as_ref(), input, &columns)?;
+            return self.project(select, input, &columns);
+        };
 
-        let table = self
-            .db
-            .tables_metadata
-            .iter()
-            .find(|m| &m.name == table_name)
+        let metadata = self.metadata.clone();
+        let table = metadata
+            .table(table_name)
             .with_context(|| format!("invalid table name: {table_name}"))?;
 
-        let mut columns = Vec::new();
+        let definition = table.definition()?;
+        if definition.module.is_some() {
+            return self.compile_virtual_table_select(select, table);
+        }
+
+        let scan_name = alias.as_deref().unwrap_or(table_name);
+        let names = table_columns(scan_name, definition);
+        let columns: Vec<&str> = names.iter().map(String::as_str).collect();
+        self.declare_columns(&names, &definition.columns);
+        if select.core.where_clause.is_none()
+            && select.order_by.is_empty()
+            && !is_aggregate(select)
+            && !is_windowed(select)
+        {
+            let StoredColumns {
+                names: stored,
+                affinities,
+                rowid_columns,
+                generated,
+            } = self.stored_columns(definition, &names)?;
+            let exprs = self.compile_result_columns(&select.core.result_columns, &columns)?;
+            let fields: Option<Vec<usize>> = exprs
+                .iter()
+                .map(|e| match e {
+                    Expr::Column(i) => Some(*i),
+                    _ => None,
+                })
+                .collect();
+
+            if let (None, Some(fields)) = (generated, fields) {
+                let scanner = self.scanner(table.first_page);
+                let scan = SeqScan::new(scan_name.to_string(), stored, affinities, fields, scanner)
+                    .with_rowid_columns(rowid_columns);
+                return Ok(self.count_rows(Operator::SeqScan(scan)));
+            }
+        }
+
+        let referenced = referenced_columns(select, &columns);
+        let input = self.compile_table_scan(
+            table,
+            scan_name,
+            &names,
+            select.core.where_clause.as_ref(),
+            referenced,
+            !ignores_row_order(select),
+        )?;
+        self.project(select, input, &columns)
+    }
+
+    /// Compiles the scan of `table`, its columns named `names`, for the rows selected by
+    /// `where_clause`: the comparisons of columns with constants are checked by the
+    /// scan or choose how the table is read, and the rest of the clause filters the
+    /// rows it produces. Only the `referenced` columns are decoded, if known, and the
+    /// rows keep their order when `ordered`.
+    fn compile_table_scan(
+        &self,
+        table: &TableMetadata,
+        scan_name: &str,
+        names: &[String],
+        where_clause: Option<&ast::Expr>,
+        referenced: Option<Vec<bool>>,
+        ordered: bool,
+    ) -> anyhow::Result<Operator> {
+        let definition = table.definition()?;
+        let columns: Vec<&str> = names.iter().map(String::as_str).collect();
+        let StoredColumns {
+            names: stored,
+            affinities,
+            rowid_columns,
+            generated,
+        } = self.stored_columns(definition, names)?;
+
+        // The scan decodes the columns of the rows computed from, so it only checks
+        // the filters and skips columns when it produces the columns of the query.
+        let (filters, rest) = match &generated {
+            None => self.scan_filters(where_clause, &columns)?,
+            Some(_) => (Vec::new(), where_clause.cloned()),
+        };
+        let skipped: Vec<usize> = match (&generated, referenced) {
+            (None, Some(referenced)) => (0..columns.len()).filter(|&i| !referenced[i]).collect(),
+            _ => Vec::new(),
+        };
+
+        let statistics = self.metadata.statistics(&table.name);
+        let estimate =
+            TableEstimate::of_btree(&self.pager, table.first_page)?.with_statistics(statistics);
+        let selectivity = where_clause.map_or(1.0, |expr| cost::selectivity(expr, statistics));
+        // Only shown when the table was analyzed, as the estimates are rough otherwise.
+        let estimated_rows = statistics.map(|_| (estimate.rows * selectivity).round() as u64);
+
+        let searches = match generated {
+            None if definition.has_rowid() => {
+                self.index_searches(&table.name, definition, &filters, &skipped, &rowid_columns)
+            }
+            _ => Vec::new(),
+        };
+        // Tables with rowids are split along the keys of their interior pages, each part
+        // scanned by a thread of its own.
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+        let partitions = match definition.has_rowid() && generated.is_none() {
+            true => self
+                .scanner(table.first_page)
+                .rowid_partitions(workers.min(MAX_SCAN_WORKERS))?,
+            false => vec![i64::MIN..=i64::MAX],
+        };
+        let mut paths = vec![
+            AccessPath::Scan,
+            AccessPath::ParallelScan {
+                workers: partitions.len(),
+            },
+        ];
+        // A row is sought by rowid when the rowid is compared to an integer constant.
+        let rowid = filters
+            .iter()
+            .filter(|f| f.op == BinaryOp::Eq && rowid_columns.contains(&f.column))
+            .find_map(|f| match f.value {
+                SendValue::Int(rowid) => Some(rowid),
+                _ => None,
+            });
+        if rowid.is_some() && definition.has_rowid() {
+            paths.push(AccessPath::RowidSeek);
+        }
+        let (rowids, rowid_bounds) = rowid_range(&filters, &rowid_columns);
+        if rowid_bounds > 0 && definition.has_rowid() {
+            paths.push(AccessPath::RowidRange {
+                range: cost::range_selectivity("rowid", false, rowid_bounds, &estimate, None),
+            });
+        }
+        for (i, search) in searches.iter().enumerate() {
+            let name = definition.columns[search.column].name.to_lowercase();
+            let bounds = search.lower.is_some() as usize + search.upper.is_some() as usize;
+            paths.push(AccessPath::IndexSearch {
+                index: i,
+                pages: TableEstimate::of_btree(&self.pager, search.index.first_page)?.pages,
+                range: cost::range_selectivity(
+                    &name,
+                    search.is_equality(),
+                    bounds,
+                    &estimate,
+                    statistics,
+                ),
+                covering: search.covering,
+            });
+        }
+
+        let scan_name = scan_name.to_string();
+        let rejected = Arc::new(AtomicUsize::new(0));
+        let stage = {
+            let (stored, affinities, rowid_columns) =
+                (stored.clone(), affinities.clone(), rowid_columns.clone());
+            let (skipped, filters, rejected) = (skipped.clone(), filters.clone(), rejected.clone());
+            let scan_name = scan_name.clone();
+            move |scanner| {
+                let fields = (0..stored.len()).collect();
+                SeqScan::new(scan_name, stored, affinities, fields, scanner)
+                    .with_rowid_columns(rowid_columns)
+                    .with_skipped(skipped)
+                    .with_filters(filters, rejected)
+                    .with_estimated_rows(estimated_rows)
+            }
+        };
+
+        // The rows are computed on, filtered, aggregated or buffered by a sort, so
+        // decoding them is worth running on another thread unless the table is small.
+        let scanner = self.scanner(table.first_page);
+        let scan = match AccessPath::choose(paths, &estimate, selectivity) {
+            AccessPath::Scan => Operator::SeqScan(stage(scanner)),
+            AccessPath::ParallelScan { workers } => {
+                let plan = Operator::SeqScan(stage.clone()(self.scanner(table.first_page))).plan();
+                let stages: Vec<_> = partitions
+                    .into_iter()
+                    .map(|rowids| {
+                        let (stage, scanner) = (stage.clone(), self.scanner(table.first_page));
+                        move || match workers {
+                            1 => Operator::SeqScan(stage(scanner)),
+                            _ => Operator::SeqScan(stage(scanner.with_rowids(rowids))),
+                        }
+                    })
+                    .collect();
+                Operator::Exchange(Exchange::partitioned(plan, stages, ordered))
+            }
+            AccessPath::RowidRange { .. } => Operator::SeqScan(stage(scanner.with_rowids(rowids))),
+            AccessPath::RowidSeek => {
+                let rowid = rowid.context("missing rowid")?;
+                Operator::RowidSeek(RowidSeek::new(stage(scanner), rowid))
+            }
+            AccessPath::IndexSearch { index, .. } => {
+                let search = &searches[index];
+                let keys = self.scanner(search.index.first_page);
+                let key_width = search.index.columns().len();
+                let scan = match search.covering {
+                    false => {
+                        IndexScan::new(stage(scanner), &search.index.name, keys, Some(key_width))
+                    }
+                    true => {
+                        // The rows are decoded from the keys, which hold the indexed
+                        // columns followed by the rowid. The other columns, which the
+                        // query doesn't refer to, are given positions past the keys.
+                        let key_position = |i: usize| {
+                            let name = definition.columns.get(i).map(|c| c.name.to_lowercase());
+                            let columns = search.index.columns();
+                            match columns
+                                .iter()
+                                .position(|c| c.name.is_some() && c.name == name)
+                            {
+                                _ if rowid_columns.contains(&i) => key_width,
+                                Some(position) => position,
+                                None => key_width + 1 + i,
+                            }
+                        };
+                        let fields: Vec<usize> = (0..stored.len()).map(key_position).collect();
+                        let mut key_names = vec![String::new(); key_width + 1 + stored.len()];
+                        let mut key_affinities = vec![Affinity::Blob; key_names.len()];
+                        // The INTEGER PRIMARY KEY and the hidden rowid column share
+                        // the last field, which is named after the former.
+                        for (i, &position) in fields.iter().enumerate().rev() {
+                            key_names[position] = stored[i].clone();
+                            key_affinities[position] = affinities[i];
+                        }
+                        let rows =
+                            SeqScan::new(scan_name, key_names, key_affinities, fields, scanner)
+                                .with_skipped(skipped)
+                                .with_filters(filters, rejected.clone())
+                                .with_estimated_rows(estimated_rows);
+                        IndexScan::new(rows, &search.index.name, keys, None)
+                    }
+                };
+                let collation = search.collation;
+                let column = definition.columns[search.column].name.clone();
+                Operator::IndexScan(scan.with_bounds(
+                    column,
+                    search.lower.clone(),
+                    search.upper.clone(),
+                    collation,
+                ))
+            }
+        };
+        let count = CountRows::new(scan, self.rows_scanned.clone());
+        let mut input = Operator::CountRows(count.with_rejected(rejected));
+        if let Some(exprs) = generated {
+            input = Operator::Project(Project::new(input, exprs));
+        }
+        self.filter(rest.as_ref(), input, &columns)
+    }
+
+    /// Appends the names of the columns of a FROM clause to `names`, as
+    /// `compile_source` names them, and the ranges of those of the tables filters can
+    /// be pushed down into to `tables`: not those on the right of a LEFT JOIN, whose
+    /// missing rows are padded with NULLs rather than filtered out.
+    fn source_layout(
+        &self,
+        from: &SelectFrom,
+        padded: bool,
+        names: &mut Vec<String>,
+        tables: &mut Vec<Range<usize>>,
+    ) -> anyhow::Result<()> {
+        match from {
+            SelectFrom::Table { name, alias } => {
+                let table = self
+                    .metadata
+                    .table(name)
+                    .with_context(|| format!("invalid table name: {name}"))?;
+                let definition = table.definition()?;
+                let start = names.len();
+                names.extend(table_columns(alias.as_deref().unwrap_or(name), definition));
+                if !padded && definition.module.is_none() {
+                    tables.push(start..names.len());
+                }
+            }
+            SelectFrom::Function(call) => {
+                let table_function = function::table_function(&call.name, call.args.len())?;
+                names.extend(qualified_columns(
+                    &call.name,
+                    table_function.columns.iter().copied(),
+                ));
+            }
+            SelectFrom::Values { rows, alias } => {
+                let columns = values_columns(rows[0].len());
+                names.extend(match alias {
+                    Some(alias) => qualified_columns(alias, columns.iter().map(String::as_str)),
+                    None => columns,
+                });
+            }
+            SelectFrom::Join(join) => {
+                self.source_layout(&join.left, padded, names, tables)?;
+                let padded = padded || join.kind == ast::JoinKind::Left;
+                self.source_layout(&join.right, padded, names, tables)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles the scans and joins of a FROM clause, returning the names of the
+    /// columns of the rows they produce, qualified by their table. Its columns start
+    /// at position `start` among those of the query, which the terms of its WHERE
+    /// clause `pushed` down into the scans of its tables are keyed by, and the scans
+    /// only decode the columns the query refers to, if `referenced` marks them.
+    fn compile_source(
+        &self,
+        from: &SelectFrom,
+        start: usize,
+        pushed: &HashMap<usize, ast::Expr>,
+        referenced: Option<&[bool]>,
+    ) -> anyhow::Result<(Operator, Vec<String>)> {
+        match from {
+            SelectFrom::Table { name, alias } => {
+                let table = self
+                    .metadata
+                    .table(name)
+                    .with_context(|| format!("invalid table name: {name}"))?;
+                let definition = table.definition()?;
+                if definition.module.is_some() {
+                    bail!("virtual tables can't be joined: {name}");
+                }
+
+                let scan_name = alias.as_deref().unwrap_or(name);
+                let columns = table_columns(scan_name, definition);
+                self.declare_columns(&columns, &definition.columns);
+                let where_clause = pushed.get(&start);
+                let referenced = referenced.map(|r| r[start..start + columns.len()].to_vec());
+                let scan = self.compile_table_scan(
+                    table,
+                    scan_name,
+                    &columns,
+                    where_clause,
+                    referenced,
+                    true,
+                )?;
+                Ok((scan, columns))
+            }
+            SelectFrom::Function(call) => {
+                let table_function = function::table_function(&call.name, call.args.len())?;
+
+                let args = call
+                    .args
+                    .iter()
+                    .map(|arg| self.compile_expr(arg, &[]))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let values = args
+                    .iter()
+                    .map(|arg| arg.eval(&[]))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let json = vec![false; values.len()];
+                let rows = (table_function.call)(&function::Args::new(&values, &json))?;
+
+                let columns = qualified_columns(&call.name, table_function.columns.iter().copied());
+                let scan = TableFunctionScan::function(call.name.clone(), rows);
+                Ok((self.count_rows(Operator::TableFunctionScan(scan)), columns))
+            }
+            SelectFrom::Values { rows, alias } => {
+                let rows = rows
+                    .iter()
+                    .map(|row| {
+                        row.iter()
+                            .map(|expr| self.compile_expr(expr, &[])?.eval(&[]))
+                            .collect::<anyhow::Result<Vec<_>>>()
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+
+                let names = values_columns(rows[0].len());
+                let columns = match alias {
+                    Some(alias) => qualified_columns(alias, names.iter().map(String::as_str)),
+                    None => names,
+                };
+                let scan = TableFunctionScan::new(rows);
+                Ok((self.count_rows(Operator::TableFunctionScan(scan)), columns))
+            }
+            SelectFrom::Join(join) => {
+                let (left, mut columns) =
+                    self.compile_source(&join.left, start, pushed, referenced)?;
+                let right_start = start + columns.len();
+                let (right, right_columns) =
+                    self.compile_source(&join.right, right_start, pushed, referenced)?;
+                // The right side is read again for each left row, so tables and joins
+                // are read once and their rows replayed. Functions and VALUES already
+                // hold their rows.
+                let right = match &join.right {
+                    SelectFrom::Function(_) | SelectFrom::Values { .. } => right,
+                    _ => Operator::Materialize(Materialize::new(right, self.memory.reservation())),
+                };
+                let left_width = columns.len();
+                columns.extend(right_columns);
+
+                let names: Vec<&str> = columns.iter().map(String::as_str).collect();
+                let constraint = join
+                    .constraint
+                    .as_ref()
+                    .map(|expr| self.compile_expr(expr, &names))
+                    .transpose()?;
+                let null_padding = match join.kind {
+                    ast::JoinKind::Inner => None,
+                    ast::JoinKind::Left => Some(columns.len() - left_width),
+                };
+                let join = NestedLoopJoin::new(left, right, constraint, null_padding)
+                    .with_interrupt(self.db.interrupt_handle().checker());
+                Ok((Operator::NestedLoopJoin(join), columns))
+            }
+        }
+    }
+
+    /// A scanner of the b-tree rooted at `page`, failing once the queries of the
+    /// database are interrupted.
+    pub(super) fn scanner(&self, page: usize) -> Scanner {
+        Scanner::new(page, self.pager.clone()).with_interrupt(self.db.interrupt_handle().checker())
+    }
+
+    /// Records the collations and affinities of the columns of a table, named `names`
+    /// by `table_columns`. The rowid has no collation and an INTEGER affinity.
+    pub(super) fn declare_columns(&self, names: &[String], columns: &[ast::ColumnDef]) {
+        let mut collations = self.collations.borrow_mut();
+        let mut affinities = self.affinities.borrow_mut();
+        for (i, name) in names.iter().enumerate() {
+            let Some(column) = columns.get(i) else {
+                affinities.insert(name.clone(), Affinity::Integer);
+                continue;
+            };
+            let collation = column.collation();
+            collations.insert(
+                name.clone(),
+                collation.with_case_folding(self.db.case_folding()),
+            );
+            affinities.insert(name.clone(), column.affinity());
+        }
+    }
+
+    fn compile_virtual_table_select(
+        self,
+        select: &ast::SelectStatement,
+        table: &TableMetadata,
+    ) -> anyhow::Result<Operator> {
+        let module = table
+            .definition()?
+            .module
+            .as_ref()
+            .context("expected a virtual table")?;
+        match module.name.to_lowercase().as_str() {
+            "fts5" => self.compile_fts5_select(select, table, &module.args),
+            "rtree" | "rtree_i32" => self.compile_rtree_select(select, table, module),
+            _ => bail!("unsupported virtual table module: {}", module.name),
+        }
+    }
+
+    fn compile_fts5_select(
+        self,
+        select: &ast::SelectStatement,
+        table: &TableMetadata,
+        args: &[String],
+    ) -> anyhow::Result<Operator> {
+        let fts = Fts5Table::new(&table.name, args)?;
+        let definition = table.definition()?;
+        let columns: Vec<&str> = definition.columns.iter().map(|c| c.name.as_str()).collect();
+
+        let rowids = match &select.core.where_clause {
+            None => None,
+            Some(ast::Expr::Binary(ast::BinaryExpr {
+                op: ast::BinaryOperator::Match,
+                lhs,
+                rhs,
+            })) => {
+                let (ast::Expr::Column(col), ast::Expr::Literal(ast::Literal::String(query))) =
+                    (lhs.as_ref(), rhs.as_ref())
+                else {
+                    bail!("MATCH expects a column on the left and a string query on the right");
+                };
+
+                let column = if col.name.eq_ignore_ascii_case(&table.name) {
+                    None
+                } else {
+                    let index = columns
+                        .iter()
+                        .position(|&c| c.eq_ignore_ascii_case(&col.name))
+                        .with_context(|| format!("invalid column name: {}", col.name))?;
+                    Some(index)
+                };
+
+                Some(fts.matching_rowids(
+                    &self.metadata,
+                    &|page| self.scanner(page),
+                    query,
+                    column,
+                )?)
+            }
+            Some(_) => bail!("WHERE clauses are only supported for full-text MATCH queries"),
+        };
+
+        let scan = Fts5Scan::new(
+            table.name.clone(),
+            fts.scanner(&self.metadata, &|page| self.scanner(page))?,
+            rowids,
+            fts.column_count(),
+            fts.has_content(),
+        );
+
+        // The rows end with the hidden column named after the table, which MATCH
+        // queries every column through, and the rowid. Neither is part of `*`.
+        let hidden = [table.name.as_str(), ROWID_COLUMN];
+        let names = qualified_columns(&table.name, columns.iter().copied().chain(hidden));
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let mut result_columns = Vec::new();
+        for column in &select.core.result_columns {
+            if let ast::ResultColumn::Expr(_) = column {
+                result_columns.push(column.clone());
+                continue;
+            }
+            for (i, _) in star_columns(column, &names)? {
+                if i < columns.len() {
+                    result_columns.push(ast::ResultColumn::Expr(ast::ExprResultColumn {
+                        expr: ast::Expr::Column(ast::Column {
+                            table: Some(table.name.clone()),
+                            name: columns[i].to_string(),
+                        }),
+                        alias: None,
+                    }));
+                }
+            }
+        }
+        let select = &ast::SelectStatement {
+            core: ast::SelectCore {
+                result_columns,
+                ..select.core.clone()
+            },
+            ..select.clone()
+        };
+
+        let input = self.count_rows(Operator::Fts5Scan(scan));
+        self.project(select, input, &names)
+    }
+
+    fn compile_rtree_select(
+        self,
+        select: &ast::SelectStatement,
+        table: &TableMetadata,
+        module: &VirtualTableModule,
+    ) -> anyhow::Result<Operator> {
+        let rtree = RTreeTable::new(&table.name, &module.name, &module.args)?;
+        let columns: Vec<&str> = table
+            .definition()?
+            .columns
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect();
+
+        let mut constraints = Vec::new();
+        if let Some(where_clause) = &select.core.where_clause {
+            collect_rtree_constraints(where_clause, &columns, &mut constraints)?;
+        }
+        for constraint in &constraints {
+            if constraint.column != 0 && !rtree.is_coordinate(constraint.column) {
+                bail!("rtree constraints are only supported on the id and coordinate columns");
+            }
+        }
+
+        let cursor = rtree.cursor(&self.metadata, &|page| self.scanner(page), constraints)?;
+        let scan = RTreeScan::new(table.name.clone(), cursor, rtree.column_count());
+
+        let input = self.count_rows(Operator::RTreeScan(scan));
+        self.project(select, input, &columns)
+    }
+
+    /// Splits the columns of a table, named `names` by `table_columns`, between the
+    /// ones stored in its records and the virtual generated ones.
+    fn stored_columns(
+        &self,
+        definition: &TableDef,
+        names: &[String],
+    ) -> anyhow::Result<StoredColumns> {
+        let mut positions = definition.stored_columns();
+        if definition.has_rowid() {
+            positions.push(definition.columns.len());
+        }
+        let stored: Vec<String> = positions.iter().map(|&i| names[i].clone()).collect();
+        let affinities = positions
+            .iter()
+            .map(|&i| {
+                definition
+                    .columns
+                    .get(i)
+                    .map_or(Affinity::Integer, ast::ColumnDef::affinity)
+            })
+            .collect();
+        let rowid_alias = definition.rowid_alias();
+        let rowid_columns = (0..positions.len())
+            .filter(|&p| {
+                positions[p] == definition.columns.len() || Some(positions[p]) == rowid_alias
+            })
+            .collect();
+        if positions.iter().copied().eq(0..names.len()) {
+            return Ok(StoredColumns {
+                names: stored,
+                affinities,
+                rowid_columns,
+                generated: None,
+            });
+        }
+
+        let stored_names: Vec<&str> = stored.iter().map(String::as_str).collect();
+        let exprs = (0..names.len())
+            .map(|i| match positions.iter().position(|&p| p == i) {
+                Some(position) => Ok(Expr::Column(position)),
+                None => {
+                    let generated = virtual_expr(definition, i, 0)?;
+                    Ok(Expr::Affinity(Box::new(CastExpr {
+                        expr: self.compile_expr(&generated, &stored_names)?,
+                        affinity: definition.columns[i].affinity(),
+                    })))
+                }
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(StoredColumns {
+            names: stored,
+            affinities,
+            rowid_columns,
+            generated: Some(exprs),
+        })
+    }
+
+    fn count_rows(&self, scan: Operator) -> Operator {
+        Operator::CountRows(CountRows::new(scan, self.rows_scanned.clone()))
+    }
+
+    /// The indexes of `table` that a query can read it through: those whose first
+    /// column the pushed-down `filters` bound, or that hold every column the query
+    /// refers to, i.e. all but `skipped`.
+    fn index_searches<'m>(
+        &'m self,
+        table: &str,
+        definition: &TableDef,
+        filters: &[ScanFilter],
+        skipped: &[usize],
+        rowid_columns: &[usize],
+    ) -> Vec<IndexSearch<'m>> {
+        let mut searches = Vec::new();
+        for index in &self.metadata.indexes {
+            let columns = index.columns();
+            let Some(IndexColumn {
+                name: Some(first),
+                collation,
+                descending: false,
+            }) = columns.first()
+            else {
+                continue;
+            };
+            if !index.table_name.eq_ignore_ascii_case(table) {
+                continue;
+            }
+            let Some(column) = (definition.columns.iter())
+                .position(|c| c.name.eq_ignore_ascii_case(first))
+                .filter(|&c| definition.rowid_alias() != Some(c))
+            else {
+                continue;
+            };
+
+            let collation = collation.unwrap_or_else(|| definition.columns[column].collation());
+            let mut search = IndexSearch {
+                index,
+                column,
+                collation,
+                lower: None,
+                upper: None,
+                covering: false,
+            };
+            // The keys are ordered as SQLite compares them: a comparison folding all
+            // letters, e.g. with Unicode case folding, can't bound the keys of a NOCASE
+            // index, which only folds ASCII ones.
+            let bounding = filters.iter().filter(|f| {
+                f.column == column && f.collation == collation && f.value != SendValue::Null
+            });
+            for filter in bounding {
+                let bound = |inclusive| {
+                    Some(IndexBound {
+                        value: filter.value.clone(),
+                        inclusive,
+                    })
+                };
+                match filter.op {
+                    BinaryOp::Eq if search.lower.is_none() && search.upper.is_none() => {
+                        (search.lower, search.upper) = (bound(true), bound(true));
+                    }
+                    BinaryOp::Gt | BinaryOp::GtEq if search.lower.is_none() => {
+                        search.lower = bound(filter.op == BinaryOp::GtEq);
+                    }
+                    BinaryOp::Lt | BinaryOp::LtEq if search.upper.is_none() => {
+                        search.upper = bound(filter.op == BinaryOp::LtEq);
+                    }
+                    _ => {}
+                }
+            }
+
+            let indexed = |i: usize| {
+                let name = definition.columns.get(i).map(|c| c.name.to_lowercase());
+                rowid_columns.contains(&i)
+                    || columns.iter().any(|c| c.name.is_some() && c.name == name)
+            };
+            search.covering = (0..=definition.columns.len())
+                .filter(|i| !skipped.contains(i))
+                .all(indexed);
+            if search.lower.is_some() || search.upper.is_some() || search.covering {
+                searches.push(search);
+            }
+        }
+        searches
+    }
+
+    /// Splits a WHERE clause between the comparisons of a column with a constant,
+    /// which the scan producing `columns` can check, and the rest of the conjunction.
+    fn scan_filters(
+        &self,
+        where_clause: Option<&ast::Expr>,
+        columns: &[&str],
+    ) -> anyhow::Result<(Vec<ScanFilter>, Option<ast::Expr>)> {
+        let mut filters = Vec::new();
+        let mut rest: Option<ast::Expr> = None;
+        for conjunct in where_clause.map(conjuncts).unwrap_or_default() {
+            // `x BETWEEN a AND b` is `x >= a AND x <= b`, each checked by the scan.
+            let comparisons = match conjunct {
+                ast::Expr::Between(between) if !between.negated => {
+                    let compare = |op, bound: &ast::Expr| {
+                        ast::Expr::Binary(ast::BinaryExpr {
+                            op,
+                            lhs: between.expr.clone(),
+                            rhs: Box::new(bound.clone()),
+                        })
+                    };
+                    vec![
+                        compare(ast::BinaryOperator::GtEq, &between.low),
+                        compare(ast::BinaryOperator::LtEq, &between.high),
+                    ]
+                }
+                conjunct => vec![conjunct.clone()],
+            };
+            let pushed = comparisons
+                .iter()
+                .map(|comparison| self.scan_filter(comparison, columns))
+                .collect::<anyhow::Result<Option<Vec<_>>>>()?;
+            match pushed {
+                Some(pushed) => filters.extend(pushed),
+                None => rest = Some(and(rest, conjunct.clone())),
+            }
+        }
+        Ok((filters, rest))
+    }
+
+    fn scan_filter(
+        &self,
+        expr: &ast::Expr,
+        columns: &[&str],
+    ) -> anyhow::Result<Option<ScanFilter>> {
+        let ast::Expr::Binary(binary) = expr else {
+            return Ok(None);
+        };
+        let op = match binary.op {
+            ast::BinaryOperator::Eq => BinaryOp::Eq,
+            ast::BinaryOperator::NotEq => BinaryOp::NotEq,
+            ast::BinaryOperator::Lt => BinaryOp::Lt,
+            ast::BinaryOperator::LtEq => BinaryOp::LtEq,
+            ast::BinaryOperator::Gt => BinaryOp::Gt,
+            ast::BinaryOperator::GtEq => BinaryOp::GtEq,
+            _ => return Ok(None),
+        };
+        let is_constant =
+            |e: &ast::Expr| matches!(e, ast::Expr::Literal(_) | ast::Expr::Parameter(_));
+        let (column, op, constant) = match (binary.lhs.as_ref(), binary.rhs.as_ref()) {
+            (column @ ast::Expr::Column(_), constant) if is_constant(constant) => {
+                (column, op, constant)
+            }
+            (constant, column @ ast::Expr::Column(_)) if is_constant(constant) => {
+                (column, op.flip(), constant)
+            }
+            _ => return Ok(None),
+        };
+        let ast::Expr::Column(column_ref) = column else {
+            unreachable!("matched a column");
+        };
+
+        // The value is compared as the scan compares it with the column's values.
+        let (_, affinity) = self.comparison_affinities(column, constant, columns);
+        let value = with_affinity(self.compile_expr(constant, &[])?, affinity).eval(&[])?;
+        Ok(Some(ScanFilter {
+            column: resolve_column(columns, column_ref)?,
+            op,
+            value: SendValue::from(&value),
+            collation: self.comparison_collation(&binary.lhs, &binary.rhs, columns)?,
+        }))
+    }
+
+    /// Filters the rows of `input` on a WHERE clause, if any.
+    fn filter(
+        &self,
+        where_clause: Option<&ast::Expr>,
+        input: Operator,
+        columns: &[&str],
+    ) -> anyhow::Result<Operator> {
+        let Some(where_clause) = where_clause else {
+            return Ok(input);
+        };
+        let predicate = self.compile_expr(where_clause, columns)?;
+        Ok(Operator::Filter(Filter::new(input, predicate)))
+    }
+
+    /// Computes the result columns from the rows of `input`, aggregating them first
+    /// when the query groups rows or calls aggregate functions.
+    fn project(
+        &self,
+        select: &ast::SelectStatement,
+        input: Operator,
+        columns: &[&str],
+    ) -> anyhow::Result<Operator> {
+        let select = &ast::SelectStatement {
+            order_by: resolve_order_by(select, columns)?,
+            ..select.clone()
+        };
+        if is_aggregate(select) {
+            return self.aggregate(select, input, columns);
+        }
+        if is_windowed(select) {
+            let mut result_columns = Vec::new();
+            for res_col in &select.core.result_columns {
+                match res_col {
+                    ast::ResultColumn::Expr(e) => result_columns.push(e.expr.clone()),
+                    star => {
+                        let selected = star_columns(star, columns)?;
+                        result_columns.extend(selected.into_iter().map(|(_, name)| {
+                            ast::Expr::Column(ast::Column {
+                                table: None,
+                                name: name.to_string(),
+                            })
+                        }))
+                    }
+                }
+            }
+            return self.window(&result_columns, &select.order_by, input, columns);
+        }
+
+        let exprs = self.compile_result_columns(&select.core.result_columns, columns)?;
+        self.sort_and_project(&select.order_by, input, columns, exprs)
+    }
+
+    /// Computes the window functions of the result columns and ORDER BY terms, then
+    /// projects the rows. The windows sharing a partitioning and ordering are computed
+    /// by a Window operator over the rows sorted on them, which appends their values to
+    /// the rows; the window calls are then replaced by references to those columns.
+    fn window(
+        &self,
+        result_columns: &[ast::Expr],
+        order_by: &[ast::OrderingTerm],
+        input: Operator,
+        columns: &[&str],
+    ) -> anyhow::Result<Operator> {
+        let mut windows = Vec::new();
+        for expr in result_columns
+            .iter()
+            .chain(order_by.iter().map(|t| &t.expr))
+        {
+            collect_windows(expr, &mut windows);
+        }
+
+        let mut names: Vec<String> = columns.iter().map(|c| c.to_string()).collect();
+        let mut operator = input;
+        while let Some(first) = windows.first().copied() {
+            let (group, rest): (Vec<_>, Vec<_>) = windows.into_iter().partition(|w| {
+                w.partition_by == first.partition_by && w.order_by == first.order_by
+            });
+            windows = rest;
+
+            let input_names: Vec<&str> = names.iter().map(String::as_str).collect();
+            let compile_all = |exprs: &mut dyn Iterator<Item = &ast::Expr>| {
+                exprs
+                    .map(|expr| self.compile_expr(expr, &input_names))
+                    .collect::<anyhow::Result<Vec<_>>>()
+            };
+            let partition_by = compile_all(&mut first.partition_by.iter())?;
+            let window_order = compile_all(&mut first.order_by.iter().map(|t| &t.expr))?;
+            let functions = group
+                .iter()
+                .map(|window| {
+                    let call = &window.function;
+                    Ok(WindowExpr {
+                        function: function::window_function(&call.name, call.args.len())?,
+                        args: compile_all(&mut call.args.iter())?,
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
+            let keys: Vec<SortKey> = partition_by
+                .iter()
+                .map(|expr| (expr, false))
+                .chain(
+                    window_order
+                        .iter()
+                        .zip(first.order_by.iter().map(|t| t.descending)),
+                )
+                .map(|(expr, descending)| SortKey {
+                    expr: expr.clone(),
+                    order: KeyOrder {
+                        descending,
+                        collation: Collation::Binary,
+                    },
+                })
+                .collect();
+            if !keys.is_empty() {
+                operator = Operator::Sort(Sort::new(operator, keys, self.memory.reservation()));
+            }
+            operator = Operator::Window(Window::new(
+                operator,
+                partition_by,
+                window_order,
+                functions,
+                self.memory.reservation(),
+            ));
+            names.extend(group.iter().map(|window| window.to_string()));
+        }
+
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let exprs = result_columns
+            .iter()
+            .map(|expr| self.compile_expr(&replace_windows(expr)?, &names))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let order_by = order_by
+            .iter()
+            .map(|term| {
+                Ok(ast::OrderingTerm {
+                    expr: replace_windows(&term.expr)?,
+                    descending: term.descending,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.sort_and_project(&order_by, operator, &names, exprs)
+    }
 
+    /// Projects the rows of `input`, sorting them first when the query has an ORDER BY.
+    fn sort_and_project(
+        &self,
+        order_by: &[ast::OrderingTerm],
+        input: Operator,
+        columns: &[&str],
+        exprs: Vec<Expr>,
+    ) -> anyhow::Result<Operator> {
+        if order_by.is_empty() {
+            return Ok(Operator::Project(Project::new(input, exprs)));
+        }
+
+        let keys = order_by
+            .iter()
+            .map(|term| {
+                Ok(SortKey {
+                    expr: self.compile_expr(&term.expr, columns)?,
+                    order: KeyOrder {
+                        descending: term.descending,
+                        collation: self.collation(&term.expr, columns)?.unwrap_or_default(),
+                    },
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let sort = match self.top_n.get() {
+            Some(limit) => Operator::TopN(TopN::new(input, keys, limit)),
+            None => Operator::Sort(Sort::new(input, keys, self.memory.reservation())),
+        };
+
+        Ok(Operator::Project(Project::new(sort, exprs)))
+    }
+
+    /// Groups the rows of `input` with a hash aggregate, whose rows hold the group
+    /// values followed by the aggregate results. The result columns, HAVING clause and
+    /// ORDER BY terms are then computed from those, with each group expression and
+    /// aggregate call replaced by a reference to the column holding its value.
+    fn aggregate(
+        &self,
+        select: &ast::SelectStatement,
+        input: Operator,
+        columns: &[&str],
+    ) -> anyhow::Result<Operator> {
+        let group_by = select
+            .core
+            .group_by
+            .iter()
+            .map(|expr| self.compile_expr(expr, columns))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut aggregation = Aggregation {
+            group_by: &select.core.group_by,
+            names: select.core.group_by.iter().map(|e| e.to_string()).collect(),
+            aggregates: Vec::new(),
+        };
+
+        let mut result_columns = Vec::new();
         for res_col in &select.core.result_columns {
             match res_col {
-                ast::ResultColumn::Star => {
-                    for i in 0..table.columns.len() {
-                        columns.push(i);
+                ast::ResultColumn::Star | ast::ResultColumn::TableStar(_) => {
+                    for (_, name) in star_columns(res_col, columns)? {
+                        let column = ast::Expr::Column(ast::Column {
+                            table: None,
+                            name: name.to_string(),
+                        });
+                        result_columns.push(self.rewrite_aggregates(
+                            &column,
+                            columns,
+                            &mut aggregation,
+                        )?);
                     }
                 }
                 ast::ResultColumn::Expr(e) => {
-                    let ast::Expr::Column(col) = &e.expr;
-                    let (index, _) = table
-                        .columns
-                        .iter()
-                        .enumerate()
-                        .find(|(_, c)| c.name == col.name)
-                        .with_context(|| format!("invalid column name: {}", col.name))?;
-                    columns.push(index);
+                    result_columns.push(self.rewrite_aggregates(
+                        &e.expr,
+                        columns,
+                        &mut aggregation,
+                    )?);
+                }
+            }
+        }
+        let having = select
+            .core
+            .having
+            .as_ref()
+            .map(|expr| self.rewrite_aggregates(expr, columns, &mut aggregation))
+            .transpose()?;
+        let order_by = select
+            .order_by
+            .iter()
+            .map(|term| {
+                Ok(ast::OrderingTerm {
+                    expr: self.rewrite_aggregates(&term.expr, columns, &mut aggregation)?,
+                    descending: term.descending,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let aggregate = HashAggregate::new(
+            input,
+            group_by,
+            aggregation.aggregates,
+            self.memory.reservation(),
+        );
+        let mut operator = Operator::HashAggregate(aggregate);
+
+        let names: Vec<&str> = aggregation.names.iter().map(String::as_str).collect();
+        if let Some(having) = having {
+            let predicate = self.compile_expr(&having, &names)?;
+            operator = Operator::Filter(Filter::new(operator, predicate));
+        }
+        if is_windowed(select) {
+            return self.window(&result_columns, &order_by, operator, &names);
+        }
+        let exprs = result_columns
+            .iter()
+            .map(|expr| self.compile_expr(expr, &names))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.sort_and_project(&order_by, operator, &names, exprs)
+    }
+
+    /// Replaces the group expressions, aggregate calls and bare columns of `expr` with
+    /// references to the columns of the aggregated rows, adding the aggregates they
+    /// need to `aggregation`.
+    fn rewrite_aggregates(
+        &self,
+        expr: &ast::Expr,
+        columns: &[&str],
+        aggregation: &mut Aggregation,
+    ) -> anyhow::Result<ast::Expr> {
+        if let Some(i) = aggregation.group_by.iter().position(|e| e == expr) {
+            return Ok(aggregation.column(i));
+        }
+
+        let (function, args) = match expr {
+            ast::Expr::Function(call) => {
+                match function::aggregate_function(&call.name, call.args.len())? {
+                    Some(function) => (function, call.args.as_slice()),
+                    None => {
+                        return expr.try_map_children(|e| {
+                            self.rewrite_aggregates(e, columns, aggregation)
+                        });
+                    }
+                }
+            }
+            ast::Expr::Column(_) => (&function::BARE_COLUMN, std::slice::from_ref(expr)),
+            _ => {
+                return expr.try_map_children(|e| self.rewrite_aggregates(e, columns, aggregation));
+            }
+        };
+
+        let name = expr.to_string();
+        if let Some(i) = aggregation.names.iter().position(|n| *n == name) {
+            return Ok(aggregation.column(i));
+        }
+
+        let args = args
+            .iter()
+            .map(|arg| self.compile_expr(arg, columns))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        aggregation
+            .aggregates
+            .push(AggregateExpr { function, args });
+        aggregation.names.push(name);
+        Ok(aggregation.column(aggregation.names.len() - 1))
+    }
+
+    fn compile_result_columns(
+        &self,
+        result_columns: &[ast::ResultColumn],
+        columns: &[&str],
+    ) -> anyhow::Result<Vec<Expr>> {
+        let mut exprs = Vec::new();
+
+        for res_col in result_columns {
+            match res_col {
+                ast::ResultColumn::Star | ast::ResultColumn::TableStar(_) => {
+                    for (i, _) in star_columns(res_col, columns)? {
+                        exprs.push(Expr::Column(i));
+                    }
                 }
+                ast::ResultColumn::Expr(e) => exprs.push(self.compile_expr(&e.expr, columns)?),
             }
         }
 
-        Ok(Operator::SeqScan(SeqScan::new(
-            columns,
-            self.db.scanner(table.first_page),
-        )))
+        Ok(exprs)
+    }
+
+    pub(super) fn compile_expr(&self, expr: &ast::Expr, columns: &[&str]) -> anyhow::Result<Expr> {
+        match expr {
+            ast::Expr::Column(col) => Ok(Expr::Column(resolve_column(columns, col)?)),
+            ast::Expr::Literal(ast::Literal::Null) => Ok(Expr::Literal(OwnedValue::Null)),
+            ast::Expr::Literal(ast::Literal::String(s)) => Ok(Expr::string(s)),
+            ast::Expr::Literal(ast::Literal::Integer(i)) => Ok(Expr::Literal(OwnedValue::Int(*i))),
+            ast::Expr::Literal(ast::Literal::Real(r)) => Ok(Expr::Literal(OwnedValue::Float(*r))),
+            ast::Expr::Parameter(parameter) => Ok(Expr::Literal(self.params.value(parameter))),
+            ast::Expr::Function(call) => {
+                if function::aggregate_function(&call.name, call.args.len())?.is_some() {
+                    bail!("misuse of aggregate function {}()", call.name);
+                }
+                if call.star {
+                    bail!("wrong number of arguments to function {}()", call.name);
+                }
+                if matches!(call.name.as_str(), "coalesce" | "ifnull" | "iif") {
+                    return self.compile_conditional(call, columns);
+                }
+                let function =
+                    function::scalar_function(&call.name, call.args.len(), self.db.case_folding())?;
+                let args = call
+                    .args
+                    .iter()
+                    .map(|arg| self.compile_expr(arg, columns))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                Ok(Expr::Function(FunctionExpr::new(function, args)))
+            }
+            ast::Expr::Binary(binary) => {
+                let op = match binary.op {
+                    ast::BinaryOperator::Eq => Some(BinaryOp::Eq),
+                    ast::BinaryOperator::NotEq => Some(BinaryOp::NotEq),
+                    ast::BinaryOperator::Is => Some(BinaryOp::Is),
+                    ast::BinaryOperator::IsNot => Some(BinaryOp::IsNot),
+                    ast::BinaryOperator::Lt => Some(BinaryOp::Lt),
+                    ast::BinaryOperator::LtEq => Some(BinaryOp::LtEq),
+                    ast::BinaryOperator::Gt => Some(BinaryOp::Gt),
+                    ast::BinaryOperator::GtEq => Some(BinaryOp::GtEq),
+                    ast::BinaryOperator::And => Some(BinaryOp::And),
+                    ast::BinaryOperator::Or => Some(BinaryOp::Or),
+                    _ => None,
+                };
+                if let Some(op) = op {
+                    let lhs = self.compile_expr(&binary.lhs, columns)?;
+                    let rhs = self.compile_expr(&binary.rhs, columns)?;
+                    if let BinaryOp::And | BinaryOp::Or = op {
+                        return Ok(Expr::Binary(Box::new(BinaryExpr {
+                            op,
+                            lhs,
+                            rhs,
+                            collation: Collation::Binary,
+                        })));
+                    }
+                    let (lhs_affinity, rhs_affinity) =
+                        self.comparison_affinities(&binary.lhs, &binary.rhs, columns);
+                    return Ok(Expr::Binary(Box::new(BinaryExpr {
+                        op,
+                        lhs: with_affinity(lhs, lhs_affinity),
+                        rhs: with_affinity(rhs, rhs_affinity),
+                        collation: self.comparison_collation(&binary.lhs, &binary.rhs, columns)?,
+                    })));
+                }
+
+                let name = match binary.op {
+                    ast::BinaryOperator::Arrow => "->",
+                    ast::BinaryOperator::LongArrow => "->>",
+                    ast::BinaryOperator::Concat => "||",
+                    ast::BinaryOperator::Match => {
+                        bail!("MATCH is only supported in the WHERE clause of full-text tables")
+                    }
+                    op => bail!("unsupported operator: {op:?}"),
+                };
+                let function = function::scalar_function(name, 2, self.db.case_folding())?;
+                let args = vec![
+                    self.compile_expr(&binary.lhs, columns)?,
+                    self.compile_expr(&binary.rhs, columns)?,
+                ];
+                Ok(Expr::Function(FunctionExpr::new(function, args)))
+            }
+            ast::Expr::Window(window) => {
+                bail!("misuse of window function {}()", window.function.name)
+            }
+            ast::Expr::Unary(unary) => {
+                let op = match unary.op {
+                    ast::UnaryOperator::Not => UnaryOp::Not,
+                };
+                Ok(Expr::Unary(Box::new(UnaryExpr {
+                    op,
+                    expr: self.compile_expr(&unary.expr, columns)?,
+                })))
+            }
+            ast::Expr::Cast(cast) => Ok(Expr::Cast(Box::new(CastExpr {
+                expr: self.compile_expr(&cast.expr, columns)?,
+                affinity: Affinity::from_type_name(&cast.type_name),
+            }))),
+            // `x BETWEEN a AND b` is `x >= a AND x <= b`, and its negation
+            // `x < a OR x > b`.
+            ast::Expr::Between(between) => {
+                let expr = self.compile_expr(&between.expr, columns)?;
+                let low = self.compile_expr(&between.low, columns)?;
+                let high = self.compile_expr(&between.high, columns)?;
+                let low_collation =
+                    self.comparison_collation(&between.expr, &between.low, columns)?;
+                let high_collation =
+                    self.comparison_collation(&between.expr, &between.high, columns)?;
+                let (low_expr_affinity, low_affinity) =
+                    self.comparison_affinities(&between.expr, &between.low, columns);
+                let (high_expr_affinity, high_affinity) =
+                    self.comparison_affinities(&between.expr, &between.high, columns);
+                let (op, low_op, high_op) = match between.negated {
+                    false => (BinaryOp::And, BinaryOp::GtEq, BinaryOp::LtEq),
+                    true => (BinaryOp::Or, BinaryOp::Lt, BinaryOp::Gt),
+                };
+                let binary = |op, lhs, rhs, collation| {
+                    Expr::Binary(Box::new(BinaryExpr {
+                        op,
+                        lhs,
+                        rhs,
+                        collation,
+                    }))
+                };
+                Ok(binary(
+                    op,
+                    binary(
+                        low_op,
+                        with_affinity(expr.clone(), low_expr_affinity),
+                        with_affinity(low, low_affinity),
+                        low_collation,
+                    ),
+                    binary(
+                        high_op,
+                        with_affinity(expr, high_expr_affinity),
+                        with_affinity(high, high_affinity),
+                        high_collation,
+                    ),
+                    Collation::Binary,
+                ))
+            }
+            // `x LIKE pattern ESCAPE e` is `like(pattern, x, e)`.
+            ast::Expr::Like(like) => {
+                let args = [&like.pattern, &like.expr]
+                    .into_iter()
+                    .chain(&like.escape)
+                    .map(|arg| self.compile_expr(arg, columns))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let function =
+                    function::scalar_function("like", args.len(), self.db.case_folding())?;
+                let like_expr = Expr::Function(FunctionExpr::new(function, args));
+                Ok(match like.negated {
+                    true => Expr::Unary(Box::new(UnaryExpr {
+                        op: UnaryOp::Not,
+                        expr: like_expr,
+                    })),
+                    false => like_expr,
+                })
+            }
+            // The collation only changes how the value compares, which is decided by
+            // the comparisons and sorts using it.
+            ast::Expr::Collate(collate) => {
+                collation_named(&collate.collation)?;
+                self.compile_expr(&collate.expr, columns)
+            }
+        }
+    }
+
+    /// The collating sequence of `expr` when compared or sorted: the one given by a
+    /// COLLATE clause, or the one declared by the column it refers to. Explicit ones
+    /// are returned with `true`, as they take precedence in comparisons.
+    fn operand_collation(
+        &self,
+        expr: &ast::Expr,
+        columns: &[&str],
+    ) -> anyhow::Result<Option<(Collation, bool)>> {
+        match expr {
+            ast::Expr::Collate(collate) => {
+                let collation = collation_named(&collate.collation)?;
+                Ok(Some((
+                    collation.with_case_folding(self.db.case_folding()),
+                    true,
+                )))
+            }
+            ast::Expr::Column(col) => Ok(resolve_column(columns, col)
+                .ok()
+                .and_then(|i| self.collations.borrow().get(columns[i]).copied())
+                .map(|collation| (collation, false))),
+            // An explicit collation applies to the expressions computed from it.
+            _ => {
+                for child in expr.children() {
+                    if let Some((collation, true)) = self.operand_collation(child, columns)? {
+                        return Ok(Some((collation, true)));
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    fn collation(&self, expr: &ast::Expr, columns: &[&str]) -> anyhow::Result<Option<Collation>> {
+        Ok(self
+            .operand_collation(expr, columns)?
+            .map(|(collation, _)| collation))
+    }
+
+    /// Follows SQLite: an explicit collation wins, the left operand's first, then the
+    /// collation of a column operand.
+    fn comparison_collation(
+        &self,
+        lhs: &ast::Expr,
+        rhs: &ast::Expr,
+        columns: &[&str],
+    ) -> anyhow::Result<Collation> {
+        let lhs = self.operand_collation(lhs, columns)?;
+        let rhs = self.operand_collation(rhs, columns)?;
+        Ok(match (lhs, rhs) {
+            (Some((collation, true)), _) | (_, Some((collation, true))) => collation,
+            (Some((collation, _)), _) | (_, Some((collation, _))) => collation,
+            (None, None) => Collation::Binary,
+        })
+    }
+
+    /// The affinity of `expr` when compared: the one of the column it refers to, or the
+    /// type it is cast to. Other expressions have none.
+    fn operand_affinity(&self, expr: &ast::Expr, columns: &[&str]) -> Option<Affinity> {
+        match expr {
+            ast::Expr::Column(col) => resolve_column(columns, col)
+                .ok()
+                .and_then(|i| self.affinities.borrow().get(columns[i]).copied()),
+            ast::Expr::Cast(cast) => Some(Affinity::from_type_name(&cast.type_name)),
+            ast::Expr::Collate(collate) => self.operand_affinity(&collate.expr, columns),
+            _ => None,
+        }
+    }
+
+    /// The affinities applied to the operands of a comparison before comparing them.
+    /// Follows SQLite: an operand with a numeric affinity applies NUMERIC to an operand
+    /// with a text, blob or no affinity, and one with a text affinity applies TEXT to
+    /// an operand with none.
+    fn comparison_affinities(
+        &self,
+        lhs: &ast::Expr,
+        rhs: &ast::Expr,
+        columns: &[&str],
+    ) -> (Option<Affinity>, Option<Affinity>) {
+        let lhs = self.operand_affinity(lhs, columns);
+        let rhs = self.operand_affinity(rhs, columns);
+        let applied = |affinity: Option<Affinity>, other: Option<Affinity>| match (affinity, other)
+        {
+            (Some(a), other) if a.is_numeric() && !other.is_some_and(Affinity::is_numeric) => {
+                Some(Affinity::Numeric)
+            }
+            (Some(Affinity::Text), None) => Some(Affinity::Text),
+            _ => None,
+        };
+        (applied(rhs, lhs), applied(lhs, rhs))
+    }
+
+    /// Compiles the functions that only evaluate the arguments they return.
+    fn compile_conditional(
+        &self,
+        call: &ast::FunctionCall,
+        columns: &[&str],
+    ) -> anyhow::Result<Expr> {
+        let arg_count = call.args.len();
+        let valid = match call.name.as_str() {
+            "coalesce" => arg_count >= 2,
+            "ifnull" => arg_count == 2,
+            _ => arg_count == 3,
+        };
+        ensure!(
+            valid,
+            "wrong number of arguments to function {}()",
+            call.name
+        );
+
+        let mut args = call
+            .args
+            .iter()
+            .map(|arg| self.compile_expr(arg, columns))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if call.name != "iif" {
+            return Ok(Expr::Coalesce(args));
+        }
+        let otherwise = args.pop().unwrap();
+        let then = args.pop().unwrap();
+        let condition = args.pop().unwrap();
+        Ok(Expr::Iif(Box::new(IifExpr {
+            condition,
+            then,
+            otherwise,
+        })))
+    }
+}
+
+/// Names `columns` as `table.column`, which unqualified references also resolve to.
+/// The qualified names of the columns of a table scanned as `table`, followed by its
+/// hidden rowid column if it has one.
+fn table_columns(table: &str, definition: &TableDef) -> Vec<String> {
+    let mut columns = qualified_columns(table, definition.columns.iter().map(|c| c.name.as_str()));
+    if definition.has_rowid() {
+        columns.push(format!("{table}.{ROWID_COLUMN}"));
+    }
+    columns
+}
+
+fn qualified_columns<'a>(table: &str, columns: impl Iterator<Item = &'a str>) -> Vec<String> {
+    columns.map(|column| format!("{table}.{column}")).collect()
+}
+
+/// Applies `affinity`, if any, to the values of `expr`, folding it into constants.
+fn with_affinity(expr: Expr, affinity: Option<Affinity>) -> Expr {
+    match (expr, affinity) {
+        (expr, None) => expr,
+        (Expr::Literal(value), Some(affinity)) => Expr::Literal(value.apply_affinity(affinity)),
+        (expr, Some(affinity)) => Expr::Affinity(Box::new(CastExpr { expr, affinity })),
+    }
+}
+
+/// The columns of a table stored in its records.
+struct StoredColumns {
+    names: Vec<String>,
+    affinities: Vec<Affinity>,
+    /// The stored columns read from the rowid of the records.
+    rowid_columns: Vec<usize>,
+    /// When the table has virtual generated columns, or doesn't store its columns in
+    /// their order, the expressions computing every column from the stored ones.
+    generated: Option<Vec<Expr>>,
+}
+
+/// The expression computing the virtual column `column` of a table, with the
+/// references to the other virtual columns replaced by their own expressions.
+fn virtual_expr(definition: &TableDef, column: usize, depth: usize) -> anyhow::Result<ast::Expr> {
+    let name = &definition.columns[column].name;
+    ensure!(
+        depth <= definition.columns.len(),
+        "generated column loop on \"{name}\""
+    );
+    let Some((expr, false)) = definition.columns[column].generated() else {
+        bail!("not a virtual column: {name}");
+    };
+    replace_virtual_columns(expr, definition, depth)
+}
+
+fn replace_virtual_columns(
+    expr: &ast::Expr,
+    definition: &TableDef,
+    depth: usize,
+) -> anyhow::Result<ast::Expr> {
+    if let ast::Expr::Column(column) = expr
+        && let Some(i) = definition
+            .columns
+            .iter()
+            .position(|c| c.name.eq_ignore_ascii_case(&column.name))
+        && matches!(definition.columns[i].generated(), Some((_, false)))
+    {
+        return virtual_expr(definition, i, depth + 1);
+    }
+    expr.try_map_children(|e| replace_virtual_columns(e, definition, depth))
+}
+
+/// Replaces the ORDER BY terms naming a result column, by its alias or its position,
+/// with the expression computing it. Like in SQLite, a term that is only an alias
+/// names the result column even when a column of the source has the same name,
+/// whereas the columns win within larger expressions.
+fn resolve_order_by(
+    select: &ast::SelectStatement,
+    columns: &[&str],
+) -> anyhow::Result<Vec<ast::OrderingTerm>> {
+    let mut outputs: Vec<(Option<&str>, ast::Expr)> = Vec::new();
+    for res_col in &select.core.result_columns {
+        match res_col {
+            ast::ResultColumn::Expr(e) => outputs.push((e.alias.as_deref(), e.expr.clone())),
+            star => outputs.extend(star_columns(star, columns)?.into_iter().map(|(_, name)| {
+                let column = ast::Column {
+                    table: None,
+                    name: name.to_string(),
+                };
+                (None, ast::Expr::Column(column))
+            })),
+        }
+    }
+    let aliased = |name: &str| {
+        outputs
+            .iter()
+            .find(|(alias, _)| alias.is_some_and(|alias| alias.eq_ignore_ascii_case(name)))
+            .map(|(_, expr)| expr.clone())
+    };
+
+    let mut terms = Vec::new();
+    for (i, term) in select.order_by.iter().enumerate() {
+        // The collation of a term doesn't change the column it names.
+        let (expr, collation) = match &term.expr {
+            ast::Expr::Collate(collate) => (collate.expr.as_ref(), Some(&collate.collation)),
+            expr => (expr, None),
+        };
+        let resolved = match expr {
+            ast::Expr::Literal(ast::Literal::Integer(n)) => {
+                let position = usize::try_from(*n)
+                    .ok()
+                    .filter(|n| (1..=outputs.len()).contains(n));
+                let Some(position) = position else {
+                    bail!(
+                        "{} ORDER BY term out of range - should be between 1 and {}",
+                        ordinal(i + 1),
+                        outputs.len()
+                    );
+                };
+                Some(outputs[position - 1].1.clone())
+            }
+            ast::Expr::Column(ast::Column { table: None, name }) => aliased(name),
+            _ => None,
+        };
+
+        let expr = match (resolved, collation) {
+            (Some(expr), Some(collation)) => ast::Expr::Collate(ast::CollateExpr {
+                expr: Box::new(expr),
+                collation: collation.clone(),
+            }),
+            (Some(expr), None) => expr,
+            (None, _) => replace_aliases(&term.expr, columns, &aliased)?,
+        };
+        terms.push(ast::OrderingTerm {
+            expr,
+            descending: term.descending,
+        });
+    }
+    Ok(terms)
+}
+
+/// Replaces the references to result column aliases that aren't columns of the
+/// source.
+fn replace_aliases(
+    expr: &ast::Expr,
+    columns: &[&str],
+    aliased: &dyn Fn(&str) -> Option<ast::Expr>,
+) -> anyhow::Result<ast::Expr> {
+    if let ast::Expr::Column(column @ ast::Column { table: None, name }) = expr
+        && resolve_column(columns, column).is_err()
+        && let Some(expr) = aliased(name)
+    {
+        return Ok(expr);
+    }
+    expr.try_map_children(|e| replace_aliases(e, columns, aliased))
+}
+
+/// Formats `n` as an English ordinal, e.g. 1st or 12th.
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{n}{suffix}")
+}
+
+fn collation_named(name: &str) -> anyhow::Result<Collation> {
+    Collation::from_name(name).with_context(|| format!("no such collation sequence: {name}"))
+}
+
+/// The names SQLite gives to the columns of a VALUES clause.
+fn values_columns(width: usize) -> Vec<String> {
+    (1..=width).map(|i| format!("column{i}")).collect()
+}
+
+/// The columns selected by `*`, or by `table.*`, with their index in `columns`.
+fn star_columns<'c>(
+    result_column: &ast::ResultColumn,
+    columns: &[&'c str],
+) -> anyhow::Result<Vec<(usize, &'c str)>> {
+    let all = columns
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|(_, column)| !is_rowid_column(column));
+    match result_column {
+        ast::ResultColumn::Star => Ok(all.collect()),
+        ast::ResultColumn::TableStar(table) => {
+            let selected: Vec<_> = all
+                .filter(|(_, column)| {
+                    column
+                        .split_once('.')
+                        .is_some_and(|(t, _)| t.eq_ignore_ascii_case(table))
+                })
+                .collect();
+            ensure!(!selected.is_empty(), "no such table: {table}");
+            Ok(selected)
+        }
+        ast::ResultColumn::Expr(_) => Ok(Vec::new()),
+    }
+}
+
+/// The name of the hidden column holding the rowid of the rows of a table, which
+/// queries refer to by one of `ROWID_NAMES` unless the table has a column named so.
+/// It can't be written as an identifier, and isn't part of `*`.
+pub(super) const ROWID_COLUMN: &str = "#rowid";
+pub(super) const ROWID_NAMES: [&str; 3] = ["rowid", "_rowid_", "oid"];
+
+fn is_rowid_column(column: &str) -> bool {
+    column
+        .rsplit_once('.')
+        .is_some_and(|(_, name)| name == ROWID_COLUMN)
+}
+
+/// Finds the column named by `col` in `columns`, whose names may be qualified by their
+/// table.
+fn resolve_column(columns: &[&str], col: &ast::Column) -> anyhow::Result<usize> {
+    let matches = |column: &str, name: &str| match &col.table {
+        Some(table) => {
+            column
+                .get(..table.len())
+                .is_some_and(|t| t.eq_ignore_ascii_case(table))
+                && column[table.len()..]
+                    .strip_prefix('.')
+                    .is_some_and(|c| c.eq_ignore_ascii_case(name))
+        }
+        None => {
+            column.eq_ignore_ascii_case(name)
+                || column
+                    .rsplit_once('.')
+                    .is_some_and(|(_, column)| column.eq_ignore_ascii_case(name))
+        }
+    };
+    let find = |name: &str| -> Vec<usize> {
+        (0..columns.len())
+            .filter(|&i| matches(columns[i], name))
+            .collect()
+    };
+
+    let mut found = find(&col.name);
+    if found.is_empty()
+        && ROWID_NAMES
+            .iter()
+            .any(|n| n.eq_ignore_ascii_case(&col.name))
+    {
+        found = find(ROWID_COLUMN);
+        // SQLite reports a rowid of several tables as missing rather than ambiguous.
+        if found.len() > 1 {
+            found.clear();
+        }
+    }
+    match found[..] {
+        [] => bail!("invalid column name: {}", ast::Expr::Column(col.clone())),
+        [index] => Ok(index),
+        _ => bail!("ambiguous column name: {}", ast::Expr::Column(col.clone())),
+    }
+}
+
+/// The columns of the rows produced by a hash aggregate, named after the expressions
+/// computing them.
+struct Aggregation<'a> {
+    group_by: &'a [ast::Expr],
+    names: Vec<String>,
+    aggregates: Vec<AggregateExpr>,
+}
+
+impl Aggregation<'_> {
+    fn column(&self, i: usize) -> ast::Expr {
+        ast::Expr::Column(ast::Column {
+            table: None,
+            name: self.names[i].clone(),
+        })
+    }
+}
+
+/// Whether the query groups its rows, or computes aggregates over them.
+fn is_aggregate(select: &ast::SelectStatement) -> bool {
+    !select.core.group_by.is_empty()
+        || select.core.having.is_some()
+        || result_exprs(select).any(contains_aggregate)
+}
+
+/// Whether the rows read by the query can arrive in any order without changing its
+/// result, as when it only counts them.
+fn ignores_row_order(select: &ast::SelectStatement) -> bool {
+    fn counts(expr: &ast::Expr) -> bool {
+        match expr {
+            ast::Expr::Function(call) if call.name.eq_ignore_ascii_case("count") => true,
+            ast::Expr::Column(_) => false,
+            _ => expr.children().into_iter().all(counts),
+        }
+    }
+    select.core.group_by.is_empty()
+        && is_aggregate(select)
+        && result_exprs(select).chain(&select.core.having).all(counts)
+}
+
+fn contains_aggregate(expr: &ast::Expr) -> bool {
+    // An aggregate called with the wrong number of arguments still makes the query an
+    // aggregate, whose planning reports the error.
+    let is_aggregate = |call: &ast::FunctionCall| {
+        !function::aggregate_function(&call.name, call.args.len()).is_ok_and(|f| f.is_none())
+    };
+    matches!(expr, ast::Expr::Function(call) if is_aggregate(call))
+        || expr.children().into_iter().any(contains_aggregate)
+}
+
+/// Whether the query computes window functions in its result columns or ORDER BY.
+fn is_windowed(select: &ast::SelectStatement) -> bool {
+    result_exprs(select).any(contains_window)
+}
+
+fn contains_window(expr: &ast::Expr) -> bool {
+    matches!(expr, ast::Expr::Window(_)) || expr.children().into_iter().any(contains_window)
+}
+
+/// Replaces the window function calls of `expr` with references to the columns
+/// holding their values, named after them.
+fn replace_windows(expr: &ast::Expr) -> anyhow::Result<ast::Expr> {
+    match expr {
+        ast::Expr::Window(window) => Ok(ast::Expr::Column(ast::Column {
+            table: None,
+            name: window.to_string(),
+        })),
+        _ => expr.try_map_children(replace_windows),
+    }
+}
+
+/// Adds the window function calls of `expr` to `windows`, once each.
+fn collect_windows<'a>(expr: &'a ast::Expr, windows: &mut Vec<&'a ast::WindowCall>) {
+    match expr {
+        ast::Expr::Window(window) => {
+            if !windows.contains(&window) {
+                windows.push(window);
+            }
+        }
+        _ => {
+            for child in expr.children() {
+                collect_windows(child, windows);
+            }
+        }
+    }
+}
+
+/// The expressions of the result columns and ORDER BY terms of the query.
+fn result_exprs(select: &ast::SelectStatement) -> impl Iterator<Item = &ast::Expr> {
+    let result_columns = select.core.result_columns.iter().filter_map(|c| match c {
+        ast::ResultColumn::Star | ast::ResultColumn::TableStar(_) => None,
+        ast::ResultColumn::Expr(e) => Some(&e.expr),
+    });
+    result_columns.chain(select.order_by.iter().map(|term| &term.expr))
+}
+
+/// Marks the columns of `columns` the query refers to, unless it selects them all with
+/// a `*`: those of the tables it selects with `table.*`, and those its expressions and
+/// join constraints use. Names that don't resolve, e.g. aliases of result columns, are
+/// left to the planning of the expressions using them.
+fn referenced_columns(select: &ast::SelectStatement, columns: &[&str]) -> Option<Vec<bool>> {
+    let star = |c: &ast::ResultColumn| matches!(c, ast::ResultColumn::Star);
+    if select.core.result_columns.iter().any(star) {
+        return None;
+    }
+
+    let mut referenced = vec![false; columns.len()];
+    for result_column in &select.core.result_columns {
+        // An unknown table is reported by the planning of the result columns.
+        for (i, _) in star_columns(result_column, columns).unwrap_or_default() {
+            referenced[i] = true;
+        }
+    }
+    let mut exprs: Vec<&ast::Expr> = result_exprs(select)
+        .chain(&select.core.where_clause)
+        .chain(&select.core.group_by)
+        .chain(&select.core.having)
+        .chain(join_constraints(&select.core.from))
+        .collect();
+    while let Some(expr) = exprs.pop() {
+        if let ast::Expr::Column(column) = expr
+            && let std::result::Result::Ok(i) = resolve_column(columns, column)
+        {
+            referenced[i] = true;
+        }
+        exprs.extend(expr.children());
+    }
+    Some(referenced)
+}
+
+/// The ON constraints of the joins of a FROM clause.
+fn join_constraints(from: &SelectFrom) -> Vec<&ast::Expr> {
+    match from {
+        SelectFrom::Join(join) => {
+            let mut constraints = join_constraints(&join.left);
+            constraints.extend(join_constraints(&join.right));
+            constraints.extend(&join.constraint);
+            constraints
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// The rowids within the bounds `filters` set on the `rowid_columns`, and the number
+/// of bounds set.
+fn rowid_range(filters: &[ScanFilter], rowid_columns: &[usize]) -> (RangeInclusive<i64>, usize) {
+    let (mut start, mut end, mut bounds) = (i64::MIN, i64::MAX, 0);
+    for filter in filters {
+        let SendValue::Int(rowid) = filter.value else {
+            continue;
+        };
+        if !rowid_columns.contains(&filter.column) {
+            continue;
+        }
+        match filter.op {
+            BinaryOp::Gt => start = start.max(rowid.saturating_add(1)),
+            BinaryOp::GtEq => start = start.max(rowid),
+            BinaryOp::Lt => end = end.min(rowid.saturating_sub(1)),
+            BinaryOp::LtEq => end = end.min(rowid),
+            _ => continue,
+        }
+        bounds += 1;
+    }
+    (start..=end, bounds.min(2))
+}
+
+/// A way of reading a table through one of its indexes, from the first key within the
+/// bounds on its first column, `column` of the table, to the last.
+struct IndexSearch<'m> {
+    index: &'m IndexMetadata,
+    column: usize,
+    /// The collation the keys are ordered by, which the bounds must compare with.
+    collation: Collation,
+    lower: Option<IndexBound>,
+    upper: Option<IndexBound>,
+    /// Whether the keys hold all the columns the query refers to.
+    covering: bool,
+}
+
+impl IndexSearch<'_> {
+    fn is_equality(&self) -> bool {
+        matches!((&self.lower, &self.upper), (Some(lower), Some(upper))
+            if lower.inclusive && upper.inclusive && lower.value == upper.value)
+    }
+}
+
+/// The terms of a conjunction, e.g. `a`, `b` and `c` for `a AND (b AND c)`.
+pub(super) fn conjuncts(expr: &ast::Expr) -> Vec<&ast::Expr> {
+    match expr {
+        ast::Expr::Binary(binary) if binary.op == ast::BinaryOperator::And => {
+            let mut terms = conjuncts(&binary.lhs);
+            terms.extend(conjuncts(&binary.rhs));
+            terms
+        }
+        expr => vec![expr],
+    }
+}
+
+/// Splits the WHERE clause of a join between the terms only referring to the columns
+/// of one of `tables`, pushed down into its scan, and the rest of the clause. The
+/// pushed terms are keyed by the position of the first column of their table among
+/// the `columns` of the join.
+fn push_down_filters(
+    where_clause: Option<&ast::Expr>,
+    columns: &[&str],
+    tables: &[Range<usize>],
+) -> (HashMap<usize, ast::Expr>, Option<ast::Expr>) {
+    let mut pushed = HashMap::new();
+    let mut rest = None;
+    for term in where_clause.map(conjuncts).unwrap_or_default() {
+        let table = match contains_aggregate(term) || contains_window(term) {
+            true => None,
+            false => referenced_table(term, columns, tables),
+        };
+        match table {
+            Some(start) => {
+                let terms = pushed.remove(&start);
+                pushed.insert(start, and(terms, term.clone()));
+            }
+            None => rest = Some(and(rest, term.clone())),
+        }
+    }
+    (pushed, rest)
+}
+
+/// The conjunction of `term` with the `terms` before it, if any.
+fn and(terms: Option<ast::Expr>, term: ast::Expr) -> ast::Expr {
+    match terms {
+        Some(terms) => ast::Expr::Binary(ast::BinaryExpr {
+            op: ast::BinaryOperator::And,
+            lhs: Box::new(terms),
+            rhs: Box::new(term),
+        }),
+        None => term,
+    }
+}
+
+/// The start of the range among `tables` holding all the columns of `columns` that
+/// `expr` refers to, if it refers to some, and they all resolve.
+fn referenced_table(expr: &ast::Expr, columns: &[&str], tables: &[Range<usize>]) -> Option<usize> {
+    let mut table = None;
+    let mut exprs = vec![expr];
+    while let Some(expr) = exprs.pop() {
+        if let ast::Expr::Column(column) = expr {
+            let i = resolve_column(columns, column).ok()?;
+            let range = tables.iter().find(|range| range.contains(&i))?;
+            if table.is_some_and(|start| start != range.start) {
+                return None;
+            }
+            table = Some(range.start);
+        }
+        exprs.extend(expr.children());
+    }
+    table
+}
+
+/// Flattens a conjunction of `column op number` comparisons into R-Tree constraints.
+fn collect_rtree_constraints(
+    expr: &ast::Expr,
+    columns: &[&str],
+    constraints: &mut Vec<Constraint>,
+) -> anyhow::Result<()> {
+    let ast::Expr::Binary(binary) = expr else {
+        bail!("unsupported rtree constraint: {expr:?}");
+    };
+
+    let op = match binary.op {
+        ast::BinaryOperator::And => {
+            collect_rtree_constraints(&binary.lhs, columns, constraints)?;
+            return collect_rtree_constraints(&binary.rhs, columns, constraints);
+        }
+        ast::BinaryOperator::Eq => ConstraintOp::Eq,
+        ast::BinaryOperator::Lt => ConstraintOp::Lt,
+        ast::BinaryOperator::LtEq => ConstraintOp::LtEq,
+        ast::BinaryOperator::Gt => ConstraintOp::Gt,
+        ast::BinaryOperator::GtEq => ConstraintOp::GtEq,
+        op => bail!("unsupported rtree constraint operator: {op:?}"),
+    };
+
+    let (column, op, value) = match (binary.lhs.as_ref(), binary.rhs.as_ref()) {
+        (ast::Expr::Column(col), ast::Expr::Literal(lit)) => (col, op, lit),
+        (ast::Expr::Literal(lit), ast::Expr::Column(col)) => (col, op.flip(), lit),
+        _ => bail!("rtree constraints must compare a column with a number"),
+    };
+
+    let value = match value {
+        ast::Literal::Integer(i) => *i as f64,
+        ast::Literal::Real(r) => *r,
+        ast::Literal::String(s) => bail!("expected a number in rtree constraint, got '{s}'"),
+        ast::Literal::Null => bail!("expected a number in rtree constraint, got NULL"),
+    };
+
+    let column = columns
+        .iter()
+        .position(|&c| c.eq_ignore_ascii_case(&column.name))
+        .with_context(|| format!("invalid column name: {}", column.name))?;
+
+    constraints.push(Constraint { column, op, value });
+    Ok(())
+}
+
+/// The columns of the rows of `statement`, named after their alias, the column they
+/// select, or the SQL of the expression they compute.
+pub fn result_schema(
+    metadata: &SchemaMetadata,
+    statement: &ast::Statement,
+) -> anyhow::Result<Schema> {
+    let named = |names: &[&str]| {
+        Ok(Schema::new(
+            names.iter().copied().map(ColumnMetadata::named).collect(),
+        ))
+    };
+    let select = match statement {
+        ast::Statement::Select(select) => select,
+        ast::Statement::Explain(_) | ast::Statement::ExplainAnalyze(_) => return named(&["plan"]),
+        ast::Statement::ExplainQueryPlan(_) => {
+            return named(&["id", "parent", "notused", "detail"]);
+        }
+        ast::Statement::Pragma(pragma) => return named(&[&pragma.name]),
+        _ => return Ok(Schema::default()),
+    };
+
+    let sources = source_columns(metadata, &select.core.from)?;
+
+    let mut columns = Vec::new();
+    for res_col in &select.core.result_columns {
+        match res_col {
+            ast::ResultColumn::Star => columns.extend(sources.iter().map(|(_, c)| c.clone())),
+            ast::ResultColumn::TableStar(table) => columns.extend(
+                sources
+                    .iter()
+                    .filter(|(t, _)| t.eq_ignore_ascii_case(table))
+                    .map(|(_, c)| c.clone()),
+            ),
+            ast::ResultColumn::Expr(e) => {
+                // Only the values of a column keep its type and table, as in SQLite.
+                let source = match &e.expr {
+                    ast::Expr::Column(column) => sources.iter().find(|(table, source)| {
+                        source.name.eq_ignore_ascii_case(&column.name)
+                            && column
+                                .table
+                                .as_ref()
+                                .is_none_or(|t| t.eq_ignore_ascii_case(table))
+                    }),
+                    _ => None,
+                };
+                let name = match &e.alias {
+                    Some(alias) => alias.clone(),
+                    None => e.expr.to_string(),
+                };
+                columns.push(ColumnMetadata {
+                    name,
+                    ..source.map(|(_, c)| c.clone()).unwrap_or_default()
+                });
+            }
+        }
+    }
+
+    Ok(Schema::new(columns))
+}
+
+/// The columns of the rows of a FROM clause, with the name they're qualified by.
+fn source_columns(
+    metadata: &SchemaMetadata,
+    from: &SelectFrom,
+) -> anyhow::Result<Vec<(String, ColumnMetadata)>> {
+    Ok(match from {
+        SelectFrom::Table { name, alias } => {
+            let table = metadata
+                .table(name)
+                .with_context(|| format!("invalid table name: {name}"))?;
+            let qualifier = alias.as_ref().unwrap_or(name);
+            table
+                .definition()?
+                .columns
+                .iter()
+                .map(|c| {
+                    let column = ColumnMetadata {
+                        name: c.name.clone(),
+                        decl_type: c.col_type.clone(),
+                        table: Some(table.name.clone()),
+                    };
+                    (qualifier.clone(), column)
+                })
+                .collect()
+        }
+        SelectFrom::Function(call) => function::table_function(&call.name, call.args.len())?
+            .columns
+            .iter()
+            .map(|c| (call.name.clone(), ColumnMetadata::named(*c)))
+            .collect(),
+        SelectFrom::Values { rows, alias } => {
+            let qualifier = alias.clone().unwrap_or_default();
+            values_columns(rows[0].len())
+                .into_iter()
+                .map(|c| (qualifier.clone(), ColumnMetadata::named(c)))
+                .collect()
+        }
+        SelectFrom::Join(join) => {
+            let mut columns = source_columns(metadata, &join.left)?;
+            columns.extend(source_columns(metadata, &join.right)?);
+            columns
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql;
+
+    fn order_by(sql: &str) -> anyhow::Result<Vec<String>> {
+        let ast::Statement::Select(select) = sql::parse_statement(sql, false)? else {
+            panic!("expected a select statement");
+        };
+        let terms = resolve_order_by(&select, &["t.a", "t.b"])?;
+        Ok(terms.iter().map(|term| term.to_string()).collect())
+    }
+
+    #[test]
+    fn order_by_result_columns() {
+        assert_eq!(
+            order_by("select b, a || 'x' as a from t order by 2 desc, a, a || b, 1 collate nocase")
+                .unwrap(),
+            vec!["a || 'x' DESC", "a || 'x'", "a || b", "b COLLATE nocase"]
+        );
+        assert_eq!(
+            order_by("select b || a as c, * from t order by c || 1, 3").unwrap(),
+            vec!["b || a || 1", "t.b"]
+        );
+
+        let error = order_by("select a from t order by 2").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "1st ORDER BY term out of range - should be between 1 and 1"
+        );
+        assert!(order_by("select a from t order by a, 0").is_err());
+    }
+
+    #[test]
+    fn rowid_columns() {
+        let column = |table: Option<&str>, name: &str| ast::Column {
+            table: table.map(str::to_string),
+            name: name.to_string(),
+        };
+        let columns = ["t.a", "t.oid", "t.#rowid", "u.b", "u.#rowid"];
+
+        assert_eq!(
+            resolve_column(&columns, &column(None, "_ROWID_")).ok(),
+            None
+        );
+        assert_eq!(
+            resolve_column(&columns, &column(Some("t"), "rowid")).unwrap(),
+            2
+        );
+        assert_eq!(
+            resolve_column(&columns, &column(Some("u"), "oid")).unwrap(),
+            4
+        );
+        assert_eq!(resolve_column(&columns, &column(None, "oid")).unwrap(), 1);
+        assert_eq!(
+            star_columns(&ast::ResultColumn::Star, &columns).unwrap(),
+            vec![(0, "t.a"), (1, "t.oid"), (3, "u.b")]
+        );
+    }
+
+    #[test]
+    fn where_conjuncts() {
+        let ast::Statement::Select(select) = sql::parse_statement(
+            "select * from t where a = 1 and (b < 2 or c) and 3 > d",
+            false,
+        )
+        .unwrap() else {
+            panic!("expected a select statement");
+        };
+        let where_clause = select.core.where_clause.unwrap();
+        let terms: Vec<String> = conjuncts(&where_clause)
+            .iter()
+            .map(|term| term.to_string())
+            .collect();
+        assert_eq!(terms, vec!["a = 1", "b < 2 OR c", "3 > d"]);
+    }
+
+    #[test]
+    fn referenced_table_columns() {
+        let referenced = |sql: &str| {
+            let ast::Statement::Select(select) = sql::parse_statement(sql, false).unwrap() else {
+                panic!("expected a select statement");
+            };
+            referenced_columns(&select, &["t.a", "t.b", "t.c", "t.d", "t.#rowid"])
+        };
+
+        assert_eq!(
+            referenced("select upper(a) as x from t where b > 1 order by x, rowid"),
+            Some(vec![true, true, false, false, true])
+        );
+        assert_eq!(
+            referenced("select count(*) from t group by c having max(t.d) > 0"),
+            Some(vec![false, false, true, true, false])
+        );
+        assert_eq!(referenced("select *, a from t"), None);
+
+        // The columns of a join, marked across its tables and constraints.
+        let referenced = |sql: &str| {
+            let ast::Statement::Select(select) = sql::parse_statement(sql, false).unwrap() else {
+                panic!("expected a select statement");
+            };
+            let columns = ["t.a", "t.b", "t.#rowid", "u.a", "u.c", "u.#rowid"];
+            referenced_columns(&select, &columns)
+        };
+        assert_eq!(
+            referenced("select t.b from t join u on u.c = t.rowid where u.a > 1"),
+            Some(vec![false, true, true, true, true, false])
+        );
+        assert_eq!(
+            referenced("select u.*, count(*) from t left join u on c = b group by t.a"),
+            Some(vec![true, true, false, true, true, false])
+        );
+    }
+
+    #[test]
+    fn rowid_bounds() {
+        let filter = |column, op, rowid| ScanFilter {
+            column,
+            op,
+            value: SendValue::Int(rowid),
+            collation: Collation::Binary,
+        };
+        let filters = [
+            filter(0, BinaryOp::Gt, 10),
+            filter(2, BinaryOp::GtEq, 5),
+            filter(2, BinaryOp::LtEq, 20),
+            filter(2, BinaryOp::Lt, i64::MIN),
+        ];
+
+        assert_eq!(rowid_range(&filters[..3], &[2]), (5..=20, 2));
+        assert_eq!(rowid_range(&filters[..3], &[0, 2]), (11..=20, 2));
+        assert_eq!(rowid_range(&filters[..1], &[2]), (i64::MIN..=i64::MAX, 0));
+        assert_eq!(rowid_range(&filters[3..], &[2]).0, i64::MIN..=i64::MIN);
     }
 }
@@ -0,0 +1,185 @@
+use std::{
+    cell::Cell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    db::{Db, RunningQuery},
+    pager::{FileVersion, IoStats},
+    sql::ast,
+    value::SendValue,
+    vfs::SharedLock,
+};
+
+use super::{
+    memory::MemoryTracker,
+    operator::{CachedScan, Operator},
+    params::Params,
+    plan::{self, Planner},
+    row::{Row, Schema},
+    spill,
+};
+
+/// A planned statement producing its rows. Queries running longer than the slow query
+/// threshold of the database are logged when dropped.
+///
+/// The file stays locked for reading while the query exists, and its pages are read
+/// from the snapshot of the WAL taken when it was planned, so that its rows all come
+/// from the same version of the database.
+pub struct Query<'d> {
+    db: &'d Db,
+    sql: String,
+    params_key: Vec<u8>,
+    op: Operator,
+    schema: Schema,
+    memory: MemoryTracker,
+    rows_scanned: Rc<Cell<usize>>,
+    rows_returned: usize,
+    io: IoStats,
+    started: Instant,
+    /// Rows to store in the result cache once the query completes, dropped if they
+    /// outgrow it.
+    result: Option<PendingResult>,
+    _lock: Option<SharedLock>,
+    _running: RunningQuery,
+}
+
+struct PendingResult {
+    version: FileVersion,
+    rows: Vec<Vec<SendValue>>,
+    size: usize,
+    capacity: usize,
+}
+
+/// What a query did so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutionStats {
+    /// Rows read by the scans of the query.
+    pub rows_scanned: usize,
+    pub rows_returned: usize,
+    /// Pages read from the file, i.e. missing from the page cache.
+    pub pages_read: usize,
+    pub cache_hits: usize,
+    /// Most bytes of rows buffered at once by the operators of the query.
+    pub peak_memory: usize,
+    pub elapsed: Duration,
+}
+
+impl<'d> Query<'d> {
+    /// Plans `sql`, with `params` bound to its parameters.
+    pub fn new(db: &'d Db, sql: &str, params: &Params) -> anyhow::Result<Self> {
+        let started = Instant::now();
+        let io = IoStats::snapshot();
+        let memory = db.memory_tracker();
+        let rows_scanned = Rc::default();
+
+        let running = db.interrupt_handle().start_query();
+        let statement = db.prepare(sql)?;
+        // Writes take their own locks, which they have to let go of while waiting for
+        // another writer.
+        let lock = match statement.is_write() {
+            true => None,
+            false => db.lock_shared()?,
+        };
+        let (metadata, version, pager) = db.pin()?;
+        let schema = plan::result_schema(&metadata, &statement)?;
+
+        // Results are cached by SQL text and parameter values, except for EXPLAIN
+        // ANALYZE, whose timings differ each time, and for the statements modifying
+        // the database, which have to run each time.
+        let result_cache = db.result_cache().filter(|_| {
+            !matches!(*statement, ast::Statement::ExplainAnalyze(_)) && !statement.is_write()
+        });
+        let params_key = params.cache_key();
+        let cached = result_cache
+            .and_then(|cache| cache.get(&(sql.to_string(), params_key.clone()), version));
+        let (op, result) = match cached {
+            Some(rows) => (Operator::CachedScan(CachedScan::new(rows)), None),
+            None => {
+                let rows_scanned = Rc::clone(&rows_scanned);
+                let op = Planner::new(db, metadata, pager, memory.clone(), rows_scanned, params)
+                    .compile(&statement)?;
+                let result = result_cache.map(|cache| PendingResult {
+                    version,
+                    rows: Vec::new(),
+                    size: 0,
+                    capacity: cache.capacity(),
+                });
+                (op, result)
+            }
+        };
+
+        Ok(Self {
+            db,
+            sql: sql.to_string(),
+            params_key,
+            op,
+            schema,
+            memory,
+            rows_scanned,
+            rows_returned: 0,
+            io,
+            started,
+            result,
+            _lock: lock,
+            _running: running,
+        })
+    }
+
+    pub fn next_row(&mut self) -> anyhow::Result<Option<Row<'_>>> {
+        let row = self.op.next_row()?;
+        match row {
+            Some(row) => {
+                self.rows_returned += 1;
+                if let Some(result) = &mut self.result {
+                    result.size += spill::row_size(row);
+                    result.rows.push(row.iter().map(SendValue::from).collect());
+                    if result.size > result.capacity {
+                        self.result = None;
+                    }
+                }
+            }
+            None => {
+                if let (Some(result), Some(cache)) = (self.result.take(), self.db.result_cache()) {
+                    let key = (self.sql.clone(), std::mem::take(&mut self.params_key));
+                    cache.insert(key, result.version, result.rows, result.size);
+                }
+            }
+        }
+        Ok(row.map(|values| Row::new(values, &self.schema)))
+    }
+
+    /// The columns of the rows.
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    pub fn stats(&self) -> ExecutionStats {
+        let io = IoStats::snapshot().since(self.io);
+        ExecutionStats {
+            rows_scanned: self.rows_scanned.get(),
+            rows_returned: self.rows_returned,
+            pages_read: io.pages_read,
+            cache_hits: io.cache_hits,
+            peak_memory: self.memory.peak(),
+            elapsed: self.started.elapsed(),
+        }
+    }
+}
+
+impl Drop for Query<'_> {
+    fn drop(&mut self) {
+        let Some(threshold) = self.db.slow_query_threshold() else {
+            return;
+        };
+
+        let stats = self.stats();
+        if stats.elapsed >= threshold {
+            eprintln!(
+                "slow query: {:.1?}, {} rows, {} pages read: {}",
+                stats.elapsed, stats.rows_returned, stats.pages_read, self.sql
+            );
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! The rows of query results, along with what the query tells about their columns.
+
+use std::ops::Deref;
+
+use crate::value::OwnedValue;
+
+/// A column of the rows of a query.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ColumnMetadata {
+    pub name: String,
+    /// The declared type of the table column the values are read from, like
+    /// `sqlite3_column_decltype()`. `None` for computed values.
+    pub decl_type: Option<String>,
+    /// The table the values are read from, if they're those of a table column.
+    pub table: Option<String>,
+}
+
+impl ColumnMetadata {
+    pub fn named(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ..Self::default()
+        }
+    }
+}
+
+/// The columns of the rows of a query.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Schema {
+    columns: Vec<ColumnMetadata>,
+}
+
+impl Schema {
+    pub fn new(columns: Vec<ColumnMetadata>) -> Self {
+        Self { columns }
+    }
+
+    pub fn columns(&self) -> &[ColumnMetadata] {
+        &self.columns
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.columns.iter().map(|column| column.name.as_str())
+    }
+
+    /// The position of the first column named `name`, ignoring ASCII case like SQL.
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .position(|column| column.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// A row of a query, which derefs to its values.
+#[derive(Debug, Clone, Copy)]
+pub struct Row<'q> {
+    values: &'q [OwnedValue],
+    schema: &'q Schema,
+}
+
+#[expect(dead_code, reason = "the CLI and the server read the values in order")]
+impl<'q> Row<'q> {
+    pub fn new(values: &'q [OwnedValue], schema: &'q Schema) -> Self {
+        Self { values, schema }
+    }
+
+    pub fn schema(&self) -> &'q Schema {
+        self.schema
+    }
+
+    /// The value of the column named `name`.
+    pub fn get(&self, name: &str) -> Option<&'q OwnedValue> {
+        self.values.get(self.schema.index_of(name)?)
+    }
+}
+
+impl Deref for Row<'_> {
+    type Target = [OwnedValue];
+
+    fn deref(&self) -> &Self::Target {
+        self.values
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_by_name() {
+        let schema = Schema::new(vec![
+            ColumnMetadata {
+                name: "id".to_string(),
+                decl_type: Some("INTEGER".to_string()),
+                table: Some("t".to_string()),
+            },
+            ColumnMetadata::named("Total"),
+            ColumnMetadata::named("total"),
+        ]);
+        let values = [OwnedValue::Int(1), OwnedValue::Int(2), OwnedValue::Int(3)];
+        let row = Row::new(&values, &schema);
+
+        assert_eq!(row.get("ID"), Some(&OwnedValue::Int(1)));
+        assert_eq!(row.get("total"), Some(&OwnedValue::Int(2)));
+        assert_eq!(row.get("missing"), None);
+        assert_eq!(row.len(), 3);
+        assert_eq!(schema.names().collect::<Vec<_>>(), ["id", "Total", "total"]);
+    }
+}
@@ -0,0 +1,220 @@
+//! Temporary files holding rows that don't fit in the memory budget of an operator.
+
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, ErrorKind, Read, Seek, Write},
+    path::PathBuf,
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{Context, bail};
+
+use crate::value::OwnedValue;
+
+const TAG_NULL: u8 = 0;
+const TAG_INT: u8 = 1;
+const TAG_FLOAT: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_BLOB: u8 = 4;
+
+static NEXT_FILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Approximate memory used by a row kept in memory.
+pub fn row_size(row: &[OwnedValue]) -> usize {
+    row.iter()
+        .map(|value| {
+            std::mem::size_of::<OwnedValue>()
+                + match value {
+                    OwnedValue::String(s) => s.len(),
+                    OwnedValue::Blob(b) => b.len(),
+                    _ => 0,
+                }
+        })
+        .sum()
+}
+
+/// Path of a temporary file, removed when dropped.
+#[derive(Debug)]
+struct TempPath(PathBuf);
+
+impl TempPath {
+    fn new() -> Self {
+        let id = NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed);
+        let name = format!("rqlite-spill-{}-{id}", std::process::id());
+        Self(std::env::temp_dir().join(name))
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// A temporary file written once, then read back sequentially with a [`SpillReader`].
+#[derive(Debug)]
+pub struct SpillWriter {
+    path: TempPath,
+    file: BufWriter<File>,
+}
+
+impl SpillWriter {
+    pub fn create() -> anyhow::Result<Self> {
+        let path = TempPath::new();
+        let file = File::create(&path.0)
+            .with_context(|| format!("create spill file {}", path.0.display()))?;
+
+        Ok(Self {
+            path,
+            file: BufWriter::new(file),
+        })
+    }
+
+    pub fn write_row(&mut self, row: &[OwnedValue]) -> anyhow::Result<()> {
+        self.file.write_all(&(row.len() as u32).to_le_bytes())?;
+
+        for value in row {
+            match value {
+                OwnedValue::Null => self.file.write_all(&[TAG_NULL])?,
+                OwnedValue::Int(i) => {
+                    self.file.write_all(&[TAG_INT])?;
+                    self.file.write_all(&i.to_le_bytes())?;
+                }
+                OwnedValue::Float(f) => {
+                    self.file.write_all(&[TAG_FLOAT])?;
+                    self.file.write_all(&f.to_le_bytes())?;
+                }
+                OwnedValue::String(s) => self.write_bytes(TAG_STRING, s.as_bytes())?,
+                OwnedValue::Blob(b) => self.write_bytes(TAG_BLOB, b)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, tag: u8, bytes: &[u8]) -> anyhow::Result<()> {
+        self.file.write_all(&[tag])?;
+        self.file.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        self.file.write_all(bytes)?;
+        Ok(())
+    }
+
+    pub fn into_reader(self) -> anyhow::Result<SpillReader> {
+        let file = self.file.into_inner().context("flush spill file")?;
+        drop(file);
+
+        let file = File::open(&self.path.0)
+            .with_context(|| format!("open spill file {}", self.path.0.display()))?;
+
+        Ok(SpillReader {
+            path: self.path,
+            file: BufReader::new(file),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct SpillReader {
+    path: TempPath,
+    file: BufReader<File>,
+}
+
+impl SpillReader {
+    /// Reads the next row into `row`, returning false at the end of the file.
+    pub fn read_row(&mut self, row: &mut Vec<OwnedValue>) -> anyhow::Result<bool> {
+        let mut len = [0; 4];
+        match self.file.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => {
+                return Err(e).with_context(|| format!("read {}", self.path.0.display()));
+            }
+        }
+
+        row.clear();
+        for _ in 0..u32::from_le_bytes(len) {
+            let value = match self.read_array::<1>()?[0] {
+                TAG_NULL => OwnedValue::Null,
+                TAG_INT => OwnedValue::Int(i64::from_le_bytes(self.read_array()?)),
+                TAG_FLOAT => OwnedValue::Float(f64::from_le_bytes(self.read_array()?)),
+                TAG_STRING => {
+                    let bytes = self.read_bytes()?;
+                    OwnedValue::String(Rc::new(
+                        String::from_utf8(bytes).context("invalid spilled string")?,
+                    ))
+                }
+                TAG_BLOB => OwnedValue::Blob(Rc::new(self.read_bytes()?)),
+                tag => bail!("invalid spilled value tag: {tag}"),
+            };
+            row.push(value);
+        }
+
+        Ok(true)
+    }
+
+    /// Restarts reading from the first row.
+    pub fn rewind(&mut self) -> anyhow::Result<()> {
+        self.file
+            .rewind()
+            .with_context(|| format!("rewind {}", self.path.0.display()))
+    }
+
+    fn read_array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        let mut buffer = [0; N];
+        self.file
+            .read_exact(&mut buffer)
+            .context("truncated spill file")?;
+        Ok(buffer)
+    }
+
+    fn read_bytes(&mut self) -> anyhow::Result<Vec<u8>> {
+        let len = u64::from_le_bytes(self.read_array()?) as usize;
+        let mut buffer = vec![0; len];
+        self.file
+            .read_exact(&mut buffer)
+            .context("truncated spill file")?;
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let rows = vec![
+            vec![
+                OwnedValue::Null,
+                OwnedValue::Int(-7),
+                OwnedValue::Float(1.5),
+            ],
+            vec![
+                OwnedValue::String(Rc::new("héllo".to_string())),
+                OwnedValue::Blob(Rc::new(vec![0, 255])),
+            ],
+            vec![],
+        ];
+
+        let mut writer = SpillWriter::create().unwrap();
+        for row in &rows {
+            writer.write_row(row).unwrap();
+        }
+        let path = writer.path.0.clone();
+        let mut reader = writer.into_reader().unwrap();
+
+        let mut row = Vec::new();
+        for expected in &rows {
+            assert!(reader.read_row(&mut row).unwrap());
+            assert_eq!(&row, expected);
+        }
+        assert!(!reader.read_row(&mut row).unwrap());
+        reader.rewind().unwrap();
+        assert!(reader.read_row(&mut row).unwrap());
+        assert_eq!(row, rows[0]);
+
+        drop(reader);
+        assert!(!path.exists());
+    }
+}
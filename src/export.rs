@@ -0,0 +1,79 @@
+//! Serialization of query results for other tools.
+
+use std::io::Write;
+
+use anyhow::Context;
+
+use crate::{
+    engine::{self, Query},
+    value::OwnedValue,
+};
+
+/// Writes each row of `query` as a JSON object on its own line, keyed by column name.
+/// Rows are flushed as they are produced, so that the output can be piped into other
+/// tools without buffering the result set.
+pub fn export_ndjson(query: &mut Query, out: &mut impl Write) -> anyhow::Result<()> {
+    let keys = query
+        .schema()
+        .names()
+        .map(|name| value_to_json(&OwnedValue::String(name.to_string().into())))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    while let Some(row) = query.next_row()? {
+        writeln!(out, "{}", row_to_json_object(&keys, &row)?)?;
+        out.flush().context("flush output")?;
+    }
+
+    Ok(())
+}
+
+fn row_to_json_object(keys: &[String], row: &[OwnedValue]) -> anyhow::Result<String> {
+    let mut object = String::from("{");
+    for (i, (key, value)) in keys.iter().zip(row).enumerate() {
+        if i > 0 {
+            object.push(',');
+        }
+        object.push_str(key);
+        object.push(':');
+        object.push_str(&value_to_json(value)?);
+    }
+    object.push('}');
+    Ok(object)
+}
+
+/// Converts a value to JSON, with blobs as hex strings.
+pub fn value_to_json(value: &OwnedValue) -> anyhow::Result<String> {
+    match value {
+        OwnedValue::Blob(blob) => {
+            let hex: String = blob.iter().map(|b| format!("{b:02x}")).collect();
+            Ok(format!("\"{hex}\""))
+        }
+        value => engine::to_json(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn json_object_rows() {
+        let keys = vec!["\"id\"".to_string(), "\"na\\\"me\"".to_string()];
+        let row = [
+            OwnedValue::Int(1),
+            OwnedValue::String(Rc::new("a\nb".to_string())),
+        ];
+        assert_eq!(
+            row_to_json_object(&keys, &row).unwrap(),
+            r#"{"id":1,"na\"me":"a\nb"}"#
+        );
+
+        let row = [OwnedValue::Null, OwnedValue::Blob(Rc::new(vec![0, 255]))];
+        assert_eq!(
+            row_to_json_object(&keys, &row).unwrap(),
+            r#"{"id":null,"na\"me":"00ff"}"#
+        );
+    }
+}
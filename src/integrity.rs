@@ -0,0 +1,619 @@
+//! `PRAGMA integrity_check`, following `sqlite3BtreeIntegrityCheck()`: the freelist
+//! and every b-tree are walked, checking their cells, overflow chains and pointer-map
+//! entries, and that every page is used exactly once. Problems are reported with the
+//! messages of SQLite, in the same order, so that the output of both can be compared.
+
+use std::fmt::Display;
+
+use anyhow::{Context, ensure};
+
+use crate::{
+    cursor::MAX_BTREE_DEPTH,
+    db::SchemaMetadata,
+    pager::{self, Pager},
+    ptrmap::{self, Ptrmap, PtrmapKind},
+    raw::{self, RawPage, RawPageType},
+};
+
+/// The number of problems reported when the pragma doesn't set it.
+pub const DEFAULT_MAX_ERRORS: usize = 100;
+
+/// The error codes SQLite reports for pages it can't read or parse.
+const SQLITE_IOERR: i32 = 10;
+const SQLITE_CORRUPT: i32 = 11;
+
+/// Checks the whole database, or only the b-trees of `table` and its indexes, returning
+/// the rows of the pragma: the problems found, at most `max_errors` of them, or "ok".
+/// A quick check doesn't compare the number of entries of the indexes with their table.
+pub fn check(
+    pager: &Pager,
+    metadata: &SchemaMetadata,
+    table: Option<&str>,
+    quick: bool,
+    max_errors: usize,
+) -> anyhow::Result<Vec<String>> {
+    let tables = match table {
+        Some(name) => vec![
+            metadata
+                .table(name)
+                .with_context(|| format!("no such table: main.{name}"))?,
+        ],
+        None => metadata.tables().iter().collect(),
+    };
+    let indexes: Vec<_> = metadata
+        .indexes
+        .iter()
+        .filter(|index| {
+            tables
+                .iter()
+                .any(|table| table.name.eq_ignore_ascii_case(&index.table_name))
+        })
+        .collect();
+
+    let mut roots = Vec::new();
+    if table.is_none() {
+        roots.push(1);
+    }
+    roots.extend(tables.iter().map(|table| table.first_page));
+    roots.extend(indexes.iter().map(|index| index.first_page));
+
+    let mut checker = Checker::new(pager, max_errors)?;
+    checker.check(&roots, table.is_some())?;
+
+    let mut rows = Vec::new();
+    if !checker.errors.is_empty() {
+        rows.push(format!(
+            "*** in database main ***\n{}",
+            checker.errors.join("\n")
+        ));
+    }
+    if !quick {
+        for index in indexes {
+            if checker.remaining == 0 {
+                break;
+            }
+            let Some(table) = metadata.table(&index.table_name) else {
+                continue;
+            };
+            if index.is_partial() {
+                continue;
+            }
+            // The entries of b-trees with unreadable pages are unknown.
+            let (Ok(index_entries), Ok(table_entries)) = (
+                count_entries(pager, index.first_page),
+                count_entries(pager, table.first_page),
+            ) else {
+                continue;
+            };
+            if index_entries != table_entries {
+                rows.push(format!("wrong # of entries in index {}", index.name));
+                checker.remaining -= 1;
+            }
+        }
+    }
+    if rows.is_empty() {
+        rows.push("ok".to_string());
+    }
+    Ok(rows)
+}
+
+/// What the messages are about, like the `zPfx` of SQLite.
+#[derive(Debug, Copy, Clone)]
+enum Prefix {
+    None,
+    Freelist,
+    Page,
+    Cell,
+    RightChild,
+}
+
+struct Checker<'p> {
+    pager: &'p Pager,
+    page_count: usize,
+    page_size: usize,
+    usable_size: usize,
+    ptrmap: Option<Ptrmap>,
+    /// Whether each page was reached, by page number.
+    referenced: Vec<bool>,
+    errors: Vec<String>,
+    /// How many more problems to report.
+    remaining: usize,
+    prefix: Prefix,
+    /// The page and the cell the prefix is about.
+    page: usize,
+    cell: usize,
+}
+
+impl<'p> Checker<'p> {
+    fn new(pager: &'p Pager, max_errors: usize) -> anyhow::Result<Self> {
+        let header = pager.header();
+        let page_count = pager.page_count()?;
+        let mut referenced = vec![false; page_count + 1];
+        // The lock-byte page is never used, but isn't reported either.
+        if let Some(lock_byte) = referenced.get_mut(pager::lock_byte_page(header.page_size)) {
+            *lock_byte = true;
+        }
+        Ok(Self {
+            pager,
+            page_count,
+            page_size: header.page_size as usize,
+            usable_size: header.usable_page_size(),
+            ptrmap: pager.ptrmap()?,
+            referenced,
+            errors: Vec::new(),
+            remaining: max_errors,
+            prefix: Prefix::None,
+            page: 0,
+            cell: 0,
+        })
+    }
+
+    /// Checks the b-trees rooted at `roots`, and unless only some of the b-trees are
+    /// checked, the freelist and the pages no b-tree uses.
+    fn check(&mut self, roots: &[usize], partial: bool) -> anyhow::Result<()> {
+        let header = self.pager.read_header()?;
+
+        if !partial {
+            self.prefix = Prefix::Freelist;
+            self.check_list(
+                true,
+                header.freelist_trunk as usize,
+                header.freelist_count as usize,
+            );
+            self.prefix = Prefix::None;
+
+            let largest_root = header.largest_root_page as usize;
+            if self.ptrmap.is_some() {
+                let max = roots.iter().copied().max().unwrap_or(0);
+                if max != largest_root {
+                    self.error(format_args!(
+                        "max rootpage ({max}) disagrees with header ({largest_root})"
+                    ));
+                }
+            } else if header.incremental_vacuum {
+                self.error("incremental_vacuum enabled with a max rootpage of zero");
+            }
+        }
+
+        for &root in roots {
+            if self.remaining == 0 {
+                break;
+            }
+            if root == 0 {
+                continue;
+            }
+            if !partial && root > 1 {
+                self.check_ptrmap(root, PtrmapKind::RootPage, 0);
+            }
+            self.check_tree_page(root, &mut 0, i64::MAX);
+        }
+
+        if !partial {
+            for n in 1..=self.page_count {
+                if self.remaining == 0 {
+                    break;
+                }
+                let is_ptrmap = self.ptrmap.is_some_and(|ptrmap| ptrmap.is_ptrmap_page(n));
+                match (self.referenced[n], is_ptrmap) {
+                    (false, false) => self.error(format_args!("Page {n} is never used")),
+                    (true, true) => self.error(format_args!("Pointer map page {n} is referenced")),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn error(&mut self, message: impl Display) {
+        if self.remaining == 0 {
+            return;
+        }
+        self.remaining -= 1;
+        let prefix = match self.prefix {
+            Prefix::None => String::new(),
+            Prefix::Freelist => "Main freelist: ".to_string(),
+            Prefix::Page => format!("Page {}: ", self.page),
+            Prefix::Cell => format!("On tree page {} cell {}: ", self.page, self.cell),
+            Prefix::RightChild => format!("On page {} at right child: ", self.page),
+        };
+        self.errors.push(format!("{prefix}{message}"));
+    }
+
+    /// Marks page `n` as used, returning whether it's invalid or already used.
+    fn check_ref(&mut self, n: usize) -> bool {
+        if n == 0 || n > self.page_count {
+            self.error(format_args!("invalid page number {n}"));
+            return true;
+        }
+        if self.referenced[n] {
+            self.error(format_args!("2nd reference to page {n}"));
+            return true;
+        }
+        self.referenced[n] = true;
+        false
+    }
+
+    /// Checks that the pointer-map entry of page `child`, if the database has a
+    /// pointer-map, is `kind` with `parent`.
+    fn check_ptrmap(&mut self, child: usize, kind: PtrmapKind, parent: usize) {
+        let Some(ptrmap) = self.ptrmap else {
+            return;
+        };
+        let entry = if child < 2 || ptrmap.is_ptrmap_page(child) {
+            None
+        } else {
+            let (map, offset) = ptrmap.locate(child);
+            self.pager
+                .read_raw(map)
+                .and_then(|page| ptrmap::read_entry(&page, offset))
+                .ok()
+                .flatten()
+        };
+        match entry {
+            Some(entry) if entry.kind == kind && entry.parent as usize == parent => {}
+            Some(entry) => self.error(format_args!(
+                "Bad ptr map entry key={child} expected=({},{parent}) got=({},{})",
+                kind.id(),
+                entry.kind.id(),
+                entry.parent
+            )),
+            None => self.error(format_args!("Failed to read ptrmap key={child}")),
+        }
+    }
+
+    /// Checks the freelist, or an overflow chain, starting at page `first` and
+    /// expected to be `expected` pages long.
+    fn check_list(&mut self, freelist: bool, first: usize, expected: usize) {
+        let errors = self.errors.len();
+        let mut remaining = expected as i64;
+        let mut n = first;
+        while n != 0 && self.remaining > 0 {
+            if self.check_ref(n) {
+                break;
+            }
+            remaining -= 1;
+            let Ok(data) = self.pager.read_raw(n) else {
+                self.error(format_args!("failed to get page {n}"));
+                break;
+            };
+            let read_u32 = |offset: usize| pager::read_be_double_at(&data, offset) as usize;
+
+            if freelist {
+                let leaves = read_u32(4);
+                self.check_ptrmap(n, PtrmapKind::FreePage, 0);
+                if leaves > self.usable_size / 4 - 2 {
+                    self.error(format_args!("freelist leaf count too big on page {n}"));
+                    remaining -= 1;
+                } else {
+                    for i in 0..leaves {
+                        let leaf = read_u32(8 + 4 * i);
+                        self.check_ptrmap(leaf, PtrmapKind::FreePage, 0);
+                        self.check_ref(leaf);
+                    }
+                    remaining -= leaves as i64;
+                }
+            } else if remaining > 0 {
+                self.check_ptrmap(read_u32(0), PtrmapKind::Overflow, n);
+            }
+            n = read_u32(0);
+        }
+
+        if remaining != 0 && errors == self.errors.len() {
+            self.error(format_args!(
+                "{} is {} but should be {expected}",
+                if freelist {
+                    "size"
+                } else {
+                    "overflow list length"
+                },
+                expected as i64 - remaining
+            ));
+        }
+    }
+
+    /// Checks the b-tree page `n` and its children, whose keys must be below `max_key`,
+    /// setting `min_key` to the smallest key found. Returns the depth of the subtree,
+    /// 0 for a leaf.
+    fn check_tree_page(&mut self, n: usize, min_key: &mut i64, max_key: i64) -> usize {
+        if n == 0 {
+            return 0;
+        }
+        let saved = (self.prefix, self.page, self.cell);
+        if self.check_ref(n) {
+            return 0;
+        }
+        self.prefix = Prefix::Page;
+        self.page = n;
+        let depth = self.check_cells(n, min_key, max_key);
+        (self.prefix, self.page, self.cell) = saved;
+        depth
+    }
+
+    fn check_cells(&mut self, n: usize, min_key: &mut i64, mut max_key: i64) -> usize {
+        let Ok(mut data) = self.pager.read_raw(n) else {
+            self.error(format_args!(
+                "unable to get the page. error code={SQLITE_IOERR}"
+            ));
+            return 0;
+        };
+        data.truncate(self.usable_size);
+        let max_cells = (self.page_size - 8) / 6;
+        let page = RawPage::parse(data, n)
+            .ok()
+            .filter(|page| page.cell_pointers.len() <= max_cells);
+        let Some((page_type, page)) = page.and_then(|page| Some((page.page_type?, page))) else {
+            self.error(format_args!(
+                "btreeInitPage() returns error code {SQLITE_CORRUPT}"
+            ));
+            return 0;
+        };
+        if !free_space_is_valid(&page, page_type) {
+            self.error("free space corruption");
+            return 0;
+        }
+
+        self.prefix = Prefix::Cell;
+        let usable = self.usable_size;
+        let mut depth = 0;
+        let mut key_can_be_equal = true;
+        if let Some(right) = page.rightmost_pointer {
+            let right = right as usize;
+            if self.ptrmap.is_some() {
+                self.prefix = Prefix::RightChild;
+                self.check_ptrmap(right, PtrmapKind::Btree, n);
+            }
+            let bound = max_key;
+            depth = self.check_tree_page(right, &mut max_key, bound);
+            key_can_be_equal = false;
+        }
+
+        // The bytes used by each cell and freeblock, to check that they don't overlap.
+        let mut used = Vec::new();
+        let mut check_coverage = true;
+        for i in (0..page.cell_pointers.len()).rev() {
+            if self.remaining == 0 {
+                break;
+            }
+            self.cell = i;
+            let offset = page.cell_pointers[i];
+            if offset < page.content_start || offset > usable - 4 {
+                self.error(format_args!(
+                    "Offset {offset} out of range {}..{}",
+                    page.content_start,
+                    usable - 4
+                ));
+                check_coverage = false;
+                continue;
+            }
+            let Some(cell) = page
+                .cell(i)
+                .ok()
+                .filter(|cell| offset + cell.size <= usable)
+            else {
+                self.error("Extends off end of page");
+                check_coverage = false;
+                continue;
+            };
+
+            if let Some(rowid) = cell.rowid {
+                if (key_can_be_equal && rowid > max_key) || (!key_can_be_equal && rowid >= max_key)
+                {
+                    self.error(format_args!("Rowid {rowid} out of order"));
+                }
+                max_key = rowid;
+                key_can_be_equal = false;
+            }
+
+            if let (Some(payload_size), Some(first)) = (cell.payload_size, cell.first_overflow) {
+                let overflow = payload_size - cell.local_payload.len();
+                let pages = (overflow + usable - 5) / (usable - 4);
+                self.check_ptrmap(first as usize, PtrmapKind::FirstOverflow, n);
+                self.check_list(false, first as usize, pages);
+            }
+
+            match cell.left_child {
+                Some(child) => {
+                    let child = child as usize;
+                    self.check_ptrmap(child, PtrmapKind::Btree, n);
+                    let bound = max_key;
+                    let child_depth = self.check_tree_page(child, &mut max_key, bound);
+                    key_can_be_equal = false;
+                    if child_depth != depth {
+                        self.error("Child page depth differs");
+                        depth = child_depth;
+                    }
+                }
+                None => used.push((offset, offset + cell.size - 1)),
+            }
+        }
+        *min_key = max_key;
+
+        self.prefix = Prefix::None;
+        if check_coverage && self.remaining > 0 {
+            if !page_type.is_leaf() {
+                for i in 0..page.cell_pointers.len() {
+                    if let Ok(cell) = page.cell(i) {
+                        used.push((cell.offset, cell.offset + cell.size - 1));
+                    }
+                }
+            }
+            let mut freeblock = page.first_freeblock;
+            while freeblock > 0 {
+                let size = read_u16(&page.data, freeblock + 2);
+                used.push((freeblock, freeblock + size - 1));
+                freeblock = read_u16(&page.data, freeblock);
+            }
+            used.sort_unstable();
+
+            let mut previous_end = page.content_start - 1;
+            let mut fragmented = 0;
+            let mut overlap = false;
+            for (start, end) in used {
+                if previous_end >= start {
+                    self.error(format_args!("Multiple uses for byte {start} of page {n}"));
+                    overlap = true;
+                    break;
+                }
+                fragmented += start - previous_end - 1;
+                previous_end = end;
+            }
+            fragmented += (usable - 1).saturating_sub(previous_end);
+            if !overlap && fragmented != page.fragmented_bytes {
+                self.error(format_args!(
+                    "Fragmentation of {fragmented} bytes reported as {} on page {n}",
+                    page.fragmented_bytes
+                ));
+            }
+        }
+
+        if page_type.is_leaf() { 0 } else { depth + 1 }
+    }
+}
+
+/// The entries of the b-tree rooted at `root`: the rows of a table, or the keys of an
+/// index. Like the cursors of SQLite, this gives up on b-trees deeper than 20 levels,
+/// which can only be corrupted ones looping on themselves.
+fn count_entries(pager: &Pager, root: usize) -> anyhow::Result<u64> {
+    let mut entries = 0;
+    for page in raw::walk_btree(pager, root) {
+        let (depth, page) = page?;
+        ensure!(depth < MAX_BTREE_DEPTH, "b-tree {root} is too deep");
+        if let Some(page_type) = page.page_type
+            && (page_type == RawPageType::TableLeaf || !page_type.is_table())
+        {
+            entries += page.cell_pointers.len() as u64;
+        }
+    }
+    Ok(entries)
+}
+
+/// Whether the freeblocks of `page` are in order and within the page, and the free
+/// space they add up to with the unallocated and fragmented bytes fits, like in
+/// `btreeComputeFreeSpace()`.
+fn free_space_is_valid(page: &RawPage, page_type: RawPageType) -> bool {
+    let usable = page.data.len();
+    let header_offset = if page.number == 1 {
+        pager::HEADER_SIZE
+    } else {
+        0
+    };
+    let header_size = if page_type.is_leaf() { 8 } else { 12 };
+    let first_cell = header_offset + header_size + 2 * page.cell_pointers.len();
+
+    let mut free = page.fragmented_bytes + page.content_start;
+    let mut freeblock = page.first_freeblock;
+    if freeblock > 0 {
+        if freeblock < page.content_start {
+            return false;
+        }
+        let (next, size) = loop {
+            if freeblock > usable - 4 {
+                return false;
+            }
+            let next = read_u16(&page.data, freeblock);
+            let size = read_u16(&page.data, freeblock + 2);
+            free += size;
+            if next <= freeblock + size + 3 {
+                break (next, size);
+            }
+            freeblock = next;
+        };
+        if next > 0 || freeblock + size > usable {
+            return false;
+        }
+    }
+    free <= usable && free >= first_cell
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> usize {
+    u16::from_be_bytes([buffer[offset], buffer[offset + 1]]) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        analyzer,
+        db::Db,
+        pager::HEADER_PREFIX,
+        testing::{index_cell, record, table_cell, text, write_leaf},
+        value::SendValue,
+    };
+
+    use super::*;
+
+    const PAGE_SIZE: usize = 512;
+
+    /// A table `t` whose rows have rowids `rowids`, indexed by `i` with a single key,
+    /// a freelist of `free_pages` pages holding only its trunk, and an unused page.
+    fn check_database(rowids: [u8; 2], free_pages: u32, max_errors: usize) -> Vec<String> {
+        let mut file = vec![0; 5 * PAGE_SIZE];
+        file[..16].copy_from_slice(HEADER_PREFIX);
+        file[16..18].copy_from_slice(&(PAGE_SIZE as u16).to_be_bytes());
+        file[28..32].copy_from_slice(&5u32.to_be_bytes());
+        file[32..36].copy_from_slice(&4u32.to_be_bytes());
+        file[36..40].copy_from_slice(&free_pages.to_be_bytes());
+
+        let schema = [
+            table_cell(
+                1,
+                &record(&[
+                    text("table"),
+                    text("t"),
+                    text("t"),
+                    SendValue::Int(2),
+                    text("CREATE TABLE t(a)"),
+                ]),
+            ),
+            table_cell(
+                2,
+                &record(&[
+                    text("index"),
+                    text("i"),
+                    text("t"),
+                    SendValue::Int(3),
+                    text("CREATE INDEX i ON t(a)"),
+                ]),
+            ),
+        ];
+        write_leaf(&mut file[..PAGE_SIZE], 100, 0x0d, &schema);
+        let rows = rowids.map(|rowid| {
+            let rowid = rowid.into();
+            table_cell(rowid, &record(&[SendValue::Int(rowid)]))
+        });
+        write_leaf(&mut file[PAGE_SIZE..2 * PAGE_SIZE], 0, 0x0d, &rows);
+        let keys = [index_cell(&record(&[SendValue::Int(1), SendValue::Int(1)]))];
+        write_leaf(
+            &mut file[2 * PAGE_SIZE..3 * PAGE_SIZE],
+            0,
+            analyzer::PAGE_LEAF_INDEX_ID,
+            &keys,
+        );
+
+        let path = std::env::temp_dir().join(format!(
+            "rqlite-integrity-{}-{}",
+            std::process::id(),
+            rowids[0]
+        ));
+        std::fs::write(&path, file).unwrap();
+        let db = Db::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        check(db.pager(), &db.metadata(), None, false, max_errors).unwrap()
+    }
+
+    #[test]
+    fn reports_problems_like_sqlite() {
+        assert_eq!(
+            check_database([1, 2], 1, DEFAULT_MAX_ERRORS),
+            [
+                "*** in database main ***\nPage 5 is never used",
+                "wrong # of entries in index i"
+            ]
+        );
+        assert_eq!(
+            check_database([2, 1], 2, 2),
+            ["*** in database main ***\n\
+              Main freelist: size is 1 but should be 2\n\
+              On tree page 2 cell 0: Rowid 2 out of order"]
+        );
+    }
+}
@@ -0,0 +1,351 @@
+//! Rollback journals. Before overwriting a page of the database file, a writer appends
+//! its original content to the journal, so the pages of a hot journal, left behind by
+//! a writer that crashed mid-transaction, are the content of the last committed
+//! version. Rather than writing them back like SQLite would, the pager reads them in
+//! place of those of the file.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs::File,
+    hash::{BuildHasher, RandomState},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, ensure};
+
+use crate::pager::read_be_double_at;
+
+const MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+const HEADER_SIZE: usize = 28;
+/// The record count of journals written without syncing, which extend to the end of
+/// the file.
+const UNKNOWN_RECORD_COUNT: u32 = u32::MAX;
+
+#[derive(Debug)]
+pub struct RollbackJournal {
+    pages: HashMap<usize, Vec<u8>>,
+    /// The size of the database in pages before the transaction.
+    db_size: usize,
+}
+
+impl RollbackJournal {
+    /// Reads the original pages saved in the journal at `path`. A journal pointing to a
+    /// super-journal that no longer exists belongs to a multi-database transaction that
+    /// committed, so there is nothing to roll back.
+    pub fn read(
+        path: impl AsRef<Path>,
+        mut file: impl Read + Seek,
+    ) -> anyhow::Result<Option<Self>> {
+        let len = file
+            .seek(SeekFrom::End(0))
+            .context("seek to end of journal")?;
+        if let Some(super_journal) = read_super_journal_name(&mut file, len)?
+            && !Path::new(&super_journal).exists()
+        {
+            return Ok(None);
+        }
+
+        let mut journal = RollbackJournal {
+            pages: HashMap::new(),
+            db_size: 0,
+        };
+        let mut offset = 0;
+        let mut header = [0; HEADER_SIZE];
+        while offset + HEADER_SIZE as u64 <= len {
+            file.seek(SeekFrom::Start(offset))
+                .context("seek to journal header")?;
+            file.read_exact(&mut header)
+                .context("read journal header")?;
+            if header[..8] != MAGIC {
+                // The transaction didn't get to change the database file.
+                if offset == 0 {
+                    return Ok(None);
+                }
+                break;
+            }
+
+            let records = read_be_double_at(&header, 8);
+            let nonce = read_be_double_at(&header, 12);
+            let sector_size = read_be_double_at(&header, 20) as u64;
+            let page_size = read_be_double_at(&header, 24) as usize;
+            ensure!(
+                sector_size.is_power_of_two() && page_size.is_power_of_two(),
+                "invalid journal header in {}",
+                path.as_ref().display()
+            );
+            if offset == 0 {
+                journal.db_size = read_be_double_at(&header, 16) as usize;
+            }
+
+            offset += sector_size;
+            let record_size = (page_size + 8) as u64;
+            let records = match records {
+                UNKNOWN_RECORD_COUNT => len.saturating_sub(offset) / record_size,
+                n => n as u64,
+            };
+            file.seek(SeekFrom::Start(offset))
+                .context("seek to journal record")?;
+            let mut record = vec![0; page_size + 8];
+            for _ in 0..records {
+                match file.read_exact(&mut record) {
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(Some(journal)),
+                    result => result.context("read journal record")?,
+                }
+                let page = read_be_double_at(&record, 0) as usize;
+                let data = &record[4..4 + page_size];
+                // The rest of the journal was never synced.
+                if page == 0
+                    || record_checksum(nonce, data) != read_be_double_at(&record, 4 + page_size)
+                {
+                    return Ok(Some(journal));
+                }
+                // Pages the transaction appended disappear when it is rolled back.
+                if page <= journal.db_size {
+                    journal.pages.entry(page).or_insert_with(|| data.to_vec());
+                }
+            }
+
+            offset += records * record_size;
+            offset = offset.div_ceil(sector_size) * sector_size;
+        }
+
+        Ok(Some(journal))
+    }
+
+    /// The content of page `n` before the transaction, if the transaction changed it.
+    pub fn page(&self, n: usize) -> Option<&[u8]> {
+        self.pages.get(&n).map(Vec::as_slice)
+    }
+
+    pub fn db_size(&self) -> usize {
+        self.db_size
+    }
+}
+
+/// The sector size recorded in the journals written, which their header is padded to.
+const SECTOR_SIZE: usize = 512;
+
+/// The journal of the transaction being written, deleted once it commits.
+#[derive(Debug)]
+pub struct JournalWriter {
+    file: File,
+    path: PathBuf,
+    nonce: u32,
+    page_size: usize,
+    /// The size of the database in pages before the transaction. The pages past it
+    /// have no original content to save.
+    db_size: usize,
+    records: u32,
+    journaled: HashSet<usize>,
+    /// Once synced, the database file may be overwritten, so the journal is kept
+    /// until the transaction commits.
+    synced: bool,
+}
+
+impl JournalWriter {
+    /// Creates the journal at `path`, of a database of `db_size` pages of `page_size`
+    /// bytes.
+    pub fn create(path: &Path, page_size: usize, db_size: usize) -> anyhow::Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .with_context(|| format!("create {}", path.display()))?;
+        let mut journal = Self {
+            file,
+            path: path.to_path_buf(),
+            nonce: RandomState::new().hash_one(path) as u32,
+            page_size,
+            db_size,
+            records: 0,
+            journaled: HashSet::new(),
+            synced: false,
+        };
+        journal.write_header()?;
+        Ok(journal)
+    }
+
+    /// Whether page `n` has original content that isn't saved yet.
+    pub fn needs(&self, n: usize) -> bool {
+        n <= self.db_size && !self.journaled.contains(&n)
+    }
+
+    /// Saves the original content of page `n`, unless it needs none.
+    pub fn append(&mut self, n: usize, page: &[u8]) -> anyhow::Result<()> {
+        if !self.needs(n) {
+            return Ok(());
+        }
+        let mut record = Vec::with_capacity(page.len() + 8);
+        record.extend((n as u32).to_be_bytes());
+        record.extend(page);
+        record.extend(record_checksum(self.nonce, page).to_be_bytes());
+        self.file
+            .write_all(&record)
+            .with_context(|| format!("journal page {n}"))?;
+        self.records += 1;
+        self.journaled.insert(n);
+        Ok(())
+    }
+
+    /// Syncs the records, then the header counting them, so that a crash can't leave
+    /// a header counting records that weren't written.
+    pub fn sync(&mut self) -> anyhow::Result<()> {
+        self.file.sync_data().context("sync journal")?;
+        self.write_header()?;
+        self.file.sync_data().context("sync journal")?;
+        self.synced = true;
+        Ok(())
+    }
+
+    /// Deletes the journal, which commits the transaction.
+    pub fn finish(self) -> anyhow::Result<()> {
+        std::fs::remove_file(&self.path).with_context(|| format!("delete {}", self.path.display()))
+    }
+
+    fn write_header(&mut self) -> anyhow::Result<()> {
+        let mut header = MAGIC.to_vec();
+        for word in [
+            self.records,
+            self.nonce,
+            self.db_size as u32,
+            SECTOR_SIZE as u32,
+            self.page_size as u32,
+        ] {
+            header.extend(word.to_be_bytes());
+        }
+        header.resize(SECTOR_SIZE, 0);
+        let position = self
+            .file
+            .stream_position()
+            .context("read journal position")?;
+        self.file
+            .seek(SeekFrom::Start(0))
+            .context("seek to journal header")?;
+        self.file
+            .write_all(&header)
+            .context("write journal header")?;
+        self.file
+            .seek(SeekFrom::Start(position.max(SECTOR_SIZE as u64)))
+            .context("seek to journal end")?;
+        Ok(())
+    }
+}
+
+impl Drop for JournalWriter {
+    /// The journal of a transaction that didn't get to write the database file is
+    /// useless, unlike one that did, which rolls it back.
+    fn drop(&mut self) {
+        if !self.synced {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Like SQLite, only samples a byte every 200 of the page.
+fn record_checksum(nonce: u32, data: &[u8]) -> u32 {
+    (1..)
+        .map(|i| data.len() as isize - 200 * i)
+        .take_while(|&i| i > 0)
+        .fold(nonce, |sum, i| sum.wrapping_add(data[i as usize] as u32))
+}
+
+/// The name of the super-journal recorded at the end of the journal, if any.
+fn read_super_journal_name(
+    file: &mut (impl Read + Seek),
+    len: u64,
+) -> anyhow::Result<Option<OsString>> {
+    if len < 16 {
+        return Ok(None);
+    }
+    let mut trailer = [0; 16];
+    file.seek(SeekFrom::Start(len - 16))
+        .context("seek to journal trailer")?;
+    file.read_exact(&mut trailer)
+        .context("read journal trailer")?;
+    if trailer[8..] != MAGIC {
+        return Ok(None);
+    }
+
+    let name_len = read_be_double_at(&trailer, 0) as u64;
+    if name_len == 0 || name_len + 16 > len {
+        return Ok(None);
+    }
+    let mut name = vec![0; name_len as usize];
+    file.seek(SeekFrom::Start(len - 16 - name_len))
+        .context("seek to super-journal name")?;
+    file.read_exact(&mut name)
+        .context("read super-journal name")?;
+
+    let name = String::from_utf8_lossy(&name)
+        .trim_end_matches('\0')
+        .to_string();
+    Ok(Some(name.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const PAGE_SIZE: usize = 512;
+    const NONCE: u32 = 0x1234_5678;
+
+    fn record(page: u32, fill: u8, nonce: u32) -> Vec<u8> {
+        let data = vec![fill; PAGE_SIZE];
+        let mut record = page.to_be_bytes().to_vec();
+        record.extend(&data);
+        record.extend(record_checksum(nonce, &data).to_be_bytes());
+        record
+    }
+
+    #[test]
+    fn original_pages() {
+        let mut file = MAGIC.to_vec();
+        for word in [UNKNOWN_RECORD_COUNT, NONCE, 3, 512, PAGE_SIZE as u32] {
+            file.extend(word.to_be_bytes());
+        }
+        file.resize(512, 0);
+        file.extend(record(2, 1, NONCE));
+        // Appended by the transaction, so not part of the original database.
+        file.extend(record(4, 2, NONCE));
+        file.extend(record(3, 3, NONCE));
+        // Torn by the crash.
+        file.extend(record(1, 4, NONCE + 1));
+
+        let journal = RollbackJournal::read("test-journal", Cursor::new(file))
+            .unwrap()
+            .unwrap();
+        assert_eq!(journal.db_size(), 3);
+        assert_eq!(journal.page(2).unwrap(), [1; PAGE_SIZE]);
+        assert_eq!(journal.page(3).unwrap(), [3; PAGE_SIZE]);
+        assert_eq!(journal.page(4), None);
+        assert_eq!(journal.page(1), None);
+    }
+
+    #[test]
+    fn written_journal() {
+        let path =
+            std::env::temp_dir().join(format!("rqlite-written-journal-{}", std::process::id()));
+        let mut writer = JournalWriter::create(&path, PAGE_SIZE, 3).unwrap();
+        writer.append(2, &[1; PAGE_SIZE]).unwrap();
+        writer.append(2, &[2; PAGE_SIZE]).unwrap();
+        writer.append(4, &[4; PAGE_SIZE]).unwrap();
+        writer.append(3, &[3; PAGE_SIZE]).unwrap();
+        writer.sync().unwrap();
+
+        let file = File::open(&path).unwrap();
+        let journal = RollbackJournal::read(&path, file).unwrap().unwrap();
+        assert_eq!(journal.db_size(), 3);
+        assert_eq!(journal.page(2).unwrap(), [1; PAGE_SIZE]);
+        assert_eq!(journal.page(3).unwrap(), [3; PAGE_SIZE]);
+        assert_eq!(journal.page(4), None);
+
+        writer.finish().unwrap();
+        assert!(!path.exists());
+    }
+}
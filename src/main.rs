@@ -1,30 +1,307 @@
-use std::io::{BufRead, Write, stdin};
+use std::{
+    io::{BufRead, Write, stdin},
+    rc::Rc,
+    sync::OnceLock,
+    time::Duration,
+};
 
 use anyhow::Context;
 
+use crate::{
+    cipher::CipherSettings,
+    page::{Freelist, TextEncoding},
+    ptrmap::Ptrmap,
+};
+
+mod analyzer;
+mod btree;
+mod cipher;
 mod cursor;
 mod db;
+mod diff;
 mod engine;
+mod export;
+mod integrity;
+mod journal;
+#[cfg(feature = "tokio")]
+mod nonblocking;
 mod page;
+mod page_cache;
 mod pager;
+mod ptrmap;
+mod raw;
+mod schema;
+mod server;
 mod sql;
+#[cfg(test)]
+mod testing;
+mod vacuum;
 mod value;
+mod vfs;
+mod vtab;
+mod wal;
+mod writer;
 
 fn main() -> anyhow::Result<()> {
-    let database = db::Db::from_file(std::env::args().nth(1).context("missing db file")?)?;
+    let mut args = std::env::args().skip(1).peekable();
+    if args.next_if_eq("diff").is_some() {
+        return diff_databases(args);
+    }
+    if args.next_if_eq("schema-diff").is_some() {
+        return diff_schemas(args);
+    }
+    if args.next_if_eq("serve").is_some() {
+        return serve_database(args);
+    }
+
+    let database = open_database(args)?;
     cli(database)
 }
 
+fn diff_databases(mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let source = args.next().context("missing source db file")?;
+    let target = args.next().context("missing target db file")?;
+
+    let source = db::Db::from_file(source)?;
+    let target = db::Db::from_file(target)?;
+
+    let mut out = std::io::stdout().lock();
+    diff::diff(&source, &target, &mut out)?;
+    out.flush().context("flush stdout")
+}
+
+fn diff_schemas(mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let source = load_schema(&args.next().context("missing source schema")?)?;
+    let target = load_schema(&args.next().context("missing target schema")?)?;
+
+    let mut out = std::io::stdout().lock();
+    for statement in schema::migrate(&source, &target)? {
+        writeln!(out, "{statement};")?;
+    }
+    out.flush().context("flush stdout")
+}
+
+/// Reads a schema from a `.sql` script, or from a database file otherwise.
+fn load_schema(path: &str) -> anyhow::Result<schema::Schema> {
+    if path.ends_with(".sql") {
+        let script = std::fs::read_to_string(path).with_context(|| format!("read {path}"))?;
+        schema::Schema::from_script(&script)
+    } else {
+        schema::Schema::from_db(&db::Db::from_file(path)?)
+    }
+}
+
+fn serve_database(mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let mut address = "127.0.0.1:8080".to_string();
+    let mut slow_query_threshold = None;
+    let mut result_cache_size = None;
+    let mut memory_limit = None;
+    let mut db_args = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => address = args.next().context("missing value for --listen")?,
+            "--slow-query-ms" => {
+                let value = args.next().context("missing value for --slow-query-ms")?;
+                slow_query_threshold = parse_slow_query_threshold(&value)?;
+            }
+            "--result-cache" => {
+                let value = args.next().context("missing value for --result-cache")?;
+                result_cache_size = parse_result_cache_size(&value)?;
+            }
+            "--memory-limit" => {
+                let value = args.next().context("missing value for --memory-limit")?;
+                memory_limit = parse_memory_limit(&value)?;
+            }
+            _ => db_args.push(arg),
+        }
+    }
+
+    let mut database = open_database(db_args.into_iter())?;
+    database.set_slow_query_threshold(slow_query_threshold);
+    database.set_result_cache_size(result_cache_size);
+    database.set_memory_limit(memory_limit);
+    server::serve(database, &address)
+}
+
+fn open_database(mut args: impl Iterator<Item = String>) -> anyhow::Result<db::Db> {
+    let mut file = None;
+    let mut key = None;
+    let mut cipher_settings = CipherSettings::default();
+    let mut cipher_page_size = None;
+    let mut cache_size = None;
+    let mut options = db::OpenOptions::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--key" => key = Some(args.next().context("missing value for --key")?),
+            "--cipher-compat" => {
+                let version = args
+                    .next()
+                    .context("missing value for --cipher-compat")?
+                    .parse()
+                    .context("invalid --cipher-compat value")?;
+                cipher_settings = CipherSettings::compat(version)?;
+            }
+            "--cipher-page-size" => {
+                let page_size = args
+                    .next()
+                    .context("missing value for --cipher-page-size")?
+                    .parse()
+                    .context("invalid --cipher-page-size value")?;
+                cipher_page_size = Some(page_size);
+            }
+            "--mmap" => options.mmap = true,
+            "--cache-size" => {
+                let value = args.next().context("missing value for --cache-size")?;
+                cache_size = Some(parse_cache_size(&value)?);
+            }
+            flag if flag.starts_with("--") => anyhow::bail!("unknown option: {flag}"),
+            _ => {
+                anyhow::ensure!(file.is_none(), "unexpected argument: {arg}");
+                file = Some(arg);
+            }
+        }
+    }
+
+    let file = file.context("missing db file")?;
+
+    let db = match key {
+        Some(key) => {
+            anyhow::ensure!(!options.mmap, "encrypted db files can't be memory-mapped");
+            if let Some(page_size) = cipher_page_size {
+                cipher_settings.page_size = page_size;
+            }
+            db::Db::from_encrypted_file(file, &key, cipher_settings)?
+        }
+        None => db::Db::open(file, options)?,
+    };
+    if let Some(pages) = cache_size {
+        db.set_page_cache_size(pages)?;
+    }
+    Ok(db)
+}
+
 fn cli(mut db: db::Db) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    interrupt_on_ctrl_c(db.interrupt_handle());
     print_flushed("rqlite> ")?;
 
     let mut line_buffer = String::new();
+    let mut show_stats = false;
+    let mut show_headers = false;
+    let mut mode = OutputMode::List;
+    let mut params = engine::Params::default();
 
-    while stdin().lock().read_line(&mut line_buffer).is_ok() {
+    while stdin().lock().read_line(&mut line_buffer)? > 0 {
         match line_buffer.trim() {
             ".exit" => break,
             ".tables" => display_tables(&mut db)?,
-            stmt => eval_query(&db, stmt)?,
+            ".space" => {
+                if let Err(e) = display_space_usage(&db) {
+                    println!("Error: {e:#}");
+                }
+            }
+            ".memory_budget" => println!("{}", db.memory_budget()),
+            cmd if cmd.starts_with(".memory_budget ") => {
+                match cmd[".memory_budget ".len()..].trim().parse() {
+                    Ok(bytes) => db.set_memory_budget(bytes),
+                    Err(e) => println!("Error: invalid memory budget: {e}"),
+                }
+            }
+            ".memory_limit" => match db.memory_limit() {
+                Some(bytes) => println!("{bytes}"),
+                None => println!("off"),
+            },
+            cmd if cmd.starts_with(".memory_limit ") => {
+                match parse_memory_limit(&cmd[".memory_limit ".len()..]) {
+                    Ok(bytes) => db.set_memory_limit(bytes),
+                    Err(e) => println!("Error: {e:#}"),
+                }
+            }
+            ".cache_size" => match db.page_cache_size()? {
+                Some(pages) => println!("{pages}"),
+                None => println!("off"),
+            },
+            cmd if cmd.starts_with(".cache_size ") => {
+                match parse_cache_size(&cmd[".cache_size ".len()..]) {
+                    Ok(pages) => db.set_page_cache_size(pages)?,
+                    Err(e) => println!("Error: {e:#}"),
+                }
+            }
+            ".mode list" => mode = OutputMode::List,
+            ".mode ndjson" => mode = OutputMode::Ndjson,
+            cmd if cmd.starts_with(".clone ") => {
+                match parse_clone(&cmd[".clone ".len()..])
+                    .and_then(|(path, options)| Ok((path, db.backup(path, options)?)))
+                {
+                    Ok((path, pages)) => println!("copied {pages} pages to {path}"),
+                    Err(e) => println!("Error: {e:#}"),
+                }
+            }
+            ".dbinfo" => {
+                if let Err(e) = display_db_info(&db) {
+                    println!("Error: {e:#}");
+                }
+            }
+            ".pages" => {
+                if let Err(e) = display_pages(&db) {
+                    println!("Error: {e:#}");
+                }
+            }
+            cmd if cmd.starts_with(".page ") => {
+                if let Err(e) = display_page(&db, &cmd[".page ".len()..]) {
+                    println!("Error: {e:#}");
+                }
+            }
+            cmd if cmd.starts_with(".cell ") => {
+                if let Err(e) = display_cell(&db, &cmd[".cell ".len()..]) {
+                    println!("Error: {e:#}");
+                }
+            }
+            cmd if cmd.starts_with(".btree ") => {
+                if let Err(e) = display_btree(&db, &cmd[".btree ".len()..]) {
+                    println!("Error: {e:#}");
+                }
+            }
+            ".case_folding ascii" => db.set_case_folding(engine::CaseFolding::Ascii),
+            ".case_folding unicode" => db.set_case_folding(engine::CaseFolding::Unicode),
+            ".headers on" => show_headers = true,
+            ".headers off" => show_headers = false,
+            ".stats on" => show_stats = true,
+            ".stats off" => show_stats = false,
+            ".slow_query_ms" => match db.slow_query_threshold() {
+                Some(threshold) => println!("{}", threshold.as_millis()),
+                None => println!("off"),
+            },
+            cmd if cmd.starts_with(".slow_query_ms ") => {
+                match parse_slow_query_threshold(&cmd[".slow_query_ms ".len()..]) {
+                    Ok(threshold) => db.set_slow_query_threshold(threshold),
+                    Err(e) => println!("Error: {e:#}"),
+                }
+            }
+            ".result_cache" => match db.result_cache() {
+                Some(cache) => println!("{}", cache.capacity()),
+                None => println!("off"),
+            },
+            cmd if cmd.starts_with(".result_cache ") => {
+                match parse_result_cache_size(&cmd[".result_cache ".len()..]) {
+                    Ok(size) => db.set_result_cache_size(size),
+                    Err(e) => println!("Error: {e:#}"),
+                }
+            }
+            cmd if cmd.starts_with(".param set ") => {
+                if let Err(e) = set_param(&mut params, &cmd[".param set ".len()..]) {
+                    println!("Error: {e:#}");
+                }
+            }
+            ".param clear" => params.clear(),
+            "" => {}
+            stmt => {
+                if let Err(e) = eval_query(&db, stmt, &params, mode, show_headers, show_stats) {
+                    println!("Error: {e:#}");
+                }
+            }
         }
 
         print_flushed("\nrqlite> ")?;
@@ -35,30 +312,359 @@ fn cli(mut db: db::Db) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Makes Ctrl-C interrupt the running query rather than exit.
+#[cfg(unix)]
+fn interrupt_on_ctrl_c(handle: db::InterruptHandle) {
+    static HANDLE: OnceLock<db::InterruptHandle> = OnceLock::new();
+
+    extern "C" fn on_sigint(_: libc::c_int) {
+        if let Some(handle) = HANDLE.get() {
+            handle.interrupt();
+        }
+    }
+
+    if HANDLE.set(handle).is_ok() {
+        let handler = on_sigint as extern "C" fn(libc::c_int) as *const () as libc::sighandler_t;
+        // SAFETY: the handler only loads and stores atomics, which is async-signal-safe.
+        unsafe { libc::signal(libc::SIGINT, handler) };
+    }
+}
+
 fn display_tables(db: &mut db::Db) -> anyhow::Result<()> {
-    for table in &db.tables_metadata {
+    for table in db.refresh_metadata()?.tables() {
         print!("{} ", &table.name)
     }
     Ok(())
 }
 
+fn display_space_usage(db: &db::Db) -> anyhow::Result<()> {
+    println!("name|pages|leaf|interior|overflow|cells|payload|unused|fragmentation");
+    for stats in db.space_usage()? {
+        println!(
+            "{}|{}|{}|{}|{}|{}|{}|{}|{:.1}%",
+            stats.name,
+            stats.pages(),
+            stats.leaf_pages,
+            stats.interior_pages,
+            stats.overflow_pages,
+            stats.cells,
+            stats.payload_bytes,
+            stats.unused_bytes,
+            stats.fragmentation()
+        );
+    }
+    Ok(())
+}
+
+fn display_db_info(db: &db::Db) -> anyhow::Result<()> {
+    let pager = db.pager();
+    let header = pager.read_header()?;
+    let encoding = match header.text_encoding {
+        TextEncoding::Utf8 => "1 (utf8)",
+        TextEncoding::Utf16Le => "2 (utf16le)",
+        TextEncoding::Utf16Be => "3 (utf16be)",
+    };
+    println!("{:<20} {}", "database page size:", header.page_size);
+    println!("{:<20} {}", "write format:", header.write_version);
+    println!("{:<20} {}", "read format:", header.read_version);
+    println!("{:<20} {}", "reserved bytes:", header.page_reserved_size);
+    println!("{:<20} {}", "file change counter:", header.change_counter);
+    println!("{:<20} {}", "database page count:", pager.page_count()?);
+    println!("{:<20} {}", "freelist page count:", db.free_page_count()?);
+    println!("{:<20} {}", "schema cookie:", header.schema_cookie);
+    println!("{:<20} {}", "schema format:", header.schema_format);
+    println!(
+        "{:<20} {}",
+        "default cache size:", header.default_cache_size
+    );
+    println!(
+        "{:<20} {}",
+        "autovacuum top root:", header.largest_root_page
+    );
+    println!(
+        "{:<20} {}",
+        "incremental vacuum:", header.incremental_vacuum as u8
+    );
+    println!("{:<20} {}", "text encoding:", encoding);
+    println!("{:<20} {}", "user version:", header.user_version);
+    println!("{:<20} {}", "application id:", header.application_id);
+    println!("{:<20} {}", "software version:", header.sqlite_version);
+    Ok(())
+}
+
+/// What the pages that aren't part of a b-tree are used for.
+struct PageRoles {
+    freelist: Freelist,
+    ptrmap: Option<Ptrmap>,
+    lock_byte_page: usize,
+}
+
+impl PageRoles {
+    fn read(db: &db::Db) -> anyhow::Result<Self> {
+        Ok(Self {
+            freelist: db.freelist()?,
+            ptrmap: db.pager().ptrmap()?,
+            lock_byte_page: pager::lock_byte_page(db.pager().header().page_size),
+        })
+    }
+
+    fn page_type_name(&self, page: &raw::RawPage) -> &'static str {
+        if page.number == self.lock_byte_page {
+            return "lock-byte";
+        }
+        if self
+            .ptrmap
+            .is_some_and(|ptrmap| ptrmap.is_ptrmap_page(page.number))
+        {
+            return "ptrmap";
+        }
+        if self.freelist.trunks.contains(&page.number) {
+            return "freelist trunk";
+        }
+        if self.freelist.leaves.contains(&page.number) {
+            return "freelist leaf";
+        }
+        btree_page_type_name(page)
+    }
+}
+
+fn btree_page_type_name(page: &raw::RawPage) -> &'static str {
+    page.page_type.map_or("other", |t| t.name())
+}
+
+fn parse_page_number(value: &str) -> anyhow::Result<usize> {
+    value
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid page number: {value}"))
+}
+
+fn display_pages(db: &db::Db) -> anyhow::Result<()> {
+    println!("page|type|cells");
+    let roles = PageRoles::read(db)?;
+    for page in raw::pages(db.pager())? {
+        let page = page?;
+        println!(
+            "{}|{}|{}",
+            page.number,
+            roles.page_type_name(&page),
+            page.cell_pointers.len()
+        );
+    }
+    Ok(())
+}
+
+fn display_page(db: &db::Db, args: &str) -> anyhow::Result<()> {
+    let page = raw::RawPage::read(db.pager(), parse_page_number(args)?)?;
+    let roles = PageRoles::read(db)?;
+    println!("type: {}", roles.page_type_name(&page));
+    if let Some(ptrmap) = roles.ptrmap
+        && ptrmap.is_ptrmap_page(page.number)
+    {
+        println!("page|type|parent");
+        for (n, entry) in ptrmap.entries(page.number, &page.data)? {
+            println!("{n}|{}|{}", entry.kind.name(), entry.parent);
+        }
+        return Ok(());
+    }
+    if page.page_type.is_none() {
+        return Ok(());
+    }
+
+    println!("first freeblock: {}", page.first_freeblock);
+    println!("fragmented bytes: {}", page.fragmented_bytes);
+    println!("content start: {}", page.content_start);
+    if let Some(rightmost) = page.rightmost_pointer {
+        println!("rightmost pointer: {rightmost}");
+    }
+    let pointers: Vec<String> = page.cell_pointers.iter().map(|p| p.to_string()).collect();
+    println!("cell pointers: {}", pointers.join(" "));
+    Ok(())
+}
+
+fn display_cell(db: &db::Db, args: &str) -> anyhow::Result<()> {
+    let (page, index) = args
+        .trim()
+        .split_once(' ')
+        .context("usage: .cell PAGE INDEX")?;
+    let page = raw::RawPage::read(db.pager(), parse_page_number(page)?)?;
+    let index = index.trim().parse().context("invalid cell index")?;
+    let cell = page.cell(index)?;
+
+    println!("offset: {}", cell.offset);
+    if let Some(left_child) = cell.left_child {
+        println!("left child: {left_child}");
+    }
+    if let Some(rowid) = cell.rowid {
+        println!("rowid: {rowid}");
+    }
+    if let Some(payload_size) = cell.payload_size {
+        println!("payload size: {payload_size}");
+        let hex: String = cell
+            .local_payload
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        println!("local payload: {hex}");
+    }
+    if let Some(first_overflow) = cell.first_overflow {
+        println!("first overflow page: {first_overflow}");
+    }
+    Ok(())
+}
+
+fn display_btree(db: &db::Db, args: &str) -> anyhow::Result<()> {
+    for page in raw::walk_btree(db.pager(), parse_page_number(args)?) {
+        let (depth, page) = page?;
+        println!(
+            "{}{}: {}, {} cells",
+            "  ".repeat(depth),
+            page.number,
+            btree_page_type_name(&page),
+            page.cell_pointers.len()
+        );
+    }
+    Ok(())
+}
+
+/// Parses a threshold in milliseconds, or `off`.
+fn parse_slow_query_threshold(value: &str) -> anyhow::Result<Option<Duration>> {
+    match value.trim() {
+        "off" => Ok(None),
+        ms => {
+            let ms = ms.parse().context("invalid slow query threshold")?;
+            Ok(Some(Duration::from_millis(ms)))
+        }
+    }
+}
+
+/// Parses a result cache size in bytes, or `off`.
+fn parse_result_cache_size(value: &str) -> anyhow::Result<Option<usize>> {
+    match value.trim() {
+        "off" => Ok(None),
+        bytes => Ok(Some(bytes.parse().context("invalid result cache size")?)),
+    }
+}
+
+/// Parses a limit on the pages the page cache holds, or `off`.
+fn parse_cache_size(value: &str) -> anyhow::Result<Option<usize>> {
+    match value.trim() {
+        "off" => Ok(None),
+        pages => Ok(Some(pages.parse().context("invalid cache size")?)),
+    }
+}
+
+/// Parses the arguments of `.clone`: the options, then the path of the copy.
+fn parse_clone(args: &str) -> anyhow::Result<(&str, db::BackupOptions)> {
+    let mut options = db::BackupOptions::default();
+    let mut rest = args.trim();
+    while let Some(option) = rest.strip_prefix("--") {
+        let (name, tail) = option
+            .split_once(char::is_whitespace)
+            .unwrap_or((option, ""));
+        match name {
+            "vacuum" => options.vacuum = true,
+            "plaintext" => options.plaintext = true,
+            _ => anyhow::bail!("unknown option: --{name}"),
+        }
+        rest = tail.trim_start();
+    }
+    anyhow::ensure!(!rest.is_empty(), "missing path of the copy");
+    Ok((rest, options))
+}
+
+/// Parses a limit on the memory of queries in bytes, or `off`.
+fn parse_memory_limit(value: &str) -> anyhow::Result<Option<usize>> {
+    match value.trim() {
+        "off" => Ok(None),
+        bytes => Ok(Some(bytes.parse().context("invalid memory limit")?)),
+    }
+}
+
+/// Binds the parameter named by the first word of `args`, `?N` or `:name`, to the
+/// rest: a number, a quoted string, NULL, or else the text as is.
+fn set_param(params: &mut engine::Params, args: &str) -> anyhow::Result<()> {
+    let (name, value) = args
+        .trim()
+        .split_once(char::is_whitespace)
+        .context("usage: .param set NAME VALUE")?;
+    let value = match value.trim() {
+        v if v.eq_ignore_ascii_case("null") => value::OwnedValue::Null,
+        v if v.len() >= 2 && v.starts_with('\'') && v.ends_with('\'') => {
+            let text = v[1..v.len() - 1].replace("''", "'");
+            value::OwnedValue::String(Rc::new(text))
+        }
+        v => match (v.parse(), v.parse()) {
+            (Ok(i), _) => value::OwnedValue::Int(i),
+            (_, Ok(x)) => value::OwnedValue::Float(x),
+            _ => value::OwnedValue::String(Rc::new(v.to_string())),
+        },
+    };
+
+    match name.strip_prefix('?') {
+        Some(index) => params.bind(index.parse().context("invalid parameter index")?, value),
+        None => params.bind_named(name, value),
+    }
+    Ok(())
+}
+
 fn print_flushed(s: &str) -> anyhow::Result<()> {
     print!("{s}");
     std::io::stdout().flush().context("flush stdout")
 }
 
-fn eval_query(db: &db::Db, query: &str) -> anyhow::Result<()> {
-    let parsed_query = sql::parse_statement(query, false)?;
-    let mut op = engine::plan::Planner::new(db).compile(&parsed_query)?;
+/// How the REPL prints rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputMode {
+    /// Values separated by `|`.
+    List,
+    /// One JSON object per row.
+    Ndjson,
+}
+
+fn eval_query(
+    db: &db::Db,
+    query: &str,
+    params: &engine::Params,
+    mode: OutputMode,
+    show_headers: bool,
+    show_stats: bool,
+) -> anyhow::Result<()> {
+    let mut op = db.query_with_params(query, params)?;
 
-    while let Some(values) = op.next_row()? {
-        let formated = values
-            .iter()
-            .map(ToString::to_string)
-            .collect::<Vec<_>>()
-            .join("|");
+    match mode {
+        OutputMode::List => {
+            let mut headers =
+                show_headers.then(|| op.schema().names().collect::<Vec<_>>().join("|"));
+            while let Some(values) = op.next_row()? {
+                // Like SQLite, the names are only printed above rows.
+                if let Some(headers) = headers.take() {
+                    println!("{headers}");
+                }
+                let formated = values
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("|");
+
+                println!("{formated}");
+            }
+        }
+        OutputMode::Ndjson => export::export_ndjson(&mut op, &mut std::io::stdout().lock())?,
+    }
 
-        println!("{formated}");
+    if show_stats {
+        let stats = op.stats();
+        println!(
+            "rows scanned: {}, rows returned: {}, pages read: {}, cache hits: {}, \
+             peak memory: {} bytes, elapsed: {:.1?}",
+            stats.rows_scanned,
+            stats.rows_returned,
+            stats.pages_read,
+            stats.cache_hits,
+            stats.peak_memory,
+            stats.elapsed
+        );
     }
 
     Ok(())
@@ -0,0 +1,290 @@
+//! Async access to databases, for services running on tokio. The pager reads the file
+//! with blocking calls, and the operators of a query share state through `Rc`, so
+//! rather than blocking the executor threads they run on the blocking pool of tokio:
+//! page reads one at a time, scans and queries on a thread of their own sending their
+//! rows back through a bounded channel. Dropping a scan or a query stops its thread
+//! at the next row.
+
+#![cfg_attr(
+    not(test),
+    expect(dead_code, reason = "the CLI and the server run queries on threads")
+)]
+
+use std::sync::Arc;
+
+use anyhow::{Context, anyhow};
+use tokio::{sync::mpsc, task};
+
+use crate::{
+    cursor::Scanner,
+    db::Db,
+    engine::{ExecutionStats, Params, Schema},
+    page,
+    pager::Pager,
+    value::SendValue,
+};
+
+/// Rows buffered by a scan or a query ahead of the task consuming them.
+const CHANNEL_ROWS: usize = 256;
+
+/// Runs `f` on the blocking pool.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    task::spawn_blocking(f)
+        .await
+        .map_err(|e| anyhow!("blocking task failed: {e}"))?
+}
+
+#[derive(Debug, Clone)]
+pub struct AsyncPager {
+    pager: Pager,
+}
+
+impl AsyncPager {
+    pub fn new(pager: Pager) -> Self {
+        Self { pager }
+    }
+
+    pub async fn page_count(&self) -> anyhow::Result<usize> {
+        let pager = self.pager.clone();
+        blocking(move || pager.page_count()).await
+    }
+
+    pub async fn read_page(&self, n: usize) -> anyhow::Result<Arc<page::Page>> {
+        let pager = self.pager.clone();
+        blocking(move || pager.read_page(n)).await
+    }
+
+    pub async fn read_raw(&self, n: usize) -> anyhow::Result<Vec<u8>> {
+        let pager = self.pager.clone();
+        blocking(move || pager.read_raw(n)).await
+    }
+
+    /// Scans the b-tree rooted at `root`.
+    pub fn scan(&self, root: usize) -> AsyncScanner {
+        AsyncScanner::new(Scanner::new(root, self.pager.clone()))
+    }
+}
+
+/// The records of a b-tree, as their rowid and the values of their fields.
+#[derive(Debug)]
+pub struct AsyncScanner {
+    records: mpsc::Receiver<anyhow::Result<(i64, Vec<SendValue>)>>,
+}
+
+impl AsyncScanner {
+    pub fn new(mut scanner: Scanner) -> Self {
+        let (sender, records) = mpsc::channel(CHANNEL_ROWS);
+        task::spawn_blocking(move || {
+            let next = |scanner: &mut Scanner| {
+                let Some(mut record) = scanner.next_record()? else {
+                    return Ok(None);
+                };
+                let mut values = Vec::new();
+                while let Some(value) = record.owned_field(values.len())? {
+                    values.push(SendValue::from(&value));
+                }
+                Ok(Some((record.rowid(), values)))
+            };
+            loop {
+                let record = match next(&mut scanner) {
+                    Ok(Some(record)) => Ok(record),
+                    Ok(None) => return,
+                    Err(e) => Err(e),
+                };
+                let failed = record.is_err();
+                if sender.blocking_send(record).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Self { records }
+    }
+
+    pub async fn next_record(&mut self) -> anyhow::Result<Option<(i64, Vec<SendValue>)>> {
+        self.records.recv().await.transpose()
+    }
+}
+
+#[derive(Clone)]
+pub struct AsyncDb {
+    db: Arc<Db>,
+}
+
+#[derive(Debug)]
+enum QueryMessage {
+    Schema(Schema),
+    Row(Vec<SendValue>),
+    Done(ExecutionStats),
+}
+
+impl AsyncDb {
+    pub fn new(db: Db) -> Self {
+        Self { db: Arc::new(db) }
+    }
+
+    pub fn pager(&self) -> AsyncPager {
+        AsyncPager::new(self.db.pager().clone())
+    }
+
+    /// Plans `sql`, with `params` bound to its parameters in order, and starts running
+    /// it.
+    pub async fn query(&self, sql: &str, params: Vec<SendValue>) -> anyhow::Result<AsyncQuery> {
+        let (sender, mut messages) = mpsc::channel(CHANNEL_ROWS);
+        let db = self.db.clone();
+        let sql = sql.to_string();
+        task::spawn_blocking(move || {
+            let mut bound = Params::default();
+            for (i, value) in params.into_iter().enumerate() {
+                bound.bind(i + 1, value.into());
+            }
+            run_query(&db, &sql, &bound, &sender);
+        });
+
+        match messages.recv().await.context("query task failed")?? {
+            QueryMessage::Schema(schema) => Ok(AsyncQuery {
+                schema,
+                messages,
+                stats: None,
+            }),
+            _ => Err(anyhow!("query sent rows before its columns")),
+        }
+    }
+}
+
+/// Sends the schema of the query, then its rows, then its statistics, until it's done,
+/// fails, or the `AsyncQuery` is dropped.
+fn run_query(
+    db: &Db,
+    sql: &str,
+    params: &Params,
+    sender: &mpsc::Sender<anyhow::Result<QueryMessage>>,
+) {
+    let mut query = match db.query_with_params(sql, params) {
+        Ok(query) => query,
+        Err(e) => {
+            let _ = sender.blocking_send(Err(e));
+            return;
+        }
+    };
+    if sender
+        .blocking_send(Ok(QueryMessage::Schema(query.schema().clone())))
+        .is_err()
+    {
+        return;
+    }
+
+    loop {
+        let message = match query.next_row() {
+            Ok(Some(row)) => Ok(QueryMessage::Row(row.iter().map(SendValue::from).collect())),
+            Ok(None) => Ok(QueryMessage::Done(query.stats())),
+            Err(e) => Err(e),
+        };
+        let done = !matches!(message, Ok(QueryMessage::Row(_)));
+        if sender.blocking_send(message).is_err() || done {
+            return;
+        }
+    }
+}
+
+/// A query running on the blocking pool.
+#[derive(Debug)]
+pub struct AsyncQuery {
+    schema: Schema,
+    messages: mpsc::Receiver<anyhow::Result<QueryMessage>>,
+    stats: Option<ExecutionStats>,
+}
+
+impl AsyncQuery {
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    pub async fn next_row(&mut self) -> anyhow::Result<Option<Vec<SendValue>>> {
+        if self.stats.is_some() {
+            return Ok(None);
+        }
+        match self.messages.recv().await.context("query task failed")?? {
+            QueryMessage::Row(row) => Ok(Some(row)),
+            QueryMessage::Done(stats) => {
+                self.stats = Some(stats);
+                Ok(None)
+            }
+            QueryMessage::Schema(_) => Err(anyhow!("query sent its columns twice")),
+        }
+    }
+
+    /// What the query did, once all its rows were read.
+    pub fn stats(&self) -> Option<ExecutionStats> {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        pager::HEADER_PREFIX,
+        testing::{record, table_cell, text, write_leaf},
+    };
+
+    use super::*;
+
+    const PAGE_SIZE: usize = 512;
+
+    #[test]
+    fn async_reads() {
+        let mut file = vec![0; 2 * PAGE_SIZE];
+        file[..16].copy_from_slice(HEADER_PREFIX);
+        file[16..18].copy_from_slice(&(PAGE_SIZE as u16).to_be_bytes());
+        file[28..32].copy_from_slice(&2u32.to_be_bytes());
+        let schema = record(&[
+            text("table"),
+            text("t"),
+            text("t"),
+            SendValue::Int(2),
+            text("CREATE TABLE t(a)"),
+        ]);
+        write_leaf(&mut file[..PAGE_SIZE], 100, 0x0d, &[table_cell(1, &schema)]);
+        let rows = [
+            table_cell(1, &record(&[text("x")])),
+            table_cell(2, &record(&[text("y")])),
+        ];
+        write_leaf(&mut file[PAGE_SIZE..], 0, 0x0d, &rows);
+        let path = std::env::temp_dir().join(format!("rqlite-async-{}", std::process::id()));
+        std::fs::write(&path, file).unwrap();
+
+        let db = AsyncDb::new(Db::from_file(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let pager = db.pager();
+            assert_eq!(pager.page_count().await.unwrap(), 2);
+            assert_eq!(pager.read_page(2).await.unwrap().cells.len(), 2);
+            assert_eq!(pager.read_raw(2).await.unwrap().len(), PAGE_SIZE);
+
+            let mut scanner = pager.scan(2);
+            assert_eq!(
+                scanner.next_record().await.unwrap(),
+                Some((1, vec![text("x")]))
+            );
+            assert_eq!(
+                scanner.next_record().await.unwrap(),
+                Some((2, vec![text("y")]))
+            );
+            assert_eq!(scanner.next_record().await.unwrap(), None);
+
+            let mut query = db
+                .query("select a from t where a = ?1", vec![text("y")])
+                .await
+                .unwrap();
+            assert_eq!(query.schema().names().collect::<Vec<_>>(), ["a"]);
+            assert_eq!(query.next_row().await.unwrap(), Some(vec![text("y")]));
+            assert_eq!(query.next_row().await.unwrap(), None);
+            assert_eq!(query.stats().unwrap().rows_returned, 1);
+            assert!(db.query("select b from t", Vec::new()).await.is_err());
+        });
+    }
+}
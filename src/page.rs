@@ -1,21 +1,91 @@
+use std::{
+    borrow::Cow,
+    sync::{Arc, OnceLock},
+};
+
 use anyhow::bail;
 
-#[derive(Debug, Copy, Clone)]
+use crate::cursor::RecordHeader;
+
+/// The 100-byte header at the start of the first page.
+#[derive(Debug, Copy, Clone, Default)]
 pub struct DbHeader {
     pub page_size: u32,
+    /// 1 for rollback journal mode, 2 for WAL mode.
+    pub write_version: u8,
+    pub read_version: u8,
     pub page_reserved_size: u8,
+    /// Incremented by each transaction committed in rollback journal mode.
+    pub change_counter: u32,
+    /// The size of the database in pages, only valid when `version_valid_for` matches
+    /// `change_counter`, see `valid_page_count`.
+    pub page_count: u32,
+    pub freelist_trunk: u32,
+    pub freelist_count: u32,
+    /// Incremented each time the schema changes.
+    pub schema_cookie: u32,
+    pub schema_format: u32,
+    pub default_cache_size: u32,
+    /// The largest root page in auto_vacuum or incremental_vacuum mode, 0 otherwise.
+    pub largest_root_page: u32,
+    pub text_encoding: TextEncoding,
+    pub user_version: u32,
+    pub incremental_vacuum: bool,
+    pub application_id: u32,
+    /// The change counter when the version number below was stored.
+    pub version_valid_for: u32,
+    /// The version of the last SQLite library that wrote to the file, e.g. 3040001.
+    pub sqlite_version: u32,
+}
+
+/// The encoding of all the text of a database.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum TextEncoding {
+    #[default]
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl TextEncoding {
+    /// Decodes text stored in the database. Invalid sequences are replaced rather than
+    /// failing the query, as SQLite doesn't validate the text it stores either.
+    pub fn decode(self, bytes: &[u8]) -> Cow<'_, str> {
+        let from_bytes = match self {
+            TextEncoding::Utf8 => return String::from_utf8_lossy(bytes),
+            TextEncoding::Utf16Le => u16::from_le_bytes,
+            TextEncoding::Utf16Be => u16::from_be_bytes,
+        };
+        let units = bytes
+            .chunks_exact(2)
+            .map(|unit| from_bytes([unit[0], unit[1]]));
+        Cow::Owned(
+            char::decode_utf16(units)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        )
+    }
 }
 
 impl DbHeader {
     pub fn usable_page_size(&self) -> usize {
         self.page_size as usize - (self.page_reserved_size as usize)
     }
+
+    /// The size of the database in pages, unless it was last written by a legacy
+    /// version of SQLite that didn't update it.
+    pub fn valid_page_count(&self) -> Option<usize> {
+        (self.page_count > 0 && self.version_valid_for == self.change_counter)
+            .then_some(self.page_count as usize)
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum PageType {
     TableLeaf,
     TableInterior,
+    IndexLeaf,
+    IndexInterior,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -54,21 +124,30 @@ impl PageHeader {
     ) -> anyhow::Result<usize> {
         match self.page_type {
             PageType::TableInterior => bail!("no payload size for interior pages"),
-            PageType::TableLeaf => {
-                let usable = db_header.usable_page_size();
-                let max_size = usable - 35;
-                if payload_size <= max_size {
-                    return Ok(payload_size);
-                }
-                let min_size = ((usable - 12) * 32 / 255) - 23;
-                let k = min_size + ((payload_size - min_size) % (usable - 4));
-                let size = if k <= max_size { k } else { min_size };
-                Ok(size)
-            }
+            page_type => Ok(local_payload_size(
+                page_type,
+                db_header.usable_page_size(),
+                payload_size,
+            )),
         }
     }
 }
 
+/// How many bytes of a payload of `payload_size` bytes a cell of a page of `page_type`
+/// holds, the rest going to overflow pages.
+pub fn local_payload_size(page_type: PageType, usable: usize, payload_size: usize) -> usize {
+    let max_size = match page_type {
+        PageType::TableLeaf => usable - 35,
+        _ => ((usable - 12) * 64 / 255) - 23,
+    };
+    if payload_size <= max_size {
+        return payload_size;
+    }
+    let min_size = ((usable - 12) * 32 / 255) - 23;
+    let k = min_size + ((payload_size - min_size) % (usable - 4));
+    if k <= max_size { k } else { min_size }
+}
+
 #[derive(Debug, Clone)]
 pub struct Page {
     pub header: PageHeader,
@@ -83,19 +162,36 @@ impl Page {
 
 #[derive(Debug, Clone)]
 pub struct TableLeafCell {
-    pub payload: Vec<u8>,
+    pub rowid: i64,
+    /// Local part of the payload, shared with the cursors reading the cell.
+    pub payload: Arc<[u8]>,
     pub first_overflow: Option<usize>,
+    /// Parsed lazily by the first scan reading the cell, and shared by the next ones.
+    pub header: OnceLock<Arc<RecordHeader>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct TableInteriorCell {
     pub left_child_page: u32,
+    /// The largest rowid of the left child.
+    pub key: i64,
+}
+
+/// A key of an index b-tree, which WITHOUT ROWID tables are stored in. The keys of
+/// interior pages come between the keys of their left child and the next ones.
+#[derive(Debug, Clone)]
+pub struct IndexCell {
+    pub left_child_page: Option<u32>,
+    pub payload: Arc<[u8]>,
+    pub first_overflow: Option<usize>,
+    pub header: OnceLock<Arc<RecordHeader>>,
 }
 
 #[derive(Debug, Clone)]
 pub enum Cell {
     TableLeaf(TableLeafCell),
     TableInterior(TableInteriorCell),
+    Index(IndexCell),
 }
 
 impl From<TableLeafCell> for Cell {
@@ -110,8 +206,53 @@ impl From<TableInteriorCell> for Cell {
     }
 }
 
+impl From<IndexCell> for Cell {
+    fn from(cell: IndexCell) -> Self {
+        Cell::Index(cell)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct OverflowPage {
     pub next: Option<usize>,
     pub payload: Vec<u8>,
 }
+
+/// A trunk page of the freelist, listing free leaf pages.
+#[derive(Debug, Clone)]
+pub struct FreelistTrunkPage {
+    pub next: Option<usize>,
+    pub leaves: Vec<usize>,
+}
+
+/// The unused pages of a database, which writers reuse before growing the file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Freelist {
+    pub trunks: Vec<usize>,
+    pub leaves: Vec<usize>,
+}
+
+impl Freelist {
+    pub fn page_count(&self) -> usize {
+        self.trunks.len() + self.leaves.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_text() {
+        let clef = "𝄞é";
+        let le: Vec<u8> = clef.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let be: Vec<u8> = clef.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        assert_eq!(TextEncoding::Utf16Le.decode(&le), clef);
+        assert_eq!(TextEncoding::Utf16Be.decode(&be), clef);
+        assert_eq!(TextEncoding::Utf8.decode(clef.as_bytes()), clef);
+
+        // A lone surrogate, and invalid UTF-8.
+        assert_eq!(TextEncoding::Utf16Le.decode(&[0x34, 0xd8]), "\u{fffd}");
+        assert_eq!(TextEncoding::Utf8.decode(&[b'a', 0xff]), "a\u{fffd}");
+    }
+}
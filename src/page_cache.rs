@@ -0,0 +1,148 @@
+//! The page cache of the pager. It holds a bounded number of pages, evicting them with
+//! the clock algorithm: a hit only flags the page as used, so that lookups can share
+//! the cache lock, and eviction sweeps the pages, clearing the flags until it finds a
+//! page that wasn't used since the last sweep.
+//!
+//! Pages start unflagged, so those a scan reads only once are evicted before the
+//! interior pages every lookup goes through.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[derive(Debug)]
+pub struct PageCache<V> {
+    slots: Vec<Slot<V>>,
+    /// The slot of each cached page, by page number.
+    index: HashMap<usize, usize>,
+    /// The next slot the sweep looks at.
+    hand: usize,
+    /// The maximum number of pages, or `None` for no limit.
+    capacity: Option<usize>,
+}
+
+#[derive(Debug)]
+struct Slot<V> {
+    number: usize,
+    value: V,
+    used: AtomicBool,
+}
+
+impl<V> Default for PageCache<V> {
+    fn default() -> Self {
+        Self {
+            slots: Vec::new(),
+            index: HashMap::new(),
+            hand: 0,
+            capacity: None,
+        }
+    }
+}
+
+impl<V: Clone> PageCache<V> {
+    pub fn get(&self, n: usize) -> Option<V> {
+        let slot = &self.slots[*self.index.get(&n)?];
+        slot.used.store(true, Ordering::Relaxed);
+        Some(slot.value.clone())
+    }
+
+    /// Caches page `n`, evicting a page if the cache is full.
+    pub fn insert(&mut self, n: usize, value: V) {
+        if let Some(&i) = self.index.get(&n) {
+            self.slots[i].value = value;
+            return;
+        }
+
+        let slot = Slot {
+            number: n,
+            value,
+            used: AtomicBool::new(false),
+        };
+        match self.capacity {
+            Some(0) => {}
+            Some(capacity) if self.slots.len() >= capacity => {
+                let i = self.victim();
+                self.index.remove(&self.slots[i].number);
+                self.slots[i] = slot;
+                self.index.insert(n, i);
+            }
+            _ => {
+                self.index.insert(n, self.slots.len());
+                self.slots.push(slot);
+            }
+        }
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Changes the maximum number of pages, evicting pages until they fit.
+    pub fn set_capacity(&mut self, capacity: Option<usize>) {
+        self.capacity = capacity;
+        let Some(capacity) = capacity else {
+            return;
+        };
+        while self.slots.len() > capacity {
+            let i = self.victim();
+            let evicted = self.slots.swap_remove(i);
+            self.index.remove(&evicted.number);
+            if let Some(moved) = self.slots.get(i) {
+                self.index.insert(moved.number, i);
+            }
+            self.hand = i;
+        }
+        if self.hand >= self.slots.len() {
+            self.hand = 0;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.clear();
+        self.index.clear();
+        self.hand = 0;
+    }
+
+    /// The slot of the first page the sweep finds unused since it last went by.
+    fn victim(&mut self) -> usize {
+        loop {
+            let i = self.hand;
+            self.hand = (i + 1) % self.slots.len();
+            if !std::mem::take(self.slots[i].used.get_mut()) {
+                return i;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_pages_not_used_since_last_sweep() {
+        let mut cache = PageCache::default();
+        cache.set_capacity(Some(3));
+        for n in 1..=3 {
+            cache.insert(n, n * 10);
+        }
+        assert_eq!(cache.get(1), Some(10));
+
+        // Page 1 gets a second chance, so page 2 is evicted first, then page 3.
+        cache.insert(4, 40);
+        assert_eq!(cache.get(2), None);
+        cache.insert(5, 50);
+        assert_eq!(cache.get(3), None);
+        assert_eq!(cache.get(1), Some(10));
+        assert_eq!(cache.get(4), Some(40));
+        assert_eq!(cache.get(5), Some(50));
+
+        cache.set_capacity(Some(1));
+        assert_eq!(cache.slots.len(), 1);
+        assert_eq!((1..=5).filter(|&n| cache.get(n).is_some()).count(), 1);
+        cache.set_capacity(Some(0));
+        cache.insert(6, 60);
+        assert_eq!(cache.get(6), None);
+    }
+}
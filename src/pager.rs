@@ -1,30 +1,106 @@
 use std::{
-    collections::HashMap,
+    cell::Cell,
     io::{Read, Seek, SeekFrom},
-    sync::{Arc, Mutex, RwLock},
+    sync::{Arc, Mutex, OnceLock, RwLock},
 };
 
-use anyhow::{Context, anyhow, bail};
+use anyhow::{Context, anyhow, bail, ensure};
 
-use crate::page::{self, DbHeader, PageHeader};
+use crate::{
+    cipher::Cipher,
+    page::{self, DbHeader, PageHeader, TextEncoding},
+    page_cache::PageCache,
+    ptrmap::Ptrmap,
+    vfs::{DbFile, FileLock, Mmap, SharedLock},
+    wal::{Wal, WalSnapshot, WalVersion},
+};
 
 pub const HEADER_SIZE: usize = 100;
-const HEADER_PREFIX: &[u8] = b"SQLite format 3\0";
+pub const HEADER_PREFIX: &[u8] = b"SQLite format 3\0";
 const HEADER_PAGE_SIZE_OFFSET: usize = 16;
+const HEADER_WRITE_VERSION_OFFSET: usize = 18;
+const HEADER_READ_VERSION_OFFSET: usize = 19;
 const HEADER_PAGE_RESERVED_SIZE_OFFSET: usize = 20;
+pub const HEADER_CHANGE_COUNTER_OFFSET: usize = 24;
+pub const HEADER_DATABASE_SIZE_OFFSET: usize = 28;
+pub const HEADER_FREELIST_TRUNK_OFFSET: usize = 32;
+pub const HEADER_FREELIST_COUNT_OFFSET: usize = 36;
+pub const HEADER_SCHEMA_COOKIE_OFFSET: usize = 40;
+const HEADER_SCHEMA_FORMAT_OFFSET: usize = 44;
+const HEADER_DEFAULT_CACHE_SIZE_OFFSET: usize = 48;
+pub const HEADER_LARGEST_ROOT_PAGE_OFFSET: usize = 52;
+const HEADER_TEXT_ENCODING_OFFSET: usize = 56;
+const HEADER_USER_VERSION_OFFSET: usize = 60;
+const HEADER_INCREMENTAL_VACUUM_OFFSET: usize = 64;
+const HEADER_APPLICATION_ID_OFFSET: usize = 68;
+pub const HEADER_VERSION_VALID_FOR_OFFSET: usize = 92;
+const HEADER_SQLITE_VERSION_OFFSET: usize = 96;
 
+const PAGE_MIN_SIZE: u16 = 512;
 const PAGE_MAX_SIZE: u32 = 65536;
+const MIN_USABLE_SIZE: u32 = 480;
+/// How much the page cache of a database holds by default, like SQLite's.
+const DEFAULT_CACHE_BYTES: usize = 2000 * 1024;
+/// The byte SQLite locks on, which is never part of the content of a page.
+const PENDING_BYTE: usize = 0x4000_0000;
 
 const PAGE_LEAF_TABLE_ID: u8 = 0x0d;
 const PAGE_INTERIOR_TABLE_ID: u8 = 0x05;
+const PAGE_LEAF_INDEX_ID: u8 = 0x0a;
+const PAGE_INTERIOR_INDEX_ID: u8 = 0x02;
 
 const PAGE_CELL_COUNT_OFFSET: usize = 3;
 const PAGE_RIGHTMOST_POINTER_OFFSET: usize = 8;
 
+thread_local! {
+    static PAGES_READ: Cell<usize> = const { Cell::new(0) };
+    static CACHE_HITS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Page accesses of the current thread. A query runs on a single thread, so the
+/// difference between two snapshots taken around it counts its own accesses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoStats {
+    pub pages_read: usize,
+    pub cache_hits: usize,
+}
+
+impl IoStats {
+    pub fn snapshot() -> Self {
+        Self {
+            pages_read: PAGES_READ.get(),
+            cache_hits: CACHE_HITS.get(),
+        }
+    }
+
+    /// Credits the current thread with accesses made on its behalf by another thread.
+    pub fn record(self) {
+        PAGES_READ.set(PAGES_READ.get() + self.pages_read);
+        CACHE_HITS.set(CACHE_HITS.get() + self.cache_hits);
+    }
+
+    pub fn since(self, earlier: IoStats) -> IoStats {
+        IoStats {
+            pages_read: self.pages_read - earlier.pages_read,
+            cache_hits: self.cache_hits - earlier.cache_hits,
+        }
+    }
+}
+
+/// Counters SQLite increments when a writer modifies the file, and its schema. Commits
+/// to the WAL only increment them when they change the first page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileVersion {
+    pub change_counter: u32,
+    pub schema_cookie: u32,
+    pub wal: Option<WalVersion>,
+}
+
 #[derive(Debug, Clone)]
 enum CachedPage {
     Page(Arc<page::Page>),
     Overflow(Arc<page::OverflowPage>),
+    FreelistTrunk(Arc<page::FreelistTrunkPage>),
 }
 
 impl From<Arc<page::Page>> for CachedPage {
@@ -63,11 +139,42 @@ impl TryFrom<CachedPage> for Arc<page::OverflowPage> {
     }
 }
 
+impl From<Arc<page::FreelistTrunkPage>> for CachedPage {
+    fn from(value: Arc<page::FreelistTrunkPage>) -> Self {
+        CachedPage::FreelistTrunk(value)
+    }
+}
+
+impl TryFrom<CachedPage> for Arc<page::FreelistTrunkPage> {
+    type Error = anyhow::Error;
+
+    fn try_from(value: CachedPage) -> Result<Self, Self::Error> {
+        if let CachedPage::FreelistTrunk(t) = value {
+            Ok(t.clone())
+        } else {
+            bail!("expected a freelist trunk page")
+        }
+    }
+}
+
+/// A cached page, along with the version of the WAL it was read from.
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    wal: Option<WalVersion>,
+    page: CachedPage,
+}
+
 #[derive(Debug)]
-pub struct Pager<I: Read + Seek = std::fs::File> {
+pub struct Pager<I: Read + Seek = Box<dyn DbFile>> {
     input: Arc<Mutex<I>>,
-    pages: Arc<RwLock<HashMap<usize, CachedPage>>>,
+    pages: Arc<RwLock<PageCache<CacheEntry>>>,
     header: DbHeader,
+    cipher: Option<Arc<Cipher>>,
+    lock: Option<Arc<FileLock>>,
+    wal: Option<Arc<Wal>>,
+    /// The snapshot of the WAL the pages are read from, rather than the latest one.
+    snapshot: Option<Arc<WalSnapshot>>,
+    mmap: Option<Arc<Mmap>>,
 }
 
 impl<I: Read + Seek> Pager<I> {
@@ -76,14 +183,229 @@ impl<I: Read + Seek> Pager<I> {
             input: Arc::new(Mutex::new(input)),
             pages: Arc::default(),
             header,
+            cipher: None,
+            lock: None,
+            wal: None,
+            snapshot: None,
+            mmap: None,
+        }
+    }
+
+    pub fn with_cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = Some(Arc::new(cipher));
+        self
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Caches at most `pages` pages, rather than every page read.
+    pub fn with_cache_size(self, pages: usize) -> Self {
+        self.pages
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .set_capacity(Some(pages));
+        self
+    }
+
+    pub fn with_lock(mut self, lock: Option<FileLock>) -> Self {
+        self.lock = lock.map(Arc::new);
+        self
+    }
+
+    /// Reads the pages committed to `wal` from it rather than from the file.
+    pub fn with_wal(mut self, wal: Option<Wal>) -> Self {
+        self.wal = wal.map(Arc::new);
+        self
+    }
+
+    /// Parses the pages of the file from `mmap` in place, rather than reading them.
+    pub fn with_mmap(mut self, mmap: Option<Mmap>) -> Self {
+        self.mmap = mmap.map(Arc::new);
+        self
+    }
+
+    /// Locks the file for reading, so that the pages read until the lock is dropped
+    /// all come from the same version of the database.
+    pub fn lock_shared(&self) -> anyhow::Result<Option<SharedLock>> {
+        self.lock.as_ref().map(FileLock::shared).transpose()
+    }
+
+    /// The lock of the file, for a writer. Like the writer, it only handles plain
+    /// files in rollback journal mode: compressed files, which aren't locked, are
+    /// replaced by `Db` instead.
+    pub fn write_lock(&self) -> anyhow::Result<&Arc<FileLock>> {
+        ensure!(self.cipher.is_none(), "cannot write an encrypted database");
+        ensure!(
+            self.wal.is_none() && self.header.write_version != 2,
+            "cannot write a database in WAL mode"
+        );
+        self.lock
+            .as_ref()
+            .context("cannot write a compressed database")
+    }
+
+    /// The header as it was when the database was opened. The page size, reserved
+    /// bytes and text encoding can't change, unlike the other fields: see
+    /// `read_header`.
+    pub fn header(&self) -> DbHeader {
+        self.header
+    }
+
+    /// Reads the current header from the first page.
+    pub fn read_header(&self) -> anyhow::Result<DbHeader> {
+        let buffer = self.load_raw(1)?;
+        Ok(DbHeader {
+            page_size: self.header.page_size,
+            page_reserved_size: self.header.page_reserved_size,
+            text_encoding: self.header.text_encoding,
+            ..parse_header_fields(&buffer)
+        })
+    }
+
+    /// Reads the version counters from the file, bypassing the page cache, once the
+    /// transactions committed to the WAL since the last call are indexed, unless the
+    /// pager is pinned to a snapshot of it.
+    pub fn file_version(&self) -> anyhow::Result<FileVersion> {
+        let wal = match (&self.snapshot, &self.wal) {
+            (Some(snapshot), _) => Some(snapshot.version()),
+            (None, Some(wal)) => Some(wal.refresh()?),
+            (None, None) => None,
+        };
+        if let Some(mmap) = &self.mmap {
+            mmap.refresh()?;
         }
+        let header = self.read_header()?;
+        Ok(FileVersion {
+            change_counter: header.change_counter,
+            schema_cookie: header.schema_cookie,
+            wal,
+        })
+    }
+
+    /// Number of pages of the database. The size stored in the header is only trusted
+    /// when it was written by the last writer, as legacy writers didn't update it.
+    pub fn page_count(&self) -> anyhow::Result<usize> {
+        if let Some(journal) = self.lock.as_ref().and_then(|lock| lock.hot_journal()) {
+            return Ok(journal.db_size());
+        }
+        if let Some(snapshot) = self.wal_snapshot()?
+            && let Some(size) = snapshot.db_size()
+        {
+            return Ok(size);
+        }
+
+        if let Some(size) = self.read_header()?.valid_page_count() {
+            return Ok(size);
+        }
+
+        let len = self
+            .input
+            .lock()
+            .map_err(|_| anyhow!("poisoned pager mutex"))?
+            .seek(SeekFrom::End(0))
+            .context("seek to end of file")?;
+        Ok(len as usize / self.header.page_size as usize)
+    }
+
+    /// Drops the cached pages, e.g. after another process modified the file.
+    /// Reads the pages from `input`, once it replaced the file.
+    pub fn replace_input(&self, input: I) -> anyhow::Result<()> {
+        *self
+            .input
+            .lock()
+            .map_err(|_| anyhow!("poisoned pager mutex"))? = input;
+        self.clear_cache()
+    }
+
+    pub fn clear_cache(&self) -> anyhow::Result<()> {
+        self.pages
+            .write()
+            .map_err(|_| anyhow!("failed to acquire pager write lock"))?
+            .clear();
+        Ok(())
+    }
+
+    /// The maximum number of cached pages, if the cache is bounded.
+    pub fn cache_size(&self) -> anyhow::Result<Option<usize>> {
+        Ok(self
+            .pages
+            .read()
+            .map_err(|_| anyhow!("poisoned page cache lock"))?
+            .capacity())
+    }
+
+    pub fn set_cache_size(&self, pages: Option<usize>) -> anyhow::Result<()> {
+        self.pages
+            .write()
+            .map_err(|_| anyhow!("failed to acquire pager write lock"))?
+            .set_capacity(pages);
+        Ok(())
+    }
+
+    /// Reads a page without parsing it, e.g. for pages the b-tree parser doesn't handle.
+    pub fn read_raw(&self, n: usize) -> anyhow::Result<Vec<u8>> {
+        ensure!(n > 0, "invalid page number: 0");
+        self.load_raw(n)
     }
 
     pub fn read_overflow(&self, n: usize) -> anyhow::Result<Arc<page::OverflowPage>> {
         self.load(n, |buffer| Ok(parse_overflow_page(buffer)))
     }
 
+    pub fn read_freelist_trunk(&self, n: usize) -> anyhow::Result<Arc<page::FreelistTrunkPage>> {
+        self.load(n, parse_freelist_trunk_page)
+            .with_context(|| format!("read freelist trunk page {n}"))
+    }
+
+    /// Walks the freelist from the first trunk page recorded in the header, checking
+    /// that it holds as many pages as the header says.
+    pub fn freelist(&self) -> anyhow::Result<page::Freelist> {
+        let header = self.read_header()?;
+        let expected = header.freelist_count as usize;
+        let mut next = header.freelist_trunk as usize;
+        let page_count = self.page_count()?;
+
+        let mut freelist = page::Freelist::default();
+        while next != 0 {
+            ensure!(
+                next <= page_count,
+                "freelist trunk page {next} is past the end of the database"
+            );
+            ensure!(
+                freelist.trunks.len() < page_count,
+                "the freelist trunk pages form a loop"
+            );
+            let trunk = self.read_freelist_trunk(next)?;
+            if let Some(&leaf) = trunk.leaves.iter().find(|&&leaf| leaf > page_count) {
+                bail!("free page {leaf} is past the end of the database");
+            }
+            freelist.trunks.push(next);
+            freelist.leaves.extend(&trunk.leaves);
+            next = trunk.next.unwrap_or(0);
+        }
+
+        ensure!(
+            freelist.page_count() == expected,
+            "the header counts {expected} free pages, but the freelist has {}",
+            freelist.page_count()
+        );
+        Ok(freelist)
+    }
+
+    /// Where the pointer-map pages are, if the database has them, i.e. is in
+    /// auto_vacuum or incremental_vacuum mode.
+    pub fn ptrmap(&self) -> anyhow::Result<Option<Ptrmap>> {
+        let buffer = self.load_raw(1)?;
+        Ok(ptrmap_of(&buffer, self.header))
+    }
+
     pub fn read_page(&self, n: usize) -> anyhow::Result<Arc<page::Page>> {
+        ensure!(
+            n != lock_byte_page(self.header.page_size),
+            "page {n} is the lock-byte page, which holds no b-tree data"
+        );
         self.load(n, |buffer| parse_page(&self.header, buffer, n))
     }
 
@@ -92,14 +414,21 @@ impl<I: Read + Seek> Pager<I> {
         Arc<T>: Into<CachedPage>,
         CachedPage: TryInto<Arc<T>, Error = anyhow::Error>,
     {
+        // A corrupted pointer to page 0 would otherwise read the first page.
+        ensure!(n > 0, "invalid page number: 0");
+        let snapshot = self.wal_snapshot()?;
+        let version = snapshot.as_ref().map(|snapshot| snapshot.version());
         {
             let read_pages = self
                 .pages
                 .read()
                 .map_err(|_| anyhow!("poisoned page cache lock"))?;
 
-            if let Some(page) = read_pages.get(&n).cloned() {
-                return page.try_into();
+            if let Some(entry) = read_pages.get(n)
+                && entry.wal == version
+            {
+                CACHE_HITS.set(CACHE_HITS.get() + 1);
+                return entry.page.try_into();
             }
         }
 
@@ -108,21 +437,142 @@ impl<I: Read + Seek> Pager<I> {
             .write()
             .map_err(|_| anyhow!("failed to acquire pager write lock"))?;
 
-        if let Some(page) = write_pages.get(&n).cloned() {
-            return page.try_into();
+        if let Some(entry) = write_pages.get(n)
+            && entry.wal == version
+        {
+            CACHE_HITS.set(CACHE_HITS.get() + 1);
+            return entry.page.try_into();
         }
 
-        let buffer = self.load_raw(n)?;
-        let parsed = f(&buffer[0..self.header.usable_page_size()])?;
+        let snapshot = snapshot.as_deref();
+        let parsed = match self.parse_mapped(snapshot, n, &f)? {
+            Some(parsed) => parsed?,
+            None => f(&self.read_uncached(snapshot, n)?[0..self.header.usable_page_size()])?,
+        };
         let ptr = Arc::new(parsed);
 
-        write_pages.insert(n, ptr.clone().into());
+        write_pages.insert(
+            n,
+            CacheEntry {
+                wal: version,
+                page: ptr.clone().into(),
+            },
+        );
 
         Ok(ptr)
     }
 
+    /// Parses page `n` where it is mapped, unless its current content is elsewhere or
+    /// needs decrypting.
+    fn parse_mapped<T>(
+        &self,
+        snapshot: Option<&WalSnapshot>,
+        n: usize,
+        f: impl Fn(&[u8]) -> anyhow::Result<T>,
+    ) -> anyhow::Result<Option<anyhow::Result<T>>> {
+        let Some(mmap) = &self.mmap else {
+            return Ok(None);
+        };
+        if self.cipher.is_some() || self.is_logged(snapshot, n) {
+            return Ok(None);
+        }
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("read_page", page = n, mapped = true).entered();
+
+        let page_size = self.header.page_size as usize;
+        let parsed = mmap.with_bytes(n.saturating_sub(1) * page_size, page_size, |page| {
+            f(&page[..self.header.usable_page_size()])
+        })?;
+        if parsed.is_some() {
+            PAGES_READ.set(PAGES_READ.get() + 1);
+            self.check_file_read(snapshot, n)?;
+        }
+        Ok(parsed)
+    }
+
+    fn is_logged(&self, snapshot: Option<&WalSnapshot>, n: usize) -> bool {
+        if let Some(journal) = self.lock.as_ref().and_then(|lock| lock.hot_journal()) {
+            return journal.page(n).is_some();
+        }
+        snapshot.is_some_and(|snapshot| snapshot.has_page(n))
+    }
+
+    /// The snapshot of the WAL the pages are read from: the pinned one, or else the
+    /// one of the last refresh.
+    fn wal_snapshot(&self) -> anyhow::Result<Option<Arc<WalSnapshot>>> {
+        match (&self.snapshot, &self.wal) {
+            (Some(snapshot), _) => Ok(Some(snapshot.clone())),
+            (None, Some(wal)) => wal.snapshot().map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+
     fn load_raw(&self, n: usize) -> anyhow::Result<Vec<u8>> {
+        self.read_uncached(self.wal_snapshot()?.as_deref(), n)
+    }
+
+    fn read_uncached(&self, snapshot: Option<&WalSnapshot>, n: usize) -> anyhow::Result<Vec<u8>> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("read_page", page = n).entered();
+
+        let mut buffer = vec![0; self.header.page_size as usize];
+        if !self.read_logged(snapshot, n, &mut buffer)? {
+            self.read_from_file(n, &mut buffer)?;
+            self.check_file_read(snapshot, n)?;
+        }
+        PAGES_READ.set(PAGES_READ.get() + 1);
+
+        if let Some(cipher) = &self.cipher {
+            cipher.decrypt_page(n, &mut buffer)?;
+        }
+
+        Ok(buffer)
+    }
+
+    /// Reads the content of page `n` from the hot journal or the WAL, returning false
+    /// when the file holds its current content.
+    fn read_logged(
+        &self,
+        snapshot: Option<&WalSnapshot>,
+        n: usize,
+        buffer: &mut [u8],
+    ) -> anyhow::Result<bool> {
+        if let Some(journal) = self.lock.as_ref().and_then(|lock| lock.hot_journal()) {
+            let Some(page) = journal.page(n) else {
+                return Ok(false);
+            };
+            ensure!(
+                page.len() == buffer.len(),
+                "the hot journal page size {} does not match the database page size",
+                page.len()
+            );
+            buffer.copy_from_slice(page);
+            return Ok(true);
+        }
+
+        match (&self.wal, snapshot) {
+            (Some(wal), Some(snapshot)) => wal.read_page(snapshot, n, buffer),
+            _ => Ok(false),
+        }
+    }
+
+    /// Fails if the page just read from the file may be newer than `snapshot`.
+    fn check_file_read(&self, snapshot: Option<&WalSnapshot>, n: usize) -> anyhow::Result<()> {
+        match (&self.wal, snapshot) {
+            (Some(wal), Some(snapshot)) => wal.check_file_read(snapshot, n),
+            _ => Ok(()),
+        }
+    }
+
+    fn read_from_file(&self, n: usize, buffer: &mut [u8]) -> anyhow::Result<()> {
         let offset = n.saturating_sub(1) * self.header.page_size as usize;
+        if let Some(mmap) = &self.mmap
+            && let Some(()) = mmap.with_bytes(offset, buffer.len(), |page| {
+                buffer.copy_from_slice(page);
+            })?
+        {
+            return Ok(());
+        }
 
         let mut input_guard = self
             .input
@@ -133,10 +583,9 @@ impl<I: Read + Seek> Pager<I> {
             .seek(SeekFrom::Start(offset as u64))
             .context("seek to page start")?;
 
-        let mut buffer = vec![0; self.header.page_size as usize];
-        input_guard.read_exact(&mut buffer).context("read page")?;
-
-        Ok(buffer)
+        input_guard
+            .read_exact(buffer)
+            .with_context(|| format!("read page {n}"))
     }
 }
 
@@ -146,10 +595,27 @@ impl Clone for Pager {
             input: self.input.clone(),
             pages: self.pages.clone(),
             header: self.header,
+            cipher: self.cipher.clone(),
+            lock: self.lock.clone(),
+            wal: self.wal.clone(),
+            snapshot: self.snapshot.clone(),
+            mmap: self.mmap.clone(),
         }
     }
 }
 
+impl Pager {
+    /// A pager reading the pages as of the transactions committed to the WAL so far,
+    /// whatever is committed or checkpointed later, for the pages a query reads to all
+    /// come from the same transaction.
+    pub fn pinned(&self) -> anyhow::Result<Pager> {
+        Ok(Pager {
+            snapshot: self.wal.as_ref().map(|wal| wal.pin()).transpose()?,
+            ..self.clone()
+        })
+    }
+}
+
 fn parse_overflow_page(buffer: &[u8]) -> page::OverflowPage {
     let next = read_be_double_at(buffer, 0);
     page::OverflowPage {
@@ -158,7 +624,46 @@ fn parse_overflow_page(buffer: &[u8]) -> page::OverflowPage {
     }
 }
 
+/// The number of pages the cache of a database holds by default.
+pub fn default_cache_size(page_size: u32) -> usize {
+    DEFAULT_CACHE_BYTES / page_size as usize
+}
+
+/// The page holding the byte SQLite locks on, which is left unused.
+pub fn lock_byte_page(page_size: u32) -> usize {
+    PENDING_BYTE / page_size as usize + 1
+}
+
+/// The pointer-map of the database whose first page is `first_page`, if it has one.
+pub fn ptrmap_of(first_page: &[u8], header: DbHeader) -> Option<Ptrmap> {
+    (read_be_double_at(first_page, HEADER_LARGEST_ROOT_PAGE_OFFSET) != 0)
+        .then(|| Ptrmap::new(header.usable_page_size(), header.page_size))
+}
+
+fn parse_freelist_trunk_page(buffer: &[u8]) -> anyhow::Result<page::FreelistTrunkPage> {
+    let next = read_be_double_at(buffer, 0);
+    let count = read_be_double_at(buffer, 4) as usize;
+    // SQLite never fills the last two slots, for compatibility with old versions.
+    let capacity = buffer.len() / 4 - 2;
+    ensure!(
+        count <= capacity,
+        "{count} free leaf pages don't fit on a trunk page"
+    );
+
+    Ok(page::FreelistTrunkPage {
+        next: if next != 0 { Some(next as usize) } else { None },
+        leaves: (0..count)
+            .map(|i| read_be_double_at(buffer, 8 + 4 * i) as usize)
+            .collect(),
+    })
+}
+
 pub fn parse_header(buffer: &[u8]) -> anyhow::Result<page::DbHeader> {
+    ensure!(
+        buffer.len() >= HEADER_SIZE,
+        "truncated header: {} bytes",
+        buffer.len()
+    );
     if !buffer.starts_with(HEADER_PREFIX) {
         let prefix = String::from_utf8_lossy(&buffer[..HEADER_PREFIX.len()]);
         anyhow::bail!("invalid header prefix: {prefix}");
@@ -167,31 +672,77 @@ pub fn parse_header(buffer: &[u8]) -> anyhow::Result<page::DbHeader> {
     let page_size_raw = read_be_word_at(buffer, HEADER_PAGE_SIZE_OFFSET);
     let page_size = match page_size_raw {
         1 => PAGE_MAX_SIZE,
-        n if n.is_power_of_two() => n as u32,
-        _ => anyhow::bail!("page size is not a power of 2: {}", page_size_raw),
+        n if n.is_power_of_two() && n >= PAGE_MIN_SIZE => n as u32,
+        _ => anyhow::bail!("invalid page size: {}", page_size_raw),
     };
 
     let page_reserved_size = buffer[HEADER_PAGE_RESERVED_SIZE_OFFSET];
+    // Like SQLite, which needs room on each page for at least four cells.
+    let usable_size = page_size - page_reserved_size as u32;
+    ensure!(
+        usable_size >= MIN_USABLE_SIZE,
+        "{page_reserved_size} reserved bytes leave only {usable_size} usable bytes per page"
+    );
+
+    // Databases get their encoding when their first table is created, so a database
+    // without tables can have none yet.
+    let text_encoding = match read_be_double_at(buffer, HEADER_TEXT_ENCODING_OFFSET) {
+        0 | 1 => TextEncoding::Utf8,
+        2 => TextEncoding::Utf16Le,
+        3 => TextEncoding::Utf16Be,
+        n => anyhow::bail!("invalid text encoding: {n}"),
+    };
 
     Ok(page::DbHeader {
         page_size,
         page_reserved_size,
+        text_encoding,
+        ..parse_header_fields(buffer)
     })
 }
 
+/// Parses the fields of the header that writers update, leaving those validated when
+/// the database is opened, which can't change, to their default.
+fn parse_header_fields(buffer: &[u8]) -> page::DbHeader {
+    let header_u32 = |offset| read_be_double_at(buffer, offset);
+    page::DbHeader {
+        write_version: buffer[HEADER_WRITE_VERSION_OFFSET],
+        read_version: buffer[HEADER_READ_VERSION_OFFSET],
+        change_counter: header_u32(HEADER_CHANGE_COUNTER_OFFSET),
+        page_count: header_u32(HEADER_DATABASE_SIZE_OFFSET),
+        freelist_trunk: header_u32(HEADER_FREELIST_TRUNK_OFFSET),
+        freelist_count: header_u32(HEADER_FREELIST_COUNT_OFFSET),
+        schema_cookie: header_u32(HEADER_SCHEMA_COOKIE_OFFSET),
+        schema_format: header_u32(HEADER_SCHEMA_FORMAT_OFFSET),
+        default_cache_size: header_u32(HEADER_DEFAULT_CACHE_SIZE_OFFSET),
+        largest_root_page: header_u32(HEADER_LARGEST_ROOT_PAGE_OFFSET),
+        user_version: header_u32(HEADER_USER_VERSION_OFFSET),
+        incremental_vacuum: header_u32(HEADER_INCREMENTAL_VACUUM_OFFSET) != 0,
+        application_id: header_u32(HEADER_APPLICATION_ID_OFFSET),
+        version_valid_for: header_u32(HEADER_VERSION_VALID_FOR_OFFSET),
+        sqlite_version: header_u32(HEADER_SQLITE_VERSION_OFFSET),
+        ..Default::default()
+    }
+}
+
 fn parse_page(db_header: &DbHeader, buffer: &[u8], page_num: usize) -> anyhow::Result<page::Page> {
     let ptr_offset = if page_num == 1 { HEADER_SIZE as u16 } else { 0 };
-    let content_buffer = &buffer[ptr_offset as usize..];
-    let header = parse_page_header(content_buffer)?;
+    let content_buffer = buffer
+        .get(ptr_offset as usize..)
+        .context("page too small for the database header")?;
+    let header = parse_page_header(content_buffer)
+        .with_context(|| format!("parse header of page {page_num}"))?;
     let cell_pointers = parse_cell_pointers(
         &content_buffer[header.byte_size()..],
         header.cell_count as usize,
         ptr_offset,
-    );
+    )
+    .with_context(|| format!("parse cell pointers of page {page_num}"))?;
 
     let cells_parsing_fn = match header.page_type {
         page::PageType::TableLeaf => parse_table_leaf_cell,
         page::PageType::TableInterior => parse_table_interior_cell,
+        page::PageType::IndexLeaf | page::PageType::IndexInterior => parse_index_cell,
     };
 
     let cells = parse_cells(
@@ -200,7 +751,8 @@ fn parse_page(db_header: &DbHeader, buffer: &[u8], page_num: usize) -> anyhow::R
         content_buffer,
         &cell_pointers,
         cells_parsing_fn,
-    )?;
+    )
+    .with_context(|| format!("parse cells of page {page_num}"))?;
 
     Ok(page::Page { header, cells })
 }
@@ -214,7 +766,13 @@ fn parse_cells(
 ) -> anyhow::Result<Vec<page::Cell>> {
     cell_pointers
         .iter()
-        .map(|&ptr| parse_fn(db_header, header, &buffer[ptr as usize..]))
+        .enumerate()
+        .map(|(i, &ptr)| {
+            let cell = buffer
+                .get(ptr as usize..)
+                .with_context(|| format!("cell {i} at offset {ptr} is past the end of the page"))?;
+            parse_fn(db_header, header, cell).with_context(|| format!("parse cell {i}"))
+        })
         .collect()
 }
 
@@ -223,20 +781,25 @@ fn parse_table_leaf_cell(
     header: &PageHeader,
     mut buffer: &[u8],
 ) -> anyhow::Result<page::Cell> {
-    let (n, size) = read_varint_at(buffer, 0);
+    let (n, size) = read_payload_size_at(buffer, 0)?;
     buffer = &buffer[n as usize..];
 
-    let (n, _) = read_varint_at(buffer, 0);
+    let (n, rowid) = read_varint_at(buffer, 0).context("read rowid")?;
     buffer = &buffer[n as usize..];
 
-    let (local_size, overflow_size) = header.local_and_overflow_size(db_header, size as usize)?;
-    let first_overflow = overflow_size.map(|_| read_be_double_at(buffer, local_size) as usize);
+    let (local_size, overflow_size) = header.local_and_overflow_size(db_header, size)?;
+    let first_overflow = overflow_size
+        .map(|_| read_u32_at(buffer, local_size).context("read overflow page number"))
+        .transpose()?
+        .map(|page| page as usize);
 
-    let payload = buffer[..local_size].to_vec();
+    let payload = Arc::from(local_payload(buffer, local_size)?);
 
     Ok(page::TableLeafCell {
+        rowid,
         payload,
         first_overflow,
+        header: OnceLock::new(),
     }
     .into())
 }
@@ -247,7 +810,39 @@ fn parse_table_interior_cell(
     buffer: &[u8],
 ) -> anyhow::Result<page::Cell> {
     Ok(page::TableInteriorCell {
-        left_child_page: read_be_double_at(buffer, 0),
+        left_child_page: read_u32_at(buffer, 0).context("read left child page number")?,
+        key: read_varint_at(buffer, 4).context("read key")?.1,
+    }
+    .into())
+}
+
+fn parse_index_cell(
+    db_header: &DbHeader,
+    header: &PageHeader,
+    mut buffer: &[u8],
+) -> anyhow::Result<page::Cell> {
+    let left_child_page = header
+        .rightmost_pointer
+        .map(|_| read_u32_at(buffer, 0).context("read left child page number"))
+        .transpose()?;
+    if left_child_page.is_some() {
+        buffer = &buffer[4..];
+    }
+
+    let (n, size) = read_payload_size_at(buffer, 0)?;
+    buffer = &buffer[n as usize..];
+
+    let (local_size, overflow_size) = header.local_and_overflow_size(db_header, size)?;
+    let first_overflow = overflow_size
+        .map(|_| read_u32_at(buffer, local_size).context("read overflow page number"))
+        .transpose()?
+        .map(|page| page as usize);
+
+    Ok(page::IndexCell {
+        left_child_page,
+        payload: Arc::from(local_payload(buffer, local_size)?),
+        first_overflow,
+        header: OnceLock::new(),
     }
     .into())
 }
@@ -256,8 +851,12 @@ fn parse_page_header(buffer: &[u8]) -> anyhow::Result<page::PageHeader> {
     let (page_type, rightmost_ptr) = match buffer[0] {
         PAGE_LEAF_TABLE_ID => (page::PageType::TableLeaf, false),
         PAGE_INTERIOR_TABLE_ID => (page::PageType::TableInterior, true),
+        PAGE_LEAF_INDEX_ID => (page::PageType::IndexLeaf, false),
+        PAGE_INTERIOR_INDEX_ID => (page::PageType::IndexInterior, true),
         _ => anyhow::bail!("unknown page type: {}", buffer[0]),
     };
+    let size = if rightmost_ptr { 12 } else { 8 };
+    ensure!(buffer.len() >= size, "truncated page header");
 
     let cell_count = read_be_word_at(buffer, PAGE_CELL_COUNT_OFFSET);
 
@@ -274,41 +873,120 @@ fn parse_page_header(buffer: &[u8]) -> anyhow::Result<page::PageHeader> {
     })
 }
 
-fn parse_cell_pointers(buffer: &[u8], n: usize, ptr_offset: u16) -> Vec<u16> {
+/// Reads the `n` cell pointers of a page, as offsets into `buffer` rather than the
+/// page when the page starts with the database header, `ptr_offset` bytes long.
+fn parse_cell_pointers(buffer: &[u8], n: usize, ptr_offset: u16) -> anyhow::Result<Vec<u16>> {
+    ensure!(
+        2 * n <= buffer.len(),
+        "{n} cell pointers don't fit on the page"
+    );
     let mut pointers = Vec::with_capacity(n);
     for i in 0..n {
-        pointers.push(read_be_word_at(buffer, 2 * i) - ptr_offset);
+        let pointer = read_be_word_at(buffer, 2 * i);
+        pointers.push(pointer.checked_sub(ptr_offset).with_context(|| {
+            format!("cell {i} at offset {pointer} overlaps the database header")
+        })?);
     }
-    pointers
+    Ok(pointers)
 }
 
-pub fn read_varint_at(buffer: &[u8], mut offset: usize) -> (u8, i64) {
-    let mut size = 0;
-    let mut result = 0;
+/// The `size` bytes of a payload stored on the page of its cell.
+fn local_payload(buffer: &[u8], size: usize) -> anyhow::Result<&[u8]> {
+    buffer
+        .get(..size)
+        .with_context(|| format!("{size} bytes of payload are past the end of the page"))
+}
 
-    while size < 9 {
-        let current_byte = buffer[offset] as i64;
-        if size == 8 {
-            result = (result << 8) | current_byte;
-        } else {
-            result = (result << 7) | (current_byte & 0b0111_1111);
-        }
+/// The most bytes a varint takes: the ninth byte contributes all of its 8 bits.
+pub const VARINT_MAX_SIZE: usize = 9;
 
-        offset += 1;
-        size += 1;
+/// Reads a varint, with fast paths for the one and two byte encodings that make up
+/// almost all record headers and cell prefixes.
+#[inline]
+pub fn read_varint_at(buffer: &[u8], offset: usize) -> anyhow::Result<(u8, i64)> {
+    match buffer.get(offset..) {
+        Some([first, ..]) if *first < 0x80 => Ok((1, *first as i64)),
+        Some([first, second, ..]) if *second < 0x80 => {
+            Ok((2, ((*first as i64 & 0x7f) << 7) | *second as i64))
+        }
+        _ => read_long_varint_at(buffer, offset),
+    }
+}
 
-        if current_byte & 0b1000_0000 == 0 {
-            break;
+fn read_long_varint_at(buffer: &[u8], offset: usize) -> anyhow::Result<(u8, i64)> {
+    let mut result = 0;
+    for size in 0..VARINT_MAX_SIZE {
+        let Some(&byte) = buffer.get(offset + size) else {
+            bail!(
+                "varint at offset {offset} is past the end of {} bytes",
+                buffer.len()
+            );
+        };
+        if size == VARINT_MAX_SIZE - 1 {
+            return Ok((VARINT_MAX_SIZE as u8, (result << 8) | byte as i64));
+        }
+        result = (result << 7) | (byte & 0b0111_1111) as i64;
+        if byte & 0b1000_0000 == 0 {
+            return Ok((size as u8 + 1, result));
         }
     }
+    unreachable!("the ninth byte of a varint ends it")
+}
+
+/// Encodes `value` as a SQLite varint.
+pub fn varint(value: i64) -> Vec<u8> {
+    let value = value as u64;
+    if value > 0x00ff_ffff_ffff_ffff {
+        let mut bytes: Vec<u8> = (0..8)
+            .map(|i| ((value >> (8 + 7 * (7 - i))) as u8 & 0x7f) | 0x80)
+            .collect();
+        bytes.push(value as u8);
+        return bytes;
+    }
+
+    let mut bytes = vec![(value & 0x7f) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        bytes.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    bytes.reverse();
+    bytes
+}
+
+/// Reads the varint giving the payload size of a cell, which can't be negative nor
+/// exceed 32 bits.
+pub fn read_payload_size_at(buffer: &[u8], offset: usize) -> anyhow::Result<(u8, usize)> {
+    let (n, size) = read_varint_at(buffer, offset).context("read payload size")?;
+    let size = u32::try_from(size).with_context(|| format!("invalid payload size: {size}"))?;
+    Ok((n, size as usize))
+}
 
-    (size, result)
+/// Reads the `N` bytes at `offset`, failing rather than panicking when a corrupted file
+/// points past the end of `input`.
+pub fn read_array_at<const N: usize>(input: &[u8], offset: usize) -> anyhow::Result<[u8; N]> {
+    offset
+        .checked_add(N)
+        .and_then(|end| input.get(offset..end))
+        .and_then(|bytes| bytes.try_into().ok())
+        .with_context(|| {
+            format!(
+                "{N} bytes at offset {offset} are past the end of {} bytes",
+                input.len()
+            )
+        })
+}
+
+fn read_u32_at(input: &[u8], offset: usize) -> anyhow::Result<u32> {
+    read_array_at(input, offset).map(u32::from_be_bytes)
 }
 
+/// Reads a 4-byte integer at an offset known to be within `input`, e.g. in a header.
 pub fn read_be_double_at(input: &[u8], offset: usize) -> u32 {
     u32::from_be_bytes(input[offset..offset + 4].try_into().unwrap())
 }
 
+/// Reads a 2-byte integer at an offset known to be within `input`.
 fn read_be_word_at(input: &[u8], offset: usize) -> u16 {
     u16::from_be_bytes(input[offset..offset + 2].try_into().unwrap())
 }
@@ -320,13 +998,19 @@ mod test {
     #[test]
     fn short_varint() {
         let buffer = [0b0000_0001];
-        assert_eq!(read_varint_at(&buffer, 0), (1, 1));
+        assert_eq!(read_varint_at(&buffer, 0).unwrap(), (1, 1));
     }
 
     #[test]
     fn middle_varint() {
         let buffer = [0b1000_0001, 0b0111_1111];
-        assert_eq!(read_varint_at(&buffer, 0), (2, 255));
+        assert_eq!(read_varint_at(&buffer, 0).unwrap(), (2, 255));
+    }
+
+    #[test]
+    fn three_byte_varint() {
+        let buffer = [0b1000_0001, 0b1000_0000, 0b0000_0001];
+        assert_eq!(read_varint_at(&buffer, 0).unwrap(), (3, 1 << 14 | 1));
     }
 
     #[test]
@@ -343,7 +1027,7 @@ mod test {
             0b0110_1101,
         ];
         assert_eq!(
-            read_varint_at(&buffer, 0),
+            read_varint_at(&buffer, 0).unwrap(),
             (
                 9,
                 0b00000001_11111100_00000000_00000000_00000000_00000000_00000000_01101101,
@@ -364,6 +1048,175 @@ mod test {
             0b1111_1111,
             0b1111_1111,
         ];
-        assert_eq!(read_varint_at(&buffer, 0), (9, -1));
+        assert_eq!(read_varint_at(&buffer, 0).unwrap(), (9, -1));
+    }
+
+    #[test]
+    fn malformed_varints() {
+        assert!(read_varint_at(&[], 0).is_err());
+        assert!(read_varint_at(&[0x81], 0).is_err());
+        assert!(read_varint_at(&[0x81, 0x80, 0x80], 0).is_err());
+        assert!(read_varint_at(&[0x01], 1).is_err());
+        // Whatever its last byte, a varint ends after nine.
+        assert_eq!(read_varint_at(&[0x80; 10], 0).unwrap(), (9, 0x80));
+        assert_eq!(read_varint_at(&[0x81, 0x01], 0).unwrap(), (2, 129));
+
+        let negative = [0xff; 9];
+        assert!(read_payload_size_at(&negative, 0).is_err());
+        assert_eq!(read_payload_size_at(&[0x87, 0x00], 0).unwrap(), (2, 896));
+    }
+
+    #[test]
+    fn index_interior_page() {
+        let db_header = DbHeader {
+            page_size: 512,
+            ..DbHeader::default()
+        };
+        let mut buffer = vec![0; 512];
+        buffer[..12].copy_from_slice(&[
+            PAGE_INTERIOR_INDEX_ID,
+            0,
+            0,
+            0,
+            1,
+            0x01,
+            0xf8,
+            0,
+            0,
+            0,
+            0,
+            7,
+        ]);
+        buffer[12..14].copy_from_slice(&[0x01, 0xf8]);
+        // Left child, payload size, and a record holding the text 'a'.
+        buffer[504..].copy_from_slice(&[0, 0, 0, 3, 3, 2, 15, b'a']);
+
+        let page = parse_page(&db_header, &buffer, 2).unwrap();
+        assert_eq!(page.header.page_type, page::PageType::IndexInterior);
+        assert_eq!(page.header.rightmost_pointer, Some(7));
+        let Some(page::Cell::Index(cell)) = page.get(0) else {
+            panic!("expected an index cell");
+        };
+        assert_eq!(cell.left_child_page, Some(3));
+        assert_eq!(&cell.payload[..], &[2, 15, b'a']);
+        assert_eq!(cell.first_overflow, None);
+    }
+
+    #[test]
+    fn index_leaf_page_with_overflow() {
+        let db_header = DbHeader {
+            page_size: 512,
+            ..DbHeader::default()
+        };
+        let mut buffer = vec![0; 512];
+        buffer[..10].copy_from_slice(&[PAGE_LEAF_INDEX_ID, 0, 0, 0, 1, 0x01, 0xd3, 0, 0x01, 0xd3]);
+        // A 200 bytes key keeps only 39 bytes on the page, less than a table leaf would.
+        buffer[0x1d3..0x1d5].copy_from_slice(&[0x81, 0x48]);
+        buffer[0x1d5..0x1fc].fill(b'k');
+        buffer[0x1fc..].copy_from_slice(&[0, 0, 0, 9]);
+
+        let page = parse_page(&db_header, &buffer, 2).unwrap();
+        assert_eq!(page.header.page_type, page::PageType::IndexLeaf);
+        assert_eq!(page.header.rightmost_pointer, None);
+        let Some(page::Cell::Index(cell)) = page.get(0) else {
+            panic!("expected an index cell");
+        };
+        assert_eq!(cell.left_child_page, None);
+        assert_eq!(cell.payload.len(), 39);
+        assert_eq!(cell.first_overflow, Some(9));
+    }
+
+    #[test]
+    fn freelist() {
+        let mut file = vec![0; 4 * 512];
+        file[..16].copy_from_slice(HEADER_PREFIX);
+        file[HEADER_PAGE_SIZE_OFFSET..][..2].copy_from_slice(&512u16.to_be_bytes());
+        file[HEADER_DATABASE_SIZE_OFFSET..][..4].copy_from_slice(&4u32.to_be_bytes());
+        file[HEADER_FREELIST_TRUNK_OFFSET..][..4].copy_from_slice(&2u32.to_be_bytes());
+        file[HEADER_FREELIST_COUNT_OFFSET..][..4].copy_from_slice(&3u32.to_be_bytes());
+        // A trunk page without a next trunk, listing pages 4 and 3.
+        file[512..528].copy_from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 4, 0, 0, 0, 3]);
+
+        let header = parse_header(&file).unwrap();
+        let pager = Pager::new(header, std::io::Cursor::new(file.clone()));
+        let freelist = pager.freelist().unwrap();
+        assert_eq!(freelist.trunks, [2]);
+        assert_eq!(freelist.leaves, [4, 3]);
+        assert_eq!(freelist.page_count(), 3);
+
+        // A trunk pointing back to itself.
+        file[512..516].copy_from_slice(&2u32.to_be_bytes());
+        let pager = Pager::new(header, std::io::Cursor::new(file));
+        assert!(pager.freelist().is_err());
+    }
+
+    #[test]
+    fn full_header() {
+        let mut file = vec![0; 2 * 1024];
+        file[..16].copy_from_slice(HEADER_PREFIX);
+        file[16..24].copy_from_slice(&[4, 0, 2, 2, 0, 64, 32, 32]);
+        // From the change counter to the SQLite version, for a database written by
+        // SQLite 3.40.1 in incremental_vacuum mode, with UTF-16le text.
+        let words = [
+            5, 2, 0, 0, 1, 4, 0, 2, 2, 7, 1, 42, 0, 0, 0, 0, 0, 5, 3040001,
+        ];
+        for (i, word) in words.into_iter().enumerate() {
+            file[24 + 4 * i..][..4].copy_from_slice(&u32::to_be_bytes(word));
+        }
+
+        let header = parse_header(&file).unwrap();
+        assert_eq!(header.page_size, 1024);
+        assert_eq!((header.write_version, header.read_version), (2, 2));
+        assert_eq!(header.change_counter, 5);
+        assert_eq!(header.valid_page_count(), Some(2));
+        assert_eq!((header.freelist_trunk, header.freelist_count), (0, 0));
+        assert_eq!((header.schema_cookie, header.schema_format), (1, 4));
+        assert_eq!(header.largest_root_page, 2);
+        assert_eq!(header.text_encoding, TextEncoding::Utf16Le);
+        assert_eq!(header.user_version, 7);
+        assert!(header.incremental_vacuum);
+        assert_eq!(header.application_id, 42);
+        assert_eq!(header.version_valid_for, 5);
+        assert_eq!(header.sqlite_version, 3040001);
+
+        // A legacy writer bumped the change counter without updating the page count.
+        file[HEADER_CHANGE_COUNTER_OFFSET..][..4].copy_from_slice(&6u32.to_be_bytes());
+        let pager = Pager::new(header, std::io::Cursor::new(file));
+        let current = pager.read_header().unwrap();
+        assert_eq!(current.change_counter, 6);
+        assert_eq!(current.valid_page_count(), None);
+        assert_eq!(pager.page_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn corrupted_pages() {
+        let mut header = [0; HEADER_SIZE];
+        header[..16].copy_from_slice(HEADER_PREFIX);
+        header[HEADER_PAGE_SIZE_OFFSET..][..2].copy_from_slice(&256u16.to_be_bytes());
+        assert!(parse_header(&header).is_err());
+        header[HEADER_PAGE_SIZE_OFFSET..][..2].copy_from_slice(&512u16.to_be_bytes());
+        header[HEADER_PAGE_RESERVED_SIZE_OFFSET] = 64;
+        assert!(parse_header(&header).is_err());
+        assert!(parse_header(&header[..50]).is_err());
+
+        let db_header = DbHeader {
+            page_size: 512,
+            ..DbHeader::default()
+        };
+        let mut buffer = vec![0; 512];
+        // A table leaf whose cell pointer is past the end of the page.
+        buffer[..10].copy_from_slice(&[PAGE_LEAF_TABLE_ID, 0, 0, 0, 1, 0x01, 0xf0, 0, 0x02, 0x10]);
+        assert!(parse_page(&db_header, &buffer, 2).is_err());
+        // On the first page, a cell pointer into the database header.
+        buffer.copy_within(..10, 100);
+        buffer[108..110].copy_from_slice(&[0, 50]);
+        assert!(parse_page(&db_header, &buffer, 1).is_err());
+        // A cell overflowing, with its overflow page number past the end of the page.
+        buffer[8..10].copy_from_slice(&[0x01, 0xf0]);
+        buffer[0x1f0..].copy_from_slice(&[0x87, 0x00, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(parse_page(&db_header, &buffer, 2).is_err());
+        // More cell pointers than fit on the page.
+        buffer[3..5].copy_from_slice(&[0x01, 0x00]);
+        assert!(parse_page(&db_header, &buffer, 2).is_err());
     }
 }
@@ -0,0 +1,155 @@
+//! Pointer-map pages of auto_vacuum databases. They are interleaved with the other
+//! pages, each one recording the parent of the pages following it, so that vacuuming
+//! can move a page and update the pointer to it.
+
+use anyhow::bail;
+
+use crate::pager::read_be_double_at;
+
+const ENTRY_SIZE: usize = 5;
+/// The first page is the schema, and the second one always a pointer-map page.
+const FIRST_PTRMAP_PAGE: usize = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PtrmapKind {
+    RootPage,
+    FreePage,
+    /// The first overflow page of a cell, whose parent is the page of the cell.
+    FirstOverflow,
+    /// A later overflow page, whose parent is the previous overflow page.
+    Overflow,
+    Btree,
+}
+
+impl PtrmapKind {
+    fn from_id(id: u8) -> anyhow::Result<Self> {
+        Ok(match id {
+            1 => PtrmapKind::RootPage,
+            2 => PtrmapKind::FreePage,
+            3 => PtrmapKind::FirstOverflow,
+            4 => PtrmapKind::Overflow,
+            5 => PtrmapKind::Btree,
+            _ => bail!("invalid pointer-map entry type: {id}"),
+        })
+    }
+
+    pub fn id(self) -> u8 {
+        match self {
+            PtrmapKind::RootPage => 1,
+            PtrmapKind::FreePage => 2,
+            PtrmapKind::FirstOverflow => 3,
+            PtrmapKind::Overflow => 4,
+            PtrmapKind::Btree => 5,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PtrmapKind::RootPage => "root page",
+            PtrmapKind::FreePage => "free page",
+            PtrmapKind::FirstOverflow => "first overflow",
+            PtrmapKind::Overflow => "overflow",
+            PtrmapKind::Btree => "b-tree",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PtrmapEntry {
+    pub kind: PtrmapKind,
+    /// The page pointing to the page, 0 for root and free pages.
+    pub parent: u32,
+}
+
+/// Where the pointer-map pages of a database are.
+#[derive(Debug, Copy, Clone)]
+pub struct Ptrmap {
+    /// The pages described by a pointer-map page, plus itself.
+    pages_per_map: usize,
+    lock_byte_page: usize,
+}
+
+impl Ptrmap {
+    pub fn new(usable_size: usize, page_size: u32) -> Self {
+        Self {
+            pages_per_map: usable_size / ENTRY_SIZE + 1,
+            lock_byte_page: crate::pager::lock_byte_page(page_size),
+        }
+    }
+
+    /// The pointer-map page describing page `n`, and the offset of its entry. Like in
+    /// SQLite, a map that would start on the lock-byte page starts right after it.
+    pub fn locate(&self, n: usize) -> (usize, usize) {
+        let group = n.saturating_sub(FIRST_PTRMAP_PAGE) / self.pages_per_map;
+        let mut map = group * self.pages_per_map + FIRST_PTRMAP_PAGE;
+        if map == self.lock_byte_page {
+            map += 1;
+        }
+        (map, ENTRY_SIZE * n.saturating_sub(map + 1))
+    }
+
+    pub fn is_ptrmap_page(&self, n: usize) -> bool {
+        n >= FIRST_PTRMAP_PAGE && self.locate(n).0 == n
+    }
+
+    /// The entries of the pointer-map page `map`, by the page they describe.
+    pub fn entries(&self, map: usize, page: &[u8]) -> anyhow::Result<Vec<(usize, PtrmapEntry)>> {
+        let group_end = (map - FIRST_PTRMAP_PAGE) / self.pages_per_map * self.pages_per_map
+            + FIRST_PTRMAP_PAGE
+            + self.pages_per_map;
+        let mut entries = Vec::new();
+        for n in (map + 1..group_end).filter(|&n| n != self.lock_byte_page) {
+            match read_entry(page, self.locate(n).1)? {
+                Some(entry) => entries.push((n, entry)),
+                None => break,
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Reads an entry, or `None` for the zeroed entries of pages past the end of the file.
+pub fn read_entry(page: &[u8], offset: usize) -> anyhow::Result<Option<PtrmapEntry>> {
+    if page[offset] == 0 {
+        return Ok(None);
+    }
+    Ok(Some(PtrmapEntry {
+        kind: PtrmapKind::from_id(page[offset])?,
+        parent: read_be_double_at(page, offset + 1),
+    }))
+}
+
+pub fn write_entry(page: &mut [u8], offset: usize, entry: PtrmapEntry) {
+    page[offset] = entry.kind.id();
+    page[offset + 1..offset + ENTRY_SIZE].copy_from_slice(&entry.parent.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_pages() {
+        // 512 bytes pages describe 102 pages each.
+        let ptrmap = Ptrmap::new(512, 512);
+        assert!(ptrmap.is_ptrmap_page(2));
+        assert!(!ptrmap.is_ptrmap_page(3));
+        assert!(ptrmap.is_ptrmap_page(105));
+        assert_eq!(ptrmap.locate(3), (2, 0));
+        assert_eq!(ptrmap.locate(104), (2, 505));
+        assert_eq!(ptrmap.locate(106), (105, 0));
+
+        let mut page = vec![0; 512];
+        let root = PtrmapEntry {
+            kind: PtrmapKind::RootPage,
+            parent: 0,
+        };
+        let child = PtrmapEntry {
+            kind: PtrmapKind::Btree,
+            parent: 3,
+        };
+        write_entry(&mut page, ptrmap.locate(3).1, root);
+        write_entry(&mut page, ptrmap.locate(4).1, child);
+        assert_eq!(ptrmap.entries(2, &page).unwrap(), [(3, root), (4, child)]);
+    }
+}
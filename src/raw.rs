@@ -0,0 +1,303 @@
+//! Low-level access to the pages and b-trees of a database, below the SQL layer, for
+//! inspection and forensic tools.
+
+use anyhow::{Context, ensure};
+
+use crate::{
+    analyzer::{
+        self, PAGE_INTERIOR_INDEX_ID, PAGE_INTERIOR_TABLE_ID, PAGE_LEAF_INDEX_ID,
+        PAGE_LEAF_TABLE_ID,
+    },
+    pager::{self, Pager},
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RawPageType {
+    IndexInterior,
+    TableInterior,
+    IndexLeaf,
+    TableLeaf,
+}
+
+impl RawPageType {
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            PAGE_INTERIOR_INDEX_ID => Some(RawPageType::IndexInterior),
+            PAGE_INTERIOR_TABLE_ID => Some(RawPageType::TableInterior),
+            PAGE_LEAF_INDEX_ID => Some(RawPageType::IndexLeaf),
+            PAGE_LEAF_TABLE_ID => Some(RawPageType::TableLeaf),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RawPageType::IndexInterior => "index interior",
+            RawPageType::TableInterior => "table interior",
+            RawPageType::IndexLeaf => "index leaf",
+            RawPageType::TableLeaf => "table leaf",
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        matches!(self, RawPageType::IndexLeaf | RawPageType::TableLeaf)
+    }
+
+    pub fn is_table(&self) -> bool {
+        matches!(self, RawPageType::TableInterior | RawPageType::TableLeaf)
+    }
+}
+
+/// A page as stored in the file. Pages that aren't b-tree pages, e.g. overflow and
+/// freelist pages, have no type and no cells.
+#[derive(Debug, Clone)]
+pub struct RawPage {
+    pub number: usize,
+    pub page_type: Option<RawPageType>,
+    pub first_freeblock: usize,
+    pub fragmented_bytes: usize,
+    pub content_start: usize,
+    /// Offsets of the cells from the start of the page.
+    pub cell_pointers: Vec<usize>,
+    pub rightmost_pointer: Option<u32>,
+    /// The usable bytes of the page.
+    pub data: Vec<u8>,
+}
+
+/// A b-tree cell. Which fields are set depends on the type of its page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawCell {
+    pub offset: usize,
+    pub left_child: Option<u32>,
+    pub rowid: Option<i64>,
+    pub payload_size: Option<usize>,
+    /// The part of the payload stored on the page.
+    pub local_payload: Vec<u8>,
+    pub first_overflow: Option<u32>,
+    /// The bytes the cell takes on the page.
+    pub size: usize,
+}
+
+impl RawPage {
+    pub fn read(pager: &Pager, number: usize) -> anyhow::Result<Self> {
+        let mut data = pager.read_raw(number)?;
+        data.truncate(pager.header().usable_page_size());
+        Self::parse(data, number).with_context(|| format!("parse page {number}"))
+    }
+
+    pub fn parse(data: Vec<u8>, number: usize) -> anyhow::Result<Self> {
+        let offset = if number == 1 { pager::HEADER_SIZE } else { 0 };
+        let mut page = RawPage {
+            number,
+            page_type: RawPageType::from_id(data[offset]),
+            first_freeblock: 0,
+            fragmented_bytes: 0,
+            content_start: 0,
+            cell_pointers: Vec::new(),
+            rightmost_pointer: None,
+            data,
+        };
+        let Some(page_type) = page.page_type else {
+            return Ok(page);
+        };
+
+        let header_size = if page_type.is_leaf() { 8 } else { 12 };
+        ensure!(
+            offset + header_size <= page.data.len(),
+            "truncated page header"
+        );
+
+        page.first_freeblock = read_u16(&page.data, offset + 1);
+        let cell_count = read_u16(&page.data, offset + 3);
+        page.content_start = match read_u16(&page.data, offset + 5) {
+            0 => 65536,
+            n => n,
+        };
+        page.fragmented_bytes = page.data[offset + 7] as usize;
+        if !page_type.is_leaf() {
+            page.rightmost_pointer = Some(pager::read_be_double_at(&page.data, offset + 8));
+        }
+
+        let pointers = offset + header_size;
+        ensure!(
+            pointers + 2 * cell_count <= page.data.len(),
+            "invalid cell count: {cell_count}"
+        );
+        page.cell_pointers = (0..cell_count)
+            .map(|i| read_u16(&page.data, pointers + 2 * i))
+            .collect();
+
+        Ok(page)
+    }
+
+    pub fn cell(&self, index: usize) -> anyhow::Result<RawCell> {
+        let page_type = self
+            .page_type
+            .with_context(|| format!("page {} is not a b-tree page", self.number))?;
+        let &offset = self.cell_pointers.get(index).with_context(|| {
+            format!(
+                "page {} has {} cells",
+                self.number,
+                self.cell_pointers.len()
+            )
+        })?;
+        ensure!(
+            offset + 4 <= self.data.len(),
+            "invalid cell pointer: {offset}"
+        );
+
+        let mut cell = RawCell {
+            offset,
+            left_child: None,
+            rowid: None,
+            payload_size: None,
+            local_payload: Vec::new(),
+            first_overflow: None,
+            size: 0,
+        };
+
+        let mut position = offset;
+        if !page_type.is_leaf() {
+            cell.left_child = Some(pager::read_be_double_at(&self.data, position));
+            position += 4;
+        }
+        if page_type == RawPageType::TableInterior {
+            let (n, rowid) = pager::read_varint_at(&self.data, position)?;
+            cell.rowid = Some(rowid);
+            cell.size = position + n as usize - offset;
+            return Ok(cell);
+        }
+
+        let (n, payload_size) = pager::read_payload_size_at(&self.data, position)?;
+        position += n as usize;
+        if page_type.is_table() {
+            let (n, rowid) = pager::read_varint_at(&self.data, position)?;
+            position += n as usize;
+            cell.rowid = Some(rowid);
+        }
+
+        let local_size =
+            analyzer::local_payload_size(self.data.len(), page_type.is_table(), payload_size);
+        ensure!(
+            position + local_size <= self.data.len(),
+            "cell payload exceeds the page"
+        );
+        cell.payload_size = Some(payload_size);
+        cell.local_payload = self.data[position..position + local_size].to_vec();
+
+        if local_size < payload_size {
+            ensure!(
+                position + local_size + 4 <= self.data.len(),
+                "invalid overflow pointer"
+            );
+            cell.first_overflow = Some(pager::read_be_double_at(&self.data, position + local_size));
+            position += 4;
+        }
+        // Like SQLite, smaller cells take 4 bytes, so that they can be freed.
+        cell.size = (position + local_size - offset).max(4);
+
+        Ok(cell)
+    }
+
+    /// Pages referenced by the cells of an interior page, in key order.
+    pub fn children(&self) -> anyhow::Result<Vec<usize>> {
+        let mut children = Vec::new();
+        for i in 0..self.cell_pointers.len() {
+            if let Some(child) = self.cell(i)?.left_child {
+                children.push(child as usize);
+            }
+        }
+        children.extend(self.rightmost_pointer.map(|p| p as usize));
+        Ok(children)
+    }
+}
+
+/// Every page of the database, in file order.
+pub fn pages(pager: &Pager) -> anyhow::Result<impl Iterator<Item = anyhow::Result<RawPage>>> {
+    let page_count = pager.page_count()?;
+    Ok((1..=page_count).map(|n| RawPage::read(pager, n)))
+}
+
+/// The pages of the b-tree rooted at `root`, depth-first, with their depth.
+pub fn walk_btree(
+    pager: &Pager,
+    root: usize,
+) -> impl Iterator<Item = anyhow::Result<(usize, RawPage)>> {
+    let mut stack = vec![(0, root)];
+    std::iter::from_fn(move || {
+        let (depth, number) = stack.pop()?;
+        let page = match RawPage::read(pager, number) {
+            Ok(page) => page,
+            Err(e) => return Some(Err(e)),
+        };
+
+        match page.page_type {
+            None => {
+                return Some(Err(anyhow::anyhow!("page {number} is not a b-tree page")));
+            }
+            Some(page_type) if !page_type.is_leaf() => match page.children() {
+                Ok(children) => stack.extend(children.into_iter().rev().map(|c| (depth + 1, c))),
+                Err(e) => return Some(Err(e)),
+            },
+            Some(_) => {}
+        }
+
+        Some(Ok((depth, page)))
+    })
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> usize {
+    u16::from_be_bytes([buffer[offset], buffer[offset + 1]]) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_interior_cell() {
+        let mut data = vec![0; 512];
+        data[..12].copy_from_slice(&[
+            PAGE_INTERIOR_INDEX_ID,
+            0,
+            0,
+            0,
+            1,
+            0x01,
+            0xf6,
+            0,
+            0,
+            0,
+            0,
+            7,
+        ]);
+        data[12..14].copy_from_slice(&[0x01, 0xf6]);
+        // Left child, payload size, payload.
+        data[502..509].copy_from_slice(&[0, 0, 0, 3, 2, 0xaa, 0xbb]);
+
+        let page = RawPage::parse(data, 5).unwrap();
+        assert_eq!(page.page_type, Some(RawPageType::IndexInterior));
+        assert_eq!(page.cell_pointers, vec![502]);
+        assert_eq!(
+            page.cell(0).unwrap(),
+            RawCell {
+                offset: 502,
+                left_child: Some(3),
+                rowid: None,
+                payload_size: Some(2),
+                local_payload: vec![0xaa, 0xbb],
+                first_overflow: None,
+                size: 7,
+            }
+        );
+        assert_eq!(page.children().unwrap(), vec![3, 7]);
+        assert!(page.cell(1).is_err());
+    }
+
+    #[test]
+    fn untyped_page() {
+        let page = RawPage::parse(vec![0; 512], 9).unwrap();
+        assert_eq!(page.page_type, None);
+        assert!(page.cell(0).is_err());
+    }
+}
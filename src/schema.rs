@@ -0,0 +1,702 @@
+//! Schema-level comparison of two databases (or SQL scripts), emitting the DDL that
+//! migrates the first schema to the second.
+
+use std::collections::HashSet;
+
+use anyhow::Context;
+
+use crate::{
+    db::Db,
+    sql::{self, ast, quote_identifier},
+    vtab,
+};
+
+#[derive(Debug, Clone)]
+struct Table {
+    name: String,
+    sql: String,
+    definition: TableDefinition,
+}
+
+#[derive(Debug, Clone)]
+enum TableDefinition {
+    Normal(ast::CreateTableStatement),
+    /// Virtual tables can only be dropped and recreated.
+    Virtual(ast::CreateVirtualTableStatement),
+}
+
+#[derive(Debug, Clone)]
+struct Index {
+    name: String,
+    table: String,
+    sql: String,
+}
+
+#[derive(Debug, Clone)]
+struct Trigger {
+    name: String,
+    table: String,
+    sql: String,
+}
+
+#[derive(Debug, Clone)]
+struct View {
+    name: String,
+    sql: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Schema {
+    tables: Vec<Table>,
+    indexes: Vec<Index>,
+    triggers: Vec<Trigger>,
+    views: Vec<View>,
+}
+
+impl Schema {
+    pub fn from_db(db: &Db) -> anyhow::Result<Self> {
+        let mut schema = Schema::default();
+
+        let metadata = db.metadata();
+        for table in metadata.tables() {
+            schema.add_statement(&table.sql)?;
+        }
+        for index in &metadata.indexes {
+            if let Some(sql) = &index.sql {
+                schema.indexes.push(Index {
+                    name: index.name.clone(),
+                    table: index.table_name.clone(),
+                    sql: sql.clone(),
+                });
+            }
+        }
+        for trigger in &metadata.triggers {
+            schema.triggers.push(Trigger {
+                name: trigger.name.clone(),
+                table: trigger.table_name.clone(),
+                sql: trigger.sql.clone(),
+            });
+        }
+        for view in &metadata.views {
+            schema.views.push(View {
+                name: view.name.clone(),
+                sql: view.sql.clone(),
+            });
+        }
+
+        schema.remove_internal_tables();
+        Ok(schema)
+    }
+
+    /// Reads the CREATE statements of a script, ignoring everything else.
+    pub fn from_script(script: &str) -> anyhow::Result<Self> {
+        let mut schema = Schema::default();
+
+        for statement in sql::split_statements(script) {
+            let create = (statement.split_whitespace().next())
+                .is_some_and(|word| word.eq_ignore_ascii_case("create"));
+            if !create {
+                continue;
+            }
+            match sql::created_view_name(&statement)? {
+                Some(name) => schema.views.push(View {
+                    name,
+                    sql: statement,
+                }),
+                None => schema.add_statement(&statement)?,
+            }
+        }
+
+        schema.remove_internal_tables();
+        Ok(schema)
+    }
+
+    fn add_statement(&mut self, statement: &str) -> anyhow::Result<()> {
+        let parsed = sql::parse_create_statement(statement)
+            .with_context(|| format!("parse schema statement: {statement}"))?;
+
+        match parsed {
+            ast::Statement::CreateTable(create) => self.tables.push(Table {
+                name: create.name.clone(),
+                sql: statement.to_string(),
+                definition: TableDefinition::Normal(create),
+            }),
+            ast::Statement::CreateVirtualTable(create) => self.tables.push(Table {
+                name: create.name.clone(),
+                sql: statement.to_string(),
+                definition: TableDefinition::Virtual(create),
+            }),
+            ast::Statement::CreateIndex(create) => self.indexes.push(Index {
+                name: create.name,
+                table: create.table,
+                sql: statement.to_string(),
+            }),
+            ast::Statement::CreateTrigger(create) => self.triggers.push(Trigger {
+                name: create.name,
+                table: create.table,
+                sql: statement.to_string(),
+            }),
+            ast::Statement::Select(_)
+            | ast::Statement::Drop(_)
+            | ast::Statement::AlterTable(_)
+            | ast::Statement::Insert(_)
+            | ast::Statement::Update(_)
+            | ast::Statement::Delete(_)
+            | ast::Statement::Pragma(_)
+            | ast::Statement::Explain(_)
+            | ast::Statement::ExplainQueryPlan(_)
+            | ast::Statement::ExplainAnalyze(_) => {
+                anyhow::bail!("expected a create statement")
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops the `sqlite_*` tables and the shadow tables of virtual tables, which are
+    /// managed by SQLite itself.
+    fn remove_internal_tables(&mut self) {
+        let shadow_tables: HashSet<String> = self
+            .tables
+            .iter()
+            .filter_map(|t| match &t.definition {
+                TableDefinition::Virtual(create) => {
+                    Some(vtab::shadow_table_names(&create.module, &create.name))
+                }
+                TableDefinition::Normal(_) => None,
+            })
+            .flatten()
+            .map(|name| name.to_lowercase())
+            .collect();
+
+        self.tables.retain(|t| {
+            !t.name.to_lowercase().starts_with("sqlite_")
+                && !shadow_tables.contains(&t.name.to_lowercase())
+        });
+    }
+
+    fn table(&self, name: &str) -> Option<&Table> {
+        self.tables
+            .iter()
+            .find(|t| t.name.eq_ignore_ascii_case(name))
+    }
+
+    fn index(&self, name: &str) -> Option<&Index> {
+        self.indexes
+            .iter()
+            .find(|i| i.name.eq_ignore_ascii_case(name))
+    }
+
+    fn trigger(&self, name: &str) -> Option<&Trigger> {
+        self.triggers
+            .iter()
+            .find(|t| t.name.eq_ignore_ascii_case(name))
+    }
+
+    fn view(&self, name: &str) -> Option<&View> {
+        self.views
+            .iter()
+            .find(|v| v.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// How a table present in both schemas is brought to its target definition.
+enum TableChange {
+    Unchanged,
+    Alter(Vec<String>),
+    /// The table is rebuilt from scratch, along with its indexes and triggers.
+    Rebuild(Vec<String>),
+}
+
+/// The statements turning `source` into `target`, in a single transaction: indexes,
+/// triggers and views are dropped first, then tables are dropped, created or altered,
+/// and the indexes, views and triggers are finally recreated.
+pub fn migrate(source: &Schema, target: &Schema) -> anyhow::Result<Vec<String>> {
+    let mut statements = Vec::new();
+    let mut rebuilt_tables = HashSet::new();
+    let mut table_statements = Vec::new();
+
+    for table in &source.tables {
+        if target.table(&table.name).is_none() {
+            table_statements.push(format!("DROP TABLE {}", quote_identifier(&table.name)));
+        }
+    }
+
+    for table in &target.tables {
+        let change = match source.table(&table.name) {
+            Some(old) => table_change(old, table),
+            None => TableChange::Rebuild(vec![table.sql.clone()]),
+        };
+
+        match change {
+            TableChange::Unchanged => {}
+            TableChange::Alter(alter) => table_statements.extend(alter),
+            TableChange::Rebuild(rebuild) => {
+                rebuilt_tables.insert(table.name.to_lowercase());
+                table_statements.extend(rebuild);
+            }
+        }
+    }
+
+    let index_changed = |index: &Index, other: Option<&Index>| {
+        other.is_none_or(|other| {
+            !other.table.eq_ignore_ascii_case(&index.table)
+                || normalize(&other.sql) != normalize(&index.sql)
+        })
+    };
+
+    let trigger_changed = |trigger: &Trigger, other: Option<&Trigger>| {
+        other.is_none_or(|other| normalize(&other.sql) != normalize(&trigger.sql))
+    };
+
+    let view_changed = |view: &View, other: Option<&View>| {
+        other.is_none_or(|other| normalize(&other.sql) != normalize(&view.sql))
+    };
+
+    // SQLite can't rename the new table of a rebuild while views or triggers refer to
+    // the dropped one, so they are dropped before it and recreated after, along with
+    // the views using them in turn and the triggers of those.
+    let mut recreated = rebuilt_tables.clone();
+    loop {
+        let count = recreated.len();
+        for view in &source.views {
+            if view_changed(view, target.view(&view.name)) || uses_any(&view.sql, &recreated)? {
+                recreated.insert(view.name.to_lowercase());
+            }
+        }
+        if recreated.len() == count {
+            break;
+        }
+    }
+
+    for index in &source.indexes {
+        let table_kept = target.table(&index.table).is_some();
+        if table_kept && index_changed(index, target.index(&index.name)) {
+            statements.push(format!("DROP INDEX {}", quote_identifier(&index.name)));
+        }
+    }
+    for trigger in &source.triggers {
+        let table_kept =
+            target.table(&trigger.table).is_some() || target.view(&trigger.table).is_some();
+        if table_kept
+            && (trigger_changed(trigger, target.trigger(&trigger.name))
+                || uses_any(&trigger.sql, &recreated)?)
+        {
+            statements.push(format!("DROP TRIGGER {}", quote_identifier(&trigger.name)));
+        }
+    }
+    for view in &source.views {
+        if recreated.contains(&view.name.to_lowercase()) {
+            statements.push(format!("DROP VIEW {}", quote_identifier(&view.name)));
+        }
+    }
+
+    statements.extend(table_statements);
+
+    for index in &target.indexes {
+        if rebuilt_tables.contains(&index.table.to_lowercase())
+            || index_changed(index, source.index(&index.name))
+        {
+            statements.push(index.sql.clone());
+        }
+    }
+    for view in &target.views {
+        if recreated.contains(&view.name.to_lowercase())
+            || view_changed(view, source.view(&view.name))
+        {
+            statements.push(view.sql.clone());
+        }
+    }
+    for trigger in &target.triggers {
+        if recreated.contains(&trigger.table.to_lowercase())
+            || trigger_changed(trigger, source.trigger(&trigger.name))
+            || uses_any(&trigger.sql, &recreated)?
+        {
+            statements.push(trigger.sql.clone());
+        }
+    }
+
+    if !statements.is_empty() {
+        statements.insert(0, "BEGIN".to_string());
+        statements.push("COMMIT".to_string());
+    }
+    Ok(statements)
+}
+
+/// Whether `sql` mentions any of the lowercase `names`.
+fn uses_any(sql: &str, names: &HashSet<String>) -> anyhow::Result<bool> {
+    for name in names {
+        if sql::find_name(sql, name)?.is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn table_change(old: &Table, new: &Table) -> TableChange {
+    if normalize(&old.sql) == normalize(&new.sql) {
+        return TableChange::Unchanged;
+    }
+
+    let name = quote_identifier(&new.name);
+    let (TableDefinition::Normal(old_create), TableDefinition::Normal(new_create)) =
+        (&old.definition, &new.definition)
+    else {
+        return TableChange::Rebuild(vec![format!("DROP TABLE {name}"), new.sql.clone()]);
+    };
+
+    if let Some(alter) = alter_columns(&name, old_create, new_create) {
+        return TableChange::Alter(alter);
+    }
+
+    // SQLite's recipe for the changes ALTER TABLE can't make: the new table is created
+    // under another name and filled, then replaces the old one.
+    let new_name = format!("new_{}", new.name);
+    let Some(create) = rename_created_table(&new.sql, &new_name) else {
+        return TableChange::Rebuild(vec![format!("DROP TABLE {name}"), new.sql.clone()]);
+    };
+    let new_name = quote_identifier(&new_name);
+    let common: Vec<String> = new_create
+        .columns
+        .iter()
+        .filter(|c| c.generated().is_none() && find_column(old_create, &c.name).is_some())
+        .map(|c| quote_identifier(&c.name))
+        .collect();
+
+    let mut rebuild = vec![create];
+    if !common.is_empty() {
+        let common = common.join(", ");
+        rebuild.push(format!(
+            "INSERT INTO {new_name}({common}) SELECT {common} FROM {name}"
+        ));
+    }
+    rebuild.push(format!("DROP TABLE {name}"));
+    rebuild.push(format!("ALTER TABLE {new_name} RENAME TO {name}"));
+
+    TableChange::Rebuild(rebuild)
+}
+
+/// The CREATE TABLE statement `sql` with the table named `name` instead, or `None`
+/// when its head can't be read.
+fn rename_created_table(sql: &str, name: &str) -> Option<String> {
+    // The tokens before the column definitions, with their offset.
+    let mut head = Vec::new();
+    let mut rest = sql;
+    while let Some((token, remainder)) = split_token(rest) {
+        if token == "(" || token.eq_ignore_ascii_case("as") {
+            break;
+        }
+        head.push((sql.len() - rest.trim_start().len(), token));
+        rest = remainder;
+    }
+
+    let mut tokens = head.as_slice();
+    let mut skip = |keywords: &[&str]| {
+        let matched = keywords.len() <= tokens.len()
+            && (keywords.iter().zip(tokens)).all(|(k, (_, t))| t.eq_ignore_ascii_case(k));
+        if matched {
+            tokens = &tokens[keywords.len()..];
+        }
+        matched
+    };
+    if !skip(&["create"]) {
+        return None;
+    }
+    let _ = skip(&["temp"]) || skip(&["temporary"]);
+    if !skip(&["table"]) {
+        return None;
+    }
+    skip(&["if", "not", "exists"]);
+
+    // The table name, which may be qualified by its schema.
+    let (start, end) = match *tokens {
+        [(start, table)] => (start, start + table.len()),
+        [(start, _), (_, "."), (offset, table)] => (start, offset + table.len()),
+        _ => return None,
+    };
+    Some(format!(
+        "{}{}{}",
+        &sql[..start],
+        quote_identifier(name),
+        &sql[end..]
+    ))
+}
+
+/// The first token of `sql`, a word, quoted identifier or single character, and the
+/// text following it.
+fn split_token(sql: &str) -> Option<(&str, &str)> {
+    let sql = sql.trim_start();
+    let first = sql.chars().next()?;
+    let close = match first {
+        '"' | '`' | '\'' => Some(first),
+        '[' => Some(']'),
+        _ => None,
+    };
+    let len = match close {
+        Some(close) => {
+            let mut chars = sql.char_indices().skip(1).peekable();
+            loop {
+                let (i, c) = chars.next()?;
+                if c != close {
+                    continue;
+                }
+                // Quotes are escaped by doubling them, unlike brackets.
+                if close != ']' && chars.next_if(|&(_, c)| c == close).is_some() {
+                    continue;
+                }
+                break i + 1;
+            }
+        }
+        None => sql
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+            .filter(|&i| i > 0)
+            .unwrap_or(first.len_utf8()),
+    };
+    Some(sql.split_at(len))
+}
+
+/// ADD/DROP COLUMN statements, when the new table only appends columns to the old
+/// one and drops some of them. SQLite can't add or drop primary key columns.
+fn alter_columns(
+    name: &str,
+    old: &ast::CreateTableStatement,
+    new: &ast::CreateTableStatement,
+) -> Option<Vec<String>> {
+    if old.constraints != new.constraints || old.without_rowid != new.without_rowid {
+        return None;
+    }
+
+    let kept: Vec<&ast::ColumnDef> = old
+        .columns
+        .iter()
+        .filter(|c| find_column(new, &c.name).is_some())
+        .collect();
+    let (existing, added) = new.columns.split_at(kept.len().min(new.columns.len()));
+
+    let same_prefix = kept.len() == existing.len()
+        && kept.iter().zip(existing).all(|(old, new)| {
+            old.name.eq_ignore_ascii_case(&new.name)
+                && old.col_type == new.col_type
+                && old.constraints == new.constraints
+        });
+    let added_plain = added
+        .iter()
+        .all(|c| c.constraints.is_empty() && find_column(old, &c.name).is_none());
+    let dropped: Vec<&ast::ColumnDef> = old
+        .columns
+        .iter()
+        .filter(|c| find_column(new, &c.name).is_none())
+        .collect();
+    let dropped_plain = dropped.iter().all(|c| {
+        c.constraints.is_empty()
+            && !old.constraints.iter().any(|constraint| match constraint {
+                ast::TableConstraint::PrimaryKey { columns, .. }
+                | ast::TableConstraint::Unique { columns, .. } => {
+                    columns.iter().any(|n| n.eq_ignore_ascii_case(&c.name))
+                }
+                // Kept constraints can't refer to a column the new table doesn't have.
+                ast::TableConstraint::Check(_) => false,
+            })
+    });
+
+    if !same_prefix || !added_plain || !dropped_plain {
+        return None;
+    }
+
+    let drops = dropped.iter().map(|c| {
+        format!(
+            "ALTER TABLE {name} DROP COLUMN {}",
+            quote_identifier(&c.name)
+        )
+    });
+    let adds = added.iter().map(|c| {
+        let mut def = quote_identifier(&c.name);
+        if let Some(col_type) = &c.col_type {
+            def.push(' ');
+            def.push_str(&col_type.to_uppercase());
+        }
+        format!("ALTER TABLE {name} ADD COLUMN {def}")
+    });
+
+    Some(drops.chain(adds).collect())
+}
+
+fn find_column<'a>(table: &'a ast::CreateTableStatement, name: &str) -> Option<&'a ast::ColumnDef> {
+    table
+        .columns
+        .iter()
+        .find(|c| c.name.eq_ignore_ascii_case(name))
+}
+
+fn normalize(sql: &str) -> String {
+    sql.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migration(source: &str, target: &str) -> Vec<String> {
+        let source = Schema::from_script(source).unwrap();
+        let target = Schema::from_script(target).unwrap();
+        migrate(&source, &target).unwrap()
+    }
+
+    #[test]
+    fn added_and_dropped_columns() {
+        let statements = migration(
+            "create table users(id integer primary key, name text, age integer);",
+            "create table users(id integer primary key, name text, email text);\n\
+             create table posts(id integer primary key, body text);",
+        );
+        assert_eq!(
+            statements,
+            vec![
+                "BEGIN",
+                "ALTER TABLE users DROP COLUMN age",
+                "ALTER TABLE users ADD COLUMN email TEXT",
+                "create table posts(id integer primary key, body text)",
+                "COMMIT",
+            ]
+        );
+    }
+
+    #[test]
+    fn rebuilt_table() {
+        let statements = migration(
+            "create table users(id integer primary key, name text);\n\
+             create index users_name on users(name);\n\
+             create table old(a);",
+            "create table users(id text primary key, name text);\n\
+             create index users_name on users(name);",
+        );
+        assert_eq!(
+            statements,
+            vec![
+                "BEGIN",
+                "DROP TABLE old",
+                "create table new_users(id text primary key, name text)",
+                "INSERT INTO new_users(id, name) SELECT id, name FROM users",
+                "DROP TABLE users",
+                "ALTER TABLE new_users RENAME TO users",
+                "create index users_name on users(name)",
+                "COMMIT",
+            ]
+        );
+    }
+
+    #[test]
+    fn rebuilt_table_with_generated_columns() {
+        let statements = migration(
+            "create table t(a integer, b, c as (abs(a)));",
+            "create table t(a text, b, c as (a || b) stored, d as (b));",
+        );
+        assert_eq!(
+            statements,
+            vec![
+                "BEGIN",
+                "create table new_t(a text, b, c as (a || b) stored, d as (b))",
+                "INSERT INTO new_t(a, b) SELECT a, b FROM t",
+                "DROP TABLE t",
+                "ALTER TABLE new_t RENAME TO t",
+                "COMMIT",
+            ]
+        );
+    }
+
+    #[test]
+    fn renamed_create_statements() {
+        let rename = |sql| rename_created_table(sql, "new t");
+        assert_eq!(
+            rename("CREATE TABLE t(a)").unwrap(),
+            "CREATE TABLE \"new t\"(a)"
+        );
+        assert_eq!(
+            rename("create temp table if not exists main . [my table] (a, b)").unwrap(),
+            "create temp table if not exists \"new t\" (a, b)"
+        );
+        assert_eq!(
+            rename("create table \"a \"\"b\"\"\"(x)").unwrap(),
+            "create table \"new t\"(x)"
+        );
+        assert_eq!(rename("create view v as select 1"), None);
+    }
+
+    #[test]
+    fn changed_indexes() {
+        let statements = migration(
+            "create table t(a, b); create index t_a on t(a); create index t_b on t(b);",
+            "create table t(a, b); create index t_a on t(a, b); -- t_b is gone",
+        );
+        assert_eq!(
+            statements,
+            vec![
+                "BEGIN",
+                "DROP INDEX t_a",
+                "DROP INDEX t_b",
+                "create index t_a on t(a, b)",
+                "COMMIT",
+            ]
+        );
+        assert!(migration("create table t(a);", "CREATE  TABLE t(a);").is_empty());
+    }
+
+    #[test]
+    fn views_over_rebuilt_table() {
+        let source = "create table t(id integer primary key, a);\n\
+                      create view v as select a from t;\n\
+                      create view w as select a from v;\n\
+                      create view old as select 1;\n\
+                      create table log(a);\n\
+                      create trigger log_ins after insert on log begin insert into t(a) values (new.a); end;";
+        let target = "create table t(id text primary key, a);\n\
+                      create view v as select a from t;\n\
+                      create view w as select a from v;\n\
+                      create view u as select id from t where a > 0;\n\
+                      create table log(a);\n\
+                      create trigger log_ins after insert on log begin insert into t(a) values (new.a); end;";
+        assert_eq!(
+            migration(source, target),
+            vec![
+                "BEGIN",
+                "DROP TRIGGER log_ins",
+                "DROP VIEW v",
+                "DROP VIEW w",
+                "DROP VIEW old",
+                "create table new_t(id text primary key, a)",
+                "INSERT INTO new_t(id, a) SELECT id, a FROM t",
+                "DROP TABLE t",
+                "ALTER TABLE new_t RENAME TO t",
+                "create view v as select a from t",
+                "create view w as select a from v",
+                "create view u as select id from t where a > 0",
+                "create trigger log_ins after insert on log begin insert into t(a) values (new.a); end",
+                "COMMIT",
+            ]
+        );
+        assert!(migration(source, source).is_empty());
+    }
+
+    #[test]
+    fn changed_triggers() {
+        let source = "create table t(a); create table log(a);\n\
+                      create trigger t_ins after insert on t begin insert into log values (1); end;\n\
+                      create trigger t_del after delete on t begin delete from log; end;";
+        let target = "create table t(a, b); create table log(a);\n\
+                      create trigger t_ins after insert on t begin insert into log values (2); end;\n\
+                      create trigger t_del after delete on t begin delete from log; end;";
+        assert_eq!(
+            migration(source, target),
+            vec![
+                "BEGIN",
+                "DROP TRIGGER t_ins",
+                "ALTER TABLE t ADD COLUMN b",
+                "create trigger t_ins after insert on t begin insert into log values (2); end",
+                "COMMIT",
+            ]
+        );
+    }
+}
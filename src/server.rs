@@ -0,0 +1,372 @@
+//! A minimal read-only HTTP server answering SQL queries with JSON. Queries are sent
+//! either as the body of `POST /query` or in the `sql` parameter of `GET /query`. The
+//! columns of the rows are sent first, then the rows as they are produced, followed by
+//! the execution statistics of the query.
+
+use std::{
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+
+use crate::{
+    db::Db,
+    engine::{ExecutionStats, Schema},
+    export::value_to_json,
+    value::OwnedValue,
+};
+
+const MAX_BODY_SIZE: usize = 1 << 20;
+/// How long a client may leave a request unfinished before its connection is dropped.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// The connections served at once. Clients beyond those are answered 503 right away.
+const MAX_CONNECTIONS: usize = 64;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+    Query(String),
+    NotFound,
+    MethodNotAllowed,
+}
+
+pub fn serve(db: Db, address: &str) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address).with_context(|| format!("listen on {address}"))?;
+    let db = Arc::new(db);
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    eprintln!("listening on http://{}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("accept connection: {e}");
+                continue;
+            }
+        };
+
+        let Some(slot) = ConnectionSlot::acquire(&connections) else {
+            let _ = stream.set_write_timeout(Some(READ_TIMEOUT));
+            let _ = write_error(
+                &mut stream,
+                "503 Service Unavailable",
+                "too many connections",
+            );
+            continue;
+        };
+        if let Err(e) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+            eprintln!("set read timeout: {e}");
+            continue;
+        }
+
+        let db = db.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            if let Err(e) = handle_connection(&db, stream) {
+                eprintln!("connection error: {e:#}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// One of the `MAX_CONNECTIONS` connections served at once, released when dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn acquire(connections: &Arc<AtomicUsize>) -> Option<Self> {
+        connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < MAX_CONNECTIONS).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self(connections.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn handle_connection(db: &Db, stream: TcpStream) -> anyhow::Result<()> {
+    let request = parse_request(BufReader::new(&stream));
+    let mut out = BufWriter::new(&stream);
+
+    match request {
+        Ok(Request::Query(query)) => run_query(db, &query, &mut out)?,
+        Ok(Request::NotFound) => write_error(&mut out, "404 Not Found", "not found")?,
+        Ok(Request::MethodNotAllowed) => {
+            write_error(&mut out, "405 Method Not Allowed", "method not allowed")?
+        }
+        Err(e) => write_error(&mut out, "400 Bad Request", &format!("{e:#}"))?,
+    }
+
+    out.flush().context("flush response")
+}
+
+fn parse_request(mut input: impl BufRead) -> anyhow::Result<Request> {
+    let mut line = String::new();
+    input.read_line(&mut line).context("read request line")?;
+
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        bail!("invalid request line");
+    };
+    let method = method.to_string();
+    let target = target.to_string();
+
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        input.read_line(&mut line).context("read request header")?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            content_length = value.trim().parse().context("invalid content length")?;
+        }
+    }
+
+    let (path, query_string) = target.split_once('?').unwrap_or((&target, ""));
+    if path != "/query" {
+        return Ok(Request::NotFound);
+    }
+
+    match method.as_str() {
+        "GET" => {
+            let sql = query_string
+                .split('&')
+                .filter_map(|param| param.split_once('='))
+                .find(|(key, _)| *key == "sql")
+                .context("missing sql parameter")?
+                .1;
+            Ok(Request::Query(percent_decode(sql)?))
+        }
+        "POST" => {
+            if content_length > MAX_BODY_SIZE {
+                bail!("request body too large");
+            }
+            let mut body = vec![0; content_length];
+            input.read_exact(&mut body).context("read request body")?;
+            Ok(Request::Query(
+                String::from_utf8(body).context("query should be valid utf-8")?,
+            ))
+        }
+        _ => Ok(Request::MethodNotAllowed),
+    }
+}
+
+fn percent_decode(input: &str) -> anyhow::Result<String> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut chars = input.bytes();
+
+    while let Some(b) = chars.next() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [
+                    chars.next().context("truncated escape")?,
+                    chars.next().context("truncated escape")?,
+                ];
+                let hex = std::str::from_utf8(&hex).context("invalid escape")?;
+                bytes.push(u8::from_str_radix(hex, 16).context("invalid escape")?);
+            }
+            b => bytes.push(b),
+        }
+    }
+
+    String::from_utf8(bytes).context("query should be valid utf-8")
+}
+
+/// Streams the rows as a chunked `{"rows": [...]}` document. Errors hit while
+/// producing rows are reported in a trailing `error` field, since the status line has
+/// already been sent by then. Statements modifying the database are refused before
+/// being planned, as the server is read-only.
+fn run_query(db: &Db, query: &str, out: &mut impl Write) -> anyhow::Result<()> {
+    match db.prepare(query) {
+        Ok(statement) if statement.is_write() => {
+            return write_error(
+                out,
+                "405 Method Not Allowed",
+                "the server is read-only: statements modifying the database are not allowed",
+            );
+        }
+        Ok(_) => {}
+        Err(e) => return write_error(out, "400 Bad Request", &format!("{e:#}")),
+    }
+    let mut op = match db.query(query) {
+        Ok(op) => op,
+        Err(e) => return write_error(out, "400 Bad Request", &format!("{e:#}")),
+    };
+
+    write!(
+        out,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: application/json\r\n\
+         Transfer-Encoding: chunked\r\n\
+         Connection: close\r\n\r\n"
+    )?;
+    let columns = columns_to_json(op.schema())?;
+    write_chunk(out, &format!("{{\"columns\":{columns},\"rows\":["))?;
+
+    let mut first = true;
+    let error = loop {
+        match op.next_row() {
+            Ok(Some(row)) => {
+                let mut chunk = String::from(if first { "[" } else { ",[" });
+                for (i, value) in row.iter().enumerate() {
+                    if i > 0 {
+                        chunk.push(',');
+                    }
+                    chunk.push_str(&value_to_json(value)?);
+                }
+                chunk.push(']');
+                write_chunk(out, &chunk)?;
+                first = false;
+            }
+            Ok(None) => break None,
+            Err(e) => break Some(e),
+        }
+    };
+
+    let stats = stats_to_json(&op.stats());
+    match error {
+        Some(e) => {
+            let message = value_to_json(&OwnedValue::String(format!("{e:#}").into()))?;
+            write_chunk(out, &format!("],\"stats\":{stats},\"error\":{message}}}"))?;
+        }
+        None => write_chunk(out, &format!("],\"stats\":{stats}}}"))?,
+    }
+
+    out.write_all(b"0\r\n\r\n")?;
+    Ok(())
+}
+
+/// The name of each column, with its declared type and table when it's a table column.
+fn columns_to_json(schema: &Schema) -> anyhow::Result<String> {
+    let text = |s: &Option<String>| match s {
+        Some(s) => value_to_json(&OwnedValue::String(s.clone().into())),
+        None => Ok("null".to_string()),
+    };
+    let columns = schema
+        .columns()
+        .iter()
+        .map(|column| {
+            Ok(format!(
+                "{{\"name\":{},\"type\":{},\"table\":{}}}",
+                text(&Some(column.name.clone()))?,
+                text(&column.decl_type)?,
+                text(&column.table)?
+            ))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(format!("[{}]", columns.join(",")))
+}
+
+fn stats_to_json(stats: &ExecutionStats) -> String {
+    format!(
+        "{{\"rows_scanned\":{},\"rows_returned\":{},\"pages_read\":{},\"cache_hits\":{},\
+         \"peak_memory\":{},\"elapsed_ms\":{:.3}}}",
+        stats.rows_scanned,
+        stats.rows_returned,
+        stats.pages_read,
+        stats.cache_hits,
+        stats.peak_memory,
+        stats.elapsed.as_secs_f64() * 1000.0
+    )
+}
+
+fn write_chunk(out: &mut impl Write, chunk: &str) -> anyhow::Result<()> {
+    write!(out, "{:x}\r\n{chunk}\r\n", chunk.len()).context("write response chunk")
+}
+
+fn write_error(out: &mut impl Write, status: &str, message: &str) -> anyhow::Result<()> {
+    let body = format!(
+        "{{\"error\":{}}}",
+        value_to_json(&OwnedValue::String(message.to_string().into()))?
+    );
+    write!(
+        out,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .context("write error response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::schema_database;
+
+    #[test]
+    fn connection_slots() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let slots: Vec<_> = (0..MAX_CONNECTIONS)
+            .map(|_| ConnectionSlot::acquire(&connections).unwrap())
+            .collect();
+        assert!(ConnectionSlot::acquire(&connections).is_none());
+
+        drop(slots);
+        assert_eq!(connections.load(Ordering::Acquire), 0);
+        assert!(ConnectionSlot::acquire(&connections).is_some());
+    }
+
+    #[test]
+    fn parse_get_request() {
+        let request = "GET /query?sql=select+*+from+t%3B HTTP/1.1\r\nHost: x\r\n\r\n";
+        assert_eq!(
+            parse_request(request.as_bytes()).unwrap(),
+            Request::Query("select * from t;".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_post_request() {
+        let request = "POST /query HTTP/1.1\r\nContent-Length: 15\r\n\r\nselect a from t";
+        assert_eq!(
+            parse_request(request.as_bytes()).unwrap(),
+            Request::Query("select a from t".to_string())
+        );
+
+        let request = "POST /other HTTP/1.1\r\n\r\n";
+        assert_eq!(
+            parse_request(request.as_bytes()).unwrap(),
+            Request::NotFound
+        );
+    }
+
+    #[test]
+    fn refuse_writes() {
+        let db = schema_database("server-writes", &[("table", "t", "t", "CREATE TABLE t(a)")]);
+        let response = |sql| {
+            let mut out = Vec::new();
+            run_query(&db, sql, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        for sql in ["delete from t", "drop table t"] {
+            assert!(
+                response(sql).starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+                "{sql}"
+            );
+        }
+        assert!(response("select count(*) from t").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(db.metadata().table("t").is_some());
+    }
+}
@@ -1,63 +1,679 @@
-#[derive(Debug, Clone, Eq, PartialEq)]
+use crate::value::{Affinity, Collation};
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Statement {
-    Select(SelectStatement),
+    Select(Box<SelectStatement>),
     CreateTable(CreateTableStatement),
+    CreateVirtualTable(CreateVirtualTableStatement),
+    CreateIndex(CreateIndexStatement),
+    CreateTrigger(CreateTriggerStatement),
+    Drop(DropStatement),
+    AlterTable(AlterTableStatement),
+    Insert(InsertStatement),
+    Update(UpdateStatement),
+    Delete(DeleteStatement),
+    Pragma(PragmaStatement),
+    Explain(Box<Statement>),
+    ExplainQueryPlan(Box<Statement>),
+    /// Runs the statement, then shows its plan with the rows each operator produced
+    /// and the time spent producing them.
+    ExplainAnalyze(Box<Statement>),
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+impl Statement {
+    /// Whether the statement modifies the database.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Statement::Insert(_)
+                | Statement::Update(_)
+                | Statement::Delete(_)
+                | Statement::Drop(_)
+                | Statement::AlterTable(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct CreateTableStatement {
     pub name: String,
     pub columns: Vec<ColumnDef>,
+    pub constraints: Vec<TableConstraint>,
+    pub without_rowid: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateVirtualTableStatement {
+    pub name: String,
+    pub module: String,
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateIndexStatement {
+    pub name: String,
+    pub table: String,
+    pub unique: bool,
+    /// The indexed columns or expressions, in the order of the keys.
+    pub columns: Vec<OrderingTerm>,
+    /// Whether the index has a WHERE clause, only holding the rows it selects.
+    pub partial: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateTriggerStatement {
+    pub name: String,
+    pub timing: TriggerTiming,
+    pub event: TriggerEvent,
+    pub table: String,
+}
+
+/// What a trigger does for each row it fires on: unless its WHEN clause is false, it
+/// runs its statements, which refer to the row being written as NEW and OLD.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TriggerProgram {
+    pub when: Option<Expr>,
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum TriggerTiming {
+    #[default]
+    Before,
+    After,
+    InsteadOf,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TriggerEvent {
+    Insert,
+    Delete,
+    /// Fires on updates of the listed columns, or of any column when empty.
+    Update(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DropStatement {
+    pub kind: SchemaObjectKind,
+    pub name: String,
+    pub if_exists: bool,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SchemaObjectKind {
+    Table,
+    Index,
+    View,
+    Trigger,
+}
+
+impl SchemaObjectKind {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            SchemaObjectKind::Table => "TABLE",
+            SchemaObjectKind::Index => "INDEX",
+            SchemaObjectKind::View => "VIEW",
+            SchemaObjectKind::Trigger => "TRIGGER",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlterTableStatement {
+    pub table: String,
+    pub action: AlterTableAction,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AlterTableAction {
+    DropColumn(String),
+}
+
+/// `PRAGMA name`, `PRAGMA name(arg)` or `PRAGMA name = arg`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PragmaStatement {
+    /// The lowercase name of the pragma.
+    pub name: String,
+    pub arg: Option<PragmaArg>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PragmaArg {
+    Integer(i64),
+    Name(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InsertStatement {
+    /// The `OR ...` clause, overriding the `ON CONFLICT` clauses of the constraints.
+    pub on_conflict: Option<ConflictResolution>,
+    pub table: String,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Expr>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateStatement {
+    pub on_conflict: Option<ConflictResolution>,
+    pub table: String,
+    /// The new values of the columns, by name.
+    pub assignments: Vec<(String, Expr)>,
+    pub where_clause: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeleteStatement {
+    pub table: String,
+    pub where_clause: Option<Expr>,
+}
+
+/// What a statement does with a row failing a constraint, set by its `OR ...` clause
+/// or the `ON CONFLICT` clause of the constraint. REPLACE deletes the conflicting rows
+/// before inserting.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum ConflictResolution {
+    #[default]
+    Abort,
+    Fail,
+    Ignore,
+    Replace,
+    Rollback,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ColumnDef {
     pub name: String,
-    pub col_type: Type,
+    /// The declared type, which may be any name, e.g. `VARCHAR(255)`.
+    pub col_type: Option<String>,
+    pub constraints: Vec<ColumnConstraint>,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
-pub enum Type {
-    Integer,
-    Real,
-    Text,
-    Blob,
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnConstraint {
+    PrimaryKey(Option<ConflictResolution>),
+    NotNull(Option<ConflictResolution>),
+    /// Backed by an automatic index, `sqlite_autoindex_<table>_<n>`.
+    Unique(Option<ConflictResolution>),
+    Check(Check),
+    Default(Expr),
+    Collate(String),
+    /// `GENERATED ALWAYS AS (expr)`, whose values are only stored in the records
+    /// when `stored`, and computed when read otherwise.
+    Generated {
+        expr: Expr,
+        stored: bool,
+    },
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableConstraint {
+    PrimaryKey {
+        columns: Vec<String>,
+        on_conflict: Option<ConflictResolution>,
+    },
+    Unique {
+        columns: Vec<String>,
+        on_conflict: Option<ConflictResolution>,
+    },
+    Check(Check),
+}
+
+/// A CHECK constraint, whose name identifies it in the errors about the rows failing
+/// it. The names of the other constraints are dropped, as their errors name the
+/// constrained columns instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: Option<String>,
+    pub expr: Expr,
+}
+
+impl ColumnDef {
+    /// The affinity given to the column by its declared type.
+    pub fn affinity(&self) -> Affinity {
+        Affinity::from_type_name(self.col_type.as_deref().unwrap_or_default())
+    }
+
+    /// Whether the column is declared as the primary key.
+    pub fn is_primary_key(&self) -> bool {
+        (self.constraints.iter()).any(|c| matches!(c, ColumnConstraint::PrimaryKey(_)))
+    }
+
+    /// The collating sequence comparing the values of the column, BINARY unless
+    /// declared otherwise.
+    pub fn collation(&self) -> Collation {
+        self.constraints
+            .iter()
+            .find_map(|constraint| match constraint {
+                ColumnConstraint::Collate(name) => Collation::from_name(name),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// The expression computing a generated column, and whether its values are stored.
+    pub fn generated(&self) -> Option<(&Expr, bool)> {
+        self.constraints
+            .iter()
+            .find_map(|constraint| match constraint {
+                ColumnConstraint::Generated { expr, stored } => Some((expr, *stored)),
+                _ => None,
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct SelectStatement {
     pub core: SelectCore,
+    pub order_by: Vec<OrderingTerm>,
+    pub limit: Option<Expr>,
+    pub offset: Option<Expr>,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderingTerm {
+    pub expr: Expr,
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct SelectCore {
+    pub distinct: bool,
     pub result_columns: Vec<ResultColumn>,
     pub from: SelectFrom,
+    pub where_clause: Option<Expr>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ResultColumn {
     Star,
+    /// `table.*`, the columns of one of the tables of the FROM clause.
+    TableStar(String),
     Expr(ExprResultColumn),
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+impl FunctionCall {
+    fn try_map_args(
+        &self,
+        f: &mut impl FnMut(&Expr) -> anyhow::Result<Expr>,
+    ) -> anyhow::Result<FunctionCall> {
+        Ok(FunctionCall {
+            name: self.name.clone(),
+            args: self.args.iter().map(f).collect::<anyhow::Result<_>>()?,
+            star: self.star,
+        })
+    }
+}
+
+impl std::fmt::Display for FunctionCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.star {
+            return write!(f, "{}(*)", self.name);
+        }
+        write!(f, "{}(", self.name)?;
+        write_list(f, self.args.iter())?;
+        write!(f, ")")
+    }
+}
+
+impl std::fmt::Display for WindowCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} OVER (", self.function)?;
+        if !self.partition_by.is_empty() {
+            write!(f, "PARTITION BY ")?;
+            write_list(f, self.partition_by.iter())?;
+        }
+        if !self.order_by.is_empty() {
+            if !self.partition_by.is_empty() {
+                write!(f, " ")?;
+            }
+            write!(f, "ORDER BY ")?;
+            write_list(f, self.order_by.iter())?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl std::fmt::Display for OrderingTerm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.descending {
+            true => write!(f, "{} DESC", self.expr),
+            false => write!(f, "{}", self.expr),
+        }
+    }
+}
+
+fn write_list<T: std::fmt::Display>(
+    f: &mut std::fmt::Formatter<'_>,
+    items: impl Iterator<Item = T>,
+) -> std::fmt::Result {
+    for (i, item) in items.enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{item}")?;
+    }
+    Ok(())
+}
+
+impl Expr {
+    /// The expressions this one is computed from.
+    pub fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Column(_) | Expr::Literal(_) | Expr::Parameter(_) => Vec::new(),
+            Expr::Function(call) => call.args.iter().collect(),
+            Expr::Window(window) => window
+                .function
+                .args
+                .iter()
+                .chain(&window.partition_by)
+                .chain(window.order_by.iter().map(|term| &term.expr))
+                .collect(),
+            Expr::Unary(unary) => vec![&unary.expr],
+            Expr::Binary(binary) => vec![&binary.lhs, &binary.rhs],
+            Expr::Between(between) => vec![&between.expr, &between.low, &between.high],
+            Expr::Like(like) => [&*like.expr, &*like.pattern]
+                .into_iter()
+                .chain(like.escape.as_deref())
+                .collect(),
+            Expr::Cast(cast) => vec![&cast.expr],
+            Expr::Collate(collate) => vec![&collate.expr],
+        }
+    }
+
+    /// Rebuilds the expression with `f` applied to the expressions it is computed from.
+    pub fn try_map_children(
+        &self,
+        mut f: impl FnMut(&Expr) -> anyhow::Result<Expr>,
+    ) -> anyhow::Result<Expr> {
+        Ok(match self {
+            Expr::Column(_) | Expr::Literal(_) | Expr::Parameter(_) => self.clone(),
+            Expr::Function(call) => Expr::Function(call.try_map_args(&mut f)?),
+            Expr::Window(window) => Expr::Window(WindowCall {
+                function: window.function.try_map_args(&mut f)?,
+                partition_by: window
+                    .partition_by
+                    .iter()
+                    .map(&mut f)
+                    .collect::<anyhow::Result<_>>()?,
+                order_by: window
+                    .order_by
+                    .iter()
+                    .map(|term| {
+                        Ok(OrderingTerm {
+                            expr: f(&term.expr)?,
+                            descending: term.descending,
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            }),
+            Expr::Unary(unary) => Expr::Unary(UnaryExpr {
+                op: unary.op,
+                expr: Box::new(f(&unary.expr)?),
+            }),
+            Expr::Binary(binary) => Expr::Binary(BinaryExpr {
+                op: binary.op,
+                lhs: Box::new(f(&binary.lhs)?),
+                rhs: Box::new(f(&binary.rhs)?),
+            }),
+            Expr::Between(between) => Expr::Between(BetweenExpr {
+                expr: Box::new(f(&between.expr)?),
+                low: Box::new(f(&between.low)?),
+                high: Box::new(f(&between.high)?),
+                negated: between.negated,
+            }),
+            Expr::Like(like) => Expr::Like(LikeExpr {
+                expr: Box::new(f(&like.expr)?),
+                pattern: Box::new(f(&like.pattern)?),
+                escape: like
+                    .escape
+                    .as_deref()
+                    .map(&mut f)
+                    .transpose()?
+                    .map(Box::new),
+                negated: like.negated,
+            }),
+            Expr::Cast(cast) => Expr::Cast(CastExpr {
+                expr: Box::new(f(&cast.expr)?),
+                type_name: cast.type_name.clone(),
+            }),
+            Expr::Collate(collate) => Expr::Collate(CollateExpr {
+                expr: Box::new(f(&collate.expr)?),
+                collation: collate.collation.clone(),
+            }),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ExprResultColumn {
     pub expr: Expr,
     pub alias: Option<String>,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Column(Column),
+    Literal(Literal),
+    Function(FunctionCall),
+    Window(WindowCall),
+    Unary(UnaryExpr),
+    Binary(BinaryExpr),
+    Between(BetweenExpr),
+    Like(LikeExpr),
+    Cast(CastExpr),
+    Collate(CollateExpr),
+    Parameter(Parameter),
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+/// A placeholder for a value bound when running the statement. Like in SQLite, `?`
+/// takes the index following the largest one used before it, and each name gets an
+/// index the first time it appears.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parameter {
+    /// The index of the parameter, starting at 1.
+    pub index: usize,
+    /// The name of the parameter as written, e.g. `:id` or `?2`, or `None` for `?`.
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Null,
+    String(String),
+    Integer(i64),
+    Real(f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionCall {
+    pub name: String,
+    pub args: Vec<Expr>,
+    /// Whether the function was called with `*` instead of arguments, as in `count(*)`.
+    pub star: bool,
+}
+
+/// `function(args) OVER (PARTITION BY ... ORDER BY ...)`, computed over the rows of
+/// the partition up to the last row sorting like the current one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowCall {
+    pub function: FunctionCall,
+    pub partition_by: Vec<Expr>,
+    pub order_by: Vec<OrderingTerm>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BinaryExpr {
+    pub op: BinaryOperator,
+    pub lhs: Box<Expr>,
+    pub rhs: Box<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnaryExpr {
+    pub op: UnaryOperator,
+    pub expr: Box<Expr>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum UnaryOperator {
+    Not,
+}
+
+/// `expr [NOT] BETWEEN low AND high`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BetweenExpr {
+    pub expr: Box<Expr>,
+    pub low: Box<Expr>,
+    pub high: Box<Expr>,
+    pub negated: bool,
+}
+
+/// `expr [NOT] LIKE pattern [ESCAPE escape]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LikeExpr {
+    pub expr: Box<Expr>,
+    pub pattern: Box<Expr>,
+    pub escape: Option<Box<Expr>>,
+    pub negated: bool,
+}
+
+/// `CAST(expr AS type_name)`. Any type name is allowed, and converts to the type of
+/// its affinity.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CastExpr {
+    pub expr: Box<Expr>,
+    pub type_name: String,
+}
+
+/// `expr COLLATE name`, comparing the value of `expr` with the named collating
+/// sequence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollateExpr {
+    pub expr: Box<Expr>,
+    pub collation: String,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BinaryOperator {
+    Arrow,
+    LongArrow,
+    Concat,
+    Match,
+    And,
+    Or,
+    Eq,
+    NotEq,
+    Is,
+    IsNot,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+impl BinaryOperator {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            BinaryOperator::Arrow => "->",
+            BinaryOperator::LongArrow => "->>",
+            BinaryOperator::Concat => "||",
+            BinaryOperator::Match => "MATCH",
+            BinaryOperator::And => "AND",
+            BinaryOperator::Or => "OR",
+            BinaryOperator::Eq => "=",
+            BinaryOperator::NotEq => "!=",
+            BinaryOperator::Is => "IS",
+            BinaryOperator::IsNot => "IS NOT",
+            BinaryOperator::Lt => "<",
+            BinaryOperator::LtEq => "<=",
+            BinaryOperator::Gt => ">",
+            BinaryOperator::GtEq => ">=",
+        }
+    }
+}
+
+/// Formats the expression as SQL, e.g. to name the result columns computing it.
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Column(Column {
+                table: Some(table),
+                name,
+            }) => write!(f, "{table}.{name}"),
+            Expr::Column(column) => write!(f, "{}", column.name),
+            Expr::Literal(Literal::Null) => write!(f, "NULL"),
+            Expr::Literal(Literal::String(s)) => write!(f, "'{}'", s.replace('\'', "''")),
+            Expr::Literal(Literal::Integer(i)) => write!(f, "{i}"),
+            Expr::Literal(Literal::Real(r)) => write!(f, "{r:?}"),
+            Expr::Function(call) => write!(f, "{call}"),
+            Expr::Window(window) => write!(f, "{window}"),
+            Expr::Unary(UnaryExpr {
+                op: UnaryOperator::Not,
+                expr,
+            }) => write!(f, "NOT {expr}"),
+            Expr::Binary(binary) => {
+                write!(f, "{} {} {}", binary.lhs, binary.op.as_sql(), binary.rhs)
+            }
+            Expr::Cast(cast) => write!(f, "CAST({} AS {})", cast.expr, cast.type_name),
+            Expr::Collate(collate) => write!(f, "{} COLLATE {}", collate.expr, collate.collation),
+            Expr::Parameter(parameter) => match &parameter.name {
+                Some(name) => write!(f, "{name}"),
+                None => write!(f, "?"),
+            },
+            Expr::Between(between) => {
+                let not = if between.negated { "NOT " } else { "" };
+                write!(
+                    f,
+                    "{} {not}BETWEEN {} AND {}",
+                    between.expr, between.low, between.high
+                )
+            }
+            Expr::Like(like) => {
+                let not = if like.negated { "NOT " } else { "" };
+                write!(f, "{} {not}LIKE {}", like.expr, like.pattern)?;
+                match &like.escape {
+                    Some(escape) => write!(f, " ESCAPE {escape}"),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Column {
+    pub table: Option<String>,
     pub name: String,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SelectFrom {
-    Table(String),
+    Table {
+        name: String,
+        alias: Option<String>,
+    },
+    Function(FunctionCall),
+    /// `(VALUES (...), ...)`, whose columns are named column1, column2, ...
+    Values {
+        rows: Vec<Vec<Expr>>,
+        alias: Option<String>,
+    },
+    Join(Box<Join>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Join {
+    pub kind: JoinKind,
+    pub left: SelectFrom,
+    pub right: SelectFrom,
+    pub constraint: Option<Expr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinKind {
+    Inner,
+    /// Keeps the left rows without a match, padding their right side with NULLs.
+    Left,
 }
@@ -2,4 +2,18 @@ pub mod ast;
 mod parser;
 mod tokenizer;
 
-pub use parser::{parse_create_statement, parse_statement};
+pub use parser::{
+    created_view_name, drop_column_definition, find_name, parse_create_statement, parse_statement,
+    parse_trigger_program, split_statements,
+};
+
+/// Quotes an identifier for use in generated SQL, when it isn't a plain name.
+pub fn quote_identifier(name: &str) -> String {
+    let plain = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if plain {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
@@ -1,9 +1,17 @@
-use anyhow::{bail, Context};
+use std::{collections::HashMap, ops::Range};
+
+use anyhow::{Context, bail, ensure};
 
 use crate::sql::{
     ast::{
-        Column, ColumnDef, CreateTableStatement, Expr, ExprResultColumn, ResultColumn, SelectCore,
-        SelectFrom, SelectStatement, Statement, Type,
+        AlterTableAction, AlterTableStatement, BetweenExpr, BinaryExpr, BinaryOperator, CastExpr,
+        Check, CollateExpr, Column, ColumnConstraint, ColumnDef, ConflictResolution,
+        CreateIndexStatement, CreateTableStatement, CreateTriggerStatement,
+        CreateVirtualTableStatement, DeleteStatement, DropStatement, Expr, ExprResultColumn,
+        FunctionCall, InsertStatement, Join, JoinKind, LikeExpr, Literal, OrderingTerm, Parameter,
+        PragmaArg, PragmaStatement, ResultColumn, SchemaObjectKind, SelectCore, SelectFrom,
+        SelectStatement, Statement, TableConstraint, TriggerEvent, TriggerProgram, TriggerTiming,
+        UnaryExpr, UnaryOperator, UpdateStatement, WindowCall,
     },
     tokenizer::{self, Token},
 };
@@ -12,17 +20,56 @@ use crate::sql::{
 struct ParserState {
     tokens: Vec<Token>,
     pos: usize,
+    /// The indexes of the named parameters, and the largest index used so far.
+    parameters: HashMap<String, usize>,
+    last_parameter: usize,
 }
 
+/// The largest parameter index SQLite accepts by default.
+const MAX_PARAMETER_INDEX: usize = 32766;
+
 impl ParserState {
     fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, pos: 0 }
+        Self {
+            tokens,
+            pos: 0,
+            parameters: HashMap::new(),
+            last_parameter: 0,
+        }
     }
 
     fn parse_statement(&mut self) -> anyhow::Result<Statement> {
         match self.peek_next_token().context("unexpected end of input")? {
-            Token::Select => self.parse_select().map(Statement::Select),
+            Token::Select => Ok(Statement::Select(Box::new(self.parse_select()?))),
+            _ if self.next_keyword_is("values") => {
+                Ok(Statement::Select(Box::new(self.parse_values_statement()?)))
+            }
             Token::Create => self.parse_create_table().map(Statement::CreateTable),
+            _ if self.next_keyword_is("insert") || self.next_keyword_is("replace") => {
+                self.parse_insert().map(Statement::Insert)
+            }
+            _ if self.next_keyword_is("update") => self.parse_update().map(Statement::Update),
+            _ if self.next_keyword_is("delete") => self.parse_delete().map(Statement::Delete),
+            _ if self.next_keyword_is("drop") => self.parse_drop().map(Statement::Drop),
+            _ if self.next_keyword_is("alter") => {
+                self.parse_alter_table().map(Statement::AlterTable)
+            }
+            _ if self.next_keyword_is("pragma") => self.parse_pragma().map(Statement::Pragma),
+            _ if self.next_keyword_is("explain") => {
+                self.advance();
+                if self.next_keyword_is("query") {
+                    self.advance();
+                    self.expect_keyword("plan")?;
+                    return Ok(Statement::ExplainQueryPlan(Box::new(
+                        self.parse_statement()?,
+                    )));
+                }
+                if self.next_keyword_is("analyze") {
+                    self.advance();
+                    return Ok(Statement::ExplainAnalyze(Box::new(self.parse_statement()?)));
+                }
+                Ok(Statement::Explain(Box::new(self.parse_statement()?)))
+            }
             token => bail!("unexpected token: {token:?}"),
         }
     }
@@ -30,52 +77,731 @@ impl ParserState {
     fn parse_create_table(&mut self) -> anyhow::Result<CreateTableStatement> {
         self.expect_eq(Token::Create)?;
         self.expect_eq(Token::Table)?;
-        let name = self.expect_identifier()?.to_string();
+        if self.next_keyword_is("if") {
+            self.advance();
+            self.expect_keyword("not")?;
+            self.expect_keyword("exists")?;
+        }
+        let name = self.parse_name()?;
         self.expect_eq(Token::LPar)?;
-        let mut columns = vec![self.parse_column_def()?];
-        while self.next_token_is(Token::Comma) {
+
+        let mut columns = Vec::new();
+        let mut constraints = Vec::new();
+        loop {
+            if self.next_is_constraint() {
+                constraints.push(self.parse_table_constraint()?);
+            } else {
+                columns.push(self.parse_column_def()?);
+            }
+
+            if !self.next_token_is(Token::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        self.expect_eq(Token::RPar)?;
+
+        let without_rowid = self.next_keyword_is("without");
+        if without_rowid {
+            self.advance();
+            self.expect_keyword("rowid")?;
+        }
+
+        Ok(CreateTableStatement {
+            name,
+            columns,
+            constraints,
+            without_rowid,
+        })
+    }
+
+    fn parse_create_virtual_table(
+        &mut self,
+        args: Vec<String>,
+    ) -> anyhow::Result<CreateVirtualTableStatement> {
+        self.expect_eq(Token::Create)?;
+        self.expect_keyword("virtual")?;
+        self.expect_eq(Token::Table)?;
+        let name = self.parse_name()?;
+        self.expect_keyword("using")?;
+        let module = self.expect_identifier()?.to_string();
+        Ok(CreateVirtualTableStatement { name, module, args })
+    }
+
+    /// Parses a CREATE INDEX statement up to its indexed columns, noting whether a
+    /// WHERE clause follows them.
+    fn parse_create_index(&mut self) -> anyhow::Result<CreateIndexStatement> {
+        self.expect_eq(Token::Create)?;
+        let unique = self.next_keyword_is("unique");
+        if unique {
             self.advance();
-            columns.push(self.parse_column_def()?);
         }
+        self.expect_keyword("index")?;
+        if self.next_keyword_is("if") {
+            self.advance();
+            self.expect_keyword("not")?;
+            self.expect_keyword("exists")?;
+        }
+        let name = self.parse_name()?;
+        self.expect_keyword("on")?;
+        let table = self.parse_name()?;
+        self.expect_eq(Token::LPar)?;
+        let columns = self.parse_ordering_terms()?;
         self.expect_eq(Token::RPar)?;
-        Ok(CreateTableStatement { name, columns })
+        Ok(CreateIndexStatement {
+            name,
+            table,
+            unique,
+            columns,
+            partial: self.next_token_is(Token::Where),
+        })
+    }
+
+    /// Parses the head of a CREATE TRIGGER statement, up to its WHEN clause or body.
+    fn parse_create_trigger(&mut self) -> anyhow::Result<CreateTriggerStatement> {
+        self.expect_eq(Token::Create)?;
+        if self.next_keyword_is("temp") || self.next_keyword_is("temporary") {
+            self.advance();
+        }
+        self.expect_keyword("trigger")?;
+        if self.next_keyword_is("if") {
+            self.advance();
+            self.expect_keyword("not")?;
+            self.expect_keyword("exists")?;
+        }
+        let name = self.parse_name()?;
+
+        let timing = if self.next_keyword_is("before") {
+            self.advance();
+            TriggerTiming::Before
+        } else if self.next_keyword_is("after") {
+            self.advance();
+            TriggerTiming::After
+        } else if self.next_keyword_is("instead") {
+            self.advance();
+            self.expect_keyword("of")?;
+            TriggerTiming::InsteadOf
+        } else {
+            TriggerTiming::default()
+        };
+
+        let event = match self.expect_identifier()? {
+            "insert" => TriggerEvent::Insert,
+            "delete" => TriggerEvent::Delete,
+            "update" => {
+                let mut columns = Vec::new();
+                if self.next_keyword_is("of") {
+                    self.advance();
+                    columns.push(self.parse_name()?);
+                    while self.next_token_is(Token::Comma) {
+                        self.advance();
+                        columns.push(self.parse_name()?);
+                    }
+                }
+                TriggerEvent::Update(columns)
+            }
+            other => bail!("unexpected trigger event: {other}"),
+        };
+
+        self.expect_keyword("on")?;
+        let table = self.parse_name()?;
+        if self.next_keyword_is("for") {
+            self.advance();
+            self.expect_keyword("each")?;
+            self.expect_keyword("row")?;
+        }
+
+        Ok(CreateTriggerStatement {
+            name,
+            timing,
+            event,
+            table,
+        })
+    }
+
+    /// Parses the WHEN clause and the body of a CREATE TRIGGER statement, following
+    /// its head.
+    fn parse_trigger_program(&mut self) -> anyhow::Result<TriggerProgram> {
+        let when = if self.next_keyword_is("when") {
+            self.advance();
+            Some(self.parse_expr()?)
+        } else {
+            None
+        };
+        self.expect_keyword("begin")?;
+        let mut statements = Vec::new();
+        loop {
+            let statement = self.parse_statement()?;
+            ensure!(
+                matches!(
+                    statement,
+                    Statement::Insert(_)
+                        | Statement::Update(_)
+                        | Statement::Delete(_)
+                        | Statement::Select(_)
+                ),
+                "unexpected statement in trigger body"
+            );
+            statements.push(statement);
+            self.expect_eq(Token::SemiColon)?;
+            if self.next_keyword_is("end") {
+                self.advance();
+                return Ok(TriggerProgram { when, statements });
+            }
+        }
+    }
+
+    fn parse_drop(&mut self) -> anyhow::Result<DropStatement> {
+        self.expect_keyword("drop")?;
+        let kind = match self.next_token() {
+            Some(Token::Table) => SchemaObjectKind::Table,
+            Some(Token::Identifier(kind)) if kind == "index" => SchemaObjectKind::Index,
+            Some(Token::Identifier(kind)) if kind == "view" => SchemaObjectKind::View,
+            Some(Token::Identifier(kind)) if kind == "trigger" => SchemaObjectKind::Trigger,
+            Some(token) => bail!("unexpected token: {token:?}"),
+            None => bail!("unexpected end of input"),
+        };
+        let if_exists = self.next_keyword_is("if");
+        if if_exists {
+            self.advance();
+            self.expect_keyword("exists")?;
+        }
+        let name = self.parse_name()?;
+        Ok(DropStatement {
+            kind,
+            name,
+            if_exists,
+        })
+    }
+
+    fn parse_alter_table(&mut self) -> anyhow::Result<AlterTableStatement> {
+        self.expect_keyword("alter")?;
+        self.expect_eq(Token::Table)?;
+        let table = self.parse_name()?;
+        self.expect_keyword("drop")?;
+        if self.next_keyword_is("column") {
+            self.advance();
+        }
+        let column = self.parse_name()?;
+        Ok(AlterTableStatement {
+            table,
+            action: AlterTableAction::DropColumn(column),
+        })
+    }
+
+    fn parse_pragma(&mut self) -> anyhow::Result<PragmaStatement> {
+        self.expect_keyword("pragma")?;
+        let mut name = self.parse_name()?;
+        if self.next_token_is(Token::Dot) {
+            self.advance();
+            ensure!(
+                name.eq_ignore_ascii_case("main"),
+                "unknown database: {name}"
+            );
+            name = self.parse_name()?;
+        }
+
+        let arg = if self.next_token_is(Token::LPar) {
+            self.advance();
+            let arg = self.parse_pragma_arg()?;
+            self.expect_eq(Token::RPar)?;
+            Some(arg)
+        } else if self.next_token_is(Token::Eq) {
+            self.advance();
+            Some(self.parse_pragma_arg()?)
+        } else {
+            None
+        };
+        Ok(PragmaStatement {
+            name: name.to_lowercase(),
+            arg,
+        })
+    }
+
+    fn parse_pragma_arg(&mut self) -> anyhow::Result<PragmaArg> {
+        let negative = self.next_token_is(Token::Minus);
+        if negative {
+            self.advance();
+        }
+        match self.peek_next_token()? {
+            Token::Integer(n) => {
+                let n = *n;
+                self.advance();
+                Ok(PragmaArg::Integer(if negative { -n } else { n }))
+            }
+            _ if !negative => Ok(PragmaArg::Name(self.parse_name()?)),
+            token => bail!("unexpected token: {token:?}"),
+        }
+    }
+
+    fn parse_insert(&mut self) -> anyhow::Result<InsertStatement> {
+        let on_conflict = if self.next_keyword_is("replace") {
+            self.advance();
+            Some(ConflictResolution::Replace)
+        } else {
+            self.expect_keyword("insert")?;
+            self.parse_conflict_resolution()?
+        };
+        self.expect_keyword("into")?;
+        let table = self.parse_name()?;
+
+        let mut columns = Vec::new();
+        if self.next_token_is(Token::LPar) {
+            self.advance();
+            columns.push(self.parse_name()?);
+            while self.next_token_is(Token::Comma) {
+                self.advance();
+                columns.push(self.parse_name()?);
+            }
+            self.expect_eq(Token::RPar)?;
+        }
+
+        let rows = self.parse_values()?;
+        Ok(InsertStatement {
+            on_conflict,
+            table,
+            columns,
+            rows,
+        })
+    }
+
+    fn parse_update(&mut self) -> anyhow::Result<UpdateStatement> {
+        self.expect_keyword("update")?;
+        let on_conflict = self.parse_conflict_resolution()?;
+        let table = self.parse_name()?;
+        self.expect_keyword("set")?;
+        let mut assignments = Vec::new();
+        loop {
+            let column = self.parse_name()?;
+            self.expect_eq(Token::Eq)?;
+            assignments.push((column, self.parse_expr()?));
+            if !self.next_token_is(Token::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        Ok(UpdateStatement {
+            on_conflict,
+            table,
+            assignments,
+            where_clause: self.parse_where()?,
+        })
+    }
+
+    fn parse_delete(&mut self) -> anyhow::Result<DeleteStatement> {
+        self.expect_keyword("delete")?;
+        self.expect_eq(Token::From)?;
+        let table = self.parse_name()?;
+        Ok(DeleteStatement {
+            table,
+            where_clause: self.parse_where()?,
+        })
+    }
+
+    fn parse_where(&mut self) -> anyhow::Result<Option<Expr>> {
+        if !self.next_token_is(Token::Where) {
+            return Ok(None);
+        }
+        self.advance();
+        self.parse_expr().map(Some)
+    }
+
+    /// Parses `VALUES (...), ...`, whose rows must all have the same number of terms.
+    fn parse_values(&mut self) -> anyhow::Result<Vec<Vec<Expr>>> {
+        self.expect_keyword("values")?;
+        let mut rows = vec![self.parse_function_args()?];
+        while self.next_token_is(Token::Comma) {
+            self.advance();
+            rows.push(self.parse_function_args()?);
+        }
+        if let Some(row) = rows.iter().find(|row| row.len() != rows[0].len()) {
+            bail!(
+                "all VALUES must have the same number of terms: {} and {}",
+                rows[0].len(),
+                row.len()
+            );
+        }
+        Ok(rows)
+    }
+
+    /// A VALUES statement is a `SELECT * FROM (VALUES ...)`.
+    fn parse_values_statement(&mut self) -> anyhow::Result<SelectStatement> {
+        let rows = self.parse_values()?;
+        Ok(SelectStatement {
+            core: SelectCore {
+                distinct: false,
+                result_columns: vec![ResultColumn::Star],
+                from: SelectFrom::Values { rows, alias: None },
+                where_clause: None,
+                group_by: Vec::new(),
+                having: None,
+            },
+            order_by: Vec::new(),
+            limit: None,
+            offset: None,
+        })
+    }
+
+    fn parse_conflict_resolution(&mut self) -> anyhow::Result<Option<ConflictResolution>> {
+        if !self.next_token_is(Token::Or) {
+            return Ok(None);
+        }
+        self.advance();
+        self.parse_conflict_resolution_keyword().map(Some)
+    }
+
+    fn parse_conflict_resolution_keyword(&mut self) -> anyhow::Result<ConflictResolution> {
+        let resolution = match self.expect_identifier()? {
+            "abort" => ConflictResolution::Abort,
+            "fail" => ConflictResolution::Fail,
+            "ignore" => ConflictResolution::Ignore,
+            "replace" => ConflictResolution::Replace,
+            "rollback" => ConflictResolution::Rollback,
+            other => bail!("unexpected conflict resolution: {other}"),
+        };
+        Ok(resolution)
     }
 
     fn parse_column_def(&mut self) -> anyhow::Result<ColumnDef> {
+        let name = self.parse_name()?;
+
+        let col_type = match self.peek_next_token()? {
+            Token::Identifier(_) | Token::QuotedIdentifier(_)
+                if !self.next_is_column_constraint() =>
+            {
+                Some(self.parse_type_name()?)
+            }
+            _ => None,
+        };
+
+        let mut constraints = Vec::new();
+        while self.next_is_column_constraint() {
+            let name = self.parse_constraint_name()?;
+            let constraint = if self.next_keyword_is("check") {
+                let expr = self.parse_check()?;
+                ColumnConstraint::Check(Check { name, expr })
+            } else if self.next_keyword_is("not") {
+                self.advance();
+                self.expect_keyword("null")?;
+                ColumnConstraint::NotNull(self.parse_conflict_clause()?)
+            } else if self.next_keyword_is("unique") {
+                self.advance();
+                ColumnConstraint::Unique(self.parse_conflict_clause()?)
+            } else if self.next_keyword_is("default") {
+                self.advance();
+                // A parenthesized expression or a literal, possibly signed.
+                ColumnConstraint::Default(self.parse_primary_expr()?)
+            } else if self.next_keyword_is("collate") {
+                self.advance();
+                ColumnConstraint::Collate(self.parse_name()?)
+            } else if self.next_keyword_is("generated") || self.next_token_is(Token::As) {
+                self.parse_generated()?
+            } else {
+                self.expect_keyword("primary")?;
+                self.expect_keyword("key")?;
+                if self.next_keyword_is("asc") || self.next_keyword_is("desc") {
+                    self.advance();
+                }
+                let on_conflict = self.parse_conflict_clause()?;
+                if self.next_keyword_is("autoincrement") {
+                    self.advance();
+                }
+                ColumnConstraint::PrimaryKey(on_conflict)
+            };
+            constraints.push(constraint);
+        }
+
         Ok(ColumnDef {
-            name: self.expect_identifier()?.to_string(),
-            col_type: self.parse_type()?,
+            name,
+            col_type,
+            constraints,
         })
     }
 
-    fn parse_type(&mut self) -> anyhow::Result<Type> {
-        let type_name = self.expect_identifier()?;
-        let t = match type_name.to_lowercase().as_str() {
-            "integer" => Type::Integer,
-            "real" => Type::Real,
-            "blob" => Type::Blob,
-            "text" | "string" => Type::Text,
-            _ => bail!("unsupported type: {type_name}"),
-        };
-        Ok(t)
+    /// Parses `[GENERATED ALWAYS] AS (expr) [VIRTUAL | STORED]`.
+    fn parse_generated(&mut self) -> anyhow::Result<ColumnConstraint> {
+        if self.next_keyword_is("generated") {
+            self.advance();
+            self.expect_keyword("always")?;
+        }
+        self.expect_eq(Token::As)?;
+        self.expect_eq(Token::LPar)?;
+        let expr = self.parse_expr()?;
+        self.expect_eq(Token::RPar)?;
+
+        let stored = self.next_keyword_is("stored");
+        if stored || self.next_keyword_is("virtual") {
+            self.advance();
+        }
+        Ok(ColumnConstraint::Generated { expr, stored })
+    }
+
+    fn next_is_column_constraint(&self) -> bool {
+        self.next_is_constraint()
+            || self.next_keyword_is("collate")
+            || self.next_keyword_is("generated")
+            || self.next_token_is(Token::As)
+    }
+
+    fn next_is_constraint(&self) -> bool {
+        ["constraint", "primary", "not", "unique", "check", "default"]
+            .iter()
+            .any(|k| self.next_keyword_is(k))
+    }
+
+    fn parse_constraint_name(&mut self) -> anyhow::Result<Option<String>> {
+        if !self.next_keyword_is("constraint") {
+            return Ok(None);
+        }
+        self.advance();
+        self.parse_name().map(Some)
+    }
+
+    /// The `ON CONFLICT` clause of a constraint, applying unless the statement has an
+    /// `OR ...` clause.
+    fn parse_conflict_clause(&mut self) -> anyhow::Result<Option<ConflictResolution>> {
+        if !self.next_keyword_is("on") {
+            return Ok(None);
+        }
+        self.advance();
+        self.expect_keyword("conflict")?;
+        self.parse_conflict_resolution_keyword().map(Some)
+    }
+
+    fn parse_check(&mut self) -> anyhow::Result<Expr> {
+        self.expect_keyword("check")?;
+        self.expect_eq(Token::LPar)?;
+        let expr = self.parse_expr()?;
+        self.expect_eq(Token::RPar)?;
+        Ok(expr)
+    }
+
+    fn parse_table_constraint(&mut self) -> anyhow::Result<TableConstraint> {
+        let name = self.parse_constraint_name()?;
+        if self.next_keyword_is("check") {
+            let expr = self.parse_check()?;
+            return Ok(TableConstraint::Check(Check { name, expr }));
+        }
+
+        let unique = self.next_keyword_is("unique");
+        if unique {
+            self.advance();
+        } else {
+            self.expect_keyword("primary")?;
+            self.expect_keyword("key")?;
+        }
+
+        self.expect_eq(Token::LPar)?;
+        let mut columns = vec![self.parse_name()?];
+        while self.next_token_is(Token::Comma) {
+            self.advance();
+            columns.push(self.parse_name()?);
+        }
+        self.expect_eq(Token::RPar)?;
+        let on_conflict = self.parse_conflict_clause()?;
+
+        if unique {
+            Ok(TableConstraint::Unique {
+                columns,
+                on_conflict,
+            })
+        } else {
+            Ok(TableConstraint::PrimaryKey {
+                columns,
+                on_conflict,
+            })
+        }
+    }
+
+    fn parse_name(&mut self) -> anyhow::Result<String> {
+        match self.peek_next_token()? {
+            Token::StringLiteral(_) => Ok(self.expect_string_literal()?.to_string()),
+            _ => Ok(self.expect_identifier()?.to_string()),
+        }
     }
 
     fn parse_select(&mut self) -> anyhow::Result<SelectStatement> {
         self.expect_eq(Token::Select)?;
+        let distinct = self.next_keyword_is("distinct");
+        if distinct || self.next_keyword_is("all") {
+            self.advance();
+        }
         let result_columns = self.parse_result_columns()?;
-        self.expect_eq(Token::From)?;
-        let from = self.parse_select_from()?;
+        // Without FROM, the result columns are computed once, as from a row without
+        // columns.
+        let from = if self.next_token_is(Token::From) {
+            self.advance();
+            self.parse_select_from()?
+        } else {
+            SelectFrom::Values {
+                rows: vec![Vec::new()],
+                alias: None,
+            }
+        };
+        let where_clause = self.parse_where()?;
+        let group_by = if self.next_keyword_is("group") {
+            self.advance();
+            self.expect_keyword("by")?;
+            self.parse_exprs()?
+        } else {
+            Vec::new()
+        };
+        let having = if self.next_keyword_is("having") {
+            self.advance();
+            Some(self.parse_expr()?)
+        } else {
+            None
+        };
+        let order_by = if self.next_keyword_is("order") {
+            self.advance();
+            self.expect_keyword("by")?;
+            self.parse_ordering_terms()?
+        } else {
+            Vec::new()
+        };
+        let (limit, offset) = self.parse_limit()?;
         Ok(SelectStatement {
             core: SelectCore {
+                distinct,
                 result_columns,
                 from,
+                where_clause,
+                group_by,
+                having,
             },
+            order_by,
+            limit,
+            offset,
         })
     }
 
+    fn parse_exprs(&mut self) -> anyhow::Result<Vec<Expr>> {
+        let mut exprs = vec![self.parse_expr()?];
+        while self.next_token_is(Token::Comma) {
+            self.advance();
+            exprs.push(self.parse_expr()?);
+        }
+        Ok(exprs)
+    }
+
+    /// Parses `LIMIT n`, `LIMIT n OFFSET m`, or its legacy form `LIMIT m, n`.
+    fn parse_limit(&mut self) -> anyhow::Result<(Option<Expr>, Option<Expr>)> {
+        if !self.next_keyword_is("limit") {
+            return Ok((None, None));
+        }
+        self.advance();
+
+        let limit = self.parse_expr()?;
+        if self.next_keyword_is("offset") {
+            self.advance();
+            Ok((Some(limit), Some(self.parse_expr()?)))
+        } else if self.next_token_is(Token::Comma) {
+            self.advance();
+            Ok((Some(self.parse_expr()?), Some(limit)))
+        } else {
+            Ok((Some(limit), None))
+        }
+    }
+
+    fn parse_ordering_terms(&mut self) -> anyhow::Result<Vec<OrderingTerm>> {
+        let mut terms = Vec::new();
+        loop {
+            let expr = self.parse_expr()?;
+            let descending = self.next_keyword_is("desc");
+            if descending || self.next_keyword_is("asc") {
+                self.advance();
+            }
+            terms.push(OrderingTerm { expr, descending });
+
+            if !self.next_token_is(Token::Comma) {
+                return Ok(terms);
+            }
+            self.advance();
+        }
+    }
+
+    /// Parses the FROM clause, joins nesting to the left.
     fn parse_select_from(&mut self) -> anyhow::Result<SelectFrom> {
-        let table = self.expect_identifier()?;
-        Ok(SelectFrom::Table(table.to_string()))
+        let mut from = self.parse_table_or_function()?;
+        loop {
+            let mut kind = JoinKind::Inner;
+            if self.next_token_is(Token::Comma) {
+                self.advance();
+            } else if self.next_keyword_is("inner") || self.next_keyword_is("cross") {
+                self.advance();
+                self.expect_keyword("join")?;
+            } else if self.next_keyword_is("left") {
+                self.advance();
+                if self.next_keyword_is("outer") {
+                    self.advance();
+                }
+                self.expect_keyword("join")?;
+                kind = JoinKind::Left;
+            } else if self.next_keyword_is("join") {
+                self.advance();
+            } else {
+                return Ok(from);
+            }
+
+            let right = self.parse_table_or_function()?;
+            let constraint = if self.next_keyword_is("on") {
+                self.advance();
+                Some(self.parse_expr()?)
+            } else {
+                None
+            };
+            from = SelectFrom::Join(Box::new(Join {
+                kind,
+                left: from,
+                right,
+                constraint,
+            }));
+        }
+    }
+
+    fn parse_table_or_function(&mut self) -> anyhow::Result<SelectFrom> {
+        if self.next_token_is(Token::LPar) {
+            self.advance();
+            let rows = self.parse_values()?;
+            self.expect_eq(Token::RPar)?;
+            let alias = self.parse_table_alias()?;
+            return Ok(SelectFrom::Values { rows, alias });
+        }
+
+        let name = self.expect_identifier()?.to_string();
+        if self.next_token_is(Token::LPar) {
+            let (args, star) = self.parse_call_args()?;
+            return Ok(SelectFrom::Function(FunctionCall { name, args, star }));
+        }
+
+        let alias = self.parse_table_alias()?;
+        Ok(SelectFrom::Table { name, alias })
+    }
+
+    fn parse_table_alias(&mut self) -> anyhow::Result<Option<String>> {
+        Ok(if self.next_token_is(Token::As) {
+            self.advance();
+            Some(self.expect_identifier()?.to_string())
+        } else if self.next_is_table_alias() {
+            Some(self.expect_identifier()?.to_string())
+        } else {
+            None
+        })
+    }
+
+    /// Whether the next token is an alias given without AS, rather than a keyword
+    /// continuing the statement.
+    fn next_is_table_alias(&self) -> bool {
+        const KEYWORDS: &[&str] = &[
+            "join", "inner", "cross", "left", "on", "group", "having", "order", "limit",
+        ];
+        let Some(token) = self.tokens.get(self.pos) else {
+            return false;
+        };
+        token.as_identifier().is_some()
+            && !token
+                .as_keyword()
+                .is_some_and(|keyword| KEYWORDS.contains(&keyword))
     }
 
     fn parse_result_columns(&mut self) -> anyhow::Result<Vec<ResultColumn>> {
@@ -92,6 +818,14 @@ impl ParserState {
             self.advance();
             return Ok(ResultColumn::Star);
         }
+        if self.peek_next_token()?.as_identifier().is_some()
+            && self.tokens.get(self.pos + 1) == Some(&Token::Dot)
+            && self.tokens.get(self.pos + 2) == Some(&Token::Star)
+        {
+            let table = self.expect_identifier()?.to_string();
+            self.pos += 2;
+            return Ok(ResultColumn::TableStar(table));
+        }
 
         Ok(ResultColumn::Expr(self.parse_expr_result_column()?))
     }
@@ -108,39 +842,412 @@ impl ParserState {
     }
 
     fn parse_expr(&mut self) -> anyhow::Result<Expr> {
-        Ok(Expr::Column(Column {
-            name: self.expect_identifier()?.to_string(),
-        }))
+        self.parse_binary_expr(0)
     }
 
-    fn next_token_is(&self, expected: Token) -> bool {
-        self.tokens.get(self.pos) == Some(&expected)
-    }
+    fn parse_binary_expr(&mut self, min_precedence: u8) -> anyhow::Result<Expr> {
+        let mut lhs = self.parse_primary_expr()?;
+        // COLLATE binds tighter than any binary operator.
+        while self.next_keyword_is("collate") {
+            self.advance();
+            lhs = Expr::Collate(CollateExpr {
+                expr: Box::new(lhs),
+                collation: self.parse_name()?,
+            });
+        }
 
-    fn expect_identifier(&mut self) -> anyhow::Result<&str> {
-        self.expect_matching(|t| matches!(t, Token::Identifier(_)))
-            .map(|t| t.as_identifier().unwrap())
-    }
+        loop {
+            if BETWEEN_PRECEDENCE >= min_precedence
+                && let Some(negated) = self.peek_negatable("between")
+            {
+                self.pos += if negated { 2 } else { 1 };
+                // The bounds bind tighter than BETWEEN, so the AND separating them
+                // isn't taken as a conjunction.
+                let low = self.parse_binary_expr(BETWEEN_PRECEDENCE + 1)?;
+                self.expect_eq(Token::And)?;
+                let high = self.parse_binary_expr(BETWEEN_PRECEDENCE + 1)?;
+                lhs = Expr::Between(BetweenExpr {
+                    expr: Box::new(lhs),
+                    low: Box::new(low),
+                    high: Box::new(high),
+                    negated,
+                });
+                continue;
+            }
 
-    fn expect_eq(&mut self, expected: Token) -> anyhow::Result<&Token> {
-        self.expect_matching(|t| *t == expected)
-    }
+            if LIKE_PRECEDENCE >= min_precedence
+                && let Some(negated) = self.peek_negatable("like")
+            {
+                self.pos += if negated { 2 } else { 1 };
+                let pattern = self.parse_binary_expr(LIKE_PRECEDENCE + 1)?;
+                let escape = if self.next_keyword_is("escape") {
+                    self.advance();
+                    Some(Box::new(self.parse_binary_expr(LIKE_PRECEDENCE + 1)?))
+                } else {
+                    None
+                };
+                lhs = Expr::Like(LikeExpr {
+                    expr: Box::new(lhs),
+                    pattern: Box::new(pattern),
+                    escape,
+                    negated,
+                });
+                continue;
+            }
 
-    fn expect_matching(&mut self, f: impl Fn(&Token) -> bool) -> anyhow::Result<&Token> {
-        match self.next_token() {
-            Some(token) if f(token) => Ok(token),
-            Some(token) => bail!("unexpected token: {:?}", token),
-            None => bail!("unexpected end of input"),
+            if IS_PRECEDENCE >= min_precedence
+                && let Some((op, width)) = self.peek_null_test()
+            {
+                self.pos += width;
+                lhs = Expr::Binary(BinaryExpr {
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(Expr::Literal(Literal::Null)),
+                });
+                continue;
+            }
+
+            let Some((op, width)) = self.peek_binary_operator() else {
+                break;
+            };
+            if precedence(op) < min_precedence {
+                break;
+            }
+            self.pos += width;
+            let rhs = self.parse_binary_expr(precedence(op) + 1)?;
+            lhs = Expr::Binary(BinaryExpr {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            });
         }
-    }
 
-    fn peek_next_token(&self) -> anyhow::Result<&Token> {
-        self.tokens.get(self.pos).context("unexpected end of input")
+        Ok(lhs)
     }
 
-    fn next_token(&mut self) -> Option<&Token> {
-        let token = self.tokens.get(self.pos);
-        if token.is_some() {
+    /// Parses the `OVER (...)` clause of a window function call. Only the default
+    /// frame is supported.
+    fn parse_window(&mut self, function: FunctionCall) -> anyhow::Result<Expr> {
+        self.expect_keyword("over")?;
+        self.expect_eq(Token::LPar)?;
+
+        let partition_by = if self.next_keyword_is("partition") {
+            self.advance();
+            self.expect_keyword("by")?;
+            self.parse_exprs()?
+        } else {
+            Vec::new()
+        };
+        let order_by = if self.next_keyword_is("order") {
+            self.advance();
+            self.expect_keyword("by")?;
+            self.parse_ordering_terms()?
+        } else {
+            Vec::new()
+        };
+        if ["range", "rows", "groups"]
+            .iter()
+            .any(|frame| self.next_keyword_is(frame))
+        {
+            bail!("unsupported window frame");
+        }
+        self.expect_eq(Token::RPar)?;
+
+        Ok(Expr::Window(WindowCall {
+            function,
+            partition_by,
+            order_by,
+        }))
+    }
+
+    fn parse_cast(&mut self) -> anyhow::Result<Expr> {
+        self.expect_eq(Token::LPar)?;
+        let expr = self.parse_expr()?;
+        self.expect_eq(Token::As)?;
+        let type_name = self.parse_type_name()?;
+        self.expect_eq(Token::RPar)?;
+        Ok(Expr::Cast(CastExpr {
+            expr: Box::new(expr),
+            type_name,
+        }))
+    }
+
+    /// A type name as SQLite accepts it: one or more words, optionally followed by
+    /// one or two sizes, e.g. `UNSIGNED BIG INT` or `DECIMAL(10, 5)`.
+    fn parse_type_name(&mut self) -> anyhow::Result<String> {
+        let mut type_name = self.expect_identifier()?.to_string();
+        while let Some(word) = self.tokens.get(self.pos).and_then(Token::as_identifier) {
+            // Column constraints follow the type of a column definition.
+            let constraint = ["null", "collate", "references", "generated"]
+                .iter()
+                .any(|k| self.next_keyword_is(k));
+            if constraint || self.next_is_constraint() {
+                break;
+            }
+            type_name.push(' ');
+            type_name.push_str(word);
+            self.advance();
+        }
+
+        if self.next_token_is(Token::LPar) {
+            self.advance();
+            let mut sizes = Vec::new();
+            loop {
+                let negative = self.next_token_is(Token::Minus);
+                if negative {
+                    self.advance();
+                }
+                sizes.push(self.parse_number(negative)?.to_string());
+                if !self.next_token_is(Token::Comma) {
+                    break;
+                }
+                self.advance();
+            }
+            self.expect_eq(Token::RPar)?;
+            type_name.push_str(&format!("({})", sizes.join(", ")));
+        }
+
+        Ok(type_name)
+    }
+
+    fn parse_primary_expr(&mut self) -> anyhow::Result<Expr> {
+        match self.peek_next_token()? {
+            Token::StringLiteral(_) => {
+                let literal = self.expect_string_literal()?.to_string();
+                Ok(Expr::Literal(Literal::String(literal)))
+            }
+            Token::Integer(_) | Token::Real(_) => self.parse_number(false),
+            Token::Parameter(_) => self.parse_parameter(),
+            Token::Minus => {
+                self.advance();
+                self.parse_number(true)
+            }
+            Token::LPar => {
+                self.advance();
+                let expr = self.parse_expr()?;
+                self.expect_eq(Token::RPar)?;
+                Ok(expr)
+            }
+            Token::Identifier(ident) if ident == "null" => {
+                self.advance();
+                Ok(Expr::Literal(Literal::Null))
+            }
+            Token::Identifier(ident) if ident == "not" => {
+                self.advance();
+                let expr = self.parse_binary_expr(NOT_PRECEDENCE)?;
+                Ok(Expr::Unary(UnaryExpr {
+                    op: UnaryOperator::Not,
+                    expr: Box::new(expr),
+                }))
+            }
+            Token::Identifier(_) | Token::QuotedIdentifier(_) => {
+                let quoted = matches!(self.peek_next_token()?, Token::QuotedIdentifier(_));
+                let name = self.expect_identifier()?.to_string();
+                if !quoted && name == "cast" && self.next_token_is(Token::LPar) {
+                    return self.parse_cast();
+                }
+                if self.next_token_is(Token::LPar) {
+                    let (args, star) = self.parse_call_args()?;
+                    let call = FunctionCall { name, args, star };
+                    if self.next_keyword_is("over") {
+                        return self.parse_window(call);
+                    }
+                    return Ok(Expr::Function(call));
+                }
+                if self.next_token_is(Token::Dot) {
+                    self.advance();
+                    let column = self.expect_identifier()?.to_string();
+                    return Ok(Expr::Column(Column {
+                        table: Some(name),
+                        name: column,
+                    }));
+                }
+                Ok(Expr::Column(Column { table: None, name }))
+            }
+            token => bail!("unexpected token: {token:?}"),
+        }
+    }
+
+    fn parse_number(&mut self, negative: bool) -> anyhow::Result<Expr> {
+        let literal = match self.next_token() {
+            Some(Token::Integer(i)) if negative => Literal::Integer(-i),
+            Some(Token::Integer(i)) => Literal::Integer(*i),
+            Some(Token::Real(r)) if negative => Literal::Real(-r),
+            Some(Token::Real(r)) => Literal::Real(*r),
+            Some(token) => bail!("unexpected token: {token:?}"),
+            None => bail!("unexpected end of input"),
+        };
+        Ok(Expr::Literal(literal))
+    }
+
+    /// Parses the arguments of a function call, and whether they were given as `*`.
+    fn parse_call_args(&mut self) -> anyhow::Result<(Vec<Expr>, bool)> {
+        if self.tokens.get(self.pos + 1) == Some(&Token::Star) {
+            self.expect_eq(Token::LPar)?;
+            self.advance();
+            self.expect_eq(Token::RPar)?;
+            return Ok((Vec::new(), true));
+        }
+        Ok((self.parse_function_args()?, false))
+    }
+
+    fn parse_function_args(&mut self) -> anyhow::Result<Vec<Expr>> {
+        self.expect_eq(Token::LPar)?;
+        let mut args = Vec::new();
+        if !self.next_token_is(Token::RPar) {
+            args.push(self.parse_expr()?);
+            while self.next_token_is(Token::Comma) {
+                self.advance();
+                args.push(self.parse_expr()?);
+            }
+        }
+        self.expect_eq(Token::RPar)?;
+        Ok(args)
+    }
+
+    /// The binary operator coming next, and the number of tokens spelling it.
+    fn peek_binary_operator(&self) -> Option<(BinaryOperator, usize)> {
+        if self.next_keyword_is("is") {
+            return Some(match self.nth_keyword_is(1, "not") {
+                true => (BinaryOperator::IsNot, 2),
+                false => (BinaryOperator::Is, 1),
+            });
+        }
+
+        let op = match self.tokens.get(self.pos)? {
+            Token::Arrow => BinaryOperator::Arrow,
+            Token::LongArrow => BinaryOperator::LongArrow,
+            Token::Concat => BinaryOperator::Concat,
+            Token::Match => BinaryOperator::Match,
+            Token::And => BinaryOperator::And,
+            Token::Or => BinaryOperator::Or,
+            Token::Eq => BinaryOperator::Eq,
+            Token::NotEq => BinaryOperator::NotEq,
+            Token::Lt => BinaryOperator::Lt,
+            Token::LtEq => BinaryOperator::LtEq,
+            Token::Gt => BinaryOperator::Gt,
+            Token::GtEq => BinaryOperator::GtEq,
+            _ => return None,
+        };
+        Some((op, 1))
+    }
+
+    /// Whether `[NOT] BETWEEN` comes next, and if so whether it is negated.
+    fn peek_negatable(&self, keyword: &str) -> Option<bool> {
+        if self.next_keyword_is(keyword) {
+            Some(false)
+        } else if self.next_keyword_is("not") && self.nth_keyword_is(1, keyword) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    /// The postfix `ISNULL`, `NOTNULL` or `NOT NULL` coming next, as the operator
+    /// comparing with NULL, and the number of tokens spelling it.
+    fn peek_null_test(&self) -> Option<(BinaryOperator, usize)> {
+        if self.next_keyword_is("isnull") {
+            Some((BinaryOperator::Is, 1))
+        } else if self.next_keyword_is("notnull") {
+            Some((BinaryOperator::IsNot, 1))
+        } else if self.next_keyword_is("not") && self.nth_keyword_is(1, "null") {
+            Some((BinaryOperator::IsNot, 2))
+        } else {
+            None
+        }
+    }
+
+    fn next_token_is(&self, expected: Token) -> bool {
+        self.tokens.get(self.pos) == Some(&expected)
+    }
+
+    /// Keywords that SQLite allows as identifiers are matched contextually.
+    fn next_keyword_is(&self, keyword: &str) -> bool {
+        self.nth_keyword_is(0, keyword)
+    }
+
+    /// Whether the `n`th token after the next one is `keyword`.
+    fn nth_keyword_is(&self, n: usize, keyword: &str) -> bool {
+        self.tokens
+            .get(self.pos + n)
+            .and_then(Token::as_keyword)
+            .is_some_and(|ident| ident == keyword)
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> anyhow::Result<()> {
+        self.expect_matching(|t| t.as_keyword() == Some(keyword))?;
+        Ok(())
+    }
+
+    fn expect_identifier(&mut self) -> anyhow::Result<&str> {
+        self.expect_matching(|t| t.as_identifier().is_some())
+            .map(|t| t.as_identifier().unwrap())
+    }
+
+    fn parse_parameter(&mut self) -> anyhow::Result<Expr> {
+        let Some(Token::Parameter(name)) = self.next_token() else {
+            bail!("expected a parameter");
+        };
+        let name = name.clone();
+
+        let index = match name.strip_prefix('?') {
+            Some("") => {
+                self.last_parameter += 1;
+                return Ok(Expr::Parameter(Parameter {
+                    index: self.last_parameter,
+                    name: None,
+                }));
+            }
+            Some(digits) => {
+                let index = digits.parse().unwrap_or(0);
+                ensure!(
+                    (1..=MAX_PARAMETER_INDEX).contains(&index),
+                    "variable number must be between ?1 and ?{MAX_PARAMETER_INDEX}"
+                );
+                index
+            }
+            None => match self.parameters.get(&name) {
+                Some(&index) => index,
+                None => self.last_parameter + 1,
+            },
+        };
+        ensure!(index <= MAX_PARAMETER_INDEX, "too many SQL variables");
+
+        self.last_parameter = self.last_parameter.max(index);
+        self.parameters.insert(name.clone(), index);
+        Ok(Expr::Parameter(Parameter {
+            index,
+            name: Some(name),
+        }))
+    }
+
+    fn expect_string_literal(&mut self) -> anyhow::Result<&str> {
+        self.expect_matching(|t| matches!(t, Token::StringLiteral(_)))
+            .map(|t| match t {
+                Token::StringLiteral(s) => s.as_str(),
+                _ => unreachable!(),
+            })
+    }
+
+    fn expect_eq(&mut self, expected: Token) -> anyhow::Result<&Token> {
+        self.expect_matching(|t| *t == expected)
+    }
+
+    fn expect_matching(&mut self, f: impl Fn(&Token) -> bool) -> anyhow::Result<&Token> {
+        match self.next_token() {
+            Some(token) if f(token) => Ok(token),
+            Some(token) => bail!("unexpected token: {:?}", token),
+            None => bail!("unexpected end of input"),
+        }
+    }
+
+    fn peek_next_token(&self) -> anyhow::Result<&Token> {
+        self.tokens.get(self.pos).context("unexpected end of input")
+    }
+
+    fn next_token(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.pos);
+        if token.is_some() {
             self.pos += 1;
         }
         token
@@ -151,26 +1258,370 @@ impl ParserState {
     }
 }
 
+const NOT_PRECEDENCE: u8 = 3;
+const IS_PRECEDENCE: u8 = 4;
+const BETWEEN_PRECEDENCE: u8 = 4;
+const LIKE_PRECEDENCE: u8 = 4;
+
+fn precedence(op: BinaryOperator) -> u8 {
+    match op {
+        BinaryOperator::Or => 1,
+        BinaryOperator::And => 2,
+        BinaryOperator::Eq
+        | BinaryOperator::NotEq
+        | BinaryOperator::Is
+        | BinaryOperator::IsNot
+        | BinaryOperator::Match => 4,
+        BinaryOperator::Lt | BinaryOperator::LtEq | BinaryOperator::Gt | BinaryOperator::GtEq => 5,
+        BinaryOperator::Arrow | BinaryOperator::LongArrow | BinaryOperator::Concat => 9,
+    }
+}
+
 pub fn parse_statement(input: &str, trailing_semicolon: bool) -> anyhow::Result<Statement> {
     let tokens = tokenizer::tokenize(input)?;
     let mut state = ParserState::new(tokens);
     let statement = state.parse_statement()?;
-    if trailing_semicolon {
+    if trailing_semicolon || state.next_token_is(Token::SemiColon) {
         state.expect_eq(Token::SemiColon)?;
     }
+    if let Some(token) = state.next_token() {
+        bail!("unexpected token: {:?}", token);
+    }
     Ok(statement)
 }
 
-pub fn parse_create_statement(input: &str) -> anyhow::Result<CreateTableStatement> {
+pub fn parse_create_statement(input: &str) -> anyhow::Result<Statement> {
+    let words: Vec<_> = input.split_whitespace().take(3).collect();
+    if words.len() == 3 && words[1].eq_ignore_ascii_case("virtual") {
+        return parse_create_virtual_table(input).map(Statement::CreateVirtualTable);
+    }
+    if words.len() >= 2
+        && (words[1].eq_ignore_ascii_case("index") || words[1].eq_ignore_ascii_case("unique"))
+    {
+        let mut state = ParserState::new(tokenizer::tokenize(input)?);
+        return state.parse_create_index().map(Statement::CreateIndex);
+    }
+    if words
+        .iter()
+        .skip(1)
+        .any(|w| w.eq_ignore_ascii_case("trigger"))
+    {
+        let end = trigger_program_start(input)?;
+        let mut state = ParserState::new(tokenizer::tokenize(&input[..end])?);
+        return state.parse_create_trigger().map(Statement::CreateTrigger);
+    }
+
     match parse_statement(input, false)? {
-        Statement::CreateTable(c) => Ok(c),
-        Statement::Select(_) => bail!("expected a create statement"),
+        statement @ Statement::CreateTable(_) => Ok(statement),
+        _ => bail!("expected a create statement"),
+    }
+}
+
+/// Module arguments are passed verbatim to the module, so they are split from the raw
+/// statement text instead of being tokenized.
+fn parse_create_virtual_table(input: &str) -> anyhow::Result<CreateVirtualTableStatement> {
+    let (head, args) = match input.find('(') {
+        Some(start) => {
+            let end = input.rfind(')').context("unterminated module arguments")?;
+            (&input[..start], split_module_args(&input[start + 1..end]))
+        }
+        None => (input, Vec::new()),
+    };
+
+    let mut state = ParserState::new(tokenizer::tokenize(head)?);
+    state.parse_create_virtual_table(args)
+}
+
+/// Byte offset of the first occurrence of `keyword` as a whole word, ignoring case.
+/// Parses the WHEN clause and the body of the CREATE TRIGGER statement `input`, which
+/// `parse_create_statement` leaves out.
+pub fn parse_trigger_program(input: &str) -> anyhow::Result<TriggerProgram> {
+    let start = trigger_program_start(input)?;
+    let mut state = ParserState::new(tokenizer::tokenize(&input[start..])?);
+    let program = state.parse_trigger_program()?;
+    if state.next_token_is(Token::SemiColon) {
+        state.advance();
+    }
+    if let Some(token) = state.next_token() {
+        bail!("unexpected token: {:?}", token);
+    }
+    Ok(program)
+}
+
+/// Where the head of a CREATE TRIGGER statement ends, at its WHEN clause or body.
+fn trigger_program_start(input: &str) -> anyhow::Result<usize> {
+    [find_keyword(input, "when"), find_keyword(input, "begin")]
+        .into_iter()
+        .flatten()
+        .min()
+        .context("missing trigger body")
+}
+
+fn find_keyword(input: &str, keyword: &str) -> Option<usize> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    input.char_indices().find_map(|(i, c)| {
+        let candidate = input.get(i..i + keyword.len())?;
+        let starts_word = i == 0 || !input[..i].ends_with(is_word);
+        let ends_word = !input[i + keyword.len()..].starts_with(is_word);
+        (is_word(c) && starts_word && ends_word && candidate.eq_ignore_ascii_case(keyword))
+            .then_some(i)
+    })
+}
+
+/// Splits a script into its statements, dropping `--` comments. The semicolons of
+/// trigger bodies don't end the CREATE TRIGGER statement, which ends with `END`.
+pub fn split_statements(input: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (None, '[') => quote = Some(']'),
+            (None, '-') if chars.peek() == Some(&'-') => {
+                for cc in chars.by_ref() {
+                    if cc == '\n' {
+                        break;
+                    }
+                }
+                current.push('\n');
+                continue;
+            }
+            (None, ';') if in_trigger_body(&current) => {}
+            (None, ';') => {
+                if !current.trim().is_empty() {
+                    statements.push(current.trim().to_string());
+                }
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+
+    if !current.trim().is_empty() {
+        statements.push(current.trim().to_string());
+    }
+    statements
+}
+
+fn in_trigger_body(statement: &str) -> bool {
+    let words: Vec<String> = statement
+        .split_whitespace()
+        .take(3)
+        .map(str::to_lowercase)
+        .collect();
+    let is_trigger = words.first().is_some_and(|w| w == "create")
+        && words.iter().skip(1).any(|w| w == "trigger");
+    let ends_body = statement
+        .trim_end()
+        .rsplit(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .next()
+        .is_some_and(|w| w.eq_ignore_ascii_case("end"));
+    is_trigger && !ends_body
+}
+
+fn split_module_args(input: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut quote = None;
+
+    for c in input.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (None, '[') => quote = Some(']'),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                args.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+
+    if !current.trim().is_empty() {
+        args.push(current.trim().to_string());
+    }
+    args
+}
+
+/// The SQL of a CREATE TABLE statement without the definition of its column at
+/// `column`, out of `columns`, edited like SQLite does: from the name of the column to
+/// the one of the next column, or from the comma before the last column to its end.
+pub fn drop_column_definition(sql: &str, column: usize, columns: usize) -> anyhow::Result<String> {
+    // The definitions, from their first lexeme to the comma or parenthesis ending them.
+    let mut definitions: Vec<Range<usize>> = Vec::new();
+    let mut start = None;
+    let mut depth = 0;
+    for (range, lexeme) in lex(sql)? {
+        match lexeme {
+            Lexeme::Char('(') if depth == 0 => {
+                depth = 1;
+                continue;
+            }
+            _ if depth == 0 => continue,
+            Lexeme::Char(',' | ')') if depth == 1 => {
+                definitions.push(start.take().unwrap_or(range.start)..range.start);
+                if lexeme == Lexeme::Char(')') {
+                    break;
+                }
+                continue;
+            }
+            Lexeme::Char('(') => depth += 1,
+            Lexeme::Char(')') => depth -= 1,
+            _ => {}
+        }
+        start.get_or_insert(range.start);
+    }
+
+    let removed = if column + 1 < columns && column + 1 < definitions.len() {
+        definitions[column].start..definitions[column + 1].start
+    } else if column > 0 && column + 1 == columns && column < definitions.len() {
+        definitions[column - 1].end..definitions[column].end
+    } else {
+        bail!("invalid definition of column {column} in {sql}")
+    };
+    Ok(format!("{}{}", &sql[..removed.start], &sql[removed.end..]))
+}
+
+/// The first mention of `name` in SQL text, as written with the name qualifying it if
+/// any, e.g. `new.c`. The names are those of any object, the SQL not being parsed.
+pub fn find_name(sql: &str, name: &str) -> anyhow::Result<Option<String>> {
+    let lexemes = lex(sql)?;
+    for (i, (range, lexeme)) in lexemes.iter().enumerate() {
+        let Lexeme::Name(found) = lexeme else {
+            continue;
+        };
+        if !found.eq_ignore_ascii_case(name) {
+            continue;
+        }
+        let start = match i.checked_sub(2).map(|i| (&lexemes[i], &lexemes[i + 1].1)) {
+            Some(((qualifier, Lexeme::Name(_)), Lexeme::Char('.'))) => qualifier.start,
+            _ => range.start,
+        };
+        return Ok(Some(sql[start..range.end].to_string()));
+    }
+    Ok(None)
+}
+
+/// The name of the view a CREATE VIEW statement creates, or `None` for another
+/// statement. The SELECT of a view isn't parsed.
+pub fn created_view_name(sql: &str) -> anyhow::Result<Option<String>> {
+    let lexemes: Vec<Lexeme> = lex(sql)?.into_iter().map(|(_, lexeme)| lexeme).collect();
+    let mut rest = lexemes.as_slice();
+    let mut skip = |keyword: &str| match rest {
+        [Lexeme::Name(name), tail @ ..] if name.eq_ignore_ascii_case(keyword) => {
+            rest = tail;
+            true
+        }
+        _ => false,
+    };
+    if !skip("create") {
+        return Ok(None);
+    }
+    let _ = skip("temp") || skip("temporary");
+    if !skip("view") {
+        return Ok(None);
+    }
+    let _ = skip("if") && skip("not") && skip("exists");
+    Ok(match rest {
+        [Lexeme::Name(_), Lexeme::Char('.'), Lexeme::Name(name), ..] | [Lexeme::Name(name), ..] => {
+            Some(name.clone())
+        }
+        _ => None,
+    })
+}
+
+/// A piece of SQL text, for the edits made to it without parsing it.
+#[derive(Debug, PartialEq)]
+enum Lexeme {
+    /// A word or quoted identifier, without its quotes.
+    Name(String),
+    Literal,
+    Char(char),
+}
+
+/// Splits SQL text into lexemes, leaving out its whitespace and comments.
+fn lex(sql: &str) -> anyhow::Result<Vec<(Range<usize>, Lexeme)>> {
+    let mut lexemes = Vec::new();
+    let mut chars = sql.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let lexeme = match c {
+            c if c.is_whitespace() => continue,
+            '-' if chars.next_if(|&(_, cc)| cc == '-').is_some() => {
+                while chars.next_if(|&(_, cc)| cc != '\n').is_some() {}
+                continue;
+            }
+            '/' if chars.next_if(|&(_, cc)| cc == '*').is_some() => {
+                while let Some((_, cc)) = chars.next() {
+                    if cc == '*' && chars.next_if(|&(_, cc)| cc == '/').is_some() {
+                        break;
+                    }
+                }
+                continue;
+            }
+            '\'' | '"' | '`' | '[' => {
+                let end = if c == '[' { ']' } else { c };
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        // Quotes are escaped by doubling them, but brackets aren't.
+                        Some((_, cc))
+                            if cc == end
+                                && end != ']'
+                                && chars.next_if(|&(_, cc)| cc == end).is_some() =>
+                        {
+                            text.push(cc)
+                        }
+                        Some((_, cc)) if cc == end => break,
+                        Some((_, cc)) => text.push(cc),
+                        None => bail!("unterminated quote in {sql}"),
+                    }
+                }
+                match c {
+                    '\'' => Lexeme::Literal,
+                    _ => Lexeme::Name(text),
+                }
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some((_, cc)) =
+                    chars.next_if(|&(_, cc)| cc.is_alphanumeric() || cc == '_')
+                {
+                    word.push(cc);
+                }
+                // Numbers, and blobs such as `x'00'`.
+                let blob = word.eq_ignore_ascii_case("x")
+                    && chars.peek().is_some_and(|&(_, cc)| cc == '\'');
+                if c.is_ascii_digit() || blob {
+                    Lexeme::Literal
+                } else {
+                    Lexeme::Name(word)
+                }
+            }
+            c => Lexeme::Char(c),
+        };
+        let end = chars.peek().map_or(sql.len(), |&(i, _)| i);
+        lexemes.push((start..end, lexeme));
     }
+    Ok(lexemes)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::value::{Affinity, CaseFolding, Collation};
 
     #[test]
     fn create_table() {
@@ -183,57 +1634,1353 @@ mod tests {
                 columns: vec![
                     ColumnDef {
                         name: "key".to_string(),
-                        col_type: Type::Integer,
+                        col_type: Some("integer".to_string()),
+                        constraints: vec![],
                     },
                     ColumnDef {
                         name: "value".to_string(),
-                        col_type: Type::Text,
+                        col_type: Some("text".to_string()),
+                        constraints: vec![],
                     }
-                ]
+                ],
+                constraints: vec![],
+                without_rowid: false,
             })
         )
     }
 
     #[test]
-    fn select_star_from_table() {
-        let input = "select * from table1";
-        let statement = parse_statement(input, false).unwrap();
+    fn create_table_with_type_names() {
+        let input = "create table t(a varchar(255) not null, b unsigned big int primary key, \
+                     c, d boolean default 0, e double precision, f decimal(10, 5), g string)";
+        let Statement::CreateTable(create) = parse_create_statement(input).unwrap() else {
+            panic!("expected a create table statement");
+        };
+
+        let types: Vec<_> = create
+            .columns
+            .iter()
+            .map(|c| c.col_type.as_deref())
+            .collect();
         assert_eq!(
-            statement,
-            Statement::Select(SelectStatement {
-                core: SelectCore {
-                    result_columns: vec![ResultColumn::Star],
-                    from: SelectFrom::Table("table1".to_string()),
+            types,
+            vec![
+                Some("varchar(255)"),
+                Some("unsigned big int"),
+                None,
+                Some("boolean"),
+                Some("double precision"),
+                Some("decimal(10, 5)"),
+                Some("string"),
+            ]
+        );
+        assert_eq!(
+            create.columns[0].constraints,
+            vec![ColumnConstraint::NotNull(None)]
+        );
+        assert_eq!(
+            create.columns[1].constraints,
+            vec![ColumnConstraint::PrimaryKey(None)]
+        );
+
+        let affinities: Vec<_> = create.columns.iter().map(ColumnDef::affinity).collect();
+        assert_eq!(
+            affinities,
+            vec![
+                Affinity::Text,
+                Affinity::Integer,
+                Affinity::Blob,
+                Affinity::Numeric,
+                Affinity::Real,
+                Affinity::Numeric,
+                Affinity::Numeric,
+            ]
+        );
+    }
+
+    #[test]
+    fn create_table_with_keys() {
+        let input =
+            "CREATE TABLE 'docs_idx'(segid, term, pgno, PRIMARY KEY(segid, term)) WITHOUT ROWID";
+        let Statement::CreateTable(create) = parse_create_statement(input).unwrap() else {
+            panic!("expected a create table statement");
+        };
+        assert_eq!(create.name, "docs_idx");
+        assert_eq!(create.columns.len(), 3);
+        assert_eq!(create.columns[0].col_type, None);
+        assert_eq!(
+            create.constraints,
+            vec![TableConstraint::PrimaryKey {
+                columns: vec!["segid".to_string(), "term".to_string()],
+                on_conflict: None,
+            }]
+        );
+        assert!(create.without_rowid);
+
+        let input = "CREATE TABLE 'docs_data'(id INTEGER PRIMARY KEY, block BLOB)";
+        let Statement::CreateTable(create) = parse_create_statement(input).unwrap() else {
+            panic!("expected a create table statement");
+        };
+        assert_eq!(
+            create.columns[0].constraints,
+            vec![ColumnConstraint::PrimaryKey(None)]
+        );
+    }
+
+    #[test]
+    fn create_table_with_checks() {
+        let input = "create table t(a integer check (a >= 0), b text constraint b_len \
+                     check(length(b) < 10), constraint ab check (a < 100 and b = 'x'))";
+        let Statement::CreateTable(create) = parse_create_statement(input).unwrap() else {
+            panic!("expected a create table statement");
+        };
+
+        let column = |name: &str| {
+            Box::new(Expr::Column(Column {
+                table: None,
+                name: name.to_string(),
+            }))
+        };
+        assert_eq!(create.columns[0].col_type.as_deref(), Some("integer"));
+        assert_eq!(
+            create.columns[0].constraints,
+            vec![ColumnConstraint::Check(Check {
+                name: None,
+                expr: Expr::Binary(BinaryExpr {
+                    op: BinaryOperator::GtEq,
+                    lhs: column("a"),
+                    rhs: Box::new(Expr::Literal(Literal::Integer(0))),
+                }),
+            })]
+        );
+        assert!(matches!(
+            create.columns[1].constraints[..],
+            [ColumnConstraint::Check(Check {
+                name: Some(ref name),
+                expr: Expr::Binary(BinaryExpr {
+                    op: BinaryOperator::Lt,
+                    ..
+                }),
+            })] if name == "b_len"
+
+        ));
+        assert!(matches!(
+            create.constraints[..],
+            [TableConstraint::Check(Check {
+                name: Some(ref name),
+                expr: Expr::Binary(BinaryExpr {
+                    op: BinaryOperator::And,
+                    ..
+                }),
+            })] if name == "ab"
+
+        ));
+    }
+
+    #[test]
+    fn create_table_with_defaults() {
+        let input = "create table t(a text default 'it''s' not null, b integer default -1, \
+                     c default (length('x')))";
+        let Statement::CreateTable(create) = parse_create_statement(input).unwrap() else {
+            panic!("expected a create table statement");
+        };
+
+        assert_eq!(
+            create.columns[0].constraints,
+            vec![
+                ColumnConstraint::Default(Expr::Literal(Literal::String("it's".to_string()))),
+                ColumnConstraint::NotNull(None),
+            ]
+        );
+        assert_eq!(
+            create.columns[1].constraints,
+            vec![ColumnConstraint::Default(Expr::Literal(Literal::Integer(
+                -1
+            )))]
+        );
+        assert!(matches!(
+            create.columns[2].constraints[..],
+            [ColumnConstraint::Default(Expr::Function(_))]
+        ));
+    }
+
+    #[test]
+    fn create_table_with_generated_columns() {
+        let input = "create table t(a int, b generated always as (a) stored, \
+                     c text as (lower(a)) virtual not null, d as (b))";
+        let Statement::CreateTable(create) = parse_create_statement(input).unwrap() else {
+            panic!("expected a create table statement");
+        };
+        let column = |name: &str| {
+            Expr::Column(Column {
+                table: None,
+                name: name.to_string(),
+            })
+        };
+
+        assert_eq!(
+            create.columns[1].constraints,
+            vec![ColumnConstraint::Generated {
+                expr: column("a"),
+                stored: true,
+            }]
+        );
+        assert!(matches!(
+            create.columns[2].constraints[..],
+            [
+                ColumnConstraint::Generated {
+                    expr: Expr::Function(_),
+                    stored: false,
                 },
+                ColumnConstraint::NotNull(None),
+            ]
+        ));
+        assert_eq!(create.columns[3].generated(), Some((&column("b"), false)));
+        assert_eq!(create.columns[0].generated(), None);
+    }
+
+    #[test]
+    fn create_table_with_unique_columns() {
+        let input = "create table t(a text not null unique on conflict replace, \
+                     b integer primary key autoincrement not null, c, unique (a, c))";
+        let Statement::CreateTable(create) = parse_create_statement(input).unwrap() else {
+            panic!("expected a create table statement");
+        };
+        assert_eq!(
+            create.columns[0].constraints,
+            vec![
+                ColumnConstraint::NotNull(None),
+                ColumnConstraint::Unique(Some(ConflictResolution::Replace)),
+            ]
+        );
+        assert_eq!(
+            create.columns[1].constraints,
+            vec![
+                ColumnConstraint::PrimaryKey(None),
+                ColumnConstraint::NotNull(None)
+            ]
+        );
+        assert_eq!(
+            create.constraints,
+            vec![TableConstraint::Unique {
+                columns: vec!["a".to_string(), "c".to_string()],
+                on_conflict: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn create_virtual_table() {
+        let input = "CREATE VIRTUAL TABLE docs USING fts5(title, body, tokenize = 'unicode61 remove_diacritics 2', prefix='2,3')";
+        assert_eq!(
+            parse_create_statement(input).unwrap(),
+            Statement::CreateVirtualTable(CreateVirtualTableStatement {
+                name: "docs".to_string(),
+                module: "fts5".to_string(),
+                args: vec![
+                    "title".to_string(),
+                    "body".to_string(),
+                    "tokenize = 'unicode61 remove_diacritics 2'".to_string(),
+                    "prefix='2,3'".to_string(),
+                ],
             })
         );
     }
 
     #[test]
-    fn select_columns_from_table() {
-        let input = "select col1 as first, col2 from table1;";
-        let statement = parse_statement(input, true).unwrap();
+    fn create_index() {
+        let input = "CREATE UNIQUE INDEX IF NOT EXISTS users_name ON users(name, age DESC)";
         assert_eq!(
-            statement,
-            Statement::Select(SelectStatement {
-                core: SelectCore {
-                    result_columns: vec![
-                        ResultColumn::Expr(ExprResultColumn {
-                            expr: Expr::Column(Column {
-                                name: "col1".to_string()
-                            }),
-                            alias: Some("first".to_string())
+            parse_create_statement(input).unwrap(),
+            Statement::CreateIndex(CreateIndexStatement {
+                name: "users_name".to_string(),
+                table: "users".to_string(),
+                unique: true,
+                columns: vec![
+                    OrderingTerm {
+                        expr: Expr::Column(Column {
+                            table: None,
+                            name: "name".to_string(),
                         }),
-                        ResultColumn::Expr(ExprResultColumn {
-                            expr: Expr::Column(Column {
-                                name: "col2".to_string()
-                            }),
-                            alias: None
+                        descending: false,
+                    },
+                    OrderingTerm {
+                        expr: Expr::Column(Column {
+                            table: None,
+                            name: "age".to_string(),
                         }),
-                    ],
-                    from: SelectFrom::Table("table1".to_string()),
-                },
+                        descending: true,
+                    },
+                ],
+                partial: false,
+            })
+        );
+
+        let input = "CREATE INDEX active ON users(name) WHERE active";
+        let Ok(Statement::CreateIndex(create)) = parse_create_statement(input) else {
+            panic!("expected a create index statement");
+        };
+        assert!(create.partial);
+    }
+
+    #[test]
+    fn create_trigger() {
+        let input = "CREATE TRIGGER IF NOT EXISTS log_update AFTER UPDATE OF name, age ON users \
+                     WHEN new.age > 0 BEGIN INSERT INTO log VALUES (new.id); END";
+        assert_eq!(
+            parse_create_statement(input).unwrap(),
+            Statement::CreateTrigger(CreateTriggerStatement {
+                name: "log_update".to_string(),
+                timing: TriggerTiming::After,
+                event: TriggerEvent::Update(vec!["name".to_string(), "age".to_string()]),
+                table: "users".to_string(),
+            })
+        );
+
+        let input = "create temp trigger t_del instead of delete on v begin select 1; end";
+        let Statement::CreateTrigger(trigger) = parse_create_statement(input).unwrap() else {
+            panic!("expected a create trigger statement");
+        };
+        assert_eq!(trigger.timing, TriggerTiming::InsteadOf);
+        assert_eq!(trigger.event, TriggerEvent::Delete);
+    }
+
+    #[test]
+    fn drop() {
+        assert_eq!(
+            parse_statement("DROP INDEX IF EXISTS users_name", false).unwrap(),
+            Statement::Drop(DropStatement {
+                kind: SchemaObjectKind::Index,
+                name: "users_name".to_string(),
+                if_exists: true,
+            })
+        );
+        assert_eq!(
+            parse_statement("drop view v", false).unwrap(),
+            Statement::Drop(DropStatement {
+                kind: SchemaObjectKind::View,
+                name: "v".to_string(),
+                if_exists: false,
+            })
+        );
+        assert!(parse_statement("drop column c", false).is_err());
+    }
+
+    #[test]
+    fn alter_table_drop_column() {
+        let expected = Statement::AlterTable(AlterTableStatement {
+            table: "users".to_string(),
+            action: AlterTableAction::DropColumn("age".to_string()),
+        });
+        assert_eq!(
+            parse_statement("ALTER TABLE users DROP COLUMN age", false).unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_statement("alter table users drop \"age\"", false).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn drop_column_definitions() {
+        // The SQL SQLite leaves after dropping the columns.
+        let drop = |sql, column, columns| drop_column_definition(sql, column, columns).unwrap();
+        assert_eq!(
+            drop("CREATE TABLE t(a, b , c )", 1, 3),
+            "CREATE TABLE t(a, c )"
+        );
+        assert_eq!(
+            drop("CREATE TABLE t(a int,\n  b text,\n  c  blob\n)", 2, 3),
+            "CREATE TABLE t(a int,\n  b text)"
+        );
+        assert_eq!(
+            drop("CREATE TABLE t(a, b, c, primary key(a))", 2, 3),
+            "CREATE TABLE t(a, b, primary key(a))"
+        );
+        assert_eq!(
+            drop(
+                "CREATE TABLE t(a, \"b,x\" default (','), c , unique(a))",
+                1,
+                3
+            ),
+            "CREATE TABLE t(a, c , unique(a))"
+        );
+        assert_eq!(
+            drop("CREATE TABLE t(a, b /* x, y */ , c -- (\n)", 2, 3),
+            "CREATE TABLE t(a, b /* x, y */ )"
+        );
+        assert!(drop_column_definition("CREATE TABLE t(a)", 1, 1).is_err());
+    }
+
+    #[test]
+    fn find_names() {
+        let find = |sql, name| find_name(sql, name).unwrap();
+        let sql = "CREATE TRIGGER tr AFTER INSERT ON t BEGIN SELECT 'c', New.C, [d]; END";
+        assert_eq!(find(sql, "c").as_deref(), Some("New.C"));
+        assert_eq!(find(sql, "d").as_deref(), Some("[d]"));
+        assert_eq!(find(sql, "x"), None);
+        assert_eq!(find("SELECT x'0a' -- x\nFROM t", "x"), None);
+    }
+
+    #[test]
+    fn created_view_names() {
+        let name = |sql| created_view_name(sql).unwrap();
+        assert_eq!(name("CREATE VIEW v AS SELECT 1").as_deref(), Some("v"));
+        assert_eq!(
+            name("create temp view if not exists main.\"my view\"(a) as select 1").as_deref(),
+            Some("my view")
+        );
+        assert_eq!(name("CREATE TABLE view(a)"), None);
+    }
+
+    #[test]
+    fn pragma() {
+        let pragma = |name: &str, arg| {
+            Statement::Pragma(PragmaStatement {
+                name: name.to_string(),
+                arg,
+            })
+        };
+        assert_eq!(
+            parse_statement("PRAGMA Integrity_Check", false).unwrap(),
+            pragma("integrity_check", None)
+        );
+        assert_eq!(
+            parse_statement("pragma main.quick_check(t)", false).unwrap(),
+            pragma("quick_check", Some(PragmaArg::Name("t".to_string())))
+        );
+        assert_eq!(
+            parse_statement("pragma integrity_check = -1", false).unwrap(),
+            pragma("integrity_check", Some(PragmaArg::Integer(-1)))
+        );
+        assert!(parse_statement("pragma temp.integrity_check", false).is_err());
+    }
+
+    #[test]
+    fn insert() {
+        let input = "insert or ignore into t(a, b) values (1, 'x')";
+        assert_eq!(
+            parse_statement(input, false).unwrap(),
+            Statement::Insert(InsertStatement {
+                on_conflict: Some(ConflictResolution::Ignore),
+                table: "t".to_string(),
+                columns: vec!["a".to_string(), "b".to_string()],
+                rows: vec![vec![
+                    Expr::Literal(Literal::Integer(1)),
+                    Expr::Literal(Literal::String("x".to_string())),
+                ]],
+            })
+        );
+
+        let Statement::Insert(insert) =
+            parse_statement("replace into t values (2)", false).unwrap()
+        else {
+            panic!("expected an insert");
+        };
+        assert_eq!(insert.on_conflict, Some(ConflictResolution::Replace));
+        assert!(insert.columns.is_empty());
+    }
+
+    #[test]
+    fn insert_multiple_rows() {
+        let Statement::Insert(insert) =
+            parse_statement("insert into t values (1, 'a'), (2, 'b'), (3, 'c')", false).unwrap()
+        else {
+            panic!("expected an insert");
+        };
+        assert_eq!(insert.rows.len(), 3);
+        assert_eq!(insert.rows[2][0], Expr::Literal(Literal::Integer(3)));
+
+        assert!(parse_statement("insert into t values (1, 'a'), (2)", false).is_err());
+    }
+
+    #[test]
+    fn update_and_delete() {
+        let Statement::Update(update) =
+            parse_statement("update or replace t set a = 1, b = c where d", false).unwrap()
+        else {
+            panic!("expected an update");
+        };
+        let column = |name: &str| {
+            Expr::Column(Column {
+                table: None,
+                name: name.to_string(),
+            })
+        };
+        assert_eq!(
+            update,
+            UpdateStatement {
+                on_conflict: Some(ConflictResolution::Replace),
+                table: "t".to_string(),
+                assignments: vec![
+                    ("a".to_string(), Expr::Literal(Literal::Integer(1))),
+                    ("b".to_string(), column("c")),
+                ],
+                where_clause: Some(column("d")),
+            }
+        );
+        assert!(parse_statement("update t set a", false).is_err());
+
+        assert_eq!(
+            parse_statement("delete from t", false).unwrap(),
+            Statement::Delete(DeleteStatement {
+                table: "t".to_string(),
+                where_clause: None,
+            })
+        );
+    }
+
+    #[test]
+    fn trigger_program() {
+        let sql = "CREATE TRIGGER r AFTER INSERT ON t WHEN new.a BEGIN \
+                   INSERT INTO u VALUES (new.a); DELETE FROM v; SELECT raise(ignore); END";
+        let program = parse_trigger_program(sql).unwrap();
+        assert_eq!(
+            program.when,
+            Some(Expr::Column(Column {
+                table: Some("new".to_string()),
+                name: "a".to_string(),
+            }))
+        );
+        assert!(matches!(
+            program.statements[..],
+            [
+                Statement::Insert(_),
+                Statement::Delete(_),
+                Statement::Select(_)
+            ]
+        ));
+
+        let body = |body| parse_trigger_program(&format!("CREATE TRIGGER r DELETE ON t {body}"));
+        assert!(body("BEGIN END").is_err());
+        assert!(body("BEGIN DELETE FROM v END").is_err());
+        assert!(body("BEGIN CREATE TABLE v(a); END").is_err());
+        assert!(body("DELETE FROM v").is_err());
+    }
+
+    #[test]
+    fn values() {
+        let rows = vec![
+            vec![
+                Expr::Literal(Literal::Integer(1)),
+                Expr::Literal(Literal::String("a".to_string())),
+            ],
+            vec![
+                Expr::Literal(Literal::Integer(2)),
+                Expr::Literal(Literal::String("b".to_string())),
+            ],
+        ];
+
+        let Statement::Select(select) =
+            parse_statement("values (1, 'a'), (2, 'b')", false).unwrap()
+        else {
+            panic!("expected a select statement");
+        };
+        assert_eq!(select.core.result_columns, vec![ResultColumn::Star]);
+        assert_eq!(
+            select.core.from,
+            SelectFrom::Values {
+                rows: rows.clone(),
+                alias: None
+            }
+        );
+
+        let input = "select column2 from (values (1, 'a'), (2, 'b')) as v";
+        let Statement::Select(select) = parse_statement(input, false).unwrap() else {
+            panic!("expected a select statement");
+        };
+        assert_eq!(
+            select.core.from,
+            SelectFrom::Values {
+                rows,
+                alias: Some("v".to_string())
+            }
+        );
+    }
+
+    #[test]
+    fn split_script() {
+        let script =
+            "create table t(a); -- a comment; with a semicolon\ncreate index i on t('x;y');\n";
+        assert_eq!(
+            split_statements(script),
+            vec!["create table t(a)", "create index i on t('x;y')"]
+        );
+
+        let script = "create trigger tr after insert on t begin\n  select 1;\n  select 2;\nend;\n\
+                      create table u(b);";
+        assert_eq!(
+            split_statements(script),
+            vec![
+                "create trigger tr after insert on t begin\n  select 1;\n  select 2;\nend",
+                "create table u(b)"
+            ]
+        );
+    }
+
+    #[test]
+    fn select_where_match() {
+        let input = "select * from docs where docs match 'term'";
+        let Statement::Select(select) = parse_statement(input, false).unwrap() else {
+            panic!("expected a select statement");
+        };
+        assert_eq!(
+            select.core.where_clause,
+            Some(Expr::Binary(BinaryExpr {
+                op: BinaryOperator::Match,
+                lhs: Box::new(Expr::Column(Column {
+                    table: None,
+                    name: "docs".to_string()
+                })),
+                rhs: Box::new(Expr::Literal(Literal::String("term".to_string()))),
+            }))
+        );
+    }
+
+    #[test]
+    fn select_where_range() {
+        let input = "select * from boxes where minx >= -1 and maxx < 2.5";
+        let Statement::Select(select) = parse_statement(input, false).unwrap() else {
+            panic!("expected a select statement");
+        };
+
+        let column = |name: &str| {
+            Box::new(Expr::Column(Column {
+                table: None,
+                name: name.to_string(),
+            }))
+        };
+        assert_eq!(
+            select.core.where_clause,
+            Some(Expr::Binary(BinaryExpr {
+                op: BinaryOperator::And,
+                lhs: Box::new(Expr::Binary(BinaryExpr {
+                    op: BinaryOperator::GtEq,
+                    lhs: column("minx"),
+                    rhs: Box::new(Expr::Literal(Literal::Integer(-1))),
+                })),
+                rhs: Box::new(Expr::Binary(BinaryExpr {
+                    op: BinaryOperator::Lt,
+                    lhs: column("maxx"),
+                    rhs: Box::new(Expr::Literal(Literal::Real(2.5))),
+                })),
+            }))
+        );
+    }
+
+    #[test]
+    fn select_star_from_table() {
+        let input = "select * from table1";
+        let statement = parse_statement(input, false).unwrap();
+        assert_eq!(
+            statement,
+            Statement::Select(Box::new(SelectStatement {
+                core: SelectCore {
+                    distinct: false,
+                    result_columns: vec![ResultColumn::Star],
+                    from: SelectFrom::Table {
+                        name: "table1".to_string(),
+                        alias: None,
+                    },
+                    where_clause: None,
+                    group_by: vec![],
+                    having: None,
+                },
+                order_by: vec![],
+                limit: None,
+                offset: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn select_table_star() {
+        let statement = parse_statement("select o.*, \"c\".* , c.name from o join c", false);
+        let Statement::Select(select) = statement.unwrap() else {
+            panic!("expected a select statement");
+        };
+        assert_eq!(
+            select.core.result_columns[..2],
+            [
+                ResultColumn::TableStar("o".to_string()),
+                ResultColumn::TableStar("c".to_string())
+            ]
+        );
+        assert!(matches!(
+            select.core.result_columns[2],
+            ResultColumn::Expr(_)
+        ));
+    }
+
+    #[test]
+    fn select_order_by() {
+        let statement = parse_statement("select a, b from t order by b desc, a", false).unwrap();
+        let Statement::Select(select) = statement else {
+            panic!("expected a select statement");
+        };
+        let column = |name: &str| {
+            Expr::Column(Column {
+                table: None,
+                name: name.to_string(),
+            })
+        };
+        assert_eq!(
+            select.order_by,
+            vec![
+                OrderingTerm {
+                    expr: column("b"),
+                    descending: true,
+                },
+                OrderingTerm {
+                    expr: column("a"),
+                    descending: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn select_join() {
+        let statement = parse_statement(
+            "select u.name from users u left join orders as o on u.id = o.user, t",
+            false,
+        )
+        .unwrap();
+        let Statement::Select(select) = statement else {
+            panic!("expected a select statement");
+        };
+        let column = |table: &str, name: &str| {
+            Box::new(Expr::Column(Column {
+                table: Some(table.to_string()),
+                name: name.to_string(),
+            }))
+        };
+        let table = |name: &str, alias: Option<&str>| SelectFrom::Table {
+            name: name.to_string(),
+            alias: alias.map(str::to_string),
+        };
+
+        assert_eq!(
+            select.core.from,
+            SelectFrom::Join(Box::new(Join {
+                kind: JoinKind::Inner,
+                left: SelectFrom::Join(Box::new(Join {
+                    kind: JoinKind::Left,
+                    left: table("users", Some("u")),
+                    right: table("orders", Some("o")),
+                    constraint: Some(Expr::Binary(BinaryExpr {
+                        op: BinaryOperator::Eq,
+                        lhs: column("u", "id"),
+                        rhs: column("o", "user"),
+                    })),
+                })),
+                right: table("t", None),
+                constraint: None,
+            }))
+        );
+        assert_eq!(
+            select.core.result_columns,
+            vec![ResultColumn::Expr(ExprResultColumn {
+                expr: *column("u", "name"),
+                alias: None,
+            })]
+        );
+    }
+
+    #[test]
+    fn select_between() {
+        let statement = parse_statement(
+            "select * from t where a between 1 and 2 and b not between c and 3",
+            false,
+        )
+        .unwrap();
+        let Statement::Select(select) = statement else {
+            panic!("expected a select statement");
+        };
+        let column = |name: &str| {
+            Box::new(Expr::Column(Column {
+                table: None,
+                name: name.to_string(),
+            }))
+        };
+        let integer = |i| Box::new(Expr::Literal(Literal::Integer(i)));
+
+        assert_eq!(
+            select.core.where_clause,
+            Some(Expr::Binary(BinaryExpr {
+                op: BinaryOperator::And,
+                lhs: Box::new(Expr::Between(BetweenExpr {
+                    expr: column("a"),
+                    low: integer(1),
+                    high: integer(2),
+                    negated: false,
+                })),
+                rhs: Box::new(Expr::Between(BetweenExpr {
+                    expr: column("b"),
+                    low: column("c"),
+                    high: integer(3),
+                    negated: true,
+                })),
+            }))
+        );
+    }
+
+    #[test]
+    fn select_boolean_precedence() {
+        let statement =
+            parse_statement("select * from t where not a = 1 or b and not c", false).unwrap();
+        let Statement::Select(select) = statement else {
+            panic!("expected a select statement");
+        };
+        let column = |name: &str| {
+            Box::new(Expr::Column(Column {
+                table: None,
+                name: name.to_string(),
+            }))
+        };
+        let not = |expr| {
+            Box::new(Expr::Unary(UnaryExpr {
+                op: UnaryOperator::Not,
+                expr,
+            }))
+        };
+        let binary = |op, lhs, rhs| Box::new(Expr::Binary(BinaryExpr { op, lhs, rhs }));
+
+        assert_eq!(
+            select.core.where_clause.map(Box::new),
+            Some(binary(
+                BinaryOperator::Or,
+                not(binary(
+                    BinaryOperator::Eq,
+                    column("a"),
+                    Box::new(Expr::Literal(Literal::Integer(1)))
+                )),
+                binary(BinaryOperator::And, column("b"), not(column("c"))),
+            ))
+        );
+    }
+
+    #[test]
+    fn select_concat() {
+        let statement = parse_statement("select * from t where a = b || 'x' || c", false).unwrap();
+        let Statement::Select(select) = statement else {
+            panic!("expected a select statement");
+        };
+        let column = |name: &str| {
+            Box::new(Expr::Column(Column {
+                table: None,
+                name: name.to_string(),
+            }))
+        };
+        let binary = |op, lhs, rhs| Box::new(Expr::Binary(BinaryExpr { op, lhs, rhs }));
+        let concat = |lhs, rhs| binary(BinaryOperator::Concat, lhs, rhs);
+
+        assert_eq!(
+            select.core.where_clause.map(Box::new),
+            Some(binary(
+                BinaryOperator::Eq,
+                column("a"),
+                concat(
+                    concat(
+                        column("b"),
+                        Box::new(Expr::Literal(Literal::String("x".to_string())))
+                    ),
+                    column("c")
+                ),
+            ))
+        );
+    }
+
+    #[test]
+    fn select_null_tests() {
+        let parse_where = |condition: &str| {
+            let statement =
+                parse_statement(&format!("select * from t where {condition}"), false).unwrap();
+            let Statement::Select(select) = statement else {
+                panic!("expected a select statement");
+            };
+            select.core.where_clause.unwrap()
+        };
+        let test = |op, rhs| {
+            Expr::Binary(BinaryExpr {
+                op,
+                lhs: Box::new(Expr::Column(Column {
+                    table: None,
+                    name: "a".to_string(),
+                })),
+                rhs: Box::new(rhs),
+            })
+        };
+        let null = Expr::Literal(Literal::Null);
+
+        assert_eq!(
+            parse_where("a is null"),
+            test(BinaryOperator::Is, null.clone())
+        );
+        assert_eq!(
+            parse_where("a isnull"),
+            test(BinaryOperator::Is, null.clone())
+        );
+        assert_eq!(
+            parse_where("a is not null"),
+            test(BinaryOperator::IsNot, null.clone())
+        );
+        assert_eq!(
+            parse_where("a notnull"),
+            test(BinaryOperator::IsNot, null.clone())
+        );
+        assert_eq!(parse_where("a not null"), test(BinaryOperator::IsNot, null));
+        assert_eq!(
+            parse_where("a is 'x'"),
+            test(
+                BinaryOperator::Is,
+                Expr::Literal(Literal::String("x".to_string()))
+            )
+        );
+    }
+
+    #[test]
+    fn select_quoted_identifiers() {
+        let statement = parse_statement(
+            r#"select "not", [Order Id] from "My Table" "join" where "null" is null"#,
+            false,
+        )
+        .unwrap();
+        let Statement::Select(select) = statement else {
+            panic!("expected a select statement");
+        };
+        let column = |name: &str| {
+            Expr::Column(Column {
+                table: None,
+                name: name.to_string(),
+            })
+        };
+
+        assert_eq!(
+            select.core.result_columns,
+            vec![
+                ResultColumn::Expr(ExprResultColumn {
+                    expr: column("not"),
+                    alias: None,
+                }),
+                ResultColumn::Expr(ExprResultColumn {
+                    expr: column("Order Id"),
+                    alias: None,
+                }),
+            ]
+        );
+        assert_eq!(
+            select.core.from,
+            SelectFrom::Table {
+                name: "My Table".to_string(),
+                alias: Some("join".to_string()),
+            }
+        );
+        assert_eq!(
+            select.core.where_clause,
+            Some(Expr::Binary(BinaryExpr {
+                op: BinaryOperator::Is,
+                lhs: Box::new(column("null")),
+                rhs: Box::new(Expr::Literal(Literal::Null)),
+            }))
+        );
+    }
+
+    #[test]
+    fn select_cast() {
+        let statement =
+            parse_statement("select cast(a as unsigned big int(10, -2)) from t", false).unwrap();
+        let Statement::Select(select) = statement else {
+            panic!("expected a select statement");
+        };
+
+        assert_eq!(
+            select.core.result_columns,
+            vec![ResultColumn::Expr(ExprResultColumn {
+                expr: Expr::Cast(CastExpr {
+                    expr: Box::new(Expr::Column(Column {
+                        table: None,
+                        name: "a".to_string(),
+                    })),
+                    type_name: "unsigned big int(10, -2)".to_string(),
+                }),
+                alias: None,
+            })]
+        );
+    }
+
+    #[test]
+    fn select_like() {
+        let input = "select * from t where a not like 'x!%%' escape '!' and b like 'y'";
+        let Statement::Select(select) = parse_statement(input, false).unwrap() else {
+            panic!("expected a select statement");
+        };
+        let column = |name: &str| {
+            Box::new(Expr::Column(Column {
+                table: None,
+                name: name.to_string(),
+            }))
+        };
+        let string = |s: &str| Box::new(Expr::Literal(Literal::String(s.to_string())));
+
+        assert_eq!(
+            select.core.where_clause,
+            Some(Expr::Binary(BinaryExpr {
+                op: BinaryOperator::And,
+                lhs: Box::new(Expr::Like(LikeExpr {
+                    expr: column("a"),
+                    pattern: string("x!%%"),
+                    escape: Some(string("!")),
+                    negated: true,
+                })),
+                rhs: Box::new(Expr::Like(LikeExpr {
+                    expr: column("b"),
+                    pattern: string("y"),
+                    escape: None,
+                    negated: false,
+                })),
+            }))
+        );
+    }
+
+    #[test]
+    fn select_collate() {
+        let input = "select a from t where a = 'x' collate nocase order by b collate rtrim desc";
+        let Statement::Select(select) = parse_statement(input, false).unwrap() else {
+            panic!("expected a select statement");
+        };
+        let column = |name: &str| {
+            Box::new(Expr::Column(Column {
+                table: None,
+                name: name.to_string(),
+            }))
+        };
+
+        assert_eq!(
+            select.core.where_clause,
+            Some(Expr::Binary(BinaryExpr {
+                op: BinaryOperator::Eq,
+                lhs: column("a"),
+                rhs: Box::new(Expr::Collate(CollateExpr {
+                    expr: Box::new(Expr::Literal(Literal::String("x".to_string()))),
+                    collation: "nocase".to_string(),
+                })),
+            }))
+        );
+        assert_eq!(
+            select.order_by,
+            vec![OrderingTerm {
+                expr: Expr::Collate(CollateExpr {
+                    expr: column("b"),
+                    collation: "rtrim".to_string(),
+                }),
+                descending: true,
+            }]
+        );
+
+        let Statement::CreateTable(create) =
+            parse_statement("create table t(a text collate nocase not null)", false).unwrap()
+        else {
+            panic!("expected a create table statement");
+        };
+        assert_eq!(
+            create.columns[0].constraints,
+            vec![
+                ColumnConstraint::Collate("nocase".to_string()),
+                ColumnConstraint::NotNull(None)
+            ]
+        );
+        assert_eq!(
+            create.columns[0].collation(),
+            Collation::NoCase(CaseFolding::Ascii)
+        );
+
+        let Statement::CreateTable(create) =
+            parse_statement("create table t(a collate nocase)", false).unwrap()
+        else {
+            panic!("expected a create table statement");
+        };
+        assert_eq!(create.columns[0].col_type, None);
+        assert_eq!(
+            create.columns[0].collation(),
+            Collation::NoCase(CaseFolding::Ascii)
+        );
+    }
+
+    #[test]
+    fn select_window() {
+        let statement = parse_statement(
+            "select rank() over (partition by a order by b desc), count(*) over () from t",
+            false,
+        )
+        .unwrap();
+        let Statement::Select(select) = statement else {
+            panic!("expected a select statement");
+        };
+        let column = |name: &str| {
+            Expr::Column(Column {
+                table: None,
+                name: name.to_string(),
+            })
+        };
+        let call = |name: &str, star| FunctionCall {
+            name: name.to_string(),
+            args: vec![],
+            star,
+        };
+
+        assert_eq!(
+            select.core.result_columns,
+            vec![
+                ResultColumn::Expr(ExprResultColumn {
+                    expr: Expr::Window(WindowCall {
+                        function: call("rank", false),
+                        partition_by: vec![column("a")],
+                        order_by: vec![OrderingTerm {
+                            expr: column("b"),
+                            descending: true,
+                        }],
+                    }),
+                    alias: None,
+                }),
+                ResultColumn::Expr(ExprResultColumn {
+                    expr: Expr::Window(WindowCall {
+                        function: call("count", true),
+                        partition_by: vec![],
+                        order_by: vec![],
+                    }),
+                    alias: None,
+                }),
+            ]
+        );
+        assert!(parse_statement("select sum(a) over (rows 1 preceding) from t", false).is_err());
+    }
+
+    #[test]
+    fn select_group_by() {
+        let statement = parse_statement(
+            "select k, count(*) from t group by k, v having count(*) > 1",
+            false,
+        )
+        .unwrap();
+        let Statement::Select(select) = statement else {
+            panic!("expected a select statement");
+        };
+        let column = |name: &str| {
+            Expr::Column(Column {
+                table: None,
+                name: name.to_string(),
             })
+        };
+        let count = Expr::Function(FunctionCall {
+            name: "count".to_string(),
+            args: vec![],
+            star: true,
+        });
+
+        assert_eq!(select.core.group_by, vec![column("k"), column("v")]);
+        assert_eq!(
+            select.core.having,
+            Some(Expr::Binary(BinaryExpr {
+                op: BinaryOperator::Gt,
+                lhs: Box::new(count.clone()),
+                rhs: Box::new(Expr::Literal(Literal::Integer(1))),
+            }))
         );
+        assert_eq!(count.to_string(), "count(*)");
+    }
+
+    #[test]
+    fn select_limit() {
+        let parse = |sql| {
+            let Statement::Select(select) = parse_statement(sql, false).unwrap() else {
+                panic!("expected a select statement");
+            };
+            (select.limit, select.offset)
+        };
+        let int = |i| Some(Expr::Literal(Literal::Integer(i)));
+
+        assert_eq!(parse("select * from t"), (None, None));
+        assert_eq!(parse("select * from t limit 10"), (int(10), None));
+        assert_eq!(
+            parse("select * from t order by a limit 10 offset 5"),
+            (int(10), int(5))
+        );
+        assert_eq!(parse("select * from t limit 5, 10"), (int(10), int(5)));
+    }
+
+    #[test]
+    fn select_distinct() {
+        let statement = parse_statement("select distinct kind from t", false).unwrap();
+        let Statement::Select(select) = statement else {
+            panic!("expected a select statement");
+        };
+        assert!(select.core.distinct);
+
+        let statement = parse_statement("select all kind from t", false).unwrap();
+        let Statement::Select(select) = statement else {
+            panic!("expected a select statement");
+        };
+        assert!(!select.core.distinct);
+    }
+
+    #[test]
+    fn select_parameters() {
+        let statement =
+            parse_statement("select ?, :a, ?5, ?, :a, :b from t where x = ?2", false).unwrap();
+        let Statement::Select(select) = statement else {
+            panic!("expected a select statement");
+        };
+        let parameter = |index, name: Option<&str>| {
+            ResultColumn::Expr(ExprResultColumn {
+                expr: Expr::Parameter(Parameter {
+                    index,
+                    name: name.map(str::to_string),
+                }),
+                alias: None,
+            })
+        };
+        assert_eq!(
+            select.core.result_columns,
+            vec![
+                parameter(1, None),
+                parameter(2, Some(":a")),
+                parameter(5, Some("?5")),
+                parameter(6, None),
+                parameter(2, Some(":a")),
+                parameter(7, Some(":b")),
+            ]
+        );
+
+        assert!(parse_statement("select ?0 from t", false).is_err());
+        assert!(parse_statement("select ?32767 from t", false).is_err());
+    }
+
+    #[test]
+    fn explain() {
+        let statement = parse_statement("explain select a from t;", true).unwrap();
+        let Statement::Explain(explained) = statement else {
+            panic!("expected an explain statement");
+        };
+        assert!(matches!(*explained, Statement::Select(_)));
+
+        let statement = parse_statement("explain query plan select a from t", false).unwrap();
+        let Statement::ExplainQueryPlan(explained) = statement else {
+            panic!("expected an explain query plan statement");
+        };
+        assert!(matches!(*explained, Statement::Select(_)));
+
+        let statement = parse_statement("explain analyze select a from t", false).unwrap();
+        assert!(matches!(statement, Statement::ExplainAnalyze(_)));
+    }
+
+    #[test]
+    fn select_columns_from_table() {
+        let input = "select col1 as first, col2 from table1;";
+        let statement = parse_statement(input, true).unwrap();
+        assert_eq!(
+            statement,
+            Statement::Select(Box::new(SelectStatement {
+                core: SelectCore {
+                    distinct: false,
+                    result_columns: vec![
+                        ResultColumn::Expr(ExprResultColumn {
+                            expr: Expr::Column(Column {
+                                table: None,
+                                name: "col1".to_string()
+                            }),
+                            alias: Some("first".to_string())
+                        }),
+                        ResultColumn::Expr(ExprResultColumn {
+                            expr: Expr::Column(Column {
+                                table: None,
+                                name: "col2".to_string()
+                            }),
+                            alias: None
+                        }),
+                    ],
+                    from: SelectFrom::Table {
+                        name: "table1".to_string(),
+                        alias: None,
+                    },
+                    where_clause: None,
+                    group_by: vec![],
+                    having: None,
+                },
+                order_by: vec![],
+                limit: None,
+                offset: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn select_json_functions() {
+        let input = "select json_extract(data, '$.a'), data -> 'b' ->> 'c' from json_each('[]')";
+        let statement = parse_statement(input, false).unwrap();
+        let data = || {
+            Box::new(Expr::Column(Column {
+                table: None,
+                name: "data".to_string(),
+            }))
+        };
+        let literal = |s: &str| Box::new(Expr::Literal(Literal::String(s.to_string())));
+        assert_eq!(
+            statement,
+            Statement::Select(Box::new(SelectStatement {
+                core: SelectCore {
+                    distinct: false,
+                    result_columns: vec![
+                        ResultColumn::Expr(ExprResultColumn {
+                            expr: Expr::Function(FunctionCall {
+                                name: "json_extract".to_string(),
+                                args: vec![*data(), *literal("$.a")],
+                                star: false,
+                            }),
+                            alias: None
+                        }),
+                        ResultColumn::Expr(ExprResultColumn {
+                            expr: Expr::Binary(BinaryExpr {
+                                op: BinaryOperator::LongArrow,
+                                lhs: Box::new(Expr::Binary(BinaryExpr {
+                                    op: BinaryOperator::Arrow,
+                                    lhs: data(),
+                                    rhs: literal("b"),
+                                })),
+                                rhs: literal("c"),
+                            }),
+                            alias: None
+                        }),
+                    ],
+                    from: SelectFrom::Function(FunctionCall {
+                        name: "json_each".to_string(),
+                        args: vec![*literal("[]")],
+                        star: false,
+                    }),
+                    where_clause: None,
+                    group_by: vec![],
+                    having: None,
+                },
+                order_by: vec![],
+                limit: None,
+                offset: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn trailing_tokens() {
+        assert!(parse_statement("select a from t;", false).is_ok());
+        assert!(parse_statement("select a from t;", true).is_ok());
+        assert!(parse_statement("select a from t", true).is_err());
+        for input in [
+            "select a from t where a in (1,2) limit 2;",
+            "select a from t limit 2 3",
+            "select a from t; select b from t",
+            "select a from t;;",
+        ] {
+            assert!(parse_statement(input, false).is_err(), "{input}");
+        }
     }
 }
@@ -1,22 +1,55 @@
-use anyhow::bail;
+use std::{iter::Peekable, str::Chars};
 
-#[derive(Debug, Eq, PartialEq)]
+use anyhow::{Context, bail, ensure};
+
+#[derive(Debug, PartialEq)]
 pub enum Token {
     Create,
     Table,
     Select,
     As,
     From,
+    Where,
+    Match,
+    And,
+    Or,
     LPar,
     RPar,
     Star,
     Comma,
+    Dot,
     SemiColon,
+    Minus,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    Arrow,
+    LongArrow,
+    Concat,
     Identifier(String),
+    /// An identifier in double quotes, brackets or backticks, which keeps its case
+    /// and is never a keyword.
+    QuotedIdentifier(String),
+    StringLiteral(String),
+    /// A parameter placeholder as written: `?`, `?N` or `:name`.
+    Parameter(String),
+    Integer(i64),
+    Real(f64),
 }
 
 impl Token {
     pub fn as_identifier(&self) -> Option<&str> {
+        match self {
+            Token::Identifier(ident) | Token::QuotedIdentifier(ident) => Some(ident),
+            _ => None,
+        }
+    }
+
+    /// The word, if the token is an unquoted one, which could be a keyword.
+    pub fn as_keyword(&self) -> Option<&str> {
         match self {
             Token::Identifier(ident) => Some(ident),
             _ => None,
@@ -34,9 +67,72 @@ pub fn tokenize(input: &str) -> anyhow::Result<Vec<Token>> {
             ')' => tokens.push(Token::RPar),
             '*' => tokens.push(Token::Star),
             ',' => tokens.push(Token::Comma),
+            '.' if chars.peek().is_some_and(char::is_ascii_digit) => {
+                tokens.push(number(c, &mut chars)?)
+            }
+            '.' => tokens.push(Token::Dot),
             ';' => tokens.push(Token::SemiColon),
+            '-' if chars.next_if_eq(&'-').is_some() => {
+                while chars.next_if(|&cc| cc != '\n').is_some() {}
+            }
+            // Like SQLite, a block comment left open ends with the input.
+            '/' if chars.next_if_eq(&'*').is_some() => {
+                while let Some(cc) = chars.next() {
+                    if cc == '*' && chars.next_if_eq(&'/').is_some() {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.next_if_eq(&'>').is_some() => {
+                if chars.next_if_eq(&'>').is_some() {
+                    tokens.push(Token::LongArrow)
+                } else {
+                    tokens.push(Token::Arrow)
+                }
+            }
+            '-' => tokens.push(Token::Minus),
+            '|' if chars.next_if_eq(&'|').is_some() => tokens.push(Token::Concat),
+            '=' => {
+                chars.next_if_eq(&'=');
+                tokens.push(Token::Eq)
+            }
+            '!' if chars.next_if_eq(&'=').is_some() => tokens.push(Token::NotEq),
+            '<' if chars.next_if_eq(&'=').is_some() => tokens.push(Token::LtEq),
+            '<' if chars.next_if_eq(&'>').is_some() => tokens.push(Token::NotEq),
+            '<' => tokens.push(Token::Lt),
+            '>' if chars.next_if_eq(&'=').is_some() => tokens.push(Token::GtEq),
+            '>' => tokens.push(Token::Gt),
+            '\'' => tokens.push(Token::StringLiteral(quoted(&mut chars, c)?)),
+            '"' | '`' => tokens.push(Token::QuotedIdentifier(quoted(&mut chars, c)?)),
+            // Brackets come from MS Access and SQL Server, and have no escape.
+            '[' => {
+                let mut ident = String::new();
+                loop {
+                    match chars.next().context("unterminated quoted identifier")? {
+                        ']' => break,
+                        cc => ident.push(cc),
+                    }
+                }
+                tokens.push(Token::QuotedIdentifier(ident));
+            }
+            '?' => {
+                let mut parameter = c.to_string();
+                while let Some(cc) = chars.next_if(char::is_ascii_digit) {
+                    parameter.push(cc);
+                }
+                tokens.push(Token::Parameter(parameter));
+            }
+            ':' => {
+                let mut parameter = c.to_string();
+                while let Some(cc) = chars.next_if(|&cc| cc.is_alphanumeric() || cc == '_') {
+                    parameter.push(cc);
+                }
+                ensure!(parameter.len() > 1, "unrecognized token: \":\"");
+                tokens.push(Token::Parameter(parameter));
+            }
             c if c.is_whitespace() => continue,
-            c if c.is_alphabetic() => {
+            c if c.is_ascii_digit() => tokens.push(number(c, &mut chars)?),
+            c if c.is_alphabetic() || c == '_' => {
                 let mut ident = c.to_string().to_lowercase();
                 while let Some(cc) = chars.next_if(|&cc| cc.is_alphanumeric() || cc == '_') {
                     ident.extend(cc.to_lowercase());
@@ -48,6 +144,10 @@ pub fn tokenize(input: &str) -> anyhow::Result<Vec<Token>> {
                     "select" => tokens.push(Token::Select),
                     "as" => tokens.push(Token::As),
                     "from" => tokens.push(Token::From),
+                    "where" => tokens.push(Token::Where),
+                    "match" => tokens.push(Token::Match),
+                    "and" => tokens.push(Token::And),
+                    "or" => tokens.push(Token::Or),
                     _ => tokens.push(Token::Identifier(ident)),
                 }
             }
@@ -58,6 +158,87 @@ pub fn tokenize(input: &str) -> anyhow::Result<Vec<Token>> {
     Ok(tokens)
 }
 
+/// Reads the rest of a string or identifier opened by `quote`, in which a doubled
+/// quote stands for the quote.
+fn quoted(chars: &mut Peekable<Chars<'_>>, quote: char) -> anyhow::Result<String> {
+    let mut text = String::new();
+    loop {
+        match chars.next() {
+            Some(c) if c == quote && chars.next_if_eq(&quote).is_some() => text.push(quote),
+            Some(c) if c == quote => return Ok(text),
+            Some(c) => text.push(c),
+            None if quote == '\'' => bail!("unterminated string literal"),
+            None => bail!("unterminated quoted identifier"),
+        }
+    }
+}
+
+/// Reads a numeric literal starting with `first`: a decimal integer, a real with a
+/// fraction or an exponent, or a hexadecimal integer. Decimal integers too large
+/// for 64 bits are reals, and hexadecimal ones wrap around like in SQLite.
+fn number(first: char, chars: &mut Peekable<Chars<'_>>) -> anyhow::Result<Token> {
+    let mut number = first.to_string();
+    if first == '0'
+        && let Some(x) = chars.next_if(|c| matches!(c, 'x' | 'X'))
+    {
+        let mut digits = String::new();
+        while let Some(c) = chars.next_if(char::is_ascii_hexdigit) {
+            digits.push(c);
+        }
+        ensure!(!digits.is_empty(), "unrecognized token: 0{x}");
+        ensure!(digits.len() <= 16, "hex literal too big: 0{x}{digits}");
+        ensure_number_ends(&format!("0{x}{digits}"), chars)?;
+        return Ok(Token::Integer(u64::from_str_radix(&digits, 16)? as i64));
+    }
+
+    let mut real = first == '.';
+    push_digits(&mut number, chars);
+    if !real && let Some(dot) = chars.next_if_eq(&'.') {
+        real = true;
+        number.push(dot);
+        push_digits(&mut number, chars);
+    }
+    if let Some(e) = chars.next_if(|c| matches!(c, 'e' | 'E')) {
+        real = true;
+        number.push(e);
+        if let Some(sign) = chars.next_if(|c| matches!(c, '+' | '-')) {
+            number.push(sign);
+        }
+        ensure!(
+            push_digits(&mut number, chars),
+            "unrecognized token: {number}"
+        );
+    }
+    ensure_number_ends(&number, chars)?;
+
+    if !real && let Ok(i) = number.parse() {
+        return Ok(Token::Integer(i));
+    }
+    let value = number
+        .parse()
+        .with_context(|| format!("invalid number: {number}"))?;
+    Ok(Token::Real(value))
+}
+
+/// Moves the digits coming next to `number`, returning whether there were any.
+fn push_digits(number: &mut String, chars: &mut Peekable<Chars<'_>>) -> bool {
+    let start = number.len();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        number.push(c);
+    }
+    number.len() > start
+}
+
+/// SQLite rejects numbers running into an identifier, e.g. `12abc`.
+fn ensure_number_ends(number: &str, chars: &mut Peekable<Chars<'_>>) -> anyhow::Result<()> {
+    if let Some(&c) = chars.peek()
+        && (c.is_alphanumeric() || c == '_')
+    {
+        bail!("unrecognized token: {number}{c}");
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -79,9 +260,140 @@ mod tests {
         assert_eq!(tokenize(input).unwrap(), expected);
     }
 
+    #[test]
+    fn tokenize_underscore_identifiers() {
+        let input = "_rowid_ _A_1 _";
+        let expected = vec![
+            Token::Identifier("_rowid_".to_string()),
+            Token::Identifier("_a_1".to_string()),
+            Token::Identifier("_".to_string()),
+        ];
+        assert_eq!(tokenize(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn tokenize_json_operators() {
+        let input = "data -> '$.a', data ->> 'B'";
+        let expected = vec![
+            Token::Identifier("data".to_string()),
+            Token::Arrow,
+            Token::StringLiteral("$.a".to_string()),
+            Token::Comma,
+            Token::Identifier("data".to_string()),
+            Token::LongArrow,
+            Token::StringLiteral("B".to_string()),
+        ];
+        assert_eq!(tokenize(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn tokenize_comparisons() {
+        let input = "x >= -1.5 and y<2 and a == b and a != 1 and 2<>b";
+        let expected = vec![
+            Token::Identifier("x".to_string()),
+            Token::GtEq,
+            Token::Minus,
+            Token::Real(1.5),
+            Token::And,
+            Token::Identifier("y".to_string()),
+            Token::Lt,
+            Token::Integer(2),
+            Token::And,
+            Token::Identifier("a".to_string()),
+            Token::Eq,
+            Token::Identifier("b".to_string()),
+            Token::And,
+            Token::Identifier("a".to_string()),
+            Token::NotEq,
+            Token::Integer(1),
+            Token::And,
+            Token::Integer(2),
+            Token::NotEq,
+            Token::Identifier("b".to_string()),
+        ];
+        assert_eq!(tokenize(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn tokenize_comments() {
+        let input = "select -- the columns\n a, /* b, */ c/**/from t -- done";
+        let expected = vec![
+            Token::Select,
+            Token::Identifier("a".to_string()),
+            Token::Comma,
+            Token::Identifier("c".to_string()),
+            Token::From,
+            Token::Identifier("t".to_string()),
+        ];
+        assert_eq!(tokenize(input).unwrap(), expected);
+
+        assert_eq!(
+            tokenize("a /* open").unwrap(),
+            vec![Token::Identifier("a".to_string())]
+        );
+        assert_eq!(
+            tokenize("1-2").unwrap(),
+            vec![Token::Integer(1), Token::Minus, Token::Integer(2)]
+        );
+    }
+
+    #[test]
+    fn tokenize_numbers() {
+        let input = "1 1.5 .5 2. 1e3 2.5E-2 0x1f 0XFFFFFFFFFFFFFFFF 9223372036854775808";
+        let expected = vec![
+            Token::Integer(1),
+            Token::Real(1.5),
+            Token::Real(0.5),
+            Token::Real(2.0),
+            Token::Real(1000.0),
+            Token::Real(0.025),
+            Token::Integer(31),
+            Token::Integer(-1),
+            Token::Real(9223372036854775808.0),
+        ];
+        assert_eq!(tokenize(input).unwrap(), expected);
+
+        for invalid in ["12abc", "1e", "1e+x", "0x", "0x12345678901234567", "0x1g"] {
+            assert!(tokenize(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn tokenize_quoted_identifiers() {
+        let input = r#"select "Col ""1""", [order], `a``b` from "T""#;
+        let expected = vec![
+            Token::Select,
+            Token::QuotedIdentifier("Col \"1\"".to_string()),
+            Token::Comma,
+            Token::QuotedIdentifier("order".to_string()),
+            Token::Comma,
+            Token::QuotedIdentifier("a`b".to_string()),
+            Token::From,
+            Token::QuotedIdentifier("T".to_string()),
+        ];
+        assert_eq!(tokenize(input).unwrap(), expected);
+        assert!(tokenize("select [a").is_err());
+    }
+
     #[test]
     fn tokenize_invalid_char() {
         let input = "select @ from table;";
         assert!(tokenize(input).is_err());
     }
+
+    #[test]
+    fn tokenize_unterminated_string() {
+        assert!(tokenize("select 'abc").is_err());
+        assert!(tokenize("select 'abc''").is_err());
+    }
+
+    #[test]
+    fn tokenize_escaped_quotes() {
+        let expected = vec![
+            Token::StringLiteral("it's".to_string()),
+            Token::StringLiteral("'".to_string()),
+            Token::StringLiteral(String::new()),
+        ];
+        assert_eq!(tokenize("'it''s' '''' ''").unwrap(), expected);
+    }
 }
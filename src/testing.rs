@@ -0,0 +1,317 @@
+//! Builders of the records and b-tree pages of hand-made database files, for tests.
+
+use crate::{
+    db::Db,
+    pager::{HEADER_PREFIX, varint},
+    value::SendValue,
+};
+
+/// A database file of `page_count` pages of `page_size` bytes, with its header filled
+/// in and its pages left empty.
+pub fn database_file(page_size: usize, page_count: u32) -> Vec<u8> {
+    let mut file = vec![0; page_size * page_count as usize];
+    file[..16].copy_from_slice(HEADER_PREFIX);
+    file[16..18].copy_from_slice(&(page_size as u16).to_be_bytes());
+    file[28..32].copy_from_slice(&page_count.to_be_bytes());
+    file
+}
+
+/// Opens the database `file`, written to a temporary file named after `name` and
+/// removed once open.
+pub fn open_database(name: &str, file: &[u8]) -> Db {
+    let path = std::env::temp_dir().join(format!("rqlite-{name}-{}", std::process::id()));
+    std::fs::write(&path, file).unwrap();
+    let db = Db::from_file(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    db
+}
+
+/// A database of the schema `entries`, `(type, name, table, sql)`, whose tables and
+/// indexes are empty, each on a page of its own following the first one, in their
+/// order. Its pages are of 512 bytes, for b-trees to span several of them with few
+/// rows, unless the schema needs larger ones.
+pub fn schema_database(name: &str, entries: &[(&str, &str, &str, &str)]) -> Db {
+    // Triggers and views have no b-tree, and a root page of 0.
+    let mut btrees = Vec::new();
+    let cells: Vec<_> = (entries.iter().zip(1..))
+        .map(|(&(kind, name, table, sql), i)| {
+            let root = match kind {
+                "table" | "index" => {
+                    btrees.push(kind);
+                    btrees.len() as i64 + 1
+                }
+                _ => 0,
+            };
+            schema_cell(i, kind, name, table, root, sql)
+        })
+        .collect();
+    let schema_size = 108 + cells.iter().map(|cell| cell.len() + 2).sum::<usize>();
+    let page_size = schema_size.next_power_of_two().max(512);
+    let mut file = database_file(page_size, btrees.len() as u32 + 1);
+    write_leaf(&mut file[..page_size], 100, 0x0d, &cells);
+    for (kind, page) in btrees.into_iter().zip(2..) {
+        let page_type = if kind == "index" { 0x0a } else { 0x0d };
+        write_leaf(page_mut(&mut file, page_size, page), 0, page_type, &[]);
+    }
+    open_database(name, &file)
+}
+
+/// A database of b-trees spanning several levels, left out of its schema: a table of
+/// the rowids 10, 20, ..., 1000, whose records hold the even values `rowid / 5 - 2`,
+/// and an index of these values with their rowids. Returns the database with the root
+/// pages of the table and the index.
+pub fn multilevel_btrees(name: &str) -> (Db, usize, usize) {
+    const PAGE_SIZE: usize = 512;
+    let mut file = database_file(PAGE_SIZE, 1);
+    write_leaf(&mut file[..PAGE_SIZE], 100, 0x0d, &[]);
+    let rowids = (1..=100).map(|i| i * 10);
+    let rows: Vec<_> = rowids
+        .clone()
+        .map(|rowid| (rowid, record(&[SendValue::Int(rowid / 5 - 2)])))
+        .collect();
+    let table = table_btree(&mut file, PAGE_SIZE, &rows, 3);
+    let keys: Vec<_> = rowids
+        .map(|rowid| record(&[SendValue::Int(rowid / 5 - 2), SendValue::Int(rowid)]))
+        .collect();
+    let index = index_btree(&mut file, PAGE_SIZE, &keys, 3);
+    (open_database(name, &file), table as usize, index as usize)
+}
+
+/// `data` in the zstd seekable format, compressed in frames of `frame_size` bytes
+/// followed by their seek table.
+pub fn seekable_zstd(data: &[u8], frame_size: usize) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut entries = Vec::new();
+    for chunk in data.chunks(frame_size) {
+        let frame = zstd::bulk::compress(chunk, 3).unwrap();
+        entries.extend((frame.len() as u32).to_le_bytes());
+        entries.extend((chunk.len() as u32).to_le_bytes());
+        output.extend(frame);
+    }
+    // The seek table is a skippable frame, ending with the frame count, a descriptor
+    // without checksums and the magic number of the format.
+    output.extend(0x184D_2A5Eu32.to_le_bytes());
+    output.extend((entries.len() as u32 + 9).to_le_bytes());
+    output.extend(&entries);
+    output.extend((entries.len() as u32 / 8).to_le_bytes());
+    output.push(0);
+    output.extend(0x8F92_EAB1u32.to_le_bytes());
+    output
+}
+
+/// A record of the values.
+pub fn record(values: &[SendValue]) -> Vec<u8> {
+    let mut types = Vec::new();
+    let mut body = Vec::new();
+    for value in values {
+        let serial_type = match value {
+            SendValue::Null => 0,
+            SendValue::Int(i) => {
+                let (serial_type, size) = match *i {
+                    -0x80..0x80 => (1, 1),
+                    -0x8000..0x8000 => (2, 2),
+                    -0x80_0000..0x80_0000 => (3, 3),
+                    -0x8000_0000..0x8000_0000 => (4, 4),
+                    -0x8000_0000_0000..0x8000_0000_0000 => (5, 6),
+                    _ => (6, 8),
+                };
+                body.extend(&i.to_be_bytes()[8 - size..]);
+                serial_type
+            }
+            SendValue::Float(f) => {
+                body.extend(f.to_be_bytes());
+                7
+            }
+            SendValue::Blob(b) => {
+                body.extend(b);
+                12 + 2 * b.len() as i64
+            }
+            SendValue::String(s) => {
+                body.extend(s.as_bytes());
+                13 + 2 * s.len() as i64
+            }
+        };
+        types.extend(varint(serial_type));
+    }
+
+    // The header size counts its own varint, which may take one more byte.
+    let mut header_size = types.len() + 1;
+    if varint(header_size as i64).len() > 1 {
+        header_size += 1;
+    }
+    [varint(header_size as i64), types, body].concat()
+}
+
+pub fn text(s: &str) -> SendValue {
+    SendValue::String(s.to_string())
+}
+
+/// A cell of the schema table, describing the table or index `name` rooted at `root`.
+/// An empty `sql` is stored as NULL, like the SQL of the indexes SQLite creates for
+/// constraints.
+pub fn schema_cell(
+    rowid: i64,
+    kind: &str,
+    name: &str,
+    table: &str,
+    root: i64,
+    sql: &str,
+) -> Vec<u8> {
+    let values = [
+        text(kind),
+        text(name),
+        text(table),
+        SendValue::Int(root),
+        match sql {
+            "" => SendValue::Null,
+            sql => text(sql),
+        },
+    ];
+    table_cell(rowid, &record(&values))
+}
+
+/// A cell of a table leaf page, holding a record small enough not to overflow.
+pub fn table_cell(rowid: i64, record: &[u8]) -> Vec<u8> {
+    [varint(record.len() as i64), varint(rowid), record.to_vec()].concat()
+}
+
+/// A cell of an index leaf page, holding a key small enough not to overflow.
+pub fn index_cell(key: &[u8]) -> Vec<u8> {
+    [varint(key.len() as i64), key.to_vec()].concat()
+}
+
+/// Writes a leaf page of the cells, whose header starts at `offset` into `page`.
+pub fn write_leaf(page: &mut [u8], offset: usize, page_type: u8, cells: &[Vec<u8>]) {
+    write_cells(page, offset, 8, page_type, cells);
+}
+
+/// Writes an interior page of the cells, pointing to `right_child` past them.
+fn write_interior(page: &mut [u8], page_type: u8, right_child: u32, cells: &[Vec<u8>]) {
+    page[8..12].copy_from_slice(&right_child.to_be_bytes());
+    write_cells(page, 0, 12, page_type, cells);
+}
+
+fn write_cells(
+    page: &mut [u8],
+    offset: usize,
+    header_size: usize,
+    page_type: u8,
+    cells: &[Vec<u8>],
+) {
+    page[offset] = page_type;
+    page[offset + 3..offset + 5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+    let mut content = page.len();
+    for (i, cell) in cells.iter().enumerate() {
+        content -= cell.len();
+        page[content..content + cell.len()].copy_from_slice(cell);
+        let pointer = offset + header_size + 2 * i;
+        page[pointer..pointer + 2].copy_from_slice(&(content as u16).to_be_bytes());
+    }
+    page[offset + 5..offset + 7].copy_from_slice(&(content as u16).to_be_bytes());
+}
+
+/// Appends a table b-tree of the records, by rowid, to the database `file`, returning
+/// its root page. Its leaves hold `fanout` cells and its interior pages point to
+/// `fanout` children, so that small tables span several levels.
+pub fn table_btree(
+    file: &mut Vec<u8>,
+    page_size: usize,
+    rows: &[(i64, Vec<u8>)],
+    fanout: usize,
+) -> u32 {
+    // The pages of a level, with the largest rowid under each of them.
+    let mut level = Vec::new();
+    for leaf in groups(rows, fanout) {
+        let cells: Vec<_> = (leaf.iter())
+            .map(|(rowid, record)| table_cell(*rowid, record))
+            .collect();
+        let page = append_page(file, page_size);
+        write_leaf(page_mut(file, page_size, page), 0, 0x0d, &cells);
+        level.push((page, leaf.last().map_or(0, |(rowid, _)| *rowid)));
+    }
+    while level.len() > 1 {
+        let children = std::mem::take(&mut level);
+        for group in groups(&children, fanout) {
+            let ((right_child, last_rowid), group) = group.split_last().unwrap();
+            let cells: Vec<_> = (group.iter())
+                .map(|(child, rowid)| [child.to_be_bytes().to_vec(), varint(*rowid)].concat())
+                .collect();
+            let page = append_page(file, page_size);
+            write_interior(page_mut(file, page_size, page), 0x05, *right_child, &cells);
+            level.push((page, *last_rowid));
+        }
+    }
+    level[0].0
+}
+
+/// Appends an index b-tree of the keys, in their order, to the database `file`,
+/// returning its root page. Like SQLite's, it holds each key once: the keys separating
+/// the children of an interior page are in its cells rather than in the leaves.
+pub fn index_btree(file: &mut Vec<u8>, page_size: usize, keys: &[Vec<u8>], fanout: usize) -> u32 {
+    // The pages of a level, each followed by the key separating it from the next one.
+    let mut level = Vec::new();
+    let mut rest = keys;
+    loop {
+        let (leaf, remaining) = rest.split_at(group_size(rest.len(), fanout));
+        let cells: Vec<_> = leaf.iter().map(|key| index_cell(key)).collect();
+        let page = append_page(file, page_size);
+        write_leaf(page_mut(file, page_size, page), 0, 0x0a, &cells);
+        let Some((separator, remaining)) = remaining.split_first() else {
+            level.push((page, None));
+            break;
+        };
+        level.push((page, Some(separator)));
+        rest = remaining;
+    }
+    while level.len() > 1 {
+        let children = std::mem::take(&mut level);
+        for group in groups(&children, fanout) {
+            let ((right_child, separator), group) = group.split_last().unwrap();
+            let cells: Vec<_> = (group.iter())
+                .map(|(child, key)| {
+                    [child.to_be_bytes().to_vec(), index_cell(key.unwrap())].concat()
+                })
+                .collect();
+            let page = append_page(file, page_size);
+            write_interior(page_mut(file, page_size, page), 0x02, *right_child, &cells);
+            level.push((page, *separator));
+        }
+    }
+    level[0].0
+}
+
+/// Splits the cells or children of a level between its pages, with `group_size`.
+fn groups<T>(mut items: &[T], fanout: usize) -> Vec<&[T]> {
+    let mut groups = Vec::new();
+    loop {
+        let (group, rest) = items.split_at(group_size(items.len(), fanout));
+        groups.push(group);
+        if rest.is_empty() {
+            return groups;
+        }
+        items = rest;
+    }
+}
+
+/// How many of the `remaining` cells or children of a level go to its next page:
+/// `fanout`, or all of them when that would leave a single one for another page.
+fn group_size(remaining: usize, fanout: usize) -> usize {
+    match remaining <= fanout + 1 {
+        true => remaining,
+        false => fanout,
+    }
+}
+
+/// Appends an empty page to the database `file`, counted by its header, returning the
+/// number of the page.
+fn append_page(file: &mut Vec<u8>, page_size: usize) -> u32 {
+    file.resize(file.len() + page_size, 0);
+    let page_count = (file.len() / page_size) as u32;
+    file[28..32].copy_from_slice(&page_count.to_be_bytes());
+    page_count
+}
+
+fn page_mut(file: &mut [u8], page_size: usize, page: u32) -> &mut [u8] {
+    let start = (page as usize - 1) * page_size;
+    &mut file[start..start + page_size]
+}
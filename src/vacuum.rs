@@ -0,0 +1,303 @@
+//! Vacuumed copies of databases: the pages in use are renumbered to follow each other,
+//! leaving out the free pages, and the pointers between them are rewritten.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, ensure};
+
+use crate::{
+    cursor::{self, RecordFieldType},
+    pager::{
+        self, HEADER_FREELIST_COUNT_OFFSET, HEADER_FREELIST_TRUNK_OFFSET,
+        HEADER_LARGEST_ROOT_PAGE_OFFSET, Pager,
+    },
+    ptrmap::{PtrmapEntry, PtrmapKind},
+    raw::{RawCell, RawPage, RawPageType},
+    writer::PageWriter,
+};
+
+/// What a page in use holds, and the page pointing to it.
+#[derive(Debug, Copy, Clone)]
+enum Role {
+    /// A page of a b-tree, its root when it has no parent. The records of the schema
+    /// table hold the root pages of the other b-trees.
+    Btree { parent: Option<usize>, schema: bool },
+    /// An overflow page, following the page of its cell when it is the first one of
+    /// the chain, else the previous overflow page.
+    Overflow { parent: usize, first: bool },
+}
+
+/// Copies the pages of the database read by `pager` to `w`, once renumbered. Returns
+/// the number of pages of the copy.
+pub fn copy(pager: &Pager, w: &mut PageWriter) -> anyhow::Result<usize> {
+    let roles = roles(pager)?;
+
+    // Like the original, the copy has pointer-map pages if it is in auto_vacuum mode,
+    // and no content on the lock-byte page.
+    let ptrmap = pager.ptrmap()?;
+    let lock_byte_page = pager::lock_byte_page(pager.header().page_size);
+    let mut numbers = HashMap::with_capacity(roles.len());
+    let mut next = 1;
+    for &n in roles.keys() {
+        while next == lock_byte_page || ptrmap.is_some_and(|ptrmap| ptrmap.is_ptrmap_page(next)) {
+            next += 1;
+        }
+        numbers.insert(n, next);
+        next += 1;
+    }
+    let number = |n: u32| -> anyhow::Result<u32> {
+        let new = numbers.get(&(n as usize));
+        Ok(*new.with_context(|| format!("page {n} is not in use"))? as u32)
+    };
+
+    let usable_size = pager.header().usable_page_size();
+    for (&n, &role) in &roles {
+        let mut data = pager.read_raw(n)?;
+        let entry = match role {
+            Role::Btree { parent, schema } => {
+                let page = RawPage::parse(data[..usable_size].to_vec(), n)
+                    .with_context(|| format!("parse page {n}"))?;
+                renumber_btree_page(&page, &mut data, schema, number)?;
+                match parent {
+                    Some(parent) => (PtrmapKind::Btree, number(parent as u32)?),
+                    None => (PtrmapKind::RootPage, 0),
+                }
+            }
+            Role::Overflow { parent, first } => {
+                let next = pager::read_be_double_at(&data, 0);
+                if next != 0 {
+                    data[..4].copy_from_slice(&number(next)?.to_be_bytes());
+                }
+                match first {
+                    true => (PtrmapKind::FirstOverflow, number(parent as u32)?),
+                    false => (PtrmapKind::Overflow, number(parent as u32)?),
+                }
+            }
+        };
+
+        if n == 1 {
+            // The free pages are left out, and the root pages renumbered.
+            data[HEADER_FREELIST_TRUNK_OFFSET..HEADER_FREELIST_COUNT_OFFSET + 4].fill(0);
+            let largest_root = pager::read_be_double_at(&data, HEADER_LARGEST_ROOT_PAGE_OFFSET);
+            if largest_root != 0 {
+                let offset = HEADER_LARGEST_ROOT_PAGE_OFFSET;
+                data[offset..offset + 4].copy_from_slice(&number(largest_root)?.to_be_bytes());
+            }
+        }
+        let new = number(n as u32)? as usize;
+        w.write_page(new, data)?;
+        if new != 1 {
+            let (kind, parent) = entry;
+            w.set_ptrmap_entry(new, PtrmapEntry { kind, parent })?;
+        }
+    }
+    Ok(next - 1)
+}
+
+/// The pages in use, reached from the schema table, and what they hold.
+fn roles(pager: &Pager) -> anyhow::Result<BTreeMap<usize, Role>> {
+    let mut roles = BTreeMap::new();
+    let schema = Role::Btree {
+        parent: None,
+        schema: true,
+    };
+    let mut pages = vec![(1, schema)];
+    while let Some((n, role)) = pages.pop() {
+        ensure!(
+            roles.insert(n, role).is_none(),
+            "page {n} is referenced twice"
+        );
+        let Role::Btree { schema, .. } = role else {
+            let next = pager::read_be_double_at(&pager.read_raw(n)?, 0) as usize;
+            if next != 0 {
+                pages.push((
+                    next,
+                    Role::Overflow {
+                        parent: n,
+                        first: false,
+                    },
+                ));
+            }
+            continue;
+        };
+
+        let page = RawPage::read(pager, n)?;
+        let page_type = page
+            .page_type
+            .with_context(|| format!("page {n} is not a b-tree page"))?;
+        let child = Role::Btree {
+            parent: Some(n),
+            schema,
+        };
+        pages.extend(page.rightmost_pointer.map(|right| (right as usize, child)));
+        for i in 0..page.cell_pointers.len() {
+            let cell = page.cell(i)?;
+            pages.extend(cell.left_child.map(|left| (left as usize, child)));
+            if let Some(first) = cell.first_overflow {
+                let first = first as usize;
+                pages.push((
+                    first,
+                    Role::Overflow {
+                        parent: n,
+                        first: true,
+                    },
+                ));
+            }
+            if schema && page_type == RawPageType::TableLeaf {
+                let (root, _, _) = schema_root(&page, &cell)?;
+                if root > 0 {
+                    let role = Role::Btree {
+                        parent: None,
+                        schema: false,
+                    };
+                    pages.push((root as usize, role));
+                }
+            }
+        }
+    }
+    Ok(roles)
+}
+
+/// Rewrites the pointers of the b-tree page `page` in its `data`, and the root pages of
+/// its records if it is a leaf of the schema table.
+fn renumber_btree_page(
+    page: &RawPage,
+    data: &mut [u8],
+    schema: bool,
+    number: impl Fn(u32) -> anyhow::Result<u32>,
+) -> anyhow::Result<()> {
+    let header = if page.number == 1 {
+        pager::HEADER_SIZE
+    } else {
+        0
+    };
+    if let Some(right) = page.rightmost_pointer {
+        data[header + 8..header + 12].copy_from_slice(&number(right)?.to_be_bytes());
+    }
+    for i in 0..page.cell_pointers.len() {
+        let cell = page.cell(i)?;
+        if let Some(left) = cell.left_child {
+            data[cell.offset..cell.offset + 4].copy_from_slice(&number(left)?.to_be_bytes());
+        }
+        if let Some(first) = cell.first_overflow {
+            let offset = payload_offset(page, &cell)? + cell.local_payload.len();
+            data[offset..offset + 4].copy_from_slice(&number(first)?.to_be_bytes());
+        }
+        if schema && page.page_type == Some(RawPageType::TableLeaf) {
+            let (root, offset, size) = schema_root(page, &cell)?;
+            if root > 0 {
+                // Pages are only renumbered down, so the root still fits its field.
+                let root = number(root as u32)? as i64;
+                data[offset..offset + size].copy_from_slice(&root.to_be_bytes()[8 - size..]);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The root page of the schema entry of `cell`, the fourth column of its record, and
+/// the offset and size of the field holding it on the page.
+fn schema_root(page: &RawPage, cell: &RawCell) -> anyhow::Result<(i64, usize, usize)> {
+    let payload = &cell.local_payload;
+    let header = cursor::parse_record_header(payload)?;
+    let field = header
+        .fields
+        .get(3)
+        .context("schema entry without a root page")?;
+    let (start, end) = (field.offset as usize, field.end_offset());
+    let bytes = payload
+        .get(start..end)
+        .context("the root page of a schema entry overflows")?;
+    let root = match field.field_type {
+        RecordFieldType::Null | RecordFieldType::Zero => 0,
+        RecordFieldType::I8
+        | RecordFieldType::I16
+        | RecordFieldType::I24
+        | RecordFieldType::I32
+        | RecordFieldType::I48
+        | RecordFieldType::I64 => bytes.iter().fold(0, |n, &b| n << 8 | b as i64),
+        _ => anyhow::bail!("invalid root page of a schema entry"),
+    };
+    Ok((root, payload_offset(page, cell)? + start, end - start))
+}
+
+/// Where the payload of `cell` starts on its page, past its child pointer and the sizes
+/// of its payload and rowid.
+fn payload_offset(page: &RawPage, cell: &RawCell) -> anyhow::Result<usize> {
+    let mut position = cell.offset + if cell.left_child.is_some() { 4 } else { 0 };
+    position += pager::read_payload_size_at(&page.data, position)?.0 as usize;
+    if cell.rowid.is_some() {
+        position += pager::read_varint_at(&page.data, position)?.0 as usize;
+    }
+    Ok(position)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        btree::{Btree, encode_record},
+        db::{BackupOptions, Db},
+        engine::KeyOrder,
+        testing::{schema_database, text},
+        value::{OwnedValue, SendValue},
+    };
+
+    fn rows(db: &Db, sql: &str) -> Vec<Vec<SendValue>> {
+        let mut query = db.query(sql).unwrap();
+        let mut rows = Vec::new();
+        while let Some(row) = query.next_row().unwrap() {
+            rows.push(row.iter().map(SendValue::from).collect());
+        }
+        rows
+    }
+
+    #[test]
+    fn vacuumed_copy() {
+        let db = schema_database(
+            "vacuum",
+            &[
+                ("table", "t", "t", "CREATE TABLE t(a)"),
+                ("index", "i", "t", "CREATE INDEX i ON t(a)"),
+            ],
+        );
+        let table = Btree::table(2);
+        let index = Btree::index(3, vec![KeyOrder::default()]);
+        // Some of the values overflow.
+        let value = |i: i64| {
+            let value = format!("{i:04}").repeat(if i % 7 == 0 { 200 } else { 3 });
+            OwnedValue::String(Rc::new(value))
+        };
+        db.write(|w| {
+            for i in 0..300 {
+                table.insert_row(w, i, &encode_record(&[value(i)], &w.header()))?;
+                index.insert_key(w, &[value(i), OwnedValue::Int(i)])?;
+            }
+            for i in (0..300).filter(|i| i % 3 != 0) {
+                table.delete_row(w, i)?;
+                index.delete_key(w, &[value(i), OwnedValue::Int(i)])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let free_pages = db.free_page_count().unwrap();
+        assert!(free_pages > 20);
+
+        let path = std::env::temp_dir().join(format!("rqlite-vacuum-{}", std::process::id()));
+        let options = BackupOptions {
+            vacuum: true,
+            ..BackupOptions::default()
+        };
+        let page_count = db.backup(&path, options).unwrap();
+        let copy = Db::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(page_count, db.pager().page_count().unwrap() - free_pages);
+        assert_eq!(copy.pager().page_count().unwrap(), page_count);
+        assert_eq!(copy.free_page_count().unwrap(), 0);
+        assert_eq!(rows(&copy, "PRAGMA integrity_check"), [[text("ok")]]);
+        let sql = "SELECT rowid, a FROM t";
+        assert_eq!(rows(&copy, sql), rows(&db, sql));
+        assert_eq!(rows(&copy, sql).len(), 100);
+    }
+}
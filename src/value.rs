@@ -1,4 +1,4 @@
-use std::{borrow::Cow, rc::Rc};
+use std::{borrow::Cow, cmp::Ordering, rc::Rc};
 
 #[derive(Debug, Clone)]
 pub enum Value<'p> {
@@ -27,7 +27,7 @@ impl Value<'_> {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum OwnedValue {
     Null,
     String(Rc<String>),
@@ -36,6 +36,370 @@ pub enum OwnedValue {
     Float(f64),
 }
 
+impl OwnedValue {
+    /// Orders values the way SQLite sorts them: NULLs first, then numbers, text and
+    /// blobs.
+    pub fn sql_cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (OwnedValue::Int(a), OwnedValue::Int(b)) => a.cmp(b),
+            (OwnedValue::Int(a), OwnedValue::Float(b)) => (*a as f64).total_cmp(b),
+            (OwnedValue::Float(a), OwnedValue::Int(b)) => a.total_cmp(&(*b as f64)),
+            (OwnedValue::Float(a), OwnedValue::Float(b)) => a.total_cmp(b),
+            (OwnedValue::String(a), OwnedValue::String(b)) => a.cmp(b),
+            (OwnedValue::Blob(a), OwnedValue::Blob(b)) => a.cmp(b),
+            (a, b) => a.type_rank().cmp(&b.type_rank()),
+        }
+    }
+
+    /// The value as a condition: NULL is unknown, numbers are true when nonzero, and
+    /// text and blobs when their numeric prefix is.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            OwnedValue::Null => None,
+            OwnedValue::Int(i) => Some(*i != 0),
+            value => Some(value.as_f64() != 0.0),
+        }
+    }
+
+    /// The value converted to a real number, NULL and non-numeric text being 0.
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            OwnedValue::Null => 0.0,
+            OwnedValue::Int(i) => *i as f64,
+            OwnedValue::Float(f) => *f,
+            OwnedValue::String(s) => numeric_prefix(s.as_bytes()),
+            OwnedValue::Blob(b) => numeric_prefix(b),
+        }
+    }
+
+    /// The value as a number: numbers, and text holding nothing but a number, which
+    /// is an integer when written as one.
+    pub fn as_number(&self) -> Option<OwnedValue> {
+        match self {
+            OwnedValue::Int(_) | OwnedValue::Float(_) => Some(self.clone()),
+            OwnedValue::String(s) => {
+                let text = s.trim();
+                if text.is_empty() || numeric_prefix_str(text) != text {
+                    return None;
+                }
+                match text.parse() {
+                    Ok(i) => Some(OwnedValue::Int(i)),
+                    Err(_) => text.parse().ok().map(OwnedValue::Float),
+                }
+            }
+            OwnedValue::Null | OwnedValue::Blob(_) => None,
+        }
+    }
+
+    /// Converts the value the way SQLite does when storing it in a column of the given
+    /// affinity: only when the conversion is lossless, e.g. text to a number when the
+    /// text holds nothing but a number.
+    pub fn apply_affinity(&self, affinity: Affinity) -> OwnedValue {
+        match (affinity, self) {
+            (Affinity::Blob, _) | (_, OwnedValue::Null | OwnedValue::Blob(_)) => self.clone(),
+            (Affinity::Text, OwnedValue::String(_)) => self.clone(),
+            (Affinity::Text, _) => self.cast(Affinity::Text),
+            (Affinity::Real, value) => match value.as_number() {
+                Some(number) => OwnedValue::Float(number.as_f64()),
+                None => self.clone(),
+            },
+            (Affinity::Integer | Affinity::Numeric, value) => match value.as_number() {
+                Some(OwnedValue::Float(f))
+                    if f.fract() == 0.0
+                        && (-9.223_372_036_854_776e18..9.223_372_036_854_776e18).contains(&f) =>
+                {
+                    OwnedValue::Int(f as i64)
+                }
+                Some(number) => number,
+                None => self.clone(),
+            },
+        }
+    }
+
+    /// Converts the value the way `CAST(value AS type)` does for a type of the given
+    /// affinity.
+    pub fn cast(&self, affinity: Affinity) -> OwnedValue {
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+        match (affinity, self) {
+            (_, OwnedValue::Null) => OwnedValue::Null,
+            (Affinity::Text, OwnedValue::String(_))
+            | (Affinity::Blob, OwnedValue::Blob(_))
+            | (Affinity::Integer, OwnedValue::Int(_))
+            | (Affinity::Real, OwnedValue::Float(_))
+            | (Affinity::Numeric, OwnedValue::Int(_) | OwnedValue::Float(_)) => self.clone(),
+            (Affinity::Text, OwnedValue::Blob(b)) => OwnedValue::String(Rc::new(text(b))),
+            (Affinity::Text, OwnedValue::Int(i)) => OwnedValue::String(Rc::new(i.to_string())),
+            (Affinity::Text, OwnedValue::Float(f)) => OwnedValue::String(Rc::new(format_real(*f))),
+            (Affinity::Blob, value) => {
+                let OwnedValue::String(s) = value.cast(Affinity::Text) else {
+                    unreachable!()
+                };
+                OwnedValue::Blob(Rc::new(s.as_bytes().to_vec()))
+            }
+            // Reals are truncated and saturate at the bounds of integers.
+            (Affinity::Integer, OwnedValue::Float(f)) => OwnedValue::Int(*f as i64),
+            (Affinity::Integer, OwnedValue::String(s)) => OwnedValue::Int(integer_prefix(s)),
+            (Affinity::Integer, OwnedValue::Blob(b)) => OwnedValue::Int(integer_prefix(&text(b))),
+            (Affinity::Real, OwnedValue::Int(i)) => OwnedValue::Float(*i as f64),
+            (Affinity::Real, value) => OwnedValue::Float(value.as_f64()),
+            (Affinity::Numeric, OwnedValue::String(s)) => text_to_numeric(s),
+            (Affinity::Numeric, OwnedValue::Blob(b)) => text_to_numeric(&text(b)),
+        }
+    }
+
+    fn type_rank(&self) -> u8 {
+        match self {
+            OwnedValue::Null => 0,
+            OwnedValue::Int(_) | OwnedValue::Float(_) => 1,
+            OwnedValue::String(_) => 2,
+            OwnedValue::Blob(_) => 3,
+        }
+    }
+
+    /// Overwrites the value with `value`, reusing the string or blob allocation when
+    /// nothing else holds it, so that row buffers don't allocate for every row.
+    pub fn set(&mut self, value: Value<'_>) {
+        match (&mut *self, &value) {
+            (OwnedValue::String(buffer), Value::String(s)) => {
+                if let Some(buffer) = Rc::get_mut(buffer) {
+                    buffer.clear();
+                    buffer.push_str(s);
+                    return;
+                }
+            }
+            (OwnedValue::Blob(buffer), Value::Blob(b)) => {
+                if let Some(buffer) = Rc::get_mut(buffer) {
+                    buffer.clear();
+                    buffer.extend_from_slice(b);
+                    return;
+                }
+            }
+            _ => {}
+        }
+
+        *self = value.into();
+    }
+}
+
+/// The type a value is converted to by a CAST or when stored in a column, derived
+/// from the name of the type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Affinity {
+    Text,
+    Numeric,
+    Integer,
+    Real,
+    Blob,
+}
+
+impl Affinity {
+    /// Follows SQLite's rules, which look for substrings of the name in order so
+    /// that any name maps to an affinity, e.g. `VARCHAR(10)` to TEXT.
+    pub fn from_type_name(name: &str) -> Self {
+        let name = name.to_ascii_uppercase();
+        let contains = |parts: &[&str]| parts.iter().any(|part| name.contains(part));
+        if contains(&["INT"]) {
+            Affinity::Integer
+        } else if contains(&["CHAR", "CLOB", "TEXT"]) {
+            Affinity::Text
+        } else if contains(&["BLOB"]) || name.is_empty() {
+            Affinity::Blob
+        } else if contains(&["REAL", "FLOA", "DOUB"]) {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+
+    pub fn is_numeric(self) -> bool {
+        matches!(self, Affinity::Integer | Affinity::Real | Affinity::Numeric)
+    }
+}
+
+/// How the case of letters is changed, by text functions and case-insensitive
+/// comparisons. SQLite only folds ASCII letters, unless built with ICU.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CaseFolding {
+    #[default]
+    Ascii,
+    Unicode,
+}
+
+/// One of SQLite's built-in collating sequences, deciding how text is compared.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Collation {
+    #[default]
+    Binary,
+    /// Folds letters to lowercase. Indexes are ordered by SQLite's, folding ASCII ones.
+    NoCase(CaseFolding),
+    /// Ignores trailing spaces.
+    RTrim,
+}
+
+impl Collation {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "binary" => Some(Collation::Binary),
+            "nocase" => Some(Collation::NoCase(CaseFolding::Ascii)),
+            "rtrim" => Some(Collation::RTrim),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Collation::Binary => "BINARY",
+            Collation::NoCase(_) => "NOCASE",
+            Collation::RTrim => "RTRIM",
+        }
+    }
+
+    /// The collation, with NOCASE folding letters as `case_folding` does.
+    pub fn with_case_folding(self, case_folding: CaseFolding) -> Self {
+        match self {
+            Collation::NoCase(_) => Collation::NoCase(case_folding),
+            collation => collation,
+        }
+    }
+
+    /// Orders values like [`OwnedValue::sql_cmp`], comparing text with the collation.
+    pub fn compare(&self, a: &OwnedValue, b: &OwnedValue) -> Ordering {
+        match (self, a, b) {
+            (
+                Collation::NoCase(CaseFolding::Ascii),
+                OwnedValue::String(a),
+                OwnedValue::String(b),
+            ) => a
+                .bytes()
+                .map(|c| c.to_ascii_lowercase())
+                .cmp(b.bytes().map(|c| c.to_ascii_lowercase())),
+            (
+                Collation::NoCase(CaseFolding::Unicode),
+                OwnedValue::String(a),
+                OwnedValue::String(b),
+            ) => a
+                .chars()
+                .flat_map(char::to_lowercase)
+                .cmp(b.chars().flat_map(char::to_lowercase)),
+            (Collation::RTrim, OwnedValue::String(a), OwnedValue::String(b)) => {
+                a.trim_end_matches(' ').cmp(b.trim_end_matches(' '))
+            }
+            _ => a.sql_cmp(b),
+        }
+    }
+}
+
+/// Formats a real number like SQLite's `%!.15g`: 15 significant digits, always
+/// with a decimal point, and in exponent notation when very large or small.
+pub fn format_real(x: f64) -> String {
+    if x.is_infinite() {
+        return if x > 0.0 { "Inf" } else { "-Inf" }.to_string();
+    }
+    if x == 0.0 {
+        return "0.0".to_string();
+    }
+
+    let scientific = format!("{:.14e}", x.abs());
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let digits = mantissa.replace('.', "");
+    let digits = digits.trim_end_matches('0');
+    let sign = if x < 0.0 { "-" } else { "" };
+
+    if !(-4..15).contains(&exponent) {
+        let fraction = if digits.len() > 1 { &digits[1..] } else { "0" };
+        let exponent_sign = if exponent < 0 { '-' } else { '+' };
+        format!(
+            "{sign}{}.{fraction}e{exponent_sign}{:02}",
+            &digits[..1],
+            exponent.abs()
+        )
+    } else if exponent < 0 {
+        let zeros = "0".repeat(exponent.unsigned_abs() as usize - 1);
+        format!("{sign}0.{zeros}{digits}")
+    } else {
+        let integer_digits = exponent as usize + 1;
+        if digits.len() <= integer_digits {
+            let zeros = "0".repeat(integer_digits - digits.len());
+            format!("{sign}{digits}{zeros}.0")
+        } else {
+            let (integer, fraction) = digits.split_at(integer_digits);
+            format!("{sign}{integer}.{fraction}")
+        }
+    }
+}
+
+/// The integer at the start of `text`, after leading spaces, saturating at the
+/// bounds of integers, or 0.
+fn integer_prefix(text: &str) -> i64 {
+    let text = text.trim_start();
+    let (negative, text) = match text.as_bytes().first() {
+        Some(b'-') => (true, &text[1..]),
+        Some(b'+') => (false, &text[1..]),
+        _ => (false, text),
+    };
+
+    let mut value: i64 = 0;
+    for digit in text.bytes().take_while(u8::is_ascii_digit) {
+        let digit = (digit - b'0') as i64;
+        value = match negative {
+            false => value.saturating_mul(10).saturating_add(digit),
+            true => value.saturating_mul(10).saturating_sub(digit),
+        };
+    }
+    value
+}
+
+/// The number at the start of `text` as an integer when it is written as one, or
+/// is a real that an integer represents exactly, and as a real otherwise.
+fn text_to_numeric(text: &str) -> OwnedValue {
+    let prefix = numeric_prefix_str(text);
+    if let Ok(i) = prefix.parse() {
+        return OwnedValue::Int(i);
+    }
+
+    // Like SQLite, only reals well within the range of doubles' exact integers
+    // become integers.
+    const EXACT_LIMIT: f64 = (1u64 << 51) as f64;
+    let real: f64 = prefix.parse().unwrap_or(0.0);
+    if real.fract() == 0.0 && real.abs() < EXACT_LIMIT {
+        OwnedValue::Int(real as i64)
+    } else {
+        OwnedValue::Float(real)
+    }
+}
+
+/// The number at the start of `bytes`, after leading spaces, or 0.
+fn numeric_prefix(bytes: &[u8]) -> f64 {
+    numeric_prefix_str(&String::from_utf8_lossy(bytes))
+        .parse()
+        .unwrap_or(0.0)
+}
+
+/// The text of the number at the start of `text`, after leading spaces.
+fn numeric_prefix_str(text: &str) -> &str {
+    let text = text.trim_start();
+    let mut end = 0;
+    let mut seen_digit = false;
+    let mut seen_dot = false;
+    let mut seen_exponent = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '+' | '-' if i == 0 => {}
+            '0'..='9' => {
+                seen_digit = true;
+                end = i + 1;
+                continue;
+            }
+            '.' if !seen_dot && !seen_exponent => seen_dot = true,
+            'e' | 'E' if seen_digit && !seen_exponent => seen_exponent = true,
+            '+' | '-' if text[..i].ends_with(['e', 'E']) => {}
+            _ => break,
+        }
+    }
+    &text[..end]
+}
+
 impl<'p> From<Value<'p>> for OwnedValue {
     fn from(value: Value<'p>) -> Self {
         match value {
@@ -48,6 +412,40 @@ impl<'p> From<Value<'p>> for OwnedValue {
     }
 }
 
+/// A value that can cross threads, unlike the `Rc`-backed [`OwnedValue`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum SendValue {
+    Null,
+    String(String),
+    Blob(Vec<u8>),
+    Int(i64),
+    Float(f64),
+}
+
+impl From<&OwnedValue> for SendValue {
+    fn from(value: &OwnedValue) -> Self {
+        match value {
+            OwnedValue::Null => SendValue::Null,
+            OwnedValue::String(s) => SendValue::String(s.as_ref().clone()),
+            OwnedValue::Blob(b) => SendValue::Blob(b.as_ref().clone()),
+            OwnedValue::Int(i) => SendValue::Int(*i),
+            OwnedValue::Float(f) => SendValue::Float(*f),
+        }
+    }
+}
+
+impl From<SendValue> for OwnedValue {
+    fn from(value: SendValue) -> Self {
+        match value {
+            SendValue::Null => OwnedValue::Null,
+            SendValue::String(s) => OwnedValue::String(Rc::new(s)),
+            SendValue::Blob(b) => OwnedValue::Blob(Rc::new(b)),
+            SendValue::Int(i) => OwnedValue::Int(i),
+            SendValue::Float(f) => OwnedValue::Float(f),
+        }
+    }
+}
+
 impl std::fmt::Display for OwnedValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -68,3 +466,175 @@ impl std::fmt::Display for OwnedValue {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truthiness() {
+        let text = |s: &str| OwnedValue::String(Rc::new(s.to_string()));
+        assert_eq!(OwnedValue::Null.as_bool(), None);
+        assert_eq!(OwnedValue::Int(0).as_bool(), Some(false));
+        assert_eq!(OwnedValue::Float(0.5).as_bool(), Some(true));
+        assert_eq!(text(" 12abc").as_bool(), Some(true));
+        assert_eq!(text("0.0e5x").as_bool(), Some(false));
+        assert_eq!(text("1e").as_bool(), Some(true));
+        assert_eq!(text("abc").as_bool(), Some(false));
+    }
+
+    #[test]
+    fn collations() {
+        let text = |s: &str| OwnedValue::String(Rc::new(s.to_string()));
+        let nocase = Collation::from_name("NoCase").unwrap();
+
+        assert_eq!(nocase.compare(&text("Abc"), &text("aBC")), Ordering::Equal);
+        assert_eq!(nocase.compare(&text("a"), &text("B")), Ordering::Less);
+        assert_eq!(
+            Collation::Binary.compare(&text("a"), &text("B")),
+            Ordering::Greater
+        );
+        assert_eq!(nocase.compare(&text("É"), &text("é")), Ordering::Less);
+        let unicode = nocase.with_case_folding(CaseFolding::Unicode);
+        assert_eq!(unicode.compare(&text("Éa"), &text("éA")), Ordering::Equal);
+        assert_eq!(unicode.compare(&text("É"), &text("f")), Ordering::Greater);
+        assert_eq!(
+            Collation::Binary.with_case_folding(CaseFolding::Unicode),
+            Collation::Binary
+        );
+        assert_eq!(
+            Collation::RTrim.compare(&text("x  "), &text("x")),
+            Ordering::Equal
+        );
+        assert_eq!(
+            nocase.compare(&OwnedValue::Int(1), &text("1")),
+            Ordering::Less
+        );
+        assert_eq!(Collation::from_name("french"), None);
+    }
+
+    #[test]
+    fn affinities() {
+        let text = |s: &str| OwnedValue::String(Rc::new(s.to_string()));
+
+        assert_eq!(
+            text(" 12 ").apply_affinity(Affinity::Integer),
+            OwnedValue::Int(12)
+        );
+        assert_eq!(
+            text("12abc").apply_affinity(Affinity::Numeric),
+            text("12abc")
+        );
+        assert_eq!(
+            OwnedValue::Float(3.0).apply_affinity(Affinity::Numeric),
+            OwnedValue::Int(3)
+        );
+        assert_eq!(
+            OwnedValue::Int(2).apply_affinity(Affinity::Real),
+            OwnedValue::Float(2.0)
+        );
+        assert_eq!(OwnedValue::Int(2).apply_affinity(Affinity::Text), text("2"));
+        assert_eq!(text("2").apply_affinity(Affinity::Blob), text("2"));
+    }
+
+    #[test]
+    fn casts() {
+        let text = |s: &str| OwnedValue::String(Rc::new(s.to_string()));
+        let cast = |value: &OwnedValue, type_name| value.cast(Affinity::from_type_name(type_name));
+
+        assert_eq!(cast(&text(" -12.9abc"), "int"), OwnedValue::Int(-12));
+        assert_eq!(cast(&text("1e3"), "integer"), OwnedValue::Int(1));
+        assert_eq!(
+            cast(&text("99999999999999999999"), "integer"),
+            OwnedValue::Int(i64::MAX)
+        );
+        assert_eq!(
+            cast(&OwnedValue::Float(-1e30), "bigint"),
+            OwnedValue::Int(i64::MIN)
+        );
+        assert_eq!(cast(&text(".5x"), "double"), OwnedValue::Float(0.5));
+        assert_eq!(cast(&text("3.0"), "numeric"), OwnedValue::Int(3));
+        assert_eq!(cast(&text("3.5e2"), "decimal(5)"), OwnedValue::Int(350));
+        assert_eq!(cast(&text("4.5e15"), "numeric"), OwnedValue::Float(4.5e15));
+        assert_eq!(cast(&text("12.5abc"), "numeric"), OwnedValue::Float(12.5));
+        assert_eq!(
+            cast(&OwnedValue::Float(3.0), "numeric"),
+            OwnedValue::Float(3.0)
+        );
+        assert_eq!(cast(&OwnedValue::Float(1.5), "varchar(3)"), text("1.5"));
+        assert_eq!(
+            cast(&OwnedValue::Int(12), "blob"),
+            OwnedValue::Blob(Rc::new(b"12".to_vec()))
+        );
+        assert_eq!(
+            cast(&OwnedValue::Blob(Rc::new(b"7".to_vec())), "integer"),
+            OwnedValue::Int(7)
+        );
+        assert_eq!(cast(&OwnedValue::Null, "text"), OwnedValue::Null);
+    }
+
+    #[test]
+    fn real_formatting() {
+        assert_eq!(format_real(100.0), "100.0");
+        assert_eq!(format_real(0.1), "0.1");
+        assert_eq!(format_real(0.0025), "0.0025");
+        assert_eq!(format_real(-0.0), "0.0");
+        assert_eq!(format_real(2.0 / 3.0), "0.666666666666667");
+        assert_eq!(format_real(123456789012345.0), "123456789012345.0");
+        assert_eq!(format_real(1e15), "1.0e+15");
+        assert_eq!(format_real(-2.5e-5), "-2.5e-05");
+        assert_eq!(format_real(1e100), "1.0e+100");
+        assert_eq!(format_real(f64::NEG_INFINITY), "-Inf");
+    }
+
+    #[test]
+    fn set_reuses_unshared_buffers() {
+        let mut value = OwnedValue::String(Rc::new(String::with_capacity(16)));
+        let OwnedValue::String(buffer) = &value else {
+            unreachable!()
+        };
+        let ptr = buffer.as_ptr();
+
+        value.set(Value::String("hello".into()));
+        assert_eq!(value, OwnedValue::String(Rc::new("hello".to_string())));
+        let OwnedValue::String(buffer) = &value else {
+            unreachable!()
+        };
+        assert_eq!(buffer.as_ptr(), ptr);
+
+        let shared = value.clone();
+        value.set(Value::String("world".into()));
+        assert_eq!(shared, OwnedValue::String(Rc::new("hello".to_string())));
+        assert_eq!(value, OwnedValue::String(Rc::new("world".to_string())));
+
+        value.set(Value::Int(3));
+        assert_eq!(value, OwnedValue::Int(3));
+    }
+
+    #[test]
+    fn sql_ordering() {
+        let mut values = vec![
+            OwnedValue::Blob(Rc::new(vec![0])),
+            OwnedValue::String(Rc::new("b".to_string())),
+            OwnedValue::Float(2.5),
+            OwnedValue::String(Rc::new("a".to_string())),
+            OwnedValue::Int(3),
+            OwnedValue::Null,
+            OwnedValue::Int(-1),
+        ];
+        values.sort_by(OwnedValue::sql_cmp);
+
+        assert_eq!(
+            values,
+            vec![
+                OwnedValue::Null,
+                OwnedValue::Int(-1),
+                OwnedValue::Float(2.5),
+                OwnedValue::Int(3),
+                OwnedValue::String(Rc::new("a".to_string())),
+                OwnedValue::String(Rc::new("b".to_string())),
+                OwnedValue::Blob(Rc::new(vec![0])),
+            ]
+        );
+    }
+}
@@ -0,0 +1,361 @@
+//! Access to databases stored in the zstd seekable format, where the file is a
+//! sequence of independent zstd frames (typically one per page) followed by a seek
+//! table in a skippable frame.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use anyhow::{Context, bail, ensure};
+
+const ZSTD_FRAME_MAGIC: u32 = 0xFD2FB528;
+const SKIPPABLE_FRAME_MAGIC: u32 = 0x184D2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+
+const SKIPPABLE_HEADER_SIZE: u64 = 8;
+const SEEK_TABLE_FOOTER_SIZE: u64 = 9;
+const SEEK_TABLE_CHECKSUM_FLAG: u8 = 0b1000_0000;
+
+#[derive(Debug, Copy, Clone)]
+struct Frame {
+    compressed_offset: u64,
+    compressed_size: u32,
+    decompressed_offset: u64,
+    decompressed_size: u32,
+}
+
+impl Frame {
+    fn decompressed_end(&self) -> u64 {
+        self.decompressed_offset + self.decompressed_size as u64
+    }
+}
+
+#[derive(Debug)]
+pub struct ZstdPageFile<R> {
+    input: R,
+    frames: Vec<Frame>,
+    len: u64,
+    position: u64,
+    current_frame: Option<(usize, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> ZstdPageFile<R> {
+    pub fn new(mut input: R) -> anyhow::Result<Self> {
+        let frames = read_seek_table(&mut input)?;
+        let len = frames.last().map(Frame::decompressed_end).unwrap_or(0);
+
+        Ok(Self {
+            input,
+            frames,
+            len,
+            position: 0,
+            current_frame: None,
+        })
+    }
+
+    fn frame_data(&mut self, index: usize) -> io::Result<&[u8]> {
+        if self.current_frame.as_ref().map(|(i, _)| *i) != Some(index) {
+            let frame = self.frames[index];
+            let mut compressed = vec![0; frame.compressed_size as usize];
+            self.input.seek(SeekFrom::Start(frame.compressed_offset))?;
+            self.input.read_exact(&mut compressed)?;
+
+            let data = zstd::bulk::decompress(&compressed, frame.decompressed_size as usize)?;
+            if data.len() != frame.decompressed_size as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupted zstd frame {index}"),
+                ));
+            }
+
+            self.current_frame = Some((index, data));
+        }
+
+        Ok(self
+            .current_frame
+            .as_ref()
+            .map(|(_, d)| d.as_slice())
+            .unwrap())
+    }
+
+    /// Writes the `page_count` pages of `page_size` bytes of the file to `output`, a
+    /// frame per page, with `pages` replacing its pages by number. The frames of the
+    /// other pages are copied as they are when they hold a single page.
+    pub fn write_pages(
+        &mut self,
+        mut output: impl Write,
+        page_size: usize,
+        page_count: usize,
+        pages: &BTreeMap<usize, Vec<u8>>,
+    ) -> anyhow::Result<()> {
+        let mut entries = Vec::with_capacity(page_count * 8);
+        let mut page = Vec::with_capacity(page_size);
+        for n in 1..=page_count {
+            let start = ((n - 1) * page_size) as u64;
+            let index = self
+                .frames
+                .partition_point(|f| f.decompressed_end() <= start);
+            let frame = match self.frames.get(index) {
+                Some(frame)
+                    if !pages.contains_key(&n)
+                        && frame.decompressed_offset == start
+                        && frame.decompressed_size as usize == page_size =>
+                {
+                    let mut compressed = vec![0; frame.compressed_size as usize];
+                    self.input.seek(SeekFrom::Start(frame.compressed_offset))?;
+                    self.input
+                        .read_exact(&mut compressed)
+                        .with_context(|| format!("read the frame of page {n}"))?;
+                    compressed
+                }
+                _ => {
+                    let data = match pages.get(&n) {
+                        Some(data) => data,
+                        None => {
+                            // Pages past the end of the file are zeroed.
+                            page.clear();
+                            self.seek(SeekFrom::Start(start))?;
+                            self.by_ref()
+                                .take(page_size as u64)
+                                .read_to_end(&mut page)
+                                .with_context(|| format!("read page {n}"))?;
+                            page.resize(page_size, 0);
+                            &page
+                        }
+                    };
+                    zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL)
+                        .with_context(|| format!("compress page {n}"))?
+                }
+            };
+            output
+                .write_all(&frame)
+                .with_context(|| format!("write the frame of page {n}"))?;
+            entries.extend((frame.len() as u32).to_le_bytes());
+            entries.extend((page_size as u32).to_le_bytes());
+        }
+        write_seek_table(&mut output, &entries).context("write seek table")?;
+        output.flush().context("flush compressed pages")
+    }
+}
+
+impl<R: Read + Seek> Read for ZstdPageFile<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let position = self.position;
+        let index = self
+            .frames
+            .partition_point(|f| f.decompressed_end() <= position);
+        let frame_start = self.frames[index].decompressed_offset;
+
+        let data = self.frame_data(index)?;
+        let start = (position - frame_start) as usize;
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for ZstdPageFile<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.position.checked_add_signed(n),
+        };
+
+        let Some(position) = position else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            ));
+        };
+
+        self.position = position;
+        Ok(position)
+    }
+}
+
+pub fn is_seekable_zstd<R: Read + Seek>(input: &mut R) -> anyhow::Result<bool> {
+    let len = input
+        .seek(SeekFrom::End(0))
+        .context("seek to end of file")?;
+    if len < SEEK_TABLE_FOOTER_SIZE + 4 {
+        input.seek(SeekFrom::Start(0))?;
+        return Ok(false);
+    }
+
+    let mut magic = [0; 4];
+    input.seek(SeekFrom::Start(0))?;
+    input.read_exact(&mut magic)?;
+    let frame_magic = u32::from_le_bytes(magic);
+
+    input.seek(SeekFrom::End(-4))?;
+    input.read_exact(&mut magic)?;
+    let seekable_magic = u32::from_le_bytes(magic);
+
+    input.seek(SeekFrom::Start(0))?;
+
+    Ok(frame_magic == ZSTD_FRAME_MAGIC && seekable_magic == SEEKABLE_MAGIC)
+}
+
+fn read_seek_table<R: Read + Seek>(input: &mut R) -> anyhow::Result<Vec<Frame>> {
+    let mut footer = [0; SEEK_TABLE_FOOTER_SIZE as usize];
+    input
+        .seek(SeekFrom::End(-(SEEK_TABLE_FOOTER_SIZE as i64)))
+        .context("seek to seek table footer")?;
+    input
+        .read_exact(&mut footer)
+        .context("read seek table footer")?;
+
+    ensure!(
+        read_le_u32(&footer, 5) == SEEKABLE_MAGIC,
+        "missing zstd seek table"
+    );
+
+    let frame_count = read_le_u32(&footer, 0) as usize;
+    let descriptor = footer[4];
+    let entry_size = if descriptor & SEEK_TABLE_CHECKSUM_FLAG != 0 {
+        12
+    } else {
+        8
+    };
+
+    let table_size = (frame_count * entry_size) as u64;
+    let table_start = input
+        .seek(SeekFrom::End(
+            -((table_size + SEEK_TABLE_FOOTER_SIZE + SKIPPABLE_HEADER_SIZE) as i64),
+        ))
+        .context("seek to seek table")?;
+
+    let mut table = vec![0; (table_size + SKIPPABLE_HEADER_SIZE) as usize];
+    input.read_exact(&mut table).context("read seek table")?;
+
+    ensure!(
+        read_le_u32(&table, 0) == SKIPPABLE_FRAME_MAGIC,
+        "invalid seek table frame"
+    );
+    ensure!(
+        read_le_u32(&table, 4) as u64 == table_size + SEEK_TABLE_FOOTER_SIZE,
+        "invalid seek table size"
+    );
+
+    let mut frames = Vec::with_capacity(frame_count);
+    let mut compressed_offset = 0;
+    let mut decompressed_offset = 0;
+
+    for entry in table[SKIPPABLE_HEADER_SIZE as usize..].chunks_exact(entry_size) {
+        let compressed_size = read_le_u32(entry, 0);
+        let decompressed_size = read_le_u32(entry, 4);
+
+        frames.push(Frame {
+            compressed_offset,
+            compressed_size,
+            decompressed_offset,
+            decompressed_size,
+        });
+
+        compressed_offset += compressed_size as u64;
+        decompressed_offset += decompressed_size as u64;
+    }
+
+    if compressed_offset != table_start {
+        bail!("seek table does not match compressed frames");
+    }
+
+    Ok(frames)
+}
+
+/// Writes the seek table of the frames, whose `entries` are their compressed and
+/// decompressed sizes.
+fn write_seek_table(output: &mut impl Write, entries: &[u8]) -> io::Result<()> {
+    let frame_count = (entries.len() / 8) as u32;
+    output.write_all(&SKIPPABLE_FRAME_MAGIC.to_le_bytes())?;
+    output.write_all(&(entries.len() as u32 + SEEK_TABLE_FOOTER_SIZE as u32).to_le_bytes())?;
+    output.write_all(entries)?;
+    output.write_all(&frame_count.to_le_bytes())?;
+    output.write_all(&[0])?;
+    output.write_all(&SEEKABLE_MAGIC.to_le_bytes())
+}
+
+fn read_le_u32(input: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(input[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::testing::seekable_zstd;
+
+    fn sample() -> Vec<u8> {
+        (0..10_000u32).map(|i| (i % 97) as u8).collect()
+    }
+
+    #[test]
+    fn detect_seekable_format() {
+        let mut compressed = Cursor::new(seekable_zstd(&sample(), 512));
+        assert!(is_seekable_zstd(&mut compressed).unwrap());
+
+        let mut plain = Cursor::new(sample());
+        assert!(!is_seekable_zstd(&mut plain).unwrap());
+    }
+
+    #[test]
+    fn read_whole_file() {
+        let data = sample();
+        let mut file = ZstdPageFile::new(Cursor::new(seekable_zstd(&data, 512))).unwrap();
+
+        let mut output = Vec::new();
+        file.read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn read_across_frames() {
+        let data = sample();
+        let mut file = ZstdPageFile::new(Cursor::new(seekable_zstd(&data, 512))).unwrap();
+
+        let mut buffer = vec![0; 1000];
+        file.seek(SeekFrom::Start(1500)).unwrap();
+        file.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, &data[1500..2500]);
+
+        file.seek(SeekFrom::Start(10)).unwrap();
+        file.read_exact(&mut buffer[..10]).unwrap();
+        assert_eq!(&buffer[..10], &data[10..20]);
+    }
+
+    #[test]
+    fn write_pages() {
+        // Frames of 1000 bytes, rewritten as frames of 500-byte pages, past the end of
+        // the data too.
+        let data = sample();
+        let mut file = ZstdPageFile::new(Cursor::new(seekable_zstd(&data, 1000))).unwrap();
+        let pages = BTreeMap::from([(3, vec![3; 500]), (22, vec![22; 500])]);
+        let mut output = Vec::new();
+        file.write_pages(&mut output, 500, 22, &pages).unwrap();
+
+        let mut expected = data.clone();
+        expected[1000..1500].fill(3);
+        expected.resize(10_500, 0);
+        expected.extend([22; 500]);
+        let mut file = ZstdPageFile::new(Cursor::new(output)).unwrap();
+        assert_eq!(file.frames.len(), 22);
+        let mut written = Vec::new();
+        file.read_to_end(&mut written).unwrap();
+        assert_eq!(written, expected);
+
+        // The frames holding a single page are copied.
+        let mut output = Vec::new();
+        file.write_pages(&mut output, 500, 22, &BTreeMap::new())
+            .unwrap();
+        assert_eq!(output, file.input.into_inner());
+    }
+}
@@ -0,0 +1,541 @@
+//! The shared lock SQLite readers take on the database file. Writers following SQLite's
+//! locking protocol can't commit while it is held, so the pages read under it all
+//! belong to the same version of the database. It is taken with `fcntl` on unix and
+//! `LockFileEx` on Windows, on the same bytes as SQLite.
+//!
+//! A writer that crashed mid-transaction leaves a hot rollback journal holding the
+//! original content of the pages it overwrote. Taking the lock reads it, so that the
+//! pager can read those pages from it until SQLite rolls it back.
+//!
+//! Writers go through the same states as SQLite's: a reserved lock while the
+//! transaction is prepared alongside readers, then an exclusive lock to write the
+//! pages. File locks belong to the whole process, so the readers of other threads are
+//! waited for with a count of their own.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, anyhow, bail};
+
+use crate::journal::RollbackJournal;
+
+const PENDING_BYTE: i64 = 0x4000_0000;
+const SHARED_FIRST: i64 = PENDING_BYTE + 2;
+const SHARED_SIZE: i64 = 510;
+const RESERVED_BYTE: i64 = PENDING_BYTE + 1;
+
+/// How long to wait for a writer to finish before giving up.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy)]
+pub enum LockKind {
+    Read,
+    Write,
+    Unlock,
+}
+
+/// The lock readers briefly take on the pending byte. Windows has SQLite take it
+/// exclusively, as it did when shared locks on Windows 9x were single random bytes.
+const PENDING_LOCK: LockKind = if cfg!(windows) {
+    LockKind::Write
+} else {
+    LockKind::Read
+};
+
+#[derive(Debug)]
+pub struct FileLock {
+    /// Opened for writing when possible, as write locks need it. Writers write the
+    /// pages through it: POSIX locks are released when any descriptor of the file
+    /// is closed, so they can't open their own.
+    file: File,
+    journal: PathBuf,
+    /// The hot journal found when the first of the live shared locks was taken.
+    hot_journal: Mutex<Option<Arc<RollbackJournal>>>,
+    /// Live shared locks. POSIX locks belong to the whole process, so the file is only
+    /// unlocked when the last of them is dropped, and they don't keep the writers of
+    /// the process from writing: those wait for the count to go down instead.
+    readers: Mutex<Readers>,
+    /// Notified when a shared lock is dropped, or a writer is done waiting.
+    readers_changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Readers {
+    count: usize,
+    /// Whether a writer waits for the readers to finish, new ones waiting for it.
+    writer_pending: bool,
+}
+
+impl FileLock {
+    /// Locks `file`, the database at `path`.
+    pub fn new(file: File, path: impl Into<PathBuf>) -> Self {
+        let mut journal = path.into().into_os_string();
+        journal.push("-journal");
+
+        Self {
+            file,
+            journal: journal.into(),
+            hot_journal: Mutex::default(),
+            readers: Mutex::default(),
+            readers_changed: Condvar::new(),
+        }
+    }
+
+    /// Takes a shared lock, waiting for a writer holding the file to finish.
+    pub fn shared(self: &Arc<Self>) -> anyhow::Result<SharedLock> {
+        let mut readers = self.wait_for_readers(|readers| !readers.writer_pending)?;
+
+        if readers.count == 0 {
+            let started = Instant::now();
+            while !self.try_lock_shared()? {
+                if started.elapsed() >= LOCK_TIMEOUT {
+                    bail!("database is locked");
+                }
+                thread::sleep(RETRY_INTERVAL);
+            }
+
+            match self.read_hot_journal() {
+                Ok(journal) => {
+                    *self
+                        .hot_journal
+                        .lock()
+                        .map_err(|_| anyhow!("poisoned hot journal lock"))? = journal.map(Arc::new);
+                }
+                Err(e) => {
+                    let _ = self.set_lock(LockKind::Unlock, SHARED_FIRST, SHARED_SIZE);
+                    return Err(e);
+                }
+            }
+        }
+        readers.count += 1;
+
+        Ok(SharedLock { lock: self.clone() })
+    }
+
+    /// Waits for the readers of the process to be `ready`, returning them locked.
+    fn wait_for_readers(
+        &self,
+        ready: impl Fn(&Readers) -> bool,
+    ) -> anyhow::Result<MutexGuard<'_, Readers>> {
+        let readers = self
+            .readers
+            .lock()
+            .map_err(|_| anyhow!("poisoned file lock"))?;
+        let (readers, timeout) = self
+            .readers_changed
+            .wait_timeout_while(readers, LOCK_TIMEOUT, |readers| !ready(readers))
+            .map_err(|_| anyhow!("poisoned file lock"))?;
+        if timeout.timed_out() && !ready(&readers) {
+            bail!("database is locked");
+        }
+        Ok(readers)
+    }
+
+    /// Lets new readers in again once a writer is done or gave up.
+    fn release_pending_writer(&self) {
+        let mut readers = self.readers.lock().unwrap_or_else(|e| e.into_inner());
+        readers.writer_pending = false;
+        self.readers_changed.notify_all();
+    }
+
+    /// Follows SQLite: readers go through the pending byte so that a writer waiting
+    /// for the existing readers to finish isn't starved by new ones.
+    fn try_lock_shared(&self) -> anyhow::Result<bool> {
+        if !self.set_lock(PENDING_LOCK, PENDING_BYTE, 1)? {
+            return Ok(false);
+        }
+
+        let locked = self.set_lock(LockKind::Read, SHARED_FIRST, SHARED_SIZE);
+        self.set_lock(LockKind::Unlock, PENDING_BYTE, 1)?;
+        Ok(locked?)
+    }
+
+    /// Takes a shared lock, then the reserved lock, which keeps other writers out while
+    /// readers go on. While another writer holds the reserved lock, the shared lock is
+    /// let go of, so that the writer can commit, until it is done.
+    pub fn reserved(self: &Arc<Self>) -> anyhow::Result<(SharedLock, ReservedLock)> {
+        let started = Instant::now();
+        loop {
+            let shared = self.shared()?;
+            if self.set_lock(LockKind::Write, RESERVED_BYTE, 1)? {
+                let reserved = ReservedLock {
+                    lock: self.clone(),
+                    exclusive: false,
+                };
+                return Ok((shared, reserved));
+            }
+            drop(shared);
+            if started.elapsed() >= LOCK_TIMEOUT {
+                bail!("database is locked");
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+    }
+
+    /// Takes a lock, retrying while another connection holds a conflicting one.
+    fn wait_for(&self, kind: LockKind, start: i64, len: i64) -> anyhow::Result<()> {
+        let started = Instant::now();
+        while !self.set_lock(kind, start, len)? {
+            if started.elapsed() >= LOCK_TIMEOUT {
+                bail!("database is locked");
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+        Ok(())
+    }
+
+    /// The file, for the writer holding the reserved lock.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn journal_path(&self) -> &Path {
+        &self.journal
+    }
+
+    /// The journal of the transaction SQLite would roll back when opening the file.
+    pub fn hot_journal(&self) -> Option<Arc<RollbackJournal>> {
+        self.hot_journal
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Reads the journal left by an interrupted writer, if any. Like in SQLite, a
+    /// journal is only hot when it isn't empty or zeroed, no live writer holds the
+    /// reserved lock, and the database isn't empty.
+    fn read_hot_journal(&self) -> anyhow::Result<Option<RollbackJournal>> {
+        let mut journal = match File::open(&self.journal) {
+            Ok(journal) => journal,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("open {}", self.journal.display()));
+            }
+        };
+
+        let mut first = [0];
+        if journal.read(&mut first)? == 0
+            || first[0] == 0
+            || self.is_reserved()?
+            || self.file.metadata()?.len() == 0
+        {
+            return Ok(None);
+        }
+
+        RollbackJournal::read(&self.journal, journal)
+            .with_context(|| format!("read hot journal {}", self.journal.display()))
+    }
+
+    /// Whether another process holds the reserved lock, i.e. is writing a transaction.
+    #[cfg(unix)]
+    fn is_reserved(&self) -> io::Result<bool> {
+        use std::os::fd::AsRawFd;
+
+        // SAFETY: see `set_lock`.
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = libc::F_WRLCK as _;
+        lock.l_whence = libc::SEEK_SET as _;
+        lock.l_start = RESERVED_BYTE as _;
+        lock.l_len = 1;
+
+        // SAFETY: see `set_lock`.
+        if unsafe { libc::fcntl(self.file.as_raw_fd(), libc::F_GETLK, &mut lock) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(lock.l_type != libc::F_UNLCK as _)
+    }
+
+    /// Windows can't query locks, so like SQLite, this tries to take it.
+    #[cfg(windows)]
+    fn is_reserved(&self) -> io::Result<bool> {
+        if !self.set_lock(LockKind::Read, RESERVED_BYTE, 1)? {
+            return Ok(true);
+        }
+        self.set_lock(LockKind::Unlock, RESERVED_BYTE, 1)?;
+        Ok(false)
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn is_reserved(&self) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    fn set_lock(&self, kind: LockKind, start: i64, len: i64) -> io::Result<bool> {
+        set_lock(&self.file, kind, start, len)
+    }
+}
+
+/// Tries to take a `kind` lock on byte `offset` of `file`, e.g. on one of the locks of
+/// the shared-memory WAL index, returning false if another connection holds a
+/// conflicting one. Like the locks of the database file, it belongs to the whole
+/// process.
+pub fn lock_byte(file: &File, kind: LockKind, offset: i64) -> io::Result<bool> {
+    set_lock(file, kind, offset, 1)
+}
+
+#[cfg(unix)]
+fn set_lock(file: &File, kind: LockKind, start: i64, len: i64) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: `flock` is a plain C struct, for which all zeroes is a valid value.
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = match kind {
+        LockKind::Read => libc::F_RDLCK,
+        LockKind::Write => libc::F_WRLCK,
+        LockKind::Unlock => libc::F_UNLCK,
+    } as _;
+    lock.l_whence = libc::SEEK_SET as _;
+    lock.l_start = start as _;
+    lock.l_len = len as _;
+
+    // SAFETY: the descriptor is open for as long as `file` and `lock` outlives
+    // the call.
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) } == 0 {
+        return Ok(true);
+    }
+
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EAGAIN | libc::EACCES) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+#[cfg(windows)]
+fn set_lock(file: &File, kind: LockKind, start: i64, len: i64) -> io::Result<bool> {
+    use std::os::windows::io::AsRawHandle;
+
+    use windows_sys::Win32::{
+        Foundation::{ERROR_LOCK_VIOLATION, HANDLE},
+        Storage::FileSystem::{
+            LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY, LockFileEx, UnlockFileEx,
+        },
+        System::IO::OVERLAPPED,
+    };
+
+    // SAFETY: `OVERLAPPED` is a plain C struct, for which all zeroes is a valid value.
+    let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+    overlapped.Anonymous.Anonymous.Offset = start as u32;
+    overlapped.Anonymous.Anonymous.OffsetHigh = (start >> 32) as u32;
+    let handle = file.as_raw_handle() as HANDLE;
+
+    // SAFETY: the handle is open for as long as `file`, and `overlapped`
+    // outlives the call, which doesn't complete asynchronously on a file opened
+    // without FILE_FLAG_OVERLAPPED.
+    let locked = unsafe {
+        match kind {
+            LockKind::Unlock => UnlockFileEx(handle, 0, len as u32, 0, &mut overlapped),
+            LockKind::Read | LockKind::Write => {
+                let mut flags = LOCKFILE_FAIL_IMMEDIATELY;
+                if matches!(kind, LockKind::Write) {
+                    flags |= LOCKFILE_EXCLUSIVE_LOCK;
+                }
+                LockFileEx(handle, flags, 0, len as u32, 0, &mut overlapped)
+            }
+        }
+    };
+    if locked != 0 {
+        return Ok(true);
+    }
+
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(code) if code == ERROR_LOCK_VIOLATION as i32 => Ok(false),
+        _ => Err(error),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn set_lock(_file: &File, _kind: LockKind, _start: i64, _len: i64) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Keeps other writers from starting a transaction until dropped.
+#[derive(Debug)]
+pub struct ReservedLock {
+    lock: Arc<FileLock>,
+    exclusive: bool,
+}
+
+impl ReservedLock {
+    /// Waits for the readers to finish, keeping new ones out with the pending lock,
+    /// then locks them out until dropped, for the pages to be written. The readers of
+    /// the process are waited for until only the one of the writer is left.
+    pub fn exclusive(&mut self) -> anyhow::Result<()> {
+        let lock = &self.lock;
+        lock.readers
+            .lock()
+            .map_err(|_| anyhow!("poisoned file lock"))?
+            .writer_pending = true;
+        if let Err(e) = lock.wait_for_readers(|readers| readers.count <= 1) {
+            lock.release_pending_writer();
+            return Err(e);
+        }
+        if let Err(e) = lock.wait_for(LockKind::Write, PENDING_BYTE, 1) {
+            lock.release_pending_writer();
+            return Err(e);
+        }
+        // Windows doesn't upgrade locks, so the shared lock of the writer goes first.
+        if cfg!(windows) {
+            lock.set_lock(LockKind::Unlock, SHARED_FIRST, SHARED_SIZE)?;
+        }
+        if let Err(e) = lock.wait_for(LockKind::Write, SHARED_FIRST, SHARED_SIZE) {
+            let _ = lock.set_lock(LockKind::Read, SHARED_FIRST, SHARED_SIZE);
+            let _ = lock.set_lock(LockKind::Unlock, PENDING_BYTE, 1);
+            lock.release_pending_writer();
+            return Err(e);
+        }
+        self.exclusive = true;
+        Ok(())
+    }
+}
+
+impl Drop for ReservedLock {
+    fn drop(&mut self) {
+        let lock = &self.lock;
+        if self.exclusive {
+            // Back to the shared lock the writer still holds.
+            if cfg!(windows) {
+                let _ = lock.set_lock(LockKind::Unlock, SHARED_FIRST, SHARED_SIZE);
+            }
+            let _ = lock.set_lock(LockKind::Read, SHARED_FIRST, SHARED_SIZE);
+            let _ = lock.set_lock(LockKind::Unlock, PENDING_BYTE, 1);
+            lock.release_pending_writer();
+        }
+        let _ = lock.set_lock(LockKind::Unlock, RESERVED_BYTE, 1);
+    }
+}
+
+/// Keeps the database file locked for reading until dropped.
+#[derive(Debug)]
+pub struct SharedLock {
+    lock: Arc<FileLock>,
+}
+
+impl Drop for SharedLock {
+    fn drop(&mut self) {
+        let mut readers = self.lock.readers.lock().unwrap_or_else(|e| e.into_inner());
+        readers.count -= 1;
+        if readers.count == 0 {
+            let _ = self
+                .lock
+                .set_lock(LockKind::Unlock, SHARED_FIRST, SHARED_SIZE);
+        }
+        self.lock.readers_changed.notify_all();
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::os::fd::AsRawFd;
+
+    use super::*;
+
+    /// Whether a writer could take the exclusive lock. Open file description locks
+    /// conflict with the process' own POSIX locks, which lets a single process test it.
+    /// The file stays open, as closing it would release the POSIX locks.
+    fn can_write(file: &File) -> bool {
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = libc::F_WRLCK as _;
+        lock.l_whence = libc::SEEK_SET as _;
+        lock.l_start = SHARED_FIRST;
+        lock.l_len = SHARED_SIZE;
+
+        let result = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_GETLK, &mut lock) };
+        assert_eq!(result, 0);
+        lock.l_type == libc::F_UNLCK as _
+    }
+
+    #[test]
+    fn shared_until_last_reader() {
+        let path = std::env::temp_dir().join(format!("rqlite-lock-{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let writer = File::options().read(true).write(true).open(&path).unwrap();
+        let lock = Arc::new(FileLock::new(File::open(&path).unwrap(), &path));
+
+        let a = lock.shared().unwrap();
+        let b = lock.shared().unwrap();
+        assert!(!can_write(&writer));
+
+        drop(a);
+        assert!(!can_write(&writer));
+        drop(b);
+        assert!(can_write(&writer));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writer_waits_for_readers() {
+        let path = std::env::temp_dir().join(format!("rqlite-writer-{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let file = File::options().read(true).write(true).open(&path).unwrap();
+        let lock = Arc::new(FileLock::new(file, &path));
+
+        // The readers of other threads hold no lock of their own on the file.
+        let reader = lock.shared().unwrap();
+        let (shared, mut reserved) = lock.reserved().unwrap();
+        let reading = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            let done = Instant::now();
+            drop(reader);
+            done
+        });
+        reserved.exclusive().unwrap();
+        let written = Instant::now();
+        assert!(written >= reading.join().unwrap());
+
+        // New readers wait for the writer to be done.
+        let waiting = thread::spawn({
+            let lock = lock.clone();
+            move || {
+                let _shared = lock.shared().unwrap();
+                Instant::now()
+            }
+        });
+        thread::sleep(Duration::from_millis(100));
+        let done = Instant::now();
+        drop(reserved);
+        assert!(waiting.join().unwrap() >= done);
+        drop(shared);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn hot_journal() {
+        let path = std::env::temp_dir().join(format!("rqlite-journal-{}", std::process::id()));
+        let journal = path.with_file_name(format!(
+            "{}-journal",
+            path.file_name().unwrap().to_str().unwrap()
+        ));
+        std::fs::write(&path, b"SQLite format 3\0").unwrap();
+        let lock = Arc::new(FileLock::new(File::open(&path).unwrap(), &path));
+
+        std::fs::write(&journal, [0; 28]).unwrap();
+        drop(lock.shared().unwrap());
+        assert!(lock.hot_journal().is_none());
+
+        // The header of a transaction on a database of 3 pages of 512 bytes.
+        let mut header = vec![0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+        for word in [0u32, 0, 3, 512, 512] {
+            header.extend(word.to_be_bytes());
+        }
+        std::fs::write(&journal, header).unwrap();
+        let shared = lock.shared().unwrap();
+        assert_eq!(lock.hot_journal().unwrap().db_size(), 3);
+        drop(shared);
+
+        std::fs::remove_file(&journal).unwrap();
+        drop(lock.shared().unwrap());
+        assert!(lock.hot_journal().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
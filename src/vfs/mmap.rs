@@ -0,0 +1,167 @@
+//! A read-only memory map of a database file, from which the pager parses pages in
+//! place rather than copying them out of the file under its mutex.
+//!
+//! The map covers the file as it was on the last refresh, and pages past its end are
+//! read from the file. Readers hold the shared lock, so SQLite writers in rollback mode
+//! can't shrink the file while a query reads it, but a checkpoint of a database in WAL
+//! mode vacuumed since the last refresh could, in which case reading the map crashes.
+
+use std::{
+    fs::File,
+    io,
+    path::Path,
+    sync::{RwLock, RwLockReadGuard},
+};
+
+use anyhow::{Context, anyhow, bail};
+
+use super::compressed;
+
+#[derive(Debug)]
+pub struct Mmap {
+    file: File,
+    mapping: RwLock<Mapping>,
+}
+
+impl Mmap {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Mmap> {
+        let mut file = File::open(path.as_ref()).context("open db file")?;
+        if compressed::is_seekable_zstd(&mut file)? {
+            bail!("compressed db files can't be memory-mapped");
+        }
+        let mapping = Mapping::new(&file).context("map db file")?;
+        Ok(Mmap {
+            file,
+            mapping: RwLock::new(mapping),
+        })
+    }
+
+    /// Maps the file again if its size changed since it was mapped.
+    pub fn refresh(&self) -> anyhow::Result<()> {
+        let len = self.file.metadata().context("stat db file")?.len() as usize;
+        if len == self.mapping()?.len {
+            return Ok(());
+        }
+        let mut mapping = self
+            .mapping
+            .write()
+            .map_err(|_| anyhow!("poisoned mmap lock"))?;
+        *mapping = Mapping::new(&self.file).context("map db file")?;
+        Ok(())
+    }
+
+    /// Calls `f` with the `len` bytes at `offset`, or returns `None` if the map ends
+    /// before them.
+    pub fn with_bytes<T>(
+        &self,
+        offset: usize,
+        len: usize,
+        f: impl FnOnce(&[u8]) -> T,
+    ) -> anyhow::Result<Option<T>> {
+        let mapping = self.mapping()?;
+        Ok(mapping.bytes().get(offset..offset + len).map(f))
+    }
+
+    fn mapping(&self) -> anyhow::Result<RwLockReadGuard<'_, Mapping>> {
+        self.mapping
+            .read()
+            .map_err(|_| anyhow!("poisoned mmap lock"))
+    }
+}
+
+#[derive(Debug)]
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only, and only unmapped when dropped.
+unsafe impl Send for Mapping {}
+// SAFETY: see above.
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    #[cfg(unix)]
+    fn new(file: &File) -> io::Result<Mapping> {
+        use std::os::fd::AsRawFd;
+
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            // mmap fails on empty ranges.
+            return Ok(Mapping {
+                ptr: std::ptr::null_mut(),
+                len,
+            });
+        }
+
+        // SAFETY: the map is read-only, and the descriptor is valid for the duration of
+        // the call.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn new(_file: &File) -> io::Result<Mapping> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn bytes(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: `ptr` points to `len` readable bytes until the mapping is dropped.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    #[cfg(unix)]
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: `ptr` and `len` describe a mapping created by `Mapping::new`, and
+            // the slices borrowed from it don't outlive `self`.
+            unsafe { libc::munmap(self.ptr.cast(), self.len) };
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn drop(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn remaps_grown_file() {
+        let path = std::env::temp_dir().join(format!("rqlite-mmap-{}", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&[1; 512]).unwrap();
+
+        let mmap = Mmap::open(&path).unwrap();
+        assert_eq!(mmap.with_bytes(0, 512, |b| b[511]).unwrap(), Some(1));
+        assert_eq!(mmap.with_bytes(512, 512, |b| b[0]).unwrap(), None);
+
+        file.write_all(&[2; 512]).unwrap();
+        mmap.refresh().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mmap.with_bytes(512, 512, |b| b[0]).unwrap(), Some(2));
+    }
+}
@@ -0,0 +1,42 @@
+use std::{
+    fmt::Debug,
+    io::{Read, Seek},
+    path::Path,
+};
+
+use anyhow::Context;
+
+mod compressed;
+mod lock;
+mod mmap;
+
+pub use compressed::ZstdPageFile;
+pub use lock::{FileLock, LockKind, SharedLock, lock_byte};
+pub use mmap::Mmap;
+
+pub trait DbFile: Read + Seek + Send + Debug {}
+
+impl<T: Read + Seek + Send + Debug> DbFile for T {}
+
+/// Opens a database file, along with the lock coordinating its readers with SQLite
+/// writers. Compressed files can't be written by SQLite, so they aren't locked.
+pub fn open(path: impl AsRef<Path>) -> anyhow::Result<(Box<dyn DbFile>, Option<FileLock>)> {
+    let mut file = std::fs::File::open(path.as_ref()).context("open db file")?;
+
+    if compressed::is_seekable_zstd(&mut file)? {
+        let file = ZstdPageFile::new(file).context("open compressed db file")?;
+        return Ok((Box::new(file), None));
+    }
+
+    // Read-only files can still be read under a shared lock.
+    let lock_file = match std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(path.as_ref())
+    {
+        Ok(file) => file,
+        Err(_) => file.try_clone().context("duplicate db file handle")?,
+    };
+    let lock = FileLock::new(lock_file, path.as_ref());
+    Ok((Box::new(file), Some(lock)))
+}
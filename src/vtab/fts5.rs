@@ -0,0 +1,504 @@
+//! Read-only access to FTS5 full-text tables. Documents live in the `{name}_content`
+//! shadow table and the inverted index in the segment leaves stored in `{name}_data`.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::{Context, bail, ensure};
+
+use crate::{cursor::Scanner, db::SchemaMetadata};
+
+const STRUCTURE_ROWID: i64 = 10;
+const STRUCTURE_V2_MARKER: [u8; 4] = [0xff, 0x00, 0x00, 0x01];
+const SEGMENT_ROWID_SHIFT: u32 = 37;
+const MAIN_INDEX_PREFIX: u8 = b'0';
+const POSLIST_COLUMN_MARKER: i64 = 1;
+
+/// Columns declared by the module arguments, ignoring `key=value` options.
+pub fn column_names(args: &[String]) -> Vec<String> {
+    args.iter()
+        .filter(|arg| !arg.contains('='))
+        .filter_map(|arg| arg.split_whitespace().next())
+        .map(|name| unquote(name).to_lowercase())
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Content {
+    Internal,
+    Contentless,
+}
+
+#[derive(Debug, Clone)]
+pub struct Fts5Table {
+    name: String,
+    columns: Vec<String>,
+    content: Content,
+}
+
+impl Fts5Table {
+    pub fn new(name: &str, args: &[String]) -> anyhow::Result<Self> {
+        let mut content = Content::Internal;
+
+        for (key, value) in args.iter().filter_map(|arg| arg.split_once('=')) {
+            let value = unquote(value.trim());
+            match key.trim().to_lowercase().as_str() {
+                "content" if value.is_empty() => content = Content::Contentless,
+                "content" => bail!("fts5 tables with external content are not supported"),
+                "tokenize" if value.split_whitespace().next() != Some("unicode61") => {
+                    bail!("unsupported fts5 tokenizer: {value}")
+                }
+                "detail" if value != "full" => bail!("unsupported fts5 detail mode: {value}"),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            columns: column_names(args),
+            content,
+        })
+    }
+
+    pub fn column_count(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn has_content(&self) -> bool {
+        self.content == Content::Internal
+    }
+
+    /// Scans the rows of the table: the documents themselves, or only their rowids for
+    /// contentless tables. The shadow tables are found in `metadata` and scanned with
+    /// `scanner`.
+    pub fn scanner(
+        &self,
+        metadata: &SchemaMetadata,
+        scanner: &dyn Fn(usize) -> Scanner,
+    ) -> anyhow::Result<Scanner> {
+        let shadow = match self.content {
+            Content::Internal => "content",
+            Content::Contentless => "docsize",
+        };
+        shadow_scanner(metadata, scanner, &self.name, shadow)
+    }
+
+    /// Rowids of the documents matching `query`, optionally restricted to one column.
+    pub fn matching_rowids(
+        &self,
+        metadata: &SchemaMetadata,
+        scanner: &dyn Fn(usize) -> Scanner,
+        query: &str,
+        column: Option<usize>,
+    ) -> anyhow::Result<BTreeSet<i64>> {
+        let terms = parse_query(query)?;
+        let index = Index::load(metadata, scanner, &self.name)?;
+
+        let mut result: Option<BTreeSet<i64>> = None;
+        for term in &terms {
+            let rowids = index.lookup(term, column)?;
+            result = Some(match result {
+                Some(r) => r.intersection(&rowids).copied().collect(),
+                None => rowids,
+            });
+        }
+
+        Ok(result.unwrap_or_default())
+    }
+}
+
+fn shadow_scanner(
+    metadata: &SchemaMetadata,
+    scanner: &dyn Fn(usize) -> Scanner,
+    table: &str,
+    shadow: &str,
+) -> anyhow::Result<Scanner> {
+    let name = format!("{table}_{shadow}");
+    let first_page = metadata
+        .table(&name)
+        .with_context(|| format!("missing fts5 shadow table {name}"))?
+        .first_page;
+    Ok(scanner(first_page))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct QueryTerm {
+    text: String,
+    prefix: bool,
+}
+
+impl QueryTerm {
+    fn matches(&self, key: &[u8]) -> bool {
+        match key.split_first() {
+            Some((&MAIN_INDEX_PREFIX, term)) if self.prefix => {
+                term.starts_with(self.text.as_bytes())
+            }
+            Some((&MAIN_INDEX_PREFIX, term)) => term == self.text.as_bytes(),
+            _ => false,
+        }
+    }
+}
+
+/// Parses the subset of the FTS5 query syntax made of barewords and prefix terms,
+/// implicitly ANDed together.
+fn parse_query(query: &str) -> anyhow::Result<Vec<QueryTerm>> {
+    let mut terms = Vec::new();
+
+    for word in query.split_whitespace() {
+        match word {
+            "AND" => continue,
+            "OR" | "NOT" | "NEAR" => bail!("unsupported fts5 query operator: {word}"),
+            _ => {}
+        }
+
+        let (word, prefix) = match word.strip_suffix('*') {
+            Some(w) => (w, true),
+            None => (word, false),
+        };
+
+        if !word.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            bail!("unsupported fts5 query syntax: {word}");
+        }
+
+        let mut tokens = word.split(|c: char| !c.is_alphanumeric());
+        match (tokens.next(), tokens.next()) {
+            (Some(token), None) if !token.is_empty() => terms.push(QueryTerm {
+                text: token.to_lowercase(),
+                prefix,
+            }),
+            _ => bail!("unsupported fts5 phrase query: {word}"),
+        }
+    }
+
+    ensure!(!terms.is_empty(), "empty fts5 query");
+    Ok(terms)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Segment {
+    id: i64,
+    first_page: i64,
+    last_page: i64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Structure {
+    /// Segments from oldest to newest.
+    segments: Vec<Segment>,
+}
+
+fn parse_structure(data: &[u8]) -> anyhow::Result<Structure> {
+    let mut reader = Reader::new(data);
+    reader.skip(4)?;
+
+    let v2 = data.get(4..8) == Some(&STRUCTURE_V2_MARKER);
+    if v2 {
+        reader.skip(4)?;
+    }
+
+    let level_count = reader.varint()?;
+    let _segment_count = reader.varint()?;
+    let _write_counter = reader.varint()?;
+
+    let mut levels = Vec::new();
+    for _ in 0..level_count {
+        let _merge = reader.varint()?;
+        let segment_count = reader.varint()?;
+
+        let mut segments = Vec::new();
+        for _ in 0..segment_count {
+            let segment = Segment {
+                id: reader.varint()?,
+                first_page: reader.varint()?,
+                last_page: reader.varint()?,
+            };
+
+            if v2 {
+                let _origin1 = reader.varint()?;
+                let _origin2 = reader.varint()?;
+                let tombstone_pages = reader.varint()?;
+                let _tombstone_entries = reader.varint()?;
+                let _entries = reader.varint()?;
+                ensure!(
+                    tombstone_pages == 0,
+                    "fts5 tombstone indexes are not supported"
+                );
+            }
+
+            segments.push(segment);
+        }
+        levels.push(segments);
+    }
+
+    // Higher levels hold older data; within a level, later segments are newer.
+    let segments = levels.into_iter().rev().flatten().collect();
+    Ok(Structure { segments })
+}
+
+#[derive(Debug)]
+struct Index {
+    structure: Structure,
+    blocks: HashMap<i64, Vec<u8>>,
+}
+
+impl Index {
+    fn load(
+        metadata: &SchemaMetadata,
+        scanner: &dyn Fn(usize) -> Scanner,
+        table: &str,
+    ) -> anyhow::Result<Self> {
+        let mut scanner = shadow_scanner(metadata, scanner, table, "data")?;
+        let mut blocks = HashMap::new();
+
+        while let Some(mut record) = scanner.next_record()? {
+            let rowid = record.rowid();
+            let block = match record.field(1)? {
+                Some(crate::value::Value::Blob(b)) => b.into_owned(),
+                _ => Vec::new(),
+            };
+            blocks.insert(rowid, block);
+        }
+
+        let structure = blocks
+            .get(&STRUCTURE_ROWID)
+            .context("missing fts5 structure record")
+            .and_then(|data| parse_structure(data))?;
+
+        Ok(Self { structure, blocks })
+    }
+
+    fn lookup(&self, term: &QueryTerm, column: Option<usize>) -> anyhow::Result<BTreeSet<i64>> {
+        // Newer segments override the entries of older ones for the same term and rowid.
+        let mut entries: BTreeMap<(Vec<u8>, i64), bool> = BTreeMap::new();
+
+        for segment in &self.structure.segments {
+            let mut leaves = Vec::new();
+            for page in segment.first_page..=segment.last_page {
+                let rowid = (segment.id << SEGMENT_ROWID_SHIFT) + page;
+                let leaf = self
+                    .blocks
+                    .get(&rowid)
+                    .with_context(|| format!("missing fts5 leaf page {rowid}"))?;
+                leaves.push(leaf.as_slice());
+            }
+
+            read_segment(&leaves, |key, rowid, poslist| {
+                if term.matches(key) {
+                    let hit = !poslist.is_empty() && poslist_has_column(poslist, column)?;
+                    entries.insert((key.to_vec(), rowid), hit);
+                }
+                Ok(())
+            })?;
+        }
+
+        Ok(entries
+            .into_iter()
+            .filter(|(_, hit)| *hit)
+            .map(|((_, rowid), _)| rowid)
+            .collect())
+    }
+}
+
+/// Decodes the leaves of a segment in order, calling `f` with each term, rowid and
+/// position list. Position lists may continue on the following leaves.
+fn read_segment(
+    leaves: &[&[u8]],
+    mut f: impl FnMut(&[u8], i64, &[u8]) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut term = Vec::new();
+    let mut rowid = 0;
+    let mut poslist = Vec::new();
+    let mut pending = 0;
+
+    for leaf in leaves {
+        ensure!(leaf.len() >= 4, "truncated fts5 leaf page");
+        let first_rowid = u16::from_be_bytes([leaf[0], leaf[1]]) as usize;
+        let leaf_size = u16::from_be_bytes([leaf[2], leaf[3]]) as usize;
+        ensure!(leaf_size <= leaf.len(), "invalid fts5 leaf size");
+
+        let mut term_offsets = Vec::new();
+        let mut footer = Reader::new(&leaf[leaf_size..]);
+        while !footer.is_empty() {
+            let delta = footer.varint()? as usize;
+            term_offsets.push(term_offsets.last().copied().unwrap_or(0) + delta);
+        }
+
+        let content = &leaf[..leaf_size];
+        let mut reader = Reader::new(content);
+        reader.skip(4)?;
+
+        if pending > 0 {
+            let n = pending.min(content.len() - reader.pos);
+            poslist.extend_from_slice(reader.take(n)?);
+            pending -= n;
+            if pending == 0 {
+                f(&term, rowid, &poslist)?;
+            }
+        }
+
+        while !reader.is_empty() {
+            if let Some(i) = term_offsets.iter().position(|&o| o == reader.pos) {
+                let prefix = if i == 0 { 0 } else { reader.varint()? as usize };
+                let suffix = reader.varint()? as usize;
+                ensure!(prefix <= term.len(), "invalid fts5 term prefix");
+                term.truncate(prefix);
+                term.extend_from_slice(reader.take(suffix)?);
+                rowid = reader.varint()?;
+            } else if reader.pos == first_rowid {
+                rowid = reader.varint()?;
+            } else {
+                rowid += reader.varint()?;
+            }
+
+            let size = (reader.varint()? >> 1) as usize;
+            let n = size.min(content.len() - reader.pos);
+            poslist.clear();
+            poslist.extend_from_slice(reader.take(n)?);
+            pending = size - n;
+            if pending == 0 {
+                f(&term, rowid, &poslist)?;
+            }
+        }
+    }
+
+    ensure!(pending == 0, "truncated fts5 position list");
+    Ok(())
+}
+
+fn poslist_has_column(poslist: &[u8], column: Option<usize>) -> anyhow::Result<bool> {
+    let Some(column) = column else {
+        return Ok(true);
+    };
+
+    let mut reader = Reader::new(poslist);
+    let mut current = 0;
+    while !reader.is_empty() {
+        match reader.varint()? {
+            POSLIST_COLUMN_MARKER => current = reader.varint()? as usize,
+            _ if current == column => return Ok(true),
+            _ => {}
+        }
+    }
+
+    Ok(false)
+}
+
+fn unquote(s: &str) -> &str {
+    for quote in ['\'', '"', '`'] {
+        if let Some(inner) = s.strip_prefix(quote).and_then(|s| s.strip_suffix(quote)) {
+            return inner;
+        }
+    }
+    s
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn skip(&mut self, n: usize) -> anyhow::Result<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + n)
+            .context("truncated fts5 record")?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> anyhow::Result<i64> {
+        let mut result = 0;
+        for i in 0..9 {
+            let byte = *self.data.get(self.pos).context("truncated fts5 varint")? as i64;
+            self.pos += 1;
+            if i == 8 {
+                return Ok((result << 8) | byte);
+            }
+            result = (result << 7) | (byte & 0x7f);
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_terms() {
+        let terms = parse_query("Alpha AND bet*").unwrap();
+        assert_eq!(
+            terms,
+            vec![
+                QueryTerm {
+                    text: "alpha".to_string(),
+                    prefix: false
+                },
+                QueryTerm {
+                    text: "bet".to_string(),
+                    prefix: true
+                },
+            ]
+        );
+
+        assert!(parse_query("a OR b").is_err());
+        assert!(parse_query("\"a b\"").is_err());
+    }
+
+    #[test]
+    fn structure_segment_order() {
+        // cookie, nLevel=2, nSegment=2, nWriteCounter=5
+        // level 0: nMerge=0, nSeg=1, segment 3 pages 1..=1
+        // level 1: nMerge=0, nSeg=1, segment 1 pages 1..=2
+        let data = [0, 0, 0, 1, 2, 2, 5, 0, 1, 3, 1, 1, 0, 1, 1, 1, 2];
+        let structure = parse_structure(&data).unwrap();
+        let ids: Vec<i64> = structure.segments.iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![1, 3]);
+    }
+
+    #[test]
+    fn leaf_with_spilled_poslist() {
+        // "0ab" in rowids 3 and 5, the second position list spilling onto the next leaf.
+        let first = [0, 8, 0, 16, 3, b'0', b'a', b'b', 3, 4, 2, 3, 2, 6, 2, 3, 4];
+        let second = [0, 5, 0, 8, 4, 9, 2, 2];
+        let mut entries = Vec::new();
+        read_segment(&[&first, &second], |term, rowid, poslist| {
+            entries.push((term.to_vec(), rowid, poslist.to_vec()));
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(
+            entries,
+            vec![
+                (b"0ab".to_vec(), 3, vec![2, 3]),
+                (b"0ab".to_vec(), 5, vec![2, 3, 4]),
+                (b"0ab".to_vec(), 9, vec![2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn poslist_columns() {
+        let poslist = [2, 1, 2, 3];
+        assert!(poslist_has_column(&poslist, Some(0)).unwrap());
+        assert!(!poslist_has_column(&poslist, Some(1)).unwrap());
+        assert!(poslist_has_column(&poslist, Some(2)).unwrap());
+        assert!(poslist_has_column(&poslist, None).unwrap());
+    }
+}
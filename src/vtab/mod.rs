@@ -0,0 +1,34 @@
+use crate::sql::ast;
+
+pub mod fts5;
+pub mod rtree;
+
+/// Columns exposed by a virtual table, derived from its module arguments. Unknown
+/// modules expose no columns and can't be queried.
+pub fn module_columns(module: &str, args: &[String]) -> Vec<ast::ColumnDef> {
+    let names = match module.to_lowercase().as_str() {
+        "fts5" => fts5::column_names(args),
+        "rtree" | "rtree_i32" => rtree::column_names(args),
+        _ => Vec::new(),
+    };
+
+    names
+        .into_iter()
+        .map(|name| ast::ColumnDef {
+            name,
+            col_type: None,
+            constraints: Vec::new(),
+        })
+        .collect()
+}
+
+/// Tables a module creates to store the content of virtual table `name`.
+pub fn shadow_table_names(module: &str, name: &str) -> Vec<String> {
+    let suffixes: &[&str] = match module.to_lowercase().as_str() {
+        "fts5" => &["data", "idx", "content", "docsize", "config"],
+        "rtree" | "rtree_i32" => &["node", "rowid", "parent"],
+        _ => &[],
+    };
+
+    suffixes.iter().map(|s| format!("{name}_{s}")).collect()
+}
@@ -0,0 +1,347 @@
+//! Read-only access to R-Tree tables. The tree nodes are stored as blobs in the
+//! `{name}_node` shadow table, and auxiliary columns in `{name}_rowid`.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, bail, ensure};
+
+use crate::{
+    cursor::Scanner,
+    db::SchemaMetadata,
+    value::{OwnedValue, Value},
+};
+
+const ROOT_NODE: i64 = 1;
+const NODE_HEADER_SIZE: usize = 4;
+const COORD_SIZE: usize = 4;
+const MAX_DIMENSIONS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CoordType {
+    Float,
+    Int,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConstraintOp {
+    Eq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+impl ConstraintOp {
+    /// The same constraint with its operands swapped, e.g. `1 < x` becomes `x > 1`.
+    pub fn flip(self) -> Self {
+        match self {
+            ConstraintOp::Eq => ConstraintOp::Eq,
+            ConstraintOp::Lt => ConstraintOp::Gt,
+            ConstraintOp::LtEq => ConstraintOp::GtEq,
+            ConstraintOp::Gt => ConstraintOp::Lt,
+            ConstraintOp::GtEq => ConstraintOp::LtEq,
+        }
+    }
+
+    fn test(self, lhs: f64, rhs: f64) -> bool {
+        match self {
+            ConstraintOp::Eq => lhs == rhs,
+            ConstraintOp::Lt => lhs < rhs,
+            ConstraintOp::LtEq => lhs <= rhs,
+            ConstraintOp::Gt => lhs > rhs,
+            ConstraintOp::GtEq => lhs >= rhs,
+        }
+    }
+
+    /// Whether some value in `[min, max]` may satisfy the constraint.
+    fn overlaps(self, min: f64, max: f64, value: f64) -> bool {
+        match self {
+            ConstraintOp::Eq => min <= value && value <= max,
+            ConstraintOp::Lt => min < value,
+            ConstraintOp::LtEq => min <= value,
+            ConstraintOp::Gt => max > value,
+            ConstraintOp::GtEq => max >= value,
+        }
+    }
+}
+
+/// A `column op value` constraint on the id (column 0) or a coordinate column.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Constraint {
+    pub column: usize,
+    pub op: ConstraintOp,
+    pub value: f64,
+}
+
+/// Columns declared by the module arguments; auxiliary columns lose their `+` prefix.
+pub fn column_names(args: &[String]) -> Vec<String> {
+    args.iter()
+        .filter_map(|arg| arg.split_whitespace().next())
+        .map(|name| name.trim_start_matches('+').to_lowercase())
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct RTreeTable {
+    name: String,
+    dimensions: usize,
+    aux_columns: usize,
+    coord_type: CoordType,
+}
+
+impl RTreeTable {
+    pub fn new(name: &str, module: &str, args: &[String]) -> anyhow::Result<Self> {
+        let coord_type = match module.to_lowercase().as_str() {
+            "rtree" => CoordType::Float,
+            "rtree_i32" => CoordType::Int,
+            _ => bail!("unsupported rtree module: {module}"),
+        };
+
+        let aux_columns = args.iter().filter(|arg| arg.starts_with('+')).count();
+        let coord_columns = args.len().saturating_sub(aux_columns + 1);
+        ensure!(
+            coord_columns >= 2 && coord_columns.is_multiple_of(2),
+            "invalid number of rtree coordinate columns: {coord_columns}"
+        );
+        ensure!(
+            coord_columns / 2 <= MAX_DIMENSIONS,
+            "too many rtree dimensions: {}",
+            coord_columns / 2
+        );
+
+        Ok(Self {
+            name: name.to_string(),
+            dimensions: coord_columns / 2,
+            aux_columns,
+            coord_type,
+        })
+    }
+
+    pub fn column_count(&self) -> usize {
+        1 + 2 * self.dimensions + self.aux_columns
+    }
+
+    pub fn is_coordinate(&self, column: usize) -> bool {
+        (1..=2 * self.dimensions).contains(&column)
+    }
+
+    /// Reads the nodes of the tree from the shadow tables, found in `metadata` and
+    /// scanned with `scanner`.
+    pub fn cursor(
+        &self,
+        metadata: &SchemaMetadata,
+        scanner: &dyn Fn(usize) -> Scanner,
+        constraints: Vec<Constraint>,
+    ) -> anyhow::Result<RTreeCursor> {
+        let mut nodes = HashMap::new();
+        let mut scan = shadow_scanner(metadata, scanner, &self.name, "node")?;
+        while let Some(mut record) = scan.next_record()? {
+            let node = record.rowid();
+            if let Some(Value::Blob(data)) = record.field(1)? {
+                nodes.insert(node, data.into_owned());
+            }
+        }
+
+        let mut aux = HashMap::new();
+        if self.aux_columns > 0 {
+            let mut scan = shadow_scanner(metadata, scanner, &self.name, "rowid")?;
+            while let Some(mut record) = scan.next_record()? {
+                let values = (0..self.aux_columns)
+                    .map(|i| Ok(record.owned_field(i + 2)?.unwrap_or(OwnedValue::Null)))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                aux.insert(record.rowid(), values);
+            }
+        }
+
+        let root = nodes.get(&ROOT_NODE).context("missing rtree root node")?;
+        ensure!(root.len() >= NODE_HEADER_SIZE, "invalid rtree root node");
+        let depth = u16::from_be_bytes([root[0], root[1]]) as usize;
+
+        Ok(RTreeCursor {
+            table: self.clone(),
+            constraints,
+            nodes,
+            aux,
+            stack: vec![(ROOT_NODE, depth, 0)],
+        })
+    }
+
+    fn cell_size(&self) -> usize {
+        8 + 2 * self.dimensions * COORD_SIZE
+    }
+
+    fn coord(&self, cell: &[u8], i: usize) -> f64 {
+        let offset = 8 + i * COORD_SIZE;
+        let bytes = cell[offset..offset + COORD_SIZE].try_into().unwrap();
+        match self.coord_type {
+            CoordType::Float => f32::from_be_bytes(bytes) as f64,
+            CoordType::Int => i32::from_be_bytes(bytes) as f64,
+        }
+    }
+
+    fn coord_value(&self, value: f64) -> OwnedValue {
+        match self.coord_type {
+            CoordType::Float => OwnedValue::Float(value),
+            CoordType::Int => OwnedValue::Int(value as i64),
+        }
+    }
+}
+
+fn shadow_scanner(
+    metadata: &SchemaMetadata,
+    scanner: &dyn Fn(usize) -> Scanner,
+    table: &str,
+    shadow: &str,
+) -> anyhow::Result<Scanner> {
+    let name = format!("{table}_{shadow}");
+    let first_page = metadata
+        .table(&name)
+        .with_context(|| format!("missing rtree shadow table {name}"))?
+        .first_page;
+    Ok(scanner(first_page))
+}
+
+/// Depth-first walk of the tree, skipping the subtrees whose bounding boxes can't
+/// satisfy the constraints.
+#[derive(Debug)]
+pub struct RTreeCursor {
+    table: RTreeTable,
+    constraints: Vec<Constraint>,
+    nodes: HashMap<i64, Vec<u8>>,
+    aux: HashMap<i64, Vec<OwnedValue>>,
+    /// Node number, height above the leaves and next cell to visit.
+    stack: Vec<(i64, usize, usize)>,
+}
+
+impl RTreeCursor {
+    /// Writes the next matching entry into `row`, returning false once the tree is
+    /// exhausted.
+    pub fn next_row(&mut self, row: &mut [OwnedValue]) -> anyhow::Result<bool> {
+        let cell_size = self.table.cell_size();
+
+        while let Some(&(node, height, cell)) = self.stack.last() {
+            let data = self
+                .nodes
+                .get(&node)
+                .with_context(|| format!("missing rtree node {node}"))?;
+            let header = data
+                .get(..NODE_HEADER_SIZE)
+                .with_context(|| format!("truncated rtree node {node}"))?;
+            let cell_count = u16::from_be_bytes([header[2], header[3]]) as usize;
+
+            if cell >= cell_count {
+                self.stack.pop();
+                continue;
+            }
+
+            let offset = NODE_HEADER_SIZE + cell * cell_size;
+            let cell_data = data
+                .get(offset..offset + cell_size)
+                .with_context(|| format!("truncated rtree node {node}"))?;
+            if let Some(top) = self.stack.last_mut() {
+                top.2 += 1;
+            }
+
+            let id = i64::from_be_bytes(cell_data[..8].try_into().unwrap());
+            let coords: Vec<f64> = (0..2 * self.table.dimensions)
+                .map(|i| self.table.coord(cell_data, i))
+                .collect();
+
+            if height > 0 {
+                if self.overlaps(&coords) {
+                    self.stack.push((id, height - 1, 0));
+                }
+                continue;
+            }
+
+            if !self.matches(id, &coords) {
+                continue;
+            }
+
+            row[0] = OwnedValue::Int(id);
+            for (i, &c) in coords.iter().enumerate() {
+                row[i + 1] = self.table.coord_value(c);
+            }
+            if let Some(aux) = self.aux.get(&id) {
+                row[1 + coords.len()..].clone_from_slice(aux);
+            }
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    fn overlaps(&self, coords: &[f64]) -> bool {
+        self.constraints.iter().filter(|c| c.column > 0).all(|c| {
+            let dimension = (c.column - 1) / 2;
+            c.op.overlaps(coords[2 * dimension], coords[2 * dimension + 1], c.value)
+        })
+    }
+
+    fn matches(&self, id: i64, coords: &[f64]) -> bool {
+        self.constraints.iter().all(|c| {
+            let value = match c.column {
+                0 => id as f64,
+                n => coords[n - 1],
+            };
+            c.op.test(value, c.value)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_columns() {
+        let args: Vec<String> = ["id", "minX", "maxX", "minY", "maxY", "+name"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            column_names(&args),
+            vec!["id", "minx", "maxx", "miny", "maxy", "name"]
+        );
+
+        let table = RTreeTable::new("demo", "rtree", &args).unwrap();
+        assert_eq!(table.dimensions, 2);
+        assert_eq!(table.column_count(), 6);
+        assert!(table.is_coordinate(4) && !table.is_coordinate(5));
+
+        assert!(RTreeTable::new("demo", "rtree", &args[..2]).is_err());
+    }
+
+    #[test]
+    fn bounding_box_pruning() {
+        assert!(ConstraintOp::GtEq.overlaps(0.0, 10.0, 10.0));
+        assert!(!ConstraintOp::Gt.overlaps(0.0, 10.0, 10.0));
+        assert!(!ConstraintOp::LtEq.overlaps(5.0, 10.0, 4.0));
+        assert!(ConstraintOp::Eq.overlaps(5.0, 10.0, 7.5));
+        assert_eq!(ConstraintOp::Lt.flip(), ConstraintOp::Gt);
+    }
+
+    #[test]
+    fn truncated_node() {
+        let args: Vec<String> = ["id", "minX", "maxX"].map(String::from).into();
+        let table = RTreeTable::new("demo", "rtree", &args).unwrap();
+
+        // A root of depth 1 whose only cell points to node 2, which is cut short.
+        let mut root = vec![0, 1, 0, 1];
+        root.extend(2i64.to_be_bytes());
+        root.extend(0f32.to_be_bytes());
+        root.extend(1f32.to_be_bytes());
+        let nodes = HashMap::from([(ROOT_NODE, root), (2, vec![0, 0])]);
+        let mut cursor = RTreeCursor {
+            table,
+            constraints: Vec::new(),
+            nodes,
+            aux: HashMap::new(),
+            stack: vec![(ROOT_NODE, 1, 0)],
+        };
+
+        let mut row = vec![OwnedValue::Null; 3];
+        let error = cursor.next_row(&mut row).unwrap_err();
+        assert_eq!(error.to_string(), "truncated rtree node 2");
+    }
+}
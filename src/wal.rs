@@ -0,0 +1,874 @@
+//! Reading of the write-ahead log of databases in WAL mode. Writers append the new
+//! content of the pages a transaction changed to the log as frames, and the last frame
+//! of a transaction marks its commit. Checkpoints later copy the frames back into the
+//! database file, so until then the newest committed frame of a page is its content.
+//!
+//! A query reads a snapshot of the committed frames, which later commits don't change.
+//! While it runs, it holds a read lock of the shared-memory WAL index like SQLite
+//! readers do: checkpoints don't copy the frames past the mark of a held lock into the
+//! file, and the log doesn't restart, so the file and the log keep the content of the
+//! snapshot. Without an index, no other connection is attached to hold back: if one
+//! attaches and checkpoints meanwhile, the reads fail rather than mix pages of two
+//! transactions.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, anyhow, ensure};
+
+use crate::{
+    pager::read_be_double_at,
+    vfs::{self, DbFile, LockKind},
+};
+
+const MAGIC_LITTLE_ENDIAN: u32 = 0x377f_0682;
+const MAGIC_BIG_ENDIAN: u32 = 0x377f_0683;
+const FORMAT_VERSION: u32 = 3_007_000;
+
+const HEADER_SIZE: usize = 32;
+const FRAME_HEADER_SIZE: usize = 24;
+
+/// The shared-memory index starts with two copies of its header, then the checkpoint
+/// counters and the locks, its integers in native byte order.
+const SHM_SALT_OFFSET: usize = 32;
+/// The read marks, each guarded by a lock: checkpoints don't copy the frames past the
+/// mark of a lock a reader holds. The first stands for a log copied entirely, which
+/// readers holding it read from the file only.
+const SHM_READ_MARKS_OFFSET: usize = 100;
+const SHM_READERS: usize = 5;
+/// The lock bytes, which Windows keeps from being read while they are locked.
+const SHM_LOCKS_OFFSET: usize = 120;
+const SHM_READ_LOCKS: i64 = 123;
+const SHM_BACKFILL_ATTEMPTED_OFFSET: usize = 128;
+#[cfg(test)]
+const SHM_HEADER_SIZE: usize = 136;
+
+/// How long to retry taking a read lock before reading without one.
+const PIN_TIMEOUT: Duration = Duration::from_secs(1);
+const PIN_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Identifies the committed content of the log: the salts change when a checkpoint
+/// restarts the log, and the frame count when a transaction commits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WalVersion {
+    pub salt: [u32; 2],
+    pub frames: u32,
+}
+
+/// How far the checkpoints of the current log went, as recorded in the shared-memory
+/// index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Checkpoint {
+    salt: [u32; 2],
+    /// The frames a checkpoint started copying, recorded before it copies them.
+    attempted: u32,
+}
+
+/// The committed content of the log as of a refresh. The pages of a query all come from
+/// the snapshot taken when it was planned, whatever was committed since.
+#[derive(Debug, Default)]
+pub struct WalSnapshot {
+    version: WalVersion,
+    /// Offsets in the log of the newest committed image of the pages, shared with the
+    /// later snapshots until a commit changes them.
+    pages: Arc<HashMap<usize, u64>>,
+    /// The size of the database in pages after the last commit.
+    db_size: usize,
+    /// The checkpoint counters before the snapshot was taken.
+    checkpoint: Option<Checkpoint>,
+    /// Holds back the checkpoints of the other connections, for a pinned snapshot.
+    pin: Option<ShmPin>,
+}
+
+impl WalSnapshot {
+    pub fn version(&self) -> WalVersion {
+        self.version
+    }
+
+    /// The size of the database in pages, if the log holds a transaction.
+    pub fn db_size(&self) -> Option<usize> {
+        (self.db_size > 0).then_some(self.db_size)
+    }
+
+    pub fn has_page(&self, n: usize) -> bool {
+        self.pages.contains_key(&n)
+    }
+}
+
+#[derive(Debug, Default)]
+struct WalIndex {
+    snapshot: Arc<WalSnapshot>,
+    big_endian: bool,
+    /// The running checksum as of the last committed frame.
+    checksum: [u32; 2],
+}
+
+#[derive(Debug)]
+pub struct Wal {
+    file: Mutex<Box<dyn DbFile>>,
+    /// The shared-memory index of the other connections, if the log belongs to a file.
+    shm: Option<Arc<Shm>>,
+    page_size: usize,
+    index: RwLock<WalIndex>,
+}
+
+#[derive(Debug)]
+struct Shm {
+    path: PathBuf,
+    state: Mutex<ShmState>,
+}
+
+#[derive(Debug, Default)]
+struct ShmState {
+    /// Kept open while pinned, as closing any descriptor of a file releases the locks
+    /// the process holds on it.
+    file: Option<File>,
+    /// Whether the file could be opened for writing, to set read marks.
+    writable: bool,
+    /// Live pins by read lock. The locks belong to the whole process, so the last pin
+    /// of a lock releases it, and the marks of the held ones can't change.
+    pins: [usize; SHM_READERS],
+}
+
+/// Keeps a read lock of the index until dropped.
+#[derive(Debug)]
+struct ShmPin {
+    shm: Arc<Shm>,
+    reader: usize,
+}
+
+/// The part of the index before the lock bytes, and the frames a checkpoint started
+/// copying.
+struct ShmHeader {
+    bytes: [u8; SHM_LOCKS_OFFSET],
+    attempted: u32,
+}
+
+impl Wal {
+    /// Opens the log of the database at `db_path`, if it has one.
+    pub fn open(db_path: impl AsRef<Path>, page_size: u32) -> anyhow::Result<Option<Wal>> {
+        let mut path = OsString::from(db_path.as_ref());
+        path.push("-wal");
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("open {}", path.to_string_lossy()));
+            }
+        };
+        let mut shm = OsString::from(db_path.as_ref());
+        shm.push("-shm");
+        Ok(Some(Wal::new(Box::new(file), page_size)?.with_shm(shm)))
+    }
+
+    pub fn new(file: Box<dyn DbFile>, page_size: u32) -> anyhow::Result<Wal> {
+        let wal = Wal {
+            file: Mutex::new(file),
+            shm: None,
+            page_size: page_size as usize,
+            index: RwLock::default(),
+        };
+        wal.refresh()?;
+        Ok(wal)
+    }
+
+    /// Coordinates with the checkpoints of the other connections through the
+    /// shared-memory index at `path`.
+    pub fn with_shm(mut self, path: impl Into<PathBuf>) -> Self {
+        self.shm = Some(Arc::new(Shm {
+            path: path.into(),
+            state: Mutex::default(),
+        }));
+        self
+    }
+
+    /// Indexes the transactions committed since the last refresh, starting over when a
+    /// checkpoint restarted the log.
+    pub fn refresh(&self) -> anyhow::Result<WalVersion> {
+        // Read first: the frames checkpointed before the log is read are part of the
+        // snapshot anyway.
+        let checkpoint = self.read_checkpoint()?;
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| anyhow!("poisoned wal file mutex"))?;
+        let mut index = self
+            .index
+            .write()
+            .map_err(|_| anyhow!("poisoned wal index lock"))?;
+
+        let Some(fresh) = self.read_header(&mut **file)? else {
+            *index = WalIndex::default();
+            index.snapshot = Arc::new(WalSnapshot {
+                checkpoint,
+                ..WalSnapshot::default()
+            });
+            return Ok(index.snapshot.version);
+        };
+        let mut snapshot = WalSnapshot {
+            checkpoint,
+            ..WalSnapshot::default()
+        };
+        if fresh.snapshot.version.salt != index.snapshot.version.salt
+            || index.snapshot.version.frames == 0
+        {
+            snapshot.version = fresh.snapshot.version;
+            *index = fresh;
+        } else {
+            snapshot.version = index.snapshot.version;
+            snapshot.pages = index.snapshot.pages.clone();
+            snapshot.db_size = index.snapshot.db_size;
+        }
+        let salt = snapshot.version.salt;
+
+        let frame_size = FRAME_HEADER_SIZE + self.page_size;
+        let start = HEADER_SIZE + snapshot.version.frames as usize * frame_size;
+        file.seek(SeekFrom::Start(start as u64))
+            .context("seek to wal frame")?;
+
+        let mut frame = vec![0; frame_size];
+        let mut uncommitted = Vec::new();
+        let mut checksum = index.checksum;
+        let mut frames = snapshot.version.frames;
+        while read_frame(&mut **file, &mut frame)? {
+            let page = read_be_double_at(&frame, 0) as usize;
+            let db_size = read_be_double_at(&frame, 4) as usize;
+            if page == 0 || [read_be_double_at(&frame, 8), read_be_double_at(&frame, 12)] != salt {
+                break;
+            }
+            checksum = wal_checksum(&frame[..8], index.big_endian, checksum);
+            checksum = wal_checksum(&frame[FRAME_HEADER_SIZE..], index.big_endian, checksum);
+            if checksum != [read_be_double_at(&frame, 16), read_be_double_at(&frame, 20)] {
+                break;
+            }
+
+            let offset = (HEADER_SIZE + frames as usize * frame_size + FRAME_HEADER_SIZE) as u64;
+            frames += 1;
+            uncommitted.push((page, offset));
+            if db_size != 0 {
+                Arc::make_mut(&mut snapshot.pages).extend(uncommitted.drain(..));
+                snapshot.version.frames = frames;
+                snapshot.db_size = db_size;
+                index.checksum = checksum;
+            }
+        }
+
+        index.snapshot = Arc::new(snapshot);
+        Ok(index.snapshot.version)
+    }
+
+    /// Takes a snapshot of the committed frames for a query, holding back the
+    /// checkpoints of the other connections until it is dropped, as they'd overwrite
+    /// the pages it reads.
+    pub fn pin(&self) -> anyhow::Result<Arc<WalSnapshot>> {
+        loop {
+            self.refresh()?;
+            let latest = self.snapshot()?;
+            let pin = match &self.shm {
+                Some(shm) => shm.pin(latest.version.frames)?,
+                None => None,
+            };
+            // A checkpoint may have copied newer frames before the lock was taken, in
+            // which case the snapshot is taken again.
+            if pin.is_some() {
+                let checkpoint = self.read_checkpoint()?;
+                let current = checkpoint == latest.checkpoint
+                    || checkpoint.is_some_and(|checkpoint| {
+                        checkpoint.salt == latest.version.salt
+                            && checkpoint.attempted <= latest.version.frames
+                    });
+                if !current {
+                    continue;
+                }
+            }
+            return Ok(Arc::new(WalSnapshot {
+                version: latest.version,
+                pages: latest.pages.clone(),
+                db_size: latest.db_size,
+                checkpoint: latest.checkpoint,
+                pin,
+            }));
+        }
+    }
+
+    /// The committed content of the log as of the last refresh.
+    pub fn snapshot(&self) -> anyhow::Result<Arc<WalSnapshot>> {
+        Ok(self
+            .index
+            .read()
+            .map_err(|_| anyhow!("poisoned wal index lock"))?
+            .snapshot
+            .clone())
+    }
+
+    /// Reads the header into an empty index, or `None` when the log is empty or its
+    /// header was never completely written, in which case it holds no transaction.
+    fn read_header(&self, file: &mut dyn DbFile) -> anyhow::Result<Option<WalIndex>> {
+        let mut header = [0; HEADER_SIZE];
+        file.seek(SeekFrom::Start(0))
+            .context("seek to wal header")?;
+        if !read_frame(file, &mut header)? {
+            return Ok(None);
+        }
+
+        let big_endian = match read_be_double_at(&header, 0) {
+            MAGIC_LITTLE_ENDIAN => false,
+            MAGIC_BIG_ENDIAN => true,
+            _ => return Ok(None),
+        };
+        let checksum = [
+            read_be_double_at(&header, 24),
+            read_be_double_at(&header, 28),
+        ];
+        if wal_checksum(&header[..24], big_endian, [0, 0]) != checksum {
+            return Ok(None);
+        }
+
+        let version = read_be_double_at(&header, 4);
+        ensure!(
+            version == FORMAT_VERSION,
+            "unsupported wal format version: {version}"
+        );
+        let page_size = read_be_double_at(&header, 8) as usize;
+        ensure!(
+            page_size == self.page_size,
+            "wal page size {page_size} does not match database page size {}",
+            self.page_size
+        );
+
+        Ok(Some(WalIndex {
+            snapshot: Arc::new(WalSnapshot {
+                version: WalVersion {
+                    salt: [
+                        read_be_double_at(&header, 16),
+                        read_be_double_at(&header, 20),
+                    ],
+                    frames: 0,
+                },
+                ..WalSnapshot::default()
+            }),
+            big_endian,
+            checksum,
+        }))
+    }
+
+    /// Reads the image of page `n` committed as of `snapshot` into `buffer`, returning
+    /// whether the log holds one. Fails if a checkpoint restarted the log since, as the
+    /// frame may then hold a newer image.
+    pub fn read_page(
+        &self,
+        snapshot: &WalSnapshot,
+        n: usize,
+        buffer: &mut [u8],
+    ) -> anyhow::Result<bool> {
+        let Some(&offset) = snapshot.pages.get(&n) else {
+            return Ok(false);
+        };
+
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| anyhow!("poisoned wal file mutex"))?;
+        file.seek(SeekFrom::Start(offset))
+            .context("seek to wal frame")?;
+        file.read_exact(buffer)
+            .with_context(|| format!("read page {n} from wal"))?;
+
+        // Writers write the header of a frame before its page, so a header still
+        // holding the salt of the snapshot means the page was read before being
+        // overwritten.
+        let mut header = [0; FRAME_HEADER_SIZE];
+        file.seek(SeekFrom::Start(offset - FRAME_HEADER_SIZE as u64))
+            .context("seek to wal frame")?;
+        file.read_exact(&mut header)
+            .with_context(|| format!("read frame of page {n} from wal"))?;
+        ensure!(
+            read_be_double_at(&header, 0) as usize == n
+                && [
+                    read_be_double_at(&header, 8),
+                    read_be_double_at(&header, 12)
+                ] == snapshot.version.salt,
+            "a checkpoint restarted the wal while the query was reading it"
+        );
+        Ok(true)
+    }
+
+    /// Fails if a checkpoint may have copied an image of page `n` committed after
+    /// `snapshot` into the file, e.g. while the page was being read from it. Snapshots
+    /// holding a read lock hold the checkpoints back.
+    pub fn check_file_read(&self, snapshot: &WalSnapshot, n: usize) -> anyhow::Result<()> {
+        if snapshot.pin.is_some() {
+            return Ok(());
+        }
+        let Some(checkpoint) = self.read_checkpoint()? else {
+            return Ok(());
+        };
+        if snapshot.checkpoint == Some(checkpoint) {
+            return Ok(());
+        }
+        ensure!(
+            checkpoint.salt == snapshot.version.salt
+                && (checkpoint.attempted <= snapshot.version.frames
+                    || !self.logged_after(snapshot, n, checkpoint.attempted)?),
+            "a checkpoint copied newer pages into the database while the query was reading it"
+        );
+        Ok(())
+    }
+
+    /// Whether the log may hold an image of page `n` committed after `snapshot`, among
+    /// its first `frames` frames. Only the newest image of each page is indexed, so an
+    /// image committed past them counts too.
+    fn logged_after(&self, snapshot: &WalSnapshot, n: usize, frames: u32) -> anyhow::Result<bool> {
+        let mut latest = self.snapshot()?;
+        if latest.version.salt == snapshot.version.salt && latest.version.frames < frames {
+            self.refresh()?;
+            latest = self.snapshot()?;
+        }
+        Ok(latest.version.salt != snapshot.version.salt
+            || latest.version.frames < frames
+            || latest.pages.get(&n) != snapshot.pages.get(&n))
+    }
+
+    fn read_checkpoint(&self) -> anyhow::Result<Option<Checkpoint>> {
+        match &self.shm {
+            Some(shm) => shm.read_checkpoint(),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Shm {
+    /// The file of the index, if another connection created one. It is reopened
+    /// unless pinned, in case the connections recreated it since.
+    fn file<'s>(&self, state: &'s mut ShmState) -> anyhow::Result<Option<&'s File>> {
+        if state.pins.iter().all(|&pins| pins == 0) {
+            let open = |write| File::options().read(true).write(write).open(&self.path);
+            let file = match open(true) {
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem
+                    ) =>
+                {
+                    open(false).map(|file| (file, false))
+                }
+                file => file.map(|file| (file, true)),
+            };
+            (state.file, state.writable) = match file {
+                Ok((file, writable)) => (Some(file), writable),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (None, false),
+                Err(e) => {
+                    return Err(e).with_context(|| format!("open {}", self.path.display()));
+                }
+            };
+        }
+        Ok(state.file.as_ref())
+    }
+
+    fn read_header(&self, mut file: &File) -> anyhow::Result<Option<ShmHeader>> {
+        let mut bytes = [0; SHM_LOCKS_OFFSET];
+        let mut attempted = [0; 4];
+        let read = file
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| file.read_exact(&mut bytes))
+            .and_then(|_| file.seek(SeekFrom::Start(SHM_BACKFILL_ATTEMPTED_OFFSET as u64)))
+            .and_then(|_| file.read_exact(&mut attempted));
+        match read {
+            Ok(()) => Ok(Some(ShmHeader {
+                bytes,
+                attempted: u32::from_ne_bytes(attempted),
+            })),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("read {}", self.path.display())),
+        }
+    }
+
+    fn read_checkpoint(&self) -> anyhow::Result<Option<Checkpoint>> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| anyhow!("poisoned wal index mutex"))?;
+        let Some(file) = self.file(&mut state)? else {
+            return Ok(None);
+        };
+        Ok(self.read_header(file)?.map(|header| header.checkpoint()))
+    }
+
+    /// Takes a read lock whose mark is at most `frames`, setting the mark of a free one
+    /// to `frames` when none has it, like SQLite readers do. `None` when no other
+    /// connection created the index, or no lock could be taken in time.
+    fn pin(self: &Arc<Self>, frames: u32) -> anyhow::Result<Option<ShmPin>> {
+        let mut guard = self
+            .state
+            .lock()
+            .map_err(|_| anyhow!("poisoned wal index mutex"))?;
+        let started = Instant::now();
+        loop {
+            self.file(&mut guard)?;
+            let state = &mut *guard;
+            let Some(file) = &state.file else {
+                return Ok(None);
+            };
+            let Some(header) = self.read_header(file)? else {
+                return Ok(None);
+            };
+            let mark = |reader: usize| header.read_mark(reader);
+            let mut best = (1..SHM_READERS)
+                .filter(|&reader| mark(reader) <= frames)
+                .max_by_key(|&reader| mark(reader));
+            let mut claimed = false;
+            if state.writable && best.is_none_or(|reader| mark(reader) < frames) {
+                // The marks of the locks the process holds are in use.
+                for reader in (1..SHM_READERS).filter(|&reader| state.pins[reader] == 0) {
+                    if self.set_read_mark(file, reader, frames)? {
+                        (best, claimed) = (Some(reader), true);
+                        break;
+                    }
+                }
+            }
+
+            if let Some(reader) = best {
+                if state.pins[reader] > 0 && !claimed {
+                    state.pins[reader] += 1;
+                    return Ok(Some(self.pin_reader(reader)));
+                }
+                let expected = if claimed { frames } else { mark(reader) };
+                let offset = SHM_READ_LOCKS + reader as i64;
+                let locked = vfs::lock_byte(file, LockKind::Read, offset)
+                    .with_context(|| format!("lock {}", self.path.display()))?;
+                if locked {
+                    // The mark may have changed before the lock was taken.
+                    let header = self.read_header(file)?;
+                    if header.is_some_and(|header| header.read_mark(reader) == expected) {
+                        state.pins[reader] = 1;
+                        return Ok(Some(self.pin_reader(reader)));
+                    }
+                    let _ = vfs::lock_byte(file, LockKind::Unlock, offset);
+                }
+            }
+
+            if started.elapsed() >= PIN_TIMEOUT {
+                return Ok(None);
+            }
+            thread::sleep(PIN_RETRY_INTERVAL);
+        }
+    }
+
+    /// Sets the read mark of `reader` to `frames`, unless another connection holds its
+    /// lock.
+    fn set_read_mark(&self, mut file: &File, reader: usize, frames: u32) -> anyhow::Result<bool> {
+        let offset = SHM_READ_LOCKS + reader as i64;
+        let locked = vfs::lock_byte(file, LockKind::Write, offset)
+            .with_context(|| format!("lock {}", self.path.display()))?;
+        if !locked {
+            return Ok(false);
+        }
+        let written = file
+            .seek(SeekFrom::Start((SHM_READ_MARKS_OFFSET + 4 * reader) as u64))
+            .and_then(|_| file.write_all(&frames.to_ne_bytes()));
+        let _ = vfs::lock_byte(file, LockKind::Unlock, offset);
+        written.with_context(|| format!("write {}", self.path.display()))?;
+        Ok(true)
+    }
+
+    fn pin_reader(self: &Arc<Self>, reader: usize) -> ShmPin {
+        ShmPin {
+            shm: self.clone(),
+            reader,
+        }
+    }
+}
+
+impl ShmHeader {
+    fn checkpoint(&self) -> Checkpoint {
+        // The salts are copied as they are from the header of the log.
+        Checkpoint {
+            salt: [
+                read_be_double_at(&self.bytes, SHM_SALT_OFFSET),
+                read_be_double_at(&self.bytes, SHM_SALT_OFFSET + 4),
+            ],
+            attempted: self.attempted,
+        }
+    }
+
+    fn read_mark(&self, reader: usize) -> u32 {
+        let offset = SHM_READ_MARKS_OFFSET + 4 * reader;
+        u32::from_ne_bytes(self.bytes[offset..offset + 4].try_into().unwrap())
+    }
+}
+
+impl Drop for ShmPin {
+    fn drop(&mut self) {
+        let mut state = self.shm.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.pins[self.reader] -= 1;
+        if state.pins[self.reader] == 0
+            && let Some(file) = &state.file
+        {
+            let offset = SHM_READ_LOCKS + self.reader as i64;
+            let _ = vfs::lock_byte(file, LockKind::Unlock, offset);
+        }
+    }
+}
+
+/// Fills `buffer`, returning false if the file ends before.
+fn read_frame(file: &mut dyn DbFile, buffer: &mut [u8]) -> anyhow::Result<bool> {
+    match file.read_exact(buffer) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e).context("read wal"),
+    }
+}
+
+/// The checksum SQLite chains through the header and the frames of the log, reading
+/// the data as 32-bit words in the byte order the magic number specifies.
+fn wal_checksum(data: &[u8], big_endian: bool, [mut s0, mut s1]: [u32; 2]) -> [u32; 2] {
+    for words in data.chunks_exact(8) {
+        let (x0, x1) = if big_endian {
+            (read_be_double_at(words, 0), read_be_double_at(words, 4))
+        } else {
+            (
+                u32::from_le_bytes(words[..4].try_into().unwrap()),
+                u32::from_le_bytes(words[4..].try_into().unwrap()),
+            )
+        };
+        s0 = s0.wrapping_add(x0).wrapping_add(s1);
+        s1 = s1.wrapping_add(x1).wrapping_add(s0);
+    }
+    [s0, s1]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const PAGE_SIZE: usize = 512;
+    const SALT: [u32; 2] = [7, 9];
+
+    fn header() -> Vec<u8> {
+        let mut header = Vec::new();
+        for word in [MAGIC_LITTLE_ENDIAN, FORMAT_VERSION, PAGE_SIZE as u32, 0] {
+            header.extend(word.to_be_bytes());
+        }
+        header.extend(SALT.iter().flat_map(|s| s.to_be_bytes()));
+        let checksum = wal_checksum(&header, false, [0, 0]);
+        header.extend(checksum.iter().flat_map(|s| s.to_be_bytes()));
+        header
+    }
+
+    /// Appends a frame holding `fill` bytes for `page`, chaining the checksum.
+    fn append_frame(wal: &mut Vec<u8>, checksum: &mut [u32; 2], page: u32, db_size: u32, fill: u8) {
+        let data = vec![fill; PAGE_SIZE];
+        let mut frame = Vec::new();
+        frame.extend(page.to_be_bytes());
+        frame.extend(db_size.to_be_bytes());
+        *checksum = wal_checksum(&frame, false, *checksum);
+        *checksum = wal_checksum(&data, false, *checksum);
+        frame.extend(SALT.iter().flat_map(|s| s.to_be_bytes()));
+        frame.extend(checksum.iter().flat_map(|s| s.to_be_bytes()));
+        wal.extend(frame);
+        wal.extend(data);
+    }
+
+    #[test]
+    fn committed_frames() {
+        let mut file = header();
+        let mut checksum = [read_be_double_at(&file, 24), read_be_double_at(&file, 28)];
+        append_frame(&mut file, &mut checksum, 2, 0, 1);
+        append_frame(&mut file, &mut checksum, 3, 3, 2);
+        append_frame(&mut file, &mut checksum, 2, 4, 3);
+        // Not committed, then a frame with a bad checksum.
+        append_frame(&mut file, &mut checksum, 3, 0, 4);
+        let mut bad = [0, 0];
+        append_frame(&mut file, &mut bad, 3, 5, 5);
+
+        let wal = Wal::new(Box::new(Cursor::new(file)), PAGE_SIZE as u32).unwrap();
+        let version = wal.refresh().unwrap();
+        assert_eq!(
+            version,
+            WalVersion {
+                salt: SALT,
+                frames: 3
+            }
+        );
+        let snapshot = wal.snapshot().unwrap();
+        assert_eq!(snapshot.db_size(), Some(4));
+
+        let mut page = vec![0; PAGE_SIZE];
+        assert!(wal.read_page(&snapshot, 2, &mut page).unwrap());
+        assert_eq!(page[0], 3);
+        assert!(wal.read_page(&snapshot, 3, &mut page).unwrap());
+        assert_eq!(page[0], 2);
+        assert!(!wal.read_page(&snapshot, 1, &mut page).unwrap());
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rqlite-{name}-{}", std::process::id()))
+    }
+
+    /// Writes the checkpoint counters of a shared-memory index for the log of `salt`,
+    /// with unused read marks.
+    fn write_shm(path: &Path, salt: [u32; 2], attempted: u32) {
+        let mut header = vec![0; SHM_HEADER_SIZE];
+        for (i, salt) in salt.iter().enumerate() {
+            let offset = SHM_SALT_OFFSET + 4 * i;
+            header[offset..offset + 4].copy_from_slice(&salt.to_be_bytes());
+        }
+        header[SHM_READ_MARKS_OFFSET..SHM_LOCKS_OFFSET].fill(0xff);
+        header[SHM_BACKFILL_ATTEMPTED_OFFSET..SHM_BACKFILL_ATTEMPTED_OFFSET + 4]
+            .copy_from_slice(&attempted.to_ne_bytes());
+        std::fs::write(path, header).unwrap();
+    }
+
+    #[test]
+    fn snapshot_outlives_commits() {
+        let path = temp_path("wal-snapshot");
+        let mut file = header();
+        let mut checksum = [read_be_double_at(&file, 24), read_be_double_at(&file, 28)];
+        append_frame(&mut file, &mut checksum, 2, 2, 1);
+        std::fs::write(&path, &file).unwrap();
+        let wal = Wal::new(Box::new(File::open(&path).unwrap()), PAGE_SIZE as u32).unwrap();
+        let before = wal.snapshot().unwrap();
+
+        append_frame(&mut file, &mut checksum, 2, 3, 2);
+        std::fs::write(&path, &file).unwrap();
+        wal.refresh().unwrap();
+        let after = wal.snapshot().unwrap();
+        assert_eq!((before.version().frames, after.version().frames), (1, 2));
+        assert_eq!((before.db_size(), after.db_size()), (Some(2), Some(3)));
+
+        let mut page = vec![0; PAGE_SIZE];
+        assert!(wal.read_page(&before, 2, &mut page).unwrap());
+        assert_eq!(page[0], 1);
+        assert!(wal.read_page(&after, 2, &mut page).unwrap());
+        assert_eq!(page[0], 2);
+
+        // A restarted log overwrites the first frame with another salt.
+        file[HEADER_SIZE + 8..HEADER_SIZE + 16].fill(0xff);
+        std::fs::write(&path, &file).unwrap();
+        assert!(wal.read_page(&before, 2, &mut page).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checkpoint_past_snapshot() {
+        let path = temp_path("wal-checkpoint");
+        let shm = temp_path("wal-checkpoint-shm");
+
+        let mut file = header();
+        let mut checksum = [read_be_double_at(&file, 24), read_be_double_at(&file, 28)];
+        append_frame(&mut file, &mut checksum, 2, 2, 1);
+        append_frame(&mut file, &mut checksum, 1, 2, 2);
+        std::fs::write(&path, &file).unwrap();
+        let wal = Wal::new(Box::new(File::open(&path).unwrap()), PAGE_SIZE as u32)
+            .unwrap()
+            .with_shm(&shm);
+        write_shm(&shm, SALT, 1);
+        wal.refresh().unwrap();
+        let snapshot = wal.snapshot().unwrap();
+        wal.check_file_read(&snapshot, 3).unwrap();
+
+        // Copying the frames of the snapshot leaves the other pages of the file alone.
+        write_shm(&shm, SALT, 2);
+        wal.check_file_read(&snapshot, 3).unwrap();
+
+        // So does copying the newer frames of other pages.
+        append_frame(&mut file, &mut checksum, 2, 3, 3);
+        std::fs::write(&path, &file).unwrap();
+        write_shm(&shm, SALT, 3);
+        wal.check_file_read(&snapshot, 3).unwrap();
+        wal.check_file_read(&snapshot, 1).unwrap();
+        assert!(wal.check_file_read(&snapshot, 2).is_err());
+
+        // Nothing is known of the pages of a restarted log.
+        write_shm(&shm, [1, 2], 0);
+        assert!(wal.check_file_read(&snapshot, 3).is_err());
+
+        std::fs::remove_file(&shm).unwrap();
+        wal.check_file_read(&snapshot, 2).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pin_holds_checkpoints_back() {
+        use std::os::fd::AsRawFd;
+
+        // Open file description locks conflict with the process' own POSIX locks.
+        let can_take = |file: &File, reader: usize| {
+            let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+            lock.l_type = libc::F_WRLCK as _;
+            lock.l_whence = libc::SEEK_SET as _;
+            lock.l_start = SHM_READ_LOCKS + reader as i64;
+            lock.l_len = 1;
+            let result = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_GETLK, &mut lock) };
+            assert_eq!(result, 0);
+            lock.l_type == libc::F_UNLCK as _
+        };
+        // Read through a descriptor kept open, as closing one releases the locks.
+        let read_mark = |file: &File, reader: usize| {
+            use std::os::unix::fs::FileExt;
+
+            let mut mark = [0; 4];
+            let offset = SHM_READ_MARKS_OFFSET + 4 * reader;
+            file.read_exact_at(&mut mark, offset as u64).unwrap();
+            u32::from_ne_bytes(mark)
+        };
+
+        let path = temp_path("wal-pin");
+        let shm = temp_path("wal-pin-shm");
+        let mut file = header();
+        let mut checksum = [read_be_double_at(&file, 24), read_be_double_at(&file, 28)];
+        append_frame(&mut file, &mut checksum, 2, 2, 1);
+        std::fs::write(&path, &file).unwrap();
+        write_shm(&shm, SALT, 0);
+        let checkpointer = File::open(&shm).unwrap();
+        let wal = Wal::new(Box::new(File::open(&path).unwrap()), PAGE_SIZE as u32)
+            .unwrap()
+            .with_shm(&shm);
+
+        // The first pin claims a read lock for the frames of its snapshot, which the
+        // next one shares.
+        let a = wal.pin().unwrap();
+        let b = wal.pin().unwrap();
+        assert!(a.has_page(2));
+        assert_eq!(read_mark(&checkpointer, 1), 1);
+        assert!(!can_take(&checkpointer, 1));
+        assert!(can_take(&checkpointer, 2));
+        drop(a);
+        assert!(!can_take(&checkpointer, 1));
+
+        // A newer snapshot can't raise the mark of a held lock, so it claims another.
+        append_frame(&mut file, &mut checksum, 3, 3, 2);
+        std::fs::write(&path, &file).unwrap();
+        let c = wal.pin().unwrap();
+        assert_eq!(
+            (read_mark(&checkpointer, 1), read_mark(&checkpointer, 2)),
+            (1, 2)
+        );
+        assert!(!can_take(&checkpointer, 2));
+        drop(b);
+        drop(c);
+        assert!(can_take(&checkpointer, 1));
+        assert!(can_take(&checkpointer, 2));
+
+        // The log can't restart under a pinned snapshot, so its reads aren't checked.
+        let pinned = wal.pin().unwrap();
+        write_shm(&shm, [1, 2], 0);
+        wal.check_file_read(&pinned, 3).unwrap();
+        drop(pinned);
+
+        std::fs::remove_file(&shm).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,489 @@
+//! The write path of the pager. Pages are modified in memory, in the on-disk format,
+//! and written back in place when the writer flushes them or commits. Without a
+//! rollback journal, the writer is only for files no other connection reads, like
+//! backups. With one, the original content of each page is journaled before the page
+//! is first modified, and the pages are kept in memory until the transaction commits.
+//! The pages of compressed databases are also kept in memory, then written with the
+//! unmodified ones to a new file.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use anyhow::{Context, ensure};
+
+use crate::{
+    journal::JournalWriter,
+    page::DbHeader,
+    pager::{
+        self, HEADER_CHANGE_COUNTER_OFFSET, HEADER_DATABASE_SIZE_OFFSET,
+        HEADER_FREELIST_COUNT_OFFSET, HEADER_FREELIST_TRUNK_OFFSET, HEADER_SCHEMA_COOKIE_OFFSET,
+        HEADER_VERSION_VALID_FOR_OFFSET,
+    },
+    ptrmap::{self, Ptrmap, PtrmapEntry, PtrmapKind},
+    vfs::{DbFile, ZstdPageFile},
+};
+
+/// How many modified pages are kept in memory before being written to the file.
+const MAX_DIRTY_PAGES: usize = 1024;
+/// How many unmodified pages are kept in memory after being read.
+const MAX_CLEAN_PAGES: usize = 256;
+
+#[derive(Debug)]
+pub struct PageWriter<'f> {
+    file: &'f File,
+    header: DbHeader,
+    page_size: usize,
+    usable_size: usize,
+    /// The pages modified since the last flush, by page number.
+    dirty: BTreeMap<usize, Vec<u8>>,
+    /// Pages read but not modified.
+    clean: HashMap<usize, Vec<u8>>,
+    page_count: usize,
+    journal: Option<JournalWriter>,
+    /// The compressed database the pages are read from, when `file` is its new
+    /// version.
+    compressed: Option<ZstdPageFile<&'f File>>,
+}
+
+impl<'f> PageWriter<'f> {
+    /// A writer of the database in `file`, whose pages are `header.page_size` bytes.
+    pub fn new(mut file: &'f File, header: DbHeader) -> anyhow::Result<Self> {
+        let page_size = header.page_size as usize;
+        let len = file.seek(SeekFrom::End(0)).context("seek to end of file")?;
+        Ok(Self {
+            file,
+            header,
+            page_size,
+            usable_size: header.usable_page_size(),
+            dirty: BTreeMap::new(),
+            clean: HashMap::new(),
+            page_count: len as usize / page_size,
+            journal: None,
+            compressed: None,
+        })
+    }
+
+    /// A writer of the database compressed in `file`, in the zstd seekable format.
+    /// The pages are written to `output` at commit, a frame per page.
+    pub fn compressed(file: &'f File, output: &'f File, header: DbHeader) -> anyhow::Result<Self> {
+        let mut compressed = ZstdPageFile::new(file).context("open compressed db file")?;
+        let len = compressed
+            .seek(SeekFrom::End(0))
+            .context("seek to end of file")?;
+        Ok(Self {
+            page_count: len as usize / header.page_size as usize,
+            compressed: Some(compressed),
+            ..Self::new(output, header)?
+        })
+    }
+
+    /// Journals the pages to the rollback journal at `path` before modifying them.
+    pub fn with_journal(mut self, path: &Path) -> anyhow::Result<Self> {
+        self.journal = Some(JournalWriter::create(
+            path,
+            self.page_size,
+            self.page_count,
+        )?);
+        Ok(self)
+    }
+
+    pub fn header(&self) -> DbHeader {
+        self.header
+    }
+
+    pub fn usable_size(&self) -> usize {
+        self.usable_size
+    }
+
+    /// The current content of page `n`.
+    pub fn page(&mut self, n: usize) -> anyhow::Result<&[u8]> {
+        ensure!(n > 0, "invalid page number: 0");
+        if !self.dirty.contains_key(&n) && !self.clean.contains_key(&n) {
+            if self.clean.len() >= MAX_CLEAN_PAGES {
+                self.clean.clear();
+            }
+            let page = self.read_page(n)?;
+            self.clean.insert(n, page);
+        }
+        match self.dirty.get(&n) {
+            Some(page) => Ok(page),
+            None => Ok(&self.clean[&n]),
+        }
+    }
+
+    /// The content of page `n`, to be modified in place. Pages past the end of the
+    /// database extend it.
+    pub fn page_mut(&mut self, n: usize) -> anyhow::Result<&mut [u8]> {
+        ensure!(n > 0, "invalid page number: 0");
+        if !self.dirty.contains_key(&n) {
+            let page = match self.clean.remove(&n) {
+                Some(page) => page,
+                None => self.read_page(n)?,
+            };
+            if let Some(journal) = &mut self.journal {
+                journal.append(n, &page)?;
+            }
+            self.insert(n, page)?;
+        }
+        Ok(self.dirty.get_mut(&n).expect("inserted above"))
+    }
+
+    /// Replaces the content of page `n`.
+    pub fn write_page(&mut self, n: usize, page: Vec<u8>) -> anyhow::Result<()> {
+        ensure!(n > 0, "invalid page number: 0");
+        ensure!(
+            page.len() == self.page_size,
+            "page {n} is {} bytes, not {}",
+            page.len(),
+            self.page_size
+        );
+        let original = self.clean.remove(&n);
+        if self
+            .journal
+            .as_ref()
+            .is_some_and(|journal| journal.needs(n))
+            && !self.dirty.contains_key(&n)
+        {
+            let original = match original {
+                Some(original) => original,
+                None => self.read_page(n)?,
+            };
+            if let Some(journal) = &mut self.journal {
+                journal.append(n, &original)?;
+            }
+        }
+        self.insert(n, page)
+    }
+
+    /// A zeroed page for new content, reusing a free page if there is one.
+    pub fn allocate(&mut self) -> anyhow::Result<usize> {
+        let header = self.page_mut(1)?;
+        let trunk = pager::read_be_double_at(header, HEADER_FREELIST_TRUNK_OFFSET) as usize;
+        if trunk == 0 {
+            let ptrmap = self.ptrmap()?;
+            let lock_byte_page = pager::lock_byte_page(self.header.page_size);
+            let mut n = self.page_count + 1;
+            loop {
+                if ptrmap.is_some_and(|ptrmap| ptrmap.is_ptrmap_page(n)) {
+                    self.page_mut(n)?;
+                } else if n != lock_byte_page {
+                    break;
+                }
+                n += 1;
+            }
+            self.page_mut(n)?;
+            return Ok(n);
+        }
+        let free_pages = pager::read_be_double_at(header, HEADER_FREELIST_COUNT_OFFSET);
+        write_u32(
+            header,
+            HEADER_FREELIST_COUNT_OFFSET,
+            free_pages.saturating_sub(1),
+        );
+
+        let trunk_page = self.page_mut(trunk)?;
+        let leaves = pager::read_be_double_at(trunk_page, 4) as usize;
+        let n = if leaves > 0 {
+            let leaf = pager::read_be_double_at(trunk_page, 4 + 4 * leaves) as usize;
+            write_u32(trunk_page, 4, leaves as u32 - 1);
+            leaf
+        } else {
+            // The trunk has no leaves left, so the next one becomes the first.
+            let next = pager::read_be_double_at(trunk_page, 0);
+            write_u32(self.page_mut(1)?, HEADER_FREELIST_TRUNK_OFFSET, next);
+            trunk
+        };
+        ensure!((2..=self.page_count).contains(&n), "invalid free page: {n}");
+
+        self.write_page(n, vec![0; self.page_size])?;
+        Ok(n)
+    }
+
+    /// Adds page `n` to the freelist, as a leaf of the first trunk page if it has room,
+    /// else as the new first trunk. Its content is zeroed, like with SQLite's
+    /// `secure_delete`.
+    pub fn free(&mut self, n: usize) -> anyhow::Result<()> {
+        ensure!(n > 1, "page {n} can't be freed");
+        ensure!(
+            !self
+                .ptrmap()?
+                .is_some_and(|ptrmap| ptrmap.is_ptrmap_page(n)),
+            "pointer-map page {n} can't be freed"
+        );
+        ensure!(
+            n != pager::lock_byte_page(self.header.page_size),
+            "lock-byte page {n} can't be freed"
+        );
+        let header = self.page_mut(1)?;
+        let trunk = pager::read_be_double_at(header, HEADER_FREELIST_TRUNK_OFFSET);
+        let free_pages = pager::read_be_double_at(header, HEADER_FREELIST_COUNT_OFFSET);
+        write_u32(header, HEADER_FREELIST_COUNT_OFFSET, free_pages + 1);
+
+        // SQLite leaves some slots of trunk pages unused, for compatibility with old
+        // versions.
+        let max_leaves = self.usable_size / 4 - 8;
+        let mut page = vec![0; self.page_size];
+        let mut is_leaf = false;
+        if trunk != 0 {
+            let trunk_page = self.page_mut(trunk as usize)?;
+            let leaves = pager::read_be_double_at(trunk_page, 4) as usize;
+            if leaves < max_leaves {
+                write_u32(trunk_page, 8 + 4 * leaves, n as u32);
+                write_u32(trunk_page, 4, leaves as u32 + 1);
+                is_leaf = true;
+            }
+        }
+        if !is_leaf {
+            write_u32(&mut page, 0, trunk);
+            write_u32(self.page_mut(1)?, HEADER_FREELIST_TRUNK_OFFSET, n as u32);
+        }
+
+        self.write_page(n, page)?;
+        self.set_ptrmap_entry(
+            n,
+            PtrmapEntry {
+                kind: PtrmapKind::FreePage,
+                parent: 0,
+            },
+        )
+    }
+
+    /// Increments the schema cookie, for connections to read the schema again once the
+    /// transaction commits.
+    pub fn change_schema(&mut self) -> anyhow::Result<()> {
+        let header = self.page_mut(1)?;
+        let cookie = pager::read_be_double_at(header, HEADER_SCHEMA_COOKIE_OFFSET).wrapping_add(1);
+        write_u32(header, HEADER_SCHEMA_COOKIE_OFFSET, cookie);
+        self.header.schema_cookie = cookie;
+        Ok(())
+    }
+
+    /// Records the parent of page `n` in the pointer-map, if the database has one.
+    pub fn set_ptrmap_entry(&mut self, n: usize, entry: PtrmapEntry) -> anyhow::Result<()> {
+        let Some(ptrmap) = self.ptrmap()? else {
+            return Ok(());
+        };
+        let (map, offset) = ptrmap.locate(n);
+        ptrmap::write_entry(self.page_mut(map)?, offset, entry);
+        Ok(())
+    }
+
+    fn ptrmap(&mut self) -> anyhow::Result<Option<Ptrmap>> {
+        let header = self.header;
+        Ok(pager::ptrmap_of(self.page_mut(1)?, header))
+    }
+
+    /// Replaces the freelist with `pages`.
+    pub fn replace_freelist(
+        &mut self,
+        pages: impl IntoIterator<Item = usize>,
+    ) -> anyhow::Result<()> {
+        let header = self.page_mut(1)?;
+        write_u32(header, HEADER_FREELIST_TRUNK_OFFSET, 0);
+        write_u32(header, HEADER_FREELIST_COUNT_OFFSET, 0);
+        pages.into_iter().try_for_each(|n| self.free(n))
+    }
+
+    /// Writes the modified pages to the file, unless it is compressed.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        if self.compressed.is_some() {
+            return Ok(());
+        }
+        for (n, page) in std::mem::take(&mut self.dirty) {
+            self.file
+                .seek(SeekFrom::Start(((n - 1) * self.page_size) as u64))
+                .context("seek to page start")?;
+            self.file
+                .write_all(&page)
+                .with_context(|| format!("write page {n}"))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the modified pages, and the header of the new version of the database,
+    /// then syncs the file. The journal is synced before the file is written, and
+    /// deleted once it is synced.
+    pub fn commit(mut self) -> anyhow::Result<()> {
+        let page_count = self.page_count;
+        let header = self.page_mut(1)?;
+        let change_counter =
+            pager::read_be_double_at(header, HEADER_CHANGE_COUNTER_OFFSET).wrapping_add(1);
+        for (offset, value) in [
+            (HEADER_CHANGE_COUNTER_OFFSET, change_counter),
+            (HEADER_DATABASE_SIZE_OFFSET, page_count as u32),
+            (HEADER_VERSION_VALID_FOR_OFFSET, change_counter),
+        ] {
+            write_u32(header, offset, value);
+        }
+
+        if let Some(journal) = &mut self.journal {
+            journal.sync()?;
+        }
+        match &mut self.compressed {
+            Some(compressed) => {
+                let output = std::io::BufWriter::new(self.file);
+                compressed.write_pages(output, self.page_size, page_count, &self.dirty)?;
+            }
+            None => {
+                self.flush()?;
+                self.file
+                    .set_len((page_count * self.page_size) as u64)
+                    .context("truncate file")?;
+            }
+        }
+        self.file.sync_all().context("sync file")?;
+        match self.journal.take() {
+            Some(journal) => journal.finish(),
+            None => Ok(()),
+        }
+    }
+
+    fn insert(&mut self, n: usize, page: Vec<u8>) -> anyhow::Result<()> {
+        // The file can't be written before the journal is synced, at commit.
+        if self.journal.is_none()
+            && self.compressed.is_none()
+            && self.dirty.len() >= MAX_DIRTY_PAGES
+            && !self.dirty.contains_key(&n)
+        {
+            self.flush()?;
+        }
+        self.dirty.insert(n, page);
+        self.page_count = self.page_count.max(n);
+        Ok(())
+    }
+
+    /// Reads page `n` from the file, zeroed where the file ends before it, e.g. when
+    /// it is between pages not flushed yet.
+    fn read_page(&mut self, n: usize) -> anyhow::Result<Vec<u8>> {
+        let mut file = self.file;
+        let input: &mut dyn DbFile = match &mut self.compressed {
+            Some(compressed) => compressed,
+            None => &mut file,
+        };
+        let mut page = Vec::with_capacity(self.page_size);
+        input
+            .seek(SeekFrom::Start(((n - 1) * self.page_size) as u64))
+            .context("seek to page start")?;
+        input
+            .take(self.page_size as u64)
+            .read_to_end(&mut page)
+            .with_context(|| format!("read page {n}"))?;
+        page.resize(self.page_size, 0);
+        Ok(page)
+    }
+}
+
+fn write_u32(buffer: &mut [u8], offset: usize, value: u32) {
+    buffer[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flush_and_commit() {
+        let path = std::env::temp_dir().join(format!("rqlite-writer-{}", std::process::id()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let header = DbHeader {
+            page_size: 512,
+            ..DbHeader::default()
+        };
+        let mut writer = PageWriter::new(&file, header).unwrap();
+
+        writer.write_page(1, vec![1; 512]).unwrap();
+        writer.write_page(3, vec![3; 512]).unwrap();
+        writer.flush().unwrap();
+        // Read back from the file, then from the dirty set.
+        writer.page_mut(3).unwrap()[0] = 4;
+        writer.page_mut(3).unwrap()[1] = 5;
+        assert_eq!(writer.page_mut(2).unwrap(), [0; 512]);
+        writer.commit().unwrap();
+
+        let file = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(file.len(), 3 * 512);
+        assert_eq!(&file[1024..1027], [4, 5, 3]);
+        let counter = pager::read_be_double_at(&file, HEADER_CHANGE_COUNTER_OFFSET);
+        assert_eq!(counter, 0x0101_0102);
+        assert_eq!(
+            pager::read_be_double_at(&file, HEADER_DATABASE_SIZE_OFFSET),
+            3
+        );
+        assert_eq!(
+            pager::read_be_double_at(&file, HEADER_VERSION_VALID_FOR_OFFSET),
+            counter
+        );
+    }
+
+    #[test]
+    fn freelist_allocation() {
+        let path = std::env::temp_dir().join(format!("rqlite-freelist-{}", std::process::id()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let header = DbHeader {
+            page_size: 512,
+            ..DbHeader::default()
+        };
+        let mut writer = PageWriter::new(&file, header).unwrap();
+        writer.write_page(1, vec![0; 512]).unwrap();
+
+        // A trunk holds 120 leaves, so the last page becomes a second trunk, which is
+        // reused first.
+        writer.replace_freelist(2..=123).unwrap();
+        assert_eq!(writer.allocate().unwrap(), 123);
+        assert_eq!(writer.allocate().unwrap(), 122);
+        writer.free(122).unwrap();
+        writer.commit().unwrap();
+
+        let file = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let pager = pager::Pager::new(header, std::io::Cursor::new(file));
+        assert_eq!(pager.page_count().unwrap(), 123);
+        let freelist = pager.freelist().unwrap();
+        assert_eq!(freelist.trunks, [2]);
+        assert_eq!(freelist.leaves.len(), 120);
+        assert_eq!(freelist.leaves[..3], [3, 4, 5]);
+        assert_eq!(freelist.leaves.last(), Some(&122));
+    }
+
+    #[test]
+    fn allocation_skips_lock_byte_page() {
+        let path = std::env::temp_dir().join(format!("rqlite-lock-byte-{}", std::process::id()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let header = DbHeader {
+            page_size: 65536,
+            ..DbHeader::default()
+        };
+        let mut writer = PageWriter::new(&file, header).unwrap();
+        writer.write_page(1, vec![0; 65536]).unwrap();
+        // The last page before the 1GiB mark, which the lock-byte page follows.
+        writer.write_page(16384, vec![0; 65536]).unwrap();
+
+        assert_eq!(writer.allocate().unwrap(), 16386);
+        assert!(writer.free(16385).is_err());
+        drop(writer);
+        std::fs::remove_file(&path).unwrap();
+    }
+}